@@ -0,0 +1,162 @@
+use crate::source::read_asar_header;
+use anyhow::{bail, Context};
+use data_encoding::HEXLOWER;
+use hive_asar::header::{Directory, Entry, FileMetadata};
+use hive_asar::Archive;
+use owo_colors::OwoColorize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::path::Path;
+use tokio::fs::File;
+use tokio::io;
+use tokio::io::AsyncReadExt;
+
+/// Opens an archive, along with its files by path, in order.
+async fn open(path: &Path) -> anyhow::Result<(Archive<File>, BTreeMap<String, FileMetadata>)> {
+  let mut file = File::open(path)
+    .await
+    .with_context(|| format!("failed to open {}", path.display()))?;
+  let read = async {
+    let root = read_asar_header(&mut file).await?;
+    Ok::<_, io::Error>((root, Archive::new(file).await?))
+  };
+  let (root, archive) =
+    (read.await).with_context(|| format!("failed to read {} as asar", path.display()))?;
+  Ok((archive, files(&root)))
+}
+
+fn files(root: &Directory) -> BTreeMap<String, FileMetadata> {
+  fn walk(dir: &Directory, prefix: &str, files: &mut BTreeMap<String, FileMetadata>) {
+    for (name, entry) in &dir.files {
+      let path = format!("{prefix}{name}");
+      match entry {
+        Entry::File(metadata) => drop(files.insert(path, metadata.clone())),
+        Entry::Directory(dir) => walk(dir, &format!("{path}/"), files),
+      }
+    }
+  }
+  let mut files = BTreeMap::new();
+  walk(root, "", &mut files);
+  files
+}
+
+/// Lists files in an asar archive, with their sizes if `long`.
+pub async fn list(path: &Path, long: bool) -> anyhow::Result<()> {
+  let (_, files) = open(path).await?;
+  for (path, metadata) in files {
+    if long {
+      let integrity = if metadata.integrity.is_some() {
+        "integrity"
+      } else {
+        "-"
+      };
+      println!("{:>10}  {integrity:<9}  {path}", metadata.size);
+    } else {
+      println!("{path}");
+    }
+  }
+  Ok(())
+}
+
+/// Checks every file in an asar archive against its integrity information.
+pub async fn verify(path: &Path) -> anyhow::Result<()> {
+  let (mut archive, files) = open(path).await?;
+  let (mut checked, mut unchecked, mut failed) = (0, 0, 0);
+  for (path, metadata) in files {
+    if metadata.integrity.is_none() {
+      unchecked += 1;
+      continue;
+    }
+    let mut file =
+      (archive.get(&path).await).with_context(|| format!("failed to read '{path}'"))?;
+    if file.check_integrity().await? {
+      checked += 1;
+    } else {
+      println!("{} {path}", "mismatch".red());
+      failed += 1;
+    }
+  }
+  if failed > 0 {
+    bail!("{failed} file(s) failed integrity check");
+  }
+  println!("{checked} file(s) passed integrity check, {unchecked} without integrity information");
+  Ok(())
+}
+
+/// Shows files added to, removed from or changed between two asar archives.
+pub async fn diff(old: &Path, new: &Path) -> anyhow::Result<()> {
+  let (old, new) = (hash_files(old).await?, hash_files(new).await?);
+  let mut changed = 0;
+  for (path, hash) in &old {
+    match new.get(path) {
+      None => println!("{}", format!("- {path}").red()),
+      Some(new_hash) if new_hash != hash => println!("{}", format!("M {path}").yellow()),
+      Some(_) => continue,
+    }
+    changed += 1;
+  }
+  for path in new.keys().filter(|x| !old.contains_key(*x)) {
+    println!("{}", format!("+ {path}").green());
+    changed += 1;
+  }
+  if changed == 0 {
+    println!("No differences");
+  }
+  Ok(())
+}
+
+/// SHA-256 of each file's content in an archive.
+async fn hash_files(path: &Path) -> anyhow::Result<BTreeMap<String, String>> {
+  let (mut archive, files) = open(path).await?;
+  let mut hashes = BTreeMap::new();
+  let mut buf = Vec::new();
+  for path in files.into_keys() {
+    buf.clear();
+    let mut file =
+      (archive.get(&path).await).with_context(|| format!("failed to read '{path}'"))?;
+    file.read_to_end(&mut buf).await?;
+    hashes.insert(path, HEXLOWER.encode(&Sha256::digest(&buf)));
+  }
+  Ok(hashes)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  async fn pack(dir: &Path, name: &str, files: &[(&str, &str)]) -> std::path::PathBuf {
+    let src = dir.join(name);
+    for (path, content) in files {
+      let path = src.join(path);
+      tokio::fs::create_dir_all(path.parent().unwrap())
+        .await
+        .unwrap();
+      tokio::fs::write(path, content).await.unwrap();
+    }
+    let archive_path = dir.join(format!("{name}.asar"));
+    let mut file = File::create(&archive_path).await.unwrap();
+    hive_asar::pack_dir(&src, &mut file).await.unwrap();
+    archive_path
+  }
+
+  #[tokio::test]
+  async fn test_hash_files() {
+    let dir = TempDir::new().unwrap();
+    let files = [("main.lua", "-- main"), ("lib/a/b.lua", "-- b")];
+    let old = pack(dir.path(), "old", &files).await;
+    let new = pack(dir.path(), "new", &[files[0], ("lib/a/b.lua", "-- c")]).await;
+
+    let (old_hashes, new_hashes) = (
+      hash_files(&old).await.unwrap(),
+      hash_files(&new).await.unwrap(),
+    );
+    let paths = old_hashes.keys().collect::<Vec<_>>();
+    assert_eq!(paths, ["lib/a/b.lua", "main.lua"]);
+    assert_eq!(old_hashes["main.lua"], new_hashes["main.lua"]);
+    assert_ne!(old_hashes["lib/a/b.lua"], new_hashes["lib/a/b.lua"]);
+
+    verify(&old).await.unwrap();
+    assert!(hash_files(&dir.path().join("old/main.lua")).await.is_err());
+  }
+}
@@ -0,0 +1,211 @@
+use crate::deploy::auth_header;
+use anyhow::Context;
+use hyper::Uri;
+use owo_colors::OwoColorize;
+use reqwest::{Client, Method};
+use std::collections::BTreeMap;
+use std::env::var;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::{mpsc, Semaphore};
+use tokio::time::{interval, MissedTickBehavior};
+use uuid::Uuid;
+
+pub struct BenchOptions {
+  pub server: Option<Uri>,
+  pub auth_token: Option<Uuid>,
+  pub target: String,
+  pub method: Method,
+  pub rps: u32,
+  pub duration: Duration,
+  pub concurrency: usize,
+}
+
+struct Sample {
+  status: Option<u16>,
+  total: Duration,
+  /// Time spent in Abel handling the request, taken from `server-timing`.
+  /// Only sent to clients allowed to invoke the service, or in dev mode.
+  handler: Option<Duration>,
+}
+
+/// Sends requests at a fixed rate and prints latency percentiles.
+///
+/// Requests that would exceed `concurrency` in-flight requests are skipped
+/// and counted as dropped, so a slow server does not silently lower the
+/// offered load.
+pub async fn bench(options: BenchOptions) -> anyhow::Result<()> {
+  let url = if options.target.starts_with("http://") || options.target.starts_with("https://") {
+    options.target.clone()
+  } else {
+    let server = match options.server {
+      Some(server) => server.to_string(),
+      None => var("ABEL_SERVER").unwrap_or_else(|_| "http://127.0.0.1:3000".into()),
+    };
+    let target = options.target.trim_start_matches('/');
+    format!("{}/{target}", server.trim_end_matches('/'))
+  };
+  let _: Uri = url.parse().context("invalid target URL")?;
+
+  println!(
+    "Benchmarking {} at {} req/s for {}s",
+    url.underline(),
+    options.rps,
+    options.duration.as_secs_f64()
+  );
+
+  let client = Client::new();
+  let auth_header = auth_header(options.auth_token)?;
+  let semaphore = Arc::new(Semaphore::new(options.concurrency.max(1)));
+  let (tx, mut rx) = mpsc::unbounded_channel();
+  let mut ticker = interval(Duration::from_secs(1) / options.rps.max(1));
+  ticker.set_missed_tick_behavior(MissedTickBehavior::Burst);
+
+  let start = Instant::now();
+  let mut dropped = 0usize;
+  while start.elapsed() < options.duration {
+    ticker.tick().await;
+    let permit = match semaphore.clone().try_acquire_owned() {
+      Ok(permit) => permit,
+      Err(_) => {
+        dropped += 1;
+        continue;
+      }
+    };
+    let mut request = client.request(options.method.clone(), &url);
+    if let Some(x) = &auth_header {
+      request = request.header("authorization", x);
+    }
+    let tx = tx.clone();
+    tokio::spawn(async move {
+      let start = Instant::now();
+      let result = request.send().await;
+      let sample = match result {
+        Ok(resp) => {
+          let handler = (resp.headers().get("server-timing"))
+            .and_then(|x| x.to_str().ok())
+            .and_then(parse_server_timing);
+          let status = resp.status().as_u16();
+          // Include body transfer in the total time
+          let _ = resp.bytes().await;
+          Sample {
+            status: Some(status),
+            total: start.elapsed(),
+            handler,
+          }
+        }
+        Err(_) => Sample {
+          status: None,
+          total: start.elapsed(),
+          handler: None,
+        },
+      };
+      let _ = tx.send(sample);
+      drop(permit);
+    });
+  }
+  drop(tx);
+
+  let mut samples = Vec::new();
+  while let Some(sample) = rx.recv().await {
+    samples.push(sample);
+  }
+  let elapsed = start.elapsed();
+
+  print_report(&samples, dropped, elapsed);
+  Ok(())
+}
+
+/// Extracts the `abel` metric from a `server-timing` header, e.g.
+/// `abel;dur=1.23`.
+fn parse_server_timing(header: &str) -> Option<Duration> {
+  header.split(',').find_map(|metric| {
+    let mut params = metric.split(';').map(str::trim);
+    if params.next()? != "abel" {
+      return None;
+    }
+    let ms = params.find_map(|x| x.strip_prefix("dur="))?;
+    Duration::try_from_secs_f64(ms.parse::<f64>().ok()? / 1000.).ok()
+  })
+}
+
+fn print_report(samples: &[Sample], dropped: usize, elapsed: Duration) {
+  let mut statuses = BTreeMap::new();
+  let mut failed = 0;
+  for sample in samples {
+    match sample.status {
+      Some(status) => *statuses.entry(status).or_insert(0usize) += 1,
+      None => failed += 1,
+    }
+  }
+
+  println!();
+  println!(
+    "Requests:  {} sent, {} completed ({:.1} req/s), {} failed, {} dropped",
+    samples.len(),
+    samples.len() - failed,
+    (samples.len() - failed) as f64 / elapsed.as_secs_f64(),
+    failed,
+    dropped
+  );
+  let statuses = (statuses.iter())
+    .map(|(status, count)| format!("{status}: {count}"))
+    .collect::<Vec<_>>();
+  println!("Statuses:  {}", statuses.join(", "));
+
+  let ok = samples.iter().filter(|x| x.status.is_some());
+  let total = ok.clone().map(|x| x.total).collect::<Vec<_>>();
+  let handler = ok.clone().filter_map(|x| x.handler).collect::<Vec<_>>();
+  let network = (ok.filter_map(|x| Some(x.total.saturating_sub(x.handler?)))).collect::<Vec<_>>();
+
+  println!();
+  println!("{:<10}{:>10}{:>10}{:>10}", "Latency", "p50", "p95", "p99");
+  print_percentiles("Total", total);
+  if handler.is_empty() {
+    println!(
+      "{}",
+      "(no server-timing header found; pass a token allowed to invoke the service to see handler time)".dimmed()
+    );
+  } else {
+    print_percentiles("Handler", handler);
+    print_percentiles("Network", network);
+  }
+}
+
+fn print_percentiles(name: &str, mut durations: Vec<Duration>) {
+  durations.sort_unstable();
+  let percentile = |p: f64| {
+    if durations.is_empty() {
+      return "-".to_string();
+    }
+    let i = ((durations.len() as f64 * p).ceil() as usize).clamp(1, durations.len()) - 1;
+    format!("{:.2}ms", durations[i].as_secs_f64() * 1000.)
+  };
+  println!(
+    "{name:<10}{:>10}{:>10}{:>10}",
+    percentile(0.5),
+    percentile(0.95),
+    percentile(0.99)
+  );
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_parse_server_timing() {
+    let parse = parse_server_timing;
+    assert_eq!(parse("abel;dur=1.5"), Some(Duration::from_micros(1500)));
+    assert_eq!(
+      parse("db;dur=3, abel; desc=\"x\"; dur=2"),
+      Some(Duration::from_millis(2))
+    );
+    assert_eq!(parse("db;dur=3"), None);
+    assert_eq!(parse("abel"), None);
+    assert_eq!(parse("abel;dur=x"), None);
+    assert_eq!(parse("abel;dur=-1"), None);
+    assert_eq!(parse("abel;dur=NaN"), None);
+    assert_eq!(parse("abel;dur=1e300"), None);
+  }
+}
@@ -1,63 +1,118 @@
+use crate::pack::check_service_dir;
 use crate::server::types::HttpUploadResponse;
 use crate::server::upload::UploadMode;
 use crate::server::JsonError;
+use crate::source::{hash_asar, hash_reader};
 use anyhow::{bail, Context};
 use hyper::http::HeaderValue;
 use hyper::Uri;
 use log::debug;
 use owo_colors::OwoColorize;
 use reqwest::multipart::{Form, Part};
-use reqwest::{Body, Client};
+use reqwest::{Body, Client, Response, StatusCode};
+use serde::Deserialize;
 use std::borrow::Cow;
 use std::env::var;
 use std::ffi::OsStr;
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 use tokio::fs::{self, File};
+use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
-pub async fn deploy(
-  server: Option<Uri>,
-  auth_token: Option<Uuid>,
-  path: PathBuf,
-  mode: UploadMode,
-) -> anyhow::Result<()> {
-  let path = fs::canonicalize(path).await?;
-  let server = server.map(Ok).unwrap_or_else(|| {
-    var("ABEL_SERVER")
-      .context("you need to specify either the env ABEL_SERVER or the argument --server")?
-      .parse()
-      .context("failed to parse env ABEL_SERVER")
-  })?;
-  let name = path.file_stem().context("no filename found")?;
-  let name = name.to_str().context("filename contains non-UTF-8 bytes")?;
-  let server = format!("{server}/services/{name}?mode={mode}");
+pub enum DeploySource {
+  /// A service folder, asar archive or Lua file, uploaded as is.
+  Path(PathBuf),
+  /// A git repository the server fetches the service from.
+  Git {
+    repo: String,
+    reference: Option<String>,
+  },
+}
 
-  let auth_token = auth_token
-    .map(|x| Ok(Some(x)))
-    .unwrap_or_else(|| {
-      std::env::var_os("ABEL_AUTH_TOKEN")
-        .map(|x| {
-          x.to_str()
-            .context("failed to parse ABEL_AUTH_TOKEN as UTF-8")?
-            .parse()
-            .context("failed to parse env ABEL_AUTH_TOKEN into UUID")
-        })
-        .transpose()
-    })?
-    .map(|x| {
-      let mut x = HeaderValue::try_from(format!("Abel {x}"))?;
-      x.set_sensitive(true);
-      anyhow::Ok(x)
-    })
-    .transpose()?;
+pub struct DeployOptions {
+  pub server: Option<Uri>,
+  pub auth_token: Option<Uuid>,
+  pub source: DeploySource,
+  /// Overrides the name derived from `source`.
+  pub name: Option<String>,
+  pub mode: UploadMode,
+  pub dry_run: bool,
+  pub force: bool,
+}
+
+pub async fn deploy(options: DeployOptions) -> anyhow::Result<()> {
+  let DeployOptions {
+    server,
+    auth_token,
+    source,
+    name,
+    mode,
+    dry_run,
+    force,
+  } = options;
+  let server = server_or_env(server)?;
+  let auth_token = auth_header(auth_token)?;
+  let client = Client::new();
+
+  let path = match source {
+    DeploySource::Path(path) => fs::canonicalize(path).await?,
+    DeploySource::Git { repo, reference } => {
+      let name = match name {
+        Some(name) => name,
+        None => repo_name(&repo)?,
+      };
+      let reference = reference.unwrap_or_else(|| "HEAD".into());
+      if dry_run {
+        println!("Would deploy '{repo}' at {reference} to service '{name}'");
+        return Ok(());
+      }
 
+      let mut query = vec![
+        ("mode", mode.to_string()),
+        ("git", repo),
+        ("ref", reference),
+      ];
+      if force {
+        query.push(("force", "true".into()));
+      }
+      let mut builder = client.put(format!("{server}/api/v1/services/{name}"));
+      if let Some(x) = auth_token {
+        builder = builder.header("authorization", x);
+      }
+      let resp = builder.query(&query).send().await?;
+      let resp: HttpUploadResponse = check_status(resp).await?.json().await?;
+      if resp.unchanged {
+        println!("Service '{name}' is unchanged; skipping");
+      } else {
+        print_upload_response(&resp);
+      }
+      return Ok(());
+    }
+  };
+  let name = match name {
+    Some(name) => name,
+    None => (path.file_stem().context("no filename found")?)
+      .to_str()
+      .context("filename contains non-UTF-8 bytes")?
+      .to_string(),
+  };
+  let service_url = format!("{server}/api/v1/services/{name}");
+
+  // Folders are packed into a temporary file, and sources are read twice,
+  // once for hashing and once for uploading, instead of held in memory.
   let metadata = fs::metadata(&path).await?;
-  let form = if metadata.is_dir() {
-    check_folder(&path)?;
-    let asar_stream = hive_asar::pack_dir_into_stream(path)
+  let mut packed = None;
+  let (kind, source_path) = if metadata.is_dir() {
+    check_service_dir(&path).await?;
+    let temp = tempfile::NamedTempFile::new()?;
+    let mut file = File::from_std(temp.reopen()?);
+    (hive_asar::pack_dir(&path, &mut file))
       .await
       .context("failed to pack directory into asar")?;
-    Form::new().part("multi", Part::stream(Body::wrap_stream(asar_stream)))
+    file.sync_all().await?;
+    let temp_path = temp.path().to_path_buf();
+    packed = Some(temp);
+    ("multi", temp_path)
   } else {
     let kind = match path.extension().and_then(OsStr::to_str) {
       Some("asar") => "multi",
@@ -70,31 +125,97 @@ pub async fn deploy(
         "single"
       }
     };
-    let file = File::open(&path).await?;
-    Form::new().part(kind, Part::stream_with_length(file, metadata.len()))
+    (kind, path.clone())
+  };
+  let source_hash = if kind == "multi" {
+    (hash_asar(File::open(&source_path).await?).await).context("failed to read asar")?
+  } else {
+    hash_reader(File::open(&source_path).await?).await?
   };
 
-  let mut builder = Client::new().put(server);
+  let mut builder = client.get(&service_url);
+  if let Some(x) = &auth_token {
+    builder = builder.header("authorization", x.clone());
+  }
+  let resp = builder.send().await?;
+  let deployed = if resp.status() == StatusCode::NOT_FOUND {
+    None
+  } else {
+    let DeployedService { source_hash } = check_status(resp).await?.json().await?;
+    Some(source_hash)
+  };
+  let action = match &deployed {
+    Some(Some(hash)) if *hash == source_hash && !force => {
+      println!("Service '{name}' is unchanged; skipping");
+      return Ok(());
+    }
+    Some(_) => "update",
+    None => "create",
+  };
+  if dry_run {
+    println!(
+      "Would {action} service '{name}' (source {})",
+      &source_hash[..12]
+    );
+    return Ok(());
+  }
+
+  let file = File::open(&source_path).await?;
+  let len = file.metadata().await?.len();
+  let part = Part::stream_with_length(Body::wrap_stream(ReaderStream::new(file)), len);
+  let form = Form::new().part(kind, part);
+  let mut builder = client.put(format!("{service_url}?mode={mode}"));
   if let Some(x) = auth_token {
     builder = builder.header("authorization", x);
   }
   let resp = builder.multipart(form).send().await?;
 
-  let status = resp.status();
-  if status.is_client_error() || status.is_server_error() {
-    let JsonError { error, detail } = resp
-      .json()
-      .await
-      .context("failed to read JSON from response body")?;
-    if let Some(detail) = detail {
-      let detail = serde_json::to_string_pretty(&detail)?;
-      bail!("server responded with error '{error}' ({status})\n\nDetail: {detail}");
-    } else {
-      bail!("server responded with error '{error}' ({status})")
-    }
-  }
+  drop(packed);
+
+  let resp: HttpUploadResponse = check_status(resp).await?.json().await?;
+  print_upload_response(&resp);
+  Ok(())
+}
+
+/// `server`, or the server in env `ABEL_SERVER`.
+pub fn server_or_env(server: Option<Uri>) -> anyhow::Result<Uri> {
+  server.map(Ok).unwrap_or_else(|| {
+    var("ABEL_SERVER")
+      .context("you need to specify either the env ABEL_SERVER or the argument --server")?
+      .parse()
+      .context("failed to parse env ABEL_SERVER")
+  })
+}
+
+/// `authorization` header of `auth_token`, or of the token in env
+/// `ABEL_AUTH_TOKEN`, if any.
+pub fn auth_header(auth_token: Option<Uuid>) -> anyhow::Result<Option<HeaderValue>> {
+  auth_token
+    .map(|x| Ok(Some(x)))
+    .unwrap_or_else(|| {
+      std::env::var_os("ABEL_AUTH_TOKEN")
+        .map(|x| {
+          x.to_str()
+            .context("failed to parse ABEL_AUTH_TOKEN as UTF-8")?
+            .parse()
+            .context("failed to parse env ABEL_AUTH_TOKEN into UUID")
+        })
+        .transpose()
+    })?
+    .map(|x| {
+      let mut x = HeaderValue::try_from(format!("Abel {x}"))?;
+      x.set_sensitive(true);
+      anyhow::Ok(x)
+    })
+    .transpose()
+}
 
-  let resp: HttpUploadResponse = resp.json().await?;
+fn print_upload_response(resp: &HttpUploadResponse) {
+  if resp.unchanged {
+    let name = resp.new_service.service.name();
+    println!("Service '{name}' is unchanged");
+    return;
+  }
   let prefix = resp
     .replaced_service
     .is_some()
@@ -110,6 +231,9 @@ pub async fn deploy(
     resp.new_service.service.name(),
     resp.new_service.service.uuid()
   );
+  if let Some(diff) = &resp.diff {
+    println!("Changes: {diff}");
+  }
 
   if !resp.errors.is_empty() {
     println!("Errors:");
@@ -138,14 +262,37 @@ pub async fn deploy(
   }
 
   debug!("Response: {resp:#?}");
+}
 
-  Ok(())
+/// Name of the repository at `url`, e.g. `hello` for
+/// `https://example.com/me/hello.git`.
+fn repo_name(url: &str) -> anyhow::Result<String> {
+  let name = (url.trim_end_matches('/').rsplit(['/', ':']).next())
+    .map(|x| x.trim_end_matches(".git"))
+    .filter(|x| !x.is_empty())
+    .context("cannot infer service name from repository URL; specify --name")?;
+  Ok(name.to_string())
 }
 
-fn check_folder(path: &Path) -> anyhow::Result<()> {
-  let main_lua_path = path.join("main.lua");
-  if main_lua_path.exists() {
-    bail!("main.lua not found in {}", path.display());
+#[derive(Deserialize)]
+struct DeployedService {
+  /// Missing for services uploaded before source hashes were recorded.
+  source_hash: Option<String>,
+}
+
+pub async fn check_status(resp: Response) -> anyhow::Result<Response> {
+  let status = resp.status();
+  if status.is_client_error() || status.is_server_error() {
+    let JsonError { error, detail } = resp
+      .json()
+      .await
+      .context("failed to read JSON from response body")?;
+    if let Some(detail) = detail {
+      let detail = serde_json::to_string_pretty(&detail)?;
+      bail!("server responded with error '{error}' ({status})\n\nDetail: {detail}");
+    } else {
+      bail!("server responded with error '{error}' ({status})")
+    }
   }
-  Ok(())
+  Ok(resp)
 }
@@ -1,21 +1,16 @@
 use crate::server::metadata::Metadata;
-use crate::server::upload::{log_result, upload_local, UploadMode};
 use crate::server::ServerState;
 use crate::SourceKind;
+use abel_core::dev::{ReloadEvent, Watcher};
 use anyhow::anyhow;
 use futures::TryFutureExt;
-use hive_asar::pack_dir_into_stream;
-use log::{error, warn};
-use notify::RecursiveMode::Recursive;
-use notify::{Event, RecommendedWatcher, Watcher};
+use log::{info, warn};
+use owo_colors::OwoColorize;
 use slug::slugify;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
 use tokio::fs::{self, File};
 use tokio::io::{self, AsyncReadExt};
-use tokio::runtime::Handle;
-use tokio_util::io::ReaderStream;
 use uuid::Uuid;
 
 pub async fn save_services_from_paths(
@@ -67,6 +62,9 @@ pub async fn save_services_from_paths(
     Metadata {
       uuid: Uuid::new_v4(),
       started: true,
+      source_hash: None,
+      source: None,
+      git: None,
     }
     .write(&service_path.join("metadata.json"))
     .await?;
@@ -91,60 +89,29 @@ pub fn init_watcher(
   state: Arc<ServerState>,
   kinds_and_names: Vec<(SourceKind, String)>,
   services: Arc<[PathBuf]>,
-) -> anyhow::Result<RecommendedWatcher> {
-  let rt = Handle::current();
-  let mut time = Instant::now();
-
-  let mut watcher = notify::recommended_watcher({
-    let services = services.clone();
-    move |result: Result<Event, notify::Error>| {
-      let now = Instant::now();
-      let dur = now.duration_since(time);
-      match result {
-        Ok(event) if dur > Duration::from_millis(100) => {
-          time = now;
-          let mut event_paths_iter = event.paths.into_iter();
-          'services: for ((kind, name), path) in kinds_and_names.iter().zip(&*services) {
-            if event_paths_iter.len() == 0 {
-              break;
-            }
-            for event_path in &mut event_paths_iter {
-              if &event_path == path || *kind == SourceKind::Multi && event_path.starts_with(path) {
-                let result = rt.block_on(async {
-                  const MODE: UploadMode = UploadMode::Hot; // FIXME: is hot update okay?
-                  let resp = match kind {
-                    SourceKind::Single => {
-                      let stream = ReaderStream::new(File::open(&path).await?);
-                      upload_local(&state, name.clone(), MODE, *kind, stream).await?
-                    }
-                    SourceKind::Multi => {
-                      let stream = pack_dir_into_stream(&path).await?;
-                      upload_local(&state, name.clone(), MODE, *kind, stream).await?
-                    }
-                  };
-                  log_result(&resp);
-                  anyhow::Ok(())
-                });
-
-                if let Err(error) = result {
-                  warn!("Error updating service '{name}': {error}");
-                  warn!("maybe check '{}'?", path.display());
-                }
+) -> anyhow::Result<Watcher> {
+  let (watcher, mut events) = Watcher::new(state)?;
+  for ((_, name), path) in kinds_and_names.iter().zip(&*services) {
+    watcher.watch_service(name, path)?;
+  }
 
-                continue 'services;
-              }
-            }
-          }
+  tokio::spawn(async move {
+    while let Some(event) = events.recv().await {
+      match event {
+        ReloadEvent::Reloaded { name, uuid } => {
+          info!("Updated service '{name}' {}", format!("({uuid})").dimmed());
+        }
+        ReloadEvent::Failed { name, error } => {
+          let path = (kinds_and_names.iter().zip(&*services))
+            .find(|((_, x), _)| *x == name)
+            .map(|(_, path)| path.display().to_string())
+            .unwrap_or_default();
+          warn!("Error updating service '{name}': {error}");
+          warn!("maybe check '{path}'?");
         }
-        Ok(_) => {}
-        Err(error) => error!("failed to watch files: {error}"),
       }
     }
-  })?;
-
-  for path in &*services {
-    watcher.watch(path, Recursive)?;
-  }
+  });
 
   Ok(watcher)
 }
@@ -0,0 +1,75 @@
+use crate::deploy::{auth_header, check_status, server_or_env};
+use crate::server::LogLine;
+use anyhow::{bail, Context};
+use chrono::{Local, TimeZone};
+use futures::TryStreamExt;
+use hyper::Uri;
+use owo_colors::OwoColorize;
+use reqwest::Client;
+use uuid::Uuid;
+
+pub struct LogsOptions {
+  pub server: Option<Uri>,
+  pub auth_token: Option<Uuid>,
+  pub service: String,
+  pub lines: usize,
+  pub follow: bool,
+}
+
+/// Prints lines recently logged by a service, and with `follow`, lines
+/// logged after them until interrupted.
+pub async fn logs(options: LogsOptions) -> anyhow::Result<()> {
+  let server = server_or_env(options.server)?.to_string();
+  let url = format!(
+    "{}/api/v1/services/{}/logs",
+    server.trim_end_matches('/'),
+    options.service
+  );
+
+  let mut builder = Client::new().get(&url).query(&[
+    ("lines", options.lines.to_string()),
+    ("follow", options.follow.to_string()),
+  ]);
+  if let Some(x) = auth_header(options.auth_token)? {
+    builder = builder.header("authorization", x);
+  }
+  let resp = (builder.send().await).with_context(|| format!("failed to connect to {url}"))?;
+  let mut body = check_status(resp).await?.bytes_stream();
+
+  let mut buf = Vec::new();
+  while let Some(chunk) = body.try_next().await? {
+    buf.extend_from_slice(&chunk);
+    while let Some(end) = buf.iter().position(|x| *x == b'\n') {
+      let line = buf.drain(..=end).collect::<Vec<_>>();
+      let line: LogLine = serde_json::from_slice(&line).context("failed to parse log line")?;
+      print_line(&line);
+    }
+  }
+  if options.follow {
+    bail!("server closed the connection");
+  }
+  Ok(())
+}
+
+fn print_line(line: &LogLine) {
+  let time = (Local.timestamp_millis_opt((line.timestamp * 1000.) as i64))
+    .single()
+    .map(|x| x.format("%Y-%m-%d %H:%M:%S%.3f").to_string())
+    .unwrap_or_default();
+  let padded = format!("{:<5}", line.level);
+  let level = match &*line.level {
+    "error" => padded.red().to_string(),
+    "warn" => padded.yellow().to_string(),
+    "debug" | "trace" => padded.dimmed().to_string(),
+    _ => padded.blue().to_string(),
+  };
+  let mut messages = line.message.lines();
+  println!(
+    "{} {level} {}",
+    time.dimmed(),
+    messages.next().unwrap_or("")
+  );
+  for message in messages {
+    println!("  {message}");
+  }
+}
@@ -1,23 +1,37 @@
+mod asar;
+mod bench;
 mod deploy;
 mod dev;
+mod logs;
+mod pack;
+mod replay;
+mod requests;
 mod resolve;
 mod server;
 mod source;
+mod status;
 
 use crate::dev::save_services_from_paths;
+use bench::{bench, BenchOptions};
 use clap::{Parser, Subcommand};
-use deploy::deploy;
+use deploy::{deploy, DeployOptions, DeploySource};
 use dev::init_watcher;
 use futures::Future;
 use hyper::Uri;
 use log::{info, warn};
+use logs::{logs, LogsOptions};
 use owo_colors::OwoColorize;
-use resolve::resolve_dep;
+use pack::{pack, unpack};
+use replay::replay;
+use requests::{requests, RequestsOptions};
+use resolve::{resolve_dep, vendor};
 use server::config::{Config, ConfigArgs, ServerArgs, HALF_NUM_CPUS};
 use server::upload::UploadMode;
 use server::{init_logger, init_state, init_state_with_stored_config, load_saved_services};
+use status::status;
 use std::path::PathBuf;
 use std::sync::Arc;
+use std::time::Duration;
 use tempfile::tempdir;
 use tokio::io;
 use uuid::Uuid;
@@ -39,6 +53,9 @@ enum Command {
   Dev {
     #[clap(flatten)]
     config: ConfigArgs,
+    /// Recent requests kept for `abel requests`; 0 disables it
+    #[clap(long, default_value_t = 100)]
+    keep_requests: usize,
     services: Vec<PathBuf>,
   },
   Deploy {
@@ -46,13 +63,155 @@ enum Command {
     server: Option<Uri>,
     #[clap(short, long)]
     auth_token: Option<Uuid>,
-    path: PathBuf,
+    #[clap(required_unless_present = "git")]
+    path: Option<PathBuf>,
     #[clap(short, long, value_enum, default_value_t)]
     mode: UploadMode,
+    /// Let the server fetch the service from this git repository instead of
+    /// uploading `path`
+    #[clap(long, conflicts_with = "path")]
+    git: Option<String>,
+    /// Branch, tag or commit to deploy from the git repository [default:
+    /// HEAD]
+    #[clap(long = "ref", requires = "git")]
+    reference: Option<String>,
+    /// Service name [default: file stem of `path`, or the repository's name]
+    #[clap(long)]
+    name: Option<String>,
+    /// Show whether the service would be created or updated, without
+    /// uploading
+    #[clap(long)]
+    dry_run: bool,
+    /// Upload even if the deployed source is the same
+    #[clap(long)]
+    force: bool,
+  },
+  /// Write hashes of a service's remote modules to its abel.lock, which
+  /// they are verified against when loaded.
+  Resolve { path: PathBuf },
+  /// Download a service's remote modules into its `vendor` folder, so that
+  /// it runs without fetching them.
+  Vendor { path: PathBuf },
+  /// Pack a service folder into an asar archive.
+  Pack {
+    path: PathBuf,
+    /// Output file [default: <path>.asar]
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+  },
+  /// Extract an asar archive into a folder.
+  Unpack {
+    path: PathBuf,
+    /// Destination folder [default: <path> without extension]
+    #[clap(short = 'd', long)]
+    dest: Option<PathBuf>,
+  },
+  /// Inspect, verify and compare asar archives.
+  Asar {
+    #[clap(subcommand)]
+    command: AsarCommand,
   },
-  Resolve {
+  /// Re-issue requests recorded by an Abel server.
+  Replay {
+    /// Server to send requests to [default: http://127.0.0.1:3000]
+    #[clap(short, long)]
+    server: Option<Uri>,
+    /// Send requests to this service instead of the recorded one
+    #[clap(long)]
+    service: Option<String>,
+    /// Number of requests in flight at the same time
+    #[clap(short, long, default_value_t = 1)]
+    concurrency: usize,
     path: PathBuf,
   },
+  /// Show recent requests kept by a server in dev mode.
+  Requests {
+    /// Server to fetch requests from [default: http://127.0.0.1:3000]
+    #[clap(short, long)]
+    server: Option<Uri>,
+    /// Only show requests to this service
+    #[clap(long)]
+    service: Option<String>,
+    /// Number of requests to show
+    #[clap(short = 'n', long, default_value_t = 20)]
+    limit: usize,
+    /// Show headers and bodies as well
+    #[clap(short, long)]
+    verbose: bool,
+  },
+  /// Show lines recently logged by a service on a server.
+  Logs {
+    #[clap(short, long)]
+    server: Option<Uri>,
+    #[clap(short, long)]
+    auth_token: Option<Uuid>,
+    service: String,
+    /// Number of recent lines to show
+    #[clap(short = 'n', long, default_value_t = 100)]
+    lines: usize,
+    /// Keep showing lines as they are logged
+    #[clap(short, long)]
+    follow: bool,
+  },
+  /// Show whether a server is ready, and the status of its services.
+  Status {
+    #[clap(short, long)]
+    server: Option<Uri>,
+    #[clap(short, long)]
+    auth_token: Option<Uuid>,
+  },
+  /// Generate load against a service and report latency percentiles.
+  Bench {
+    /// Server to send requests to, if target is a service name [default: http://127.0.0.1:3000]
+    #[clap(short, long)]
+    server: Option<Uri>,
+    /// Token allowed to invoke the service, so that the server reports
+    /// handler time
+    #[clap(short, long)]
+    auth_token: Option<Uuid>,
+    /// Full URL, or service name and path (e.g. `hello/world`)
+    target: String,
+    #[clap(short = 'X', long, default_value = "GET")]
+    method: hyper::Method,
+    /// Requests sent per second
+    #[clap(short, long, default_value_t = 100)]
+    rps: u32,
+    /// Duration in seconds
+    #[clap(short, long, default_value_t = 10)]
+    duration: u64,
+    /// Maximum requests in flight; requests over this are dropped
+    #[clap(short, long, default_value_t = 256)]
+    concurrency: usize,
+  },
+}
+
+#[derive(Debug, Subcommand)]
+enum AsarCommand {
+  /// List files in an archive.
+  List {
+    path: PathBuf,
+    /// Show sizes and whether files have integrity information
+    #[clap(short, long)]
+    long: bool,
+  },
+  /// Extract an archive into a folder.
+  Extract {
+    path: PathBuf,
+    /// Destination folder [default: <path> without extension]
+    #[clap(short = 'd', long)]
+    dest: Option<PathBuf>,
+  },
+  /// Pack a service folder into an archive.
+  Pack {
+    path: PathBuf,
+    /// Output file [default: <path>.asar]
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+  },
+  /// Check files in an archive against their integrity information.
+  Verify { path: PathBuf },
+  /// Show files added, removed or changed between two archives.
+  Diff { old: PathBuf, new: PathBuf },
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -67,6 +226,8 @@ fn main() -> anyhow::Result<()> {
 
   match args.command {
     Command::Server { args } => {
+      #[cfg(unix)]
+      server::handover::init();
       init_logger();
       info!("Starting abel-server v{ver}");
       block_on(async {
@@ -80,10 +241,14 @@ fn main() -> anyhow::Result<()> {
         }
 
         load_saved_services(&state, &abel_path.join("services")).await?;
-        server::run(config, state).await
+        server::run(config, state, true).await
       })
     }
-    Command::Dev { config, services } => {
+    Command::Dev {
+      config,
+      keep_requests,
+      services,
+    } => {
       init_logger();
       info!("Starting abel-server v{ver} (dev mode)");
 
@@ -98,6 +263,7 @@ fn main() -> anyhow::Result<()> {
       };
       let default_config = Config {
         auth_token: None,
+        request_log: Some(keep_requests),
         ..Default::default()
       };
       let services = services
@@ -112,7 +278,7 @@ fn main() -> anyhow::Result<()> {
         let kinds_and_names = save_services_from_paths(&services, &services_path).await?;
 
         load_saved_services(&state, &services_path).await?;
-        let server_handle = tokio::spawn(server::run(config, state.clone()));
+        let server_handle = tokio::spawn(server::run(config, state.clone(), false));
         let _watcher = init_watcher(state, kinds_and_names, services)?;
         server_handle.await?
       })
@@ -122,15 +288,155 @@ fn main() -> anyhow::Result<()> {
       auth_token,
       path,
       mode,
+      git,
+      reference,
+      name,
+      dry_run,
+      force,
     } => {
-      if let Err(error) = block_on(deploy(server, auth_token, path, mode)) {
+      let source = match (path, git) {
+        (_, Some(repo)) => DeploySource::Git { repo, reference },
+        (Some(path), None) => DeploySource::Path(path),
+        (None, None) => unreachable!(),
+      };
+      let options = DeployOptions {
+        server,
+        auth_token,
+        source,
+        name,
+        mode,
+        dry_run,
+        force,
+      };
+      if let Err(error) = block_on(deploy(options)) {
         println!("{} {error:?}", "error:".red().bold());
         std::process::exit(1);
       }
       Ok(())
     }
     Command::Resolve { path } => {
-      block_on(resolve_dep(path))?;
+      if let Err(error) = block_on(resolve_dep(path)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Vendor { path } => {
+      if let Err(error) = block_on(vendor(path)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Pack { path, output } => {
+      if let Err(error) = block_on(pack(path, output)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Unpack { path, dest } => {
+      if let Err(error) = block_on(unpack(path, dest)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Asar { command } => {
+      let result = block_on(async {
+        match command {
+          AsarCommand::List { path, long } => asar::list(&path, long).await,
+          AsarCommand::Extract { path, dest } => unpack(path, dest).await,
+          AsarCommand::Pack { path, output } => pack(path, output).await,
+          AsarCommand::Verify { path } => asar::verify(&path).await,
+          AsarCommand::Diff { old, new } => asar::diff(&old, &new).await,
+        }
+      });
+      if let Err(error) = result {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Bench {
+      server,
+      auth_token,
+      target,
+      method,
+      rps,
+      duration,
+      concurrency,
+    } => {
+      let options = BenchOptions {
+        server,
+        auth_token,
+        target,
+        method,
+        rps,
+        duration: Duration::from_secs(duration),
+        concurrency,
+      };
+      if let Err(error) = block_on(bench(options)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Requests {
+      server,
+      service,
+      limit,
+      verbose,
+    } => {
+      let options = RequestsOptions {
+        server,
+        service,
+        limit,
+        verbose,
+      };
+      if let Err(error) = block_on(requests(options)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Logs {
+      server,
+      auth_token,
+      service,
+      lines,
+      follow,
+    } => {
+      let options = LogsOptions {
+        server,
+        auth_token,
+        service,
+        lines,
+        follow,
+      };
+      if let Err(error) = block_on(logs(options)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Status { server, auth_token } => {
+      if let Err(error) = block_on(status(server, auth_token)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
+      Ok(())
+    }
+    Command::Replay {
+      server,
+      service,
+      concurrency,
+      path,
+    } => {
+      if let Err(error) = block_on(replay(server, service, concurrency, path)) {
+        println!("{} {error:?}", "error:".red().bold());
+        std::process::exit(1);
+      }
       Ok(())
     }
   }
@@ -0,0 +1,116 @@
+use abel_core::Config;
+use anyhow::{bail, Context};
+use hive_asar::{pack_dir, Archive};
+use std::path::{Path, PathBuf};
+use tokio::fs::{self, File};
+use tokio::io::AsyncWriteExt;
+
+/// Packs a service folder into an asar archive, defaulting to `<folder>.asar`
+/// next to it.
+pub async fn pack(path: PathBuf, output: Option<PathBuf>) -> anyhow::Result<()> {
+  let path = fs::canonicalize(path).await?;
+  check_service_dir(&path).await?;
+  let output = match (output, path.file_name()) {
+    (Some(output), _) => output,
+    (None, Some(name)) => path.with_file_name(format!("{}.asar", name.to_string_lossy())),
+    (None, None) => bail!(
+      "cannot infer output from {}; specify it with -o",
+      path.display()
+    ),
+  };
+
+  let mut file = File::create(&output)
+    .await
+    .with_context(|| format!("failed to create {}", output.display()))?;
+  pack_dir(&path, &mut file)
+    .await
+    .context("failed to pack directory into asar")?;
+  file.flush().await?;
+
+  println!("Packed {} into {}", path.display(), output.display());
+  Ok(())
+}
+
+/// Extracts an asar archive into a folder, defaulting to the archive's path
+/// without extension.
+pub async fn unpack(path: PathBuf, dest: Option<PathBuf>) -> anyhow::Result<()> {
+  let dest = match dest {
+    Some(dest) => dest,
+    None if path.extension().is_some() => path.with_extension(""),
+    None => bail!(
+      "cannot infer destination from {}; specify it with -d",
+      path.display()
+    ),
+  };
+  if dest.exists() && fs::read_dir(&dest).await?.next_entry().await?.is_some() {
+    bail!("{} already exists and is not empty", dest.display());
+  }
+
+  let file = File::open(&path)
+    .await
+    .with_context(|| format!("failed to open {}", path.display()))?;
+  let mut archive = Archive::new(file).await.context("failed to read asar")?;
+  fs::create_dir_all(&dest).await?;
+  archive
+    .extract(&dest)
+    .await
+    .context("failed to extract asar")?;
+
+  println!("Unpacked {} into {}", path.display(), dest.display());
+  Ok(())
+}
+
+/// Checks that a folder is a valid service: it contains `main.lua`, and its
+/// `abel.json`, if any, parses.
+pub async fn check_service_dir(path: &Path) -> anyhow::Result<()> {
+  if !path.join("main.lua").is_file() {
+    bail!("main.lua not found in {}", path.display());
+  }
+  let config_path = path.join("abel.json");
+  if config_path.exists() {
+    let content = fs::read(&config_path).await?;
+    serde_json::from_slice::<Config>(&content)
+      .with_context(|| format!("invalid {}", config_path.display()))?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn test_check_service_dir() {
+    let dir = TempDir::new().unwrap();
+    assert!(check_service_dir(dir.path()).await.is_err());
+    fs::write(dir.path().join("main.lua"), "").await.unwrap();
+    check_service_dir(dir.path()).await.unwrap();
+    fs::write(dir.path().join("abel.json"), r#"{ "aliases": 1 }"#)
+      .await
+      .unwrap();
+    assert!(check_service_dir(dir.path()).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_pack_unpack() {
+    let dir = TempDir::new().unwrap();
+    let service_path = dir.path().join("a");
+    fs::create_dir_all(service_path.join("lib")).await.unwrap();
+    fs::write(service_path.join("main.lua"), "-- main")
+      .await
+      .unwrap();
+    fs::write(service_path.join("lib/b.lua"), "-- b")
+      .await
+      .unwrap();
+
+    pack(service_path, None).await.unwrap();
+    let archive_path = dir.path().join("a.asar");
+    assert!(unpack(archive_path.clone(), None).await.is_err());
+
+    let dest = dir.path().join("unpacked");
+    unpack(archive_path, Some(dest.clone())).await.unwrap();
+    let content = fs::read_to_string(dest.join("lib/b.lua")).await.unwrap();
+    assert_eq!(content, "-- b");
+  }
+}
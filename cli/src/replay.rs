@@ -0,0 +1,109 @@
+use crate::server::RecordedRequest;
+use anyhow::Context;
+use data_encoding::BASE64;
+use futures::{stream, StreamExt};
+use hyper::Uri;
+use owo_colors::OwoColorize;
+use reqwest::{Client, Method};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+use tokio::fs;
+
+/// Re-issues requests recorded by an Abel server against `server`.
+///
+/// Requests are sent `concurrency` at a time; with the default of 1 they are
+/// sent one by one in the recorded order.
+pub async fn replay(
+  server: Option<Uri>,
+  service: Option<String>,
+  concurrency: usize,
+  path: PathBuf,
+) -> anyhow::Result<()> {
+  let server = server
+    .map(|x| x.to_string())
+    .unwrap_or_else(|| "http://127.0.0.1:3000".into());
+  let server = server.trim_end_matches('/');
+
+  let content = fs::read_to_string(&path)
+    .await
+    .with_context(|| format!("failed to read {}", path.display()))?;
+  let records = (content.lines().enumerate())
+    .filter(|(_, line)| !line.trim().is_empty())
+    .map(|(i, line)| {
+      serde_json::from_str::<RecordedRequest>(line)
+        .with_context(|| format!("invalid record at line {}", i + 1))
+    })
+    .collect::<anyhow::Result<Vec<_>>>()?;
+
+  let client = Client::new();
+  let start = Instant::now();
+  let results = stream::iter(records)
+    .map(|record| {
+      let service = service.as_deref().unwrap_or(&record.service);
+      let url = format!("{server}/{service}{}", record.path);
+      let client = &client;
+      async move {
+        let method = Method::from_bytes(record.method.as_bytes())?;
+        let mut builder = client.request(method, &url);
+        for (k, v) in &record.headers {
+          // Let reqwest fill these in for the new connection
+          if !matches!(&**k, "host" | "content-length" | "transfer-encoding") {
+            builder = builder.header(k, v);
+          }
+        }
+        if let Some(body) = &record.body {
+          builder = builder.body(BASE64.decode(body.as_bytes())?);
+        }
+        let start = Instant::now();
+        let resp = builder.send().await?;
+        anyhow::Ok((record.method, url, resp.status(), start.elapsed()))
+      }
+    })
+    .buffered(concurrency.max(1))
+    .collect::<Vec<_>>()
+    .await;
+
+  let total = results.len();
+  let mut failed = 0;
+  for result in results {
+    match result {
+      Ok((method, url, status, elapsed)) => {
+        let status_str = status.as_u16().to_string();
+        if status.is_server_error() {
+          failed += 1;
+          println!(
+            "{method} {url} {} {}",
+            status_str.red(),
+            fmt_duration(elapsed)
+          );
+        } else if status.is_client_error() {
+          println!(
+            "{method} {url} {} {}",
+            status_str.yellow(),
+            fmt_duration(elapsed)
+          );
+        } else {
+          println!(
+            "{method} {url} {} {}",
+            status_str.green(),
+            fmt_duration(elapsed)
+          );
+        }
+      }
+      Err(error) => {
+        failed += 1;
+        println!("{} {error:?}", "error:".red().bold());
+      }
+    }
+  }
+  println!(
+    "Replayed {total} requests in {}, {failed} failed",
+    fmt_duration(start.elapsed())
+  );
+
+  Ok(())
+}
+
+fn fmt_duration(duration: Duration) -> String {
+  format!("{:.2}ms", duration.as_secs_f64() * 1000.)
+}
@@ -0,0 +1,115 @@
+use crate::server::{LoggedBody, LoggedRequest};
+use anyhow::{bail, Context};
+use chrono::{Local, TimeZone};
+use hyper::Uri;
+use owo_colors::OwoColorize;
+use reqwest::Client;
+
+pub struct RequestsOptions {
+  pub server: Option<Uri>,
+  pub service: Option<String>,
+  pub limit: usize,
+  pub verbose: bool,
+}
+
+/// Prints requests kept by a server in dev mode, oldest first.
+pub async fn requests(options: RequestsOptions) -> anyhow::Result<()> {
+  let server = (options.server)
+    .map(|x| x.to_string())
+    .unwrap_or_else(|| "http://127.0.0.1:3000".into());
+  let url = format!("{}/api/v1/__abel/requests", server.trim_end_matches('/'));
+
+  let mut query = vec![("limit", options.limit.to_string())];
+  if let Some(service) = options.service {
+    query.push(("service", service));
+  }
+  let resp = Client::new()
+    .get(&url)
+    .query(&query)
+    .send()
+    .await
+    .with_context(|| format!("failed to connect to {url}"))?;
+  if !resp.status().is_success() {
+    bail!("{url} returned {}: {}", resp.status(), resp.text().await?);
+  }
+  let entries: Vec<LoggedRequest> = resp.json().await?;
+
+  if entries.is_empty() {
+    println!("No requests yet");
+  }
+  for entry in entries.iter().rev() {
+    print_entry(entry, options.verbose);
+  }
+  Ok(())
+}
+
+fn print_entry(entry: &LoggedRequest, verbose: bool) {
+  let time = (Local.timestamp_millis_opt((entry.timestamp * 1000.) as i64))
+    .single()
+    .map(|x| x.format("%H:%M:%S%.3f").to_string())
+    .unwrap_or_default();
+  let status = if entry.status >= 500 {
+    entry.status.red().to_string()
+  } else if entry.status >= 400 {
+    entry.status.yellow().to_string()
+  } else {
+    entry.status.green().to_string()
+  };
+  println!(
+    "{} {} {} {}/{} {status} {}",
+    format!("#{}", entry.id).dimmed(),
+    time.dimmed(),
+    entry.method.bold(),
+    entry.service,
+    entry.path.trim_start_matches('/'),
+    format!("({:.3} ms)", entry.duration_ms).dimmed(),
+  );
+  for line in &entry.logs {
+    let level = match &*line.level {
+      "error" => line.level.red().to_string(),
+      "warn" => line.level.yellow().to_string(),
+      _ => line.level.blue().to_string(),
+    };
+    for message in line.message.lines() {
+      println!("  {level} {message}");
+    }
+    for (k, v) in &line.fields {
+      println!("      {} {v}", format!("{k}:").dimmed());
+    }
+  }
+  if let Some(error) = &entry.error {
+    let mut lines = error.lines();
+    println!("  {} {}", "error".red(), lines.next().unwrap_or(""));
+    for line in lines {
+      println!("        {line}");
+    }
+  }
+
+  if verbose {
+    if let Some(id) = &entry.request_id {
+      println!("  {} {id}", "request id:".dimmed());
+    }
+    println!("  {}", "request".underline());
+    print_message(&entry.request_headers, &entry.request_body);
+    if entry.error.is_none() {
+      println!("  {}", "response".underline());
+      print_message(&entry.response_headers, &entry.response_body);
+    }
+  }
+}
+
+fn print_message(headers: &[(String, String)], body: &LoggedBody) {
+  for (k, v) in headers {
+    println!("    {}: {v}", k.dimmed());
+  }
+  if !body.content.is_empty() {
+    println!();
+    for line in body.content.lines() {
+      println!("    {line}");
+    }
+    if body.truncated {
+      let size = (body.size).map_or_else(|| "more".into(), |x| format!("{x} bytes in total"));
+      println!("    {}", format!("<truncated; {size}>").dimmed());
+    }
+  }
+}
@@ -1,24 +1,84 @@
 use crate::source::DirSource;
-use abel_core::mlua::{ExternalResult, Lua, Table};
+use abel_core::mlua::{Lua, Table};
 use abel_core::source::{Source, SourceUserData};
-use abel_core::{load_create_require, mlua, RemoteInterface};
+use abel_core::{load_create_require, mlua, vendor_path, RemoteInterface, LOCKFILE, VENDOR_DIR};
+use anyhow::{anyhow, Context};
 use data_encoding::HEXLOWER;
+use hyper::Uri;
 use sha2::{Digest, Sha256};
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use tokio::fs;
 
-pub async fn resolve_dep(path: PathBuf) -> mlua::Result<()> {
-  let lua = Lua::new();
-  let create_require = load_create_require(&lua)?;
-  let source = Source::new(DirSource(path));
+/// Loads the service at `path` with every global stubbed out, downloading
+/// the remote modules it requires.
+///
+/// Returns module hashes keyed like `path @uri`, and the modules themselves
+/// as `{ code, uri }`.
+async fn resolve(lua: &Lua, path: PathBuf) -> mlua::Result<(Table<'_>, Table<'_>)> {
+  let create_require = load_create_require(lua)?;
+  let source = Source::new(DirSource::new(path));
   let remote = RemoteInterface::new(None);
   let sha256 = lua.create_function(|lua, s: mlua::String| {
     let out = HEXLOWER.encode(&Sha256::digest(s));
     lua.create_string(&out)
   })?;
-  let hashes: Table = lua
+  lua
     .load(include_str!("resolve_dep.lua"))
     .call_async((SourceUserData(source), remote, create_require, sha256))
-    .await?;
-  println!("{}", serde_json::to_string_pretty(&hashes).to_lua_err()?);
+    .await
+}
+
+/// Writes hashes of the remote modules of the service at `path` to its
+/// lockfile.
+pub async fn resolve_dep(path: PathBuf) -> anyhow::Result<()> {
+  let lua = Lua::new();
+  let (hashes, _) = resolve(&lua, path.clone()).await?;
+  // Sorted, so that the lockfile diffs well
+  let hashes = (hashes.pairs::<String, String>()).collect::<mlua::Result<BTreeMap<_, _>>>()?;
+  let lockfile_path = path.join(LOCKFILE);
+  let mut json = serde_json::to_string_pretty(&hashes)?;
+  json.push('\n');
+  fs::write(&lockfile_path, json)
+    .await
+    .with_context(|| format!("failed to write '{}'", lockfile_path.display()))?;
+  println!(
+    "Locked {} modules in {}",
+    hashes.len(),
+    lockfile_path.display()
+  );
+  Ok(())
+}
+
+/// Downloads the remote modules of the service at `path` into its `vendor`
+/// directory, where they are loaded from instead of the network.
+pub async fn vendor(path: PathBuf) -> anyhow::Result<()> {
+  let lua = Lua::new();
+  let (_, modules) = resolve(&lua, path.clone()).await?;
+  let mut count = 0;
+  for pair in modules.pairs::<String, Table>() {
+    let (key, module) = pair?;
+    let code: mlua::String = module.get("code")?;
+    let uri: String = module.get("uri")?;
+    let vendor_path = (uri.parse::<Uri>().ok().as_ref())
+      .and_then(vendor_path)
+      .ok_or_else(|| anyhow!("cannot vendor '{key}' downloaded from '{uri}'"))?;
+    write_module(&path.join(&vendor_path), code.as_bytes())
+      .await
+      .with_context(|| format!("failed to write '{vendor_path}'"))?;
+    println!("Vendored '{key}' into {vendor_path}");
+    count += 1;
+  }
+  println!(
+    "Vendored {count} modules into {}",
+    path.join(VENDOR_DIR).display()
+  );
   Ok(())
 }
+
+async fn write_module(path: &Path, code: &[u8]) -> std::io::Result<()> {
+  if let Some(parent) = path.parent() {
+    fs::create_dir_all(parent).await?;
+  }
+  fs::write(path, code).await
+}
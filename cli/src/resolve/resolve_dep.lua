@@ -40,12 +40,13 @@ local nop_metatable = {
 setmetatable(nop_table, nop_metatable)
 setmetatable(nop_global_table, nop_metatable)
 
-local hashes = {}
+local hashes, modules = {}, {}
 local remote_wrapper = {
   load = function(_, modname, uri, env)
     local code, req_uri = remote:get(modname, uri)
     local r = #modname > 0 and modname .. " @" .. uri or "@" .. uri
     hashes[r] = sha256(code)
+    modules[r] = { code = code, uri = tostring(req_uri) }
     load(code, "@" .. tostring(req_uri), "t", env)()
     return nop_table
   end
@@ -65,7 +66,7 @@ local stdlibs = {
   "math", "string", "table", "coroutine",
   "os", "utf8", "fs", "http",
   "json", "rand", "crypto", "stream",
-  "testing",
+  "testing", "pagination",
 }
 for _, v in ipairs(stdlibs) do
   package.preload[v] = return_nop
@@ -73,4 +74,4 @@ end
 
 source:load("main.lua", nop_global_table)()
 
-return hashes
+return hashes, modules
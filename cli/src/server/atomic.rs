@@ -0,0 +1,101 @@
+use std::ffi::OsString;
+use std::path::Path;
+use tokio::fs::{self, File, OpenOptions};
+use tokio::io::{self, AsyncWriteExt};
+use uuid::Uuid;
+
+/// Writes `contents` to `path` so that readers, even after a crash, see either
+/// the old file or the complete new one, never a truncated one.
+///
+/// Contents are written to a temporary file next to `path`, flushed to disk,
+/// then renamed over `path`.
+pub async fn write_atomic(path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+  write_atomic_with_mode(path.as_ref(), contents.as_ref(), 0o666).await
+}
+
+/// Same as [`write_atomic`], but only the owner can read or write the file,
+/// for files holding credentials.
+pub async fn write_atomic_private(
+  path: impl AsRef<Path>,
+  contents: impl AsRef<[u8]>,
+) -> io::Result<()> {
+  write_atomic_with_mode(path.as_ref(), contents.as_ref(), 0o600).await
+}
+
+#[cfg_attr(not(unix), allow(unused_variables))]
+async fn write_atomic_with_mode(path: &Path, contents: &[u8], mode: u32) -> io::Result<()> {
+  let parent = match path.parent() {
+    Some(p) if !p.as_os_str().is_empty() => p,
+    _ => Path::new("."),
+  };
+  let file_name = path
+    .file_name()
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+
+  let mut temp_name = OsString::from(".");
+  temp_name.push(file_name);
+  temp_name.push(format!(".{}.tmp", Uuid::new_v4().to_simple()));
+  let temp_path = parent.join(temp_name);
+
+  let result = async {
+    let mut options = OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    options.mode(mode);
+    let mut file = options.open(&temp_path).await?;
+    file.write_all(contents).await?;
+    file.sync_all().await?;
+    drop(file);
+    fs::rename(&temp_path, path).await
+  }
+  .await;
+
+  if result.is_err() {
+    let _ = fs::remove_file(&temp_path).await;
+    return result;
+  }
+
+  // Make the rename itself durable
+  #[cfg(unix)]
+  File::open(parent).await?.sync_all().await?;
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn test_write_atomic() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("a.json");
+    write_atomic(&path, "old").await.unwrap();
+    write_atomic(&path, "new").await.unwrap();
+    assert_eq!(fs::read_to_string(&path).await.unwrap(), "new");
+
+    // No temporary files are left behind
+    let mut entries = fs::read_dir(dir.path()).await.unwrap();
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+      names.push(entry.file_name());
+    }
+    assert_eq!(names, ["a.json"]);
+
+    let error = write_atomic(dir.path().join("b/a.json"), "").await;
+    assert_eq!(error.unwrap_err().kind(), io::ErrorKind::NotFound);
+  }
+
+  #[cfg(unix)]
+  #[tokio::test]
+  async fn test_write_atomic_private() {
+    use std::os::unix::fs::PermissionsExt;
+
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("secrets.json");
+    write_atomic_private(&path, "{}").await.unwrap();
+    let mode = fs::metadata(&path).await.unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+  }
+}
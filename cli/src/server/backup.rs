@@ -0,0 +1,416 @@
+//! Snapshots of services' local storage, taken on `backup.schedule` in their
+//! `abel.json` or on demand.
+//!
+//! Backups are stored as `<abel_path>/backups/<service>/<id>.asar`, where
+//! `<id>` is the UTC time it was taken in, down to milliseconds, with a
+//! `-<n>` suffix should another backup have been taken in the same one.
+//! Backed up paths are kept under `data/` in the archive, and listed in
+//! `backup.json`.
+//!
+//! SQLite databases may be written to while they are backed up, so they are
+//! not copied byte by byte, but snapshotted with `VACUUM INTO`. Their
+//! `-wal`, `-shm` and `-journal` files are left out, as the snapshot already
+//! includes what they hold.
+
+use super::error::Error;
+use super::{Result, ServerState};
+use abel_core::{normalize_path_str, BackupConfig};
+use chrono::{NaiveDateTime, Timelike, Utc};
+use hive_asar::{Archive, Writer};
+use log::{error, info};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::{BTreeSet, HashSet};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncRead, AsyncReadExt};
+use tokio::task::spawn_blocking;
+use uuid::Uuid;
+
+const ID_FORMAT: &str = "%Y%m%dT%H%M%S%3fZ";
+/// Format of IDs of backups taken before they had sub-second precision.
+const SECONDS_ID_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+const SQLITE_HEADER: &[u8] = b"SQLite format 3\0";
+const SQLITE_SIDE_FILES: &[&str] = &["-wal", "-shm", "-journal"];
+
+#[derive(Debug, Serialize)]
+pub struct BackupInfo {
+  pub id: String,
+  pub size: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Manifest {
+  paths: Vec<String>,
+}
+
+/// Runs scheduled backups of all services, checking once every minute.
+pub async fn run_scheduler(state: Arc<ServerState>) {
+  let mut last_minute = None;
+  loop {
+    let now = Utc::now();
+    let until_next_minute = 60. - now.second() as f64 - now.nanosecond() as f64 / 1e9;
+    tokio::time::sleep(Duration::from_secs_f64(until_next_minute.max(0.))).await;
+
+    let now = Utc::now();
+    let minute = now.timestamp() / 60;
+    if last_minute == Some(minute) {
+      continue;
+    }
+    last_minute = Some(minute);
+
+    let due = (state.abel.list_services())
+      .filter_map(|x| {
+        let service = x.upgrade();
+        let config = service.backup().filter(|x| x.schedule.matches(&now))?;
+        Some((service.name().to_string(), config.clone()))
+      })
+      .collect::<Vec<_>>();
+    for (name, config) in due {
+      let state = state.clone();
+      tokio::spawn(async move {
+        match backup(&state, &name, &config).await {
+          Ok(x) => info!("Backed up service '{name}' ({})", x.id),
+          Err(error) => error!("failed to back up service '{name}': {error}"),
+        }
+      });
+    }
+  }
+}
+
+fn backups_path(state: &ServerState, name: &str) -> PathBuf {
+  state.abel_path.join("backups").join(name)
+}
+
+fn storage_path(state: &ServerState, name: &str) -> PathBuf {
+  state.abel_path.join("storage").join(name)
+}
+
+/// Turns `local:db.sqlite` into `db.sqlite`.
+fn parse_path(path: &str) -> Result<String> {
+  match path.split_once(':') {
+    Some(("local", path)) => Ok(normalize_path_str(path)),
+    Some(_) => {
+      let detail = json!({ "path": path });
+      Err(("only local storage can be backed up", detail).into())
+    }
+    None => Ok(normalize_path_str(path)),
+  }
+}
+
+/// Backs up paths in `config` and removes backups beyond `config.keep`.
+pub async fn backup(state: &ServerState, name: &str, config: &BackupConfig) -> Result<BackupInfo> {
+  let paths = (config.paths.iter())
+    .map(String::as_str)
+    .map(parse_path)
+    .collect::<Result<Vec<_>>>()?;
+
+  // Snapshots of SQLite databases are staged here until written out.
+  let staging = state.abel_path.join("tmp").join(Uuid::new_v4().to_string());
+  fs::create_dir_all(&staging).await?;
+  let result = write_backup(state, name, &paths, &staging).await;
+  let _ = fs::remove_dir_all(&staging).await;
+  let id = result?;
+
+  let dir = backups_path(state, name);
+  let backups = list(state, name).await?;
+  let excess = backups.len().saturating_sub(config.keep);
+  for old in &backups[..excess] {
+    fs::remove_file(dir.join(format!("{}.asar", old.id))).await?;
+  }
+
+  let size = fs::metadata(dir.join(format!("{id}.asar"))).await?.len();
+  Ok(BackupInfo { id, size })
+}
+
+/// Writes a new backup of `paths`, returning its ID.
+async fn write_backup(
+  state: &ServerState,
+  name: &str,
+  paths: &[String],
+  staging: &Path,
+) -> Result<String> {
+  let storage = storage_path(state, name);
+  let mut files = BTreeSet::new();
+  for path in paths {
+    collect_files(&mut files, &storage, path).await?;
+  }
+  let mut databases = HashSet::new();
+  for path in &files {
+    if is_sqlite(&storage.join(path)).await? {
+      databases.insert(path.clone());
+    }
+  }
+
+  let mut writer = Writer::<Box<dyn AsyncRead + Send + Unpin>>::new();
+  for (i, path) in files.iter().enumerate() {
+    let is_side_file = (SQLITE_SIDE_FILES.iter())
+      .filter_map(|x| path.strip_suffix(x))
+      .any(|x| databases.contains(x));
+    if is_side_file {
+      continue;
+    }
+    let mut full_path = storage.join(path);
+    if databases.contains(path) {
+      let snapshot = staging.join(i.to_string());
+      snapshot_sqlite(full_path, snapshot.clone()).await?;
+      full_path = snapshot;
+    }
+    let file = File::open(&full_path).await?;
+    let len = file.metadata().await?.len();
+    writer.add(&format!("data/{path}"), Box::new(file), len);
+  }
+  let manifest = serde_json::to_vec(&Manifest {
+    paths: paths.to_vec(),
+  })?;
+  let manifest_len = manifest.len() as _;
+  writer.add("backup.json", Box::new(Cursor::new(manifest)), manifest_len);
+
+  let dir = backups_path(state, name);
+  fs::create_dir_all(&dir).await?;
+  let base_id = Utc::now().format(ID_FORMAT).to_string();
+  let temp_path = dir.join(format!(".{base_id}.{}.tmp", Uuid::new_v4().to_simple()));
+  let result = async {
+    let mut file = File::create(&temp_path).await?;
+    writer.write(&mut file).await?;
+    file.sync_all().await?;
+    // Hard links never replace an existing file, unlike renaming, so backups
+    // taken in the same millisecond keep apart.
+    let mut id = base_id.clone();
+    for n in 2.. {
+      match fs::hard_link(&temp_path, dir.join(format!("{id}.asar"))).await {
+        Ok(()) => break,
+        Err(error) if error.kind() == io::ErrorKind::AlreadyExists => id = format!("{base_id}-{n}"),
+        Err(error) => return Err(error),
+      }
+    }
+    Ok(id)
+  }
+  .await;
+  let _ = fs::remove_file(&temp_path).await;
+  Ok(result?)
+}
+
+/// Collects files under `path` in `storage` into `files`.
+async fn collect_files(files: &mut BTreeSet<String>, storage: &Path, path: &str) -> io::Result<()> {
+  let mut stack = vec![path.to_string()];
+  while let Some(path) = stack.pop() {
+    let full_path = storage.join(&path);
+    let metadata = match fs::metadata(&full_path).await {
+      Ok(x) => x,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+      Err(error) => return Err(error),
+    };
+    if metadata.is_dir() {
+      let mut entries = fs::read_dir(&full_path).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        stack.push(if path.is_empty() {
+          name
+        } else {
+          format!("{path}/{name}")
+        });
+      }
+    } else {
+      files.insert(path);
+    }
+  }
+  Ok(())
+}
+
+async fn is_sqlite(path: &Path) -> io::Result<bool> {
+  let mut header = Vec::with_capacity(SQLITE_HEADER.len());
+  let file = File::open(path).await?;
+  (file.take(SQLITE_HEADER.len() as _))
+    .read_to_end(&mut header)
+    .await?;
+  Ok(header == SQLITE_HEADER)
+}
+
+/// Writes a consistent copy of the SQLite database at `src` to `dest`, even
+/// while others are writing to it.
+async fn snapshot_sqlite(src: PathBuf, dest: PathBuf) -> io::Result<()> {
+  spawn_blocking(move || {
+    use rusqlite::{Connection, OpenFlags};
+    let conn = Connection::open_with_flags(&src, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+    conn.execute("VACUUM INTO ?1", [dest.to_string_lossy()])?;
+    Ok::<_, rusqlite::Error>(())
+  })
+  .await?
+  .map_err(io::Error::other)
+}
+
+/// Whether `id` names a backup.
+fn is_backup_id(id: &str) -> bool {
+  let time = match id.rsplit_once('-') {
+    Some((time, n)) if !n.is_empty() && n.bytes().all(|x| x.is_ascii_digit()) => time,
+    _ => id,
+  };
+  (NaiveDateTime::parse_from_str(time, ID_FORMAT).is_ok())
+    || NaiveDateTime::parse_from_str(time, SECONDS_ID_FORMAT).is_ok()
+}
+
+/// Lists backups of service `name`, oldest first.
+pub async fn list(state: &ServerState, name: &str) -> Result<Vec<BackupInfo>> {
+  let dir = backups_path(state, name);
+  if !dir.exists() {
+    return Ok(Vec::new());
+  }
+  let mut backups = Vec::new();
+  let mut entries = fs::read_dir(&dir).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let file_name = entry.file_name().to_string_lossy().into_owned();
+    let id = match file_name.strip_suffix(".asar") {
+      Some(id) if is_backup_id(id) => id,
+      _ => continue,
+    };
+    backups.push(BackupInfo {
+      id: id.into(),
+      size: entry.metadata().await?.len(),
+    });
+  }
+  backups.sort_by_cached_key(|x| sort_key(&x.id));
+  Ok(backups)
+}
+
+/// Orders backups by the time they were taken, then by their `-<n>` suffix.
+fn sort_key(id: &str) -> (String, u64) {
+  let (time, n) = match id.rsplit_once('-') {
+    Some((time, n)) => (time, n.parse().unwrap_or(0)),
+    None => (id, 0),
+  };
+  // IDs with only seconds end in 'Z' right after them, which would sort
+  // after the same second's ones with milliseconds.
+  let time = match time.strip_suffix('Z') {
+    Some(x) if x.len() == "YYYYMMDDTHHMMSS".len() => format!("{x}000Z"),
+    _ => time.to_string(),
+  };
+  (time, n)
+}
+
+/// Replaces backed up paths in service `name`'s local storage with the ones
+/// in backup `id`. Paths that did not exist at that time are removed.
+///
+/// The service is stopped while restoring, and started again afterwards if
+/// it was running.
+pub async fn restore(state: &ServerState, name: &str, id: &str) -> Result<BackupInfo> {
+  let was_running = state.abel.get_service(name)?.is_running();
+  let backup_path = backups_path(state, name).join(format!("{id}.asar"));
+  if id.contains(['/', '\\', '.']) || !backup_path.exists() {
+    return Err(Error::from((404, "backup not found", json!({ "id": id }))));
+  }
+
+  let mut archive = Archive::new_from_file(&backup_path).await?;
+  let mut manifest = Vec::new();
+  io::AsyncReadExt::read_to_end(&mut archive.get("backup.json").await?, &mut manifest).await?;
+  let Manifest { paths } = serde_json::from_slice(&manifest)?;
+  let temp_dir = state.abel_path.join("tmp").join(Uuid::new_v4().to_string());
+  fs::create_dir(&temp_dir).await?;
+
+  if was_running {
+    state.abel.stop_service(name).await?;
+  }
+
+  let result = async {
+    archive.extract(&temp_dir).await?;
+    let storage = storage_path(state, name);
+    for path in paths {
+      let (src, dest) = (temp_dir.join("data").join(&path), storage.join(&path));
+      match fs::metadata(&dest).await {
+        Ok(x) if x.is_dir() => fs::remove_dir_all(&dest).await?,
+        Ok(_) => fs::remove_file(&dest).await?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error),
+      }
+      if src.exists() {
+        if let Some(parent) = dest.parent() {
+          fs::create_dir_all(parent).await?;
+        }
+        fs::rename(&src, &dest).await?;
+      }
+    }
+    fs::create_dir_all(&storage).await
+  }
+  .await;
+  let _ = fs::remove_dir_all(&temp_dir).await;
+
+  if was_running {
+    state.abel.start_service(name).await?;
+  }
+  result?;
+
+  let size = fs::metadata(&backup_path).await?.len();
+  Ok(BackupInfo {
+    id: id.into(),
+    size,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rusqlite::Connection;
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_backup_id() {
+    let id = Utc::now().format(ID_FORMAT).to_string();
+    assert!(is_backup_id(&id));
+    assert!(is_backup_id(&format!("{id}-2")));
+    assert!(is_backup_id("20220101T000000Z"));
+    assert!(!is_backup_id("20220101T000000123Z-"));
+    assert!(!is_backup_id("latest"));
+  }
+
+  #[test]
+  fn test_backup_order() {
+    let mut ids = vec![
+      "20220101T000001000Z",
+      "20220101T000000500Z-10",
+      "20220101T000000Z",
+      "20220101T000000500Z-2",
+      "20220101T000000500Z",
+    ];
+    ids.sort_by_cached_key(|x| sort_key(x));
+    assert_eq!(ids, [
+      "20220101T000000Z",
+      "20220101T000000500Z",
+      "20220101T000000500Z-2",
+      "20220101T000000500Z-10",
+      "20220101T000001000Z",
+    ]);
+  }
+
+  #[tokio::test]
+  async fn test_snapshot_sqlite() {
+    let dir = TempDir::new().unwrap();
+    let (src, dest) = (dir.path().join("db.sqlite"), dir.path().join("snapshot"));
+    let conn = Connection::open(&src).unwrap();
+    conn.pragma_update(None, "journal_mode", "WAL").unwrap();
+    conn
+      .execute_batch("CREATE TABLE t (x); INSERT INTO t VALUES (42);")
+      .unwrap();
+    assert!(is_sqlite(&src).await.unwrap());
+
+    // Still held open, with the insert only in the WAL.
+    snapshot_sqlite(src, dest.clone()).await.unwrap();
+    let snapshot = Connection::open(&dest).unwrap();
+    let x: i64 = snapshot
+      .query_row("SELECT x FROM t", [], |x| x.get(0))
+      .unwrap();
+    assert_eq!(x, 42);
+    drop(conn);
+  }
+
+  #[tokio::test]
+  async fn test_is_sqlite() {
+    let dir = TempDir::new().unwrap();
+    let path = dir.path().join("a");
+    fs::write(&path, "SQLite").await.unwrap();
+    assert!(!is_sqlite(&path).await.unwrap());
+  }
+}
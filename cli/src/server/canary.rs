@@ -0,0 +1,280 @@
+//! Canary versions of running services.
+//!
+//! A canary is uploaded to `PUT /services/<name>/canary` and receives the
+//! requests matching its rule, while the rest still go to the stable version.
+//! Its source is stored in the service's `canary` folder until it is promoted
+//! to replace the stable version, or rolled back.
+//!
+//! Canaries are not restored on restart; leftover `canary` folders are
+//! removed when loading saved services, and when the service is stopped for
+//! being idle.
+
+use super::types::ServiceDiff;
+use super::upload::{
+  check_reserved, next_source_field, parse_multipart, read_store_service_temp, response,
+  store_source, stored_source_stats, StoredSource, UploadResponse,
+};
+use super::{json_response, versions, Result, ServerState};
+use abel_core::service::Service;
+use abel_core::CanaryRule;
+use abel_core::ErrorKind::NoCanary;
+use futures::TryStreamExt;
+use hyper::{Body, Request, Response, StatusCode};
+use log::{info, warn};
+use owo_colors::OwoColorize;
+use std::ffi::OsString;
+use std::path::{Path, PathBuf};
+use tokio::{fs, io};
+use uuid::Uuid;
+
+fn canary_path(state: &ServerState, name: &str) -> PathBuf {
+  state.abel_path.join("services").join(name).join("canary")
+}
+
+pub async fn upload(state: &ServerState, name: &str, req: Request<Body>) -> Result<Response<Body>> {
+  let (parts, body) = req.into_parts();
+  let rule: CanaryRule = serde_qs::from_str(parts.uri.query().unwrap_or(""))?;
+  if !(0. ..=100.).contains(&rule.percent) {
+    return Err(From::from((
+      "invalid percent",
+      "percent must be a number from 0 to 100",
+    )));
+  }
+
+  let mut multipart = parse_multipart(&parts.headers, body)?;
+  let (kind, source_field) = next_source_field(&mut multipart).await?;
+  let source_stream = source_field.map_err(io::Error::other);
+  let (temp_path, source, config) = read_store_service_temp(state, kind, source_stream).await?;
+  check_reserved(name, &config)?;
+
+  let (service, replaced) = (state.abel)
+    .canary_update_service(name, None, source, config, rule)
+    .await?;
+  let uuid = service.try_upgrade()?.uuid();
+  let stored = StoredSource::File(kind, &temp_path);
+  store_source(&canary_path(state, name), uuid, stored).await?;
+
+  if let Some(replaced) = replaced {
+    info!(
+      "Replaced canary of service '{name}' {}",
+      format!("({} -> {uuid})", replaced.uuid()).dimmed()
+    );
+  } else {
+    info!(
+      "Deployed canary of service '{name}' {}",
+      format!("({uuid})").dimmed()
+    );
+  }
+  status(state, name)
+}
+
+pub fn status(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  let canary = (state.abel.canary(name)).ok_or(NoCanary { name: name.into() })?;
+  json_response(StatusCode::OK, canary)
+}
+
+/// Replaces the stable version with the canary, moving its stored source
+/// into the service's folder.
+pub async fn promote(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let (service, replaced) = state.abel.promote_canary(name)?;
+  let uuid = service.try_upgrade()?.uuid();
+
+  let service_path = state.abel_path.join("services").join(name);
+  let old_stats = stored_source_stats(&service_path).await;
+  promote_stored(&service_path, uuid, state.kept_versions).await?;
+  let stats = old_stats.zip(stored_source_stats(&service_path).await);
+  let diff = ServiceDiff::between(replaced.info(), service.try_upgrade()?.info(), stats);
+
+  info!("Promoted canary of service '{name}'");
+  response(UploadResponse {
+    new_service: Service::Running(service),
+    replaced_service: Some(replaced),
+    errors: Default::default(),
+    unchanged: false,
+    diff: Some(diff),
+  })
+  .await
+}
+
+pub async fn rollback(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let canary = state.abel.rollback_canary(name)?;
+  remove_stored(state, name).await?;
+  info!(
+    "Rolled back canary of service '{name}' {}",
+    format!("({})", canary.uuid()).dimmed()
+  );
+  json_response(StatusCode::OK, canary.info())
+}
+
+/// Replaces the source stored in `service_path` with its canary's, archiving
+/// the current one as a version.
+///
+/// The new folder is assembled next to the current one and swapped in with
+/// two renames, so the service folder never holds a partial source. If the
+/// server dies between them, [`recover`] puts the old folder back on start.
+async fn promote_stored(service_path: &Path, uuid: Uuid, keep: usize) -> io::Result<()> {
+  versions::archive(service_path, uuid, keep).await?;
+
+  let staging = sibling_path(service_path, "promote");
+  if staging.exists() {
+    fs::remove_dir_all(&staging).await?;
+  }
+  fs::rename(service_path.join("canary"), &staging).await?;
+  for dir in ["versions", "checkouts"] {
+    let path = service_path.join(dir);
+    if path.exists() {
+      fs::rename(path, staging.join(dir)).await?;
+    }
+  }
+
+  let old = sibling_path(service_path, "old");
+  fs::rename(service_path, &old).await?;
+  if let Err(error) = fs::rename(&staging, service_path).await {
+    fs::rename(&old, service_path).await?;
+    return Err(error);
+  }
+  fs::remove_dir_all(old).await
+}
+
+/// `.<name>.<suffix>` next to the service folder. Service names never start
+/// with a dot, so these do not clash with services.
+fn sibling_path(service_path: &Path, suffix: &str) -> PathBuf {
+  let mut file_name = OsString::from(".");
+  file_name.push(service_path.file_name().unwrap_or_default());
+  file_name.push(".");
+  file_name.push(suffix);
+  service_path.with_file_name(file_name)
+}
+
+/// Cleans up promotions interrupted by a crash in `services_path`: restores
+/// service folders moved aside but not yet replaced, along with their
+/// versions, and removes the rest.
+pub async fn recover(services_path: &Path) -> io::Result<()> {
+  let mut staged = Vec::new();
+  let mut entries = fs::read_dir(services_path).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let file_name = entry.file_name();
+    let file_name = match file_name.to_str() {
+      Some(x) if x.starts_with('.') => &x[1..],
+      _ => continue,
+    };
+    if let Some(name) = file_name.strip_suffix(".old") {
+      let service_path = services_path.join(name);
+      if service_path.exists() {
+        fs::remove_dir_all(entry.path()).await?;
+      } else {
+        warn!("Restoring service '{name}' from an interrupted canary promotion");
+        fs::rename(entry.path(), service_path).await?;
+      }
+    } else if let Some(name) = file_name.strip_suffix(".promote") {
+      staged.push((services_path.join(name), entry.path()));
+    }
+  }
+
+  for (service_path, staging) in staged {
+    for dir in ["versions", "checkouts"] {
+      let (from, to) = (staging.join(dir), service_path.join(dir));
+      if from.exists() && service_path.exists() && !to.exists() {
+        fs::rename(from, to).await?;
+      }
+    }
+    fs::remove_dir_all(staging).await?;
+  }
+  Ok(())
+}
+
+/// Removes the stored source of service `name`'s canary, if any.
+pub async fn remove_stored(state: &ServerState, name: &str) -> io::Result<()> {
+  let path = canary_path(state, name);
+  if path.exists() {
+    fs::remove_dir_all(path).await?;
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::metadata::Metadata;
+  use tempfile::TempDir;
+
+  async fn write_service(path: &Path, uuid: Uuid, code: &str) {
+    fs::create_dir_all(path).await.unwrap();
+    let metadata = Metadata {
+      uuid,
+      started: true,
+      source_hash: None,
+      source: None,
+      git: None,
+    };
+    metadata.write(&path.join("metadata.json")).await.unwrap();
+    fs::write(path.join("source.lua"), code).await.unwrap();
+  }
+
+  async fn read(path: impl AsRef<Path>) -> String {
+    fs::read_to_string(path).await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_promote_stored() {
+    let dir = TempDir::new().unwrap();
+    let service_path = dir.path().join("a");
+    let (old, new) = (Uuid::new_v4(), Uuid::new_v4());
+    write_service(&service_path, old, "old").await;
+    write_service(&service_path.join("canary"), new, "new").await;
+    fs::create_dir_all(service_path.join("checkouts/x"))
+      .await
+      .unwrap();
+
+    promote_stored(&service_path, new, 1).await.unwrap();
+
+    assert_eq!(read(service_path.join("source.lua")).await, "new");
+    let metadata = Metadata::read(&service_path.join("metadata.json")).await;
+    assert_eq!(metadata.unwrap().uuid, new);
+    let version_path = service_path.join("versions").join(old.to_string());
+    assert_eq!(read(version_path.join("source.lua")).await, "old");
+    assert!(service_path.join("checkouts/x").exists());
+    assert!(!service_path.join("canary").exists());
+
+    let mut entries = fs::read_dir(dir.path()).await.unwrap();
+    let mut names = Vec::new();
+    while let Some(entry) = entries.next_entry().await.unwrap() {
+      names.push(entry.file_name());
+    }
+    assert_eq!(names, ["a"]);
+  }
+
+  #[tokio::test]
+  async fn test_recover_before_swap() {
+    let dir = TempDir::new().unwrap();
+    let (old, new) = (Uuid::new_v4(), Uuid::new_v4());
+    // Moved aside, with versions already moved to the staged folder
+    write_service(&dir.path().join(".a.old"), old, "old").await;
+    write_service(&dir.path().join(".a.promote"), new, "new").await;
+    fs::create_dir_all(dir.path().join(".a.promote/versions/v"))
+      .await
+      .unwrap();
+
+    recover(dir.path()).await.unwrap();
+
+    let service_path = dir.path().join("a");
+    assert_eq!(read(service_path.join("source.lua")).await, "old");
+    assert!(service_path.join("versions/v").exists());
+    assert!(!dir.path().join(".a.old").exists());
+    assert!(!dir.path().join(".a.promote").exists());
+  }
+
+  #[tokio::test]
+  async fn test_recover_after_swap() {
+    let dir = TempDir::new().unwrap();
+    let (old, new) = (Uuid::new_v4(), Uuid::new_v4());
+    write_service(&dir.path().join(".a.old"), old, "old").await;
+    write_service(&dir.path().join("a"), new, "new").await;
+
+    recover(dir.path()).await.unwrap();
+
+    assert_eq!(read(dir.path().join("a/source.lua")).await, "new");
+    assert!(!dir.path().join(".a.old").exists());
+  }
+}
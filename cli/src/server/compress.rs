@@ -0,0 +1,313 @@
+//! Compression of service responses.
+//!
+//! Bodies are compressed as they stream, so responses backed by byte streams
+//! are not buffered as a whole. Each chunk is flushed as it comes, so that
+//! clients do not wait on the encoder for data the service already sent.
+//!
+//! Brotli is preferred when the client accepts it, as it packs text
+//! noticeably tighter; gzip is the fallback every client supports.
+
+use brotli::CompressorWriter;
+use bytes::Bytes;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use futures::stream;
+use hyper::body::HttpBody;
+use hyper::header::{
+  HeaderValue, ACCEPT_ENCODING, ACCEPT_RANGES, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE,
+  ETAG, VARY,
+};
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use std::io::{self, Write};
+
+/// Bodies known to be smaller than this are left as is.
+const MIN_SIZE: u64 = 1024;
+
+/// Brotli quality. Higher ones cost too much CPU for compressing on the fly.
+const BROTLI_QUALITY: u32 = 5;
+
+/// Brotli window size, as a power of two.
+const BROTLI_LGWIN: u32 = 22;
+
+/// Content codings responses are compressed with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+  Brotli,
+  Gzip,
+}
+
+impl Encoding {
+  fn name(self) -> &'static str {
+    match self {
+      Self::Brotli => "br",
+      Self::Gzip => "gzip",
+    }
+  }
+}
+
+/// Picks the encoding the client prefers according to `Accept-Encoding`,
+/// with brotli winning ties.
+pub fn negotiate(headers: &HeaderMap) -> Option<Encoding> {
+  let accept = headers.get(ACCEPT_ENCODING).and_then(|x| x.to_str().ok())?;
+  let (mut br, mut gzip, mut any) = (None, None, None);
+  for x in accept.split(',') {
+    let mut params = x.split(';').map(str::trim);
+    let coding = params.next().unwrap_or("");
+    let q = (params.find_map(|x| match x.split_once('=') {
+      Some((q, v)) if q.eq_ignore_ascii_case("q") => v.parse::<f32>().ok(),
+      _ => None,
+    }))
+    .unwrap_or(1.);
+    let slot = if coding.eq_ignore_ascii_case("br") {
+      &mut br
+    } else if ["gzip", "x-gzip"]
+      .iter()
+      .any(|x| coding.eq_ignore_ascii_case(x))
+    {
+      &mut gzip
+    } else if coding == "*" {
+      &mut any
+    } else {
+      continue;
+    };
+    *slot = Some(q);
+  }
+  let br = br.or(any).unwrap_or(0.);
+  let gzip = gzip.or(any).unwrap_or(0.);
+  if br > 0. && br >= gzip {
+    Some(Encoding::Brotli)
+  } else if gzip > 0. {
+    Some(Encoding::Gzip)
+  } else {
+    None
+  }
+}
+
+/// Compresses `resp`'s body with `encoding`, if any, and if it is worth it,
+/// i.e. the body is not already encoded, not too small, and of a compressible
+/// type.
+pub fn compress(mut resp: Response<Body>, encoding: Option<Encoding>) -> Response<Body> {
+  let encoding = match encoding {
+    Some(x) => x,
+    None => return resp,
+  };
+  let headers = resp.headers();
+  let status = resp.status();
+  if status == StatusCode::NO_CONTENT
+    || status == StatusCode::NOT_MODIFIED
+    || status == StatusCode::PARTIAL_CONTENT
+    || headers.contains_key(CONTENT_ENCODING)
+    || !is_compressible(headers)
+    || matches!(resp.body().size_hint().exact(), Some(x) if x < MIN_SIZE)
+  {
+    return resp;
+  }
+
+  let headers = resp.headers_mut();
+  headers.remove(CONTENT_LENGTH);
+  headers.insert(CONTENT_ENCODING, HeaderValue::from_static(encoding.name()));
+  headers.append(VARY, HeaderValue::from_static("accept-encoding"));
+  // Ranges are of the uncompressed body
+  headers.remove(ACCEPT_RANGES);
+  // Compressed bodies are no longer byte-for-byte the same
+  if let Some(etag) = headers.get(ETAG).and_then(|x| x.to_str().ok()) {
+    if !etag.starts_with("W/") {
+      if let Ok(weak) = HeaderValue::try_from(format!("W/{etag}")) {
+        headers.insert(ETAG, weak);
+      }
+    }
+  }
+  resp.map(|body| encode(body, Encoder::new(encoding)))
+}
+
+fn is_compressible(headers: &HeaderMap) -> bool {
+  let content_type = match headers.get(CONTENT_TYPE).and_then(|x| x.to_str().ok()) {
+    Some(x) => x
+      .split(';')
+      .next()
+      .unwrap_or("")
+      .trim()
+      .to_ascii_lowercase(),
+    None => return false,
+  };
+  // Events need to arrive as soon as they are sent
+  if content_type == "text/event-stream" {
+    return false;
+  }
+  content_type.starts_with("text/")
+    || content_type.ends_with("+json")
+    || content_type.ends_with("+xml")
+    || [
+      "application/json",
+      "application/javascript",
+      "application/xml",
+      "application/wasm",
+      "image/svg+xml",
+    ]
+    .contains(&&*content_type)
+}
+
+enum Encoder {
+  Brotli(Box<CompressorWriter<Vec<u8>>>),
+  Gzip(GzEncoder<Vec<u8>>),
+}
+
+impl Encoder {
+  fn new(encoding: Encoding) -> Self {
+    match encoding {
+      Encoding::Brotli => Self::Brotli(Box::new(CompressorWriter::new(
+        Vec::new(),
+        4096,
+        BROTLI_QUALITY,
+        BROTLI_LGWIN,
+      ))),
+      Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+    }
+  }
+
+  fn get_mut(&mut self) -> &mut Vec<u8> {
+    match self {
+      Self::Brotli(x) => x.get_mut(),
+      Self::Gzip(x) => x.get_mut(),
+    }
+  }
+
+  fn finish(self) -> io::Result<Vec<u8>> {
+    match self {
+      Self::Brotli(x) => Ok(x.into_inner()),
+      Self::Gzip(x) => x.finish(),
+    }
+  }
+}
+
+impl Write for Encoder {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    match self {
+      Self::Brotli(x) => x.write(buf),
+      Self::Gzip(x) => x.write(buf),
+    }
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    match self {
+      Self::Brotli(x) => x.flush(),
+      Self::Gzip(x) => x.flush(),
+    }
+  }
+}
+
+fn encode(body: Body, encoder: Encoder) -> Body {
+  let stream = stream::unfold(Some((body, encoder)), |state| async move {
+    let (mut body, mut encoder) = state?;
+    loop {
+      match body.data().await {
+        Some(Ok(chunk)) => {
+          if let Err(error) = encoder.write_all(&chunk).and_then(|_| encoder.flush()) {
+            return Some((Err(error), None));
+          }
+          let output = std::mem::take(encoder.get_mut());
+          if !output.is_empty() {
+            return Some((Ok(Bytes::from(output)), Some((body, encoder))));
+          }
+        }
+        Some(Err(error)) => return Some((Err(io::Error::other(error)), None)),
+        None => return Some((encoder.finish().map(Bytes::from), None)),
+      }
+    }
+  });
+  Body::wrap_stream(stream)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use brotli::Decompressor;
+  use flate2::read::GzDecoder;
+  use hyper::header::HeaderName;
+  use std::io::Read;
+  use std::time::Duration;
+
+  fn headers(pairs: &[(HeaderName, &'static str)]) -> HeaderMap {
+    (pairs.iter())
+      .map(|(k, v)| (k.clone(), HeaderValue::from_static(v)))
+      .collect()
+  }
+
+  fn decode(encoding: Encoding, bytes: &[u8]) -> String {
+    let mut output = String::new();
+    match encoding {
+      Encoding::Brotli => Decompressor::new(bytes, 4096).read_to_string(&mut output),
+      Encoding::Gzip => GzDecoder::new(bytes).read_to_string(&mut output),
+    }
+    .unwrap();
+    output
+  }
+
+  #[test]
+  fn test_negotiate() {
+    use Encoding::*;
+    let negotiate = |x| negotiate(&headers(&[(ACCEPT_ENCODING, x)]));
+    assert_eq!(negotiate("gzip"), Some(Gzip));
+    assert_eq!(negotiate("gzip, deflate, br"), Some(Brotli));
+    assert_eq!(negotiate("br;q=0.5, GZIP"), Some(Gzip));
+    assert_eq!(negotiate("br;q=0, *"), Some(Gzip));
+    assert_eq!(negotiate("*"), Some(Brotli));
+    assert_eq!(negotiate("gzip;q=0"), None);
+    assert_eq!(negotiate("deflate"), None);
+    assert_eq!(super::negotiate(&HeaderMap::new()), None);
+  }
+
+  #[tokio::test]
+  async fn test_compress() {
+    let text = "hello ".repeat(1000);
+    for encoding in [Encoding::Brotli, Encoding::Gzip] {
+      let mut resp = Response::new(Body::from(text.clone()));
+      *resp.headers_mut() = headers(&[(CONTENT_TYPE, "text/plain"), (ETAG, "\"x\"")]);
+      let resp = compress(resp, Some(encoding));
+      assert_eq!(resp.headers()[CONTENT_ENCODING], encoding.name());
+      assert_eq!(resp.headers()[ETAG], "W/\"x\"");
+      let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+      assert_eq!(decode(encoding, &body), text);
+    }
+  }
+
+  #[test]
+  fn test_compress_skipped() {
+    let compressed = |size, content_type| {
+      let mut resp = Response::new(Body::from("a".repeat(size)));
+      *resp.headers_mut() = headers(&[(CONTENT_TYPE, content_type)]);
+      (compress(resp, Some(Encoding::Gzip)).headers()).contains_key(CONTENT_ENCODING)
+    };
+    assert!(compressed(2048, "application/json"));
+    assert!(!compressed(16, "text/plain"));
+    assert!(!compressed(2048, "image/png"));
+    assert!(!compressed(2048, "text/event-stream"));
+  }
+
+  #[tokio::test]
+  async fn test_compress_flushes_chunks() {
+    // Bytes sent before any of the data, i.e. gzip's 10-byte header
+    for (encoding, header) in [(Encoding::Brotli, 0), (Encoding::Gzip, 10)] {
+      let (mut tx, body) = Body::channel();
+      let mut resp = Response::new(body);
+      *resp.headers_mut() = headers(&[(CONTENT_TYPE, "text/plain")]);
+      let mut body = compress(resp, Some(encoding)).into_body();
+
+      // The first chunk is readable before the stream ends, not just the
+      // header
+      tx.send_data("first".into()).await.unwrap();
+      let first = tokio::time::timeout(Duration::from_secs(1), body.data())
+        .await
+        .expect("chunk not flushed")
+        .unwrap()
+        .unwrap();
+      assert!(first.len() > header);
+
+      tx.send_data("second".into()).await.unwrap();
+      drop(tx);
+      let rest = hyper::body::to_bytes(body).await.unwrap();
+      let body = [&first[..], &rest[..]].concat();
+      assert_eq!(decode(encoding, &body), "firstsecond");
+    }
+  }
+}
@@ -1,8 +1,18 @@
+use super::atomic::write_atomic;
+use super::cors::CorsConfig;
+use super::lockout::LockoutConfig;
+use super::otlp::OtlpConfig;
+use super::record::RecordConfig;
+use super::replica::ReplicaConfig;
+use super::tls::TlsConfig;
+use abel_core::{HttpClientConfig, IdleConfig, RemoteCacheConfig, RemoteCredential, StorageConfig};
 use clap::Parser;
 use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::SocketAddr;
+use std::num::NonZeroUsize;
 use std::path::{Path, PathBuf};
 use tokio::fs;
 use uuid::Uuid;
@@ -40,6 +50,15 @@ pub struct ConfigArgs {
   /// Abel executor pool size [overrides config]
   #[clap(long)]
   pub pool_size: Option<usize>,
+
+  /// Sentry-compatible DSN to report service errors to [overrides config]
+  #[clap(long)]
+  pub report_dsn: Option<String>,
+
+  /// Load remote modules missing from, or not matching, services'
+  /// abel.lock with a warning [overrides config]
+  #[clap(long)]
+  pub allow_unlocked: bool,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -47,6 +66,80 @@ pub struct Config {
   pub listen: SocketAddr,
   pub auth_token: Option<Uuid>,
   pub(crate) pool_size: Option<usize>,
+  pub report_dsn: Option<String>,
+  pub(crate) report_rate_limit: Option<u32>,
+  /// Check asar sources' `integrity` blocks when reading them.
+  pub(crate) verify_asar_integrity: Option<bool>,
+  /// Record incoming service requests for `abel replay`.
+  pub(crate) record: Option<RecordConfig>,
+  /// Serve HTTPS instead of plain HTTP.
+  pub(crate) tls: Option<TlsConfig>,
+  /// Unloading and stopping idle services, unless they override it.
+  pub(crate) idle: Option<IdleConfig>,
+  /// JSON object of secrets services can use, relative to Abel's working
+  /// path. Defaults to `secrets.json`.
+  pub(crate) secrets_file: Option<PathBuf>,
+  /// Names of secrets each service may list in `abel.json`, by service name,
+  /// e.g. `{ "billing": ["STRIPE_KEY"] }`. Services are granted none by
+  /// default.
+  pub(crate) secret_grants: Option<HashMap<String, HashSet<String>>>,
+  /// Follow a primary server as a read replica.
+  pub(crate) replica: Option<ReplicaConfig>,
+  /// Seconds to keep serving after a shutdown signal while `/api/v1/readyz`
+  /// fails, giving load balancers time to stop sending traffic. Defaults to 0.
+  pub(crate) drain_delay: Option<u64>,
+  /// Compress service responses with brotli or gzip for clients that accept
+  /// it, unless services override it with `compress` in `abel.json`.
+  /// Defaults to false.
+  pub(crate) compress: Option<bool>,
+  /// Seconds between runs of services' `abel.health`. Defaults to 30.
+  pub(crate) health_check_interval: Option<u64>,
+  /// Previously deployed sources kept for each service, which it can be
+  /// rolled back to. Defaults to 5.
+  pub(crate) kept_versions: Option<usize>,
+  /// Remove Lua stack tracebacks from errors shown to unauthenticated
+  /// clients, in addition to hiding internal errors. Defaults to false.
+  pub(crate) hide_tracebacks: Option<bool>,
+  /// Isolates each worker keeps loaded, apart from pinned services'.
+  /// Defaults to 16.
+  pub(crate) isolate_cache_size: Option<NonZeroUsize>,
+  /// Recent requests kept in memory and served at `/__abel/requests`. Only
+  /// set by `abel dev`, since it keeps request bodies; it cannot be enabled
+  /// in `config.json`.
+  #[serde(skip)]
+  pub(crate) request_log: Option<usize>,
+  /// Export tracing spans to an OpenTelemetry collector.
+  pub(crate) otlp: Option<OtlpConfig>,
+  /// Default timeout and proxy of services' outbound requests.
+  pub(crate) http_client: Option<HttpClientConfig>,
+  /// Load remote modules missing from, or not matching, services'
+  /// `abel.lock` with a warning, instead of failing. Defaults to false.
+  pub(crate) allow_unlocked: Option<bool>,
+  /// Credentials for fetching remote modules from private hosts, keyed by
+  /// host or `host:port`, e.g. `{ "modules.internal": { "bearer": "..." } }`.
+  pub(crate) remote_credentials: Option<HashMap<String, RemoteCredential>>,
+  /// Size and age limits of the remote module cache. Unlimited by default.
+  pub(crate) remote_cache: Option<RemoteCacheConfig>,
+  /// Origins allowed to call the management API from browsers. Disabled by
+  /// default.
+  pub(crate) management_cors: Option<CorsConfig>,
+  /// Remote backend of services' local storage, e.g. an S3 bucket, in which
+  /// each service has its own prefix. Local storage only lives on disk by
+  /// default.
+  pub(crate) storage: Option<StorageConfig>,
+  /// Seconds a token stays valid after it is rotated, unless the rotation
+  /// request sets its own. Tokens rotating themselves may only ask for less.
+  /// Defaults to 86400, i.e. a day.
+  pub(crate) token_grace_period: Option<u64>,
+  /// Locking out clients after repeated failed authentication. Enabled with
+  /// default thresholds unless `max_failures` is 0. Behind a reverse proxy,
+  /// list it in `trusted_proxies`, or every client is locked out together.
+  pub(crate) auth_lockout: Option<LockoutConfig>,
+  /// Derive UUIDs of deployed services from their name and source, instead
+  /// of generating random ones, so that the same artifact has the same UUID
+  /// on every server. Deploying the source a running service already runs
+  /// then leaves it as is. Defaults to false.
+  pub(crate) deterministic_uuids: Option<bool>,
 }
 
 impl Default for Config {
@@ -55,6 +148,32 @@ impl Default for Config {
       listen: ([127, 0, 0, 1], 3000).into(),
       auth_token: Some(Uuid::new_v4()),
       pool_size: None,
+      report_dsn: None,
+      report_rate_limit: None,
+      verify_asar_integrity: None,
+      record: None,
+      tls: None,
+      idle: None,
+      secrets_file: None,
+      secret_grants: None,
+      replica: None,
+      drain_delay: None,
+      compress: None,
+      health_check_interval: None,
+      kept_versions: None,
+      hide_tracebacks: None,
+      isolate_cache_size: None,
+      request_log: None,
+      otlp: None,
+      http_client: None,
+      allow_unlocked: None,
+      remote_credentials: None,
+      remote_cache: None,
+      management_cors: None,
+      storage: None,
+      token_grace_period: None,
+      auth_lockout: None,
+      deterministic_uuids: None,
     }
   }
 }
@@ -63,7 +182,7 @@ impl Config {
   async fn init(path: impl AsRef<Path>) -> io::Result<Self> {
     let default_config = Self::default();
     let content = serde_json::to_string_pretty(&default_config)?;
-    fs::write(path, content.as_bytes()).await?;
+    write_atomic(path, content.as_bytes()).await?;
     Ok(default_config)
   }
 
@@ -84,10 +203,19 @@ impl Config {
     args.listen.map(|x| self.listen = x);
     args.auth_token.map(|x| self.auth_token = Some(x));
     args.pool_size.map(|x| self.pool_size = Some(x));
+    args.report_dsn.map(|x| self.report_dsn = Some(x));
+    if args.allow_unlocked {
+      self.allow_unlocked = Some(true);
+    }
     self
   }
 
   pub fn pool_size(&self) -> usize {
     self.pool_size.unwrap_or(*HALF_NUM_CPUS)
   }
+
+  /// Maximum number of error reports sent per minute.
+  pub fn report_rate_limit(&self) -> u32 {
+    self.report_rate_limit.unwrap_or(30)
+  }
 }
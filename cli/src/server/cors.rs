@@ -0,0 +1,205 @@
+//! CORS for the management API, so that dashboards hosted on other origins
+//! can call it from browsers.
+//!
+//! Service endpoints are not covered; services send their own CORS headers.
+
+use super::error::Error;
+use anyhow::bail;
+use hyper::header::{
+  HeaderValue, ACCESS_CONTROL_ALLOW_CREDENTIALS, ACCESS_CONTROL_ALLOW_HEADERS,
+  ACCESS_CONTROL_ALLOW_METHODS, ACCESS_CONTROL_ALLOW_ORIGIN, ACCESS_CONTROL_EXPOSE_HEADERS,
+  ACCESS_CONTROL_MAX_AGE, ACCESS_CONTROL_REQUEST_METHOD, ORIGIN, VARY,
+};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+
+const ALLOW_METHODS: &str = "GET, POST, PUT, PATCH, DELETE";
+const ALLOW_HEADERS: &str = "authorization, content-type, idempotency-key";
+const EXPOSE_HEADERS: &str = "x-request-id, retry-after, content-disposition, www-authenticate";
+
+/// `management_cors` in `config.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorsConfig {
+  /// Origins allowed to call the management API, e.g.
+  /// `https://dash.example.com`, or `*` for any.
+  pub allow_origins: Vec<String>,
+  /// Let browsers include cookies and HTTP authentication. Not needed for
+  /// the `Authorization` header set by scripts, and not allowed with `*`.
+  #[serde(default)]
+  pub allow_credentials: bool,
+  /// Seconds browsers may cache preflight results. Defaults to 600.
+  pub max_age: Option<u64>,
+}
+
+pub struct Cors {
+  config: CorsConfig,
+  any_origin: bool,
+}
+
+impl Cors {
+  pub fn new(config: CorsConfig) -> anyhow::Result<Self> {
+    let any_origin = config.allow_origins.iter().any(|x| x == "*");
+    if any_origin && config.allow_credentials {
+      bail!("`management_cors` cannot allow credentials from any origin");
+    }
+    Ok(Self { config, any_origin })
+  }
+
+  /// Value of `Access-Control-Allow-Origin` for `req`, if it is a
+  /// cross-origin request from an allowed origin.
+  pub fn allowed_origin(&self, req: &Request<Body>) -> Result<Option<HeaderValue>, Error> {
+    let origin = match req.headers().get(ORIGIN) {
+      Some(x) => x,
+      None => return Ok(None),
+    };
+    if self.any_origin {
+      Ok(Some(HeaderValue::from_static("*")))
+    } else if (self.config.allow_origins.iter()).any(|x| x.as_bytes() == origin.as_bytes()) {
+      Ok(Some(origin.clone()))
+    } else {
+      let origin = String::from_utf8_lossy(origin.as_bytes());
+      Err(Error::from((
+        403,
+        "origin not allowed",
+        json!({ "origin": origin }),
+      )))
+    }
+  }
+
+  pub fn is_preflight(&self, req: &Request<Body>) -> bool {
+    req.method() == Method::OPTIONS && req.headers().contains_key(ACCESS_CONTROL_REQUEST_METHOD)
+  }
+
+  /// Response to a preflight request, to which [`apply`](Self::apply) adds
+  /// the allowed origin.
+  pub fn preflight(&self) -> Response<Body> {
+    let mut resp = Response::new(Body::empty());
+    *resp.status_mut() = StatusCode::NO_CONTENT;
+    let headers = resp.headers_mut();
+    let max_age = self.config.max_age.unwrap_or(600);
+    headers.insert(
+      ACCESS_CONTROL_ALLOW_METHODS,
+      HeaderValue::from_static(ALLOW_METHODS),
+    );
+    headers.insert(
+      ACCESS_CONTROL_ALLOW_HEADERS,
+      HeaderValue::from_static(ALLOW_HEADERS),
+    );
+    headers.insert(ACCESS_CONTROL_MAX_AGE, max_age.into());
+    resp
+  }
+
+  /// Adds CORS headers for `origin` returned by
+  /// [`allowed_origin`](Self::allowed_origin) to `resp`.
+  pub fn apply(&self, origin: Option<HeaderValue>, resp: &mut Response<Body>) {
+    let headers = resp.headers_mut();
+    if !self.any_origin {
+      headers.append(VARY, HeaderValue::from_static("origin"));
+    }
+    let origin = match origin {
+      Some(x) => x,
+      None => return,
+    };
+    headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+    headers.insert(
+      ACCESS_CONTROL_EXPOSE_HEADERS,
+      HeaderValue::from_static(EXPOSE_HEADERS),
+    );
+    if self.config.allow_credentials {
+      headers.insert(
+        ACCESS_CONTROL_ALLOW_CREDENTIALS,
+        HeaderValue::from_static("true"),
+      );
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::Config;
+  use crate::server::handle;
+  use crate::server::tests::state;
+  use tempfile::TempDir;
+
+  fn cors(allow_origins: &[&str], allow_credentials: bool) -> anyhow::Result<Cors> {
+    Cors::new(CorsConfig {
+      allow_origins: allow_origins.iter().map(|x| x.to_string()).collect(),
+      allow_credentials,
+      max_age: None,
+    })
+  }
+
+  fn request(method: Method, path: &str, origin: Option<&str>) -> Request<Body> {
+    let mut req = Request::builder().method(method).uri(path);
+    if let Some(origin) = origin {
+      req = req.header(ORIGIN, origin);
+    }
+    req.body(Body::empty()).unwrap()
+  }
+
+  #[test]
+  fn test_allowed_origin() {
+    let a = cors(&["https://a.example"], true).unwrap();
+    let allowed = |origin| a.allowed_origin(&request(Method::GET, "/", origin));
+    assert_eq!(allowed(None).unwrap(), None);
+    assert_eq!(
+      allowed(Some("https://a.example")).unwrap().unwrap(),
+      "https://a.example"
+    );
+    let error = allowed(Some("https://b.example")).unwrap_err();
+    assert_eq!(error.into_status_and_body().0, 403);
+
+    let mut resp = Response::new(Body::empty());
+    a.apply(allowed(Some("https://a.example")).unwrap(), &mut resp);
+    assert_eq!(resp.headers()[VARY], "origin");
+    assert_eq!(resp.headers()[ACCESS_CONTROL_ALLOW_CREDENTIALS], "true");
+
+    let any = cors(&["*"], false).unwrap();
+    let origin = any.allowed_origin(&request(Method::GET, "/", Some("https://b.example")));
+    assert_eq!(origin.unwrap().unwrap(), "*");
+    assert!(cors(&["*"], true).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_management_cors() {
+    let dir = TempDir::new().unwrap();
+    let config = Config {
+      management_cors: Some(CorsConfig {
+        allow_origins: vec!["https://a.example".into()],
+        allow_credentials: false,
+        max_age: Some(60),
+      }),
+      ..Default::default()
+    };
+    let state = state(dir.path(), config).await;
+
+    let mut req = request(
+      Method::OPTIONS,
+      "/api/v1/services",
+      Some("https://a.example"),
+    );
+    (req.headers_mut()).insert(
+      ACCESS_CONTROL_REQUEST_METHOD,
+      HeaderValue::from_static("PUT"),
+    );
+    let resp = handle(state.clone(), req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::NO_CONTENT);
+    assert_eq!(
+      resp.headers()[ACCESS_CONTROL_ALLOW_ORIGIN],
+      "https://a.example"
+    );
+    assert_eq!(resp.headers()[ACCESS_CONTROL_MAX_AGE], "60");
+
+    let req = request(Method::GET, "/services", Some("https://b.example"));
+    let resp = handle(state.clone(), req).await.unwrap();
+    assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+    // Services send their own CORS headers
+    let req = request(Method::GET, "/a", Some("https://b.example"));
+    let resp = handle(state, req).await.unwrap();
+    assert_ne!(resp.status(), StatusCode::FORBIDDEN);
+    assert!(!resp.headers().contains_key(ACCESS_CONTROL_ALLOW_ORIGIN));
+  }
+}
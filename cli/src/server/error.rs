@@ -32,6 +32,17 @@ impl Error {
     &self.kind
   }
 
+  /// Seconds the client should wait before retrying, sent as `Retry-After`.
+  pub fn retry_after(&self) -> Option<u64> {
+    match &self.kind {
+      ErrorKind::Abel(error) => match error.kind() {
+        abel_core::ErrorKind::RateLimited { retry_after, .. } => Some(*retry_after),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+
   pub fn into_status_and_body(self) -> (StatusCode, JsonError<'static>) {
     use ErrorKind::*;
     let (status, error, detail, _backtrace) = match self.kind {
@@ -76,10 +87,81 @@ impl Error {
       _ => panic!("expected null, string or object as error detail"),
     };
 
+    let detail = detail.map(|mut x| {
+      limit_object(&mut x, 1);
+      x
+    });
     (status, JsonError { error, detail })
   }
 }
 
+/// Nesting depth of error detail beyond which values are replaced.
+const MAX_DETAIL_DEPTH: usize = 8;
+/// Bytes of a string in error detail beyond which it is truncated.
+const MAX_DETAIL_STRING: usize = 4096;
+/// Entries of an array or object in error detail beyond which the rest are
+/// dropped.
+const MAX_DETAIL_ENTRIES: usize = 64;
+
+/// Caps the size of error detail, which may come from services, marking
+/// where it is truncated.
+fn limit_detail(value: &mut serde_json::Value, depth: usize) {
+  use serde_json::Value;
+  match value {
+    Value::Array(_) | Value::Object(_) if depth >= MAX_DETAIL_DEPTH => {
+      *value = "<too deep>".into();
+    }
+    Value::String(s) if s.len() > MAX_DETAIL_STRING => {
+      let mut end = MAX_DETAIL_STRING;
+      while !s.is_char_boundary(end) {
+        end -= 1;
+      }
+      let truncated = s.len() - end;
+      s.truncate(end);
+      s.push_str(&format!("<{truncated} bytes truncated>"));
+    }
+    Value::Array(a) => {
+      if a.len() > MAX_DETAIL_ENTRIES {
+        let truncated = a.len() - MAX_DETAIL_ENTRIES;
+        a.truncate(MAX_DETAIL_ENTRIES);
+        a.push(format!("<{truncated} items truncated>").into());
+      }
+      (a.iter_mut()).for_each(|x| limit_detail(x, depth + 1));
+    }
+    Value::Object(o) => limit_object(o, depth + 1),
+    _ => {}
+  }
+}
+
+fn limit_object(o: &mut serde_json::Map<String, serde_json::Value>, depth: usize) {
+  if o.len() > MAX_DETAIL_ENTRIES {
+    let truncated = o.len() - MAX_DETAIL_ENTRIES;
+    let keys = o
+      .keys()
+      .skip(MAX_DETAIL_ENTRIES)
+      .cloned()
+      .collect::<Vec<_>>();
+    keys.iter().for_each(|x| drop(o.remove(x)));
+    o.insert("<truncated>".into(), format!("{truncated} entries").into());
+  }
+  (o.values_mut()).for_each(|x| limit_detail(x, depth));
+}
+
+/// Removes Lua stack tracebacks from strings in error detail.
+fn strip_tracebacks(value: &mut serde_json::Value) {
+  use serde_json::Value;
+  match value {
+    Value::String(s) => {
+      if let Some(i) = s.find("\nstack traceback:") {
+        s.truncate(i);
+      }
+    }
+    Value::Array(a) => a.iter_mut().for_each(strip_tracebacks),
+    Value::Object(o) => o.values_mut().for_each(strip_tracebacks),
+    _ => {}
+  }
+}
+
 impl<E: Into<ErrorKind>> From<E> for Error {
   fn from(x: E) -> Self {
     Self {
@@ -178,6 +260,10 @@ pub enum ErrorKind {
   #[strum(props(status = "401", error = "unauthorized"))]
   Unauthorized,
 
+  #[error("forbidden: missing scope '{required}'")]
+  #[strum(props(status = "403", error = "forbidden"))]
+  Forbidden { required: String },
+
   // Errors when reading multipart body are *mostly* client-side, so they all
   // currently use 400 Bad Request for simplicity.
   //
@@ -265,6 +351,7 @@ pub fn method_not_allowed(expected: &[&'static str], got: &Method) -> Error {
 pub struct ErrorAuthWrapper {
   inner: Error,
   uuid: Option<Uuid>,
+  hide_tracebacks: bool,
 }
 
 impl ErrorAuthWrapper {
@@ -275,7 +362,17 @@ impl ErrorAuthWrapper {
     } else {
       None
     };
-    Self { inner, uuid }
+    Self {
+      inner,
+      uuid,
+      hide_tracebacks: false,
+    }
+  }
+
+  /// Also removes Lua stack tracebacks from errors shown in full.
+  pub fn hide_tracebacks(mut self, hide: bool) -> Self {
+    self.hide_tracebacks = hide;
+    self
   }
 
   pub fn uuid(&self) -> Option<Uuid> {
@@ -303,8 +400,84 @@ impl From<ErrorAuthWrapper> for Response<Body> {
           }
         }),
       )
+    } else if error.hide_tracebacks {
+      let (status, mut body) = error.inner.into_status_and_body();
+      if let Some(detail) = &mut body.detail {
+        detail.values_mut().for_each(strip_tracebacks);
+      }
+      json_response_raw(status, body)
     } else {
       error.inner.into()
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::Value;
+
+  #[test]
+  fn test_limit_detail() {
+    let mut nested = json!("end");
+    for _ in 0..10 {
+      nested = json!({ "a": nested });
+    }
+    let long = format!("a{}", "é".repeat(MAX_DETAIL_STRING / 2));
+    let mut detail = json!({
+      "nested": nested,
+      "long": long,
+      "array": (0..70).collect::<Vec<_>>(),
+      "object": (0..70).map(|x| (x.to_string(), Value::from(x))).collect::<serde_json::Map<_, _>>(),
+    });
+    limit_object(detail.as_object_mut().unwrap(), 1);
+
+    let too_deep = format!("/nested{}", "/a".repeat(MAX_DETAIL_DEPTH - 1));
+    assert_eq!(detail.pointer(&too_deep).unwrap(), "<too deep>");
+
+    // Truncated at a character boundary
+    let long = detail["long"].as_str().unwrap();
+    assert_eq!(
+      long.len(),
+      MAX_DETAIL_STRING - 1 + "<2 bytes truncated>".len()
+    );
+    assert!(long.ends_with("é<2 bytes truncated>"));
+
+    let array = detail["array"].as_array().unwrap();
+    assert_eq!(array.len(), MAX_DETAIL_ENTRIES + 1);
+    assert_eq!(array[MAX_DETAIL_ENTRIES - 1], MAX_DETAIL_ENTRIES - 1);
+    assert_eq!(array[MAX_DETAIL_ENTRIES], "<6 items truncated>");
+
+    let object = detail["object"].as_object().unwrap();
+    assert_eq!(object.len(), MAX_DETAIL_ENTRIES + 1);
+    assert!(object.contains_key(&(MAX_DETAIL_ENTRIES - 1).to_string()));
+    assert_eq!(object["<truncated>"], "6 entries");
+  }
+
+  #[test]
+  fn test_limit_detail_untouched() {
+    let mut detail = json!({ "msg": "error", "list": [1, { "a": null }] });
+    let expected = detail.clone();
+    limit_object(detail.as_object_mut().unwrap(), 1);
+    assert_eq!(detail, expected);
+  }
+
+  #[test]
+  fn test_strip_tracebacks() {
+    let traceback = "boom\nstack traceback:\n\t[C]: in ?";
+    let mut detail = json!({
+      "msg": traceback,
+      "errors": [traceback, { "inner": traceback }],
+      "other": "no traceback\nhere",
+    });
+    strip_tracebacks(&mut detail);
+    assert_eq!(
+      detail,
+      json!({
+        "msg": "boom",
+        "errors": ["boom", { "inner": "boom" }],
+        "other": "no traceback\nhere",
+      })
+    );
+  }
+}
@@ -0,0 +1,193 @@
+//! Rejecting requests by services' `filters` before they reach Lua.
+
+use super::{Error, Result};
+use abel_core::{normalize_path_str, RequestFilters};
+use futures::{future, TryStreamExt};
+use hyper::body::Bytes;
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, Request};
+use serde_json::json;
+use std::io;
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+/// Largest request body read into memory before calling a service, e.g. for
+/// recording or validation.
+pub const MAX_BUFFERED_BODY: u64 = 64 * 1024 * 1024;
+
+/// Checks `req` against `filters`, returning it with its body limited to
+/// `max_body_size` if it passes.
+pub fn apply(
+  filters: &RequestFilters,
+  sub_path: &str,
+  req: Request<Body>,
+) -> Result<Request<Body>> {
+  let method = req.method().as_str();
+  if (filters.block_methods.iter()).any(|x| x.eq_ignore_ascii_case(method)) {
+    return Err((405, "method blocked", json!({ "method": method })).into());
+  }
+  let path = normalize_path_str(sub_path);
+  if (filters.block_paths.iter()).any(|x| is_under(&path, &normalize_path_str(x))) {
+    return Err((404, "path not found", json!({ "path": sub_path })).into());
+  }
+  for rule in &filters.block_headers {
+    let contains = rule.contains.to_ascii_lowercase();
+    let matched = (req.headers().get_all(&*rule.name).iter())
+      .any(|x| matches!(x.to_str(), Ok(x) if x.to_ascii_lowercase().contains(&contains)));
+    if matched {
+      return Err((403, "request blocked", json!({ "header": rule.name })).into());
+    }
+  }
+
+  let max = match filters.max_body_size {
+    Some(max) => max,
+    None => return Ok(req),
+  };
+  let len = (req.headers().get(CONTENT_LENGTH))
+    .and_then(|x| x.to_str().ok())
+    .and_then(|x| x.parse::<u64>().ok());
+  match len {
+    Some(len) if len > max => Err(too_large(max)),
+    Some(_) => Ok(req),
+    // Bodies of unknown length are cut off once they grow too large
+    None => Ok(req.map(|body| {
+      let mut read = 0;
+      Body::wrap_stream(body.map_err(BoxError::from).and_then(move |chunk| {
+        read += chunk.len() as u64;
+        let result = if read > max {
+          let msg = format!("request body larger than {max} bytes");
+          Err(io::Error::other(msg).into())
+        } else {
+          Ok(chunk)
+        };
+        future::ready(result)
+      }))
+    })),
+  }
+}
+
+/// Whether normalized `path` is `prefix` or under it, comparing whole
+/// segments, so that `/admin` covers `/admin/x` but not `/administrator`.
+fn is_under(path: &str, prefix: &str) -> bool {
+  match path.strip_prefix(prefix) {
+    Some(rest) => prefix.is_empty() || rest.is_empty() || rest.starts_with('/'),
+    None => false,
+  }
+}
+
+/// Reads `body` into memory, failing with 413 once it is larger than `max`.
+pub async fn buffer(mut body: Body, max: u64) -> Result<Bytes> {
+  let mut buf = Vec::new();
+  while let Some(chunk) = body.try_next().await.map_err(|error| {
+    Error::from((
+      400,
+      "failed to read request body",
+      json!({ "msg": error.to_string() }),
+    ))
+  })? {
+    if (buf.len() + chunk.len()) as u64 > max {
+      return Err(too_large(max));
+    }
+    buf.extend_from_slice(&chunk);
+  }
+  Ok(buf.into())
+}
+
+fn too_large(max: u64) -> Error {
+  Error::from((413, "request body too large", json!({ "max": max })))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use abel_core::HeaderFilter;
+  use hyper::StatusCode;
+
+  fn filters() -> RequestFilters {
+    RequestFilters {
+      block_methods: vec!["TRACE".into()],
+      block_paths: vec!["/wp-admin".into(), "/.git/".into()],
+      block_headers: vec![HeaderFilter {
+        name: "user-agent".into(),
+        contains: "BadBot".into(),
+      }],
+      max_body_size: Some(4),
+    }
+  }
+
+  fn status(result: Result<Request<Body>>) -> StatusCode {
+    match result {
+      Ok(_) => StatusCode::OK,
+      Err(error) => error.kind().status(),
+    }
+  }
+
+  fn get(path: &str) -> StatusCode {
+    status(apply(&filters(), path, Request::new(Body::empty())))
+  }
+
+  #[test]
+  fn test_block_paths() {
+    assert_eq!(get("/wp-admin"), StatusCode::NOT_FOUND);
+    assert_eq!(get("/wp-admin/"), StatusCode::NOT_FOUND);
+    assert_eq!(get("/wp-admin/setup.php"), StatusCode::NOT_FOUND);
+    assert_eq!(get("/.git/config"), StatusCode::NOT_FOUND);
+    assert_eq!(get("/wp-administrator"), StatusCode::OK);
+    assert_eq!(get("/a/wp-admin"), StatusCode::OK);
+    assert_eq!(get("/"), StatusCode::OK);
+  }
+
+  #[test]
+  fn test_block_paths_normalized() {
+    assert_eq!(get("//wp-admin"), StatusCode::NOT_FOUND);
+    assert_eq!(get("/./wp-admin"), StatusCode::NOT_FOUND);
+    assert_eq!(get("/x/../wp-admin/"), StatusCode::NOT_FOUND);
+    assert_eq!(get("\\wp-admin"), StatusCode::NOT_FOUND);
+  }
+
+  #[test]
+  fn test_block_methods_and_headers() {
+    let req = Request::builder()
+      .method("trace")
+      .body(Body::empty())
+      .unwrap();
+    assert_eq!(
+      status(apply(&filters(), "/", req)),
+      StatusCode::METHOD_NOT_ALLOWED
+    );
+
+    let req = (Request::builder())
+      .header("user-agent", "Mozilla/5.0 (compatible; badbot/1.0)")
+      .body(Body::empty())
+      .unwrap();
+    assert_eq!(status(apply(&filters(), "/", req)), StatusCode::FORBIDDEN);
+  }
+
+  #[tokio::test]
+  async fn test_max_body_size() {
+    let req = (Request::builder())
+      .header(CONTENT_LENGTH, "5")
+      .body(Body::from("hello"))
+      .unwrap();
+    assert_eq!(
+      status(apply(&filters(), "/", req)),
+      StatusCode::PAYLOAD_TOO_LARGE
+    );
+
+    // Without a length, the body fails once it grows too large.
+    let (mut tx, body) = Body::channel();
+    let req = apply(&filters(), "/", Request::new(body)).unwrap();
+    tokio::spawn(async move {
+      let _ = tx.send_data("abc".into()).await;
+      let _ = tx.send_data("de".into()).await;
+    });
+    assert!(hyper::body::to_bytes(req.into_body()).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_buffer() {
+    assert_eq!(&buffer(Body::from("abcd"), 4).await.unwrap()[..], b"abcd");
+    let error = buffer(Body::from("abcde"), 4).await.unwrap_err();
+    assert_eq!(error.kind().status(), StatusCode::PAYLOAD_TOO_LARGE);
+  }
+}
@@ -0,0 +1,270 @@
+//! Services deployed straight from git repositories.
+//!
+//! A ref is shallowly fetched and checked out into
+//! `services/<name>/checkouts/<commit>`, which is then opened through the
+//! `file://` source backend like any other source URI. The checkout's tree
+//! hash is stored as the service's source hash, so that fetching the same
+//! tree again does not update the service.
+//!
+//! Only `https://` and `ssh://` repositories can be fetched, and symlinks are
+//! checked out as plain files, so that neither can reach the server's own
+//! files.
+
+use super::metadata::Metadata;
+use super::{Error, Result};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
+use tokio::fs;
+use tokio::process::Command;
+
+/// Where a service's source was fetched from.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct GitInfo {
+  pub repo: String,
+  #[serde(rename = "ref")]
+  pub reference: String,
+  pub commit: String,
+}
+
+/// Configuration git always runs with.
+const GIT_CONFIG: &[&str] = &[
+  "-c",
+  "protocol.allow=never",
+  "-c",
+  "protocol.https.allow=always",
+  "-c",
+  "protocol.ssh.allow=always",
+  "-c",
+  "core.symlinks=false",
+];
+
+/// A ref of a repository, shallowly fetched into a folder.
+pub struct GitSource {
+  path: PathBuf,
+  pub commit: String,
+  pub tree: String,
+}
+
+impl GitSource {
+  /// Fetches `reference` of `repo` into a new repository at `path`, without
+  /// checking it out yet.
+  pub async fn fetch(repo: &str, reference: &str, path: PathBuf) -> Result<Self> {
+    if !repo.starts_with("https://") && !repo.starts_with("ssh://") {
+      return Err(Error::from((
+        "unsupported repository",
+        json!({ "msg": "repository must be an https:// or ssh:// URL", "repo": repo }),
+      )));
+    }
+    fs::create_dir_all(&path).await?;
+    let result = async {
+      git(&path, ["init", "-q"]).await?;
+      git(&path, [
+        "fetch", "-q", "--depth", "1", "--", repo, reference,
+      ])
+      .await?;
+      let commit = git(&path, ["rev-parse", "FETCH_HEAD"]).await?;
+      let tree = git(&path, ["rev-parse", "FETCH_HEAD^{tree}"]).await?;
+      Ok((commit, tree))
+    }
+    .await;
+    match result {
+      Ok((commit, tree)) => Ok(Self { path, commit, tree }),
+      Err(error) => {
+        let _ = fs::remove_dir_all(&path).await;
+        Err(error)
+      }
+    }
+  }
+
+  /// Checks the fetched commit out into `dest` without its `.git` folder,
+  /// reusing `dest` if it is already checked out.
+  pub async fn checkout(self, dest: &Path) -> Result<()> {
+    if !dest.exists() {
+      git(&self.path, ["checkout", "-q", "--detach", "FETCH_HEAD"]).await?;
+      fs::remove_dir_all(self.path.join(".git")).await?;
+      fs::create_dir_all(dest.parent().unwrap()).await?;
+      fs::rename(&self.path, dest).await?;
+    }
+    Ok(())
+  }
+
+  pub async fn remove(self) -> Result<()> {
+    fs::remove_dir_all(&self.path).await?;
+    Ok(())
+  }
+}
+
+/// Runs git in `dir`, returning its trimmed stdout.
+async fn git<I: IntoIterator<Item = S>, S: AsRef<OsStr>>(dir: &Path, args: I) -> Result<String> {
+  let output = Command::new("git")
+    .args(GIT_CONFIG)
+    .args(args)
+    .current_dir(dir)
+    .env("GIT_TERMINAL_PROMPT", "0")
+    .kill_on_drop(true)
+    .output()
+    .await?;
+  if !output.status.success() {
+    let msg = String::from_utf8_lossy(&output.stderr).trim().to_string();
+    return Err(Error::from(("git failed", json!({ "msg": msg }))));
+  }
+  Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Removes checkouts in `service_path` that neither the current version nor
+/// any kept one is loaded from.
+pub async fn prune_checkouts(service_path: &Path) -> Result<()> {
+  let checkouts_path = service_path.join("checkouts");
+  if !checkouts_path.exists() {
+    return Ok(());
+  }
+
+  let mut used = HashSet::new();
+  let mut metadata_paths = vec![service_path.join("metadata.json")];
+  let versions_path = service_path.join("versions");
+  if versions_path.exists() {
+    let mut versions = fs::read_dir(versions_path).await?;
+    while let Some(version) = versions.next_entry().await? {
+      metadata_paths.push(version.path().join("metadata.json"));
+    }
+  }
+  for path in metadata_paths {
+    if let Ok(Metadata { git: Some(git), .. }) = Metadata::read(&path).await {
+      used.insert(git.commit);
+    }
+  }
+
+  let mut checkouts = fs::read_dir(checkouts_path).await?;
+  while let Some(checkout) = checkouts.next_entry().await? {
+    let commit = checkout.file_name().to_string_lossy().into_owned();
+    if !used.contains(&commit) {
+      fs::remove_dir_all(checkout.path()).await?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+  use uuid::Uuid;
+
+  /// Runs git in `dir` without the restrictions of [`GIT_CONFIG`].
+  fn raw_git(dir: &Path, args: &[&str]) {
+    let status = std::process::Command::new("git")
+      .args(["-c", "user.name=a", "-c", "user.email=a@example.com"])
+      .args(args)
+      .current_dir(dir)
+      .output()
+      .unwrap()
+      .status;
+    assert!(status.success(), "git {args:?} failed");
+  }
+
+  /// Repository with `main.lua` and a symlink to `/etc/passwd` committed.
+  fn repo(dir: &Path) -> PathBuf {
+    let path = dir.join("repo");
+    std::fs::create_dir(&path).unwrap();
+    std::fs::write(path.join("main.lua"), "-- main").unwrap();
+    std::os::unix::fs::symlink("/etc/passwd", path.join("passwd")).unwrap();
+    raw_git(&path, &["init", "-q"]);
+    raw_git(&path, &["add", "."]);
+    raw_git(&path, &["commit", "-q", "-m", "init"]);
+    path
+  }
+
+  #[tokio::test]
+  async fn test_fetch_rejected() {
+    let dir = TempDir::new().unwrap();
+    let repo = repo(dir.path());
+    let repo_url = format!("file://{}", repo.display());
+    for repo in [&*repo_url, repo.to_str().unwrap(), "git://example.com/a"] {
+      let path = dir.path().join("fetch");
+      assert!(GitSource::fetch(repo, "HEAD", path.clone()).await.is_err());
+      assert!(!path.exists());
+    }
+
+    // Even if reached, other protocols are refused by git itself
+    let path = dir.path().join("fetch");
+    fs::create_dir(&path).await.unwrap();
+    git(&path, ["init", "-q"]).await.unwrap();
+    let fetch = git(&path, [
+      "fetch", "-q", "--depth", "1", "--", &repo_url, "HEAD",
+    ]);
+    assert!(fetch.await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_checkout() {
+    let dir = TempDir::new().unwrap();
+    let repo = repo(dir.path());
+    let path = dir.path().join("fetch");
+    std::fs::create_dir(&path).unwrap();
+    raw_git(&path, &["init", "-q"]);
+    let repo_url = format!("file://{}", repo.display());
+    raw_git(&path, &[
+      "-c",
+      "protocol.file.allow=always",
+      "fetch",
+      "-q",
+      "--depth",
+      "1",
+      "--",
+      &repo_url,
+      "HEAD",
+    ]);
+    let commit = git(&path, ["rev-parse", "FETCH_HEAD"]).await.unwrap();
+    let tree = git(&path, ["rev-parse", "FETCH_HEAD^{tree}"])
+      .await
+      .unwrap();
+
+    let dest = dir.path().join("checkouts").join(&commit);
+    let source = GitSource { path, commit, tree };
+    source.checkout(&dest).await.unwrap();
+    assert_eq!(
+      fs::read_to_string(dest.join("main.lua")).await.unwrap(),
+      "-- main"
+    );
+    assert!(!dest.join(".git").exists());
+    let passwd = fs::symlink_metadata(dest.join("passwd")).await.unwrap();
+    assert!(passwd.is_file());
+  }
+
+  #[tokio::test]
+  async fn test_prune_checkouts() {
+    let dir = TempDir::new().unwrap();
+    let git_info = |commit: &str| GitInfo {
+      repo: "https://example.com/a.git".into(),
+      reference: "main".into(),
+      commit: commit.into(),
+    };
+    let write_metadata = |path: PathBuf, commit| async move {
+      fs::create_dir_all(&path).await.unwrap();
+      let metadata = Metadata {
+        uuid: Uuid::new_v4(),
+        started: true,
+        source_hash: None,
+        source: None,
+        git: Some(git_info(commit)),
+      };
+      metadata.write(&path.join("metadata.json")).await.unwrap();
+    };
+    write_metadata(dir.path().into(), "current").await;
+    write_metadata(dir.path().join("versions/v"), "kept").await;
+    for commit in ["current", "kept", "unused"] {
+      fs::create_dir_all(dir.path().join("checkouts").join(commit))
+        .await
+        .unwrap();
+    }
+
+    prune_checkouts(dir.path()).await.unwrap();
+    let checkouts = dir.path().join("checkouts");
+    assert!(checkouts.join("current").exists());
+    assert!(checkouts.join("kept").exists());
+    assert!(!checkouts.join("unused").exists());
+  }
+}
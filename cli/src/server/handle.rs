@@ -1,104 +1,599 @@
-use super::error::ErrorKind::Unauthorized;
-use super::error::{method_not_allowed, ErrorAuthWrapper};
+use super::error::ErrorKind::{Forbidden, Unauthorized};
+use super::error::{method_not_allowed, Error, ErrorAuthWrapper};
+use super::git::GitInfo;
+use super::idempotency::{Idempotency, IdempotencyStore};
+use super::mirror::{self, should_mirror};
+use super::replica::{self, Replica};
+use super::report::ErrorReport;
+use super::tokens::Scope::{self, ServiceInvoke, ServicesRead, ServicesWrite};
+use super::tokens::{Auth, Rotation, TokenInfo};
 use super::types::{OwnedServiceWithStatus, ServiceWithStatus};
 use super::upload::upload;
-use super::{authenticate, json_response, Metadata, Result, ServerState};
+use super::{
+  authenticate, backup, canary, compress, filter, json_response, openapi, presents_credentials,
+  service_logs, versions, Metadata, Result, ServerState,
+};
 use crate::server::types::ServiceStatus::{Running, Stopped};
+use abel_core::service::normalize_name;
 use abel_core::ErrorKind::{ServiceDropped, ServiceNotFound};
-use hyper::{Body, Method, Request, Response, StatusCode};
+use abel_core::{ClientAddr, RequestId};
+use hyper::header::{HeaderValue, CONNECTION, RETRY_AFTER};
+use hyper::{Body, HeaderMap, Method, Request, Response, StatusCode};
 use log::{error, info};
 use owo_colors::OwoColorize;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use serde_json::json;
 use std::borrow::Cow;
 use std::convert::Infallible;
+use std::sync::atomic::Ordering;
 use std::sync::Arc;
+use std::time::Instant;
+use tokio::sync::oneshot;
+use tracing::field::Empty;
+use tracing::{info_span, Instrument};
+use uuid::Uuid;
+
+/// First path segments of the management API under `/api/v1`.
+pub(super) const MANAGEMENT_PATHS: &[&str] = &[
+  "readyz", "metrics", "usage", "export", "services", "__abel", "tokens", "auth", "cache",
+  "replica",
+];
+
+/// First path segments of the management API that are also reachable without
+/// `/api/v1`, as opposed to services': those served before the API was
+/// versioned, and `__abel`, which is not a valid service name. Services
+/// cannot be deployed under these names, or aliased to them.
+pub(super) const UNVERSIONED_PATHS: &[&str] = &["services", "__abel"];
+
+/// Largest body of management requests taking JSON parameters.
+const MAX_PARAMS_BODY: u64 = 64 * 1024;
 
 pub(crate) async fn handle(
   state: Arc<ServerState>,
-  req: Request<Body>,
+  mut req: Request<Body>,
 ) -> Result<Response<Body>, Infallible> {
   const GET: &Method = &Method::GET;
-  #[allow(unused)]
   const POST: &Method = &Method::POST;
   const PUT: &Method = &Method::PUT;
   const PATCH: &Method = &Method::PATCH;
   const DELETE: &Method = &Method::DELETE;
 
+  // Clients locked out for failed authentication may still make anonymous
+  // requests, but tokens they present are not checked.
+  let client_addr = req.extensions().get::<ClientAddr>().map(|x| x.0);
+  let lockout = (state.lockout.as_ref().zip(client_addr))
+    .map(|(lockout, peer)| (lockout, lockout.client_ip(peer, req.headers())));
+  let locked_out = (lockout.filter(|_| presents_credentials(&req)))
+    .and_then(|(lockout, addr)| lockout.check(addr));
+  let auth = if locked_out.is_some() {
+    Auth::Anonymous
+  } else {
+    authenticate(&state, &mut req).await
+  };
+  match (lockout, &auth) {
+    (Some((lockout, addr)), Auth::Anonymous)
+      if locked_out.is_none() && presents_credentials(&req) =>
+    {
+      lockout.fail(addr)
+    }
+    (Some((lockout, addr)), Auth::Admin | Auth::Token(_)) => lockout.succeed(addr),
+    _ => {}
+  }
+
   let method = req.method();
   let path = req.uri().path();
   let segments = path
     .split('/')
     .filter(|x| !x.is_empty())
-    .collect::<Box<_>>();
+    .collect::<Box<[_]>>();
+  // The management API lives under `/api/v1`. Paths that existed before are
+  // still reachable without the prefix for older clients, while newer ones
+  // are not, so that they do not shadow services of the same name.
+  let versioned = segments.starts_with(&["api", "v1"]);
+  let segments = if versioned {
+    &segments[2..]
+  } else {
+    &segments[..]
+  };
+  let management_paths = if versioned {
+    MANAGEMENT_PATHS
+  } else {
+    UNVERSIONED_PATHS
+  };
+  let is_management = (segments.first()).is_some_and(|x| management_paths.contains(x));
+
+  let cors = (state.cors.as_ref()).filter(|_| versioned || is_management || segments.is_empty());
+  let (cors_origin, cors_error) = match cors.map(|x| x.allowed_origin(&req)) {
+    Some(Ok(origin)) => (origin, None),
+    Some(Err(error)) => (None, Some(error)),
+    None => (None, None),
+  };
 
-  let auth = authenticate(&state, &req);
+  // Whether internal errors are shown in full
+  let mut privileged = !matches!(auth, Auth::Anonymous);
+  // Only set for requests to services
+  let mut request_id = None;
+
+  let result = match (method, segments) {
+    // Cross-origin requests to the management API
+    _ if cors_error.is_some() => Err(cors_error.unwrap()),
+    _ if cors.is_some_and(|x| x.is_preflight(&req)) => Ok(cors.unwrap().preflight()),
+    _ if locked_out.is_some() => Err(Error::from((
+      429,
+      "too many failed authentications",
+      json!({ "retry_after": locked_out.unwrap().as_secs() + 1 }),
+    ))),
+
+    // OpenAPI description of the management API
+    (GET, ["openapi.json"]) if versioned => json_response(StatusCode::OK, &*openapi::DOCUMENT),
+    (_, ["openapi.json"]) if versioned => Err(method_not_allowed(&["GET"], method)),
+    _ if versioned && !is_management => {
+      Err((404, "path not found", json!({ "path": path })).into())
+    }
 
-  let result = match (method, &*segments) {
     (GET, []) => hello_world().await,
 
+    // Service entry
+    (_, [service_name, ..]) if !is_management => {
+      let sub_path = "/".to_string() + path[1..].split_once('/').unwrap_or(("", "")).1;
+      let service_name = state.abel.resolve_service_name(service_name).to_string();
+      privileged = auth.allows(&ServiceInvoke(service_name.clone()));
+      let id = get_request_id(req.headers());
+      request_id = Some(id.clone());
+      let traceparent = (req.headers().get("traceparent")).and_then(|x| x.to_str().ok());
+      let span = info_span!(
+        "handle_request",
+        otel.kind = "server",
+        otel.status_code = Empty,
+        traceparent,
+        http.method = %method,
+        http.target = %req.uri(),
+        http.status_code = Empty,
+        service = %service_name,
+        request_id = &*id,
+      );
+      let result =
+        if is_following(&state) && ![GET, &Method::HEAD, &Method::OPTIONS].contains(&method) {
+          Err(read_only_error(&state))
+        } else {
+          let entry = service_entry(&state, service_name, sub_path, req, privileged, id.clone());
+          entry.instrument(span.clone()).await
+        };
+      let status = match &result {
+        Ok(resp) => resp.status(),
+        Err(error) => error.kind().status(),
+      };
+      span.record("http.status_code", status.as_u16());
+      if status.is_server_error() {
+        span.record("otel.status_code", "ERROR");
+      }
+      result.map_err(|mut error| {
+        error.add_detail("request_id", &*id);
+        error
+      })
+    }
+
+    // Readiness probe for load balancers, failing while draining
+    (GET, ["readyz"]) => readyz(&state),
+    (_, ["readyz"]) => Err(method_not_allowed(&["GET"], method)),
+
+    // Prometheus metrics
+    (_, ["metrics"]) if !auth.allows(&ServicesRead) => Err(denied(&auth, ServicesRead)),
+    (GET, ["metrics"]) => metrics(&state),
+    (_, ["metrics"]) => Err(method_not_allowed(&["GET"], method)),
+    // Isolate cache statistics
+    (_, ["metrics", "runtime"]) if !auth.allows(&ServicesRead) => Err(denied(&auth, ServicesRead)),
+    (GET, ["metrics", "runtime"]) => json_response(StatusCode::OK, state.abel.runtime_stats()),
+    (_, ["metrics", "runtime"]) => Err(method_not_allowed(&["GET"], method)),
+
+    // Usage reports
+    (_, ["usage"]) if !auth.allows(&ServicesRead) => Err(denied(&auth, ServicesRead)),
+    (GET, ["usage"]) => usage(&state, req.uri().query().unwrap_or("")).await,
+    (_, ["usage"]) => Err(method_not_allowed(&["GET"], method)),
+
+    // Service artifacts for read replicas
+    (_, ["export", ..]) if !auth.allows(&Scope::Replica) => Err(denied(&auth, Scope::Replica)),
+    (GET, ["export"]) => replica::export_list(&state).await,
+    (GET, ["export", name]) => {
+      replica::export_source(&state, &state.abel.resolve_service_name(name)).await
+    }
+    (_, ["export", ..]) => Err(method_not_allowed(&["GET"], method)),
+
     // Service management API entry
     (_, ["services", ..]) => match (method, &segments[1..]) {
-      _ if !auth => Err(Unauthorized.into()),
+      (GET, _) if !auth.allows(&ServicesRead) => Err(denied(&auth, ServicesRead)),
+      _ if method != GET && !auth.allows(&ServicesWrite) => Err(denied(&auth, ServicesWrite)),
+      // Caches are per worker and not synced, so replicas may flush theirs
+      (DELETE, [name, "cache"]) => flush_cache(&state, &state.abel.resolve_service_name(name)),
+      _ if method != GET && is_following(&state) => Err(read_only_error(&state)),
       (GET, []) => list(&state),
       (_, []) => Err(method_not_allowed(&["GET"], method)),
 
-      (GET, [name]) => get(&state, name),
-      (PUT, [name]) => upload(&state, (*name).into(), req).await,
-      (PATCH, [name]) => start_stop(&state, name, req.uri().query().unwrap_or("")).await,
-      (DELETE, [name]) => remove(&state, name).await,
+      // Service names are normalized here, and all but newly uploaded ones
+      // may also be referred to by their aliases.
+      (GET, [name]) => get(&state, &state.abel.resolve_service_name(name)).await,
+      (PUT, [name]) => upload(&state, normalize_name(name).into(), req).await,
+      (PATCH, [name]) => {
+        let name = state.abel.resolve_service_name(name);
+        start_stop(&state, &name, req.uri().query().unwrap_or("")).await
+      }
+      (DELETE, [name]) => remove(&state, &state.abel.resolve_service_name(name)).await,
       (_, [_name]) => Err(method_not_allowed(
         &["GET", "PUT", "PATCH", "DELETE"],
         method,
       )),
 
+      // Backups of local storage
+      (GET, [name, "backups"]) => {
+        list_backups(&state, &state.abel.resolve_service_name(name)).await
+      }
+      (POST, [name, "backups"]) => {
+        create_backup(&state, &state.abel.resolve_service_name(name)).await
+      }
+      (_, [_name, "backups"]) => Err(method_not_allowed(&["GET", "POST"], method)),
+      (POST, [name, "backups", id, "restore"]) => {
+        restore_backup(&state, &state.abel.resolve_service_name(name), id).await
+      }
+      (_, [_name, "backups", _id, "restore"]) => Err(method_not_allowed(&["POST"], method)),
+
+      (GET, [name, "health"]) => health(&state, &state.abel.resolve_service_name(name)).await,
+      (_, [_name, "health"]) => Err(method_not_allowed(&["GET"], method)),
+
+      // Recent lines logged by the service
+      (GET, [name, "logs"]) => {
+        let name = state.abel.resolve_service_name(name);
+        service_logs::logs(state.clone(), &name, req.uri().query().unwrap_or(""))
+      }
+      (_, [_name, "logs"]) => Err(method_not_allowed(&["GET"], method)),
+
+      // Caches of the `cache` module
+      (GET, [name, "cache"]) => cache_stats(&state, &state.abel.resolve_service_name(name)),
+      (_, [_name, "cache"]) => Err(method_not_allowed(&["GET", "DELETE"], method)),
+
+      // Previously deployed versions
+      (GET, [name, "versions"]) => {
+        let name = state.abel.resolve_service_name(name);
+        versions::list(&state, &name)
+          .await
+          .and_then(|x| json_response(StatusCode::OK, x))
+      }
+      (_, [_name, "versions"]) => Err(method_not_allowed(&["GET"], method)),
+      (POST, [name, "rollback"]) => {
+        let name = state.abel.resolve_service_name(name);
+        versions::rollback(&state, &name, req.uri().query().unwrap_or("")).await
+      }
+      (_, [_name, "rollback"]) => Err(method_not_allowed(&["POST"], method)),
+
+      // Canary versions
+      (GET, [name, "canary"]) => canary::status(&state, &state.abel.resolve_service_name(name)),
+      (PUT, [name, "canary"]) => {
+        canary::upload(&state, &state.abel.resolve_service_name(name), req).await
+      }
+      (DELETE, [name, "canary"]) => {
+        canary::rollback(&state, &state.abel.resolve_service_name(name)).await
+      }
+      (_, [_name, "canary"]) => Err(method_not_allowed(&["GET", "PUT", "DELETE"], method)),
+      (POST, [name, "canary", "promote"]) => {
+        canary::promote(&state, &state.abel.resolve_service_name(name)).await
+      }
+      (_, [_name, "canary", "promote"]) => Err(method_not_allowed(&["POST"], method)),
+
       (_, [..]) => Err((404, "path not found", json!({ "path": path })).into()),
     },
 
-    // Service entry
-    (_, [service_name, ..]) => {
-      let sub_path = "/".to_string() + path[1..].split_once('/').unwrap_or(("", "")).1;
-      let service_name: String = (*service_name).into();
-      match state.abel.get_running_service(&service_name) {
-        Ok(service) => {
-          let result = state.abel.run_service(service, sub_path, req).await;
-          match result {
-            Ok(resp) => Ok(resp),
-            // Hide `ServiceDropped` from normal users
-            Err(error) if matches!(error.kind(), ServiceDropped) && !auth => {
-              error!("{error}");
-              Err(From::from(ServiceNotFound {
-                name: service_name.into(),
-              }))
-            }
-            Err(error) => Err(error.into()),
-          }
-        }
-        Err(error) => Err(error.into()),
-      }
-    }
+    // Recent requests, kept in dev mode
+    (_, ["__abel", "requests"]) if !auth.is_admin() => Err(denied(&auth, "admin")),
+    (GET, ["__abel", "requests"]) => match &state.request_log {
+      Some(request_log) => request_log.list(req.uri().query().unwrap_or("")),
+      None => Err((404, "request log disabled", json!({ "path": path })).into()),
+    },
+    (_, ["__abel", "requests"]) => Err(method_not_allowed(&["GET"], method)),
+
+    // Token management, only available with the server's own auth token
+    (_, ["tokens", ..]) => match (method, &segments[1..]) {
+      _ if !auth.is_admin() => Err(denied(&auth, "admin")),
+      (GET, []) => list_tokens(&state),
+      (POST, []) => create_token(&state, req).await,
+      (_, []) => Err(method_not_allowed(&["GET", "POST"], method)),
+      (DELETE, [id]) => revoke_token(&state, id).await,
+      (_, [_id]) => Err(method_not_allowed(&["DELETE"], method)),
+      (_, [..]) => Err((404, "path not found", json!({ "path": path })).into()),
+    },
+
+    // What the presented token is allowed to do
+    (GET, ["auth", "self"]) => auth_self(&auth),
+    (_, ["auth", "self"]) => Err(method_not_allowed(&["GET"], method)),
+    // Replacing a token, which stays valid for a while
+    (POST, ["auth", "tokens", "rotate"]) => rotate_token(&state, &auth, req).await,
+    (_, ["auth", "tokens", "rotate"]) => Err(method_not_allowed(&["POST"], method)),
+
+    // Remote module cache, only available with the server's own auth token
+    (_, ["cache"]) if !auth.is_admin() => Err(denied(&auth, "admin")),
+    (GET, ["cache"]) => remote_cache_stats(&state).await,
+    (DELETE, ["cache"]) => purge_remote_cache(&state).await,
+    (_, ["cache"]) => Err(method_not_allowed(&["GET", "DELETE"], method)),
+
+    // Read replica status and promotion, only available with the server's own
+    // auth token
+    (_, ["replica", ..]) => match (method, &segments[1..], &state.replica) {
+      _ if !auth.is_admin() => Err(denied(&auth, "admin")),
+      (_, _, None) => Err((404, "not a replica", json!({ "path": path })).into()),
+      (GET, [], Some(replica)) => json_response(StatusCode::OK, replica.status()),
+      (_, [], _) => Err(method_not_allowed(&["GET"], method)),
+      (POST, ["promote"], Some(replica)) => promote(replica),
+      (_, ["promote"], _) => Err(method_not_allowed(&["POST"], method)),
+      (_, [..], _) => Err((404, "path not found", json!({ "path": path })).into()),
+    },
 
     _ => Err((404, "path not found", json!({ "path": path })).into()),
   };
 
-  Ok(result.unwrap_or_else(|error| {
+  let retry_after = (locked_out.map(|x| x.as_secs() + 1))
+    .or_else(|| result.as_ref().err().and_then(Error::retry_after));
+  let mut resp = result.unwrap_or_else(|error| {
     let server_error = error.kind().status().is_server_error();
-    let error = ErrorAuthWrapper::new(auth, error);
+    let error = ErrorAuthWrapper::new(privileged, error)
+      .hide_tracebacks(!privileged && state.hide_tracebacks);
     if server_error {
+      let mut msg = error.to_string();
       if let Some(uuid) = error.uuid() {
-        error!("{error} {}", format!("({})", uuid).dimmed());
-      } else {
-        error!("{error}");
+        msg += &format!(" {}", format!("({uuid})").dimmed());
       }
+      if let Some(id) = &request_id {
+        msg += &format!(" {}", format!("[request {id}]").dimmed());
+      }
+      error!("{msg}");
     }
     error.into()
-  }))
+  });
+  if let Some(value) = (request_id.as_deref()).and_then(|x| HeaderValue::from_str(x).ok()) {
+    resp.headers_mut().insert(X_REQUEST_ID, value);
+  }
+  if let Some(secs) = retry_after {
+    (resp.headers_mut()).insert(RETRY_AFTER, secs.into());
+  }
+  if let Some(cors) = cors {
+    cors.apply(cors_origin, &mut resp);
+  }
+  // Have keep-alive clients reconnect, hopefully to another instance
+  if state.draining.load(Ordering::Acquire) {
+    (resp.headers_mut()).insert(CONNECTION, HeaderValue::from_static("close"));
+  }
+  Ok(resp)
+}
+
+async fn service_entry(
+  state: &Arc<ServerState>,
+  service_name: String,
+  sub_path: String,
+  req: Request<Body>,
+  auth: bool,
+  request_id: Arc<str>,
+) -> Result<Response<Body>> {
+  let filters =
+    (state.abel.get_service(&service_name).ok()).and_then(|x| x.upgrade().filters().cloned());
+  let mut req = match &filters {
+    Some(filters) => filter::apply(filters, &sub_path, req)?,
+    None => req,
+  };
+  // Seen by the service both in headers and as `req.id`
+  if let Ok(value) = HeaderValue::from_str(&request_id) {
+    req.headers_mut().insert(X_REQUEST_ID, value);
+  }
+  req.extensions_mut().insert(RequestId(request_id));
+  let service = state.abel.activate_service(&service_name).await?;
+  let service = (state.abel)
+    .pick_canary(&service_name, req.headers())
+    .unwrap_or(service);
+
+  // Recording, mirroring, validation, idempotency and the request log need
+  // the whole request body.
+  let mut compare_tx = None;
+  let recorder = (state.recorder.as_ref()).filter(|x| x.should_record(&service_name, &sub_path));
+  let mirror = (service.try_upgrade().ok())
+    .and_then(|x| x.mirror().cloned())
+    .filter(should_mirror);
+  let encoding = ((service.try_upgrade().ok())
+    .map_or(state.compress, |x| x.compress().unwrap_or(state.compress))
+    && req.method() != Method::HEAD)
+    .then(|| compress::negotiate(req.headers()))
+    .flatten();
+  let openapi = (service.try_upgrade().ok()).and_then(|x| x.openapi().cloned());
+  let idempotency = (service.try_upgrade().ok()).and_then(|x| x.idempotency());
+  let mut pending = None;
+  let mut idempotent = None;
+  let req = if recorder.is_some()
+    || mirror.is_some()
+    || openapi.is_some()
+    || idempotency.is_some()
+    || state.request_log.is_some()
+  {
+    let (parts, body) = req.into_parts();
+    let max = (filters.and_then(|x| x.max_body_size)).map_or(filter::MAX_BUFFERED_BODY, |x| {
+      x.min(filter::MAX_BUFFERED_BODY)
+    });
+    let body = filter::buffer(body, max).await?;
+    if let Some(openapi) = openapi {
+      let (method, query) = (&parts.method, parts.uri.query());
+      openapi.validate(method, &sub_path, query, &parts.headers, &body)?;
+    }
+    if let (Some(config), Some(key)) = (idempotency, IdempotencyStore::key(&parts)?) {
+      let store = &state.idempotency;
+      match (store.begin(&service_name, key, config.ttl(), &parts, &body)).await? {
+        Idempotency::Replay(resp) => return Ok(compress::compress(resp, encoding)),
+        Idempotency::Pending(x) => idempotent = Some(x),
+      }
+    }
+    if let Some(recorder) = recorder {
+      recorder
+        .record(&service_name, &sub_path, &parts, &body)
+        .await;
+    }
+    if let Some(request_log) = &state.request_log {
+      pending = Some(request_log.start(&service_name, &sub_path, &parts, &body));
+    }
+    if let Some(config) = mirror {
+      let (tx, rx) = config.compare.is_some().then(oneshot::channel).unzip();
+      compare_tx = tx;
+      let (state, name) = (state.clone(), service_name.clone());
+      mirror::mirror(state, name, config, &sub_path, &parts, body.clone(), rx);
+    }
+    Request::from_parts(parts, body.into())
+  } else {
+    req
+  };
+  let report_info = service.try_upgrade().ok().and_then(|guard| {
+    let dsn = guard.report_dsn();
+    state.reporter.enabled(dsn).then(|| {
+      let parts = (
+        req.method().clone(),
+        req.uri().clone(),
+        req.headers().clone(),
+      );
+      (guard.uuid(), dsn.map(String::from), parts)
+    })
+  });
+  let start = Instant::now();
+  let result = match &pending {
+    Some(pending) => {
+      let logs = pending.logs.clone();
+      (state.abel)
+        .run_service_with_logs(service, sub_path, req, logs)
+        .await
+    }
+    None => state.abel.run_service(service, sub_path, req).await,
+  };
+  let elapsed = start.elapsed();
+  if let (Err(error), Some((uuid, dsn, (method, uri, headers)))) = (&result, report_info) {
+    if error.kind().internal() {
+      state.reporter.report(ErrorReport {
+        service_name: &service_name,
+        service_uuid: uuid,
+        service_dsn: dsn.as_deref(),
+        method: &method,
+        uri: &uri,
+        headers: &headers,
+        error: &error.to_string(),
+      });
+    }
+  }
+  match result {
+    Ok(resp) => {
+      let mut resp = match idempotent {
+        Some(idempotent) => idempotent.finish(resp).await,
+        None => resp,
+      };
+      // Lets clients (e.g. `abel bench`) tell handler time from network time.
+      // Timings help probing for side channels, so the public never sees them.
+      if auth || state.request_log.is_some() {
+        let timing = format!("abel;dur={:.3}", elapsed.as_secs_f64() * 1000.);
+        if let Ok(timing) = HeaderValue::try_from(timing) {
+          resp.headers_mut().append("server-timing", timing);
+        }
+      }
+      let resp = match compare_tx {
+        Some(tx) => mirror::send_primary(tx, resp).await,
+        None => resp,
+      };
+      let resp = match (&state.request_log, pending) {
+        (Some(request_log), Some(pending)) => request_log.finish(pending, elapsed, resp),
+        _ => resp,
+      };
+      Ok(compress::compress(resp, encoding))
+    }
+    Err(error) => {
+      if let (Some(request_log), Some(pending)) = (&state.request_log, pending) {
+        let status = error.kind().status();
+        request_log.finish_error(pending, elapsed, status, error.to_string());
+      }
+      match error {
+        // Hide `ServiceDropped` from normal users
+        error if matches!(error.kind(), ServiceDropped) && !auth => {
+          error!("{error}");
+          Err(From::from(ServiceNotFound {
+            name: service_name.into(),
+          }))
+        }
+        error => Err(error.into()),
+      }
+    }
+  }
+}
+
+const X_REQUEST_ID: &str = "x-request-id";
+
+/// Takes the request's `x-request-id` if it looks sane, or generates one.
+fn get_request_id(headers: &HeaderMap) -> Arc<str> {
+  (headers.get(X_REQUEST_ID).and_then(|x| x.to_str().ok()))
+    .filter(|x| !x.is_empty() && x.len() <= 128 && x.bytes().all(|b| b.is_ascii_graphic()))
+    .map(Into::into)
+    .unwrap_or_else(|| Uuid::new_v4().to_string().into())
+}
+
+/// Error for requests lacking `required`.
+fn denied(auth: &Auth, required: impl ToString) -> Error {
+  match auth {
+    Auth::Anonymous => Unauthorized.into(),
+    _ => Forbidden {
+      required: required.to_string(),
+    }
+    .into(),
+  }
 }
 
 async fn hello_world() -> Result<Response<Body>> {
   json_response(StatusCode::OK, json!({ "msg": "Hello, world!" }))
 }
 
+fn readyz(state: &ServerState) -> Result<Response<Body>> {
+  if state.draining.load(Ordering::Acquire) {
+    json_response(StatusCode::SERVICE_UNAVAILABLE, json!({ "ready": false }))
+  } else {
+    json_response(StatusCode::OK, json!({ "ready": true }))
+  }
+}
+
+fn metrics(state: &ServerState) -> Result<Response<Body>> {
+  let resp = Response::builder()
+    .header("content-type", "text/plain; version=0.0.4")
+    .body(state.abel.metrics().to_prometheus().into())
+    .unwrap();
+  Ok(resp)
+}
+
+async fn usage(state: &ServerState, query: &str) -> Result<Response<Body>> {
+  #[derive(Deserialize)]
+  struct Query {
+    date: Option<String>,
+    #[serde(default)]
+    format: Format,
+  }
+
+  #[derive(Default, Deserialize)]
+  #[serde(rename_all = "lowercase")]
+  enum Format {
+    #[default]
+    Json,
+    Csv,
+  }
+
+  let Query { date, format } = serde_qs::from_str(query)?;
+  if date.is_none() {
+    state.usage.update(state).await?;
+  }
+  let report = state.usage.read(date.as_deref()).await?;
+  match format {
+    Format::Json => json_response(StatusCode::OK, report),
+    Format::Csv => {
+      let resp = Response::builder()
+        .header("content-type", "text/csv")
+        .body(report.to_csv().into())
+        .unwrap();
+      Ok(resp)
+    }
+  }
+}
+
 fn list(state: &ServerState) -> Result<Response<Body>> {
   let services = state
     .abel
@@ -108,12 +603,25 @@ fn list(state: &ServerState) -> Result<Response<Body>> {
   json_response(StatusCode::OK, services)
 }
 
-fn get(state: &ServerState, name: &str) -> Result<Response<Body>> {
+async fn get(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  #[derive(Serialize)]
+  struct GetResponse<'a> {
+    #[serde(flatten)]
+    service: ServiceWithStatus<'a>,
+    source_hash: Option<String>,
+    git: Option<GitInfo>,
+  }
+
   let service = state.abel.get_service(name)?;
-  json_response(
-    StatusCode::OK,
-    ServiceWithStatus::from_guard(&service.upgrade()),
-  )
+  let metadata_path = (state.abel_path).join(format!("services/{name}/metadata.json"));
+  let Metadata {
+    source_hash, git, ..
+  } = Metadata::read(&metadata_path).await?;
+  json_response(StatusCode::OK, GetResponse {
+    service: ServiceWithStatus::from_guard(&service.upgrade()),
+    source_hash,
+    git,
+  })
 }
 
 async fn start_stop(state: &ServerState, name: &str, query: &str) -> Result<Response<Body>> {
@@ -139,27 +647,333 @@ async fn start_stop(state: &ServerState, name: &str, query: &str) -> Result<Resp
     Operation::Start => {
       let service = state.abel.start_service(name).await?;
       Metadata::modify(&metadata_path, |m| m.started = true).await?;
+      let guard = service.upgrade();
       json_response(StatusCode::OK, ServiceWithStatus {
         status: Running,
-        service: Cow::Borrowed(service.upgrade().info()),
+        service: Cow::Borrowed(guard.info()),
+        health: guard.health(),
       })
     }
     Operation::Stop => {
       let result = state.abel.stop_service(name).await;
       Metadata::modify(&metadata_path, |m| m.started = false).await?;
+      canary::remove_stored(state, name).await?;
       result.map_err(From::from).and_then(|x| {
         json_response(StatusCode::OK, ServiceWithStatus {
           status: Stopped,
           service: Cow::Borrowed(x.info()),
+          health: None,
         })
       })
     }
   }
 }
 
+fn is_following(state: &ServerState) -> bool {
+  matches!(&state.replica, Some(x) if x.is_following())
+}
+
+fn read_only_error(state: &ServerState) -> Error {
+  state.replica.as_ref().unwrap().read_only_error()
+}
+
+fn promote(replica: &Replica) -> Result<Response<Body>> {
+  if replica.promote() {
+    info!("Promoted to primary");
+  }
+  json_response(StatusCode::OK, replica.status())
+}
+
 async fn remove(state: &ServerState, service_name: &str) -> Result<Response<Body>> {
   let removed = state.abel.remove_service(service_name).await?;
   tokio::fs::remove_dir_all(state.abel_path.join("services").join(service_name)).await?;
   info!("Removed service '{}' ({})", removed.name(), removed.uuid());
   json_response(StatusCode::OK, removed.info())
 }
+
+/// Runs the service's health check now. Responds with 503 if it is
+/// unhealthy or stopped, so that it can be probed directly.
+async fn health(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  if !state.abel.get_service(name)?.is_running() {
+    let body = json!({ "healthy": false, "detail": "service stopped" });
+    return json_response(StatusCode::SERVICE_UNAVAILABLE, body);
+  }
+  match state.abel.check_health(name).await? {
+    Some(health) if health.healthy => json_response(StatusCode::OK, health),
+    Some(health) => json_response(StatusCode::SERVICE_UNAVAILABLE, health),
+    None => json_response(StatusCode::OK, json!({ "healthy": true, "detail": null })),
+  }
+}
+
+fn cache_stats(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  json_response(StatusCode::OK, state.abel.cache_stats(name)?)
+}
+
+fn flush_cache(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.flush_cache(name)?;
+  info!("Flushed caches of service '{name}'");
+  json_response(StatusCode::OK, json!({ "flushed": name }))
+}
+
+async fn remote_cache_stats(state: &ServerState) -> Result<Response<Body>> {
+  json_response(StatusCode::OK, state.abel.remote_cache_stats().await?)
+}
+
+async fn purge_remote_cache(state: &ServerState) -> Result<Response<Body>> {
+  let removed = state.abel.purge_remote_cache().await?;
+  info!("Purged {} remote modules from cache", removed.modules);
+  json_response(StatusCode::OK, json!({ "purged": removed }))
+}
+
+async fn list_backups(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  json_response(StatusCode::OK, backup::list(state, name).await?)
+}
+
+async fn create_backup(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  let config = (state.abel.get_service(name)?.upgrade().backup().cloned())
+    .ok_or_else(|| Error::from(("service has no backup config", json!({ "name": name }))))?;
+  let backup = backup::backup(state, name, &config).await?;
+  info!("Backed up service '{name}' ({})", backup.id);
+  json_response(StatusCode::OK, backup)
+}
+
+async fn restore_backup(state: &ServerState, name: &str, id: &str) -> Result<Response<Body>> {
+  let restored = backup::restore(state, name, id).await?;
+  info!("Restored service '{name}' from backup {id}");
+  json_response(StatusCode::OK, restored)
+}
+
+/// Kind and scopes of the request's token. Anonymous requests get an empty
+/// list instead of 401, so that tooling can hide what it cannot do.
+fn auth_self(auth: &Auth) -> Result<Response<Body>> {
+  let value = match auth {
+    Auth::Anonymous => json!({ "kind": "anonymous", "scopes": [] }),
+    Auth::Admin => json!({
+      "kind": "admin",
+      "scopes": [ServicesRead, ServicesWrite, ServiceInvoke("*".into()), Scope::Replica],
+    }),
+    Auth::Token(info) => {
+      let mut value = serde_json::to_value(info)?;
+      value["kind"] = "token".into();
+      value
+    }
+  };
+  json_response(StatusCode::OK, value)
+}
+
+fn list_tokens(state: &ServerState) -> Result<Response<Body>> {
+  json_response(StatusCode::OK, state.tokens.list())
+}
+
+async fn create_token(state: &ServerState, req: Request<Body>) -> Result<Response<Body>> {
+  #[derive(Deserialize)]
+  struct Body {
+    name: Option<String>,
+    scopes: Vec<Scope>,
+  }
+
+  #[derive(Serialize)]
+  struct Created {
+    #[serde(flatten)]
+    info: TokenInfo,
+    token: Uuid,
+  }
+
+  let body = hyper::body::to_bytes(req.into_body())
+    .await
+    .map_err(|error| {
+      Error::from((
+        400,
+        "failed to read request body",
+        json!({ "msg": error.to_string() }),
+      ))
+    })?;
+  let Body { name, scopes } = serde_json::from_slice(&body)?;
+  let (info, token) = state.tokens.create(name, scopes).await?;
+  info!("Created token {}", info.id);
+  json_response(StatusCode::OK, Created { info, token })
+}
+
+/// Rotates the request's own token, or any token by ID with the server's auth
+/// token.
+async fn rotate_token(
+  state: &ServerState,
+  auth: &Auth,
+  req: Request<Body>,
+) -> Result<Response<Body>> {
+  #[derive(Default, Deserialize)]
+  struct Body {
+    id: Option<Uuid>,
+    /// Seconds the old token stays valid, at most `token_grace_period` unless
+    /// rotated with the server's auth token.
+    grace_period: Option<u64>,
+  }
+
+  #[derive(Serialize)]
+  struct Rotated {
+    #[serde(flatten)]
+    info: TokenInfo,
+    token: Uuid,
+    previous: TokenInfo,
+  }
+
+  // Checked before reading the body, so that anyone cannot make the server
+  // buffer it
+  if let Auth::Anonymous = auth {
+    return Err(Unauthorized.into());
+  }
+  let body = filter::buffer(req.into_body(), MAX_PARAMS_BODY).await?;
+  let Body { id, grace_period } = if body.is_empty() {
+    Body::default()
+  } else {
+    serde_json::from_slice(&body)?
+  };
+  let id = match (auth, id) {
+    (Auth::Anonymous, _) => return Err(Unauthorized.into()),
+    (Auth::Token(info), None) => info.id,
+    (Auth::Token(info), Some(id)) if info.id == id => id,
+    (Auth::Token(_), Some(_)) => return Err(denied(auth, "admin")),
+    (Auth::Admin, Some(id)) => id,
+    (Auth::Admin, None) => {
+      let msg = "the server's own auth token can only be changed in its config";
+      return Err(("token ID required", json!({ "msg": msg })).into());
+    }
+  };
+
+  // Tokens rotating themselves cannot outlive the configured grace period,
+  // so that a leaked one cannot keep itself valid by rotating.
+  let grace_period = match auth {
+    Auth::Admin => grace_period.unwrap_or(state.token_grace_period),
+    _ => (grace_period.unwrap_or(u64::MAX)).min(state.token_grace_period),
+  };
+  match state.tokens.rotate(id, grace_period).await? {
+    Rotation::Rotated { old, new, token } => {
+      info!("Rotated token {id} into {}", new.id);
+      json_response(StatusCode::OK, Rotated {
+        info: new,
+        token,
+        previous: old,
+      })
+    }
+    Rotation::NotFound => Err((404, "token not found", json!({ "id": id })).into()),
+    Rotation::AlreadyRotated(old) => Err(Error::from((
+      409,
+      "token already rotated",
+      json!({ "id": id, "expires_at": old.expires_at }),
+    ))),
+  }
+}
+
+async fn revoke_token(state: &ServerState, id: &str) -> Result<Response<Body>> {
+  let id =
+    Uuid::parse_str(id).map_err(|_| Error::from(("invalid token ID", json!({ "id": id }))))?;
+  match state.tokens.revoke(id).await? {
+    Some(info) => {
+      info!("Revoked token {id}");
+      json_response(StatusCode::OK, info)
+    }
+    None => Err((404, "token not found", json!({ "id": id })).into()),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::Config;
+  use crate::server::tests::state;
+  use crate::source::SingleSource;
+  use abel_core::source::Source;
+  use std::time::{SystemTime, UNIX_EPOCH};
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn test_server_timing() {
+    let dir = TempDir::new().unwrap();
+    let token = Uuid::new_v4();
+    let config = Config {
+      auth_token: Some(token),
+      ..Default::default()
+    };
+    let state = state(dir.path(), config).await;
+    let source = Source::new(SingleSource::new(r#"abel.listen("/", function() end)"#));
+    (state
+      .abel
+      .cold_update_or_create_service("a", None, source, Default::default()))
+    .await
+    .unwrap();
+
+    for (token, expected) in [(None, false), (Some(token), true)] {
+      let mut req = Request::get("/a").body(Body::empty()).unwrap();
+      if let Some(token) = token {
+        let value = HeaderValue::try_from(format!("Abel {token}")).unwrap();
+        req.headers_mut().insert("authorization", value);
+      }
+      let resp = handle(state.clone(), req).await.unwrap();
+      assert_eq!(resp.status(), 204);
+      assert_eq!(resp.headers().contains_key("server-timing"), expected);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_rotate_token() {
+    let dir = TempDir::new().unwrap();
+    let config = Config {
+      token_grace_period: Some(60),
+      ..Default::default()
+    };
+    let state = state(dir.path(), config).await;
+    let (_, token) = (state.tokens.create(None, Vec::new())).await.unwrap();
+    let rotate = |token: Option<Uuid>, body: Body| {
+      let mut req = Request::post("/api/v1/auth/tokens/rotate");
+      if let Some(token) = token {
+        req = req.header("authorization", format!("Abel {token}"));
+      }
+      handle(state.clone(), req.body(body).unwrap())
+    };
+
+    // Rejected before the body is read
+    let body = Body::wrap_stream(futures::stream::pending::<Result<Vec<u8>, hyper::Error>>());
+    let resp = rotate(None, body).await.unwrap();
+    assert_eq!(resp.status(), 401);
+
+    let body = Body::from(format!(r#"{{ "grace_period": {} }}"#, u64::MAX));
+    let resp = rotate(Some(token), body).await.unwrap();
+    assert_eq!(resp.status(), 200);
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let rotated = serde_json::from_slice::<serde_json::Value>(&body).unwrap();
+    let expires_at = rotated["previous"]["expires_at"].as_u64().unwrap();
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+    assert!(expires_at <= now.as_secs() + 60);
+
+    let body = Body::from(vec![b' '; MAX_PARAMS_BODY as usize + 1]);
+    let resp = rotate(Some(token), body).await.unwrap();
+    assert_eq!(resp.status(), 413);
+  }
+
+  #[tokio::test]
+  async fn test_services_not_shadowed() {
+    let dir = TempDir::new().unwrap();
+    let state = state(dir.path(), Default::default()).await;
+    let source = Source::new(SingleSource::new(
+      r#"abel.listen("/", function() return "service" end)"#,
+    ));
+    (state.abel)
+      .cold_update_or_create_service("metrics", None, source, Default::default())
+      .await
+      .unwrap();
+
+    let get = |path| {
+      handle(
+        state.clone(),
+        Request::get(path).body(Body::empty()).unwrap(),
+      )
+    };
+    let resp = get("/metrics").await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "service");
+    let resp = get("/api/v1/metrics").await.unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_ne!(body, "service");
+  }
+}
@@ -0,0 +1,311 @@
+//! Upgrading the server binary without dropping connections.
+//!
+//! On `SIGUSR2`, the server runs its binary again with the same arguments,
+//! handing over its listening socket and the lock on its Abel path as file
+//! descriptors. Once the new process has loaded its services and is serving,
+//! it sends the old one `SIGTERM`, which then stops accepting and drains
+//! in-flight requests. Unlike on any other shutdown, the old process then
+//! leaves its services and persisted state alone, as they now belong to the
+//! new process. Should the new process fail to start, the old one keeps
+//! serving.
+//!
+//! The binary is run again by the name it was started with, so replacing the
+//! file at that path before sending `SIGUSR2` upgrades it.
+
+use futures::Future;
+use log::{error, info, warn};
+use std::fs::File;
+use std::os::unix::io::{FromRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::{env, io};
+use tokio::process::{Child, Command};
+use tokio::signal::unix::{signal, SignalKind};
+
+const LISTENER_FD: &str = "ABEL_LISTENER_FD";
+const LOCK_FD: &str = "ABEL_LOCK_FD";
+const PARENT_PID: &str = "ABEL_HANDOVER_PID";
+
+/// What the previous process handed over, read from the environment by
+/// [`init`].
+#[derive(Debug, Default, PartialEq, Eq)]
+struct Inherited {
+  listener: Option<RawFd>,
+  lock: Option<RawFd>,
+  parent: Option<libc::pid_t>,
+}
+
+impl Inherited {
+  fn read(var: impl Fn(&str) -> Option<String>) -> Self {
+    Self {
+      listener: var(LISTENER_FD).and_then(|x| x.parse().ok()),
+      lock: var(LOCK_FD).and_then(|x| x.parse().ok()),
+      parent: var(PARENT_PID).and_then(|x| x.parse().ok()),
+    }
+  }
+}
+
+static INHERITED: Mutex<Inherited> = Mutex::new(Inherited {
+  listener: None,
+  lock: None,
+  parent: None,
+});
+
+/// Whether this process has handed over to a new one.
+static HANDED_OVER: AtomicBool = AtomicBool::new(false);
+
+/// Takes what the previous process handed over from the environment, so that
+/// it is not passed on to anything this process starts.
+///
+/// Must be called before any other thread is started, as modifying the
+/// environment is not thread-safe.
+pub fn init() {
+  let inherited = Inherited::read(|var| env::var(var).ok());
+  for var in [LISTENER_FD, LOCK_FD, PARENT_PID] {
+    env::remove_var(var);
+  }
+  *INHERITED.lock().unwrap() = inherited;
+}
+
+/// Listening socket handed over by the previous process, if any.
+pub fn inherited_listener() -> Option<std::net::TcpListener> {
+  let fd = INHERITED.lock().unwrap().listener.take()?;
+  match check_listener(fd) {
+    Ok(()) => Some(unsafe { std::net::TcpListener::from_raw_fd(fd) }),
+    Err(error) => {
+      warn!("ignoring {LISTENER_FD}={fd}: {error}");
+      None
+    }
+  }
+}
+
+/// Locked lock file handed over by the previous process, if any.
+pub fn inherited_lock() -> Option<File> {
+  let fd = INHERITED.lock().unwrap().lock.take()?;
+  match check_lock(fd) {
+    Ok(()) => Some(unsafe { File::from_raw_fd(fd) }),
+    Err(error) => {
+      warn!("ignoring {LOCK_FD}={fd}: {error}");
+      None
+    }
+  }
+}
+
+/// Checks that `fd` is open and not one of the standard streams, which the
+/// previous process never hands over.
+///
+/// Descriptors are taken ownership of once checked, so one that is invalid,
+/// or already owned by something else, must be caught here rather than
+/// closed from under its owner later.
+fn check_open(fd: RawFd) -> io::Result<()> {
+  if fd <= libc::STDERR_FILENO {
+    return Err(io::Error::other("standard streams are not handed over"));
+  }
+  if unsafe { libc::fcntl(fd, libc::F_GETFD) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+/// Checks that `fd` is a listening TCP socket.
+fn check_listener(fd: RawFd) -> io::Result<()> {
+  check_open(fd)?;
+  let sock_type = getsockopt(fd, libc::SO_TYPE)?;
+  let listening = getsockopt(fd, libc::SO_ACCEPTCONN)?;
+  let mut addr: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+  let mut len = std::mem::size_of_val(&addr) as libc::socklen_t;
+  let addr_ptr = (&mut addr as *mut libc::sockaddr_storage).cast();
+  if unsafe { libc::getsockname(fd, addr_ptr, &mut len) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  let family = libc::c_int::from(addr.ss_family);
+  let tcp = sock_type == libc::SOCK_STREAM && [libc::AF_INET, libc::AF_INET6].contains(&family);
+  if !tcp || listening == 0 {
+    return Err(io::Error::other("not a listening TCP socket"));
+  }
+  Ok(())
+}
+
+fn getsockopt(fd: RawFd, option: libc::c_int) -> io::Result<libc::c_int> {
+  let mut value: libc::c_int = 0;
+  let mut len = std::mem::size_of_val(&value) as libc::socklen_t;
+  let value_ptr = (&mut value as *mut libc::c_int).cast();
+  if unsafe { libc::getsockopt(fd, libc::SOL_SOCKET, option, value_ptr, &mut len) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(value)
+}
+
+/// Checks that `fd` is a regular file, as the lock file is.
+fn check_lock(fd: RawFd) -> io::Result<()> {
+  check_open(fd)?;
+  let mut stat: libc::stat = unsafe { std::mem::zeroed() };
+  if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  if stat.st_mode & libc::S_IFMT != libc::S_IFREG {
+    return Err(io::Error::other("not a regular file"));
+  }
+  Ok(())
+}
+
+/// Whether a new process has taken over, or is taking over, from this one.
+///
+/// Services and the Abel path then belong to the new process, so this one
+/// must not stop the former or write to the latter while shutting down.
+pub fn handed_over() -> bool {
+  HANDED_OVER.load(Ordering::SeqCst)
+}
+
+/// Tells the previous process, if any, to shut down now that this one is
+/// serving.
+pub fn notify_parent() {
+  let pid = match INHERITED.lock().unwrap().parent.take() {
+    Some(pid) => pid,
+    None => return,
+  };
+  // The variable might have leaked from elsewhere; only our own parent
+  // handed anything over.
+  if pid != unsafe { libc::getppid() } {
+    warn!("ignoring {PARENT_PID}={pid}, which is not the parent process");
+    return;
+  }
+  if unsafe { libc::kill(pid, libc::SIGTERM) } == 0 {
+    info!("Took over from process {pid}");
+  } else {
+    let error = io::Error::last_os_error();
+    warn!("failed to tell process {pid} to shut down: {error}");
+  }
+}
+
+/// Hands `listener` and `lock` over to a new process on each `SIGUSR2`.
+pub async fn run_on_signal(listener: RawFd, lock: RawFd) {
+  let (mut sigusr2, mut sigterm) = match (
+    signal(SignalKind::user_defined2()),
+    signal(SignalKind::terminate()),
+  ) {
+    (Ok(x), Ok(y)) => (x, y),
+    (Err(error), _) | (_, Err(error)) => return warn!("failed to listen for signals: {error}"),
+  };
+  while sigusr2.recv().await.is_some() {
+    info!("SIGUSR2 received; starting a new process to hand over to");
+    // Set before the new process can possibly tell this one to shut down.
+    HANDED_OVER.store(true, Ordering::SeqCst);
+    let mut child = match spawn(listener, lock) {
+      Ok(child) => child,
+      Err(error) => {
+        HANDED_OVER.store(false, Ordering::SeqCst);
+        error!("failed to start new process: {error}");
+        continue;
+      }
+    };
+    if wait_for_handover(&mut child, sigterm.recv()).await {
+      return;
+    }
+    HANDED_OVER.store(false, Ordering::SeqCst);
+  }
+}
+
+/// Waits until either `taken_over` completes, returning `true`, or `child`
+/// exits before that, meaning it failed to take over.
+async fn wait_for_handover(child: &mut Child, taken_over: impl Future) -> bool {
+  tokio::select! {
+    _ = taken_over => true,
+    status = child.wait() => {
+      match status {
+        Ok(status) => error!("new process exited before taking over ({status}); still serving"),
+        Err(error) => error!("failed to wait for new process: {error}; still serving"),
+      }
+      false
+    }
+  }
+}
+
+fn spawn(listener: RawFd, lock: RawFd) -> io::Result<Child> {
+  let mut args = env::args_os();
+  let program = (args.next()).ok_or_else(|| io::Error::other("no program name"))?;
+  let mut command = Command::new(program);
+  command
+    .args(args)
+    .env(LISTENER_FD, listener.to_string())
+    .env(LOCK_FD, lock.to_string())
+    .env(PARENT_PID, std::process::id().to_string());
+  // File descriptors are opened close-on-exec; only the new process's copies
+  // are kept open.
+  unsafe {
+    command.pre_exec(move || {
+      clear_cloexec(listener)?;
+      clear_cloexec(lock)
+    });
+  }
+  command.spawn()
+}
+
+fn clear_cloexec(fd: RawFd) -> io::Result<()> {
+  let flags = unsafe { libc::fcntl(fd, libc::F_GETFD) };
+  if flags < 0 || unsafe { libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) } < 0 {
+    return Err(io::Error::last_os_error());
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::collections::HashMap;
+  use std::process::Stdio;
+
+  #[test]
+  fn test_read_inherited() {
+    let vars = HashMap::from([(LISTENER_FD, "3"), (LOCK_FD, "4"), (PARENT_PID, "1234")]);
+    let inherited = Inherited::read(|var| vars.get(var).map(|x| x.to_string()));
+    let expected = Inherited {
+      listener: Some(3),
+      lock: Some(4),
+      parent: Some(1234),
+    };
+    assert_eq!(inherited, expected);
+
+    let vars = HashMap::from([(LISTENER_FD, "three"), (PARENT_PID, "")]);
+    let inherited = Inherited::read(|var| vars.get(var).map(|x| x.to_string()));
+    assert_eq!(inherited, Inherited::default());
+  }
+
+  #[test]
+  fn test_check_inherited() {
+    use std::os::unix::io::AsRawFd;
+
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let file = tempfile::tempfile().unwrap();
+    let socket = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+    assert!(check_listener(listener.as_raw_fd()).is_ok());
+    assert!(check_lock(file.as_raw_fd()).is_ok());
+
+    assert!(check_listener(file.as_raw_fd()).is_err());
+    assert!(check_listener(socket.as_raw_fd()).is_err());
+    assert!(check_lock(listener.as_raw_fd()).is_err());
+    assert!(check_listener(libc::STDIN_FILENO).is_err());
+    // Not open, as it is past any descriptor limit
+    assert!(check_lock(RawFd::MAX).is_err());
+  }
+
+  fn command(script: &str) -> Command {
+    let mut command = Command::new("sh");
+    command.args(["-c", script]).stdin(Stdio::null());
+    command
+  }
+
+  #[tokio::test]
+  async fn test_handover_failed() {
+    let mut child = command("exit 1").spawn().unwrap();
+    let taken_over = futures::future::pending::<()>();
+    assert!(!wait_for_handover(&mut child, taken_over).await);
+  }
+
+  #[tokio::test]
+  async fn test_handover_succeeded() {
+    let mut child = command("sleep 10").kill_on_drop(true).spawn().unwrap();
+    let taken_over = futures::future::ready(());
+    assert!(wait_for_handover(&mut child, taken_over).await);
+  }
+}
@@ -0,0 +1,391 @@
+use super::atomic::write_atomic;
+use super::error::Error;
+use super::{signing, ServerState};
+use data_encoding::{BASE64, HEXLOWER};
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::{HeaderName, HeaderValue, AUTHORIZATION, CONTENT_LENGTH, COOKIE, DATE};
+use hyper::http::request::Parts;
+use hyper::{Body, Method, Response, StatusCode};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+pub const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Set on responses sent again for a retried request.
+const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+/// Larger or streaming responses are not stored.
+const MAX_STORED_SIZE: u64 = 1024 * 1024;
+/// Headers that belong to a single exchange, and are not replayed.
+const SKIPPED_HEADERS: &[&str] = &["server-timing", "x-request-id"];
+
+/// A stored response, as one JSON file per key.
+#[derive(Debug, Serialize, Deserialize)]
+struct StoredResponse {
+  expires_at: u64,
+  /// Hash of the request's method, URI and body, so that a key reused for a
+  /// different request is caught.
+  fingerprint: String,
+  status: u16,
+  headers: Vec<(String, String)>,
+  /// Base64-encoded body.
+  body: String,
+}
+
+/// Responses to requests with an `Idempotency-Key`, stored under
+/// `<abel_path>/idempotency/<service>/<hash>.json` until they expire.
+///
+/// Keys are scoped to the caller, i.e. the hash covers the request's
+/// `Authorization` and `Cookie` along with the key, so that one caller
+/// cannot be replayed another's response.
+pub struct IdempotencyStore {
+  path: PathBuf,
+  in_flight: Mutex<HashSet<(String, String)>>,
+}
+
+/// What to do with a request carrying an `Idempotency-Key`.
+pub enum Idempotency<'a> {
+  /// Send this stored response instead of running the service.
+  Replay(Response<Body>),
+  /// Run the service, and store its response with [`Pending::finish`].
+  Pending(Pending<'a>),
+}
+
+impl IdempotencyStore {
+  pub fn new(abel_path: &Path) -> Self {
+    Self {
+      path: abel_path.join("idempotency"),
+      in_flight: Default::default(),
+    }
+  }
+
+  /// The request's key, if the method is not safe to be retried by itself.
+  pub fn key(parts: &Parts) -> Result<Option<String>, Error> {
+    if !matches!(
+      parts.method,
+      Method::POST | Method::PUT | Method::PATCH | Method::DELETE
+    ) {
+      return Ok(None);
+    }
+    let key = match parts.headers.get(IDEMPOTENCY_KEY) {
+      Some(x) => x,
+      None => return Ok(None),
+    };
+    (key.to_str().ok())
+      .filter(|x| !x.is_empty() && x.len() <= 255 && x.bytes().all(|b| b.is_ascii_graphic()))
+      .map(|x| Some(x.into()))
+      .ok_or_else(|| {
+        let msg = "must be 1 to 255 visible ASCII characters";
+        Error::from(("invalid idempotency key", json!({ "msg": msg })))
+      })
+  }
+
+  /// Finds the stored response for `key`, or marks it as in flight.
+  ///
+  /// Fails with 409 if a request with the same key is still being handled,
+  /// and with 422 if the key was used for a different request.
+  pub async fn begin(
+    &self,
+    service_name: &str,
+    key: String,
+    ttl: u64,
+    parts: &Parts,
+    body: &[u8],
+  ) -> Result<Idempotency<'_>, Error> {
+    let fingerprint = fingerprint(parts, body);
+    let entry = entry_hash(parts, &key);
+    let path = self.path.join(service_name).join(format!("{entry}.json"));
+    let in_flight_key = (service_name.to_string(), entry);
+    if !self.in_flight.lock().unwrap().insert(in_flight_key.clone()) {
+      return Err(Error::from((
+        409,
+        "idempotency key in use",
+        json!({ "msg": "a request with this key is still being handled" }),
+      )));
+    }
+    let pending = Pending {
+      store: self,
+      in_flight_key,
+      path,
+      fingerprint,
+      ttl,
+    };
+
+    let stored = match fs::read(&pending.path).await {
+      Ok(x) => serde_json::from_slice::<StoredResponse>(&x).ok(),
+      Err(_) => None,
+    };
+    match stored {
+      Some(stored) if stored.expires_at > unix_secs() => {
+        if stored.fingerprint != pending.fingerprint {
+          return Err(Error::from((
+            422,
+            "idempotency key reused",
+            json!({ "msg": "this key was used for a different request" }),
+          )));
+        }
+        Ok(Idempotency::Replay(stored.into_response()))
+      }
+      _ => Ok(Idempotency::Pending(pending)),
+    }
+  }
+
+  /// Removes expired responses of every service.
+  async fn sweep(&self) -> std::io::Result<()> {
+    let now = unix_secs();
+    let mut services = match fs::read_dir(&self.path).await {
+      Ok(x) => x,
+      Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+      Err(error) => return Err(error),
+    };
+    while let Some(service) = services.next_entry().await? {
+      let mut entries = fs::read_dir(service.path()).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        let expired = match fs::read(entry.path()).await {
+          Ok(x) => {
+            serde_json::from_slice::<StoredResponse>(&x).map_or(true, |x| x.expires_at <= now)
+          }
+          Err(_) => false,
+        };
+        if expired {
+          let _ = fs::remove_file(entry.path()).await;
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+impl StoredResponse {
+  fn into_response(self) -> Response<Body> {
+    let mut resp = Response::new(Body::from(
+      BASE64.decode(self.body.as_bytes()).unwrap_or_default(),
+    ));
+    *resp.status_mut() = StatusCode::from_u16(self.status).unwrap_or(StatusCode::OK);
+    let headers = resp.headers_mut();
+    for (name, value) in self.headers {
+      if let (Ok(name), Ok(value)) = (HeaderName::try_from(name), HeaderValue::try_from(value)) {
+        headers.append(name, value);
+      }
+    }
+    headers.insert(IDEMPOTENT_REPLAYED, HeaderValue::from_static("true"));
+    resp
+  }
+}
+
+/// A request whose key is in flight until this is dropped.
+pub struct Pending<'a> {
+  store: &'a IdempotencyStore,
+  in_flight_key: (String, String),
+  path: PathBuf,
+  fingerprint: String,
+  ttl: u64,
+}
+
+impl Pending<'_> {
+  /// Stores `resp`, unless it is a server error, which clients may retry, or
+  /// too large to keep.
+  pub async fn finish(self, resp: Response<Body>) -> Response<Body> {
+    let small = matches!(resp.body().size_hint().upper(), Some(x) if x <= MAX_STORED_SIZE);
+    if resp.status().is_server_error() || !small {
+      return resp;
+    }
+    let (parts, body) = resp.into_parts();
+    let body = match hyper::body::to_bytes(body).await {
+      Ok(x) => x,
+      Err(error) => {
+        let body = Body::wrap_stream(futures::stream::once(async { Err::<Bytes, _>(error) }));
+        return Response::from_parts(parts, body);
+      }
+    };
+
+    let headers = (parts.headers.iter())
+      .filter(|(k, _)| *k != CONTENT_LENGTH && *k != DATE && !SKIPPED_HEADERS.contains(&k.as_str()))
+      .filter_map(|(k, v)| Some((k.to_string(), v.to_str().ok()?.to_string())))
+      .collect();
+    let stored = StoredResponse {
+      expires_at: unix_secs() + self.ttl,
+      fingerprint: self.fingerprint.clone(),
+      status: parts.status.as_u16(),
+      headers,
+      body: BASE64.encode(&body),
+    };
+    if let Err(error) = self.write(&stored).await {
+      warn!("failed to store idempotent response: {error}");
+    }
+    Response::from_parts(parts, body.into())
+  }
+
+  async fn write(&self, stored: &StoredResponse) -> anyhow::Result<()> {
+    if let Some(parent) = self.path.parent() {
+      fs::create_dir_all(parent).await?;
+    }
+    write_atomic(&self.path, serde_json::to_vec(stored)?).await?;
+    Ok(())
+  }
+}
+
+impl Drop for Pending<'_> {
+  fn drop(&mut self) {
+    (self.store.in_flight.lock().unwrap()).remove(&self.in_flight_key);
+  }
+}
+
+/// Hash of `key` and who the request is made by. Signed requests are
+/// identified by their credential, as their signatures differ each time.
+fn entry_hash(parts: &Parts, key: &str) -> String {
+  let header = |name| (parts.headers.get(name)).map_or(&b""[..], HeaderValue::as_bytes);
+  let authorization = header(AUTHORIZATION);
+  let authorization = (std::str::from_utf8(authorization).ok())
+    .and_then(|x| x.strip_prefix(signing::SCHEME))
+    .and_then(|x| {
+      x.split(',')
+        .map(str::trim)
+        .find(|x| x.starts_with("Credential="))
+    })
+    .map_or(authorization, str::as_bytes);
+
+  let mut hasher = Sha256::new();
+  hasher.update(authorization);
+  hasher.update(b"\n");
+  hasher.update(header(COOKIE));
+  hasher.update(b"\n");
+  hasher.update(key);
+  HEXLOWER.encode(&hasher.finalize())
+}
+
+fn fingerprint(parts: &Parts, body: &[u8]) -> String {
+  let mut hasher = Sha256::new();
+  hasher.update(parts.method.as_str());
+  hasher.update(b"\n");
+  hasher.update(parts.uri.to_string());
+  hasher.update(b"\n");
+  hasher.update(body);
+  HEXLOWER.encode(&hasher.finalize())
+}
+
+fn unix_secs() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_secs())
+    .unwrap_or_default()
+}
+
+/// Removes expired responses once every hour.
+pub async fn run_sweeper(state: Arc<ServerState>) {
+  let mut interval = tokio::time::interval(Duration::from_secs(3600));
+  loop {
+    interval.tick().await;
+    if let Err(error) = state.idempotency.sweep().await {
+      warn!("failed to remove expired idempotent responses: {error}");
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::Request;
+  use tempfile::TempDir;
+
+  fn parts(method: Method, key: Option<&str>, authorization: &str) -> Parts {
+    let mut req = Request::builder()
+      .method(method)
+      .uri("/a/b")
+      .header(AUTHORIZATION, authorization);
+    if let Some(key) = key {
+      req = req.header(IDEMPOTENCY_KEY, key);
+    }
+    req.body(()).unwrap().into_parts().0
+  }
+
+  fn status(error: Error) -> StatusCode {
+    error.into_status_and_body().0
+  }
+
+  #[test]
+  fn test_key() {
+    let key = |method, key| IdempotencyStore::key(&parts(method, key, ""));
+    assert_eq!(key(Method::GET, Some("k")).unwrap(), None);
+    assert_eq!(key(Method::POST, None).unwrap(), None);
+    assert_eq!(key(Method::POST, Some("k")).unwrap().unwrap(), "k");
+    assert!(key(Method::POST, Some("a b")).is_err());
+    assert!(key(Method::POST, Some(&"k".repeat(256))).is_err());
+  }
+
+  #[tokio::test]
+  async fn test_replay() {
+    let dir = TempDir::new().unwrap();
+    let store = IdempotencyStore::new(dir.path());
+    let own = parts(Method::POST, Some("k"), "Abel a");
+    let begin = |body: &'static [u8], req| store.begin("a", "k".into(), 60, req, body);
+
+    let pending = match begin(b"body", &own).await.unwrap() {
+      Idempotency::Pending(x) => x,
+      Idempotency::Replay(_) => panic!("nothing stored yet"),
+    };
+    let error = begin(b"body", &own).await.err().unwrap();
+    assert_eq!(status(error), StatusCode::CONFLICT);
+    let resp = Response::builder()
+      .status(StatusCode::CREATED)
+      .header("x-request-id", "1")
+      .header("x-custom", "a")
+      .body(Body::from("created"))
+      .unwrap();
+    pending.finish(resp).await;
+
+    let resp = match begin(b"body", &own).await.unwrap() {
+      Idempotency::Replay(x) => x,
+      Idempotency::Pending(_) => panic!("response not stored"),
+    };
+    assert_eq!(resp.status(), StatusCode::CREATED);
+    assert_eq!(resp.headers()[IDEMPOTENT_REPLAYED], "true");
+    assert_eq!(resp.headers()["x-custom"], "a");
+    assert!(!resp.headers().contains_key("x-request-id"));
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    assert_eq!(body, "created");
+
+    let error = begin(b"other body", &own).await.err().unwrap();
+    assert_eq!(status(error), StatusCode::UNPROCESSABLE_ENTITY);
+
+    // Keys are scoped to the caller
+    let other = parts(Method::POST, Some("k"), "Abel b");
+    let result = begin(b"body", &other).await.unwrap();
+    assert!(matches!(result, Idempotency::Pending(_)));
+  }
+
+  #[tokio::test]
+  async fn test_not_stored() {
+    let dir = TempDir::new().unwrap();
+    let store = IdempotencyStore::new(dir.path());
+    let parts = parts(Method::POST, Some("k"), "");
+    let begin = |ttl| store.begin("a", "k".into(), ttl, &parts, b"");
+    let finish = |ttl, status| async move {
+      match begin(ttl).await.unwrap() {
+        Idempotency::Pending(x) => {
+          let mut resp = Response::new(Body::empty());
+          *resp.status_mut() = status;
+          x.finish(resp).await;
+        }
+        Idempotency::Replay(_) => panic!("unexpected replay"),
+      }
+    };
+
+    // Server errors may be retried
+    finish(60, StatusCode::SERVICE_UNAVAILABLE).await;
+    assert!(matches!(begin(60).await.unwrap(), Idempotency::Pending(_)));
+
+    // Expired, and then swept
+    finish(0, StatusCode::OK).await;
+    assert!(matches!(begin(60).await.unwrap(), Idempotency::Pending(_)));
+    store.sweep().await.unwrap();
+    let mut entries = fs::read_dir(dir.path().join("idempotency/a"))
+      .await
+      .unwrap();
+    assert!(entries.next_entry().await.unwrap().is_none());
+  }
+}
@@ -0,0 +1,115 @@
+#[cfg(unix)]
+use super::handover::inherited_lock;
+use anyhow::bail;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+
+/// Advisory lock on an Abel working path, held for the server's lifetime.
+///
+/// The lock file records the holder's PID and listening address, so that a
+/// second server pointed at the same path can tell who is using it. The lock
+/// is released by the OS when the holding process exits, so a stale lock file
+/// left over from a crash does not block startup. A process taking over from
+/// another one inherits its lock.
+#[derive(Debug)]
+pub struct PathLock {
+  _file: File,
+}
+
+impl PathLock {
+  pub fn acquire(abel_path: &Path, listen: SocketAddr) -> anyhow::Result<Self> {
+    if let Some(file) = inherited_lock() {
+      return Self::hold(file, listen);
+    }
+
+    let lock_path = abel_path.join("abel.lock");
+    let mut file = OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(false)
+      .open(&lock_path)?;
+
+    if !try_lock(&file)? {
+      let mut holder = String::new();
+      file.read_to_string(&mut holder)?;
+      let mut lines = holder.lines();
+      let pid = lines.next().unwrap_or("unknown");
+      let listen = lines.next().unwrap_or("unknown");
+      bail!(
+        "Abel path '{}' is in use by another instance (PID {pid}, listening on {listen})",
+        abel_path.display(),
+      );
+    }
+
+    Self::hold(file, listen)
+  }
+
+  fn hold(mut file: File, listen: SocketAddr) -> anyhow::Result<Self> {
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    writeln!(file, "{}\n{listen}", std::process::id())?;
+    file.sync_all()?;
+    Ok(Self { _file: file })
+  }
+}
+
+#[cfg(unix)]
+impl std::os::unix::io::AsRawFd for PathLock {
+  fn as_raw_fd(&self) -> std::os::unix::io::RawFd {
+    self._file.as_raw_fd()
+  }
+}
+
+#[cfg(not(unix))]
+fn inherited_lock() -> Option<File> {
+  None
+}
+
+#[cfg(unix)]
+fn try_lock(file: &File) -> std::io::Result<bool> {
+  use std::os::unix::io::AsRawFd;
+
+  let result = unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) };
+  if result == 0 {
+    Ok(true)
+  } else {
+    let error = std::io::Error::last_os_error();
+    if error.raw_os_error() == Some(libc::EWOULDBLOCK) {
+      Ok(false)
+    } else {
+      Err(error)
+    }
+  }
+}
+
+#[cfg(not(unix))]
+fn try_lock(_file: &File) -> std::io::Result<bool> {
+  Ok(true)
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_path_lock() {
+    let dir = TempDir::new().unwrap();
+    let listen = "127.0.0.1:3000".parse().unwrap();
+    let lock = PathLock::acquire(dir.path(), listen).unwrap();
+    let holder = std::fs::read_to_string(dir.path().join("abel.lock")).unwrap();
+    assert_eq!(holder, format!("{}\n{listen}\n", std::process::id()));
+
+    let error = PathLock::acquire(dir.path(), "127.0.0.1:3001".parse().unwrap());
+    let error = error.unwrap_err().to_string();
+    assert!(error.contains(&format!("PID {}", std::process::id())));
+    assert!(error.contains("listening on 127.0.0.1:3000"));
+
+    // Released once dropped, even with the lock file left behind
+    drop(lock);
+    PathLock::acquire(dir.path(), listen).unwrap();
+  }
+}
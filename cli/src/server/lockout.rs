@@ -0,0 +1,259 @@
+//! Locking out clients that keep presenting invalid tokens, since the
+//! management API is often reachable from the internet with only a UUID
+//! guarding it.
+//!
+//! Failures are counted per client IP, or per /64 prefix for IPv6, as a
+//! single host usually has a whole /64 to pick addresses from. Once they
+//! reach `max_failures`, the IP is locked out, for twice as long on each
+//! further failure. Requests from it that present a token are then rejected
+//! without checking the token; anonymous ones, e.g. to public services, are not
+//! affected. Failures and lockouts are logged to the `abel::audit` target.
+//!
+//! Behind a reverse proxy every client shares the proxy's address, so a few
+//! failures would lock everyone out. List the proxy in `trusted_proxies` to
+//! count failures by the client it names in `X-Forwarded-For` instead, or in
+//! `exempt`, which also exempts its clients.
+
+use super::ServerState;
+use hyper::HeaderMap;
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// `auth_lockout` in `config.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LockoutConfig {
+  /// Failed attempts in a row from one IP before it is locked out. Defaults
+  /// to 5; 0 disables lockouts.
+  pub max_failures: Option<u32>,
+  /// Seconds of the first lockout. Defaults to 60.
+  pub lockout: Option<u64>,
+  /// Longest lockout in seconds, after which failures are also forgotten.
+  /// Defaults to 3600.
+  pub max_lockout: Option<u64>,
+  /// IPs that are never locked out.
+  #[serde(default)]
+  pub exempt: Vec<IpAddr>,
+  /// Reverse proxies whose `X-Forwarded-For` is trusted to name the client.
+  /// Other peers' headers are ignored, as anyone may send them.
+  #[serde(default)]
+  pub trusted_proxies: Vec<IpAddr>,
+}
+
+struct Failures {
+  count: u32,
+  last: Instant,
+  locked_until: Option<Instant>,
+}
+
+pub struct Lockout {
+  max_failures: u32,
+  lockout: Duration,
+  max_lockout: Duration,
+  exempt: HashSet<IpAddr>,
+  trusted_proxies: HashSet<IpAddr>,
+  /// Keyed by [`client_key`].
+  clients: Mutex<HashMap<IpAddr, Failures>>,
+}
+
+/// Address failures of `ip` are counted under: IPv4 addresses as they are,
+/// including IPv4-mapped IPv6 ones, and other IPv6 addresses by their /64
+/// prefix.
+fn client_key(ip: IpAddr) -> IpAddr {
+  match ip {
+    IpAddr::V4(_) => ip,
+    IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+      Some(v4) => IpAddr::V4(v4),
+      None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+    },
+  }
+}
+
+impl Lockout {
+  /// Returns `None` if lockouts are disabled.
+  pub fn new(config: LockoutConfig) -> Option<Self> {
+    let max_failures = config.max_failures.unwrap_or(5);
+    (max_failures > 0).then(|| Self {
+      max_failures,
+      lockout: Duration::from_secs(config.lockout.unwrap_or(60)),
+      max_lockout: Duration::from_secs(config.max_lockout.unwrap_or(3600)),
+      exempt: config.exempt.into_iter().collect(),
+      trusted_proxies: config.trusted_proxies.into_iter().collect(),
+      clients: Default::default(),
+    })
+  }
+
+  /// The client a request from `peer` is counted against: the rightmost
+  /// address in `X-Forwarded-For` that is not a trusted proxy, if `peer` is
+  /// one, or `peer` itself.
+  pub fn client_ip(&self, peer: IpAddr, headers: &HeaderMap) -> IpAddr {
+    if !self.trusted_proxies.contains(&peer) {
+      return peer;
+    }
+    (headers.get_all("x-forwarded-for").iter())
+      .filter_map(|x| x.to_str().ok())
+      .flat_map(|x| x.split(','))
+      .rev()
+      .map(|x| x.trim().parse::<IpAddr>())
+      .find(|x| !matches!(x, Ok(x) if self.trusted_proxies.contains(x)))
+      .and_then(Result::ok)
+      .unwrap_or(peer)
+  }
+
+  /// Time left until `ip` may authenticate again, if it is locked out.
+  pub fn check(&self, ip: IpAddr) -> Option<Duration> {
+    let clients = self.clients.lock().unwrap();
+    let locked_until = clients.get(&client_key(ip))?.locked_until?;
+    let left = locked_until.saturating_duration_since(Instant::now());
+    (!left.is_zero()).then_some(left)
+  }
+
+  pub fn fail(&self, ip: IpAddr) {
+    if self.exempt.contains(&ip) {
+      return;
+    }
+    let now = Instant::now();
+    let mut clients = self.clients.lock().unwrap();
+    let failures = clients.entry(client_key(ip)).or_insert(Failures {
+      count: 0,
+      last: now,
+      locked_until: None,
+    });
+    if now.duration_since(failures.last) > self.max_lockout {
+      failures.count = 0;
+    }
+    failures.count += 1;
+    failures.last = now;
+    let count = failures.count;
+    warn!(target: "abel::audit", "failed authentication from {ip} ({count} in a row)");
+
+    if count >= self.max_failures {
+      let factor = 1u32
+        .checked_shl(count - self.max_failures)
+        .unwrap_or(u32::MAX);
+      let lockout = (self.lockout.saturating_mul(factor)).min(self.max_lockout);
+      failures.locked_until = Some(now + lockout);
+      warn!(
+        target: "abel::audit",
+        "locked out {ip} for {}s after {count} failed authentications",
+        lockout.as_secs(),
+      );
+    }
+  }
+
+  pub fn succeed(&self, ip: IpAddr) {
+    let mut clients = self.clients.lock().unwrap();
+    if clients.remove(&client_key(ip)).is_some() {
+      info!(target: "abel::audit", "{ip} authenticated after failed attempts");
+    }
+  }
+
+  /// Forgets clients whose failures no longer count.
+  fn sweep(&self) {
+    let now = Instant::now();
+    let mut clients = self.clients.lock().unwrap();
+    clients.retain(|_, x| {
+      now.duration_since(x.last) <= self.max_lockout || x.locked_until.is_some_and(|t| t > now)
+    });
+  }
+}
+
+pub async fn run_sweeper(state: Arc<ServerState>) {
+  let lockout = match &state.lockout {
+    Some(lockout) => lockout,
+    None => return,
+  };
+  let mut interval = tokio::time::interval(Duration::from_secs(600));
+  loop {
+    interval.tick().await;
+    lockout.sweep();
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn lockout(exempt: Vec<IpAddr>) -> Lockout {
+    Lockout::new(LockoutConfig {
+      max_failures: Some(2),
+      lockout: Some(60),
+      max_lockout: Some(150),
+      exempt,
+      trusted_proxies: vec![ip("10.0.0.1"), ip("10.0.0.2")],
+    })
+    .unwrap()
+  }
+
+  fn ip(x: &str) -> IpAddr {
+    x.parse().unwrap()
+  }
+
+  #[test]
+  fn test_lockout() {
+    let lockout = lockout(Vec::new());
+    let a = ip("192.0.2.1");
+    lockout.fail(a);
+    assert!(lockout.check(a).is_none());
+    lockout.fail(a);
+    assert!(lockout.check(a).unwrap() > Duration::from_secs(59));
+    assert!(lockout.check(ip("192.0.2.2")).is_none());
+
+    // Doubled on each further failure, up to `max_lockout`
+    lockout.fail(a);
+    assert!(lockout.check(a).unwrap() > Duration::from_secs(119));
+    lockout.fail(a);
+    assert!(lockout.check(a).unwrap() <= Duration::from_secs(150));
+
+    lockout.succeed(a);
+    assert!(lockout.check(a).is_none());
+  }
+
+  #[test]
+  fn test_lockout_exempt() {
+    let a = ip("192.0.2.1");
+    let lockout = lockout(vec![a]);
+    (0..5).for_each(|_| lockout.fail(a));
+    assert!(lockout.check(a).is_none());
+  }
+
+  #[test]
+  fn test_lockout_ipv6_prefix() {
+    let lockout = lockout(Vec::new());
+    lockout.fail(ip("2001:db8:0:1::1"));
+    lockout.fail(ip("2001:db8:0:1::2"));
+    assert!(lockout.check(ip("2001:db8:0:1:abcd::3")).is_some());
+    assert!(lockout.check(ip("2001:db8:0:2::1")).is_none());
+
+    // Mapped IPv4 addresses are the IPv4 clients they stand for
+    lockout.fail(ip("192.0.2.1"));
+    lockout.fail(ip("::ffff:192.0.2.1"));
+    assert!(lockout.check(ip("192.0.2.1")).is_some());
+    assert!(lockout.check(ip("::ffff:192.0.2.2")).is_none());
+  }
+
+  #[test]
+  fn test_client_ip() {
+    let lockout = lockout(Vec::new());
+    let client_ip = |peer: &str, forwarded: &[&str]| {
+      let mut headers = HeaderMap::new();
+      for x in forwarded {
+        headers.append("x-forwarded-for", x.parse().unwrap());
+      }
+      lockout.client_ip(ip(peer), &headers)
+    };
+
+    assert_eq!(client_ip("192.0.2.1", &["198.51.100.1"]), ip("192.0.2.1"));
+    assert_eq!(client_ip("10.0.0.1", &["198.51.100.1"]), ip("198.51.100.1"));
+    // Only the entries appended by trusted proxies are believed
+    assert_eq!(
+      client_ip("10.0.0.1", &["203.0.113.1, 198.51.100.1", "10.0.0.2"]),
+      ip("198.51.100.1"),
+    );
+    assert_eq!(client_ip("10.0.0.1", &["forged, junk"]), ip("10.0.0.1"));
+    assert_eq!(client_ip("10.0.0.1", &[]), ip("10.0.0.1"));
+  }
+}
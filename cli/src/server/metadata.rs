@@ -1,3 +1,5 @@
+use super::atomic::write_atomic;
+use super::git::GitInfo;
 use super::Result;
 use serde::{Deserialize, Serialize};
 use std::path::Path;
@@ -9,6 +11,16 @@ use uuid::Uuid;
 pub struct Metadata {
   pub uuid: Uuid,
   pub started: bool,
+  /// SHA-256 of the uploaded source, in hex.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub source_hash: Option<String>,
+  /// URI the source is opened from through the server's source backends,
+  /// instead of a stored file.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub source: Option<String>,
+  /// Commit the source was checked out from, if deployed from git.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub git: Option<GitInfo>,
 }
 
 impl Metadata {
@@ -18,13 +30,13 @@ impl Metadata {
   }
 
   pub async fn write(&self, path: &Path) -> io::Result<()> {
-    fs::write(path, serde_json::to_string(self)?).await
+    write_atomic(path, serde_json::to_string(self)?).await
   }
 
   pub async fn modify(path: &Path, f: impl FnOnce(&mut Self)) -> Result<()> {
     let mut metadata: Metadata = serde_json::from_slice(&fs::read(path).await?)?;
     f(&mut metadata);
-    fs::write(path, serde_json::to_vec(&metadata)?).await?;
+    write_atomic(path, serde_json::to_vec(&metadata)?).await?;
     Ok(())
   }
 }
@@ -0,0 +1,149 @@
+//! Upgrades on-disk layout of an Abel path to the current one.
+//!
+//! Layout version is stored in `<abel_path>/layout_version`. Paths without it
+//! are either fresh, or created before versioning was introduced (version 0).
+
+use super::atomic::write_atomic;
+use anyhow::{bail, Context};
+use log::info;
+use std::path::Path;
+use tokio::fs;
+
+pub const LAYOUT_VERSION: u32 = 1;
+
+pub async fn migrate(abel_path: &Path) -> anyhow::Result<()> {
+  let marker_path = abel_path.join("layout_version");
+  let mut version = if marker_path.exists() {
+    let content = fs::read_to_string(&marker_path).await?;
+    (content.trim().parse::<u32>())
+      .with_context(|| format!("invalid layout version: {content:?}"))?
+  } else if abel_path.join("services").exists() {
+    0
+  } else {
+    // Fresh Abel path
+    write_atomic(&marker_path, LAYOUT_VERSION.to_string()).await?;
+    return Ok(());
+  };
+
+  if version > LAYOUT_VERSION {
+    bail!(
+      "Abel path '{}' has layout version {version}, but this version of Abel only supports up to \
+      {LAYOUT_VERSION}; please upgrade Abel",
+      abel_path.display(),
+    );
+  }
+
+  while version < LAYOUT_VERSION {
+    info!(
+      "Migrating Abel path layout from version {version} to {}",
+      version + 1
+    );
+    match version {
+      0 => v0_to_v1(abel_path).await,
+      _ => unreachable!(),
+    }
+    .with_context(|| format!("failed to migrate layout from version {version}"))?;
+    version += 1;
+    write_atomic(&marker_path, version.to_string()).await?;
+  }
+  Ok(())
+}
+
+/// Packs Hive-era `src/` folders of services into `source.asar`, and creates
+/// directories introduced since then.
+async fn v0_to_v1(abel_path: &Path) -> anyhow::Result<()> {
+  let mut services = fs::read_dir(abel_path.join("services")).await?;
+  while let Some(service_folder) = services.next_entry().await? {
+    if !service_folder.file_type().await?.is_dir() {
+      continue;
+    }
+    let path = service_folder.path();
+    let src_path = path.join("src");
+    if !src_path.is_dir() {
+      continue;
+    }
+    if path.join("source.asar").exists() || path.join("source.lua").exists() {
+      bail!("both src/ and source file found in '{}'", path.display());
+    }
+
+    let temp_path = path.join("source.asar.tmp");
+    let mut dest = fs::File::create(&temp_path).await?;
+    hive_asar::pack_dir(&src_path, &mut dest).await?;
+    dest.sync_all().await?;
+    fs::rename(&temp_path, path.join("source.asar")).await?;
+    fs::remove_dir_all(&src_path).await?;
+    info!("Packed '{}' into source.asar", src_path.display());
+  }
+
+  for dir in ["storage", "cache"] {
+    let dir = abel_path.join(dir);
+    if !dir.exists() {
+      fs::create_dir(dir).await?;
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tempfile::TempDir;
+
+  async fn layout_version(abel_path: &Path) -> String {
+    fs::read_to_string(abel_path.join("layout_version"))
+      .await
+      .unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_migrate_fresh() {
+    let dir = TempDir::new().unwrap();
+    migrate(dir.path()).await.unwrap();
+    assert_eq!(layout_version(dir.path()).await, LAYOUT_VERSION.to_string());
+
+    let newer = (LAYOUT_VERSION + 1).to_string();
+    fs::write(dir.path().join("layout_version"), &newer)
+      .await
+      .unwrap();
+    assert!(migrate(dir.path()).await.is_err());
+    assert_eq!(layout_version(dir.path()).await, newer);
+  }
+
+  #[tokio::test]
+  async fn test_migrate_v0() {
+    let dir = TempDir::new().unwrap();
+    let service_path = dir.path().join("services/a");
+    fs::create_dir_all(service_path.join("src")).await.unwrap();
+    fs::write(service_path.join("src/main.lua"), "-- main")
+      .await
+      .unwrap();
+
+    migrate(dir.path()).await.unwrap();
+    assert_eq!(layout_version(dir.path()).await, "1");
+    assert!(!service_path.join("src").exists());
+    let archive_path = service_path.join("source.asar");
+    let mut archive = hive_asar::Archive::new_from_file(archive_path)
+      .await
+      .unwrap();
+    let mut content = String::new();
+    let mut file = archive.get("main.lua").await.unwrap();
+    tokio::io::AsyncReadExt::read_to_string(&mut file, &mut content)
+      .await
+      .unwrap();
+    assert_eq!(content, "-- main");
+    assert!(dir.path().join("storage").is_dir());
+    assert!(dir.path().join("cache").is_dir());
+  }
+
+  #[tokio::test]
+  async fn test_migrate_v0_conflict() {
+    let dir = TempDir::new().unwrap();
+    let service_path = dir.path().join("services/a");
+    fs::create_dir_all(service_path.join("src")).await.unwrap();
+    fs::write(service_path.join("source.lua"), "")
+      .await
+      .unwrap();
+    assert!(migrate(dir.path()).await.is_err());
+    assert!(!dir.path().join("layout_version").exists());
+  }
+}
@@ -0,0 +1,492 @@
+use super::record::SENSITIVE_HEADERS;
+use super::ServerState;
+use abel_core::{MirrorCompare, MirrorConfig};
+use hyper::body::{Bytes, HttpBody};
+use hyper::http::request::Parts;
+use hyper::{Body, HeaderMap, Request, Response, StatusCode};
+use log::{debug, warn};
+use once_cell::sync::Lazy;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::oneshot;
+
+/// Responses larger than this, primary or shadow, are not compared.
+const MAX_COMPARE_SIZE: u64 = 4 * 1024 * 1024;
+
+/// Differences beyond this are left out of the diff log.
+const MAX_DIFFS: usize = 32;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+  reqwest::Client::builder()
+    .timeout(Duration::from_secs(30))
+    .build()
+    .unwrap()
+});
+
+pub fn should_mirror(config: &MirrorConfig) -> bool {
+  config.percent >= 100. || rand::random::<f64>() * 100. < config.percent
+}
+
+/// Status, headers and buffered body of a response.
+pub struct ResponseSnapshot {
+  status: StatusCode,
+  headers: HeaderMap,
+  body: Bytes,
+}
+
+/// Buffers the primary response and hands a copy of it to the mirror task
+/// for comparison, if it is small enough.
+pub async fn send_primary(
+  tx: oneshot::Sender<ResponseSnapshot>,
+  resp: Response<Body>,
+) -> Response<Body> {
+  let small = matches!(resp.body().size_hint().upper(), Some(x) if x <= MAX_COMPARE_SIZE);
+  if !small {
+    return resp;
+  }
+  let (parts, body) = resp.into_parts();
+  match hyper::body::to_bytes(body).await {
+    Ok(body) => {
+      let _ = tx.send(ResponseSnapshot {
+        status: parts.status,
+        headers: parts.headers.clone(),
+        body: body.clone(),
+      });
+      Response::from_parts(parts, body.into())
+    }
+    Err(error) => {
+      let body = Body::wrap_stream(futures::stream::once(async { Err::<Bytes, _>(error) }));
+      Response::from_parts(parts, body)
+    }
+  }
+}
+
+/// Sends a copy of the request to the mirror target in the background.
+///
+/// The response is discarded after being compared against `primary`, if
+/// comparison is configured and both are small enough; only failures and
+/// mismatches are counted in metrics.
+pub fn mirror(
+  state: Arc<ServerState>,
+  service_name: String,
+  config: MirrorConfig,
+  sub_path: &str,
+  parts: &Parts,
+  body: Bytes,
+  primary: Option<oneshot::Receiver<ResponseSnapshot>>,
+) {
+  let path_and_query = match parts.uri.query() {
+    Some(query) => format!("{sub_path}?{query}"),
+    None => sub_path.into(),
+  };
+  let sub_path = sub_path.to_string();
+  let method = parts.method.clone();
+  let mut headers = parts.headers.clone();
+  headers.remove("host");
+  for name in SENSITIVE_HEADERS {
+    headers.remove(*name);
+  }
+
+  tokio::spawn(async move {
+    // Shadow bodies are only read if there is something to compare them to
+    let compare = config.compare.as_ref().zip(primary);
+    let read_body = compare.is_some();
+    let target = &config.target;
+    let result = if target.starts_with("http://") || target.starts_with("https://") {
+      let url = format!("{}{path_and_query}", target.trim_end_matches('/'));
+      let result = async {
+        let mut resp = (CLIENT.request(method, url))
+          .headers(headers)
+          .body(body)
+          .send()
+          .await
+          .map_err(|x| x.to_string())?;
+        let status = resp.status();
+        if !read_body {
+          return Ok((status, None));
+        }
+        let mut body = LimitedBody::default();
+        while let Some(chunk) = resp.chunk().await.map_err(|x| x.to_string())? {
+          if !body.push(&chunk) {
+            return Ok((status, None));
+          }
+        }
+        let snapshot = body.snapshot(status, resp.headers().clone());
+        Ok::<_, String>((status, Some(snapshot)))
+      };
+      result.await
+    } else {
+      let target = state.abel.resolve_service_name(target);
+      let mut req = Request::new(Body::from(body));
+      *req.method_mut() = method;
+      *req.headers_mut() = headers;
+      let result = async {
+        *req.uri_mut() = format!("/{target}{path_and_query}")
+          .parse()
+          .map_err(|_| "invalid mirrored URI".to_string())?;
+        let service = (state.abel.activate_service(&target).await).map_err(|x| x.to_string())?;
+        let resp =
+          (state.abel.run_service(service, sub_path, req).await).map_err(|x| x.to_string())?;
+        let (parts, mut resp_body) = resp.into_parts();
+        if !read_body {
+          return Ok((parts.status, None));
+        }
+        let mut body = LimitedBody::default();
+        while let Some(chunk) = resp_body.data().await {
+          if !body.push(&chunk.map_err(|x| x.to_string())?) {
+            return Ok((parts.status, None));
+          }
+        }
+        Ok((
+          parts.status,
+          Some(body.snapshot(parts.status, parts.headers)),
+        ))
+      };
+      result.await
+    };
+
+    let shadow = match result {
+      Ok((status, shadow)) => {
+        (state.abel).record_mirror(&service_name, status.is_server_error());
+        shadow
+      }
+      Err(error) => {
+        debug!("failed to mirror request of service '{service_name}': {error}");
+        state.abel.record_mirror(&service_name, true);
+        return;
+      }
+    };
+
+    // Shadow is missing if it was too large to compare
+    if let (Some(shadow), Some((compare, primary))) = (shadow, compare) {
+      // Primary failed or was too large to compare
+      let primary = match primary.await {
+        Ok(primary) => primary,
+        Err(_) => return,
+      };
+      let diffs = diff(compare, &primary, &shadow);
+      (state.abel).record_mirror_comparison(&service_name, !diffs.is_empty());
+      let sample_rate = compare.log_sample_rate.unwrap_or(1.);
+      if !diffs.is_empty() && (sample_rate >= 1. || rand::random::<f64>() < sample_rate) {
+        let entry = DiffLogEntry {
+          timestamp: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|x| x.as_secs_f64())
+            .unwrap_or_default(),
+          service: &service_name,
+          target: &config.target,
+          path: &path_and_query,
+          diffs,
+        };
+        if let Err(error) = write_diff_log(&state, &entry).await {
+          warn!("failed to write mirror diff log of service '{service_name}': {error}");
+        }
+      }
+    }
+  });
+}
+
+/// Shadow response body, buffered up to [`MAX_COMPARE_SIZE`].
+#[derive(Default)]
+struct LimitedBody(Vec<u8>);
+
+impl LimitedBody {
+  /// Appends `chunk`, or returns false if the body would then be too large
+  /// to compare.
+  fn push(&mut self, chunk: &[u8]) -> bool {
+    let fits = (self.0.len() + chunk.len()) as u64 <= MAX_COMPARE_SIZE;
+    if fits {
+      self.0.extend_from_slice(chunk);
+    }
+    fits
+  }
+
+  fn snapshot(self, status: StatusCode, headers: HeaderMap) -> ResponseSnapshot {
+    ResponseSnapshot {
+      status,
+      headers,
+      body: self.0.into(),
+    }
+  }
+}
+
+#[derive(Serialize)]
+struct Diff {
+  field: String,
+  primary: Value,
+  shadow: Value,
+}
+
+#[derive(Serialize)]
+struct DiffLogEntry<'a> {
+  timestamp: f64,
+  service: &'a str,
+  target: &'a str,
+  path: &'a str,
+  diffs: Vec<Diff>,
+}
+
+fn diff(
+  compare: &MirrorCompare,
+  primary: &ResponseSnapshot,
+  shadow: &ResponseSnapshot,
+) -> Vec<Diff> {
+  let mut diffs = Vec::new();
+  if primary.status != shadow.status {
+    diffs.push(Diff {
+      field: "status".into(),
+      primary: primary.status.as_u16().into(),
+      shadow: shadow.status.as_u16().into(),
+    });
+  }
+
+  for name in &compare.headers {
+    let get = |headers: &HeaderMap| {
+      let values = (headers.get_all(name.as_str()).iter())
+        .map(|x| String::from_utf8_lossy(x.as_bytes()).into_owned())
+        .collect::<Vec<_>>();
+      values.join(", ")
+    };
+    let (p, s) = (get(&primary.headers), get(&shadow.headers));
+    if p != s {
+      diffs.push(Diff {
+        field: format!("header:{}", name.to_ascii_lowercase()),
+        primary: p.into(),
+        shadow: s.into(),
+      });
+    }
+  }
+
+  let json = serde_json::from_slice::<Value>(&primary.body)
+    .and_then(|p| Ok((p, serde_json::from_slice::<Value>(&shadow.body)?)));
+  match json {
+    Ok((mut p, mut s)) => {
+      for path in &compare.ignore_paths {
+        remove_pointer(&mut p, path);
+        remove_pointer(&mut s, path);
+      }
+      diff_json("body", &p, &s, &mut diffs);
+    }
+    Err(_) if primary.body != shadow.body => diffs.push(Diff {
+      field: "body".into(),
+      primary: json!({ "len": primary.body.len() }),
+      shadow: json!({ "len": shadow.body.len() }),
+    }),
+    Err(_) => {}
+  }
+
+  diffs.truncate(MAX_DIFFS);
+  diffs
+}
+
+fn remove_pointer(value: &mut Value, pointer: &str) {
+  if let Some((parent, key)) = pointer.rsplit_once('/') {
+    let key = key.replace("~1", "/").replace("~0", "~");
+    match value.pointer_mut(parent) {
+      Some(Value::Object(map)) => drop(map.remove(&key)),
+      Some(Value::Array(array)) => {
+        if let Ok(i) = key.parse::<usize>() {
+          if i < array.len() {
+            array.remove(i);
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+}
+
+fn diff_json(field: &str, p: &Value, s: &Value, diffs: &mut Vec<Diff>) {
+  if diffs.len() >= MAX_DIFFS {
+    return;
+  }
+  match (p, s) {
+    (Value::Object(p), Value::Object(s)) => {
+      for (k, pv) in p {
+        let field = format!("{field}/{}", k.replace('~', "~0").replace('/', "~1"));
+        match s.get(k) {
+          Some(sv) => diff_json(&field, pv, sv, diffs),
+          None => diffs.push(Diff {
+            field,
+            primary: pv.clone(),
+            shadow: Value::Null,
+          }),
+        }
+      }
+      for (k, sv) in s.iter().filter(|(k, _)| !p.contains_key(*k)) {
+        diffs.push(Diff {
+          field: format!("{field}/{}", k.replace('~', "~0").replace('/', "~1")),
+          primary: Value::Null,
+          shadow: sv.clone(),
+        });
+      }
+    }
+    (Value::Array(pa), Value::Array(sa)) if pa.len() == sa.len() => {
+      for (i, (pv, sv)) in pa.iter().zip(sa).enumerate() {
+        diff_json(&format!("{field}/{i}"), pv, sv, diffs);
+      }
+    }
+    _ if p != s => diffs.push(Diff {
+      field: field.into(),
+      primary: p.clone(),
+      shadow: s.clone(),
+    }),
+    _ => {}
+  }
+}
+
+async fn write_diff_log(state: &ServerState, entry: &DiffLogEntry<'_>) -> std::io::Result<()> {
+  let dir = state.abel_path.join("mirror-diffs");
+  if !dir.exists() {
+    fs::create_dir(&dir).await?;
+  }
+  let mut line = serde_json::to_vec(entry)?;
+  line.push(b'\n');
+  let mut file = OpenOptions::new()
+    .create(true)
+    .append(true)
+    .open(dir.join(format!("{}.jsonl", entry.service)))
+    .await?;
+  file.write_all(&line).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::Config;
+  use crate::server::tests::state;
+  use crate::source::SingleSource;
+  use abel_core::source::Source;
+  use tempfile::TempDir;
+
+  fn snapshot(status: u16, etag: &str, body: &str) -> ResponseSnapshot {
+    let mut headers = HeaderMap::new();
+    headers.insert("etag", etag.parse().unwrap());
+    ResponseSnapshot {
+      status: StatusCode::from_u16(status).unwrap(),
+      headers,
+      body: Bytes::from(body.to_string()),
+    }
+  }
+
+  fn fields(diffs: &[Diff]) -> Vec<&str> {
+    diffs.iter().map(|x| &*x.field).collect()
+  }
+
+  #[test]
+  fn test_diff() {
+    let compare = MirrorCompare {
+      headers: vec!["ETag".into()],
+      ignore_paths: vec!["/meta/time".into(), "/list/0".into()],
+      log_sample_rate: None,
+    };
+    let primary = snapshot(
+      200,
+      "1",
+      r#"{ "meta": { "time": 1 }, "a/b": 1, "list": [0, 1], "x": 1 }"#,
+    );
+    let shadow = snapshot(
+      201,
+      "2",
+      r#"{ "meta": { "time": 2 }, "a/b": 2, "list": [1, 1], "y": 1 }"#,
+    );
+    let diffs = diff(&compare, &primary, &shadow);
+    assert_eq!(fields(&diffs), [
+      "status", "header:etag", "body/a~1b", "body/x", "body/y"
+    ]);
+    assert_eq!(diffs[0].shadow, 201);
+    assert_eq!(
+      (&diffs[3].primary, &diffs[3].shadow),
+      (&json!(1), &Value::Null)
+    );
+
+    let diffs = diff(
+      &compare,
+      &snapshot(200, "1", "a"),
+      &snapshot(200, "1", "bc"),
+    );
+    assert_eq!(fields(&diffs), ["body"]);
+    assert_eq!(diffs[0].shadow, json!({ "len": 2 }));
+    let same = diff(&compare, &snapshot(200, "1", "a"), &snapshot(200, "1", "a"));
+    assert!(same.is_empty());
+
+    let many = serde_json::to_string(&(0..100).collect::<Vec<_>>()).unwrap();
+    let diffs = diff(
+      &compare,
+      &snapshot(200, "1", "[]"),
+      &snapshot(200, "1", &many),
+    );
+    assert_eq!(fields(&diffs), ["body"]);
+    let zeros = serde_json::to_string(&vec![0; 100]).unwrap();
+    let diffs = diff(
+      &compare,
+      &snapshot(200, "1", &zeros),
+      &snapshot(200, "1", &many),
+    );
+    assert_eq!(diffs.len(), MAX_DIFFS);
+  }
+
+  #[test]
+  fn test_limited_body() {
+    let mut body = LimitedBody::default();
+    assert!(body.push(&[0; MAX_COMPARE_SIZE as usize - 1]));
+    assert!(body.push(b"a"));
+    assert!(!body.push(b"b"));
+    let snapshot = body.snapshot(StatusCode::OK, HeaderMap::new());
+    assert_eq!(snapshot.body.len() as u64, MAX_COMPARE_SIZE);
+  }
+
+  #[tokio::test]
+  async fn test_mirror_to_service() {
+    let dir = TempDir::new().unwrap();
+    let state = state(dir.path(), Config::default()).await;
+    let source = Source::new(SingleSource::new(
+      r#"abel.listen("/", function(req) return { method = req.method } end)"#,
+    ));
+    (state.abel)
+      .cold_update_or_create_service("b", None, source, Default::default())
+      .await
+      .unwrap();
+
+    let config = MirrorConfig {
+      target: "b".into(),
+      percent: 100.,
+      compare: Some(Default::default()),
+    };
+    assert!(should_mirror(&config));
+    let (tx, rx) = oneshot::channel();
+    let parts = Request::get("/a/x").body(()).unwrap().into_parts().0;
+    mirror(
+      state.clone(),
+      "a".into(),
+      config,
+      "/",
+      &parts,
+      Bytes::new(),
+      Some(rx),
+    );
+    let primary = Response::new(Body::from(r#"{ "method": "POST" }"#));
+    let primary = send_primary(tx, primary).await;
+    let body = hyper::body::to_bytes(primary.into_body()).await.unwrap();
+    assert_eq!(body, r#"{ "method": "POST" }"#);
+
+    let log_path = dir.path().join("mirror-diffs/a.jsonl");
+    let log = async {
+      loop {
+        match fs::read_to_string(&log_path).await {
+          Ok(x) if x.ends_with('\n') => break x,
+          _ => tokio::time::sleep(Duration::from_millis(10)).await,
+        }
+      }
+    };
+    let log = tokio::time::timeout(Duration::from_secs(10), log).await;
+    let entry = serde_json::from_str::<Value>(&log.unwrap()).unwrap();
+    assert_eq!(entry["target"], "b");
+    assert_eq!(entry["diffs"][0]["field"], "body/method");
+    assert_eq!(entry["diffs"][0]["shadow"], "GET");
+  }
+}
@@ -3,32 +3,79 @@ pub mod metadata;
 pub mod types;
 pub mod upload;
 
+mod atomic;
+mod backup;
+mod canary;
+mod compress;
+mod cors;
 mod error;
+mod filter;
+mod git;
 mod handle;
+#[cfg(unix)]
+pub mod handover;
+mod idempotency;
+mod lock;
+mod lockout;
+mod migrate;
+mod mirror;
+mod openapi;
+mod otlp;
+mod record;
+mod replica;
+mod report;
+mod request_log;
+mod service_logs;
+mod signing;
+mod tls;
+mod tokens;
+mod usage;
+mod versions;
 
 pub use error::JsonError;
+pub use record::RecordedRequest;
+pub use request_log::{LoggedBody, LoggedRequest};
+pub use service_logs::LogLine;
 
-use crate::source::{AsarSource, SingleSource};
+use crate::source::{builtin_sources, read_config, ArchiveKind, SingleSource};
 use abel_core::service::Service;
 use abel_core::source::Source;
-use abel_core::{Abel, AbelOptions};
-use anyhow::bail;
+use abel_core::{Abel, AbelOptions, ClientAddr};
+use anyhow::{bail, Context};
 use config::{Config, ServerArgs};
+use cors::Cors;
 use error::Error;
+use futures::FutureExt;
 use handle::handle;
-use hive_asar::Archive;
+use hyper::server::conn::{AddrIncoming, AddrStream};
 use hyper::service::{make_service_fn, service_fn};
 use hyper::{Body, Request, Response, Server, StatusCode};
+use idempotency::IdempotencyStore;
+use lock::PathLock;
+use lockout::Lockout;
 use log::{error, info, warn};
 use metadata::Metadata;
+use migrate::migrate;
 use owo_colors::OwoColorize;
+use record::Recorder;
+use replica::Replica;
+use report::Reporter;
+use request_log::RequestLog;
 use serde::Serialize;
+use service_logs::Tee;
+use signing::{SeenSignatures, Signed};
+use std::collections::HashMap;
 use std::convert::Infallible;
 use std::io;
+use std::net::{IpAddr, SocketAddr};
 use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use tokens::{Auth, TokenStore};
 use tokio::fs;
-use tokio::io::AsyncReadExt;
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::server::TlsStream;
+use usage::UsageTracker;
 use uuid::Uuid;
 
 type Result<T, E = Error> = std::result::Result<T, E>;
@@ -37,35 +84,169 @@ pub struct ServerState {
   pub abel: Abel,
   pub abel_path: PathBuf,
   pub auth_token: Option<Uuid>,
+  pub tokens: TokenStore,
+  pub token_grace_period: u64,
+  pub reporter: Reporter,
+  pub verify_asar_integrity: bool,
+  /// Whether services' UUIDs are derived from their source.
+  pub deterministic_uuids: bool,
+  pub recorder: Option<Recorder>,
+  pub usage: UsageTracker,
+  pub replica: Option<Replica>,
+  /// Set once a shutdown signal is received.
+  pub draining: AtomicBool,
+  /// Default of services' `compress`.
+  pub compress: bool,
+  /// Previous versions of each service kept for rolling back.
+  pub kept_versions: usize,
+  /// Whether Lua stack tracebacks are removed from errors shown to
+  /// unauthenticated clients.
+  pub hide_tracebacks: bool,
+  /// Recent requests, kept when running in dev mode.
+  pub request_log: Option<RequestLog>,
+  /// Responses to requests with an `Idempotency-Key`.
+  pub idempotency: IdempotencyStore,
+  /// CORS of the management API, if enabled.
+  pub cors: Option<Cors>,
+  /// Lockouts of clients presenting invalid tokens, unless disabled.
+  pub lockout: Option<Lockout>,
+  /// Signatures of recent signed requests, against replaying them.
+  pub seen_signatures: SeenSignatures,
+  _lock: PathLock,
 }
 
-pub async fn run(config: Config, state: Arc<ServerState>) -> anyhow::Result<()> {
-  let state2 = state.clone();
-  let make_svc = make_service_fn(move |_conn| {
-    let state = state2.clone();
-    async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
-  });
+impl AsRef<Abel> for ServerState {
+  fn as_ref(&self) -> &Abel {
+    &self.abel
+  }
+}
 
-  let server = Server::bind(&config.listen)
-    .serve(make_svc)
-    .with_graceful_shutdown(shutdown_signal());
+/// Serves until a shutdown signal. With `handover`, `SIGUSR2` hands the
+/// server over to a new process.
+pub async fn run(config: Config, state: Arc<ServerState>, handover: bool) -> anyhow::Result<()> {
+  let new_service = {
+    let state = state.clone();
+    move |addr: Option<IpAddr>| {
+      let state = state.clone();
+      let service = service_fn(move |mut req: Request<Body>| {
+        if let Some(addr) = addr {
+          req.extensions_mut().insert(ClientAddr(addr));
+        }
+        handle(state.clone(), req)
+      });
+      async move { Ok::<_, Infallible>(service) }
+    }
+  };
 
-  info!("Abel is listening to {}", config.listen.underline());
+  let listener = bind(config.listen).await?;
+  #[cfg(unix)]
+  if handover {
+    use std::os::unix::io::AsRawFd;
+    let (listener, lock) = (listener.as_raw_fd(), state._lock.as_raw_fd());
+    tokio::spawn(handover::run_on_signal(listener, lock));
+  }
+  #[cfg(not(unix))]
+  let _ = handover;
+
+  let server = if let Some(tls) = &config.tls {
+    let incoming = tls::incoming(listener, tls).await?;
+    let server = Server::builder(hyper::server::accept::from_stream(incoming))
+      .serve(make_service_fn(move |conn: &TlsStream<TcpStream>| {
+        let addr = conn.get_ref().0.peer_addr();
+        new_service(addr.ok().map(|x| x.ip()))
+      }))
+      .with_graceful_shutdown(drain(state.clone(), config.drain_delay));
+    info!("Abel is listening to {} (HTTPS)", config.listen.underline());
+    server.boxed()
+  } else {
+    let server = Server::builder(AddrIncoming::from_listener(listener)?)
+      .serve(make_service_fn(move |conn: &AddrStream| {
+        new_service(Some(conn.remote_addr().ip()))
+      }))
+      .with_graceful_shutdown(drain(state.clone(), config.drain_delay));
+    info!("Abel is listening to {}", config.listen.underline());
+    server.boxed()
+  };
 
+  tokio::spawn(backup::run_scheduler(state.clone()));
+  tokio::spawn(usage::run_updater(state.clone()));
+  tokio::spawn(idempotency::run_sweeper(state.clone()));
+  tokio::spawn(lockout::run_sweeper(state.clone()));
+  tokio::spawn(stop_idle_services(state.clone()));
+  tokio::spawn(evict_remote_cache(state.clone()));
+  tokio::spawn(check_health(
+    state.clone(),
+    config.health_check_interval.unwrap_or(30),
+  ));
+  tokio::spawn(replica::run_sync(state.clone()));
+
+  #[cfg(unix)]
+  handover::notify_parent();
   if let Err(error) = server.await {
     error!("fatal server error: {}", error);
   }
 
+  #[cfg(unix)]
+  if handover::handed_over() {
+    info!("Handed over to the new process; leaving services to it");
+    return Ok(());
+  }
+
+  if let Err(error) = state.usage.update(&state).await {
+    warn!("failed to update usage report: {error}");
+  }
   state.abel.stop_all_services().await;
 
   Ok(())
 }
 
+async fn stop_idle_services(state: Arc<ServerState>) {
+  let mut interval = tokio::time::interval(std::time::Duration::from_secs(60));
+  loop {
+    interval.tick().await;
+    // Stopping a service drops its canary
+    for name in state.abel.stop_idle_services().await {
+      if let Err(error) = canary::remove_stored(&state, &name).await {
+        warn!("failed to remove canary of service '{name}': {error}");
+      }
+    }
+  }
+}
+
+async fn evict_remote_cache(state: Arc<ServerState>) {
+  let mut interval = tokio::time::interval(std::time::Duration::from_secs(3600));
+  loop {
+    interval.tick().await;
+    match state.abel.evict_remote_cache().await {
+      Ok(removed) if removed.modules > 0 => info!(
+        "Evicted {} remote modules ({} bytes) from cache",
+        removed.modules, removed.size
+      ),
+      Ok(_) => {}
+      Err(error) => warn!("failed to evict remote module cache: {error}"),
+    }
+  }
+}
+
+async fn check_health(state: Arc<ServerState>, secs: u64) {
+  let mut interval = tokio::time::interval(std::time::Duration::from_secs(secs.max(1)));
+  loop {
+    interval.tick().await;
+    state.abel.check_all_health().await;
+  }
+}
+
 pub fn init_logger() {
   if option_env!("RUST_LOG").is_none() {
     std::env::set_var("RUST_LOG", "INFO");
   }
-  pretty_env_logger::init();
+  let mut builder = pretty_env_logger::formatted_builder();
+  if let Ok(filters) = std::env::var("RUST_LOG") {
+    builder.parse_filters(&filters);
+  }
+  let logger = builder.build();
+  log::set_max_level(logger.filter());
+  log::set_boxed_logger(Box::new(Tee(logger))).unwrap();
 }
 
 pub async fn init_state(
@@ -74,17 +255,54 @@ pub async fn init_state(
 ) -> anyhow::Result<(PathBuf, Config, Arc<ServerState>)> {
   let ServerArgs { config, abel_path } = args;
 
-  let (local_storage_path, remote_cache_path) = init_paths(&abel_path).await;
   let config = init_config.merge(config);
+  if !abel_path.exists() {
+    fs::create_dir_all(&abel_path).await?;
+  }
+  let lock = PathLock::acquire(&abel_path, config.listen)?;
+  migrate(&abel_path).await?;
+  let (local_storage_path, remote_cache_path) = init_paths(&abel_path).await;
+  if let Some(otlp_config) = config.otlp.clone() {
+    otlp::init(otlp_config);
+  }
 
   let state = Arc::new(ServerState {
     abel: Abel::new(AbelOptions {
       runtime_pool_size: config.pool_size(),
       local_storage_path,
       remote_cache_path: Some(remote_cache_path),
+      idle: config.idle.unwrap_or_default(),
+      secrets: load_secrets(&abel_path, &config).await?,
+      secret_grants: config.secret_grants.clone().unwrap_or_default(),
+      isolate_cache_size: config.isolate_cache_size,
+      http_client: config.http_client.clone().unwrap_or_default(),
+      allow_unlocked: config.allow_unlocked.unwrap_or(false),
+      remote_credentials: config.remote_credentials.clone().unwrap_or_default(),
+      remote_cache: config.remote_cache.unwrap_or_default(),
+      modules: Vec::new(),
+      sources: builtin_sources(),
+      storage: config.storage.clone(),
     })?,
     abel_path: abel_path.clone(),
     auth_token: config.auth_token,
+    tokens: TokenStore::load(&abel_path).await?,
+    token_grace_period: config.token_grace_period.unwrap_or(86400),
+    reporter: Reporter::new(config.report_dsn.as_deref(), config.report_rate_limit()),
+    verify_asar_integrity: config.verify_asar_integrity.unwrap_or(false),
+    deterministic_uuids: config.deterministic_uuids.unwrap_or(false),
+    recorder: (config.record.clone()).map(|x| Recorder::new(x, &abel_path)),
+    usage: UsageTracker::new(&abel_path),
+    replica: config.replica.clone().map(Replica::new),
+    draining: AtomicBool::new(false),
+    compress: config.compress.unwrap_or(false),
+    kept_versions: config.kept_versions.unwrap_or(5),
+    hide_tracebacks: config.hide_tracebacks.unwrap_or(false),
+    request_log: (config.request_log).filter(|x| *x > 0).map(RequestLog::new),
+    idempotency: IdempotencyStore::new(&abel_path),
+    cors: config.management_cors.clone().map(Cors::new).transpose()?,
+    lockout: Lockout::new(config.auth_lockout.clone().unwrap_or_default()),
+    seen_signatures: SeenSignatures::default(),
+    _lock: lock,
   });
   Ok((abel_path, config, state))
 }
@@ -96,6 +314,26 @@ pub async fn init_state_with_stored_config(
   init_state(args, Config::load(config_path).await?).await
 }
 
+async fn load_secrets(
+  abel_path: &Path,
+  config: &Config,
+) -> anyhow::Result<HashMap<String, String>> {
+  let path = abel_path.join(
+    config
+      .secrets_file
+      .as_deref()
+      .unwrap_or("secrets.json".as_ref()),
+  );
+  if config.secrets_file.is_none() && !path.exists() {
+    return Ok(HashMap::new());
+  }
+  let content = (fs::read(&path).await)
+    .with_context(|| format!("failed to read secrets file {}", path.display()))?;
+  let secrets = serde_json::from_slice(&content)
+    .with_context(|| format!("invalid secrets file {}", path.display()))?;
+  Ok(secrets)
+}
+
 async fn init_paths(abel_path: &Path) -> (PathBuf, PathBuf) {
   async fn create_dir_path(path: impl AsRef<Path>) -> io::Result<()> {
     if !path.as_ref().exists() {
@@ -127,40 +365,51 @@ async fn init_paths(abel_path: &Path) -> (PathBuf, PathBuf) {
 }
 
 pub async fn load_saved_services(state: &ServerState, services_path: &Path) -> anyhow::Result<()> {
+  canary::recover(services_path).await?;
   let mut services = fs::read_dir(services_path).await?;
 
   while let Some(service_folder) = services.next_entry().await? {
     if service_folder.file_type().await?.is_dir() {
       let name = service_folder.file_name().to_string_lossy().into_owned();
+      if name.starts_with('.') {
+        continue;
+      }
       let result = async {
         let metadata_path = service_folder.path().join("metadata.json");
         let mut metadata = Metadata::read(&metadata_path).await?;
 
-        let asar_path = service_folder.path().join("source.asar");
-        let lua_path = service_folder.path().join("source.lua");
-
-        let (source, config) = match (asar_path.exists(), lua_path.exists()) {
-          (true, false) => {
-            let mut archive = Archive::new_from_file(asar_path).await?;
-
-            let config = if let Ok(mut config_file) = archive.get("abel.json").await {
-              let mut config_bytes = Vec::with_capacity(config_file.metadata().size as _);
-              config_file.read_to_end(&mut config_bytes).await?;
-              serde_json::from_slice(&config_bytes)?
-            } else {
-              Default::default()
-            };
+        let canary_path = service_folder.path().join("canary");
+        if canary_path.exists() {
+          warn!("Discarding canary of service '{name}' left from last run");
+          fs::remove_dir_all(canary_path).await?;
+        }
 
-            let source = Source::new(AsarSource(archive));
+        let lua_path = service_folder.path().join("source.lua");
+        let archives = [ArchiveKind::Asar, ArchiveKind::Zip]
+          .into_iter()
+          .map(|kind| (kind, service_folder.path().join(kind.file_name())))
+          .filter(|(_, path)| path.exists())
+          .collect::<Vec<_>>();
+
+        let (source, config) = match (&metadata.source, &archives[..], lua_path.exists()) {
+          (Some(uri), [], false) => {
+            let source = state.abel.open_source(uri).await?;
+            let config = read_config::<anyhow::Error>(&source).await?;
             (source, config)
           }
-          (false, true) => {
+          (Some(_), ..) => bail!("both a source URI and source files found"),
+          (None, [(kind, path)], false) => {
+            let source = kind.open(path, state.verify_asar_integrity).await?;
+            let config = read_config::<anyhow::Error>(&source).await?;
+            (source, config)
+          }
+          (None, [], true) => {
             let code = fs::read(lua_path).await?;
             let source = Source::new(SingleSource::new(code));
             (source, Default::default())
           }
-          (true, true) => bail!("both source.asar and source.lua found"),
-          (false, false) => bail!("neither source.asar nor source.lua found"),
+          (None, [], false) => bail!("none of source.asar, source.zip or source.lua found"),
+          _ => bail!("more than one of source.asar, source.zip and source.lua found"),
         };
 
         let (service, error_payload) = if metadata.started {
@@ -206,6 +455,16 @@ pub async fn load_saved_services(state: &ServerState, services_path: &Path) -> a
   Ok(())
 }
 
+/// Binds `addr`, unless a listener was handed over by a previous process.
+async fn bind(addr: SocketAddr) -> io::Result<TcpListener> {
+  #[cfg(unix)]
+  if let Some(listener) = handover::inherited_listener() {
+    listener.set_nonblocking(true)?;
+    return TcpListener::from_std(listener);
+  }
+  TcpListener::bind(addr).await
+}
+
 #[cfg(unix)]
 async fn shutdown_signal() {
   use tokio::select;
@@ -228,6 +487,18 @@ async fn shutdown_signal() {
   info!("gracefully shutting down");
 }
 
+/// Waits for a shutdown signal, then fails `/api/v1/readyz` for `delay` seconds
+/// before letting the server close its listener.
+async fn drain(state: Arc<ServerState>, delay: Option<u64>) {
+  shutdown_signal().await;
+  state.draining.store(true, Ordering::Release);
+  let delay = delay.unwrap_or(0);
+  if delay > 0 {
+    info!("Draining connections for {delay}s");
+    tokio::time::sleep(std::time::Duration::from_secs(delay)).await;
+  }
+}
+
 pub fn json_response(status: StatusCode, body: impl Serialize) -> Result<Response<Body>> {
   Ok(json_response_raw(status, body))
 }
@@ -240,14 +511,109 @@ pub fn json_response_raw(status: StatusCode, body: impl Serialize) -> Response<B
     .unwrap()
 }
 
-pub(crate) fn authenticate(state: &ServerState, req: &Request<Body>) -> bool {
-  let result = if let Some(uuid) = state.auth_token {
-    (req.headers())
-      .get("authorization")
-      .map(|x| x == &format!("Abel {uuid}"))
-      .unwrap_or(false)
+/// Abel token in the request's `Authorization` header, if any.
+fn presented_token(req: &Request<Body>) -> Option<&str> {
+  (req.headers().get("authorization"))
+    .and_then(|x| x.to_str().ok())
+    .and_then(|x| x.strip_prefix("Abel "))
+}
+
+/// Whether `req` presents a token, or is signed with one.
+pub(crate) fn presents_credentials(req: &Request<Body>) -> bool {
+  (req.headers().get("authorization"))
+    .and_then(|x| x.to_str().ok())
+    .is_some_and(|x| x.starts_with("Abel ") || x.starts_with(signing::SCHEME))
+}
+
+/// Identifies who `req` is made by. The body of a signed request is read to
+/// verify it, and put back afterwards.
+pub(crate) async fn authenticate(state: &ServerState, req: &mut Request<Body>) -> Auth {
+  let uuid = match state.auth_token {
+    Some(uuid) => uuid,
+    None => return Auth::Admin,
+  };
+  if let Some(signed) = Signed::parse(req) {
+    return authenticate_signed(state, req, uuid, signed).await;
+  }
+  match presented_token(req) {
+    Some(token) if signing::constant_time_eq(token.as_bytes(), uuid.to_string().as_bytes()) => {
+      Auth::Admin
+    }
+    Some(token) => (state.tokens.get(token)).map_or(Auth::Anonymous, Auth::Token),
+    None => Auth::Anonymous,
+  }
+}
+
+async fn authenticate_signed(
+  state: &ServerState,
+  req: &mut Request<Body>,
+  uuid: Uuid,
+  signed: Signed,
+) -> Auth {
+  if !signed.is_fresh() {
+    return Auth::Anonymous;
+  }
+  let (auth, key) = if signed.credential == "admin" {
+    (Auth::Admin, signing::signing_key(&uuid.to_string()))
   } else {
-    true
+    let token = (signed.credential.parse().ok()).and_then(|id| state.tokens.get_by_id(id));
+    match token {
+      Some((info, key)) => (Auth::Token(info), key),
+      None => return Auth::Anonymous,
+    }
   };
-  result
+  if signed.verify(req, key.as_bytes()).await && state.seen_signatures.insert(&signed) {
+    auth
+  } else {
+    Auth::Anonymous
+  }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+  use super::*;
+  use clap::Parser;
+  use config::ConfigArgs;
+
+  /// State of a server working in `abel_path`, for testing handlers.
+  pub async fn state(abel_path: &Path, config: Config) -> Arc<ServerState> {
+    let args = ServerArgs {
+      config: ConfigArgs::parse_from(["abel"]),
+      abel_path: abel_path.into(),
+    };
+    let config = Config {
+      pool_size: config.pool_size.or(Some(1)),
+      ..config
+    };
+    init_state(args, config).await.unwrap().2
+  }
+
+  #[tokio::test]
+  async fn test_authenticate_token() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let token = Uuid::new_v4();
+    let config = Config {
+      auth_token: Some(token),
+      ..Default::default()
+    };
+    let state = state(dir.path(), config).await;
+    let auth = |value: String| {
+      let state = state.clone();
+      async move {
+        let req = Request::get("/").header("authorization", value);
+        authenticate(&state, &mut req.body(Body::empty()).unwrap()).await
+      }
+    };
+    assert!(matches!(auth(format!("Abel {token}")).await, Auth::Admin));
+    let other = Uuid::new_v4();
+    assert!(matches!(
+      auth(format!("Abel {other}")).await,
+      Auth::Anonymous
+    ));
+    let prefix = &token.to_string()[..8];
+    assert!(matches!(
+      auth(format!("Abel {prefix}")).await,
+      Auth::Anonymous
+    ));
+  }
 }
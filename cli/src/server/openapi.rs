@@ -0,0 +1,439 @@
+//! OpenAPI description of the management API, served at
+//! `/api/v1/openapi.json`.
+//!
+//! It is built from [`ROUTES`], which lists every route `handle` serves
+//! under `/api/v1`, so a route added there should be added here too.
+
+use once_cell::sync::Lazy;
+use serde_json::{json, Map, Value};
+use Access::*;
+
+/// Prefix of the management API's versioned paths.
+pub const PREFIX: &str = "/api/v1";
+
+/// Who may call a route.
+#[derive(Clone, Copy)]
+enum Access {
+  Public,
+  /// A token with this scope, or the server's own auth token.
+  Scope(&'static str),
+  /// Only the server's own auth token.
+  Admin,
+}
+
+struct Route {
+  method: &'static str,
+  /// Path after [`PREFIX`], with parameters in braces.
+  path: &'static str,
+  summary: &'static str,
+  access: Access,
+  /// Names and descriptions of query parameters.
+  query: &'static [(&'static str, &'static str)],
+  /// Schema of the response body, in `components`.
+  response: Option<&'static str>,
+}
+
+const fn route(method: &'static str, path: &'static str, summary: &'static str) -> Route {
+  Route {
+    method,
+    path,
+    summary,
+    access: Public,
+    query: &[],
+    response: None,
+  }
+}
+
+impl Route {
+  const fn access(self, access: Access) -> Self {
+    Self { access, ..self }
+  }
+
+  const fn query(self, query: &'static [(&'static str, &'static str)]) -> Self {
+    Self { query, ..self }
+  }
+
+  const fn response(self, schema: &'static str) -> Self {
+    Self {
+      response: Some(schema),
+      ..self
+    }
+  }
+}
+
+const READ: Access = Scope("services:read");
+const WRITE: Access = Scope("services:write");
+const REPLICA: Access = Scope("replica");
+
+static ROUTES: &[Route] = &[
+  route("get", "/openapi.json", "This document"),
+  route("get", "/readyz", "Readiness probe, failing while draining"),
+  route("get", "/metrics", "Prometheus metrics").access(READ),
+  route("get", "/metrics/runtime", "Isolate cache statistics").access(READ),
+  route("get", "/usage", "Usage report").access(READ).query(&[
+    (
+      "date",
+      "Day of the report, e.g. `2022-08-01`; today by default",
+    ),
+    ("format", "`json` or `csv`"),
+  ]),
+  route("get", "/export", "Services to be synced by read replicas").access(REPLICA),
+  route("get", "/export/{name}", "Stored source of a service").access(REPLICA),
+  route("get", "/services", "List services")
+    .access(READ)
+    .response("ServiceList"),
+  route("get", "/services/{name}", "Get a service")
+    .access(READ)
+    .response("ServiceDetails"),
+  route("put", "/services/{name}", "Create or update a service")
+    .access(WRITE)
+    .query(&[
+      (
+        "mode",
+        "`create`, `hot`, `cold` or `load`; `create` by default",
+      ),
+      ("git", "Git repository to fetch the source from instead"),
+      ("ref", "Branch, tag or commit of `git`; `HEAD` by default"),
+      ("force", "Update from `git` even if the tree is unchanged"),
+    ])
+    .response("UploadResponse"),
+  route("patch", "/services/{name}", "Start or stop a service")
+    .access(WRITE)
+    .query(&[("op", "`start` or `stop`")])
+    .response("ServiceWithStatus"),
+  route("delete", "/services/{name}", "Remove a stopped service").access(WRITE),
+  route(
+    "get",
+    "/services/{name}/backups",
+    "List backups of local storage",
+  )
+  .access(READ),
+  route("post", "/services/{name}/backups", "Back up local storage").access(WRITE),
+  route(
+    "post",
+    "/services/{name}/backups/{id}/restore",
+    "Restore a backup of local storage",
+  )
+  .access(WRITE),
+  route(
+    "get",
+    "/services/{name}/health",
+    "Run a service's health check",
+  )
+  .access(READ),
+  route(
+    "get",
+    "/services/{name}/logs",
+    "Recent lines logged by a service",
+  )
+  .access(READ)
+  .query(&[
+    ("lines", "Number of recent lines; 100 by default"),
+    ("follow", "Keep streaming lines as they are logged"),
+  ]),
+  route(
+    "get",
+    "/services/{name}/cache",
+    "Statistics of the `cache` module",
+  )
+  .access(READ),
+  route(
+    "delete",
+    "/services/{name}/cache",
+    "Flush the `cache` module",
+  )
+  .access(WRITE),
+  route("get", "/services/{name}/versions", "List previous versions").access(READ),
+  route(
+    "post",
+    "/services/{name}/rollback",
+    "Roll back to a previous version",
+  )
+  .access(WRITE)
+  .query(&[("to", "UUID of the version; the latest by default")])
+  .response("UploadResponse"),
+  route("get", "/services/{name}/canary", "Get the canary").access(READ),
+  route("put", "/services/{name}/canary", "Deploy a canary")
+    .access(WRITE)
+    .query(&[
+      ("percent", "Percentage of requests sent to the canary"),
+      (
+        "header",
+        "Requests with this header always go to the canary",
+      ),
+      ("header_value", "Only match `header` with this value"),
+    ]),
+  route("delete", "/services/{name}/canary", "Roll back the canary").access(WRITE),
+  route(
+    "post",
+    "/services/{name}/canary/promote",
+    "Replace the service with its canary",
+  )
+  .access(WRITE)
+  .response("UploadResponse"),
+  route(
+    "get",
+    "/__abel/requests",
+    "Recent requests, kept in dev mode",
+  )
+  .access(Admin)
+  .query(&[
+    ("service", "Only requests to this service"),
+    ("limit", "Number of requests"),
+  ]),
+  route("get", "/tokens", "List tokens")
+    .access(Admin)
+    .response("TokenList"),
+  route("post", "/tokens", "Create a token")
+    .access(Admin)
+    .response("NewToken"),
+  route("delete", "/tokens/{id}", "Revoke a token")
+    .access(Admin)
+    .response("TokenInfo"),
+  route("get", "/auth/self", "What the presented token may do"),
+  route(
+    "post",
+    "/auth/tokens/rotate",
+    "Replace a token, which stays valid for a while",
+  )
+  .response("RotatedToken"),
+  route("get", "/cache", "Statistics of the remote module cache").access(Admin),
+  route("delete", "/cache", "Purge the remote module cache").access(Admin),
+  route("get", "/replica", "Read replica status").access(Admin),
+  route("post", "/replica/promote", "Promote a read replica").access(Admin),
+];
+
+pub static DOCUMENT: Lazy<Value> = Lazy::new(|| {
+  let mut paths = Map::new();
+  for route in ROUTES {
+    let item = paths
+      .entry(format!("{PREFIX}{}", route.path))
+      .or_insert_with(|| json!({}));
+    item[route.method] = operation(route);
+  }
+  json!({
+    "openapi": "3.0.3",
+    "info": {
+      "title": "Abel management API",
+      "version": env!("CARGO_PKG_VERSION"),
+    },
+    "paths": paths,
+    "components": {
+      "securitySchemes": {
+        "token": {
+          "type": "apiKey",
+          "in": "header",
+          "name": "authorization",
+          "description": "`Abel <token>`",
+        },
+        "signature": {
+          "type": "apiKey",
+          "in": "header",
+          "name": "authorization",
+          "description": "`Abel-HMAC-SHA256 Credential=<id>, Timestamp=<ts>, Signature=<sig>`",
+        },
+      },
+      "schemas": schemas(),
+    },
+  })
+});
+
+fn operation(route: &Route) -> Value {
+  let path_params = (route.path.split('/'))
+    .filter_map(|x| x.strip_prefix('{')?.strip_suffix('}'))
+    .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }));
+  let query_params = (route.query.iter()).map(|(name, description)| {
+    json!({ "name": name, "in": "query", "description": description, "schema": { "type": "string" } })
+  });
+  let success = match route.response {
+    Some(schema) => json!({
+      "description": "OK",
+      "content": {
+        "application/json": { "schema": { "$ref": format!("#/components/schemas/{schema}") } },
+      },
+    }),
+    None => json!({ "description": "OK" }),
+  };
+  let mut operation = json!({
+    "summary": route.summary,
+    "parameters": path_params.chain(query_params).collect::<Vec<_>>(),
+    "responses": {
+      "200": success,
+      "default": {
+        "description": "Error",
+        "content": {
+          "application/json": { "schema": { "$ref": "#/components/schemas/Error" } },
+        },
+      },
+    },
+  });
+  let requirement = json!([{ "token": [] }, { "signature": [] }]);
+  match route.access {
+    Public => {}
+    Scope(scope) => {
+      operation["security"] = requirement;
+      operation["x-abel-scope"] = scope.into();
+    }
+    Admin => {
+      operation["security"] = requirement;
+      operation["x-abel-scope"] = "admin".into();
+    }
+  }
+  operation
+}
+
+fn schemas() -> Value {
+  let token_info = json!({
+    "type": "object",
+    "required": ["id", "scopes", "created_at"],
+    "properties": {
+      "id": { "type": "string", "format": "uuid" },
+      "name": { "type": "string", "nullable": true },
+      "scopes": { "type": "array", "items": { "type": "string" } },
+      "created_at": { "type": "integer" },
+      "expires_at": { "type": "integer" },
+    },
+  });
+  let mut new_token = token_info.clone();
+  new_token["required"] = json!(["id", "scopes", "created_at", "token"]);
+  new_token["properties"]["token"] = json!({ "type": "string", "format": "uuid" });
+  let mut rotated_token = new_token.clone();
+  rotated_token["properties"]["previous"] = json!({ "$ref": "#/components/schemas/TokenInfo" });
+
+  json!({
+    "Error": {
+      "type": "object",
+      "required": ["error"],
+      "properties": {
+        "error": { "type": "string" },
+        "detail": { "type": "object", "nullable": true },
+      },
+    },
+    "ServiceInfo": {
+      "type": "object",
+      "required": ["name", "uuid", "paths"],
+      "properties": {
+        "name": { "type": "string" },
+        "pkg_name": { "type": "string", "nullable": true },
+        "description": { "type": "string", "nullable": true },
+        "paths": { "type": "array", "items": { "type": "string" } },
+        "uuid": { "type": "string", "format": "uuid" },
+        "aliases": { "type": "array", "items": { "type": "string" } },
+      },
+      "additionalProperties": true,
+    },
+    "ServiceWithStatus": {
+      "type": "object",
+      "required": ["status", "service"],
+      "properties": {
+        "status": { "type": "string", "enum": ["running", "stopped"] },
+        "service": { "$ref": "#/components/schemas/ServiceInfo" },
+        "health": { "type": "object" },
+      },
+    },
+    "ServiceDetails": {
+      "allOf": [
+        { "$ref": "#/components/schemas/ServiceWithStatus" },
+        {
+          "type": "object",
+          "properties": {
+            "source_hash": { "type": "string", "nullable": true },
+            "git": { "type": "object", "nullable": true },
+          },
+        },
+      ],
+    },
+    "ServiceList": {
+      "type": "array",
+      "items": { "$ref": "#/components/schemas/ServiceWithStatus" },
+    },
+    "UploadResponse": {
+      "type": "object",
+      "required": ["new_service"],
+      "properties": {
+        "new_service": { "$ref": "#/components/schemas/ServiceWithStatus" },
+        "replaced_service": { "$ref": "#/components/schemas/ServiceInfo" },
+        "errors": {
+          "type": "object",
+          "properties": {
+            "start": { "type": "string" },
+            "stop": { "type": "string" },
+          },
+        },
+        "unchanged": { "type": "boolean" },
+        "diff": { "$ref": "#/components/schemas/ServiceDiff" },
+      },
+    },
+    "ServiceDiff": {
+      "type": "object",
+      "properties": {
+        "routes_added": { "type": "array", "items": { "type": "string" } },
+        "routes_removed": { "type": "array", "items": { "type": "string" } },
+        "config_changed": { "type": "array", "items": { "type": "string" } },
+        "source": {
+          "type": "object",
+          "required": ["files", "size"],
+          "properties": {
+            "files": { "type": "integer" },
+            "size": { "type": "integer" },
+          },
+        },
+      },
+    },
+    "TokenInfo": token_info,
+    "TokenList": {
+      "type": "array",
+      "items": { "$ref": "#/components/schemas/TokenInfo" },
+    },
+    "NewToken": new_token,
+    "RotatedToken": rotated_token,
+  })
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::handle::{handle, MANAGEMENT_PATHS};
+  use crate::server::tests::state;
+  use hyper::{Body, Request};
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_management_paths_documented() {
+    for path in MANAGEMENT_PATHS {
+      assert!(
+        ROUTES
+          .iter()
+          .any(|x| x.path[1..].split('/').next() == Some(path)),
+        "/{path} not documented"
+      );
+    }
+  }
+
+  #[tokio::test]
+  async fn test_routes_served() {
+    let dir = TempDir::new().unwrap();
+    let state = state(dir.path(), Default::default()).await;
+    for route in ROUTES {
+      let path = (route.path.split('/'))
+        .map(|x| if x.starts_with('{') { "x" } else { x })
+        .collect::<Vec<_>>()
+        .join("/");
+      let req = Request::builder()
+        .method(route.method.to_uppercase().as_str())
+        .uri(format!("{PREFIX}{path}"))
+        .body(Body::empty())
+        .unwrap();
+      let resp = handle(state.clone(), req).await.unwrap();
+      let status = resp.status();
+      let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+      let body: Value = serde_json::from_slice(&body).unwrap_or_default();
+      assert!(
+        status != 405 && body["error"] != "path not found",
+        "{} {} not served: {status} {body}",
+        route.method,
+        route.path
+      );
+    }
+  }
+}
@@ -0,0 +1,470 @@
+//! Exporting spans to an OpenTelemetry collector over OTLP/HTTP.
+//!
+//! Spans are collected by a minimal [`Subscriber`] and sent as OTLP JSON in
+//! batches. Fields become span attributes, except for a few special ones:
+//!
+//! - `otel.kind`: `"server"`, `"client"` or `"internal"` (the default);
+//! - `otel.status_code`: `"ERROR"` marks the span as failed;
+//! - `traceparent`: a W3C trace context header the span continues, set only on
+//!   the root span of an incoming request.
+
+use log::{debug, warn};
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fmt::Debug;
+use std::num::NonZeroU64;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::field::{Field, Visit};
+use tracing::span::{Attributes, Id, Record};
+use tracing::{Event, Metadata, Subscriber};
+use tracing_core::span::Current;
+
+/// Number of locks open spans are split across, so that threads working on
+/// different spans rarely wait for each other.
+const SPAN_SHARDS: usize = 64;
+
+/// Spans waiting to be exported beyond this are dropped.
+const MAX_QUEUED_SPANS: usize = 4096;
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Where spans are exported to, as specified in `config.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OtlpConfig {
+  /// Collector's base URL, e.g. `http://localhost:4318`. Spans are sent to
+  /// `/v1/traces` under it.
+  pub endpoint: String,
+  /// Extra headers sent with every export, e.g. for authentication.
+  #[serde(default)]
+  pub headers: HashMap<String, String>,
+  /// Fraction of new traces that are exported, from 0 to 1. Traces
+  /// continued from an incoming `traceparent` follow its sampling decision.
+  /// Defaults to 1.
+  pub sample_ratio: Option<f64>,
+  /// `service.name` of exported spans. Defaults to `abel`.
+  pub service_name: Option<String>,
+}
+
+/// Sets up exporting spans as the global tracing subscriber.
+///
+/// Must be called inside a Tokio runtime, and only once.
+pub fn init(config: OtlpConfig) {
+  let collector = Arc::new(Collector::default());
+  let subscriber = OtlpSubscriber {
+    collector: collector.clone(),
+    next_id: AtomicU64::new(1),
+    sample_ratio: config.sample_ratio.unwrap_or(1.).clamp(0., 1.),
+    spans: Default::default(),
+  };
+  if tracing::subscriber::set_global_default(subscriber).is_err() {
+    warn!("tracing subscriber already set; spans will not be exported");
+    return;
+  }
+  tokio::spawn(export_loop(config, collector));
+}
+
+#[derive(Default)]
+struct Collector {
+  queue: Mutex<Vec<Value>>,
+  dropped: AtomicU64,
+}
+
+impl Collector {
+  fn push(&self, span: Value) {
+    let mut queue = self.queue.lock().unwrap();
+    if queue.len() < MAX_QUEUED_SPANS {
+      queue.push(span);
+    } else {
+      self.dropped.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+}
+
+async fn export_loop(config: OtlpConfig, collector: Arc<Collector>) {
+  let url = format!("{}/v1/traces", config.endpoint.trim_end_matches('/'));
+  let service_name = config.service_name.as_deref().unwrap_or("abel");
+  let client = reqwest::Client::new();
+  let mut interval = tokio::time::interval(EXPORT_INTERVAL);
+  loop {
+    interval.tick().await;
+    let spans = std::mem::take(&mut *collector.queue.lock().unwrap());
+    let dropped = collector.dropped.swap(0, Ordering::Relaxed);
+    if dropped > 0 {
+      warn!("{dropped} spans dropped because the export queue was full");
+    }
+    if spans.is_empty() {
+      continue;
+    }
+
+    let count = spans.len();
+    let body = json!({
+      "resourceSpans": [{
+        "resource": {
+          "attributes": [attribute("service.name", service_name.into())],
+        },
+        "scopeSpans": [{
+          "scope": { "name": "abel", "version": env!("CARGO_PKG_VERSION") },
+          "spans": spans,
+        }],
+      }],
+    });
+    let mut req = client.post(&url).json(&body);
+    for (k, v) in &config.headers {
+      req = req.header(k, v);
+    }
+    match req.send().await {
+      Ok(resp) if resp.status().is_success() => debug!("exported {count} spans"),
+      Ok(resp) => warn!(
+        "failed to export {count} spans: {} returned {}",
+        url,
+        resp.status()
+      ),
+      Err(error) => warn!("failed to export {count} spans: {error}"),
+    }
+  }
+}
+
+struct OtlpSubscriber {
+  collector: Arc<Collector>,
+  next_id: AtomicU64,
+  sample_ratio: f64,
+  spans: Spans,
+}
+
+/// Open spans by ID, sharded by it.
+struct Spans([Mutex<HashMap<u64, SpanData>>; SPAN_SHARDS]);
+
+impl Default for Spans {
+  fn default() -> Self {
+    Self(std::array::from_fn(|_| Default::default()))
+  }
+}
+
+impl Spans {
+  fn shard(&self, id: u64) -> MutexGuard<'_, HashMap<u64, SpanData>> {
+    self.0[id as usize % SPAN_SHARDS].lock().unwrap()
+  }
+
+  fn with<R>(&self, id: &Id, f: impl FnOnce(&mut SpanData) -> R) -> Option<R> {
+    let id = id.into_u64();
+    self.shard(id).get_mut(&id).map(f)
+  }
+}
+
+struct SpanData {
+  metadata: &'static Metadata<'static>,
+  trace_id: u128,
+  span_id: u64,
+  parent_span_id: Option<u64>,
+  sampled: bool,
+  start: u64,
+  kind: u8,
+  error: bool,
+  attributes: Vec<Value>,
+  refs: usize,
+}
+
+thread_local! {
+  /// Spans entered on this thread, innermost last.
+  static STACK: RefCell<Vec<Id>> = const { RefCell::new(Vec::new()) };
+}
+
+fn unix_nanos() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_nanos() as u64)
+    .unwrap_or_default()
+}
+
+fn attribute(key: &str, value: Value) -> Value {
+  json!({ "key": key, "value": value })
+}
+
+/// Parses `00-{trace id}-{parent id}-{flags}`.
+fn parse_traceparent(s: &str) -> Option<(u128, u64, bool)> {
+  let mut parts = s.trim().split('-');
+  let (version, trace_id, parent_id, flags) =
+    (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+  if version != "00" || trace_id.len() != 32 || parent_id.len() != 16 || flags.len() != 2 {
+    return None;
+  }
+  let trace_id = u128::from_str_radix(trace_id, 16)
+    .ok()
+    .filter(|x| *x != 0)?;
+  let parent_id = u64::from_str_radix(parent_id, 16)
+    .ok()
+    .filter(|x| *x != 0)?;
+  let flags = u8::from_str_radix(flags, 16).ok()?;
+  Some((trace_id, parent_id, flags & 1 == 1))
+}
+
+/// Collects fields into span data.
+struct FieldVisitor<'a> {
+  data: &'a mut SpanData,
+  traceparent: Option<String>,
+}
+
+impl FieldVisitor<'_> {
+  fn add(&mut self, field: &Field, value: Value) {
+    match (field.name(), &value) {
+      ("otel.kind", Value::String(kind)) => {
+        self.data.kind = match &**kind {
+          "server" => 2,
+          "client" => 3,
+          _ => 1,
+        }
+      }
+      ("otel.status_code", Value::String(code)) => self.data.error = code == "ERROR",
+      ("traceparent", Value::String(s)) => self.traceparent = Some(s.clone()),
+      (name, _) => {
+        let value = match value {
+          Value::String(s) => json!({ "stringValue": s }),
+          Value::Bool(b) => json!({ "boolValue": b }),
+          Value::Number(n) if n.is_f64() => json!({ "doubleValue": n }),
+          Value::Number(n) => json!({ "intValue": n.to_string() }),
+          _ => return,
+        };
+        self.data.attributes.push(attribute(name, value));
+      }
+    }
+  }
+}
+
+impl Visit for FieldVisitor<'_> {
+  fn record_str(&mut self, field: &Field, value: &str) {
+    self.add(field, value.into())
+  }
+
+  fn record_i64(&mut self, field: &Field, value: i64) {
+    self.add(field, value.into())
+  }
+
+  fn record_u64(&mut self, field: &Field, value: u64) {
+    self.add(field, value.into())
+  }
+
+  fn record_f64(&mut self, field: &Field, value: f64) {
+    self.add(field, value.into())
+  }
+
+  fn record_bool(&mut self, field: &Field, value: bool) {
+    self.add(field, value.into())
+  }
+
+  fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+    self.add(field, format!("{value:?}").into())
+  }
+}
+
+impl OtlpSubscriber {
+  fn current(&self) -> Option<Id> {
+    STACK.with(|x| x.borrow().last().cloned())
+  }
+
+  fn export(&self, data: SpanData) {
+    let mut span = json!({
+      "traceId": format!("{:032x}", data.trace_id),
+      "spanId": format!("{:016x}", data.span_id),
+      "name": data.metadata.name(),
+      "kind": data.kind,
+      "startTimeUnixNano": data.start.to_string(),
+      "endTimeUnixNano": unix_nanos().to_string(),
+      "attributes": data.attributes,
+    });
+    if let Some(parent) = data.parent_span_id {
+      span["parentSpanId"] = format!("{parent:016x}").into();
+    }
+    if data.error {
+      span["status"] = json!({ "code": 2 });
+    }
+    self.collector.push(span);
+  }
+}
+
+impl Subscriber for OtlpSubscriber {
+  fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+    // Leave out spans from dependencies, e.g. h2's per-connection ones
+    metadata.is_span() && metadata.target().starts_with("abel")
+  }
+
+  fn new_span(&self, attrs: &Attributes<'_>) -> Id {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let parent = if attrs.is_contextual() {
+      self.current()
+    } else {
+      attrs.parent().cloned()
+    };
+
+    let parent =
+      (parent.as_ref()).and_then(|x| self.spans.with(x, |x| (x.trace_id, x.span_id, x.sampled)));
+    let mut rng = thread_rng();
+    let mut data = SpanData {
+      metadata: attrs.metadata(),
+      trace_id: parent.map_or_else(|| rng.gen(), |x| x.0),
+      span_id: rng.gen(),
+      parent_span_id: parent.map(|x| x.1),
+      sampled: parent.map_or_else(|| rng.gen_bool(self.sample_ratio), |x| x.2),
+      start: unix_nanos(),
+      kind: 1,
+      error: false,
+      attributes: Vec::new(),
+      refs: 1,
+    };
+    let mut visitor = FieldVisitor {
+      data: &mut data,
+      traceparent: None,
+    };
+    attrs.record(&mut visitor);
+    let traceparent = visitor.traceparent.as_deref().and_then(parse_traceparent);
+    if let (None, Some((trace_id, parent_id, sampled))) = (&data.parent_span_id, traceparent) {
+      data.trace_id = trace_id;
+      data.parent_span_id = Some(parent_id);
+      data.sampled = sampled;
+    }
+    self.spans.shard(id).insert(id, data);
+    Id::from_non_zero_u64(NonZeroU64::new(id).unwrap())
+  }
+
+  fn record(&self, span: &Id, values: &Record<'_>) {
+    self.spans.with(span, |data| {
+      values.record(&mut FieldVisitor {
+        data,
+        traceparent: None,
+      })
+    });
+  }
+
+  fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+  fn event(&self, _event: &Event<'_>) {}
+
+  fn enter(&self, span: &Id) {
+    STACK.with(|x| x.borrow_mut().push(span.clone()));
+  }
+
+  fn exit(&self, span: &Id) {
+    STACK.with(|x| {
+      let mut stack = x.borrow_mut();
+      if let Some(pos) = stack.iter().rposition(|x| x == span) {
+        stack.remove(pos);
+      }
+    });
+  }
+
+  fn clone_span(&self, id: &Id) -> Id {
+    self.spans.with(id, |data| data.refs += 1);
+    id.clone()
+  }
+
+  fn try_close(&self, id: Id) -> bool {
+    let mut spans = self.spans.shard(id.into_u64());
+    let closed = match spans.get_mut(&id.into_u64()) {
+      Some(data) => {
+        data.refs -= 1;
+        data.refs == 0
+      }
+      None => false,
+    };
+    if closed {
+      let data = spans.remove(&id.into_u64()).unwrap();
+      drop(spans);
+      if data.sampled {
+        self.export(data);
+      }
+    }
+    closed
+  }
+
+  fn current_span(&self) -> Current {
+    let current = (self.current()).and_then(|id| {
+      let metadata = self.spans.with(&id, |x| x.metadata)?;
+      Some((id, metadata))
+    });
+    match current {
+      Some((id, metadata)) => Current::new(id, metadata),
+      None => Current::none(),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use tracing::info_span;
+
+  #[test]
+  fn test_parse_traceparent() {
+    let trace_id = 0x4bf92f3577b34da6a3ce929d0e0e4736;
+    let parent_id = 0x00f067aa0ba902b7;
+    assert_eq!(
+      parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+      Some((trace_id, parent_id, true)),
+    );
+    assert_eq!(
+      parse_traceparent(" 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00 "),
+      Some((trace_id, parent_id, false)),
+    );
+  }
+
+  #[test]
+  fn test_parse_invalid_traceparent() {
+    let invalid = [
+      "",
+      "01-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01",
+      "00-00000000000000000000000000000000-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-0000000000000000-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e473-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7",
+      "00-4bf92f3577b34da6a3ce929d0e0e473g-00f067aa0ba902b7-01",
+      "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-xx",
+    ];
+    for s in invalid {
+      assert_eq!(parse_traceparent(s), None, "{s:?}");
+    }
+  }
+
+  fn exported(f: impl FnOnce()) -> Vec<Value> {
+    let collector = Arc::new(Collector::default());
+    let subscriber = OtlpSubscriber {
+      collector: collector.clone(),
+      next_id: AtomicU64::new(1),
+      sample_ratio: 1.,
+      spans: Default::default(),
+    };
+    tracing::subscriber::with_default(subscriber, f);
+    let spans = std::mem::take(&mut *collector.queue.lock().unwrap());
+    spans
+  }
+
+  #[test]
+  fn test_continue_trace() {
+    let spans = exported(|| {
+      let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+      let root = info_span!("request", traceparent, otel.kind = "server");
+      let _guard = root.enter();
+      info_span!("handler", path = "/").in_scope(|| {});
+    });
+    let [child, root] = &spans[..] else {
+      panic!("expected 2 spans, got {spans:?}");
+    };
+    assert_eq!(root["traceId"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(root["parentSpanId"], "00f067aa0ba902b7");
+    assert_eq!(root["kind"], 2);
+    assert_eq!(child["traceId"], root["traceId"]);
+    assert_eq!(child["parentSpanId"], root["spanId"]);
+  }
+
+  #[test]
+  fn test_not_sampled() {
+    let spans = exported(|| {
+      let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-00";
+      let root = info_span!("request", traceparent);
+      let _guard = root.enter();
+      info_span!("handler").in_scope(|| {});
+    });
+    assert!(spans.is_empty());
+  }
+}
@@ -0,0 +1,178 @@
+use data_encoding::BASE64;
+use hyper::body::Bytes;
+use hyper::http::request::Parts;
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs::{self, OpenOptions};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+
+/// Headers that are never written to disk.
+pub(super) const SENSITIVE_HEADERS: &[&str] = &["authorization", "cookie", "proxy-authorization"];
+
+/// Which requests get recorded, as specified in `config.json`.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RecordConfig {
+  /// Services whose requests are recorded. Records every service if empty.
+  #[serde(default)]
+  pub services: Vec<String>,
+  /// Only record requests whose path (inside the service) starts with this.
+  pub path_prefix: Option<String>,
+  /// Fraction of matching requests to record, from 0 to 1. Defaults to 1.
+  pub sample_rate: Option<f64>,
+  /// Bodies larger than this (in bytes) are not recorded. Defaults to 1 MiB.
+  pub max_body_size: Option<usize>,
+}
+
+/// A single recorded request, stored as one line of JSON.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct RecordedRequest {
+  pub timestamp: f64,
+  pub service: String,
+  pub method: String,
+  /// Path and query inside the service, e.g. `/foo?bar=baz`.
+  pub path: String,
+  pub headers: Vec<(String, String)>,
+  /// Base64-encoded body. `None` if it exceeded `max_body_size`.
+  pub body: Option<String>,
+}
+
+/// Captures requests to `<abel_path>/records/<service>.jsonl` for later
+/// replaying with `abel replay`.
+pub struct Recorder {
+  config: RecordConfig,
+  path: PathBuf,
+  lock: Mutex<()>,
+}
+
+impl Recorder {
+  pub fn new(config: RecordConfig, abel_path: &Path) -> Self {
+    Self {
+      config,
+      path: abel_path.join("records"),
+      lock: Mutex::new(()),
+    }
+  }
+
+  pub fn should_record(&self, service_name: &str, sub_path: &str) -> bool {
+    let config = &self.config;
+    if !config.services.is_empty() && !config.services.iter().any(|x| x == service_name) {
+      return false;
+    }
+    if let Some(prefix) = &config.path_prefix {
+      if !sub_path.starts_with(prefix.as_str()) {
+        return false;
+      }
+    }
+    let sample_rate = config.sample_rate.unwrap_or(1.);
+    sample_rate >= 1. || rand::random::<f64>() < sample_rate
+  }
+
+  /// Writes the request down. Failures are only logged.
+  pub async fn record(&self, service_name: &str, sub_path: &str, parts: &Parts, body: &Bytes) {
+    let path = match parts.uri.query() {
+      Some(query) => format!("{sub_path}?{query}"),
+      None => sub_path.into(),
+    };
+    let headers = (parts.headers.iter())
+      .filter(|(k, _)| !SENSITIVE_HEADERS.contains(&k.as_str()))
+      .filter_map(|(k, v)| Some((k.as_str().into(), v.to_str().ok()?.into())))
+      .collect();
+    let max_body_size = self.config.max_body_size.unwrap_or(1024 * 1024);
+    let record = RecordedRequest {
+      timestamp: SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs_f64())
+        .unwrap_or_default(),
+      service: service_name.into(),
+      method: parts.method.to_string(),
+      path,
+      headers,
+      body: (body.len() <= max_body_size).then(|| BASE64.encode(body)),
+    };
+
+    if let Err(error) = self.write(&record).await {
+      warn!("failed to record request to service '{service_name}': {error}");
+    }
+  }
+
+  async fn write(&self, record: &RecordedRequest) -> std::io::Result<()> {
+    let mut line = serde_json::to_vec(record)?;
+    line.push(b'\n');
+
+    let _guard = self.lock.lock().await;
+    if !self.path.exists() {
+      fs::create_dir(&self.path).await?;
+    }
+    let file_path = self.path.join(format!("{}.jsonl", record.service));
+    let mut file = OpenOptions::new()
+      .create(true)
+      .append(true)
+      .open(file_path)
+      .await?;
+    file.write_all(&line).await?;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::Request;
+  use tempfile::TempDir;
+
+  #[test]
+  fn test_should_record() {
+    let config = RecordConfig {
+      services: vec!["a".into()],
+      path_prefix: Some("/api".into()),
+      ..Default::default()
+    };
+    let recorder = Recorder::new(config, Path::new("."));
+    assert!(recorder.should_record("a", "/api/x"));
+    assert!(!recorder.should_record("a", "/x"));
+    assert!(!recorder.should_record("b", "/api/x"));
+
+    let config = RecordConfig {
+      sample_rate: Some(0.),
+      ..Default::default()
+    };
+    let recorder = Recorder::new(config, Path::new("."));
+    assert!(!recorder.should_record("a", "/"));
+  }
+
+  #[tokio::test]
+  async fn test_record() {
+    let dir = TempDir::new().unwrap();
+    let config = RecordConfig {
+      max_body_size: Some(4),
+      ..Default::default()
+    };
+    let recorder = Recorder::new(config, dir.path());
+    let parts = (Request::post("/a/x?q=1"))
+      .header("authorization", "Abel secret")
+      .header("content-type", "text/plain")
+      .body(())
+      .unwrap()
+      .into_parts()
+      .0;
+    for body in ["body", "large"] {
+      (recorder.record("a", "/x", &parts, &Bytes::from(body))).await;
+    }
+
+    let records = fs::read_to_string(dir.path().join("records/a.jsonl"));
+    let records = (records.await.unwrap().lines())
+      .map(|x| serde_json::from_str::<RecordedRequest>(x).unwrap())
+      .collect::<Vec<_>>();
+    assert_eq!(records.len(), 2);
+    assert_eq!((&*records[0].method, &*records[0].path), ("POST", "/x?q=1"));
+    assert_eq!(records[0].headers, [(
+      "content-type".into(),
+      "text/plain".into()
+    )]);
+    assert_eq!(records[0].body.as_deref(), Some("Ym9keQ=="));
+    assert_eq!(records[1].body, None);
+  }
+}
@@ -0,0 +1,392 @@
+//! Following a primary server as a read replica.
+//!
+//! The primary exports its services at `/api/v1/export`, listing their UUIDs
+//! and whether they are started, and `/api/v1/export/<name>`, serving their
+//! stored source. Both require the `replica` scope, since sources may hold
+//! things `services:read` tokens should not see. Every `interval` seconds, a
+//! replica downloads services whose UUID differs from its own copy, removes
+//! ones gone from the primary, and starts or stops the rest to match.
+//!
+//! Until promoted, a replica rejects writes to the management API and
+//! service requests other than `GET`, `HEAD` and `OPTIONS`.
+
+use super::metadata::Metadata;
+use super::upload::{log_result, upload_local, UploadMode};
+use super::{json_response, Error, Result, ServerState};
+use crate::source::ArchiveKind;
+use crate::SourceKind;
+use anyhow::{bail, Context};
+use bytes::Bytes;
+use hyper::header::CONTENT_DISPOSITION;
+use hyper::{Body, Response, StatusCode};
+use log::{info, warn};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::collections::HashSet;
+use std::io;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs;
+
+static CLIENT: Lazy<reqwest::Client> = Lazy::new(|| {
+  reqwest::Client::builder()
+    .timeout(Duration::from_secs(60))
+    .build()
+    .unwrap()
+});
+
+/// Following a primary server, as specified in `config.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ReplicaConfig {
+  /// Base URL of the primary, e.g. `https://abel.example.com`.
+  pub primary: String,
+  /// Token with the `replica` scope on the primary.
+  pub token: Option<String>,
+  /// Seconds between syncs. Defaults to 30.
+  pub interval: Option<u64>,
+}
+
+pub struct Replica {
+  config: ReplicaConfig,
+  promoted: AtomicBool,
+  /// Unix time of the last successful sync, or 0 if there is none yet.
+  last_sync: AtomicU64,
+}
+
+#[derive(Serialize)]
+pub struct ReplicaStatus<'a> {
+  primary: &'a str,
+  promoted: bool,
+  last_sync: Option<u64>,
+}
+
+impl Replica {
+  pub fn new(config: ReplicaConfig) -> Self {
+    Self {
+      config,
+      promoted: AtomicBool::new(false),
+      last_sync: AtomicU64::new(0),
+    }
+  }
+
+  /// Whether the server still follows the primary, i.e. is not promoted.
+  pub fn is_following(&self) -> bool {
+    !self.promoted.load(Ordering::Acquire)
+  }
+
+  /// Stops following the primary until restarted. Returns whether it was
+  /// following before.
+  pub fn promote(&self) -> bool {
+    !self.promoted.swap(true, Ordering::AcqRel)
+  }
+
+  pub fn status(&self) -> ReplicaStatus<'_> {
+    let last_sync = self.last_sync.load(Ordering::Acquire);
+    ReplicaStatus {
+      primary: &self.config.primary,
+      promoted: !self.is_following(),
+      last_sync: (last_sync > 0).then_some(last_sync),
+    }
+  }
+
+  /// Error for requests a following replica does not serve.
+  pub fn read_only_error(&self) -> Error {
+    Error::from((
+      405,
+      "read-only replica",
+      json!({ "primary": self.config.primary }),
+    ))
+  }
+
+  async fn get(&self, path: &str) -> anyhow::Result<reqwest::Response> {
+    let url = format!("{}/{path}", self.config.primary.trim_end_matches('/'));
+    let mut req = CLIENT.get(&url);
+    if let Some(token) = &self.config.token {
+      req = req.header("authorization", format!("Abel {token}"));
+    }
+    let resp = req.send().await?;
+    if !resp.status().is_success() {
+      bail!("GET {url} returned {}", resp.status());
+    }
+    Ok(resp)
+  }
+}
+
+/// A service in the primary's `/api/v1/export` list.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ExportedService {
+  pub name: String,
+  #[serde(flatten)]
+  pub metadata: Metadata,
+}
+
+pub async fn export_list(state: &ServerState) -> Result<Response<Body>> {
+  let names = (state.abel.list_services())
+    .map(|x| x.upgrade().name().to_string())
+    .collect::<Vec<_>>();
+  let mut services = Vec::with_capacity(names.len());
+  for name in names {
+    let path = state.abel_path.join("services").join(&name);
+    let metadata = Metadata::read(&path.join("metadata.json")).await?;
+    services.push(ExportedService { name, metadata });
+  }
+  json_response(StatusCode::OK, services)
+}
+
+pub async fn export_source(state: &ServerState, name: &str) -> Result<Response<Body>> {
+  state.abel.get_service(name)?;
+  let path = state.abel_path.join("services").join(name);
+  let file_names = [
+    "source.lua",
+    ArchiveKind::Asar.file_name(),
+    ArchiveKind::Zip.file_name(),
+  ];
+  for file_name in file_names {
+    match fs::read(path.join(file_name)).await {
+      Ok(content) => {
+        let resp = Response::builder()
+          .header("content-type", "application/octet-stream")
+          .header(
+            CONTENT_DISPOSITION,
+            format!("attachment; filename=\"{file_name}\""),
+          )
+          .body(content.into())
+          .unwrap();
+        return Ok(resp);
+      }
+      Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+      Err(error) => return Err(error.into()),
+    }
+  }
+  Err((500, "service source not found", json!({ "name": name })).into())
+}
+
+/// Syncs from the primary every `interval` seconds until promoted.
+pub async fn run_sync(state: Arc<ServerState>) {
+  let replica = match &state.replica {
+    Some(x) => x,
+    None => return,
+  };
+  let secs = replica.config.interval.unwrap_or(30).max(1);
+  let mut interval = tokio::time::interval(Duration::from_secs(secs));
+  loop {
+    interval.tick().await;
+    if !replica.is_following() {
+      info!(
+        "Promoted to primary; no longer syncing from {}",
+        replica.config.primary
+      );
+      break;
+    }
+    match sync(&state, replica).await {
+      Ok(()) => {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        replica.last_sync.store(now.as_secs(), Ordering::Release);
+      }
+      Err(error) => warn!("failed to sync from primary: {error:#}"),
+    }
+  }
+}
+
+async fn sync(state: &ServerState, replica: &Replica) -> anyhow::Result<()> {
+  let exported: Vec<ExportedService> = replica.get("api/v1/export").await?.json().await?;
+
+  for service in &exported {
+    if let Err(error) = sync_service(state, replica, service).await {
+      warn!("failed to sync service '{}': {error:#}", service.name);
+    }
+  }
+
+  let exported_names = exported.iter().map(|x| &*x.name).collect::<HashSet<_>>();
+  let removed = (state.abel.list_services())
+    .map(|x| x.upgrade().name().to_string())
+    .filter(|x| !exported_names.contains(&**x))
+    .collect::<Vec<_>>();
+  for name in removed {
+    if let Err(error) = remove_service(state, &name).await {
+      warn!("failed to remove service '{name}': {error:#}");
+    }
+  }
+  Ok(())
+}
+
+async fn remove_service(state: &ServerState, name: &str) -> anyhow::Result<()> {
+  if state.abel.get_running_service(name).is_ok() {
+    state.abel.stop_service(name).await?;
+  }
+  let removed = state.abel.remove_service(name).await?;
+  fs::remove_dir_all(state.abel_path.join("services").join(name)).await?;
+  info!(
+    "Removed service '{name}' ({}) following primary",
+    removed.uuid()
+  );
+  Ok(())
+}
+
+async fn sync_service(
+  state: &ServerState,
+  replica: &Replica,
+  exported: &ExportedService,
+) -> anyhow::Result<()> {
+  let ExportedService { name, metadata } = exported;
+  let metadata_path = (state.abel_path)
+    .join("services")
+    .join(name)
+    .join("metadata.json");
+  let local = match state.abel.get_service(name) {
+    Ok(_) => Some(Metadata::read(&metadata_path).await?),
+    Err(_) => None,
+  };
+
+  match local {
+    Some(local) if local.uuid == metadata.uuid => {
+      if local.started == metadata.started {
+        return Ok(());
+      }
+      if metadata.started {
+        state.abel.start_service(name).await?;
+        info!("Started service '{name}' following primary");
+      } else {
+        state.abel.stop_service(name).await?;
+        info!("Stopped service '{name}' following primary");
+      }
+      Metadata::modify(&metadata_path, |m| m.started = metadata.started).await?;
+    }
+    _ => {
+      let resp = replica.get(&format!("api/v1/export/{name}")).await?;
+      let kind = match (resp.headers().get(CONTENT_DISPOSITION)).and_then(|x| x.to_str().ok()) {
+        Some(x) if x.contains("\"source.lua\"") => SourceKind::Single,
+        _ => SourceKind::Multi,
+      };
+      let content = resp.bytes().await?;
+      let mode = if metadata.started {
+        UploadMode::Cold
+      } else {
+        UploadMode::Load
+      };
+      let stream = futures::stream::once(async { io::Result::<Bytes>::Ok(content) });
+      let resp = upload_local(
+        state,
+        name.clone(),
+        mode,
+        kind,
+        Some(metadata.uuid),
+        Box::pin(stream),
+      )
+      .await
+      .with_context(|| format!("failed to load source of '{name}'"))?;
+      log_result(&resp);
+      if !metadata.started {
+        Metadata::modify(&metadata_path, |m| m.started = false).await?;
+      }
+    }
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::Config;
+  use crate::server::handle;
+  use crate::server::tests::state;
+  use hyper::service::{make_service_fn, service_fn};
+  use hyper::{Request, Server};
+  use std::convert::Infallible;
+  use std::net::TcpListener;
+  use tempfile::TempDir;
+  use uuid::Uuid;
+
+  async fn upload(state: &ServerState, name: &str) -> Uuid {
+    let code = format!(r#"abel.listen("/", function() return "{name}" end)"#);
+    let stream = futures::stream::once(async { io::Result::Ok(Bytes::from(code)) });
+    let resp = upload_local(
+      state,
+      name.into(),
+      UploadMode::Cold,
+      SourceKind::Single,
+      None,
+      Box::pin(stream),
+    )
+    .await
+    .unwrap();
+    resp.new_service.upgrade().uuid()
+  }
+
+  /// Serves `state` on a local port, returning its base URL.
+  fn serve(state: Arc<ServerState>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = Server::from_tcp(listener).unwrap();
+    tokio::spawn(server.serve(make_service_fn(move |_| {
+      let state = state.clone();
+      async move { Ok::<_, Infallible>(service_fn(move |req| handle(state.clone(), req))) }
+    })));
+    format!("http://{addr}/")
+  }
+
+  fn is_running(state: &ServerState, name: &str) -> bool {
+    state.abel.get_running_service(name).is_ok()
+  }
+
+  #[tokio::test]
+  async fn test_sync() {
+    let (primary_dir, replica_dir) = (TempDir::new().unwrap(), TempDir::new().unwrap());
+    let token = Uuid::new_v4();
+    let config = Config {
+      auth_token: Some(token),
+      ..Default::default()
+    };
+    let primary = state(primary_dir.path(), config).await;
+    let request = |state: &Arc<ServerState>, builder: hyper::http::request::Builder| {
+      let req = (builder.header("authorization", format!("Abel {token}")))
+        .body(Body::empty())
+        .unwrap();
+      let state = state.clone();
+      async move { handle(state, req).await.unwrap().status() }
+    };
+    let a = upload(&primary, "a").await;
+    let b = upload(&primary, "b").await;
+    let stop_b = Request::patch("/services/b?op=stop");
+    assert!(request(&primary, stop_b).await.is_success());
+
+    let config = Config {
+      replica: Some(ReplicaConfig {
+        primary: serve(primary.clone()),
+        token: Some(token.to_string()),
+        interval: None,
+      }),
+      auth_token: Some(token),
+      ..Default::default()
+    };
+    let state = state(replica_dir.path(), config).await;
+    let replica = state.replica.as_ref().unwrap();
+    upload(&state, "c").await;
+
+    sync(&state, replica).await.unwrap();
+    let uuid = |name| state.abel.get_service(name).unwrap().upgrade().uuid();
+    assert_eq!((uuid("a"), uuid("b")), (a, b));
+    assert!(is_running(&state, "a") && !is_running(&state, "b"));
+    assert!(state.abel.get_service("c").is_err());
+    assert!(!replica_dir.path().join("services/c").exists());
+
+    // Replaced and started on the primary
+    let a = upload(&primary, "a").await;
+    let start_b = Request::patch("/services/b?op=start");
+    assert!(request(&primary, start_b).await.is_success());
+    sync(&state, replica).await.unwrap();
+    assert_eq!((uuid("a"), uuid("b")), (a, b));
+    assert!(is_running(&state, "a") && is_running(&state, "b"));
+
+    // Writes are rejected until promoted
+    let remove_a = Request::delete("/services/a");
+    assert_eq!(
+      request(&state, remove_a).await,
+      StatusCode::METHOD_NOT_ALLOWED
+    );
+    assert!(replica.promote());
+    assert!(!replica.is_following() && !replica.promote());
+  }
+}
@@ -0,0 +1,306 @@
+//! Recent service requests kept in memory, for inspecting in dev mode. They
+//! may hold credentials in bodies, so they are only kept by `abel dev` and
+//! only served to the server's own auth token.
+//!
+//! Each entry holds the request and response with their headers and the
+//! start of their bodies, how long handling took, and lines the service
+//! logged meanwhile. They are served at `GET /__abel/requests`, newest
+//! first, and pretty-printed by `abel requests`.
+
+use super::record::SENSITIVE_HEADERS;
+use super::{json_response, Result};
+use abel_core::{CapturedLog, LogCapture, RequestId};
+use bytes::Bytes;
+use futures::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::http::request::Parts;
+use hyper::{Body, HeaderMap, Response, StatusCode};
+use serde::{Deserialize, Serialize};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Bodies are cut off after this many bytes.
+const MAX_BODY: usize = 4096;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedRequest {
+  pub id: u64,
+  #[serde(default)]
+  pub request_id: Option<String>,
+  pub timestamp: f64,
+  pub service: String,
+  pub method: String,
+  /// Path and query inside the service, e.g. `/foo?bar=baz`.
+  pub path: String,
+  pub request_headers: Vec<(String, String)>,
+  pub request_body: LoggedBody,
+  pub status: u16,
+  pub response_headers: Vec<(String, String)>,
+  pub response_body: LoggedBody,
+  /// Time until the response head was ready, in milliseconds.
+  pub duration_ms: f64,
+  pub error: Option<String>,
+  pub logs: Vec<LoggedLine>,
+}
+
+/// Start of a body, lossily decoded as UTF-8.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct LoggedBody {
+  pub content: String,
+  /// Total size in bytes, if it has been read to the end.
+  pub size: Option<usize>,
+  pub truncated: bool,
+}
+
+impl LoggedBody {
+  fn push(&mut self, chunk: &[u8]) {
+    let len = self.content.len();
+    if len < MAX_BODY {
+      let end = chunk.len().min(MAX_BODY - len);
+      self
+        .content
+        .push_str(&String::from_utf8_lossy(&chunk[..end]));
+    }
+    self.truncated |= len + chunk.len() > MAX_BODY;
+  }
+
+  fn from_bytes(bytes: &[u8]) -> Self {
+    let mut body = Self::default();
+    body.push(bytes);
+    body.size = Some(bytes.len());
+    body
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LoggedLine {
+  pub level: String,
+  pub message: String,
+  #[serde(default, skip_serializing_if = "serde_json::Map::is_empty")]
+  pub fields: serde_json::Map<String, serde_json::Value>,
+}
+
+impl From<CapturedLog> for LoggedLine {
+  fn from(x: CapturedLog) -> Self {
+    Self {
+      level: x.level.as_str().to_lowercase(),
+      message: x.message,
+      fields: x.fields,
+    }
+  }
+}
+
+/// The last `capacity` requests.
+pub struct RequestLog {
+  capacity: usize,
+  next_id: AtomicU64,
+  entries: Mutex<VecDeque<Arc<Mutex<LoggedRequest>>>>,
+}
+
+/// A request being handled, written to the log once it is done.
+pub struct PendingRequest {
+  entry: LoggedRequest,
+  pub logs: LogCapture,
+}
+
+impl RequestLog {
+  pub fn new(capacity: usize) -> Self {
+    Self {
+      capacity,
+      next_id: AtomicU64::new(1),
+      entries: Mutex::new(VecDeque::with_capacity(capacity)),
+    }
+  }
+
+  pub fn start(
+    &self,
+    service: &str,
+    sub_path: &str,
+    parts: &Parts,
+    body: &Bytes,
+  ) -> PendingRequest {
+    let path = match parts.uri.query() {
+      Some(query) => format!("{sub_path}?{query}"),
+      None => sub_path.into(),
+    };
+    let entry = LoggedRequest {
+      id: self.next_id.fetch_add(1, Ordering::Relaxed),
+      request_id: (parts.extensions.get::<RequestId>()).map(|x| x.0.to_string()),
+      timestamp: SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|x| x.as_secs_f64())
+        .unwrap_or_default(),
+      service: service.into(),
+      method: parts.method.to_string(),
+      path,
+      request_headers: headers(&parts.headers),
+      request_body: LoggedBody::from_bytes(body),
+      status: 0,
+      response_headers: Vec::new(),
+      response_body: Default::default(),
+      duration_ms: 0.,
+      error: None,
+      logs: Vec::new(),
+    };
+    PendingRequest {
+      entry,
+      logs: Default::default(),
+    }
+  }
+
+  /// Logs a request that failed with `error`.
+  pub fn finish_error(
+    &self,
+    mut pending: PendingRequest,
+    duration: Duration,
+    status: StatusCode,
+    error: String,
+  ) {
+    pending.entry.status = status.as_u16();
+    pending.entry.error = Some(error);
+    self.push(pending, duration);
+  }
+
+  /// Logs a request, returning its response with the body copied into the
+  /// log as it is sent.
+  pub fn finish(
+    &self,
+    mut pending: PendingRequest,
+    duration: Duration,
+    resp: Response<Body>,
+  ) -> Response<Body> {
+    pending.entry.status = resp.status().as_u16();
+    pending.entry.response_headers = headers(resp.headers());
+    let entry = self.push(pending, duration);
+
+    let (parts, body) = resp.into_parts();
+    if body.is_end_stream() {
+      entry.lock().unwrap().response_body.size = Some(0);
+      return Response::from_parts(parts, body);
+    }
+    let mut size = 0;
+    let body = body
+      .inspect_ok(move |chunk| {
+        let mut entry = entry.lock().unwrap();
+        entry.response_body.push(chunk);
+        size += chunk.len();
+        entry.response_body.size = Some(size);
+      })
+      .into_stream();
+    Response::from_parts(parts, Body::wrap_stream(body))
+  }
+
+  fn push(&self, pending: PendingRequest, duration: Duration) -> Arc<Mutex<LoggedRequest>> {
+    let PendingRequest { mut entry, logs } = pending;
+    entry.duration_ms = duration.as_secs_f64() * 1000.;
+    entry.logs = logs.lock().drain(..).map(From::from).collect();
+    let entry = Arc::new(Mutex::new(entry));
+    let mut entries = self.entries.lock().unwrap();
+    if entries.len() >= self.capacity {
+      entries.pop_front();
+    }
+    entries.push_back(entry.clone());
+    entry
+  }
+
+  /// Requests newest first, optionally of a single service.
+  pub fn list(&self, query: &str) -> Result<Response<Body>> {
+    #[derive(Deserialize)]
+    struct Query {
+      service: Option<String>,
+      limit: Option<usize>,
+    }
+
+    let Query { service, limit } = serde_qs::from_str(query)?;
+    let entries = (self.entries.lock().unwrap().iter().rev())
+      .map(|x| x.lock().unwrap().clone())
+      .filter(|x| service.as_ref().is_none_or(|s| *s == x.service))
+      .take(limit.unwrap_or(usize::MAX))
+      .collect::<Vec<_>>();
+    json_response(StatusCode::OK, entries)
+  }
+}
+
+fn headers(headers: &HeaderMap) -> Vec<(String, String)> {
+  (headers.iter())
+    .map(|(k, v)| {
+      let v = if SENSITIVE_HEADERS.contains(&k.as_str()) {
+        "<redacted>".into()
+      } else {
+        String::from_utf8_lossy(v.as_bytes()).into_owned()
+      };
+      (k.as_str().into(), v)
+    })
+    .collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hyper::Request;
+
+  fn parts(uri: &str) -> Parts {
+    (Request::post(uri).header("authorization", "Abel secret"))
+      .body(())
+      .unwrap()
+      .into_parts()
+      .0
+  }
+
+  async fn list(log: &RequestLog, query: &str) -> Vec<LoggedRequest> {
+    let body = log.list(query).unwrap().into_body();
+    serde_json::from_slice(&hyper::body::to_bytes(body).await.unwrap()).unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_request_log() {
+    let log = RequestLog::new(2);
+    let body = Bytes::from(vec![b'a'; MAX_BODY + 1]);
+    let pending = log.start("a", "/x", &parts("/a/x?q=1"), &body);
+    let resp = Response::new(Body::wrap_stream(futures::stream::iter([
+      Ok::<_, hyper::Error>("hello, "),
+      Ok("world"),
+    ])));
+    let resp = log.finish(pending, Duration::from_millis(5), resp);
+
+    let entry = list(&log, "").await.remove(0);
+    assert_eq!(entry.path, "/x?q=1");
+    assert_eq!(entry.request_headers, [(
+      "authorization".into(),
+      "<redacted>".into()
+    )]);
+    assert_eq!(entry.request_body.content.len(), MAX_BODY);
+    assert!(entry.request_body.truncated);
+    assert_eq!(entry.request_body.size, Some(MAX_BODY + 1));
+    assert_eq!(entry.response_body.size, None);
+
+    // Copied as it is sent
+    hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let entry = list(&log, "").await.remove(0);
+    assert_eq!(entry.status, 200);
+    assert_eq!(entry.duration_ms, 5.);
+    assert_eq!(entry.response_body.content, "hello, world");
+    assert_eq!(entry.response_body.size, Some(12));
+    assert!(!entry.response_body.truncated);
+  }
+
+  #[tokio::test]
+  async fn test_request_log_list() {
+    let log = RequestLog::new(2);
+    for service in ["a", "b", "a"] {
+      let pending = log.start(service, "/", &parts("/"), &Bytes::new());
+      let error = "error".to_string();
+      log.finish_error(pending, Duration::ZERO, StatusCode::BAD_GATEWAY, error);
+    }
+
+    let entries = list(&log, "").await;
+    let ids = entries.iter().map(|x| x.id).collect::<Vec<_>>();
+    assert_eq!(ids, [3, 2]);
+    assert_eq!(entries[0].status, 502);
+    assert_eq!(entries[0].error.as_deref(), Some("error"));
+    assert_eq!(list(&log, "service=b").await[0].id, 2);
+    assert_eq!(list(&log, "limit=1").await.len(), 1);
+  }
+}
@@ -0,0 +1,246 @@
+//! Recent lines logged by services, served at `GET /services/<name>/logs`
+//! and printed by `abel logs`.
+//!
+//! Lines are taken from the logger, so only those passing `RUST_LOG` are kept,
+//! and only the last [`CAPACITY`] of each service. With `follow`, the
+//! response is streamed as lines are logged, until the server drains.
+
+use super::{Result, ServerState};
+use futures::{stream, StreamExt};
+use hyper::{Body, Response};
+use log::{Log, Metadata, Record};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, VecDeque};
+use std::convert::Infallible;
+use std::sync::atomic::Ordering;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::broadcast;
+use tokio::sync::broadcast::error::RecvError;
+
+/// Lines kept for each service.
+const CAPACITY: usize = 1000;
+
+/// How often followers check whether the server is draining.
+const DRAIN_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+static SERVICE_LOGS: Lazy<ServiceLogs> = Lazy::new(|| ServiceLogs {
+  lines: Default::default(),
+  sender: broadcast::channel(256).0,
+});
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LogLine {
+  /// Unix time in seconds.
+  pub timestamp: f64,
+  pub service: String,
+  pub level: String,
+  pub message: String,
+}
+
+struct ServiceLogs {
+  lines: Mutex<HashMap<String, VecDeque<LogLine>>>,
+  sender: broadcast::Sender<LogLine>,
+}
+
+impl ServiceLogs {
+  fn push(&self, line: LogLine) {
+    let mut lines = self.lines.lock().unwrap();
+    let service_lines = lines.entry(line.service.clone()).or_default();
+    if service_lines.len() >= CAPACITY {
+      service_lines.pop_front();
+    }
+    service_lines.push_back(line.clone());
+    // Sent while locked, so that followers subscribing in `recent` neither
+    // miss nor repeat lines.
+    let _ = self.sender.send(line);
+  }
+
+  /// The last `limit` lines of `service`, and optionally the lines after
+  /// them.
+  fn recent(
+    &self,
+    service: &str,
+    limit: usize,
+    follow: bool,
+  ) -> (Vec<LogLine>, Option<broadcast::Receiver<LogLine>>) {
+    let lines = self.lines.lock().unwrap();
+    let recent = (lines.get(service))
+      .map(|x| {
+        x.iter()
+          .skip(x.len().saturating_sub(limit))
+          .cloned()
+          .collect()
+      })
+      .unwrap_or_default();
+    (recent, follow.then(|| self.sender.subscribe()))
+  }
+}
+
+/// Logger passing records on to the inner one, keeping lines of services.
+pub struct Tee<L>(pub L);
+
+impl<L: Log> Log for Tee<L> {
+  fn enabled(&self, metadata: &Metadata) -> bool {
+    self.0.enabled(metadata)
+  }
+
+  fn log(&self, record: &Record) {
+    if !self.0.enabled(record.metadata()) {
+      return;
+    }
+    self.0.log(record);
+    let service = (record.target().strip_prefix("service '")).and_then(|x| x.strip_suffix('\''));
+    if let Some(service) = service {
+      SERVICE_LOGS.push(LogLine {
+        timestamp: SystemTime::now()
+          .duration_since(UNIX_EPOCH)
+          .map(|x| x.as_secs_f64())
+          .unwrap_or_default(),
+        service: service.into(),
+        level: record.level().as_str().to_lowercase(),
+        message: record.args().to_string(),
+      });
+    }
+  }
+
+  fn flush(&self) {
+    self.0.flush()
+  }
+}
+
+/// Lines of service `name` as newline-delimited JSON.
+pub fn logs(state: Arc<ServerState>, name: &str, query: &str) -> Result<Response<Body>> {
+  #[derive(Deserialize)]
+  struct Query {
+    lines: Option<usize>,
+    #[serde(default)]
+    follow: bool,
+  }
+
+  let Query { lines, follow } = serde_qs::from_str(query)?;
+  state.abel.get_service(name)?;
+  let (recent, receiver) = SERVICE_LOGS.recent(name, lines.unwrap_or(100), follow);
+
+  let name = name.to_string();
+  let following = stream::unfold(receiver, move |receiver| {
+    let (state, name) = (state.clone(), name.clone());
+    async move {
+      let mut receiver = receiver?;
+      loop {
+        match tokio::time::timeout(DRAIN_CHECK_INTERVAL, receiver.recv()).await {
+          Ok(Ok(line)) if line.service == name => return Some((line, Some(receiver))),
+          Ok(Ok(_) | Err(RecvError::Lagged(_))) => {}
+          Ok(Err(RecvError::Closed)) => return None,
+          Err(_) if state.draining.load(Ordering::Acquire) => return None,
+          Err(_) => {}
+        }
+      }
+    }
+  });
+  let body = (stream::iter(recent).chain(following))
+    .map(|line| Ok::<_, Infallible>(serde_json::to_string(&line).unwrap() + "\n"));
+  let resp = Response::builder()
+    .header("content-type", "application/x-ndjson")
+    .body(Body::wrap_stream(body))
+    .unwrap();
+  Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::Config;
+  use crate::server::tests::state;
+  use crate::source::SingleSource;
+  use abel_core::source::Source;
+  use hyper::body::HttpBody;
+  use log::Level;
+  use tempfile::TempDir;
+
+  /// Inner logger enabled up to a level.
+  struct Inner(Level);
+
+  impl Log for Inner {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+      metadata.level() <= self.0
+    }
+
+    fn log(&self, _record: &Record) {}
+
+    fn flush(&self) {}
+  }
+
+  fn log(logger: &impl Log, target: &str, level: Level, message: &str) {
+    logger.log(
+      &Record::builder()
+        .target(target)
+        .level(level)
+        .args(format_args!("{message}"))
+        .build(),
+    );
+  }
+
+  fn messages(service: &str) -> Vec<String> {
+    let (lines, _) = SERVICE_LOGS.recent(service, usize::MAX, false);
+    lines.into_iter().map(|x| x.message).collect()
+  }
+
+  #[test]
+  fn test_tee() {
+    let tee = Tee(Inner(Level::Info));
+    log(&tee, "service 'tee'", Level::Info, "kept");
+    log(&tee, "service 'tee'", Level::Debug, "filtered");
+    log(&tee, "abel::server", Level::Info, "not a service");
+    assert_eq!(messages("tee"), ["kept"]);
+
+    for i in 0..CAPACITY + 1 {
+      log(&tee, "service 'tee-full'", Level::Info, &i.to_string());
+    }
+    let messages = messages("tee-full");
+    assert_eq!(messages.len(), CAPACITY);
+    assert_eq!(messages[0], "1");
+  }
+
+  async fn next_message(body: &mut Body) -> Option<String> {
+    let chunk = body.data().await?.unwrap();
+    Some(serde_json::from_slice::<LogLine>(&chunk).unwrap().message)
+  }
+
+  #[tokio::test]
+  async fn test_logs() {
+    let dir = TempDir::new().unwrap();
+    let state = state(dir.path(), Config::default()).await;
+    let source = Source::new(SingleSource::new(r#"abel.listen("/", function() end)"#));
+    (state.abel)
+      .cold_update_or_create_service("logs", None, source, Default::default())
+      .await
+      .unwrap();
+    let tee = Tee(Inner(Level::Info));
+    for message in ["a", "b", "c"] {
+      log(&tee, "service 'logs'", Level::Info, message);
+    }
+
+    let resp = logs(state.clone(), "logs", "lines=2").unwrap();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    let lines = (body.split(|x| *x == b'\n'))
+      .filter(|x| !x.is_empty())
+      .map(|x| serde_json::from_slice::<LogLine>(x).unwrap().message)
+      .collect::<Vec<_>>();
+    assert_eq!(lines, ["b", "c"]);
+    assert!(logs(state.clone(), "nonexistent", "").is_err());
+
+    let mut body = logs(state.clone(), "logs", "lines=1&follow=true")
+      .unwrap()
+      .into_body();
+    assert_eq!(next_message(&mut body).await.unwrap(), "c");
+    log(&tee, "service 'other'", Level::Info, "other");
+    log(&tee, "service 'logs'", Level::Info, "d");
+    assert_eq!(next_message(&mut body).await.unwrap(), "d");
+
+    // Ends once the server drains
+    state.draining.store(true, Ordering::Release);
+    assert_eq!(next_message(&mut body).await, None);
+  }
+}
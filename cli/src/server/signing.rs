@@ -0,0 +1,226 @@
+//! Signed requests, an alternative to presenting tokens as they are, so that
+//! the token never travels with requests and a captured one cannot be
+//! replayed.
+//!
+//! A signed request carries:
+//!
+//! ```text
+//! Authorization: Abel-HMAC-SHA256 Credential=<id>, Timestamp=<ts>, Signature=<sig>
+//! ```
+//!
+//! where `<id>` is the token's ID, or `admin` for the server's auth token,
+//! `<ts>` is the current Unix time in seconds, and `<sig>` is the lowercase
+//! hex HMAC-SHA256 of
+//!
+//! ```text
+//! <method>\n<path and query>\n<ts>\n<lowercase hex SHA-256 of body>
+//! ```
+//!
+//! keyed with the token's signing key, i.e. the lowercase hex HMAC-SHA256 of
+//! `abel-signing-key` keyed with the token. It is not the token's hash the
+//! token store looks tokens up by, so hashes cannot sign requests. Requests
+//! more than [`MAX_SKEW`] seconds away from the server's clock are rejected, as
+//! are signatures already seen within that window.
+
+use data_encoding::HEXLOWER;
+use hmac::{Hmac, Mac};
+use hyper::body::HttpBody;
+use hyper::header::CONTENT_LENGTH;
+use hyper::{Body, Request};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub const SCHEME: &str = "Abel-HMAC-SHA256";
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// Seconds a signed request's timestamp may be off from the server's clock.
+pub const MAX_SKEW: u64 = 300;
+
+/// Largest body of a signed request, which is buffered to be hashed before
+/// the request is authenticated. Larger uploads should present the token
+/// itself instead.
+const MAX_BODY: usize = 1024 * 1024;
+
+/// Seen signatures are swept of expired ones once there are this many.
+const SWEEP_THRESHOLD: usize = 1024;
+
+/// Parsed `Authorization` header of a signed request.
+pub struct Signed {
+  pub credential: String,
+  pub timestamp: u64,
+  signature: Vec<u8>,
+}
+
+impl Signed {
+  pub fn parse(req: &Request<Body>) -> Option<Self> {
+    let header = (req.headers().get("authorization"))
+      .and_then(|x| x.to_str().ok())?
+      .strip_prefix(SCHEME)?
+      .strip_prefix(' ')?;
+    let (mut credential, mut timestamp, mut signature) = (None, None, None);
+    for param in header.split(',') {
+      match param.trim().split_once('=')? {
+        ("Credential", x) => credential = Some(x.to_string()),
+        ("Timestamp", x) => timestamp = Some(x.parse().ok()?),
+        ("Signature", x) => signature = Some(HEXLOWER.decode(x.as_bytes()).ok()?),
+        _ => return None,
+      }
+    }
+    Some(Self {
+      credential: credential?,
+      timestamp: timestamp?,
+      signature: signature?,
+    })
+  }
+
+  /// Whether the timestamp is within [`MAX_SKEW`] of now.
+  pub fn is_fresh(&self) -> bool {
+    unix_now().abs_diff(self.timestamp) <= MAX_SKEW
+  }
+
+  /// Checks the signature of `req` with `key`, buffering its body to hash it.
+  ///
+  /// Requests declaring a body larger than [`MAX_BODY`] fail without reading
+  /// it.
+  pub async fn verify(&self, req: &mut Request<Body>, key: &[u8]) -> bool {
+    let content_length = (req.headers().get(CONTENT_LENGTH))
+      .map(|x| x.to_str().ok().and_then(|x| x.parse::<usize>().ok()));
+    match content_length {
+      Some(Some(len)) if len <= MAX_BODY => {}
+      Some(_) => return false,
+      None if req.body().size_hint().lower() as usize > MAX_BODY => return false,
+      None => {}
+    }
+    let body = match read_body(req.body_mut()).await {
+      Some(body) => body,
+      None => return false,
+    };
+    let path = (req.uri().path_and_query()).map_or("/", |x| x.as_str());
+    let string_to_sign = format!(
+      "{}\n{path}\n{}\n{}",
+      req.method(),
+      self.timestamp,
+      HEXLOWER.encode(&Sha256::digest(&body)),
+    );
+    *req.body_mut() = Body::from(body);
+    constant_time_eq(
+      &hmac_sha256(key, string_to_sign.as_bytes()),
+      &self.signature,
+    )
+  }
+}
+
+/// Key signing requests with `token`.
+pub fn signing_key(token: &str) -> String {
+  HEXLOWER.encode(&hmac_sha256(token.as_bytes(), b"abel-signing-key"))
+}
+
+/// Signatures of accepted requests, which are not accepted again.
+#[derive(Default)]
+pub struct SeenSignatures {
+  /// Signatures and when they can no longer be fresh.
+  seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+impl SeenSignatures {
+  /// Records `signed`'s signature, returning `false` if it was seen before.
+  pub fn insert(&self, signed: &Signed) -> bool {
+    let now = unix_now();
+    let mut seen = self.seen.lock().unwrap();
+    if seen.len() >= SWEEP_THRESHOLD {
+      seen.retain(|_, x| *x >= now);
+    }
+    (seen.insert(signed.signature.clone(), signed.timestamp + MAX_SKEW)).is_none()
+  }
+}
+
+async fn read_body(body: &mut Body) -> Option<Vec<u8>> {
+  let mut buf = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk.ok()?;
+    if buf.len() + chunk.len() > MAX_BODY {
+      return None;
+    }
+    buf.extend_from_slice(&chunk);
+  }
+  Some(buf)
+}
+
+fn hmac_sha256(key: &[u8], data: &[u8]) -> Vec<u8> {
+  let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts keys of any length");
+  mac.update(data);
+  mac.finalize().into_bytes().to_vec()
+}
+
+/// Compares secrets without returning early, so the time taken does not tell
+/// how much of a guess was right.
+pub(super) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|x| x.as_secs())
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::tokens::hash_token;
+
+  fn signed_request(key: &str, timestamp: u64) -> (Request<Body>, Signed) {
+    let body = b"{}";
+    let string_to_sign = format!(
+      "POST\n/services\n{timestamp}\n{}",
+      HEXLOWER.encode(&Sha256::digest(body)),
+    );
+    let signature = hmac_sha256(key.as_bytes(), string_to_sign.as_bytes());
+    let req = Request::post("/services")
+      .header(
+        "authorization",
+        format!(
+          "{SCHEME} Credential=admin, Timestamp={timestamp}, Signature={}",
+          HEXLOWER.encode(&signature)
+        ),
+      )
+      .body(Body::from(&body[..]))
+      .unwrap();
+    let signed = Signed::parse(&req).unwrap();
+    (req, signed)
+  }
+
+  #[tokio::test]
+  async fn test_signing_key() {
+    let token = "00000000-0000-0000-0000-000000000001";
+    let key = signing_key(token);
+    let now = unix_now();
+
+    let (mut req, signed) = signed_request(&key, now);
+    assert!(signed.verify(&mut req, key.as_bytes()).await);
+
+    // A signature made with the stored hash of the token is not accepted
+    let (mut req, signed) = signed_request(&hash_token(token), now);
+    assert!(!signed.verify(&mut req, key.as_bytes()).await);
+  }
+
+  #[tokio::test]
+  async fn test_large_body_not_read() {
+    let key = signing_key("00000000-0000-0000-0000-000000000001");
+    let (mut req, signed) = signed_request(&key, unix_now());
+    let len = (MAX_BODY + 1).to_string();
+    req
+      .headers_mut()
+      .insert(CONTENT_LENGTH, len.parse().unwrap());
+    assert!(!signed.verify(&mut req, key.as_bytes()).await);
+    // The body is left as is
+    assert_eq!(
+      &hyper::body::to_bytes(req.into_body()).await.unwrap()[..],
+      b"{}"
+    );
+  }
+}
@@ -0,0 +1,184 @@
+use anyhow::Context;
+use futures::{Future, Stream};
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc;
+use tokio::time::{sleep, timeout};
+use tokio_rustls::rustls::crypto::ring;
+use tokio_rustls::rustls::pki_types::pem::PemObject;
+use tokio_rustls::rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use tokio_rustls::rustls::server::WebPkiClientVerifier;
+use tokio_rustls::rustls::{RootCertStore, ServerConfig};
+use tokio_rustls::server::TlsStream;
+use tokio_rustls::TlsAcceptor;
+
+/// Serving HTTPS directly, as specified in `config.json`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TlsConfig {
+  /// PEM file containing the certificate chain.
+  pub cert: PathBuf,
+  /// PEM file containing the private key.
+  pub key: PathBuf,
+  /// PEM file containing CA certificates. If set, clients must present a
+  /// certificate signed by one of them.
+  #[serde(default)]
+  pub client_ca: Option<PathBuf>,
+}
+
+/// Clients not done with the TLS handshake in this long are disconnected.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long accepting pauses after an error not caused by the client, e.g.
+/// running out of file descriptors, instead of failing again right away.
+const ACCEPT_ERROR_DELAY: Duration = Duration::from_secs(1);
+
+/// Accepts TCP connections on `listener` and performs TLS handshakes on them.
+///
+/// Handshakes run in their own tasks so a slow client does not hold up
+/// others; failed or timed out ones are only logged.
+pub async fn incoming(
+  listener: TcpListener,
+  config: &TlsConfig,
+) -> anyhow::Result<impl Stream<Item = io::Result<TlsStream<TcpStream>>>> {
+  let acceptor = TlsAcceptor::from(Arc::new(server_config(config).await?));
+  Ok(accept(listener, HANDSHAKE_TIMEOUT, move |stream| {
+    let acceptor = acceptor.clone();
+    async move { acceptor.accept(stream).await }
+  }))
+}
+
+async fn server_config(config: &TlsConfig) -> anyhow::Result<ServerConfig> {
+  let certs = CertificateDer::pem_slice_iter(&read(&config.cert).await?)
+    .collect::<Result<Vec<_>, _>>()
+    .context("invalid TLS certificate")?;
+  let key =
+    PrivateKeyDer::from_pem_slice(&read(&config.key).await?).context("invalid TLS private key")?;
+
+  let provider = Arc::new(ring::default_provider());
+  let builder = ServerConfig::builder_with_provider(provider.clone())
+    .with_safe_default_protocol_versions()
+    .context("failed to create TLS acceptor")?;
+  let builder = if let Some(client_ca) = &config.client_ca {
+    let mut roots = RootCertStore::empty();
+    for cert in CertificateDer::pem_slice_iter(&read(client_ca).await?) {
+      (roots.add(cert?)).context("invalid client CA certificate")?;
+    }
+    let verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider)
+      .build()
+      .context("invalid client CA certificates")?;
+    builder.with_client_cert_verifier(verifier)
+  } else {
+    builder.with_no_client_auth()
+  };
+  (builder.with_single_cert(certs, key)).context("invalid TLS certificate or key")
+}
+
+async fn read(path: &Path) -> anyhow::Result<Vec<u8>> {
+  (fs::read(path).await).with_context(|| format!("failed to read {}", path.display()))
+}
+
+fn accept<T, E, F, Fut>(
+  listener: TcpListener,
+  handshake_timeout: Duration,
+  handshake: F,
+) -> impl Stream<Item = io::Result<T>>
+where
+  T: Send + 'static,
+  E: Display + Send,
+  F: Fn(TcpStream) -> Fut + Send + 'static,
+  Fut: Future<Output = Result<T, E>> + Send + 'static,
+{
+  let (tx, mut rx) = mpsc::channel(64);
+  tokio::spawn(async move {
+    while !tx.is_closed() {
+      let (stream, peer) = match listener.accept().await {
+        Ok(x) => x,
+        Err(error) if is_connection_error(&error) => {
+          debug!("failed to accept connection: {error}");
+          continue;
+        }
+        Err(error) => {
+          warn!("failed to accept connection: {error}");
+          sleep(ACCEPT_ERROR_DELAY).await;
+          continue;
+        }
+      };
+      let (handshake, tx) = (handshake(stream), tx.clone());
+      tokio::spawn(async move {
+        match timeout(handshake_timeout, handshake).await {
+          Ok(Ok(stream)) => drop(tx.send(Ok(stream)).await),
+          Ok(Err(error)) => debug!("TLS handshake with {peer} failed: {error}"),
+          Err(_) => debug!("TLS handshake with {peer} timed out"),
+        }
+      });
+    }
+  });
+
+  futures::stream::poll_fn(move |cx| rx.poll_recv(cx))
+}
+
+/// Errors caused by a single client, after which accepting can go on.
+fn is_connection_error(error: &io::Error) -> bool {
+  use io::ErrorKind::*;
+  matches!(
+    error.kind(),
+    ConnectionRefused | ConnectionAborted | ConnectionReset
+  )
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use futures::StreamExt;
+  use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+  async fn listen() -> (TcpListener, std::net::SocketAddr) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    (listener, addr)
+  }
+
+  #[tokio::test]
+  async fn test_handshake() {
+    let (listener, addr) = listen().await;
+    let incoming = accept(listener, Duration::from_secs(10), |mut stream| async move {
+      let byte = stream.read_u8().await?;
+      Ok::<_, io::Error>(byte)
+    });
+    tokio::pin!(incoming);
+
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    client.write_u8(42).await.unwrap();
+    assert_eq!(incoming.next().await.unwrap().unwrap(), 42);
+  }
+
+  #[tokio::test]
+  async fn test_handshake_timeout() {
+    let (listener, addr) = listen().await;
+    let incoming = accept(listener, Duration::from_millis(50), |stream| async move {
+      futures::future::pending::<()>().await;
+      Ok::<_, io::Error>(stream)
+    });
+
+    // The server hangs up on a client that never finishes the handshake.
+    let mut client = TcpStream::connect(addr).await.unwrap();
+    let read = timeout(Duration::from_secs(5), client.read(&mut [0; 1])).await;
+    assert_eq!(read.unwrap().unwrap(), 0);
+    drop(incoming);
+  }
+
+  #[test]
+  fn test_is_connection_error() {
+    assert!(is_connection_error(&io::ErrorKind::ConnectionReset.into()));
+    assert!(!is_connection_error(&io::Error::from_raw_os_error(
+      libc::EMFILE
+    )));
+  }
+}
@@ -0,0 +1,317 @@
+use super::atomic::write_atomic_private;
+use super::signing::signing_key;
+use data_encoding::HEXLOWER;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use sha2::{Digest, Sha256};
+use std::fmt::{self, Display, Formatter};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+use tokio::fs;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// Permission granted to a token.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Scope {
+  /// List and inspect services, and read metrics.
+  ServicesRead,
+  /// Upload, start, stop and remove services. Implies `services:read`.
+  ServicesWrite,
+  /// Call service `name` as an authenticated user, i.e. see its internal
+  /// errors. `*` matches every service.
+  ServiceInvoke(String),
+  /// Download services' stored sources from `/api/v1/export`, as read
+  /// replicas do. Not implied by any other scope.
+  Replica,
+}
+
+impl Scope {
+  fn allows(&self, required: &Scope) -> bool {
+    use Scope::*;
+    match (self, required) {
+      (ServicesWrite, ServicesRead) => true,
+      (ServiceInvoke(a), ServiceInvoke(b)) => a == "*" || a == b,
+      (a, b) => a == b,
+    }
+  }
+}
+
+impl Display for Scope {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::ServicesRead => f.write_str("services:read"),
+      Self::ServicesWrite => f.write_str("services:write"),
+      Self::ServiceInvoke(name) => write!(f, "service:{name}:invoke"),
+      Self::Replica => f.write_str("replica"),
+    }
+  }
+}
+
+impl FromStr for Scope {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    match s {
+      "services:read" => Ok(Self::ServicesRead),
+      "services:write" => Ok(Self::ServicesWrite),
+      "replica" => Ok(Self::Replica),
+      _ => match (s.strip_prefix("service:")).and_then(|x| x.strip_suffix(":invoke")) {
+        Some(name) if !name.is_empty() => Ok(Self::ServiceInvoke(name.into())),
+        _ => Err(format!("invalid scope: {s:?}")),
+      },
+    }
+  }
+}
+
+impl Serialize for Scope {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(self)
+  }
+}
+
+impl<'de> Deserialize<'de> for Scope {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+/// Who a request is made by.
+#[derive(Debug, Clone)]
+pub enum Auth {
+  /// No or unknown token.
+  Anonymous,
+  /// The server's own auth token, or any request if it has none.
+  Admin,
+  /// A token from the token store, without its hash.
+  Token(TokenInfo),
+}
+
+impl Auth {
+  pub fn is_admin(&self) -> bool {
+    matches!(self, Self::Admin)
+  }
+
+  pub fn allows(&self, scope: &Scope) -> bool {
+    match self {
+      Self::Anonymous => false,
+      Self::Admin => true,
+      Self::Token(info) => info.scopes.iter().any(|x| x.allows(scope)),
+    }
+  }
+}
+
+/// A stored token. The token itself is only kept as its SHA-256 hash, which
+/// cannot be used in its place.
+///
+/// Its signing key is kept as is, and is as good as the token for signed
+/// requests, so `tokens.json` is only readable by the server's user and must
+/// be protected like the server's own auth token.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TokenInfo {
+  pub id: Uuid,
+  pub name: Option<String>,
+  pub scopes: Vec<Scope>,
+  pub created_at: u64,
+  /// Set once the token is rotated, after which it is removed.
+  #[serde(skip_serializing_if = "Option::is_none", default)]
+  pub expires_at: Option<u64>,
+  #[serde(skip_serializing_if = "String::is_empty", default)]
+  hash: String,
+  /// Empty for tokens created before signing keys were stored, which cannot
+  /// sign requests until they are rotated.
+  #[serde(skip_serializing_if = "String::is_empty", default)]
+  signing_key: String,
+}
+
+/// Outcome of [`TokenStore::rotate`].
+pub enum Rotation {
+  Rotated {
+    /// The old token's info, now expiring.
+    old: TokenInfo,
+    new: TokenInfo,
+    token: Uuid,
+  },
+  NotFound,
+  /// The token is already being rotated out.
+  AlreadyRotated(TokenInfo),
+}
+
+/// Additional auth tokens, persisted in `<abel_path>/tokens.json`.
+pub struct TokenStore {
+  path: PathBuf,
+  tokens: RwLock<Vec<TokenInfo>>,
+  write_lock: Mutex<()>,
+}
+
+impl TokenStore {
+  pub async fn load(abel_path: &Path) -> io::Result<Self> {
+    let path = abel_path.join("tokens.json");
+    let tokens = if path.exists() {
+      serde_json::from_slice(&fs::read(&path).await?)?
+    } else {
+      Vec::new()
+    };
+    Ok(Self {
+      path,
+      tokens: RwLock::new(tokens),
+      write_lock: Mutex::new(()),
+    })
+  }
+
+  /// Info of `token` without its hash, if it exists.
+  pub fn get(&self, token: &str) -> Option<TokenInfo> {
+    let hash = hash_token(token);
+    let now = unix_now();
+    let tokens = self.tokens.read().unwrap();
+    (tokens.iter())
+      .find(|x| x.hash == hash && !x.is_expired(now))
+      .map(TokenInfo::without_hash)
+  }
+
+  /// Info of token of `id` and its key for signing requests.
+  pub fn get_by_id(&self, id: Uuid) -> Option<(TokenInfo, String)> {
+    let now = unix_now();
+    let tokens = self.tokens.read().unwrap();
+    (tokens.iter())
+      .find(|x| x.id == id && !x.is_expired(now) && !x.signing_key.is_empty())
+      .map(|x| (x.without_hash(), x.signing_key.clone()))
+  }
+
+  /// Token info without hashes.
+  pub fn list(&self) -> Vec<TokenInfo> {
+    let now = unix_now();
+    let tokens = self.tokens.read().unwrap();
+    (tokens.iter())
+      .filter(|x| !x.is_expired(now))
+      .map(TokenInfo::without_hash)
+      .collect()
+  }
+
+  /// Creates a new token, returning its info and the token itself.
+  pub async fn create(
+    &self,
+    name: Option<String>,
+    scopes: Vec<Scope>,
+  ) -> io::Result<(TokenInfo, Uuid)> {
+    let (info, token) = TokenInfo::new(name, scopes);
+    self.modify(|tokens| tokens.push(info.clone())).await?;
+    Ok((info.without_hash(), token))
+  }
+
+  /// Issues a new token with the same name and scopes as token of `id`,
+  /// which stays valid for `grace_period` seconds.
+  pub async fn rotate(&self, id: Uuid, grace_period: u64) -> io::Result<Rotation> {
+    (self.modify(|tokens| {
+      let old = match tokens.iter_mut().find(|x| x.id == id) {
+        Some(old) => old,
+        None => return Rotation::NotFound,
+      };
+      if old.expires_at.is_some() {
+        return Rotation::AlreadyRotated(old.without_hash());
+      }
+      old.expires_at = Some(unix_now().saturating_add(grace_period));
+      let old = old.without_hash();
+      let (new, token) = TokenInfo::new(old.name.clone(), old.scopes.clone());
+      tokens.push(new.clone());
+      Rotation::Rotated {
+        old,
+        new: new.without_hash(),
+        token,
+      }
+    }))
+    .await
+  }
+
+  /// Revokes token of `id`, returning its info if it existed.
+  pub async fn revoke(&self, id: Uuid) -> io::Result<Option<TokenInfo>> {
+    let removed = (self.modify(|tokens| {
+      let i = tokens.iter().position(|x| x.id == id)?;
+      Some(tokens.remove(i))
+    }))
+    .await?;
+    Ok(removed.as_ref().map(TokenInfo::without_hash))
+  }
+
+  async fn modify<R>(&self, f: impl FnOnce(&mut Vec<TokenInfo>) -> R) -> io::Result<R> {
+    // Changes are only applied in memory after they are written to disk.
+    let _guard = self.write_lock.lock().await;
+    let mut tokens = self.tokens.read().unwrap().clone();
+    let result = f(&mut tokens);
+    let now = unix_now();
+    tokens.retain(|x| !x.is_expired(now));
+    write_atomic_private(&self.path, serde_json::to_vec_pretty(&tokens)?).await?;
+    *self.tokens.write().unwrap() = tokens;
+    Ok(result)
+  }
+}
+
+impl TokenInfo {
+  fn new(name: Option<String>, scopes: Vec<Scope>) -> (Self, Uuid) {
+    let token = Uuid::new_v4();
+    let info = Self {
+      id: Uuid::new_v4(),
+      name,
+      scopes,
+      created_at: unix_now(),
+      expires_at: None,
+      hash: hash_token(&token.to_string()),
+      signing_key: signing_key(&token.to_string()),
+    };
+    (info, token)
+  }
+
+  fn is_expired(&self, now: u64) -> bool {
+    self.expires_at.is_some_and(|t| t <= now)
+  }
+
+  fn without_hash(&self) -> Self {
+    Self {
+      hash: String::new(),
+      signing_key: String::new(),
+      ..self.clone()
+    }
+  }
+}
+
+fn unix_now() -> u64 {
+  SystemTime::now()
+    .duration_since(UNIX_EPOCH)
+    .map(|x| x.as_secs())
+    .unwrap_or_default()
+}
+
+pub fn hash_token(token: &str) -> String {
+  HEXLOWER.encode(&Sha256::digest(token.as_bytes()))
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_scope_allows() {
+    assert!(Scope::ServicesWrite.allows(&Scope::ServicesRead));
+    assert!(!Scope::ServicesRead.allows(&Scope::ServicesWrite));
+    assert!(Scope::ServiceInvoke("*".into()).allows(&Scope::ServiceInvoke("a".into())));
+    assert!(!Scope::ServiceInvoke("b".into()).allows(&Scope::ServiceInvoke("a".into())));
+    assert!(!Scope::ServicesWrite.allows(&Scope::Replica));
+    assert!(Scope::Replica.allows(&Scope::Replica));
+  }
+
+  #[test]
+  fn test_scope_round_trip() {
+    for s in [
+      "services:read",
+      "services:write",
+      "service:a:invoke",
+      "replica",
+    ] {
+      assert_eq!(s.parse::<Scope>().unwrap().to_string(), s);
+    }
+    assert!("service::invoke".parse::<Scope>().is_err());
+  }
+}
@@ -1,8 +1,10 @@
-use abel_core::service::{Service, ServiceGuard, ServiceInfo};
+use abel_core::service::{Health, Service, ServiceGuard, ServiceInfo};
 use ouroboros::self_referencing;
 use serde::{Deserialize, Serialize, Serializer};
 use serde_with::skip_serializing_none;
 use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fmt::{self, Display, Formatter};
 
 #[self_referencing]
 pub struct OwnedServiceWithStatus<'a> {
@@ -44,6 +46,9 @@ pub enum ServiceStatus {
 pub struct ServiceWithStatus<'a> {
   pub status: ServiceStatus,
   pub service: Cow<'a, ServiceInfo>,
+  /// Latest health check of running services that define `abel.health`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub health: Option<Health>,
 }
 
 impl<'a> ServiceWithStatus<'a> {
@@ -53,10 +58,12 @@ impl<'a> ServiceWithStatus<'a> {
       ServiceGuard::Running { service } => Self {
         status: Running,
         service: Cow::Borrowed(service.info()),
+        health: service.health(),
       },
       ServiceGuard::Stopped { service } => Self {
         status: Stopped,
         service: Cow::Borrowed(service.info()),
+        health: None,
       },
     }
   }
@@ -91,4 +98,166 @@ pub struct HttpUploadResponse<'a> {
   pub replaced_service: Option<Cow<'a, ServiceInfo>>,
   #[serde(default, skip_serializing_if = "ErrorPayload::is_empty")]
   pub errors: ErrorPayload<'a>,
+  /// The fetched source, or with `deterministic_uuids` any source, is what
+  /// the service already runs, so it was left as is.
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub unchanged: bool,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub diff: Option<ServiceDiff>,
+}
+
+/// What an update changed from the service it replaced.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct ServiceDiff {
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub routes_added: Vec<String>,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub routes_removed: Vec<String>,
+  /// Configuration keys added, removed or changed.
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub config_changed: Vec<String>,
+  /// Change in the stored source, unless either version is opened from a
+  /// URI.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub source: Option<SourceDelta>,
+}
+
+/// Change in number of files and their total size in bytes.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+pub struct SourceDelta {
+  pub files: i64,
+  pub size: i64,
+}
+
+impl ServiceDiff {
+  /// Compares two services' routes and configuration, with their stored
+  /// sources' number and total size of files, if known.
+  pub fn between(
+    old: &ServiceInfo,
+    new: &ServiceInfo,
+    stats: Option<((u64, u64), (u64, u64))>,
+  ) -> Self {
+    let routes = |x: &ServiceInfo| (x.paths().iter()).map(|x| x.as_str().to_string()).collect();
+    let (old_routes, new_routes): (BTreeSet<_>, BTreeSet<_>) = (routes(old), routes(new));
+
+    let config = |x| match serde_json::to_value(x) {
+      Ok(serde_json::Value::Object(x)) => x,
+      _ => Default::default(),
+    };
+    let (old_config, new_config) = (config(old), config(new));
+    let config_changed = (old_config.keys().chain(new_config.keys()))
+      .filter(|x| !matches!(x.as_str(), "name" | "uuid" | "paths"))
+      .filter(|x| old_config.get(*x) != new_config.get(*x))
+      .cloned()
+      .collect::<BTreeSet<_>>();
+
+    Self {
+      routes_added: new_routes.difference(&old_routes).cloned().collect(),
+      routes_removed: old_routes.difference(&new_routes).cloned().collect(),
+      config_changed: config_changed.into_iter().collect(),
+      source: stats.map(
+        |((old_files, old_size), (new_files, new_size))| SourceDelta {
+          files: new_files as i64 - old_files as i64,
+          size: new_size as i64 - old_size as i64,
+        },
+      ),
+    }
+  }
+}
+
+impl Display for ServiceDiff {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    let mut parts = Vec::new();
+    if !self.routes_added.is_empty() || !self.routes_removed.is_empty() {
+      let added = self.routes_added.iter().map(|x| format!("+{x}"));
+      let removed = self.routes_removed.iter().map(|x| format!("-{x}"));
+      parts.push(format!(
+        "routes {}",
+        added.chain(removed).collect::<Vec<_>>().join(" ")
+      ));
+    }
+    if !self.config_changed.is_empty() {
+      parts.push(format!("config {}", self.config_changed.join(", ")));
+    }
+    if let Some(SourceDelta { files, size }) = self.source {
+      parts.push(format!("files {files:+}, {size:+} bytes"));
+    }
+    if parts.is_empty() {
+      write!(f, "no routes or config changed")
+    } else {
+      write!(f, "{}", parts.join("; "))
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::Config;
+  use crate::server::tests::state;
+  use crate::source::SingleSource;
+  use abel_core::source::Source;
+  use std::collections::HashMap;
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn test_service_diff() {
+    let dir = TempDir::new().unwrap();
+    let state = state(dir.path(), Config::default()).await;
+    let create = |name, code, config| {
+      let state = state.clone();
+      async move {
+        let source = Source::new(SingleSource::new(code));
+        let (service, _, _) = (state
+          .abel
+          .cold_update_or_create_service(name, None, source, config))
+        .await
+        .unwrap();
+        service.upgrade().info().clone()
+      }
+    };
+
+    let old = create(
+      "a",
+      r#"abel.listen("/x", function() end); abel.listen("/y", function() end)"#,
+      abel_core::Config {
+        description: Some("old".into()),
+        env: HashMap::from([("A".into(), "1".into())]),
+        ..Default::default()
+      },
+    )
+    .await;
+    let new = create(
+      "b",
+      r#"abel.listen("/y", function() end); abel.listen("/z", function() end)"#,
+      abel_core::Config {
+        description: Some("new".into()),
+        compress: Some(true),
+        env: HashMap::from([("A".into(), "1".into())]),
+        ..Default::default()
+      },
+    )
+    .await;
+
+    let diff = ServiceDiff::between(&old, &new, Some(((2, 100), (1, 150))));
+    assert_eq!(diff.routes_added, ["/z"]);
+    assert_eq!(diff.routes_removed, ["/x"]);
+    assert_eq!(diff.config_changed, ["compress", "description"]);
+    assert!(matches!(
+      diff.source,
+      Some(SourceDelta {
+        files: -1,
+        size: 50
+      })
+    ));
+    assert_eq!(
+      diff.to_string(),
+      "routes +/z -/x; config compress, description; files -1, +50 bytes"
+    );
+
+    let diff = ServiceDiff::between(&old, &old, None);
+    assert!(diff.routes_added.is_empty() && diff.routes_removed.is_empty());
+    assert!(diff.config_changed.is_empty() && diff.source.is_none());
+    assert_eq!(diff.to_string(), "no routes or config changed");
+  }
 }
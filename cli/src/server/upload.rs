@@ -1,25 +1,29 @@
+use super::git::{prune_checkouts, GitInfo, GitSource};
+use super::handle::UNVERSIONED_PATHS;
 use super::metadata::Metadata;
-use super::types::{HttpUploadResponse, ServiceWithStatus};
-use super::{json_response, Result, ServerState};
-use crate::source::{AsarSource, SingleSource};
+use super::types::{HttpUploadResponse, ServiceDiff, ServiceWithStatus};
+use super::{json_response, versions, Error, Result, ServerState};
+use crate::source::{hash_asar, hash_reader, read_config, ArchiveKind, SingleSource};
 use crate::SourceKind;
-use abel_core::service::{ErrorPayload, Service};
+use abel_core::service::{normalize_name, ErrorPayload, Service};
 use abel_core::source::Source;
 use abel_core::ErrorKind::ServiceExists;
 use abel_core::{Config, ServiceImpl};
 use bytes::{Bytes, BytesMut};
 use futures::{Stream, TryStreamExt};
-use hive_asar::Archive;
 use hyper::{Body, HeaderMap, Request, Response, StatusCode};
 use log::{info, warn};
-use multer::{Constraints, Multipart, SizeLimit};
+use multer::{Constraints, Field, Multipart, SizeLimit};
 use owo_colors::OwoColorize;
 use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
 use std::borrow::Cow;
+use std::iter;
 use std::path::{Path, PathBuf};
 use strum::{Display, EnumString, IntoStaticStr};
 use tokio::fs::{self, File};
-use tokio::io::{self, AsyncReadExt};
+use tokio::io;
 use tokio_util::io::StreamReader;
 use uuid::Uuid;
 
@@ -57,12 +61,24 @@ pub enum UploadMode {
 struct UploadQuery {
   #[serde(default)]
   mode: UploadMode,
+  /// Fetches the source from this git repository instead.
+  git: Option<String>,
+  /// Branch, tag or commit of `git` to fetch, defaulting to `HEAD`.
+  #[serde(rename = "ref")]
+  reference: Option<String>,
+  /// Updates the service from `git` even if the fetched tree is unchanged.
+  #[serde(default)]
+  force: bool,
 }
 
 pub struct UploadResponse<'a> {
   pub new_service: Service<'a>,
   pub replaced_service: Option<ServiceImpl>,
   pub errors: ErrorPayload,
+  /// The same source is already running, so nothing was replaced.
+  pub unchanged: bool,
+  /// What changed from `replaced_service`.
+  pub diff: Option<ServiceDiff>,
 }
 
 pub async fn upload(
@@ -71,10 +87,29 @@ pub async fn upload(
   req: Request<Body>,
 ) -> Result<Response<Body>> {
   let (parts, body) = req.into_parts();
+  let UploadQuery {
+    mode,
+    git,
+    reference,
+    force,
+  } = serde_qs::from_str(parts.uri.query().unwrap_or(""))?;
+  if let Some(repo) = git {
+    let reference = reference.unwrap_or_else(|| "HEAD".into());
+    return upload_git(state, name, mode, repo, reference, force).await;
+  }
   let mut multipart = parse_multipart(&parts.headers, body)?;
 
-  let UploadQuery { mode } = serde_qs::from_str(parts.uri.query().unwrap_or(""))?;
+  let (kind, source_field) = next_source_field(&mut multipart).await?;
+  let source_stream = source_field.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
+  let resp = upload_local(state, name, mode, kind, None, source_stream).await?;
 
+  response(resp).await
+}
+
+/// Reads the `single` or `multi` field that starts the multipart upload.
+pub(super) async fn next_source_field(
+  multipart: &mut Multipart<'static>,
+) -> Result<(SourceKind, Field<'static>)> {
   let source_field = multipart.next_field().await?.ok_or((
     "no source uploaded",
     "specify either `single` or `multi` field in multipart",
@@ -90,11 +125,7 @@ pub async fn upload(
       )))
     }
   };
-
-  let source_stream = source_field.map_err(|e| io::Error::new(io::ErrorKind::Other, e));
-  let resp = upload_local(state, name, mode, kind, source_stream).await?;
-
-  response(resp).await
+  Ok((kind, source_field))
 }
 
 pub async fn upload_local(
@@ -102,14 +133,97 @@ pub async fn upload_local(
   name: String,
   mode: UploadMode,
   kind: SourceKind,
+  uuid: Option<Uuid>,
   source_stream: impl Stream<Item = io::Result<Bytes>> + Unpin,
 ) -> Result<UploadResponse> {
-  let (temp_path, source, config) =
-    read_store_service_temp(&state.abel_path, kind, source_stream).await?;
-  create_service(state, mode, name, config, source, kind, &temp_path).await
+  let (temp_path, source, config) = read_store_service_temp(state, kind, source_stream).await?;
+  let stored = StoredSource::File(kind, &temp_path);
+  create_service(state, mode, name, uuid, config, source, stored).await
 }
 
-fn parse_multipart(headers: &HeaderMap, body: Body) -> Result<Multipart<'static>> {
+/// Creates or updates a service whose source is opened from `stored.uri`,
+/// which is then stored in place of its source.
+pub(super) async fn upload_uri(
+  state: &ServerState,
+  name: String,
+  mode: UploadMode,
+  uuid: Option<Uuid>,
+  stored: UriSource,
+) -> Result<UploadResponse> {
+  let source = state.abel.open_source(&stored.uri).await?;
+  let config = read_config::<Error>(&source).await?;
+  create_service(
+    state,
+    mode,
+    name,
+    uuid,
+    config,
+    source,
+    StoredSource::Uri(stored),
+  )
+  .await
+}
+
+/// Fetches `reference` of `repo` and deploys it, unless the service is
+/// already running the same tree.
+async fn upload_git(
+  state: &ServerState,
+  name: String,
+  mode: UploadMode,
+  repo: String,
+  reference: String,
+  force: bool,
+) -> Result<Response<Body>> {
+  let service_path = state.abel_path.join("services").join(&name);
+  let fetched = GitSource::fetch(
+    &repo,
+    &reference,
+    state.abel_path.join(format!("tmp/{}", Uuid::new_v4())),
+  )
+  .await?;
+
+  let deployed = Metadata::read(&service_path.join("metadata.json"))
+    .await
+    .ok();
+  let unchanged = deployed.is_some_and(|x| {
+    x.source_hash.as_ref() == Some(&fetched.tree) && x.git.is_some_and(|x| x.repo == repo)
+  });
+  if let (true, false, Ok(service)) = (unchanged, force, state.abel.get_service(&name)) {
+    fetched.remove().await?;
+    let guard = service.upgrade();
+    return json_response(StatusCode::OK, HttpUploadResponse {
+      new_service: ServiceWithStatus::from_guard(&guard),
+      replaced_service: None,
+      errors: Default::default(),
+      unchanged: true,
+      diff: None,
+    });
+  }
+
+  let checkout_path = service_path.join("checkouts").join(&fetched.commit);
+  let stored = UriSource {
+    uri: String::new(),
+    source_hash: Some(fetched.tree.clone()),
+    git: Some(GitInfo {
+      repo,
+      reference,
+      commit: fetched.commit.clone(),
+    }),
+  };
+  fetched.checkout(&checkout_path).await?;
+  let stored = UriSource {
+    uri: format!(
+      "file://{}",
+      fs::canonicalize(&checkout_path).await?.display()
+    ),
+    ..stored
+  };
+  let resp = upload_uri(state, name, mode, None, stored).await;
+  prune_checkouts(&service_path).await?;
+  response(resp?).await
+}
+
+pub(super) fn parse_multipart(headers: &HeaderMap, body: Body) -> Result<Multipart<'static>> {
   let allowed_fields = vec!["single", "multi", "config"];
   let size_limit = SizeLimit::new()
     .for_field("single", 1024u64.pow(2) * 5)
@@ -128,12 +242,12 @@ fn parse_multipart(headers: &HeaderMap, body: Body) -> Result<Multipart<'static>
   Ok(Multipart::with_constraints(body, boundary, constraints))
 }
 
-async fn read_store_service_temp(
-  abel_path: &Path,
+pub(super) async fn read_store_service_temp(
+  state: &ServerState,
   kind: SourceKind,
   mut source_stream: impl Stream<Item = io::Result<Bytes>> + Unpin,
 ) -> Result<(PathBuf, Source, Config)> {
-  let temp_path = abel_path.join(format!("tmp/{}", Uuid::new_v4()));
+  let temp_path = state.abel_path.join(format!("tmp/{}", Uuid::new_v4()));
 
   let (source, config) = match kind {
     SourceKind::Single => {
@@ -151,17 +265,11 @@ async fn read_store_service_temp(
       let mut writer = File::create(&temp_path).await?;
       io::copy(&mut reader, &mut writer).await?;
 
-      let mut archive = Archive::new_from_file(&temp_path).await?;
-
-      let config = if let Ok(mut config_file) = archive.get("abel.json").await {
-        let mut config_bytes = vec![0; config_file.metadata().size as _];
-        config_file.read_to_end(&mut config_bytes).await?;
-        serde_json::from_slice(&config_bytes)?
-      } else {
-        Default::default()
-      };
-
-      let source = Source::new(AsarSource(archive));
+      let archive_kind = ArchiveKind::detect(&temp_path).await?;
+      let source = (archive_kind)
+        .open(&temp_path, state.verify_asar_integrity)
+        .await?;
+      let config = read_config::<Error>(&source).await?;
       (source, config)
     }
   };
@@ -169,22 +277,76 @@ async fn read_store_service_temp(
   Ok((temp_path, source, config))
 }
 
+/// What is stored for a service to be loaded again after restarting.
+pub(super) enum StoredSource<'a> {
+  /// Uploaded source, at its temporary path.
+  File(SourceKind, &'a Path),
+  /// URI the source is opened from.
+  Uri(UriSource),
+}
+
+pub(super) struct UriSource {
+  pub uri: String,
+  pub source_hash: Option<String>,
+  pub git: Option<GitInfo>,
+}
+
+/// Rejects `name` or any alias in `config` that is taken by the management
+/// API's unversioned paths.
+pub(super) fn check_reserved(name: &str, config: &Config) -> Result<()> {
+  let names = iter::once(name).chain(config.aliases.iter().map(String::as_str));
+  match names
+    .map(normalize_name)
+    .find(|x| UNVERSIONED_PATHS.contains(&&**x))
+  {
+    Some(reserved) => Err(From::from((
+      400,
+      "reserved service name",
+      json!({ "name": reserved }),
+    ))),
+    None => Ok(()),
+  }
+}
+
 async fn create_service<'a>(
   state: &'a ServerState,
   mode: UploadMode,
   name: String,
+  uuid: Option<Uuid>,
   config: Config,
   source: Source,
-  source_kind: SourceKind,
-  temp_path: &Path,
+  stored: StoredSource<'_>,
 ) -> Result<UploadResponse<'a>> {
+  check_reserved(&name, &config)?;
+  let derived_uuid = match uuid {
+    Some(_) => None,
+    None => derive_uuid(state, &name, &stored).await?,
+  };
+  if let (Some(uuid), UploadMode::Hot | UploadMode::Cold) = (derived_uuid, mode) {
+    if let Ok(service) = state.abel.get_running_service(&name) {
+      if service.upgrade().uuid() == uuid {
+        if let StoredSource::File(_, temp_path) = stored {
+          fs::remove_file(temp_path).await?;
+        }
+        return Ok(UploadResponse {
+          new_service: Service::Running(service),
+          replaced_service: None,
+          errors: Default::default(),
+          unchanged: true,
+          diff: None,
+        });
+      }
+    }
+  }
+  let uuid = uuid.or(derived_uuid);
+
   let (new_service, replaced_service, errors) = match mode {
     UploadMode::Create if state.abel.get_service(&name).is_ok() => {
       return Err(ServiceExists { name: name.into() }.into())
     }
     UploadMode::Hot if state.abel.get_running_service(&name).is_ok() => {
       let (service, replaced) = (state.abel)
-        .hot_update_service(name, None, source, config)
+        .hot_update_service(name, uuid, source, config)
         .await?;
       (
         Service::Running(service),
@@ -194,12 +356,12 @@ async fn create_service<'a>(
     }
     UploadMode::Hot | UploadMode::Cold | UploadMode::Create => {
       (state.abel)
-        .cold_update_or_create_service(name, None, source, config)
+        .cold_update_or_create_service(name, uuid, source, config)
         .await?
     }
     UploadMode::Load => {
       let (service, replaced, error_payload) = (state.abel)
-        .load_service(name, None, source, config)
+        .load_service(name, uuid, source, config)
         .await?;
       (Service::Stopped(service), replaced, error_payload)
     }
@@ -207,27 +369,155 @@ async fn create_service<'a>(
   let guard = new_service.upgrade();
 
   let service_path = state.abel_path.join("services").join(guard.name());
-  if service_path.exists() {
-    fs::remove_dir_all(&service_path).await?;
+  let old_stats = if replaced_service.is_some() {
+    let stats = stored_source_stats(&service_path).await;
+    versions::archive(&service_path, guard.uuid(), state.kept_versions).await?;
+    stats
+  } else {
+    None
+  };
+  store_source(&service_path, guard.uuid(), stored).await?;
+  let diff = match &replaced_service {
+    Some(replaced) => {
+      let stats = old_stats.zip(stored_source_stats(&service_path).await);
+      Some(ServiceDiff::between(replaced.info(), guard.info(), stats))
+    }
+    None => None,
+  };
+
+  Ok(UploadResponse {
+    new_service,
+    replaced_service,
+    errors,
+    unchanged: false,
+    diff,
+  })
+}
+
+/// Number and total size of files in the source stored in the folder at
+/// `path`, or `None` if it is opened from a URI or cannot be read.
+pub(super) async fn stored_source_stats(path: &Path) -> Option<(u64, u64)> {
+  let stats = async {
+    let single = path.join("source.lua");
+    if single.exists() {
+      return Ok(Some((1, fs::metadata(single).await?.len())));
+    }
+    for kind in [ArchiveKind::Asar, ArchiveKind::Zip] {
+      let archive = path.join(kind.file_name());
+      if archive.exists() {
+        return kind.file_stats(&archive).await.map(Some);
+      }
+    }
+    Ok::<_, io::Error>(None)
+  };
+  stats.await.unwrap_or_else(|error| {
+    warn!(
+      "failed to read stored source in {}: {error}",
+      path.display()
+    );
+    None
+  })
+}
+
+/// Stores an uploaded source and its metadata in the folder at `path`,
+/// removing everything else in it but previous versions and git checkouts.
+pub(super) async fn store_source(path: &Path, uuid: Uuid, stored: StoredSource<'_>) -> Result<()> {
+  if path.exists() {
+    let mut entries = fs::read_dir(path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      if entry.file_type().await?.is_dir() {
+        if entry.file_name() != "versions" && entry.file_name() != "checkouts" {
+          fs::remove_dir_all(entry.path()).await?;
+        }
+      } else {
+        fs::remove_file(entry.path()).await?;
+      }
+    }
+  } else {
+    fs::create_dir(path).await?;
   }
-  fs::create_dir(&service_path).await?;
 
+  let (source_kind, temp_path) = match stored {
+    StoredSource::File(kind, temp_path) => (kind, temp_path),
+    StoredSource::Uri(UriSource {
+      uri,
+      source_hash,
+      git,
+    }) => {
+      let metadata = Metadata {
+        uuid,
+        started: true,
+        source_hash,
+        source: Some(uri),
+        git,
+      };
+      metadata.write(&path.join("metadata.json")).await?;
+      return Ok(());
+    }
+  };
   let metadata = Metadata {
-    uuid: guard.uuid(),
+    uuid,
     started: true,
+    source_hash: Some(hash_source(temp_path, source_kind).await?),
+    source: None,
+    git: None,
   };
-  metadata.write(&service_path.join("metadata.json")).await?;
+  metadata.write(&path.join("metadata.json")).await?;
 
   match source_kind {
-    SourceKind::Single => fs::rename(temp_path, service_path.join("source.lua")).await?,
-    SourceKind::Multi => fs::hard_link(temp_path, service_path.join("source.asar")).await?,
+    SourceKind::Single => fs::rename(temp_path, path.join("source.lua")).await?,
+    SourceKind::Multi => {
+      let file_name = ArchiveKind::detect(temp_path).await?.file_name();
+      fs::hard_link(temp_path, path.join(file_name)).await?
+    }
   }
+  Ok(())
+}
 
-  Ok(UploadResponse {
-    new_service,
-    replaced_service,
-    errors,
-  })
+/// UUID of service `name` derived from its source if `deterministic_uuids`
+/// is on, so that deploying the same source anywhere gives the same UUID.
+/// `abel.json` is part of the source, and so is covered too.
+///
+/// Sources opened from URIs are only identified by their git tree, if any;
+/// others get random UUIDs.
+async fn derive_uuid(
+  state: &ServerState,
+  name: &str,
+  stored: &StoredSource<'_>,
+) -> Result<Option<Uuid>> {
+  if !state.deterministic_uuids {
+    return Ok(None);
+  }
+  let source_hash = match stored {
+    StoredSource::File(kind, temp_path) => hash_source(temp_path, *kind).await?,
+    StoredSource::Uri(UriSource {
+      source_hash: Some(hash),
+      ..
+    }) => hash.clone(),
+    StoredSource::Uri(_) => return Ok(None),
+  };
+  let digest = Sha256::new()
+    .chain_update(name.as_bytes())
+    .chain_update([0])
+    .chain_update(source_hash.as_bytes())
+    .finalize();
+  let mut bytes = [0; 16];
+  bytes.copy_from_slice(&digest[..16]);
+  // Marked as name-based, like version 5 UUIDs, which are SHA-1 hashes
+  let uuid = uuid::Builder::from_bytes(bytes)
+    .set_variant(uuid::Variant::RFC4122)
+    .set_version(uuid::Version::Sha1)
+    .build();
+  Ok(Some(uuid))
+}
+
+/// SHA-256 of a stored source, as compared by `abel deploy`. Asar archives
+/// are hashed by their contents; other sources as is.
+async fn hash_source(path: &Path, kind: SourceKind) -> io::Result<String> {
+  if kind == SourceKind::Multi && ArchiveKind::detect(path).await? == ArchiveKind::Asar {
+    return hash_asar(File::open(path).await?).await;
+  }
+  hash_reader(File::open(path).await?).await
 }
 
 pub fn log_result(
@@ -235,10 +525,18 @@ pub fn log_result(
     new_service,
     replaced_service,
     errors,
+    unchanged,
+    diff,
   }: &UploadResponse,
 ) {
   let service = new_service.upgrade();
-  if let Some(replaced) = replaced_service {
+  if *unchanged {
+    info!(
+      "Service '{}' is unchanged {}",
+      service.name(),
+      format!("({})", service.uuid()).dimmed(),
+    );
+  } else if let Some(replaced) = replaced_service {
     info!(
       "Updated service '{}' {}",
       service.name(),
@@ -251,17 +549,26 @@ pub fn log_result(
       format!("({})", service.uuid()).dimmed(),
     );
   }
+  if let Some(diff) = diff {
+    info!(
+      target: "abel::audit",
+      "Service '{}' updated: {diff}",
+      service.name()
+    );
+  }
   if !errors.is_empty() {
     warn!("errors: {errors:?}");
   }
 }
 
-async fn response(resp: UploadResponse<'_>) -> Result<Response<Body>> {
+pub(super) async fn response(resp: UploadResponse<'_>) -> Result<Response<Body>> {
   log_result(&resp);
   let UploadResponse {
     new_service,
     replaced_service,
     errors,
+    unchanged,
+    diff,
   } = resp;
 
   let guard = new_service.upgrade();
@@ -269,6 +576,32 @@ async fn response(resp: UploadResponse<'_>) -> Result<Response<Body>> {
     new_service: ServiceWithStatus::from_guard(&guard),
     replaced_service: replaced_service.as_ref().map(|x| Cow::Borrowed(x.info())),
     errors: errors.into(),
+    unchanged,
+    diff,
   };
   json_response(StatusCode::OK, body)
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_check_reserved() {
+    let config = |aliases: &[&str]| Config {
+      aliases: aliases.iter().map(|x| x.to_string()).collect(),
+      ..Default::default()
+    };
+    assert!(check_reserved("foo", &config(&["bar"])).is_ok());
+    // Only reachable under `/api/v1`, so they do not shadow services
+    assert!(check_reserved("metrics", &config(&["auth"])).is_ok());
+    for (name, aliases) in [
+      ("services", &[][..]),
+      ("foo", &["Services"]),
+      ("foo", &["bar", "services"]),
+    ] {
+      let error = check_reserved(name, &config(aliases)).unwrap_err();
+      assert_eq!(error.kind().status(), 400);
+    }
+  }
+}
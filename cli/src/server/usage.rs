@@ -0,0 +1,246 @@
+//! Daily per-service usage reports for chargeback.
+//!
+//! Counters from [`Abel::metrics`](abel_core::Abel::metrics) are added up
+//! into `<abel_path>/usage/<date>.json` (and an equivalent `.csv`) every
+//! minute, so a day's report includes requests up to a minute after UTC
+//! midnight. Storage is the size of the service's local storage at the time
+//! of the last update.
+
+use super::atomic::write_atomic;
+use super::{Result, ServerState};
+use chrono::{NaiveDate, Utc};
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::fs;
+use tokio::sync::Mutex;
+
+const DATE_FORMAT: &str = "%Y-%m-%d";
+
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct ServiceUsage {
+  pub service: String,
+  pub requests: u64,
+  pub cpu_seconds: f64,
+  pub egress_bytes: u64,
+  pub storage_bytes: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct UsageReport {
+  pub date: String,
+  pub services: Vec<ServiceUsage>,
+}
+
+impl UsageReport {
+  pub fn to_csv(&self) -> String {
+    let mut s = "service,requests,cpu_seconds,egress_bytes,storage_bytes\n".to_string();
+    for x in &self.services {
+      writeln!(
+        s,
+        "{},{},{},{},{}",
+        x.service, x.requests, x.cpu_seconds, x.egress_bytes, x.storage_bytes
+      )
+      .unwrap();
+    }
+    s
+  }
+}
+
+/// Counter values at the last update, which the next one is counted from.
+#[derive(Default)]
+struct Counted {
+  requests: u64,
+  cpu_seconds: f64,
+  egress_bytes: u64,
+}
+
+pub struct UsageTracker {
+  path: PathBuf,
+  counted: Mutex<HashMap<String, Counted>>,
+}
+
+impl UsageTracker {
+  pub fn new(abel_path: &Path) -> Self {
+    Self {
+      path: abel_path.join("usage"),
+      counted: Default::default(),
+    }
+  }
+
+  fn report_path(&self, date: &str, ext: &str) -> PathBuf {
+    self.path.join(format!("{date}.{ext}"))
+  }
+
+  /// Reads the report of `date`, or today's if `None`.
+  pub async fn read(&self, date: Option<&str>) -> Result<UsageReport> {
+    let date = match date {
+      Some(date) => {
+        let date = NaiveDate::parse_from_str(date, DATE_FORMAT).map_err(|_| "invalid date")?;
+        date.format(DATE_FORMAT).to_string()
+      }
+      None => Utc::now().format(DATE_FORMAT).to_string(),
+    };
+    match fs::read(self.report_path(&date, "json")).await {
+      Ok(bytes) => Ok(serde_json::from_slice(&bytes)?),
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Ok(UsageReport {
+        date,
+        services: Vec::new(),
+      }),
+      Err(error) => Err(error.into()),
+    }
+  }
+
+  /// Adds usage since the last update to today's report.
+  pub async fn update(&self, state: &ServerState) -> Result<()> {
+    let mut counted = self.counted.lock().await;
+    let date = Utc::now().format(DATE_FORMAT).to_string();
+    let mut report = self.read(Some(&date)).await?;
+
+    for x in state.abel.metrics().services {
+      let last = counted.entry(x.name.clone()).or_default();
+      // Counters restart from zero when a service is removed and re-added
+      let since = |now, last| if now >= last { now - last } else { now };
+      let usage = match report.services.iter().position(|u| u.service == x.name) {
+        Some(i) => &mut report.services[i],
+        None => {
+          report.services.push(ServiceUsage {
+            service: x.name.clone(),
+            ..Default::default()
+          });
+          report.services.last_mut().unwrap()
+        }
+      };
+      usage.requests += since(x.requests, last.requests);
+      usage.egress_bytes += since(x.egress_bytes, last.egress_bytes);
+      usage.cpu_seconds += if x.cpu_time >= last.cpu_seconds {
+        x.cpu_time - last.cpu_seconds
+      } else {
+        x.cpu_time
+      };
+      *last = Counted {
+        requests: x.requests,
+        cpu_seconds: x.cpu_time,
+        egress_bytes: x.egress_bytes,
+      };
+    }
+
+    let names = (state.abel.list_services())
+      .map(|x| x.upgrade().name().to_string())
+      .collect::<Vec<_>>();
+    for name in names {
+      let storage_bytes = dir_size(&state.abel_path.join("storage").join(&name)).await?;
+      match report.services.iter_mut().find(|u| u.service == name) {
+        Some(usage) => usage.storage_bytes = storage_bytes,
+        None => report.services.push(ServiceUsage {
+          service: name,
+          storage_bytes,
+          ..Default::default()
+        }),
+      }
+    }
+    report.services.sort_by(|a, b| a.service.cmp(&b.service));
+
+    fs::create_dir_all(&self.path).await?;
+    let json = serde_json::to_vec_pretty(&report)?;
+    write_atomic(self.report_path(&date, "json"), json).await?;
+    write_atomic(self.report_path(&date, "csv"), report.to_csv()).await?;
+    Ok(())
+  }
+}
+
+/// Updates usage reports every minute.
+pub async fn run_updater(state: Arc<ServerState>) {
+  let mut interval = tokio::time::interval(Duration::from_secs(60));
+  loop {
+    interval.tick().await;
+    if let Err(error) = state.usage.update(&state).await {
+      warn!("failed to update usage report: {error}");
+    }
+  }
+}
+
+async fn dir_size(path: &Path) -> io::Result<u64> {
+  let mut size = 0;
+  let mut stack = vec![path.to_path_buf()];
+  while let Some(path) = stack.pop() {
+    let mut entries = match fs::read_dir(&path).await {
+      Ok(x) => x,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => continue,
+      Err(error) => return Err(error),
+    };
+    while let Some(entry) = entries.next_entry().await? {
+      let metadata = entry.metadata().await?;
+      if metadata.is_dir() {
+        stack.push(entry.path());
+      } else {
+        size += metadata.len();
+      }
+    }
+  }
+  Ok(size)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::Config;
+  use crate::server::handle;
+  use crate::server::tests::state;
+  use crate::source::SingleSource;
+  use abel_core::source::Source;
+  use hyper::{Body, Request};
+  use tempfile::TempDir;
+
+  #[tokio::test]
+  async fn test_usage_report() {
+    let dir = TempDir::new().unwrap();
+    let state = state(dir.path(), Config::default()).await;
+    let source = Source::new(SingleSource::new(r#"abel.listen("/", function() end)"#));
+    (state.abel)
+      .cold_update_or_create_service("a", None, source, Default::default())
+      .await
+      .unwrap();
+    let storage_path = dir.path().join("storage/a/x");
+    fs::create_dir_all(&storage_path).await.unwrap();
+    fs::write(storage_path.join("y"), [0; 100]).await.unwrap();
+
+    let request = |n| {
+      let state = state.clone();
+      async move {
+        for _ in 0..n {
+          let req = Request::get("/a").body(Body::empty()).unwrap();
+          assert!(handle(state.clone(), req)
+            .await
+            .unwrap()
+            .status()
+            .is_success());
+        }
+        state.usage.update(&state).await.unwrap();
+        state.usage.read(None).await.unwrap()
+      }
+    };
+    let report = request(2).await;
+    assert_eq!(report.services.len(), 1);
+    assert_eq!(report.services[0].service, "a");
+    assert_eq!(report.services[0].requests, 2);
+    assert_eq!(report.services[0].storage_bytes, 100);
+
+    // Only requests since the last update are added
+    let report = request(1).await;
+    assert_eq!(report.services[0].requests, 3);
+    let csv_path = state.usage.report_path(&report.date, "csv");
+    let csv = fs::read_to_string(csv_path).await.unwrap();
+    let row = format!("a,3,{},0,100", report.services[0].cpu_seconds);
+    assert_eq!(csv.lines().nth(1), Some(&*row));
+
+    let report = state.usage.read(Some("2000-01-01")).await.unwrap();
+    assert!(report.services.is_empty());
+    assert!(state.usage.read(Some("yesterday")).await.is_err());
+  }
+}
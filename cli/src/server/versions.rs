@@ -0,0 +1,248 @@
+//! Previously deployed sources of services, kept for rolling back.
+//!
+//! When a service is updated, its stored source and `metadata.json` are moved
+//! to `versions/<uuid>` in its folder, and only the newest `kept_versions`
+//! (from `config.json`) of them are kept.
+
+use super::metadata::Metadata;
+use super::upload::{response, upload_local, upload_uri, UploadMode, UriSource};
+use super::{Result, ServerState};
+use crate::source::ArchiveKind;
+use crate::SourceKind;
+use abel_core::ErrorKind::ServiceStopped;
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+use tokio::fs::{self, File};
+use tokio::io;
+use tokio_util::io::ReaderStream;
+use uuid::Uuid;
+
+#[derive(Debug, Serialize)]
+pub struct VersionInfo {
+  pub uuid: Uuid,
+  /// Unix time the version was replaced.
+  pub replaced_at: u64,
+}
+
+fn versions_path(state: &ServerState, name: &str) -> PathBuf {
+  state.abel_path.join("services").join(name).join("versions")
+}
+
+/// Links the source currently stored in `service_path` into its `versions`
+/// folder before it is replaced by version `new`, removing the oldest
+/// versions beyond `keep`. The originals are left for the caller to replace,
+/// so the service folder stays complete until then.
+pub async fn archive(service_path: &Path, new: Uuid, keep: usize) -> io::Result<()> {
+  let metadata_path = service_path.join("metadata.json");
+  if !metadata_path.exists() {
+    return Ok(());
+  }
+  let versions_path = service_path.join("versions");
+  // Rolled back to, so it is the current version again
+  let new_path = versions_path.join(new.to_string());
+  if new_path.exists() {
+    fs::remove_dir_all(&new_path).await?;
+  }
+  if keep == 0 {
+    return Ok(());
+  }
+
+  let uuid = Metadata::read(&metadata_path).await?.uuid;
+  let version_path = versions_path.join(uuid.to_string());
+  if version_path.exists() {
+    fs::remove_dir_all(&version_path).await?;
+  }
+  fs::create_dir_all(&version_path).await?;
+
+  let file_names = [
+    "metadata.json",
+    "source.lua",
+    ArchiveKind::Asar.file_name(),
+    ArchiveKind::Zip.file_name(),
+  ];
+  for file_name in file_names {
+    let path = service_path.join(file_name);
+    if path.exists() {
+      let version_file = version_path.join(file_name);
+      if fs::hard_link(&path, &version_file).await.is_err() {
+        fs::copy(path, version_file).await?;
+      }
+    }
+  }
+
+  let mut versions = read_versions(&versions_path).await?;
+  for (path, _) in versions.drain(keep.min(versions.len())..) {
+    fs::remove_dir_all(path).await?;
+  }
+  Ok(())
+}
+
+/// Versions in `versions_path`, newest first.
+async fn read_versions(versions_path: &Path) -> io::Result<Vec<(PathBuf, VersionInfo)>> {
+  let mut versions = Vec::new();
+  if !versions_path.exists() {
+    return Ok(Vec::new());
+  }
+  let mut entries = fs::read_dir(versions_path).await?;
+  while let Some(entry) = entries.next_entry().await? {
+    let uuid = match entry.file_name().to_str().and_then(|x| x.parse().ok()) {
+      Some(x) => x,
+      None => continue,
+    };
+    let modified = entry.metadata().await?.modified()?;
+    let replaced_at = modified.duration_since(UNIX_EPOCH).unwrap_or_default();
+    versions.push((entry.path(), uuid, replaced_at));
+  }
+  versions.sort_by(|(_, _, a), (_, _, b)| b.cmp(a));
+  let versions = (versions.into_iter())
+    .map(|(path, uuid, replaced_at)| {
+      let info = VersionInfo {
+        uuid,
+        replaced_at: replaced_at.as_secs(),
+      };
+      (path, info)
+    })
+    .collect();
+  Ok(versions)
+}
+
+pub async fn list(state: &ServerState, name: &str) -> Result<Vec<VersionInfo>> {
+  state.abel.get_service(name)?;
+  let versions = read_versions(&versions_path(state, name)).await?;
+  Ok(versions.into_iter().map(|(_, x)| x).collect())
+}
+
+/// Cold-updates service `name` back to version `to`, or the latest replaced
+/// one if not given.
+pub async fn rollback(
+  state: &ServerState,
+  name: &str,
+  query: &str,
+) -> Result<hyper::Response<hyper::Body>> {
+  #[derive(Deserialize)]
+  struct Query {
+    to: Option<Uuid>,
+  }
+
+  let Query { to } = serde_qs::from_str(query)?;
+  if state.abel.get_service(name)?.is_stopped() {
+    return Err(ServiceStopped { name: name.into() }.into());
+  }
+  let versions = read_versions(&versions_path(state, name)).await?;
+  let (version_path, uuid) = match to {
+    Some(to) => versions.into_iter().find(|(_, x)| x.uuid == to),
+    None => versions.into_iter().next(),
+  }
+  .map(|(path, x)| (path, x.uuid))
+  .ok_or_else(|| {
+    (
+      404,
+      "version not found",
+      json!({ "name": name, "uuid": to }),
+    )
+  })?;
+
+  let metadata = Metadata::read(&version_path.join("metadata.json")).await?;
+  if let Some(uri) = metadata.source {
+    let stored = UriSource {
+      uri,
+      source_hash: metadata.source_hash,
+      git: metadata.git,
+    };
+    let resp = upload_uri(state, name.into(), UploadMode::Cold, Some(uuid), stored).await?;
+    info!("Rolled back service '{name}' to version {uuid}");
+    return response(resp).await;
+  }
+
+  let (kind, source_path) = if version_path.join("source.lua").exists() {
+    (SourceKind::Single, version_path.join("source.lua"))
+  } else {
+    let path = [ArchiveKind::Asar, ArchiveKind::Zip]
+      .into_iter()
+      .map(|x| version_path.join(x.file_name()))
+      .find(|x| x.exists())
+      .ok_or_else(|| (500, "version source not found", json!({ "uuid": uuid })))?;
+    (SourceKind::Multi, path)
+  };
+
+  let stream = ReaderStream::new(File::open(&source_path).await?);
+  let resp = upload_local(
+    state,
+    name.into(),
+    UploadMode::Cold,
+    kind,
+    Some(uuid),
+    stream,
+  )
+  .await?;
+
+  info!("Rolled back service '{name}' to version {uuid}");
+  response(resp).await
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::server::config::Config;
+  use crate::server::tests::state;
+  use bytes::Bytes;
+  use std::time::Duration;
+  use tempfile::TempDir;
+
+  async fn upload(state: &ServerState, version: usize) -> Uuid {
+    // Versions are ordered by modification time
+    tokio::time::sleep(Duration::from_millis(20)).await;
+    let code = format!(r#"abel.listen("/", function() return {version} end)"#);
+    let stream = futures::stream::once(async { io::Result::Ok(Bytes::from(code)) });
+    let resp = upload_local(
+      state,
+      "a".into(),
+      UploadMode::Cold,
+      SourceKind::Single,
+      None,
+      Box::pin(stream),
+    )
+    .await
+    .unwrap();
+    resp.new_service.upgrade().uuid()
+  }
+
+  async fn uuids(state: &ServerState) -> Vec<Uuid> {
+    let versions = list(state, "a").await.unwrap();
+    versions.into_iter().map(|x| x.uuid).collect()
+  }
+
+  #[tokio::test]
+  async fn test_rollback() {
+    let dir = TempDir::new().unwrap();
+    let config = Config {
+      kept_versions: Some(2),
+      ..Default::default()
+    };
+    let state = state(dir.path(), config).await;
+    let mut uploaded = Vec::new();
+    for version in 0..4 {
+      uploaded.push(upload(&state, version).await);
+    }
+    assert_eq!(uuids(&state).await, [uploaded[2], uploaded[1]]);
+
+    let current = || state.abel.get_service("a").unwrap().upgrade().uuid();
+    rollback(&state, "a", "").await.unwrap();
+    assert_eq!(current(), uploaded[2]);
+    assert_eq!(uuids(&state).await, [uploaded[3], uploaded[1]]);
+
+    let query = format!("to={}", uploaded[1]);
+    rollback(&state, "a", &query).await.unwrap();
+    assert_eq!(current(), uploaded[1]);
+    assert_eq!(uuids(&state).await, [uploaded[2], uploaded[3]]);
+
+    let query = format!("to={}", uploaded[0]);
+    let error = rollback(&state, "a", &query).await.unwrap_err();
+    assert_eq!(error.into_status_and_body().0, 404);
+    state.abel.stop_service("a").await.unwrap();
+    assert!(rollback(&state, "a", "").await.is_err());
+  }
+}
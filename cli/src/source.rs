@@ -1,108 +1,330 @@
-use abel_core::normalize_path_str;
-use abel_core::source::{Metadata, SourceVfs};
+pub use abel_core::source::{DirSource, SingleSource, ZipSource};
+
+use abel_core::source::{Metadata, Source, SourceRegistry, SourceVfs};
+use abel_core::Config;
 use async_trait::async_trait;
-use hive_asar::header::Entry;
-use hive_asar::{Archive, DuplicableFile};
-use std::io::Cursor;
-use std::path::PathBuf;
+use data_encoding::HEXLOWER;
+use futures::FutureExt;
+use hive_asar::header::{Directory, Entry};
+use hive_asar::{check_asar_format, Archive, DuplicableFile};
+use sha2::{Digest, Sha256};
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io;
+use tokio::fs::{self, File};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
 
-pub struct AsarSource(pub(crate) Archive<DuplicableFile>);
+/// Archive formats accepted as multi-file sources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveKind {
+  Asar,
+  Zip,
+}
 
-#[async_trait]
-impl SourceVfs for AsarSource {
-  type File = hive_asar::File<DuplicableFile>;
+impl ArchiveKind {
+  /// Detects the format of the archive at `path` by its magic bytes,
+  /// defaulting to asar.
+  pub async fn detect(path: &Path) -> io::Result<Self> {
+    let mut magic = [0; 4];
+    let mut file = File::open(path).await?;
+    match file.read_exact(&mut magic).await {
+      Ok(_) if &magic == b"PK\x03\x04" => Ok(Self::Zip),
+      Ok(_) => Ok(Self::Asar),
+      Err(error) if error.kind() == io::ErrorKind::UnexpectedEof => Ok(Self::Asar),
+      Err(error) => Err(error),
+    }
+  }
 
-  async fn get(&self, path: &str) -> io::Result<Self::File> {
-    self.0.get_owned(path).await
+  /// File name of the stored source in a service's folder.
+  pub fn file_name(self) -> &'static str {
+    match self {
+      Self::Asar => "source.asar",
+      Self::Zip => "source.zip",
+    }
   }
 
-  async fn exists(&self, path: &str) -> io::Result<bool> {
-    Ok(self.0.get_entry(path).is_some())
+  /// Number and total size of files in the archive at `path`.
+  pub async fn file_stats(self, path: &Path) -> io::Result<(u64, u64)> {
+    fn walk(dir: &Directory, stats: &mut (u64, u64)) {
+      for entry in dir.files.values() {
+        match entry {
+          Entry::File(metadata) => *stats = (stats.0 + 1, stats.1 + metadata.size),
+          Entry::Directory(dir) => walk(dir, stats),
+        }
+      }
+    }
+    match self {
+      Self::Asar => {
+        let mut stats = (0, 0);
+        walk(
+          &read_asar_header(&mut File::open(path).await?).await?,
+          &mut stats,
+        );
+        Ok(stats)
+      }
+      Self::Zip => {
+        let zip = ZipSource::open(path).await?;
+        Ok(
+          zip
+            .files()
+            .fold((0, 0), |(n, size), (_, x)| (n + 1, size + x)),
+        )
+      }
+    }
   }
 
-  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
-    let entry = (self.0)
-      .get_entry(path)
-      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file or directory"))?;
-    match entry {
-      Entry::Directory(_) => Ok(Metadata::Dir),
-      Entry::File(m) => Ok(Metadata::File { size: m.size }),
+  pub async fn open(self, path: &Path, verify_asar_integrity: bool) -> io::Result<Source> {
+    match self {
+      Self::Asar => {
+        let archive = Archive::new_from_file(path).await?;
+        let source = AsarSource::new(archive).verify_integrity(verify_asar_integrity);
+        Ok(Source::new(source))
+      }
+      Self::Zip => Ok(Source::new(ZipSource::open(path).await?)),
     }
   }
 }
 
-pub struct SingleSource(Arc<[u8]>);
+/// Source backends the server opens `source` URIs in services' metadata
+/// with. Only `file://` (a directory on the server) is built in.
+///
+/// These URIs are written by the server itself, e.g. for git checkouts, and
+/// never taken from requests, as `file://` reaches any directory.
+pub fn builtin_sources() -> SourceRegistry {
+  let mut registry = SourceRegistry::default();
+  registry.register(
+    "file",
+    Arc::new(|uri| {
+      let path = PathBuf::from(uri.trim_start_matches("file://"));
+      async move {
+        if !path.is_absolute() || !fs::metadata(&path).await?.is_dir() {
+          let msg = format!("'{}' is not an absolute directory path", path.display());
+          return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        Ok(Source::new(DirSource::new(path)))
+      }
+      .boxed()
+    }),
+  );
+  registry
+}
 
-impl SingleSource {
-  pub fn new(src: impl AsRef<[u8]>) -> Self {
-    Self(Arc::from(src.as_ref()))
+/// Reads `abel.json` at the root of `source`, or the default config if it
+/// does not exist.
+pub async fn read_config<E>(source: &Source) -> Result<Config, E>
+where
+  E: From<io::Error> + From<serde_json::Error>,
+{
+  if !source.exists("abel.json").await? {
+    return Ok(Default::default());
   }
+  let mut config_bytes = Vec::new();
+  source
+    .get("abel.json")
+    .await?
+    .read_to_end(&mut config_bytes)
+    .await?;
+  Ok(serde_json::from_slice(&config_bytes)?)
 }
 
-#[async_trait]
-impl SourceVfs for SingleSource {
-  type File = Cursor<Arc<[u8]>>;
+/// Largest asar header read, which lists every file in the archive.
+const MAX_ASAR_HEADER: u32 = 16 * 1024 * 1024;
 
-  async fn get(&self, path: &str) -> io::Result<Self::File> {
-    match &*normalize_path_str(path) {
-      "main.lua" => Ok(Cursor::new(self.0.clone())),
-      "" => Err(io::Error::from_raw_os_error(libc::EISDIR)),
-      _ => Err(io::Error::new(
-        io::ErrorKind::NotFound,
-        "No such file or directory",
-      )),
+/// Root directory of an asar archive, which `Archive` does not expose,
+/// leaving `reader` at the start of the archive.
+pub async fn read_asar_header<R>(reader: &mut R) -> io::Result<Directory>
+where
+  R: AsyncRead + AsyncSeek + Unpin,
+{
+  let header_len = (check_asar_format(reader).await?)
+    .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an asar archive"))?;
+  if header_len > MAX_ASAR_HEADER {
+    let msg = format!("asar header larger than {MAX_ASAR_HEADER} bytes");
+    return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+  }
+  let mut header = vec![0; header_len as usize];
+  reader.read_exact(&mut header).await?;
+  reader.seek(SeekFrom::Start(0)).await?;
+  Ok(serde_json::from_slice(&header)?)
+}
+
+/// SHA-256 of an asar archive's files, in hex.
+///
+/// Files are hashed in order of their paths, so the result does not depend on
+/// how they are laid out in the archive, which varies between packings.
+pub async fn hash_asar<R>(mut reader: R) -> io::Result<String>
+where
+  R: AsyncRead + AsyncSeek + Unpin,
+{
+  fn collect_files(dir: &Directory, prefix: &str, files: &mut Vec<(String, u64)>) {
+    for (name, entry) in &dir.files {
+      let path = if prefix.is_empty() {
+        name.to_string()
+      } else {
+        format!("{prefix}/{name}")
+      };
+      match entry {
+        Entry::File(metadata) => files.push((path, metadata.size)),
+        Entry::Directory(dir) => collect_files(dir, &path, files),
+      }
     }
   }
 
-  async fn exists(&self, path: &str) -> io::Result<bool> {
-    match &*normalize_path_str(path) {
-      "main.lua" | "" => Ok(true),
-      _ => Ok(false),
+  let mut files = Vec::new();
+  collect_files(&read_asar_header(&mut reader).await?, "", &mut files);
+  files.sort();
+
+  let mut archive = Archive::new(reader).await?;
+  let mut hasher = Sha256::new();
+  for (path, size) in files {
+    hasher.update(path.as_bytes());
+    hasher.update([0]);
+    hasher.update(size.to_be_bytes());
+    if update_hasher(&mut hasher, archive.get(&path).await?).await? != size {
+      let msg = format!("'{path}' is not as large as the asar header says");
+      return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
     }
   }
+  Ok(HEXLOWER.encode(&hasher.finalize()))
+}
 
-  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
-    match &*normalize_path_str(path) {
-      "main.lua" => Ok(Metadata::File {
-        size: self.0.len() as _,
-      }),
-      "" => Ok(Metadata::Dir),
-      _ => Err(io::Error::new(
-        io::ErrorKind::NotFound,
-        "No such file or directory",
-      )),
+/// SHA-256 of everything `reader` reads, in hex.
+pub async fn hash_reader(reader: impl AsyncRead + Unpin) -> io::Result<String> {
+  let mut hasher = Sha256::new();
+  update_hasher(&mut hasher, reader).await?;
+  Ok(HEXLOWER.encode(&hasher.finalize()))
+}
+
+/// Feeds everything `reader` reads to `hasher`, returning how many bytes
+/// that was.
+async fn update_hasher(hasher: &mut Sha256, mut reader: impl AsyncRead + Unpin) -> io::Result<u64> {
+  let mut buf = vec![0; 64 * 1024];
+  let mut total = 0;
+  loop {
+    let len = reader.read(&mut buf).await?;
+    if len == 0 {
+      return Ok(total);
     }
+    hasher.update(&buf[..len]);
+    total += len as u64;
   }
 }
 
-pub struct DirSource(pub(crate) PathBuf);
+pub struct AsarSource {
+  archive: Archive<DuplicableFile>,
+  verify_integrity: bool,
+}
+
+impl AsarSource {
+  pub fn new(archive: Archive<DuplicableFile>) -> Self {
+    Self {
+      archive,
+      verify_integrity: false,
+    }
+  }
+
+  /// Checks files against their `integrity` blocks when they are read, and
+  /// fails with `InvalidData` on mismatch.
+  ///
+  /// Files without integrity information are read as-is.
+  pub fn verify_integrity(mut self, verify: bool) -> Self {
+    self.verify_integrity = verify;
+    self
+  }
+}
 
 #[async_trait]
-impl SourceVfs for DirSource {
-  type File = tokio::fs::File;
+impl SourceVfs for AsarSource {
+  type File = hive_asar::File<DuplicableFile>;
 
   async fn get(&self, path: &str) -> io::Result<Self::File> {
-    tokio::fs::File::open(self.0.join(normalize_path_str(path))).await
+    let mut file = self.archive.get_owned(path).await?;
+    if self.verify_integrity && !file.check_integrity().await? {
+      return Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("integrity check failed for '{path}'"),
+      ));
+    }
+    Ok(file)
   }
 
   async fn exists(&self, path: &str) -> io::Result<bool> {
-    Ok(
-      tokio::fs::metadata(self.0.join(normalize_path_str(path)))
-        .await
-        .is_ok(),
-    )
+    Ok(self.archive.get_entry(path).is_some())
   }
 
   async fn metadata(&self, path: &str) -> io::Result<Metadata> {
-    let metadata = tokio::fs::metadata(self.0.join(normalize_path_str(path))).await?;
-    if metadata.is_file() {
-      Ok(Metadata::File {
-        size: metadata.len(),
-      })
-    } else {
-      Ok(Metadata::Dir)
+    let entry = (self.archive)
+      .get_entry(path)
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file or directory"))?;
+    match entry {
+      Entry::Directory(_) => Ok(Metadata::Dir),
+      Entry::File(m) => Ok(Metadata::File { size: m.size }),
     }
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::Cursor;
+
+  fn asar(files: &[(&str, &[u8])]) -> Vec<u8> {
+    let header = serde_json::json!({
+      "files": (files.iter())
+        .scan(0, |offset, (name, content)| {
+          let entry = serde_json::json!({ "size": content.len(), "offset": offset.to_string() });
+          *offset += content.len();
+          Some((name.to_string(), entry))
+        })
+        .collect::<serde_json::Map<_, _>>(),
+    });
+    let header = serde_json::to_vec(&header).unwrap();
+    pickle(&header, files.iter().flat_map(|x| x.1.iter().copied()))
+  }
+
+  /// Lays out an asar archive: a pickled header size, the pickled header,
+  /// then file contents.
+  fn pickle(header: &[u8], contents: impl IntoIterator<Item = u8>) -> Vec<u8> {
+    let padded = (header.len() + 3) / 4 * 4;
+    let mut result = Vec::new();
+    result.extend(4u32.to_le_bytes());
+    result.extend((padded as u32 + 8).to_le_bytes());
+    result.extend((padded as u32 + 4).to_le_bytes());
+    result.extend((header.len() as u32).to_le_bytes());
+    result.extend(header);
+    result.resize(16 + padded, 0);
+    result.extend(contents);
+    result
+  }
+
+  #[tokio::test]
+  async fn test_hash_asar() {
+    let a = asar(&[("a.lua", b"a"), ("main.lua", b"main")]);
+    let b = asar(&[("main.lua", b"main"), ("a.lua", b"a")]);
+    let c = asar(&[("main.lua", b"main"), ("a.lua", b"b")]);
+    let hash = |x: Vec<u8>| async move { hash_asar(Cursor::new(x)).await.unwrap() };
+    let a = hash(a).await;
+    assert_eq!(a, hash(b).await);
+    assert_ne!(a, hash(c).await);
+  }
+
+  #[tokio::test]
+  async fn test_asar_header_too_large() {
+    let mut archive = pickle(b"{}", []);
+    archive[12..16].copy_from_slice(&(MAX_ASAR_HEADER + 1).to_le_bytes());
+    archive[4..8].copy_from_slice(&(MAX_ASAR_HEADER + 12).to_le_bytes());
+    archive[8..12].copy_from_slice(&(MAX_ASAR_HEADER + 8).to_le_bytes());
+    let error = read_asar_header(&mut Cursor::new(archive))
+      .await
+      .unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+    assert!(error.to_string().contains("larger than"));
+  }
+
+  #[tokio::test]
+  async fn test_hash_reader() {
+    let hash = hash_reader(&b"abc"[..]).await.unwrap();
+    let expected = "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+    assert_eq!(hash, expected);
+  }
+}
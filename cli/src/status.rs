@@ -0,0 +1,81 @@
+use crate::deploy::{auth_header, check_status, server_or_env};
+use crate::server::types::{ServiceStatus, ServiceWithStatus};
+use anyhow::{bail, Context};
+use hyper::Uri;
+use owo_colors::OwoColorize;
+use reqwest::{Client, StatusCode};
+use uuid::Uuid;
+
+/// Prints whether a server is ready and the status of its services, failing
+/// if it is draining or any running service is unhealthy.
+pub async fn status(server: Option<Uri>, auth_token: Option<Uuid>) -> anyhow::Result<()> {
+  let server = server_or_env(server)?.to_string();
+  let server = server.trim_end_matches('/');
+  let client = Client::new();
+
+  let url = format!("{server}/api/v1/readyz");
+  let resp =
+    (client.get(&url).send().await).with_context(|| format!("failed to connect to {url}"))?;
+  let ready = match resp.status() {
+    StatusCode::OK => true,
+    StatusCode::SERVICE_UNAVAILABLE => false,
+    _ => {
+      check_status(resp).await?;
+      bail!("unexpected response from {url}");
+    }
+  };
+  let state = if ready {
+    "ready".green().to_string()
+  } else {
+    "draining".yellow().to_string()
+  };
+  println!("Server {server} is {state}");
+
+  let mut builder = client.get(format!("{server}/api/v1/services"));
+  if let Some(x) = auth_header(auth_token)? {
+    builder = builder.header("authorization", x);
+  }
+  let services: Vec<ServiceWithStatus> = check_status(builder.send().await?).await?.json().await?;
+
+  if services.is_empty() {
+    println!("No services");
+  }
+  let width = (services.iter())
+    .map(|x| x.service.name().len())
+    .max()
+    .unwrap_or(0);
+  let mut unhealthy = 0;
+  for ServiceWithStatus {
+    status,
+    service,
+    health,
+  } in &services
+  {
+    let status = match status {
+      ServiceStatus::Running => "running".green().to_string(),
+      ServiceStatus::Stopped => "stopped".yellow().to_string(),
+    };
+    let health = match health {
+      Some(x) if x.healthy => "healthy".green().to_string(),
+      Some(_) => {
+        unhealthy += 1;
+        "unhealthy".red().to_string()
+      }
+      None => String::new(),
+    };
+    let line = format!(
+      "  {:<width$}  {status}  {}  {health}",
+      service.name(),
+      service.uuid().dimmed(),
+    );
+    println!("{}", line.trim_end());
+  }
+
+  if !ready {
+    bail!("server is draining");
+  }
+  if unhealthy > 0 {
+    bail!("{unhealthy} service(s) unhealthy");
+  }
+  Ok(())
+}
@@ -1,8 +1,403 @@
-use serde::Deserialize;
+use crate::cron::Schedule;
+use crate::storage::ServiceStorageConfig;
+use crate::ErrorKind::{SecretNotFound, SecretNotGranted};
+use crate::Result;
+use data_encoding::BASE64;
+use hyper::header::HeaderValue;
+use serde::{Deserialize, Deserializer, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{self, Debug, Formatter};
 
 #[derive(Debug, Default, Deserialize)]
 pub struct Config {
   #[serde(rename = "name")]
   pub pkg_name: Option<String>,
   pub description: Option<String>,
+  /// Sentry-compatible DSN that this service's errors are reported to,
+  /// overriding the server-wide one.
+  pub report_dsn: Option<String>,
+  /// Alternative names this service can also be reached by.
+  #[serde(default)]
+  pub aliases: Vec<String>,
+  #[serde(default)]
+  pub limits: Limits,
+  pub mirror: Option<MirrorConfig>,
+  pub backup: Option<BackupConfig>,
+  /// Overrides the server-wide idle settings.
+  pub idle: Option<IdleConfig>,
+  /// Whether to compress responses for clients that accept it, overriding
+  /// the server-wide setting.
+  pub compress: Option<bool>,
+  /// Requests rejected before reaching Lua.
+  pub filters: Option<RequestFilters>,
+  /// Replays stored responses to retried requests with the same
+  /// `Idempotency-Key`.
+  pub idempotency: Option<IdempotencyConfig>,
+  /// Limits how often each client may call the service. Requests over the
+  /// limit are rejected with 429.
+  pub rate_limit: Option<RateLimitConfig>,
+  /// Keeps the service's isolates from being evicted from workers' caches
+  /// once loaded, so that hot services never pay for reloading. Workers still
+  /// load them on their first request; combine with `prewarm` to load them on
+  /// every worker up front.
+  #[serde(default)]
+  pub pinned: bool,
+  /// Loads the service's isolates on every worker when it is started or
+  /// updated, instead of on the first request each worker handles.
+  #[serde(default)]
+  pub prewarm: bool,
+  /// Validates requests against the `openapi.json` shipped in the service's
+  /// source before they reach Lua, rejecting mismatching ones with 400.
+  #[serde(default)]
+  pub validate_openapi: bool,
+  /// Time zone and locale the service renders dates and text in.
+  #[serde(flatten)]
+  pub regional: RegionalConfig,
+  /// Where the service's local storage is kept in the server-wide remote
+  /// backend, which must be set.
+  pub storage: Option<ServiceStorageConfig>,
+  /// Variables exposed to Lua as `abel.env`.
+  #[serde(default)]
+  pub env: HashMap<String, String>,
+  /// Names of secrets also exposed in `abel.env`, whose values are taken from
+  /// the server's secrets file or `ABEL_SECRET_<name>` environment variables.
+  /// Each of them must be granted to the service by the server.
+  #[serde(default)]
+  pub secrets: Vec<String>,
+}
+
+/// A service's regional defaults, exposed to Lua as `abel.service.timezone`
+/// and `abel.service.locale`. Both are unset by default.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct RegionalConfig {
+  /// IANA time zone name, e.g. `Asia/Shanghai`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub timezone: Option<String>,
+  /// BCP 47 language tag, e.g. `zh-CN`.
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub locale: Option<String>,
+}
+
+/// Values of secrets that services list in `secrets`. Ones not found here
+/// are read from environment variables prefixed with [`SECRET_ENV_PREFIX`],
+/// so that services cannot read the rest of the server's environment.
+///
+/// A service can only read secrets the server grants it by name.
+#[derive(Default)]
+pub(crate) struct Secrets {
+  pub values: HashMap<String, String>,
+  pub grants: HashMap<String, HashSet<String>>,
+}
+
+/// Prefix of environment variables holding secrets, e.g. `ABEL_SECRET_FOO`
+/// for secret `FOO`.
+pub(crate) const SECRET_ENV_PREFIX: &str = "ABEL_SECRET_";
+
+impl Secrets {
+  /// Reads secret `name` for `service`, failing if it is not granted to it.
+  pub fn get(&self, service: &str, name: &str) -> Result<String> {
+    if !(self.grants.get(service)).is_some_and(|x| x.contains(name)) {
+      let (service, name) = (service.into(), name.into());
+      return Err(SecretNotGranted { service, name }.into());
+    }
+    (self.values.get(name).cloned())
+      .or_else(|| std::env::var(format!("{SECRET_ENV_PREFIX}{name}")).ok())
+      .ok_or_else(|| SecretNotFound { name: name.into() }.into())
+  }
+}
+
+impl Debug for Secrets {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.debug_struct("Secrets")
+      .field("values", &self.values.keys().collect::<Vec<_>>())
+      .field("grants", &self.grants)
+      .finish()
+  }
+}
+
+/// Resource limits of a service.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct Limits {
+  /// How much Lua memory usage may grow while handling a single request.
+  ///
+  /// This bounds the worker, not the request: all services on a worker share
+  /// one Lua state, and the limit applies to its total usage, counted from
+  /// when the request started. Allocations of other requests on the same
+  /// worker count against it as well, and memory they free makes room.
+  pub memory_mb: Option<usize>,
+  /// CPU time a single request may take, including tasks it spawns.
+  /// Defaults to 1 second.
+  pub cpu_ms_per_request: Option<u64>,
+  /// Maximum number of requests handled at the same time.
+  #[serde(alias = "max_concurrency")]
+  pub max_concurrent_requests: Option<usize>,
+  /// How many requests over `max_concurrent_requests` may wait for a slot.
+  /// Ones beyond this are rejected with 429. Defaults to 0.
+  pub max_queued_requests: Option<usize>,
+  /// Milliseconds a queued request waits for a slot before it is rejected
+  /// with 429 too. Defaults to waiting until one is free.
+  pub queue_timeout_ms: Option<u64>,
+}
+
+/// What happens to a service after it receives no requests for a while.
+/// Both are in seconds since the last request; unset ones are disabled.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct IdleConfig {
+  /// Unload the service's Lua isolates. They are loaded again on the next
+  /// request. Checked once every minute.
+  pub unload_after: Option<u64>,
+  /// Stop the service. It is started again on the next request.
+  pub stop_after: Option<u64>,
+}
+
+impl IdleConfig {
+  /// Fills unset fields with `default`'s.
+  pub fn or(self, default: Self) -> Self {
+    Self {
+      unload_after: self.unload_after.or(default.unload_after),
+      stop_after: self.stop_after.or(default.stop_after),
+    }
+  }
+}
+
+/// Defaults of outbound requests made with `http.request`, which services
+/// can override per request.
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+pub struct HttpClientConfig {
+  /// Milliseconds to wait for a response's headers, including redirects.
+  /// No timeout by default.
+  pub timeout_ms: Option<u64>,
+  /// HTTP proxy every request goes through, e.g. `http://proxy:3128`.
+  pub proxy: Option<String>,
+}
+
+/// Limits of the remote module cache. Modules over them are evicted on
+/// startup and once every hour, least recently used first.
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize)]
+pub struct RemoteCacheConfig {
+  /// Total size of cached modules. Unlimited by default.
+  pub max_size_mb: Option<u64>,
+  /// Seconds a cached module may go unused. Unlimited by default.
+  pub max_age: Option<u64>,
+}
+
+/// Credentials sent when fetching remote modules from a host, e.g. a private
+/// module registry.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RemoteCredential {
+  /// Sent as `Authorization: Bearer <token>`.
+  Bearer(String),
+  Basic {
+    username: String,
+    password: Option<String>,
+  },
+}
+
+impl RemoteCredential {
+  pub(crate) fn header_value(&self) -> Option<HeaderValue> {
+    let value = match self {
+      Self::Bearer(token) => format!("Bearer {token}"),
+      Self::Basic { username, password } => {
+        let pair = format!("{username}:{}", password.as_deref().unwrap_or(""));
+        format!("Basic {}", BASE64.encode(pair.as_bytes()))
+      }
+    };
+    let mut value = HeaderValue::try_from(value).ok()?;
+    value.set_sensitive(true);
+    Some(value)
+  }
+}
+
+impl Debug for RemoteCredential {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    match self {
+      Self::Bearer(_) => f.write_str("Bearer(..)"),
+      Self::Basic { username, .. } => f
+        .debug_struct("Basic")
+        .field("username", username)
+        .finish_non_exhaustive(),
+    }
+  }
+}
+
+/// Rules for rejecting requests early, e.g. scanner noise or oversized
+/// uploads, without spending worker time on them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct RequestFilters {
+  /// Methods rejected with 405, e.g. `TRACE`.
+  #[serde(default)]
+  pub block_methods: Vec<String>,
+  /// Paths rejected with 404 along with everything under them, e.g.
+  /// `/wp-admin`. They are compared segment by segment, after resolving `.`,
+  /// `..` and repeated slashes.
+  #[serde(default)]
+  pub block_paths: Vec<String>,
+  /// Header rules; matching requests are rejected with 403.
+  #[serde(default)]
+  pub block_headers: Vec<HeaderFilter>,
+  /// Bodies larger than this many bytes are rejected with 413.
+  pub max_body_size: Option<u64>,
+}
+
+/// Responses to `POST`, `PUT`, `PATCH` and `DELETE` requests carrying an
+/// `Idempotency-Key` header are stored, and sent again for later requests with
+/// the same key instead of running the service twice.
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+pub struct IdempotencyConfig {
+  /// Seconds a stored response is kept. Defaults to 1 day.
+  pub ttl: Option<u64>,
+}
+
+impl IdempotencyConfig {
+  pub fn ttl(&self) -> u64 {
+    self.ttl.unwrap_or(86400)
+  }
+}
+
+/// A token bucket per client, refilled at `rate` tokens per second and
+/// holding at most `burst` of them. Each request takes one.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RateLimitConfig {
+  /// Requests per second each client may make on average, at least
+  /// [`MIN_RATE`](Self::MIN_RATE).
+  #[serde(deserialize_with = "deserialize_rate")]
+  pub rate: f64,
+  /// Requests each client may make at once. Defaults to `rate`, at least 1.
+  pub burst: Option<u32>,
+  /// What tells clients apart. Defaults to their IP.
+  #[serde(default)]
+  pub key: RateLimitKey,
+}
+
+impl RateLimitConfig {
+  /// One request a day.
+  pub const MIN_RATE: f64 = 1. / 86400.;
+
+  pub fn burst(&self) -> u32 {
+    (self.burst)
+      .unwrap_or_else(|| self.rate.ceil().min(u32::MAX as f64) as u32)
+      .max(1)
+  }
+}
+
+fn deserialize_rate<'de, D: Deserializer<'de>>(deserializer: D) -> Result<f64, D::Error> {
+  let rate = f64::deserialize(deserializer)?;
+  if rate.is_finite() && rate >= RateLimitConfig::MIN_RATE {
+    Ok(rate)
+  } else {
+    Err(serde::de::Error::custom(format!(
+      "rate must be a number no less than {}",
+      RateLimitConfig::MIN_RATE
+    )))
+  }
+}
+
+/// What rate limits are counted by, e.g. `"ip"` or `{ "header": "x-api-key" }`.
+/// Requests without it share one bucket.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RateLimitKey {
+  /// The client's IP, if the embedder passes it in
+  /// [`ClientAddr`](crate::ClientAddr).
+  #[default]
+  Ip,
+  /// Value of a request header. Clients may send any value they like, so
+  /// this only limits well-behaved ones unless a trusted proxy sets it.
+  Header(String),
+}
+
+/// Matches requests whose header `name` contains `contains`, ignoring case.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HeaderFilter {
+  pub name: String,
+  pub contains: String,
+}
+
+/// Copies a share of a service's incoming requests to another target, with
+/// the responses discarded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MirrorConfig {
+  /// Name of another service, or an HTTP(S) URL that the request path is
+  /// appended to.
+  pub target: String,
+  /// Percentage of requests to mirror, from 0 to 100.
+  #[serde(default = "default_mirror_percent")]
+  pub percent: f64,
+  /// Compare mirrored responses against the primary ones.
+  pub compare: Option<MirrorCompare>,
+}
+
+/// How primary and mirrored responses are compared. Status codes are always
+/// compared; bodies are compared as JSON if both parse, or byte by byte.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct MirrorCompare {
+  /// Response headers to compare.
+  #[serde(default)]
+  pub headers: Vec<String>,
+  /// JSON pointers (e.g. `/meta/timestamp`) left out when comparing bodies.
+  #[serde(default)]
+  pub ignore_paths: Vec<String>,
+  /// Fraction of mismatches written to the diff log, from 0 to 1. Defaults
+  /// to 1.
+  pub log_sample_rate: Option<f64>,
+}
+
+fn default_mirror_percent() -> f64 {
+  100.
+}
+
+/// Scheduled snapshots of a service's local storage.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct BackupConfig {
+  /// Local storage paths to back up, e.g. `local:db.sqlite`. Directories are
+  /// backed up recursively.
+  pub paths: Vec<String>,
+  /// When to back up, as a cron expression in UTC.
+  pub schedule: Schedule,
+  /// Number of backups to keep. Defaults to 7.
+  #[serde(default = "default_backup_keep")]
+  pub keep: usize,
+}
+
+fn default_backup_keep() -> usize {
+  7
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::ErrorKind;
+
+  #[test]
+  fn test_secret_grants() {
+    let secrets = Secrets {
+      values: [("DB_PASSWORD".into(), "hunter2".into())].into(),
+      grants: [(
+        "app".into(),
+        ["DB_PASSWORD".into(), "MISSING".into()].into(),
+      )]
+      .into(),
+    };
+    assert_eq!(secrets.get("app", "DB_PASSWORD").unwrap(), "hunter2");
+    assert!(matches!(
+      secrets.get("other", "DB_PASSWORD").unwrap_err().kind(),
+      ErrorKind::SecretNotGranted { .. }
+    ));
+    assert!(matches!(
+      secrets.get("app", "MISSING").unwrap_err().kind(),
+      ErrorKind::SecretNotFound { .. }
+    ));
+  }
+
+  #[test]
+  fn test_rate_limit_rate() {
+    let parse =
+      |rate: f64| serde_json::from_value::<RateLimitConfig>(serde_json::json!({ "rate": rate }));
+    assert!(parse(0.5).is_ok());
+    assert!(parse(RateLimitConfig::MIN_RATE).is_ok());
+    assert!(parse(0.).is_err());
+    assert!(parse(-1.).is_err());
+    assert!(parse(1e-300).is_err());
+  }
 }
@@ -0,0 +1,158 @@
+use chrono::{DateTime, Datelike, TimeZone, Timelike};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::fmt::{self, Display, Formatter};
+use std::str::FromStr;
+
+/// A five-field cron expression (`minute hour day-of-month month
+/// day-of-week`), e.g. `0 3 * * *`.
+///
+/// Each field accepts `*`, numbers, ranges (`1-5`), lists (`1,3`) and steps
+/// (`*/15`, `0-30/10`). As with most cron implementations, if both
+/// day-of-month and day-of-week are restricted, either matching is enough.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schedule {
+  source: String,
+  minutes: u64,
+  hours: u64,
+  days: u64,
+  months: u64,
+  weekdays: u64,
+  any_day: bool,
+  any_weekday: bool,
+}
+
+impl Schedule {
+  /// Whether the schedule fires in the minute of `time`.
+  pub fn matches<Tz: TimeZone>(&self, time: &DateTime<Tz>) -> bool {
+    let bit = |mask: u64, x: u32| mask & (1 << x) != 0;
+    let day = bit(self.days, time.day());
+    let weekday = bit(self.weekdays, time.weekday().num_days_from_sunday());
+    let day_matches = match (self.any_day, self.any_weekday) {
+      (false, false) => day || weekday,
+      _ => day && weekday,
+    };
+    bit(self.minutes, time.minute())
+      && bit(self.hours, time.hour())
+      && bit(self.months, time.month())
+      && day_matches
+  }
+}
+
+fn parse_field(field: &str, min: u32, max: u32) -> Result<u64, String> {
+  let mut mask = 0;
+  for part in field.split(',') {
+    let (range, step) = match part.split_once('/') {
+      Some((range, step)) => {
+        let step = step
+          .parse::<u32>()
+          .map_err(|_| format!("invalid step: {step:?}"))?;
+        if step == 0 {
+          return Err("step cannot be zero".into());
+        }
+        (range, step)
+      }
+      None => (part, 1),
+    };
+    let parse_num = |x: &str| {
+      (x.parse::<u32>().ok())
+        .filter(|x| (min..=max).contains(x))
+        .ok_or_else(|| format!("invalid value {x:?}, expected {min}-{max}"))
+    };
+    let (start, end) = match range {
+      "*" => (min, max),
+      _ => match range.split_once('-') {
+        Some((start, end)) => (parse_num(start)?, parse_num(end)?),
+        None if step > 1 => (parse_num(range)?, max),
+        None => {
+          let x = parse_num(range)?;
+          (x, x)
+        }
+      },
+    };
+    if start > end {
+      return Err(format!("invalid range: {range:?}"));
+    }
+    for x in (start..=end).step_by(step as _) {
+      mask |= 1 << x;
+    }
+  }
+  Ok(mask)
+}
+
+impl FromStr for Schedule {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let fields = s.split_whitespace().collect::<Vec<_>>();
+    let (minute, hour, day, month, weekday) = match fields[..] {
+      [minute, hour, day, month, weekday] => (minute, hour, day, month, weekday),
+      _ => {
+        return Err(format!(
+          "expected 5 fields in cron expression, got {}",
+          fields.len()
+        ))
+      }
+    };
+    let mut weekdays = parse_field(weekday, 0, 7)?;
+    // Both 0 and 7 are Sunday
+    if weekdays & (1 << 7) != 0 {
+      weekdays |= 1;
+    }
+    Ok(Self {
+      source: s.into(),
+      minutes: parse_field(minute, 0, 59)?,
+      hours: parse_field(hour, 0, 23)?,
+      days: parse_field(day, 1, 31)?,
+      months: parse_field(month, 1, 12)?,
+      weekdays,
+      any_day: day == "*",
+      any_weekday: weekday == "*",
+    })
+  }
+}
+
+impl Display for Schedule {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.write_str(&self.source)
+  }
+}
+
+impl Serialize for Schedule {
+  fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_str(&self.source)
+  }
+}
+
+impl<'de> Deserialize<'de> for Schedule {
+  fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+    let s = String::deserialize(deserializer)?;
+    s.parse().map_err(serde::de::Error::custom)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use chrono::Utc;
+  use test_case::test_case;
+
+  #[test_case("0 3 * * *", "2022-10-11T03:00:00Z" => true; "daily")]
+  #[test_case("0 3 * * *", "2022-10-11T03:01:00Z" => false; "daily wrong minute")]
+  #[test_case("*/15 * * * *", "2022-10-11T12:45:00Z" => true; "step")]
+  #[test_case("0 0 * * 0", "2022-10-16T00:00:00Z" => true; "sunday")]
+  #[test_case("0 0 * * 7", "2022-10-16T00:00:00Z" => true; "sunday as 7")]
+  #[test_case("0 0 1 * 1", "2022-10-17T00:00:00Z" => true; "day or weekday")]
+  #[test_case("0 0 1-5 * *", "2022-10-17T00:00:00Z" => false; "day range")]
+  fn test_schedule(schedule: &str, time: &str) -> bool {
+    let schedule = schedule.parse::<Schedule>().unwrap();
+    schedule.matches(&time.parse::<DateTime<Utc>>().unwrap())
+  }
+
+  #[test_case("* * * *"; "too few fields")]
+  #[test_case("60 * * * *"; "out of range")]
+  #[test_case("*/0 * * * *"; "zero step")]
+  #[test_case("5-1 * * * *"; "reversed range")]
+  fn test_invalid_schedule(schedule: &str) {
+    assert!(schedule.parse::<Schedule>().is_err());
+  }
+}
@@ -0,0 +1,175 @@
+//! Reloading services when their files change, for development setups.
+
+use crate::source::{SingleSource, SnapshotSource, Source};
+use crate::{Abel, Config, Error, Result};
+use log::{debug, error};
+use notify::RecursiveMode::{NonRecursive, Recursive};
+use notify::{Event, RecommendedWatcher, Watcher as _};
+use parking_lot::Mutex;
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::mpsc::{self, UnboundedReceiver, UnboundedSender};
+use tokio::time::{sleep_until, Instant};
+use uuid::Uuid;
+
+/// How long a service's files must stay unchanged before it is reloaded.
+const DEBOUNCE: Duration = Duration::from_millis(100);
+
+#[derive(Debug)]
+pub enum ReloadEvent {
+  Reloaded { name: String, uuid: Uuid },
+  Failed { name: String, error: Error },
+}
+
+#[derive(Debug, Clone)]
+struct WatchedService {
+  name: String,
+  path: PathBuf,
+  is_dir: bool,
+}
+
+type WatchedServices = Arc<Mutex<Vec<WatchedService>>>;
+
+/// Watches service files and hot-updates services when they change.
+///
+/// A service is either a single Lua file, or a directory containing
+/// `main.lua` and optionally `abel.json`. Reloads are reported through the
+/// receiver returned by [`Watcher::new`]. Dropping the watcher stops it.
+pub struct Watcher {
+  inner: Mutex<RecommendedWatcher>,
+  services: WatchedServices,
+}
+
+impl Watcher {
+  /// Creates a watcher. Must be called inside a Tokio runtime.
+  ///
+  /// `abel` can be `Arc<Abel>` or anything shared that holds an `Abel`.
+  pub fn new<A>(abel: Arc<A>) -> Result<(Self, UnboundedReceiver<ReloadEvent>)>
+  where
+    A: AsRef<Abel> + Send + Sync + 'static,
+  {
+    let (path_tx, path_rx) = mpsc::unbounded_channel();
+    let (event_tx, event_rx) = mpsc::unbounded_channel();
+    let inner = notify::recommended_watcher(move |result: notify::Result<Event>| match result {
+      Ok(event) => event.paths.into_iter().for_each(|x| drop(path_tx.send(x))),
+      Err(error) => error!("failed to watch files: {error}"),
+    })?;
+    let services = WatchedServices::default();
+    tokio::spawn(reload_loop(abel, services.clone(), path_rx, event_tx));
+    let watcher = Self {
+      inner: Mutex::new(inner),
+      services,
+    };
+    Ok((watcher, event_rx))
+  }
+
+  /// Starts watching `path` for service `name`. The service is not loaded
+  /// until the first change.
+  pub fn watch_service(&self, name: impl Into<String>, path: impl AsRef<Path>) -> Result<()> {
+    let path = std::fs::canonicalize(path)?;
+    let is_dir = path.is_dir();
+    let mode = if is_dir { Recursive } else { NonRecursive };
+    self.inner.lock().watch(&path, mode)?;
+    self.services.lock().push(WatchedService {
+      name: name.into(),
+      path,
+      is_dir,
+    });
+    Ok(())
+  }
+
+  /// Stops watching files of service `name`.
+  pub fn unwatch_service(&self, name: &str) -> Result<()> {
+    let mut services = self.services.lock();
+    if let Some(i) = services.iter().position(|x| x.name == name) {
+      let service = services.remove(i);
+      self.inner.lock().unwatch(&service.path)?;
+    }
+    Ok(())
+  }
+}
+
+async fn reload_loop<A>(
+  abel: Arc<A>,
+  services: WatchedServices,
+  mut path_rx: UnboundedReceiver<PathBuf>,
+  event_tx: UnboundedSender<ReloadEvent>,
+) where
+  A: AsRef<Abel> + Send + Sync + 'static,
+{
+  let mut pending = HashMap::<String, (Instant, WatchedService)>::new();
+  loop {
+    let next_deadline = pending.values().map(|x| x.0).min();
+    let next = async {
+      match next_deadline {
+        Some(deadline) => sleep_until(deadline).await,
+        None => futures::future::pending().await,
+      }
+    };
+
+    tokio::select! {
+      path = path_rx.recv() => {
+        let path = match path {
+          Some(path) => path,
+          None => break,
+        };
+        let services = services.lock();
+        let matched = services.iter().find(|x| {
+          path == x.path || x.is_dir && path.starts_with(&x.path)
+        });
+        if let Some(service) = matched {
+          let deadline = Instant::now() + DEBOUNCE;
+          pending.insert(service.name.clone(), (deadline, service.clone()));
+        }
+      }
+      _ = next => {
+        let now = Instant::now();
+        let due = pending
+          .iter()
+          .filter(|(_, (deadline, _))| *deadline <= now)
+          .map(|(name, _)| name.clone())
+          .collect::<Vec<_>>();
+        for name in due {
+          let (_, service) = pending.remove(&name).unwrap();
+          let event = match reload((*abel).as_ref(), &service).await {
+            Ok(uuid) => ReloadEvent::Reloaded { name, uuid },
+            Err(error) => ReloadEvent::Failed { name, error },
+          };
+          debug!("{event:?}");
+          if event_tx.send(event).is_err() {
+            return;
+          }
+        }
+      }
+    }
+  }
+}
+
+/// Hot-updates `service` with its files as they are now.
+///
+/// Directories are copied into memory first. Otherwise the new version would
+/// read modules lazily from the live directory, mixing files from before and
+/// after a later edit.
+async fn reload(abel: &Abel, service: &WatchedService) -> Result<Uuid> {
+  let (source, config) = if service.is_dir {
+    let source = Source::new(SnapshotSource::new(&service.path).await?);
+    let config = match source.get_bytes("abel.json").await {
+      Ok(bytes) => serde_json::from_slice(&bytes)
+        .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?,
+      Err(error) if error.kind() == io::ErrorKind::NotFound => Config::default(),
+      Err(error) => return Err(error.into()),
+    };
+    (source, config)
+  } else {
+    let code = tokio::fs::read(&service.path).await?;
+    (Source::new(SingleSource::new(code)), Config::default())
+  };
+  let (service, _) = abel
+    .hot_update_service(service.name.clone(), None, source, config)
+    .await?;
+  let uuid = service.try_upgrade()?.uuid();
+  Ok(uuid)
+}
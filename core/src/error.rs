@@ -1,4 +1,5 @@
 use crate::lua::error::CustomError;
+use crate::openapi::Violation;
 use crate::service::ServiceName;
 use backtrace::Backtrace;
 use hyper::StatusCode;
@@ -74,6 +75,13 @@ pub enum ErrorKind {
   #[strum(props(status = "409", error = "service already exists"))]
   ServiceExists { name: ServiceName },
 
+  #[error("alias '{alias}' is already used by service '{service}'")]
+  #[strum(props(status = "409", error = "service alias conflict"))]
+  AliasConflict {
+    alias: ServiceName,
+    service: ServiceName,
+  },
+
   #[error("service '{name}' is still running")]
   #[strum(props(status = "409", error = "service is running"))]
   ServiceRunning { name: ServiceName },
@@ -82,10 +90,72 @@ pub enum ErrorKind {
   #[strum(props(status = "409", error = "service is stopped"))]
   ServiceStopped { name: ServiceName },
 
+  #[error("service '{name}' has no canary")]
+  #[strum(props(status = "404", error = "no canary"))]
+  NoCanary { name: ServiceName },
+
   #[error("service is dropped")]
   #[strum(props(status = "500", error = "service is dropped"))]
   ServiceDropped,
 
+  #[error("method '{method}' not found in service '{service}'")]
+  #[strum(props(status = "404", error = "RPC method not found"))]
+  RpcMethodNotFound {
+    service: ServiceName,
+    method: Box<str>,
+  },
+
+  #[error("secret '{name}' not found")]
+  #[strum(props(status = "400", error = "secret not found"))]
+  SecretNotFound { name: Box<str> },
+
+  #[error("secret '{name}' is not granted to service '{service}'")]
+  #[strum(props(status = "403", error = "secret not granted"))]
+  SecretNotGranted {
+    service: ServiceName,
+    name: Box<str>,
+  },
+
+  #[error("service sets `storage`, but the server has no remote storage")]
+  #[strum(props(status = "400", error = "remote storage not configured"))]
+  StorageNotConfigured,
+
+  #[error("service '{name}' is handling too many requests")]
+  #[strum(props(status = "429", error = "service overloaded"))]
+  ServiceOverloaded { name: ServiceName },
+
+  #[error("client exceeded the rate limit of service '{name}'")]
+  #[strum(props(status = "429", error = "rate limited"))]
+  RateLimited {
+    name: ServiceName,
+    /// Seconds until the client may retry.
+    retry_after: u64,
+  },
+
+  #[error("CPU time limit exceeded")]
+  #[strum(props(status = "408", error = "CPU time limit exceeded"))]
+  CpuLimitExceeded,
+
+  #[error("memory limit exceeded")]
+  #[strum(props(status = "503", error = "memory limit exceeded"))]
+  MemoryLimitExceeded,
+
+  #[error("stack overflow")]
+  #[strum(props(status = "500", error = "stack overflow"))]
+  StackOverflow,
+
+  #[error("service '{service}' {fault}")]
+  #[strum(props(status = "500", error = "service fault"))]
+  ServiceFault { service: ServiceName, fault: Fault },
+
+  #[error("invalid openapi.json: {msg}")]
+  #[strum(props(status = "400", error = "invalid OpenAPI document"))]
+  InvalidOpenApi { msg: Box<str> },
+
+  #[error("request does not match the service's OpenAPI document")]
+  #[strum(props(status = "400", error = "request validation failed"))]
+  RequestValidation { errors: Vec<Violation> },
+
   #[error("entry '{entry}' not found")]
   #[strum(props(status = "404", error = "entry not found"))]
   EntryNotFound { entry: Box<str> },
@@ -107,6 +177,14 @@ pub enum ErrorKind {
     tokio::io::Error,
   ),
 
+  #[error(transparent)]
+  #[strum(props(status = "500", error = "file watch error"))]
+  Notify(
+    #[from]
+    #[serde(serialize_with = "serialize_error")]
+    notify::Error,
+  ),
+
   #[error(transparent)]
   #[strum(props(status = "500", error = "regex error"))]
   Regex(
@@ -121,6 +199,32 @@ pub enum ErrorKind {
   Custom(CustomError),
 }
 
+/// Resource exhaustion while running a service's Lua code.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Fault {
+  OutOfMemory,
+  StackOverflow,
+}
+
+impl Fault {
+  pub fn as_str(self) -> &'static str {
+    match self {
+      Self::OutOfMemory => "out_of_memory",
+      Self::StackOverflow => "stack_overflow",
+    }
+  }
+}
+
+impl std::fmt::Display for Fault {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    match self {
+      Self::OutOfMemory => f.write_str("ran out of memory"),
+      Self::StackOverflow => f.write_str("overflowed its stack"),
+    }
+  }
+}
+
 fn serialize_error<E, S>(error: E, ser: S) -> Result<S::Ok, S::Error>
 where
   E: std::error::Error,
@@ -151,6 +255,16 @@ impl ErrorKind {
     }
   }
 
+  /// The kind of resource exhaustion this error comes from, if any.
+  pub fn fault(&self) -> Option<Fault> {
+    match self {
+      Self::MemoryLimitExceeded => Some(Fault::OutOfMemory),
+      Self::StackOverflow => Some(Fault::StackOverflow),
+      Self::ServiceFault { fault, .. } => Some(*fault),
+      _ => None,
+    }
+  }
+
   pub fn internal(&self) -> bool {
     match self {
       Self::Custom { .. } => false,
@@ -1,65 +1,216 @@
+pub mod dev;
+pub mod metrics;
+pub mod openapi;
 pub mod service;
 pub mod source;
+pub mod storage;
 
 mod config;
+mod cron;
 mod error;
 mod lua;
 mod path;
+mod rate_limit;
 mod runtime;
 mod task;
 
-pub use config::Config;
-pub use error::{Error, ErrorKind, Result};
-pub use lua::require::{load_create_require, RemoteInterface};
-pub use mlua;
-pub use mlua::Error as LuaError;
+pub use config::{
+  BackupConfig, Config, HeaderFilter, HttpClientConfig, IdempotencyConfig, IdleConfig, Limits,
+  MirrorCompare, MirrorConfig, RateLimitConfig, RateLimitKey, RegionalConfig, RemoteCacheConfig,
+  RemoteCredential, RequestFilters,
+};
+pub use cron::Schedule;
+pub use error::{Error, ErrorKind, Fault, Result};
+pub use lua::cache::CacheStats;
+pub use lua::require::{
+  load_create_require, vendor_path, RemoteCacheStats, RemoteInterface, LOCKFILE, VENDOR_DIR,
+};
+pub use lua::sandbox::ModuleRegistrar;
+pub use mlua::{self, Error as LuaError};
 pub use path::normalize_path_str;
-pub use runtime::check_name;
-pub use service::{RunningService, RunningServiceGuard, ServiceImpl};
+pub use runtime::{check_name, CapturedLog, LogCapture};
+pub use service::{CanaryRule, CanaryStatus, RunningService, RunningServiceGuard, ServiceImpl};
+pub use source::{Source, SourceRegistry, SourceVfs};
+pub use storage::{LocalStorage, S3Config, ServiceStorageConfig, StorageBackend, StorageConfig};
 
-use hyper::{Body, Request, Response};
+use config::Secrets;
+use dashmap::DashMap;
+use futures::TryStreamExt;
+use hyper::body::HttpBody;
+use hyper::{Body, HeaderMap, Request, Response};
+use log::{info, warn};
+use lua::bytecode::BytecodeCache;
+use lua::cache::CacheState;
+use lua::sandbox::HostModules;
+use metrics::{Metrics, MetricsSnapshot, RuntimeStats};
+use nonzero_ext::nonzero;
+use once_cell::sync::OnceCell;
+use parking_lot::Mutex;
+use rate_limit::RateLimiter;
+use runtime::wait::Waiters;
 use runtime::Runtime;
-use service::{ErrorPayload, Service, ServiceName, ServicePool, StoppedService};
-use source::Source;
+use service::{
+  unix_secs, ErrorPayload, Health, Service, ServiceName, ServicePool, Services, StoppedService,
+};
+use std::collections::{HashMap, HashSet};
+use std::net::IpAddr;
+use std::num::NonZeroUsize;
 use std::path::PathBuf;
-use std::sync::Arc;
-use task::Pool;
+use std::sync::{Arc, Weak};
+use std::time::{Duration, Instant};
+use task::{Pool, TaskContext, TaskLimits};
+use tracing::field::Empty;
+use tracing::{info_span, Instrument, Span};
 use uuid::Uuid;
+use ErrorKind::{RateLimited, ServiceOverloaded};
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// ID of a request, taken from its `x-request-id` header or generated.
+///
+/// Put into a request's extensions before [`Abel::run_service`], it is exposed
+/// to Lua as `req.id`, included in the service's logs and passed on in
+/// requests the service makes.
+#[derive(Debug, Clone)]
+pub struct RequestId(pub Arc<str>);
+
+/// Address of the client a request comes from.
+///
+/// Put into a request's extensions before [`Abel::run_service`], it keys
+/// services' rate limits by IP.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub IpAddr);
 
 pub struct Abel {
+  inner: Arc<AbelInner>,
+  state: Arc<AbelState>,
+  sources: SourceRegistry,
+}
+
+/// Parts of [`Abel`] services reach back into through
+/// [`AbelState::abel`], e.g. to call each other.
+pub(crate) struct AbelInner {
   runtime_pool: Pool,
   service_pool: ServicePool,
   state: Arc<AbelState>,
+  /// Services stopped for being idle, which are started again on the next
+  /// request.
+  idle_stopped: Mutex<HashMap<ServiceName, Uuid>>,
+  /// Keeps concurrent requests from starting the same idle service twice,
+  /// by service name. Entries are removed once nobody is starting the
+  /// service.
+  activating: DashMap<ServiceName, Arc<tokio::sync::Mutex<()>>>,
 }
 
 #[derive(Debug)]
 pub struct AbelState {
   pub local_storage_path: PathBuf,
   pub remote: RemoteInterface,
+  pub(crate) metrics: Metrics,
+  pub(crate) idle: IdleConfig,
+  pub(crate) secrets: Secrets,
+  pub(crate) services: Arc<Services>,
+  pub(crate) waiters: Waiters,
+  pub(crate) caches: DashMap<ServiceName, Arc<CacheState>>,
+  pub(crate) isolate_cache_size: NonZeroUsize,
+  pub(crate) http_client: HttpClientConfig,
+  pub(crate) bytecode: Arc<BytecodeCache>,
+  pub(crate) modules: HostModules,
+  pub(crate) storage: Option<StorageConfig>,
+  pub(crate) rate_limiter: RateLimiter,
+  /// The [`Abel`] this state belongs to, set once it is created.
+  pub(crate) abel: OnceCell<Weak<AbelInner>>,
 }
 
 pub struct AbelOptions {
   pub runtime_pool_size: usize,
   pub local_storage_path: PathBuf,
   pub remote_cache_path: Option<PathBuf>,
+  /// Idle settings of services that do not override them.
+  pub idle: IdleConfig,
+  /// Values of secrets services can use, before falling back to environment
+  /// variables.
+  pub secrets: HashMap<String, String>,
+  /// Names of secrets each service may read, by service name. Services
+  /// cannot read any secret not granted here.
+  pub secret_grants: HashMap<String, HashSet<String>>,
+  /// Isolates each worker keeps loaded, apart from pinned services'. Loading
+  /// an evicted one again runs the service's source. Defaults to 16.
+  pub isolate_cache_size: Option<NonZeroUsize>,
+  /// Defaults of services' outbound requests.
+  pub http_client: HttpClientConfig,
+  /// Load remote modules missing from, or not matching, sources'
+  /// `abel.lock` with a warning, instead of failing.
+  pub allow_unlocked: bool,
+  /// Credentials for fetching remote modules, keyed by host, or host and
+  /// port.
+  pub remote_credentials: HashMap<String, RemoteCredential>,
+  /// Limits of the cache at `remote_cache_path`.
+  pub remote_cache: RemoteCacheConfig,
+  /// Extra Lua modules services can `require`, by name. They take precedence
+  /// over built-in ones with the same name.
+  pub modules: Vec<(String, ModuleRegistrar)>,
+  /// Backends services' sources can be opened from by URI.
+  pub sources: SourceRegistry,
+  /// Remote backend of services' local storage, unless they set their own.
+  /// Without it, local storage only lives on disk.
+  pub storage: Option<StorageConfig>,
+}
+
+impl AsRef<Abel> for Abel {
+  fn as_ref(&self) -> &Abel {
+    self
+  }
 }
 
 impl Abel {
   pub fn new(options: AbelOptions) -> Result<Self> {
     let state = Arc::new(AbelState {
       local_storage_path: options.local_storage_path,
-      remote: RemoteInterface::new(options.remote_cache_path),
+      remote: RemoteInterface::new(options.remote_cache_path)
+        .allow_unlocked(options.allow_unlocked)
+        .credentials(options.remote_credentials)
+        .cache_limits(options.remote_cache),
+      metrics: Metrics::default(),
+      idle: options.idle,
+      secrets: Secrets {
+        values: options.secrets,
+        grants: options.secret_grants,
+      },
+      services: Default::default(),
+      waiters: Default::default(),
+      caches: Default::default(),
+      isolate_cache_size: (options.isolate_cache_size).unwrap_or(nonzero!(16usize)),
+      http_client: options.http_client,
+      bytecode: Default::default(),
+      modules: HostModules::new(options.modules),
+      storage: options.storage,
+      rate_limiter: Default::default(),
+      abel: OnceCell::new(),
     });
-    Ok(Self {
+    let inner = Arc::new(AbelInner {
       runtime_pool: Pool::new(options.runtime_pool_size, {
         let state = state.clone();
         move || Runtime::new(state.clone())
       })?,
       service_pool: ServicePool::new(state.clone()),
+      state: state.clone(),
+      idle_stopped: Default::default(),
+      activating: Default::default(),
+    });
+    let _ = state.abel.set(Arc::downgrade(&inner));
+    Ok(Self {
+      inner,
       state,
+      sources: options.sources,
     })
   }
 
+  /// Opens a source through the backend registered for `uri`'s scheme.
+  pub async fn open_source(&self, uri: &str) -> Result<Source> {
+    Ok(self.sources.open(uri).await?)
+  }
+
   pub async fn load_service(
     &self,
     name: impl Into<ServiceName>,
@@ -67,8 +218,8 @@ impl Abel {
     source: Source,
     config: Config,
   ) -> Result<(StoppedService<'_>, Option<ServiceImpl>, ErrorPayload)> {
-    (self.service_pool)
-      .load(&self.runtime_pool, name.into(), uuid, source, config)
+    (self.inner.service_pool)
+      .load(&self.inner.runtime_pool, name.into(), uuid, source, config)
       .await
   }
 
@@ -79,8 +230,8 @@ impl Abel {
     source: Source,
     config: Config,
   ) -> Result<(Service<'_>, Option<ServiceImpl>, ErrorPayload)> {
-    (self.service_pool)
-      .cold_update_or_create(&self.runtime_pool, name.into(), uuid, source, config)
+    (self.inner.service_pool)
+      .cold_update_or_create(&self.inner.runtime_pool, name.into(), uuid, source, config)
       .await
   }
 
@@ -91,8 +242,28 @@ impl Abel {
     source: Source,
     config: Config,
   ) -> Result<(RunningService, ServiceImpl)> {
-    (self.service_pool)
-      .hot_update(&self.runtime_pool, name.into(), uuid, source, config)
+    (self.inner.service_pool)
+      .hot_update(&self.inner.runtime_pool, name.into(), uuid, source, config)
+      .await
+  }
+
+  pub async fn canary_update_service(
+    &self,
+    name: impl Into<ServiceName>,
+    uuid: Option<Uuid>,
+    source: Source,
+    config: Config,
+    rule: CanaryRule,
+  ) -> Result<(RunningService, Option<ServiceImpl>)> {
+    (self.inner.service_pool)
+      .canary_update(
+        &self.inner.runtime_pool,
+        name.into(),
+        uuid,
+        source,
+        config,
+        rule,
+      )
       .await
   }
 
@@ -103,23 +274,74 @@ impl Abel {
     source: Source,
     config: Config,
   ) -> Result<(StoppedService<'_>, ErrorPayload)> {
-    let (service, replaced, error_payload) = (self.service_pool)
-      .load(&self.runtime_pool, name.into(), Some(uuid), source, config)
+    let (service, replaced, error_payload) = (self.inner.service_pool)
+      .load(
+        &self.inner.runtime_pool,
+        name.into(),
+        Some(uuid),
+        source,
+        config,
+      )
       .await?;
     assert!(replaced.is_none());
     Ok((service, error_payload))
   }
 
+  /// Resolves a service name or one of its aliases to the service's name,
+  /// after normalizing it.
+  ///
+  /// If nothing matches, the normalized name is returned as-is.
+  pub fn resolve_service_name(&self, name: &str) -> ServiceName {
+    let name = service::normalize_name(name);
+    (self.inner.service_pool)
+      .resolve_name(&name)
+      .unwrap_or_else(|| (*name).into())
+  }
+
   pub fn get_service(&self, name: &str) -> Result<Service<'_>> {
-    (self.service_pool)
+    (self.inner.service_pool)
       .get(name)
       .ok_or_else(|| ErrorKind::ServiceNotFound { name: name.into() }.into())
   }
 
   pub fn get_running_service(&self, name: &str) -> Result<RunningService> {
-    (self.service_pool)
-      .get_running(name)
-      .ok_or_else(|| ErrorKind::ServiceNotFound { name: name.into() }.into())
+    self.inner.get_running_service(name)
+  }
+
+  /// Same as [`get_running_service`](Self::get_running_service), but starts
+  /// the service if it was stopped for being idle.
+  pub async fn activate_service(&self, name: &str) -> Result<RunningService> {
+    self.inner.activate_service(name).await
+  }
+
+  /// Stops running services idle for longer than their `stop_after`,
+  /// returning the names of those stopped.
+  pub async fn stop_idle_services(&self) -> Vec<ServiceName> {
+    let idle = (self.inner.service_pool.list())
+      .filter(|x| x.is_running())
+      .filter_map(|x| {
+        let service = x.upgrade();
+        let stop_after = service.idle_config(self.state.idle).stop_after?;
+        (service.idle_secs() >= stop_after).then(|| (service.name.clone(), service.uuid()))
+      })
+      .collect::<Vec<_>>();
+    let mut stopped = Vec::new();
+    for (name, uuid) in idle {
+      match self
+        .inner
+        .service_pool
+        .stop(&self.inner.runtime_pool, &name)
+        .await
+      {
+        Ok(_) => {
+          info!("Stopped idle service '{name}'");
+          self.inner.idle_stopped.lock().insert(name.clone(), uuid);
+          stopped.push(name);
+        }
+        Err(error) => warn!("failed to stop idle service '{name}': {error}"),
+      }
+    }
+    stopped
   }
 
   pub async fn run_service(
@@ -128,28 +350,648 @@ impl Abel {
     path: String,
     req: Request<Body>,
   ) -> Result<Response<Body>> {
-    (self.runtime_pool)
-      .scope(move |rt| async move { Ok(rt.handle_request(service, &path, req).await?.into()) })
+    self.run_service_logged(service, path, req, None).await
+  }
+
+  /// Same as [`run_service`](Self::run_service), but also collects lines the
+  /// service logs while handling the request into `logs`.
+  pub async fn run_service_with_logs(
+    &self,
+    service: RunningService,
+    path: String,
+    req: Request<Body>,
+    logs: LogCapture,
+  ) -> Result<Response<Body>> {
+    self
+      .run_service_logged(service, path, req, Some(logs))
       .await
   }
 
+  async fn run_service_logged(
+    &self,
+    service: RunningService,
+    path: String,
+    req: Request<Body>,
+    logs: Option<LogCapture>,
+  ) -> Result<Response<Body>> {
+    let start = Instant::now();
+    let name = {
+      let guard = service.try_upgrade()?;
+      guard.touch();
+      guard.name.clone()
+    };
+    let span = info_span!("run_service", service = %name, path = %path, request_id = Empty);
+    if let Some(RequestId(id)) = req.extensions().get() {
+      span.record("request_id", &**id);
+    }
+    let cpu_time = Arc::<Mutex<Duration>>::default();
+    let result = (self.run_service_inner(service, path, req, cpu_time.clone(), logs))
+      .instrument(span)
+      .await;
+    let error = match &result {
+      Ok(resp) => resp.status().is_server_error(),
+      Err(_) => true,
+    };
+    let cpu_time = *cpu_time.lock();
+    (self.state.metrics).record_request(&name, start.elapsed(), cpu_time, error);
+    result.map(|resp| self.count_egress(name, resp))
+  }
+
+  /// Counts response body bytes of `service_name` as they are sent.
+  fn count_egress(&self, service_name: ServiceName, resp: Response<Body>) -> Response<Body> {
+    if let Some(len) = resp.body().size_hint().exact() {
+      (self.state.metrics).record_egress(&service_name, len);
+      return resp;
+    }
+    let state = self.state.clone();
+    let (parts, body) = resp.into_parts();
+    let body = body.inspect_ok(move |chunk| {
+      (state.metrics).record_egress(&service_name, chunk.len() as _);
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+  }
+
+  async fn run_service_inner(
+    &self,
+    service: RunningService,
+    path: String,
+    req: Request<Body>,
+    cpu_time: Arc<Mutex<Duration>>,
+    logs: Option<LogCapture>,
+  ) -> Result<Response<Body>> {
+    let (limits, concurrency, rate_limit, name) = {
+      let guard = service.try_upgrade()?;
+      let rate_limit = guard.rate_limit.clone();
+      (
+        task_limits(&guard),
+        guard.concurrency.clone(),
+        rate_limit,
+        guard.name.clone(),
+      )
+    };
+    if let Some(config) = rate_limit {
+      (self.state.rate_limiter.acquire(&name, &config, &req)).map_err(|wait| RateLimited {
+        name: name.clone(),
+        retry_after: wait.as_secs_f64().ceil() as u64,
+      })?;
+    }
+    let _permit = match concurrency {
+      Some(x) => Some(x.acquire().await.ok_or(ServiceOverloaded { name })?),
+      None => None,
+    };
+    let request_id = req.extensions().get::<RequestId>().map(|x| x.0.clone());
+    // The handler runs on another thread, so its span's parent is passed on
+    // explicitly.
+    let parent = Span::current();
+    (self.inner.runtime_pool)
+      .scope_with_cpu_time(limits, cpu_time, move |rt| async move {
+        if let Some(logs) = logs {
+          TaskContext::capture_logs(rt.lua(), logs);
+        }
+        if let Some(id) = request_id {
+          TaskContext::set_request_id(rt.lua(), id);
+        }
+        let span = info_span!(parent: &parent, "lua_handler", path = %path);
+        let resp = rt.handle_request(service, &path, req).instrument(span);
+        Ok(resp.await?.into())
+      })
+      .await
+  }
+
+  /// Runs a standalone Lua chunk on the runtime pool with the standard
+  /// sandbox, without creating a service.
+  ///
+  /// `name` is only used in logs. `args` are passed to the chunk, and its
+  /// first return value is returned as JSON.
+  pub async fn eval(
+    &self,
+    name: Option<&str>,
+    source: Source,
+    args: impl serde::Serialize,
+  ) -> Result<serde_json::Value> {
+    let name = name.unwrap_or("<anonymous>").to_string();
+    let args = serde_json::to_value(args).map_err(mlua::Error::external)?;
+    (self.inner.runtime_pool)
+      .scope(move |rt| async move { rt.eval(&name, source, args).await })
+      .await
+  }
+
+  /// Counts a request mirrored from `service_name`, and whether mirroring
+  /// it failed.
+  pub fn record_mirror(&self, service_name: &str, error: bool) {
+    self.state.metrics.record_mirror(service_name, error)
+  }
+
+  /// Counts a comparison between a primary and a mirrored response of
+  /// `service_name`, and whether they differed.
+  pub fn record_mirror_comparison(&self, service_name: &str, mismatch: bool) {
+    (self.state.metrics).record_mirror_comparison(service_name, mismatch)
+  }
+
+  /// Statistics of the workers' isolate caches.
+  pub fn runtime_stats(&self) -> RuntimeStats {
+    let metrics = &self.state.metrics;
+    RuntimeStats {
+      workers: self.inner.runtime_pool.size(),
+      isolate_cache_size: self.state.isolate_cache_size.get(),
+      isolate_cache_hits: metrics.isolate_cache_hits(),
+      isolate_cache_misses: metrics.isolate_cache_misses(),
+      isolate_evictions: metrics.isolate_evictions(),
+      pinned_services: (self.inner.service_pool.list())
+        .filter(|x| x.is_running() && x.upgrade().pinned())
+        .count(),
+    }
+  }
+
+  /// Takes a snapshot of request counters, latencies and isolate cache
+  /// statistics.
+  pub fn metrics(&self) -> MetricsSnapshot {
+    let active_services = self.list_services().filter(|x| x.is_running()).count();
+    self.state.metrics.snapshot(active_services)
+  }
+
   pub fn list_services(&self) -> impl Iterator<Item = Service<'_>> {
-    self.service_pool.list()
+    self.inner.service_pool.list()
+  }
+
+  /// Picks the canary of service `name` for a request with `headers`, if
+  /// there is one and its rule matches.
+  pub fn pick_canary(&self, name: &str, headers: &HeaderMap) -> Option<RunningService> {
+    self.inner.service_pool.pick_canary(name, headers)
+  }
+
+  pub fn canary(&self, name: &str) -> Option<CanaryStatus> {
+    self.inner.service_pool.canary(name)
+  }
+
+  pub fn promote_canary(&self, name: &str) -> Result<(RunningService, ServiceImpl)> {
+    let (service, replaced) =
+      (self.inner.service_pool).promote_canary(&self.inner.runtime_pool, name)?;
+    service.try_upgrade()?.touch();
+    Ok((service, replaced))
+  }
+
+  pub fn rollback_canary(&self, name: &str) -> Result<ServiceImpl> {
+    (self.inner.service_pool).rollback_canary(&self.inner.runtime_pool, name)
   }
 
   pub async fn stop_service(&self, name: &str) -> Result<StoppedService<'_>> {
-    self.service_pool.stop(&self.runtime_pool, name).await
+    self.inner.idle_stopped.lock().remove(name);
+    self
+      .inner
+      .service_pool
+      .stop(&self.inner.runtime_pool, name)
+      .await
   }
 
   pub async fn stop_all_services(&self) {
-    self.service_pool.stop_all(&self.runtime_pool).await
+    self
+      .inner
+      .service_pool
+      .stop_all(&self.inner.runtime_pool)
+      .await
   }
 
   pub async fn start_service(&self, name: &str) -> Result<RunningService> {
-    self.service_pool.start(&self.runtime_pool, name).await
+    self.inner.start_service(name).await
   }
 
   pub async fn remove_service(&self, name: &str) -> Result<ServiceImpl> {
-    self.service_pool.remove(&self.state, name).await
+    let service = self.inner.service_pool.remove(&self.state, name).await?;
+    self.inner.idle_stopped.lock().remove(name);
+    self.state.metrics.remove_service(name);
+    self.state.caches.remove(name);
+    Ok(service)
+  }
+
+  /// Runs `abel.health` of running service `name` and records the result.
+  /// Returns `None` if the service defines none.
+  pub async fn check_health(&self, name: &str) -> Result<Option<Health>> {
+    let service = self.get_running_service(name)?;
+    let cell = match service.try_upgrade()?.health.clone() {
+      Some(x) => x,
+      None => return Ok(None),
+    };
+    let check =
+      (self.inner.runtime_pool).scope(move |rt| async move { rt.run_health(service).await });
+    let (healthy, detail) = match tokio::time::timeout(HEALTH_CHECK_TIMEOUT, check).await {
+      Ok(Ok(x)) => x,
+      Ok(Err(error)) => (false, Some(error.to_string().into())),
+      Err(_) => (false, Some("health check timed out".into())),
+    };
+    let health = Health {
+      healthy,
+      detail,
+      checked_at: unix_secs(),
+    };
+
+    let previous = cell.lock().replace(health.clone());
+    let was_healthy = !matches!(previous, Some(x) if !x.healthy);
+    if was_healthy && !healthy {
+      match &health.detail {
+        Some(detail) => warn!("service '{name}' is unhealthy: {detail}"),
+        None => warn!("service '{name}' is unhealthy"),
+      }
+    } else if !was_healthy && healthy {
+      info!("service '{name}' is healthy again");
+    }
+    Ok(Some(health))
+  }
+
+  /// Checks every running service that defines `abel.health`.
+  pub async fn check_all_health(&self) {
+    let names = (self.inner.service_pool.list())
+      .filter(|x| x.is_running())
+      .filter_map(|x| {
+        let service = x.upgrade();
+        service.health.is_some().then(|| service.name.clone())
+      })
+      .collect::<Vec<_>>();
+    for name in names {
+      if let Err(error) = self.check_health(&name).await {
+        warn!("failed to check health of service '{name}': {error}");
+      }
+    }
+  }
+
+  /// Drops every worker's cached values of service `name`.
+  pub fn flush_cache(&self, name: &str) -> Result<()> {
+    self.get_service(name)?;
+    if let Some(cache) = self.state.caches.get(name) {
+      cache.flush();
+    }
+    Ok(())
+  }
+
+  /// Hit and miss counts of service `name`'s caches, across all workers.
+  pub fn cache_stats(&self, name: &str) -> Result<CacheStats> {
+    self.get_service(name)?;
+    Ok(match self.state.caches.get(name) {
+      Some(cache) => cache.stats(),
+      None => CacheState::default().stats(),
+    })
+  }
+
+  /// Number and total size of cached remote modules.
+  pub async fn remote_cache_stats(&self) -> Result<RemoteCacheStats> {
+    Ok(self.state.remote.cache_stats().await?)
+  }
+
+  /// Removes cached remote modules over the configured limits, returning
+  /// what was removed.
+  pub async fn evict_remote_cache(&self) -> Result<RemoteCacheStats> {
+    Ok(self.state.remote.evict_cache().await?)
+  }
+
+  /// Removes every cached remote module, returning what was removed.
+  pub async fn purge_remote_cache(&self) -> Result<RemoteCacheStats> {
+    Ok(self.state.remote.purge_cache().await?)
   }
 }
+
+impl AbelInner {
+  fn get_running_service(&self, name: &str) -> Result<RunningService> {
+    (self.service_pool)
+      .get_running(name)
+      .ok_or_else(|| ErrorKind::ServiceNotFound { name: name.into() }.into())
+  }
+
+  pub async fn activate_service(&self, name: &str) -> Result<RunningService> {
+    if let Some(service) = self.service_pool.get_running(name) {
+      return Ok(service);
+    }
+    let lock = self.activating.entry(name.into()).or_default().clone();
+    let result = {
+      let _guard = lock.lock().await;
+      self.activate_service_locked(name).await
+    };
+    drop(lock);
+    (self.activating).remove_if(name, |_, x| Arc::strong_count(x) == 1);
+    result
+  }
+
+  async fn activate_service_locked(&self, name: &str) -> Result<RunningService> {
+    if let Some(service) = self.service_pool.get_running(name) {
+      return Ok(service);
+    }
+    let uuid = self.idle_stopped.lock().get(name).copied();
+    match (uuid, self.service_pool.get(name)) {
+      (Some(uuid), Some(service)) if service.upgrade().uuid() == uuid => {}
+      _ => return self.get_running_service(name),
+    }
+    match self.start_service(name).await {
+      Ok(service) => {
+        info!("Started idle service '{name}' on request");
+        Ok(service)
+      }
+      // Possibly started by another request in the meantime
+      Err(error) => self.get_running_service(name).map_err(|_| error),
+    }
+  }
+
+  async fn start_service(&self, name: &str) -> Result<RunningService> {
+    self.idle_stopped.lock().remove(name);
+    let service = self.service_pool.start(&self.runtime_pool, name).await?;
+    service.try_upgrade()?.touch();
+    Ok(service)
+  }
+}
+
+/// Limits of a task handling a request to `service`, or a call to one of its
+/// RPC methods.
+fn task_limits(service: &RunningServiceGuard) -> TaskLimits {
+  let mut limits = TaskLimits::default();
+  if let Some(ms) = service.limits.cpu_ms_per_request {
+    limits.cpu_time = Duration::from_millis(ms);
+  }
+  limits.memory = (service.limits.memory_mb).map(|x| x.saturating_mul(1024 * 1024));
+  limits
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use source::SingleSource;
+  use tempfile::TempDir;
+
+  pub(crate) fn abel(dir: &TempDir) -> Abel {
+    let local_storage_path = dir.path().join("storage");
+    std::fs::create_dir(&local_storage_path).unwrap();
+    Abel::new(AbelOptions {
+      runtime_pool_size: 1,
+      local_storage_path,
+      remote_cache_path: None,
+      idle: Default::default(),
+      secrets: Default::default(),
+      secret_grants: Default::default(),
+      isolate_cache_size: None,
+      http_client: Default::default(),
+      allow_unlocked: false,
+      remote_credentials: Default::default(),
+      remote_cache: Default::default(),
+      modules: Vec::new(),
+      sources: Default::default(),
+      storage: None,
+    })
+    .unwrap()
+  }
+
+  #[tokio::test]
+  async fn test_eval_storage_not_shared() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let code = r#"
+      local fs = require "fs"
+      local existed = fs.exists "marker"
+      local file <close> = fs.open("marker", "w")
+      file:write "x"
+      return existed
+    "#;
+    for _ in 0..2 {
+      let source = Source::new(SingleSource::new(code));
+      let existed = abel.eval(None, source, ()).await.unwrap();
+      assert_eq!(existed, serde_json::json!(false));
+    }
+  }
+
+  #[tokio::test]
+  async fn test_activate_service() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    for name in ["a", "b"] {
+      let source = Source::new(SingleSource::new(r#"abel.listen("/", function() end)"#));
+      (abel.cold_update_or_create_service(name, None, source, Default::default()))
+        .await
+        .unwrap();
+      let uuid = abel.stop_service(name).await.unwrap().uuid();
+      abel.inner.idle_stopped.lock().insert(name.into(), uuid);
+    }
+
+    let (a1, a2, b) = tokio::join!(
+      abel.activate_service("a"),
+      abel.activate_service("a"),
+      abel.activate_service("b"),
+    );
+    let (a1, a2, b) = (a1.unwrap(), a2.unwrap(), b.unwrap());
+    assert!(a1.ptr_eq(&a2));
+    assert_eq!(b.upgrade().name, "b");
+    assert!(abel.inner.activating.is_empty());
+  }
+
+  #[tokio::test]
+  async fn test_canary_concurrency() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let code = r#"abel.listen("/", function() end)"#;
+    let config = |max| Config {
+      limits: Limits {
+        max_concurrent_requests: Some(max),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let concurrency = |service: &RunningService| service.upgrade().concurrency.clone().unwrap();
+
+    let source = Source::new(SingleSource::new(code));
+    (abel.cold_update_or_create_service("a", None, source, config(1)))
+      .await
+      .unwrap();
+    let stable = concurrency(&abel.inner.service_pool.get_running("a").unwrap());
+
+    // Shares the stable version's slots
+    let source = Source::new(SingleSource::new(code));
+    let (canary, _) =
+      (abel.canary_update_service("a", None, source, config(1), Default::default()))
+        .await
+        .unwrap();
+    assert!(Arc::ptr_eq(&concurrency(&canary), &stable));
+
+    // Keeps them if promoted with the same limit...
+    let (promoted, _) = abel.promote_canary("a").unwrap();
+    assert!(Arc::ptr_eq(&concurrency(&promoted), &stable));
+
+    // ...but gets its own if it configures another one
+    let source = Source::new(SingleSource::new(code));
+    (abel.canary_update_service("a", None, source, config(2), Default::default()))
+      .await
+      .unwrap();
+    let (promoted, _) = abel.promote_canary("a").unwrap();
+    let promoted = concurrency(&promoted);
+    assert!(!Arc::ptr_eq(&promoted, &stable));
+    assert!(service::Concurrency::enforces(
+      Some(&promoted),
+      &config(2).limits
+    ));
+  }
+
+  #[tokio::test]
+  async fn test_fault_status() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let code = r#"
+      abel.listen("/oom", function()
+        local t = {}
+        for i = 1, 1e8 do t[i] = i end
+      end)
+      abel.listen("/overflow", function()
+        local t = setmetatable({}, { __index = function(t, k) return t[k] end })
+        return t.x
+      end)
+    "#;
+    let config = Config {
+      limits: Limits {
+        memory_mb: Some(16),
+        ..Default::default()
+      },
+      ..Default::default()
+    };
+    let source = Source::new(SingleSource::new(code));
+    (abel.cold_update_or_create_service("a", None, source, config))
+      .await
+      .unwrap();
+
+    let run = |path: &str| {
+      let service = abel.inner.service_pool.get_running("a").unwrap();
+      let req = Request::get(path).body(Body::empty()).unwrap();
+      abel.run_service(service, path.into(), req)
+    };
+    let error = run("/oom").await.unwrap_err();
+    assert!(matches!(error.kind(), ErrorKind::MemoryLimitExceeded));
+    assert_eq!(error.kind().status(), 503);
+    assert!(error.kind().internal());
+    let error = run("/overflow").await.unwrap_err();
+    assert!(matches!(error.kind(), ErrorKind::ServiceFault {
+      fault: Fault::StackOverflow,
+      ..
+    }));
+    assert_eq!(error.kind().status(), 500);
+    // Reported like other internal errors
+    assert!(error.kind().internal());
+  }
+
+  #[tokio::test]
+  async fn test_middleware_routing() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let code = r#"
+      abel.use(function(req, next)
+        req.path = req.path:gsub("^/old/", "/new/")
+        return next()
+      end)
+      abel.use(function(req, next)
+        local wrapped = setmetatable({ wrapped = true }, { __index = req })
+        return next(wrapped)
+      end)
+      abel.listen("/new/:id", function(req)
+        return "new " .. req.params.id .. " at " .. req.path
+      end)
+      abel.listen("/:name", function(req)
+        return req.params.name .. (req.wrapped and " wrapped" or "")
+      end)
+    "#;
+    let source = Source::new(SingleSource::new(code));
+    (abel.cold_update_or_create_service("a", None, source, Default::default()))
+      .await
+      .unwrap();
+
+    for (path, expected) in [("/foo", "foo wrapped"), ("/old/1", "new 1 at /new/1")] {
+      let service = abel.inner.service_pool.get_running("a").unwrap();
+      let req = Request::get(path).body(Body::empty()).unwrap();
+      let resp = abel.run_service(service, path.into(), req).await.unwrap();
+      let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+      assert_eq!(body, expected);
+    }
+  }
+
+  #[tokio::test]
+  async fn test_detached_aborted_on_replace() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let code = r#"
+      abel.listen("/", function()
+        abel.spawn_detached(function()
+          while true do abel.sleep(1000) end
+        end)
+      end)
+    "#;
+    let spawn = |service: RunningService| async {
+      let req = Request::get("/").body(Body::empty()).unwrap();
+      abel.run_service(service, "/".into(), req).await.unwrap();
+    };
+
+    let source = Source::new(SingleSource::new(code));
+    (abel.cold_update_or_create_service("a", None, source, Default::default()))
+      .await
+      .unwrap();
+    spawn(abel.inner.service_pool.get_running("a").unwrap()).await;
+
+    let source = Source::new(SingleSource::new(code));
+    let (service, replaced) = (abel.hot_update_service("a", None, source, Default::default()))
+      .await
+      .unwrap();
+    spawn(service.clone()).await;
+
+    // The replaced version's task is gone, while the new one's is not
+    let pool = &abel.inner.runtime_pool;
+    assert_eq!(pool.abort_detached(replaced.uuid()), 0);
+    assert_eq!(pool.abort_detached(service.upgrade().uuid()), 1);
+  }
+
+  #[tokio::test]
+  async fn test_timeout() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let code = r#"
+      local cases = {
+        ok = function() return "done" end,
+        sleep = function() abel.sleep(1000) end,
+        busy = function() while true do end end,
+        caught = function()
+          while true do pcall(function() while true do end end) end
+        end,
+        nested = function()
+          return abel.timeout(1000, function() while true do end end)
+        end,
+        huge = function()
+          return abel.timeout(math.maxinteger, function() return "no deadline" end)
+        end,
+        sqlite = function()
+          db:query "WITH RECURSIVE c(x) AS (SELECT 1 UNION ALL SELECT x + 1 FROM c) SELECT count(*) FROM c"
+        end,
+        wait = function() return abel.wait_for("key", math.maxinteger) end,
+      }
+      abel.listen("/:case", function(req)
+        db = db or require("sqlite").open "local:test.db"
+        local _, result = pcall(abel.timeout, 50, cases[req.params.case])
+        -- The connection is free again
+        assert(db:query("SELECT 1 AS x")[1].x == 1)
+        return tostring(result)
+      end)
+    "#;
+    let source = Source::new(SingleSource::new(code));
+    (abel.cold_update_or_create_service("a", None, source, Default::default()))
+      .await
+      .unwrap();
+
+    for (case, expected) in [
+      ("ok", "done"),
+      ("sleep", "timed out after 50ms"),
+      ("busy", "timed out after 50ms"),
+      ("caught", "timed out after 50ms"),
+      ("nested", "timed out after 50ms"),
+      ("huge", "no deadline"),
+      ("sqlite", "timed out after 50ms"),
+      ("wait", ""),
+    ] {
+      let service = abel.inner.service_pool.get_running("a").unwrap();
+      let path = format!("/{case}");
+      let req = Request::get(&path).body(Body::empty()).unwrap();
+      let resp = abel.run_service(service, path, req).await.unwrap();
+      let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+      let body = String::from_utf8_lossy(&body);
+      assert!(body.contains(expected), "{case}: {body}");
+    }
+    // The wait gave up at the deadline instead of lingering until collected
+    assert!(abel.state.waiters.is_empty());
+  }
+}
\ No newline at end of file
@@ -0,0 +1,43 @@
+//! Compiled chunks shared between isolates, and between workers.
+
+use clru::CLruCache;
+use nonzero_ext::nonzero;
+use parking_lot::Mutex;
+use sha2::{Digest, Sha256};
+use std::num::NonZeroUsize;
+use std::sync::Arc;
+
+/// Chunks kept before the least recently used one is evicted.
+const DEFAULT_CAPACITY: NonZeroUsize = nonzero!(256usize);
+
+/// Path of a source file and the hash of its code.
+pub type ChunkKey = (Box<str>, [u8; 32]);
+
+/// Dumped bytecode of source files, keyed by their path and the hash of their
+/// code, so a changed file is never served stale bytecode.
+#[derive(Debug)]
+pub struct BytecodeCache(Mutex<CLruCache<ChunkKey, Arc<[u8]>>>);
+
+impl BytecodeCache {
+  pub fn new(capacity: NonZeroUsize) -> Self {
+    Self(Mutex::new(CLruCache::new(capacity)))
+  }
+
+  pub fn key(path: &str, code: &[u8]) -> ChunkKey {
+    (path.into(), Sha256::digest(code).into())
+  }
+
+  pub fn get(&self, key: &ChunkKey) -> Option<Arc<[u8]>> {
+    self.0.lock().get(key).cloned()
+  }
+
+  pub fn insert(&self, key: ChunkKey, bytecode: Vec<u8>) {
+    self.0.lock().put(key, bytecode.into());
+  }
+}
+
+impl Default for BytecodeCache {
+  fn default() -> Self {
+    Self::new(DEFAULT_CAPACITY)
+  }
+}
@@ -1,5 +1,5 @@
 local lua_error = error
-local handle_http_error, pcall = ...
+local handle_http_error, pcall, assert_arg = ...
 
 function error(msg, level)
   if type(msg) == "table" then
@@ -13,4 +13,32 @@ function assert(pred, msg)
   error(msg or "assertion failed!", 2)
 end
 
+-- Raises an HTTP error with `code` as its `error`, and `msg`, if any, as
+-- `detail.msg`.
+function fail(status, code, msg, detail)
+  if math.type(status) ~= "integer" or status < 400 or status > 599 then
+    lua_error("bad argument #1 to 'fail' (status must be an integer from 400 to 599)", 2)
+  end
+  if type(code) ~= "string" then
+    lua_error("bad argument #2 to 'fail' (string expected, got " .. type(code) .. ")", 2)
+  end
+  if msg ~= nil and type(msg) ~= "string" then
+    lua_error("bad argument #3 to 'fail' (string expected, got " .. type(msg) .. ")", 2)
+  end
+  if detail ~= nil and type(detail) ~= "table" then
+    lua_error("bad argument #4 to 'fail' (table expected, got " .. type(detail) .. ")", 2)
+  end
+
+  local new_detail
+  if detail or msg then
+    new_detail = {}
+    for k, v in pairs(detail or {}) do
+      new_detail[k] = v
+    end
+    new_detail.msg = msg
+  end
+  handle_http_error { status = status, error = code, detail = new_detail }
+end
+
 _G.pcall = pcall
+_G.assert_arg = assert_arg
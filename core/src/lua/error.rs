@@ -1,4 +1,4 @@
-use crate::task::TimeoutError;
+use crate::task::{DeadlineError, TimeoutError};
 use bstr::ByteSlice;
 use hyper::StatusCode;
 use mlua::Error::*;
@@ -56,10 +56,11 @@ pub fn resolve_callback_error(error: &mlua::Error) -> &mlua::Error {
 pub fn modify_global_error_handling(lua: &Lua) -> mlua::Result<()> {
   let handle_http_error = create_fn_handle_http_error(lua)?;
   let pcall = create_fn_pcall(lua)?;
+  let assert_arg = create_fn_assert_arg(lua)?;
   lua
     .load(include_str!("error.lua"))
     .set_name("@[error]")?
-    .call((handle_http_error, pcall))
+    .call((handle_http_error, pcall, assert_arg))
 }
 
 fn create_fn_handle_http_error(lua: &Lua) -> mlua::Result<Function> {
@@ -85,6 +86,53 @@ fn create_fn_handle_http_error(lua: &Lua) -> mlua::Result<Function> {
   })
 }
 
+/// Creates an error that becomes an HTTP error response, as if the Lua code
+/// called `error { status = status, error = error, detail = detail }`.
+pub fn http_error(
+  lua: &Lua,
+  status: StatusCode,
+  error: &str,
+  detail: serde_json::Value,
+) -> mlua::Error {
+  let source = (|| {
+    let table = lua.create_table()?;
+    table.raw_set("status", status.as_u16())?;
+    table.raw_set("error", error)?;
+    table.raw_set("detail", lua.to_value(&detail)?)?;
+    lua.create_registry_value(table)
+  })();
+  let error = CustomError {
+    status,
+    error: error.into(),
+    detail,
+    source: source.ok(),
+  };
+  error.to_lua_err()
+}
+
+/// `assert_arg(cond, pos, msg)`: raises a "bad argument" error on behalf of
+/// the calling function if `cond` is falsy, just like Lua's own functions.
+fn create_fn_assert_arg(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let cond = args.pop_front().unwrap_or(Nil);
+    let pos = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let msg: Option<mlua::String> =
+      check_value(lua, Some(args.pop_front().unwrap_or(Nil)), "string")
+        .map_err(tag_handler(lua, 3, 0))?;
+    if !matches!(cond, Nil | mlua::Value::Boolean(false)) {
+      return Ok(cond);
+    }
+    let pos = usize::try_from(pos).map_err(|_| arg_error(lua, 2, "out of range", 0))?;
+    let msg = msg.as_ref().map(|x| x.to_string_lossy());
+    Err(arg_error(
+      lua,
+      pos,
+      msg.as_deref().unwrap_or("assertion failed!"),
+      1,
+    ))
+  })
+}
+
 fn create_fn_pcall(lua: &Lua) -> mlua::Result<Function> {
   lua.create_async_function(|lua, args: MultiValue| async move {
     let (success, value): (bool, mlua::Value) = lua
@@ -96,7 +144,8 @@ fn create_fn_pcall(lua: &Lua) -> mlua::Result<Function> {
     } else {
       let value = if let mlua::Value::Error(error) = value {
         if let mlua::Error::ExternalError(ext) = resolve_callback_error(&error) {
-          if ext.is::<TimeoutError>() {
+          // Limits cannot be caught, or the code would run on past them
+          if ext.is::<TimeoutError>() || ext.is::<DeadlineError>() {
             return Err(error);
           }
           ext
@@ -21,6 +21,7 @@ pub struct IsolateBuilder<'lua> {
 
 impl<'lua> IsolateBuilder<'lua> {
   pub(super) fn new(lua: &'lua Lua, source: Source, remote: RemoteInterface) -> mlua::Result<Self> {
+    let remote = remote.with_source(source.clone());
     let (local_env, internal): (_, Table) = isolate_bootstrap(lua, source.clone(), remote)?;
     let preload = internal.raw_get_path("<internal>", &["package", "preload"])?;
     Ok(Self {
@@ -0,0 +1,61 @@
+local generation, record, clock = ...
+
+local cache = {}
+local entries = {}
+local seen_generation = generation()
+
+-- Drops everything if caches were flushed since we last looked
+local function check_generation()
+  local current = generation()
+  if current ~= seen_generation then
+    entries = {}
+    seen_generation = current
+  end
+end
+
+--- Returns the cached value of `key`, or calls `loader(key)` and caches its
+--- result if there is none or it is expired.
+---
+--- `nil` results are not cached. `options.ttl` is in seconds; without it,
+--- values stay until flushed.
+---
+--- @param key any
+--- @param loader fun(key: any): any
+--- @param options { ttl: number? }?
+--- @return any
+function cache.get(key, loader, options)
+  if key == nil then
+    error("bad argument #1 to 'get' (key expected, got nil)", 2)
+  end
+  if type(loader) ~= "function" then
+    error("bad argument #2 to 'get' (function expected, got " .. type(loader) .. ")", 2)
+  end
+  local ttl = options and options.ttl
+  if ttl ~= nil and type(ttl) ~= "number" then
+    error("bad field 'ttl' (number expected, got " .. type(ttl) .. ")", 2)
+  end
+
+  check_generation()
+  local entry = entries[key]
+  if entry and (not entry.expires or entry.expires > clock()) then
+    record(true)
+    return entry.value
+  end
+
+  record(false)
+  local value = loader(key)
+  if value ~= nil then
+    check_generation()
+    entries[key] = { value = value, expires = ttl and clock() + ttl }
+  end
+  return value
+end
+
+--- Removes `key` from this worker's cache.
+---
+--- @param key any
+function cache.delete(key)
+  entries[key] = nil
+end
+
+return cache
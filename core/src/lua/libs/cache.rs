@@ -0,0 +1,66 @@
+//! Memoizing values in each worker's isolate of a service.
+//!
+//! Entries live in Lua and are never shared between workers. What is shared
+//! is a generation counter: flushing bumps it, and every isolate drops its
+//! entries the next time it sees a new generation.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+#[derive(Debug, Default)]
+pub struct CacheState {
+  generation: AtomicU64,
+  hits: AtomicU64,
+  misses: AtomicU64,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct CacheStats {
+  pub hits: u64,
+  pub misses: u64,
+  /// Number of times the caches were flushed.
+  pub flushes: u64,
+}
+
+impl CacheState {
+  pub fn flush(&self) {
+    self.generation.fetch_add(1, Ordering::AcqRel);
+  }
+
+  pub fn stats(&self) -> CacheStats {
+    CacheStats {
+      hits: self.hits.load(Ordering::Relaxed),
+      misses: self.misses.load(Ordering::Relaxed),
+      flushes: self.generation.load(Ordering::Acquire),
+    }
+  }
+}
+
+pub fn create_preload_cache(
+  state: Arc<CacheState>,
+) -> impl FnOnce(&mlua::Lua) -> mlua::Result<mlua::Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let generation = {
+        let state = state.clone();
+        lua.create_function(move |_, ()| Ok(state.generation.load(Ordering::Acquire)))?
+      };
+      let record = {
+        let state = state.clone();
+        lua.create_function(move |_, hit: bool| {
+          let counter = if hit { &state.hits } else { &state.misses };
+          counter.fetch_add(1, Ordering::Relaxed);
+          Ok(())
+        })?
+      };
+      let start = Instant::now();
+      let clock = lua.create_function(move |_, ()| Ok(start.elapsed().as_secs_f64()))?;
+      lua
+        .load(include_str!("cache.lua"))
+        .set_name("@[cache]")?
+        .call::<_, mlua::Table>((generation, record, clock))
+    })
+  }
+}
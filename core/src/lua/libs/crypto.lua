@@ -0,0 +1,30 @@
+local crypto = ...
+
+-- Feeds a string or a stream of strings into the hasher
+local function feed(hasher, data, format)
+  local type_data = type(data)
+  if type_data == "string" then
+    hasher:write(data)
+  elseif (type_data == "table" or type_data == "userdata") and data.read then
+    while true do
+      local chunk = data:read()
+      if chunk == nil then break end
+      hasher:write(chunk)
+    end
+  else
+    error("bad argument to hash (string or stream expected, got " .. type_data .. ")", 3)
+  end
+  return hasher:finalize(format)
+end
+
+function crypto.sha256(data, format)
+  return feed(crypto.Sha256(), data, format)
+end
+
+function crypto.sha512(data, format)
+  return feed(crypto.Sha512(), data, format)
+end
+
+function crypto.hmac(algorithm, key, data, format)
+  return feed(crypto.Hmac(algorithm, key), data, format)
+end
@@ -1,8 +1,15 @@
-use crate::lua::error::{check_string, check_userdata_mut, rt_error, tag_handler};
+use crate::lua::error::{
+  arg_error, check_integer, check_string, check_userdata_mut, rt_error, tag_handler,
+};
 use crate::lua::LuaCacheExt;
-use data_encoding::HEXLOWER;
-use digest::Digest;
+use data_encoding::{BASE64, BASE64URL_NOPAD, HEXLOWER};
+use digest::{Digest, KeyInit};
+use hmac::{Hmac, Mac};
 use mlua::{Function, Lua, MultiValue, UserData};
+use openssl::hash::MessageDigest;
+use openssl::pkey::{Id, PKey};
+use openssl::sign::{Signer, Verifier};
+use rand::{thread_rng, RngCore};
 use sha2::{Sha224, Sha256, Sha384, Sha512, Sha512_224, Sha512_256};
 
 pub fn create_preload_crypto(lua: &Lua) -> mlua::Result<Function> {
@@ -14,13 +21,62 @@ pub fn create_preload_crypto(lua: &Lua) -> mlua::Result<Function> {
     crypto_table.raw_set("Sha512", create_digest_interface::<Sha512>(lua)?)?;
     crypto_table.raw_set("Sha512_224", create_digest_interface::<Sha512_224>(lua)?)?;
     crypto_table.raw_set("Sha512_256", create_digest_interface::<Sha512_256>(lua)?)?;
+    crypto_table.raw_set("Hmac", create_fn_hmac(lua)?)?;
+    crypto_table.raw_set("random_bytes", create_fn_random_bytes(lua)?)?;
+    crypto_table.raw_set("constant_time_eq", create_fn_constant_time_eq(lua)?)?;
+    crypto_table.raw_set("encode", create_fn_encode(lua)?)?;
+    crypto_table.raw_set("decode", create_fn_decode(lua)?)?;
+    crypto_table.raw_set("rsa_sign", create_fn_rsa_sign(lua)?)?;
+    crypto_table.raw_set("rsa_verify", create_fn_rsa_verify(lua)?)?;
+    lua
+      .load(include_str!("crypto.lua"))
+      .set_name("@[crypto]")?
+      .call::<_, ()>(crypto_table.clone())?;
     Ok(crypto_table)
   })
 }
 
-struct LuaHasher<H: Digest + 'static>(Option<H>);
+trait DynHasher {
+  fn update(&mut self, data: &[u8]);
+  fn finalize(self: Box<Self>) -> Vec<u8>;
+}
+
+struct DigestHasher<H: Digest>(H);
+
+impl<H: Digest> DynHasher for DigestHasher<H> {
+  fn update(&mut self, data: &[u8]) {
+    self.0.update(data)
+  }
+
+  fn finalize(self: Box<Self>) -> Vec<u8> {
+    self.0.finalize().to_vec()
+  }
+}
+
+struct MacHasher<M: Mac>(M);
+
+impl<M: Mac> MacHasher<M> {
+  fn new(key: &[u8]) -> Self
+  where
+    M: KeyInit,
+  {
+    Self(<M as KeyInit>::new_from_slice(key).expect("HMAC accepts keys of any length"))
+  }
+}
+
+impl<M: Mac> DynHasher for MacHasher<M> {
+  fn update(&mut self, data: &[u8]) {
+    self.0.update(data)
+  }
+
+  fn finalize(self: Box<Self>) -> Vec<u8> {
+    self.0.finalize().into_bytes().to_vec()
+  }
+}
+
+struct LuaHasher(Option<Box<dyn DynHasher>>);
 
-impl<H: Digest + 'static> UserData for LuaHasher<H> {
+impl UserData for LuaHasher {
   fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
     methods.add_function("write", |lua, mut args: MultiValue| {
       let mut this =
@@ -34,13 +90,12 @@ impl<H: Digest + 'static> UserData for LuaHasher<H> {
       }
     });
 
-    // TODO: output format
     methods.add_function("finalize", |lua, mut args: MultiValue| {
       let mut this =
         check_userdata_mut::<Self>(args.pop_front(), "hasher").map_err(tag_handler(lua, 1, 0))?;
+      let format = OutputFormat::from_lua(lua, args.pop_front(), 2)?;
       if let Some(inner) = this.with_borrowed_mut(|x| &mut x.0).take() {
-        let out = inner.finalize();
-        lua.create_string(&HEXLOWER.encode(&out))
+        format.encode(lua, &inner.finalize())
       } else {
         Err(rt_error("attempt to finalize a hasher after finalizing"))
       }
@@ -48,15 +103,186 @@ impl<H: Digest + 'static> UserData for LuaHasher<H> {
   }
 }
 
+enum OutputFormat {
+  Hex,
+  Base64,
+  Base64Url,
+  Raw,
+}
+
+impl OutputFormat {
+  fn from_lua(lua: &Lua, value: Option<mlua::Value>, pos: usize) -> mlua::Result<Self> {
+    let format = match value {
+      None | Some(mlua::Value::Nil) => return Ok(Self::Hex),
+      value => check_string(lua, value).map_err(tag_handler(lua, pos, 0))?,
+    };
+    match format.as_bytes() {
+      b"hex" => Ok(Self::Hex),
+      b"base64" => Ok(Self::Base64),
+      b"base64url" => Ok(Self::Base64Url),
+      b"raw" => Ok(Self::Raw),
+      _ => Err(arg_error(
+        lua,
+        pos,
+        "expected one of 'hex', 'base64', 'base64url' or 'raw'",
+        0,
+      )),
+    }
+  }
+
+  fn encode<'lua>(&self, lua: &'lua Lua, out: &[u8]) -> mlua::Result<mlua::String<'lua>> {
+    match self {
+      Self::Hex => lua.create_string(&HEXLOWER.encode(out)),
+      Self::Base64 => lua.create_string(&BASE64.encode(out)),
+      Self::Base64Url => lua.create_string(&BASE64URL_NOPAD.encode(out)),
+      Self::Raw => lua.create_string(out),
+    }
+  }
+
+  fn decode(&self, data: &[u8]) -> Option<Vec<u8>> {
+    match self {
+      Self::Hex => HEXLOWER.decode(&data.to_ascii_lowercase()).ok(),
+      Self::Base64 => BASE64.decode(data).ok(),
+      // Tolerate padding, which some encoders add anyway
+      Self::Base64Url => {
+        let end = data.iter().rposition(|&x| x != b'=').map_or(0, |x| x + 1);
+        BASE64URL_NOPAD.decode(&data[..end]).ok()
+      }
+      Self::Raw => Some(data.to_vec()),
+    }
+  }
+}
+
 fn create_digest_interface<H: Digest + 'static>(lua: &Lua) -> mlua::Result<Function> {
   lua.create_function(|lua, mut args: MultiValue| {
     if args.is_empty() {
-      lua.pack(LuaHasher(Some(H::new())))
+      lua.pack(LuaHasher(Some(Box::new(DigestHasher(H::new())))))
     } else {
       let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+      let format = OutputFormat::from_lua(lua, args.pop_front(), 2)?;
       let out = H::digest(data);
-      let out = lua.create_string(&HEXLOWER.encode(&out))?;
-      Ok(mlua::Value::String(out))
+      Ok(mlua::Value::String(format.encode(lua, &out)?))
+    }
+  })
+}
+
+fn create_fn_hmac(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let algorithm = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let key = key.as_bytes();
+    let hasher: Box<dyn DynHasher> = match algorithm.as_bytes() {
+      b"sha224" => Box::new(MacHasher::<Hmac<Sha224>>::new(key)),
+      b"sha256" => Box::new(MacHasher::<Hmac<Sha256>>::new(key)),
+      b"sha384" => Box::new(MacHasher::<Hmac<Sha384>>::new(key)),
+      b"sha512" => Box::new(MacHasher::<Hmac<Sha512>>::new(key)),
+      _ => {
+        return Err(arg_error(
+          lua,
+          1,
+          "expected one of 'sha224', 'sha256', 'sha384' or 'sha512'",
+          0,
+        ))
+      }
+    };
+    Ok(LuaHasher(Some(hasher)))
+  })
+}
+
+/// Most bytes `random_bytes` returns at once.
+const MAX_RANDOM_BYTES: i64 = 1024 * 1024;
+
+fn create_fn_random_bytes(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let n = check_integer(args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    if !(0..=MAX_RANDOM_BYTES).contains(&n) {
+      let msg = format!("expected integer between 0 and {MAX_RANDOM_BYTES}");
+      return Err(arg_error(lua, 1, &msg, 0));
+    }
+    let mut bytes = vec![0; n as usize];
+    thread_rng().fill_bytes(&mut bytes);
+    lua.create_string(&bytes)
+  })
+}
+
+/// Compares two strings in time depending only on their lengths.
+fn create_fn_constant_time_eq(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let a = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let b = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+      return Ok(false);
+    }
+    Ok(a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0)
+  })
+}
+
+fn create_fn_encode(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let format = OutputFormat::from_lua(lua, args.pop_front(), 2)?;
+    format.encode(lua, data.as_bytes())
+  })
+}
+
+/// Returns `nil` if `data` is not valid in the format.
+fn create_fn_decode(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+    let format = OutputFormat::from_lua(lua, args.pop_front(), 2)?;
+    (format.decode(data.as_bytes()))
+      .map(|x| lua.create_string(&x))
+      .transpose()
+  })
+}
+
+fn check_rsa_digest(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<MessageDigest> {
+  let algorithm = check_string(lua, value).map_err(tag_handler(lua, 1, 0))?;
+  match algorithm.as_bytes() {
+    b"sha256" => Ok(MessageDigest::sha256()),
+    b"sha384" => Ok(MessageDigest::sha384()),
+    b"sha512" => Ok(MessageDigest::sha512()),
+    _ => Err(arg_error(
+      lua,
+      1,
+      "expected one of 'sha256', 'sha384' or 'sha512'",
+      0,
+    )),
+  }
+}
+
+/// Signs with RSASSA-PKCS1-v1_5, returning the raw signature.
+fn create_fn_rsa_sign(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let digest = check_rsa_digest(lua, args.pop_front())?;
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 3, 0))?;
+    let key = PKey::private_key_from_pem(key.as_bytes())
+      .map_err(|_| arg_error(lua, 2, "invalid PEM private key", 0))?;
+    if key.id() != Id::RSA {
+      return Err(arg_error(lua, 2, "RSA key expected", 0));
+    }
+    let mut signer = Signer::new(digest, &key).map_err(rt_error)?;
+    signer.update(data.as_bytes()).map_err(rt_error)?;
+    lua.create_string(&signer.sign_to_vec().map_err(rt_error)?)
+  })
+}
+
+fn create_fn_rsa_verify(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_function(|lua, mut args: MultiValue| {
+    let digest = check_rsa_digest(lua, args.pop_front())?;
+    let key = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 0))?;
+    let data = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 3, 0))?;
+    let signature = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 4, 0))?;
+    let key = PKey::public_key_from_pem(key.as_bytes())
+      .map_err(|_| arg_error(lua, 2, "invalid PEM public key", 0))?;
+    if key.id() != Id::RSA {
+      return Err(arg_error(lua, 2, "RSA key expected", 0));
     }
+    let mut verifier = Verifier::new(digest, &key).map_err(rt_error)?;
+    verifier.update(data.as_bytes()).map_err(rt_error)?;
+    // Malformed signatures are just invalid
+    Ok(verifier.verify(signature.as_bytes()).unwrap_or(false))
   })
 }
@@ -6,15 +6,14 @@ use crate::lua::error::{
 use crate::lua::LuaCacheExt;
 use crate::path::normalize_path_str;
 use crate::source::{Metadata, ReadOnlyFile, Source};
+use crate::storage::LocalStorage;
 use crate::task::TaskContext;
 use bstr::ByteSlice;
 use mlua::Value::Nil;
 use mlua::{AnyUserData, Function, Lua, MultiValue, UserData, UserDataMethods};
 use pin_project::pin_project;
 use std::io::SeekFrom;
-use std::path::Path;
 use std::pin::Pin;
-use std::sync::Arc;
 use std::task::{Context, Poll};
 use tempfile::tempfile;
 use tokio::fs::{self, File, OpenOptions};
@@ -24,27 +23,29 @@ use tokio::io::{
 };
 use tokio::task::spawn_blocking;
 
-// Note that "lsp" stands for "local storage path".
 pub fn create_preload_fs(
   source: Source,
-  lsp: Arc<Path>,
+  storage: LocalStorage,
 ) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
   |lua| {
     lua.create_function(move |lua, ()| {
       let fs = lua.create_table()?;
-      fs.raw_set("open", create_fn_fs_open(lua, source.clone(), lsp.clone())?)?;
+      fs.raw_set(
+        "open",
+        create_fn_fs_open(lua, source.clone(), storage.clone())?,
+      )?;
       fs.raw_set("type", create_fn_fs_type(lua)?)?;
       fs.raw_set("tmpfile", create_fn_fs_tmpfile(lua)?)?;
-      fs.raw_set("mkdir", create_fn_fs_mkdir(lua, lsp.clone())?)?;
-      fs.raw_set("remove", create_fn_fs_remove(lua, lsp.clone())?)?;
-      fs.raw_set("rename", create_fn_fs_rename(lua, lsp.clone())?)?;
+      fs.raw_set("mkdir", create_fn_fs_mkdir(lua, storage.clone())?)?;
+      fs.raw_set("remove", create_fn_fs_remove(lua, storage.clone())?)?;
+      fs.raw_set("rename", create_fn_fs_rename(lua, storage.clone())?)?;
       fs.raw_set(
         "metadata",
-        create_fn_fs_metadata(lua, source.clone(), lsp.clone())?,
+        create_fn_fs_metadata(lua, source.clone(), storage.clone())?,
       )?;
       fs.raw_set(
         "exists",
-        create_fn_fs_exists(lua, source.clone(), lsp.clone())?,
+        create_fn_fs_exists(lua, source.clone(), storage.clone())?,
       )?;
       Ok(fs)
     })
@@ -124,7 +125,7 @@ impl ReadMode {
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
-enum Scheme {
+pub(super) enum Scheme {
   Local,
   Source,
 }
@@ -139,7 +140,7 @@ impl Scheme {
   }
 }
 
-fn parse_path<'a>(path: &'a mlua::String<'a>) -> mlua::Result<(Scheme, &'a str)> {
+pub(super) fn parse_path<'a>(path: &'a mlua::String<'a>) -> mlua::Result<(Scheme, &'a str)> {
   let path = path.as_bytes();
   let path =
     std::str::from_utf8(path).map_err(|_| rt_error_fmt!("invalid path: '{}'", path.as_bstr()))?;
@@ -149,7 +150,36 @@ fn parse_path<'a>(path: &'a mlua::String<'a>) -> mlua::Result<(Scheme, &'a str)>
     .unwrap_or(Ok((Scheme::Local, path)))
 }
 
-pub struct LuaFile(pub(crate) BufStream<GenericFile>);
+/// Opens a file for reading, with the same path rules as `fs.open`.
+pub(crate) async fn open_read(
+  source: &Source,
+  storage: &LocalStorage,
+  path: &mlua::String<'_>,
+) -> mlua::Result<io::Result<GenericFile>> {
+  let (scheme, path) = parse_path(path)?;
+  let result = match scheme {
+    Scheme::Local => {
+      async {
+        storage.fetch(path).await?;
+        let file = File::open(storage.disk_path(path)).await?;
+        if file.metadata().await?.is_dir() {
+          return Err(io::Error::from_raw_os_error(libc::EISDIR));
+        }
+        Ok(GenericFile::File(file))
+      }
+      .await
+    }
+    Scheme::Source => source.get(path).await.map(GenericFile::ReadOnly),
+  };
+  Ok(result)
+}
+
+/// An open file, and where it is uploaded to when closed, if written in
+/// remotely backed local storage.
+pub struct LuaFile(
+  pub(crate) BufStream<GenericFile>,
+  pub(crate) Option<(LocalStorage, String)>,
+);
 
 async fn read_once<'lua>(
   this: &mut LuaFile,
@@ -228,6 +258,9 @@ impl UserData for LuaFile {
     async fn close(_lua: &Lua, this: AnyUserData<'_>) -> mlua::Result<()> {
       if let Ok(mut this) = this.take::<LuaFile>() {
         this.0.flush().await.map_err(rt_error)?;
+        if let Some((storage, path)) = this.1 {
+          storage.upload(&path).await.map_err(rt_error)?;
+        }
       }
       Ok(())
     }
@@ -416,11 +449,15 @@ impl AsyncSeek for GenericFile {
   }
 }
 
-fn create_fn_fs_open(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Result<Function<'_>> {
+fn create_fn_fs_open(
+  lua: &Lua,
+  source: Source,
+  storage: LocalStorage,
+) -> mlua::Result<Function<'_>> {
   use OpenMode::*;
   lua.create_async_function(move |lua, mut args: MultiValue| {
     let source = source.clone();
-    let lsp = lsp.clone();
+    let storage = storage.clone();
     async move {
       let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
       let mode = args
@@ -432,12 +469,20 @@ fn create_fn_fs_open(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Result<
       let (scheme, path) = parse_path(&path)?;
       let mode = OpenMode::from_lua(mode)?;
 
+      let mut upload = None;
       let file = match scheme {
         Scheme::Local => {
-          let path = normalize_path_str(path);
+          // Truncated anyway
+          if !matches!(mode, Write | ReadWriteNew) {
+            storage.fetch(path).await.map_err(rt_error)?;
+          }
+          if mode != Read {
+            storage.create_parent(path).await.map_err(rt_error)?;
+            upload = Some((storage.clone(), path.to_string()));
+          }
           mode
             .to_open_options()
-            .open(lsp.join(path))
+            .open(storage.disk_path(path))
             .await
             .map(GenericFile::File)
             .map_err(rt_error)?
@@ -456,7 +501,8 @@ fn create_fn_fs_open(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Result<
         Write | Append => (0, 8192),
         _ => (8192, 8192),
       };
-      let file = lua.create_userdata(LuaFile(BufStream::with_capacity(rc, wc, file)))?;
+      let file = BufStream::with_capacity(rc, wc, file);
+      let file = lua.create_userdata(LuaFile(file, upload))?;
       TaskContext::register(lua, file.clone())?;
       Ok(file)
     }
@@ -482,21 +528,26 @@ fn create_fn_fs_tmpfile(lua: &Lua) -> mlua::Result<Function> {
     spawn_blocking(tempfile)
       .await
       .map_err(|x| rt_error_fmt!("background task failed: {x}"))?
-      .map(|file| LuaFile(BufStream::new(GenericFile::File(File::from_std(file)))))
+      .map(|file| {
+        LuaFile(
+          BufStream::new(GenericFile::File(File::from_std(file))),
+          None,
+        )
+      })
       .map_err(rt_error)
   })
 }
 
-fn create_fn_fs_mkdir(lua: &Lua, lsp: Arc<Path>) -> mlua::Result<Function> {
+fn create_fn_fs_mkdir(lua: &Lua, storage: LocalStorage) -> mlua::Result<Function> {
   lua.create_async_function(move |lua, mut args: MultiValue| {
-    let lsp = lsp.clone();
+    let storage = storage.clone();
     async move {
       let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
       let all = check_truthiness(args.pop_front());
 
       let (scheme, path) = parse_path(&path)?;
       let path = match scheme {
-        Scheme::Local => lsp.join(normalize_path_str(path)),
+        Scheme::Local => storage.disk_path(path),
         Scheme::Source => return Err(rt_error("cannot modify service source")),
       };
 
@@ -510,18 +561,20 @@ fn create_fn_fs_mkdir(lua: &Lua, lsp: Arc<Path>) -> mlua::Result<Function> {
   })
 }
 
-fn create_fn_fs_remove(lua: &Lua, lsp: Arc<Path>) -> mlua::Result<Function> {
+fn create_fn_fs_remove(lua: &Lua, storage: LocalStorage) -> mlua::Result<Function> {
   lua.create_async_function(move |lua, mut args: MultiValue| {
-    let lsp = lsp.clone();
+    let storage = storage.clone();
     async move {
       let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
       let all = check_truthiness(args.pop_front());
 
       let (scheme, path) = parse_path(&path)?;
-      let path = match scheme {
-        Scheme::Local => lsp.join(normalize_path_str(path)),
-        Scheme::Source => return Err(rt_error("cannot modify service source")),
-      };
+      if scheme == Scheme::Source {
+        return Err(rt_error("cannot modify service source"));
+      }
+      storage.fetch(path).await.map_err(rt_error)?;
+      storage.remove_remote(path).await.map_err(rt_error)?;
+      let path = storage.disk_path(path);
 
       let result = {
         let metadata = fs::metadata(&path).await?;
@@ -540,9 +593,9 @@ fn create_fn_fs_remove(lua: &Lua, lsp: Arc<Path>) -> mlua::Result<Function> {
   })
 }
 
-fn create_fn_fs_rename(lua: &Lua, lsp: Arc<Path>) -> mlua::Result<Function> {
+fn create_fn_fs_rename(lua: &Lua, storage: LocalStorage) -> mlua::Result<Function> {
   lua.create_async_function(move |lua, mut args: MultiValue| {
-    let lsp = lsp.clone();
+    let storage = storage.clone();
     async move {
       let from = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
       let to = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
@@ -550,9 +603,19 @@ fn create_fn_fs_rename(lua: &Lua, lsp: Arc<Path>) -> mlua::Result<Function> {
       let (to_scheme, to) = parse_path(&to)?;
 
       if from_scheme == Scheme::Local && to_scheme == Scheme::Local {
-        let from = lsp.join(normalize_path_str(from));
-        let to = lsp.join(normalize_path_str(to));
-        fs::rename(from, to).await.map_err(rt_error)
+        storage.fetch(from).await.map_err(rt_error)?;
+        let from_path = storage.disk_path(from);
+        if from_path.is_file() {
+          fs::rename(&from_path, storage.disk_path(to))
+            .await
+            .map_err(rt_error)?;
+          storage.upload(to).await.map_err(rt_error)?;
+          storage.remove_remote(from).await.map_err(rt_error)
+        } else {
+          fs::rename(from_path, storage.disk_path(to))
+            .await
+            .map_err(rt_error)
+        }
       } else {
         Err(rt_error("'rename' only works on local storage"))
       }
@@ -560,19 +623,23 @@ fn create_fn_fs_rename(lua: &Lua, lsp: Arc<Path>) -> mlua::Result<Function> {
   })
 }
 
-fn create_fn_fs_metadata(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Result<Function> {
+fn create_fn_fs_metadata(
+  lua: &Lua,
+  source: Source,
+  storage: LocalStorage,
+) -> mlua::Result<Function> {
   lua.create_async_function(move |lua, mut args: MultiValue| {
     let source = source.clone();
-    let lsp = lsp.clone();
+    let storage = storage.clone();
     async move {
       let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
       let (scheme, path) = parse_path(&path)?;
 
       let result = match scheme {
         Scheme::Local => {
-          let path = lsp.join(normalize_path_str(path));
           async {
-            let md = fs::metadata(path).await?;
+            storage.fetch(path).await?;
+            let md = fs::metadata(storage.disk_path(path)).await?;
             if md.is_dir() {
               Ok(Metadata::Dir)
             } else if md.is_file() {
@@ -603,16 +670,19 @@ fn create_fn_fs_metadata(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Res
   })
 }
 
-fn create_fn_fs_exists(lua: &Lua, source: Source, lsp: Arc<Path>) -> mlua::Result<Function> {
+fn create_fn_fs_exists(lua: &Lua, source: Source, storage: LocalStorage) -> mlua::Result<Function> {
   lua.create_async_function(move |lua, mut args: MultiValue| {
     let source = source.clone();
-    let lsp = lsp.clone();
+    let storage = storage.clone();
     async move {
       let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
       let (scheme, path) = parse_path(&path)?;
 
       match scheme {
-        Scheme::Local => Ok(lsp.join(normalize_path_str(path)).exists()),
+        Scheme::Local => {
+          storage.fetch(path).await.map_err(rt_error)?;
+          Ok(storage.disk_path(path).exists())
+        }
         Scheme::Source => source.exists(path).await.map_err(rt_error),
       }
     }
@@ -0,0 +1,244 @@
+//! Client-side load balancing across upstream replicas.
+//!
+//! Failures are detected passively: a target is marked down for
+//! `fail_timeout` seconds after `max_fails` consecutive connection errors or
+//! 502/503/504 responses. When the time is up, the target is checked by
+//! requesting `health` (if given) before it is picked again.
+
+use super::client::RequestOptions;
+use super::{check_request, send_request, LuaResponse};
+use crate::lua::error::{
+  bad_field, check_userdata, check_value, rt_error, rt_error_fmt, tag_handler, TableCheckExt,
+};
+use crate::lua::{LuaCacheExt, LUA_HTTP_CLIENT};
+use bstr::ByteSlice;
+use hyper::http::uri::{Parts, PathAndQuery};
+use hyper::{Body, Request, StatusCode, Uri};
+use log::warn;
+use mlua::{Function, Lua, MultiValue, Table, UserData, UserDataMethods};
+use rand::{thread_rng, Rng};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+const HEALTH_CHECK_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub fn create_fn_http_balancer(lua: &Lua) -> mlua::Result<Function<'_>> {
+  lua.create_cached_function("abel:http.balancer", |lua, mut args: MultiValue| {
+    let options: Table =
+      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 1))?;
+    Ok(LuaBalancer(Rc::new(Balancer::from_table(lua, options)?)))
+  })
+}
+
+enum Policy {
+  RoundRobin,
+  Random,
+}
+
+struct Target {
+  base: Uri,
+  fails: Cell<u32>,
+  down_until: Cell<Option<Instant>>,
+}
+
+struct Balancer {
+  targets: Vec<Target>,
+  policy: Policy,
+  health: Option<PathAndQuery>,
+  max_fails: u32,
+  fail_timeout: Duration,
+  next: Cell<usize>,
+}
+
+impl Balancer {
+  fn from_table(lua: &Lua, options: Table) -> mlua::Result<Self> {
+    let targets = (options.check_raw_get::<Table>(lua, "targets", "table")?)
+      .sequence_values::<mlua::String>()
+      .map(|x| {
+        let x = x?;
+        let base = Uri::try_from(x.as_bytes()).map_err(|error| {
+          bad_field(
+            "targets",
+            format!("invalid URI '{}' ({error})", x.to_string_lossy()),
+          )
+        })?;
+        if base.scheme().is_none() || base.authority().is_none() {
+          let msg = format!("'{}' is not an absolute URI", x.to_string_lossy());
+          return Err(bad_field("targets", msg));
+        }
+        Ok(Target {
+          base,
+          fails: Cell::new(0),
+          down_until: Cell::new(None),
+        })
+      })
+      .collect::<mlua::Result<Vec<_>>>()?;
+    if targets.is_empty() {
+      return Err(bad_field("targets", "at least one target expected"));
+    }
+
+    let policy = options.check_raw_get::<Option<mlua::String>>(lua, "policy", "string")?;
+    let policy = match policy.as_ref().map(|x| x.as_bytes()) {
+      None | Some(b"round_robin") => Policy::RoundRobin,
+      Some(b"random") => Policy::Random,
+      Some(x) => {
+        return Err(bad_field(
+          "policy",
+          format!("unknown policy '{}'", x.as_bstr()),
+        ))
+      }
+    };
+
+    let health = (options.check_raw_get::<Option<mlua::String>>(lua, "health", "string")?)
+      .map(|x| {
+        PathAndQuery::try_from(x.as_bytes()).map_err(|error| {
+          bad_field(
+            "health",
+            format!("invalid path '{}' ({error})", x.to_string_lossy()),
+          )
+        })
+      })
+      .transpose()?;
+
+    let max_fails = (options.check_raw_get::<Option<u32>>(lua, "max_fails", "integer")?)
+      .unwrap_or(1)
+      .max(1);
+
+    let fail_timeout =
+      (options.check_raw_get::<Option<f64>>(lua, "fail_timeout", "number")?).unwrap_or(10.);
+    if !(fail_timeout.is_finite() && fail_timeout >= 0.) {
+      return Err(bad_field("fail_timeout", "non-negative number expected"));
+    }
+    // Targets are marked down until `now + fail_timeout` later on, so leave
+    // plenty of room before that overflows
+    let fail_timeout = (Duration::try_from_secs_f64(fail_timeout).ok())
+      .filter(|x| {
+        (x.checked_mul(2))
+          .and_then(|x| Instant::now().checked_add(x))
+          .is_some()
+      })
+      .ok_or_else(|| bad_field("fail_timeout", "number too large"))?;
+
+    Ok(Self {
+      targets,
+      policy,
+      health,
+      max_fails,
+      fail_timeout,
+      next: Cell::new(0),
+    })
+  }
+
+  /// Picks an available target according to the policy, skipping ones that
+  /// are down.
+  async fn pick(&self) -> mlua::Result<&Target> {
+    let len = self.targets.len();
+    let start = match self.policy {
+      Policy::RoundRobin => {
+        let next = self.next.get();
+        self.next.set((next + 1) % len);
+        next
+      }
+      Policy::Random => thread_rng().gen_range(0..len),
+    };
+    for i in 0..len {
+      let target = &self.targets[(start + i) % len];
+      if self.is_available(target).await {
+        return Ok(target);
+      }
+    }
+    Err(rt_error("no healthy upstream target available"))
+  }
+
+  async fn is_available(&self, target: &Target) -> bool {
+    match target.down_until.get() {
+      None => true,
+      Some(until) if Instant::now() < until => false,
+      Some(_) => {
+        let healthy = match &self.health {
+          Some(health) => check_health(&target.base, health).await,
+          None => true,
+        };
+        target.down_until.set(if healthy {
+          None
+        } else {
+          Some(Instant::now() + self.fail_timeout)
+        });
+        healthy
+      }
+    }
+  }
+
+  fn record(&self, target: &Target, success: bool) {
+    if success {
+      target.fails.set(0);
+      return;
+    }
+    let fails = target.fails.get() + 1;
+    if fails < self.max_fails {
+      target.fails.set(fails);
+      return;
+    }
+    target.fails.set(0);
+    target
+      .down_until
+      .set(Some(Instant::now() + self.fail_timeout));
+    warn!("upstream target '{}' marked down", target.base);
+  }
+}
+
+async fn check_health(base: &Uri, health: &PathAndQuery) -> bool {
+  let uri = match join_uri(base, health.as_str()) {
+    Ok(uri) => uri,
+    Err(_) => return false,
+  };
+  let req = Request::get(uri).body(Body::empty()).unwrap();
+  let resp = tokio::time::timeout(HEALTH_CHECK_TIMEOUT, LUA_HTTP_CLIENT.request(req)).await;
+  matches!(resp, Ok(Ok(resp)) if resp.status().is_success())
+}
+
+/// Replaces `uri`'s scheme and authority with `base`'s, prefixing its path
+/// with `base`'s.
+fn join_uri(base: &Uri, path_and_query: &str) -> mlua::Result<Uri> {
+  let prefix = base.path().trim_end_matches('/');
+  let mut parts = Parts::default();
+  parts.scheme = base.scheme().cloned();
+  parts.authority = base.authority().cloned();
+  parts.path_and_query = Some(
+    PathAndQuery::try_from(format!("{prefix}{path_and_query}"))
+      .map_err(|error| rt_error_fmt!("invalid URI ({error})"))?,
+  );
+  Uri::from_parts(parts).map_err(rt_error)
+}
+
+fn is_failure(status: StatusCode) -> bool {
+  matches!(
+    status,
+    StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT
+  )
+}
+
+#[derive(Clone)]
+struct LuaBalancer(Rc<Balancer>);
+
+impl UserData for LuaBalancer {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_async_function("request", |lua, mut args: MultiValue| async move {
+      let this = check_userdata::<Self>(args.pop_front(), "balancer")
+        .map_err(tag_handler(lua, 1, 1))?
+        .with_borrowed(|x| x.0.clone());
+      let mut req = check_request(lua, args.pop_front(), 2)?;
+
+      let target = this.pick().await?;
+      let path_and_query = req.uri.path_and_query().map_or("/", |x| x.as_str());
+      req.uri = join_uri(&target.base, path_and_query)?;
+      let result = send_request(lua, req, &RequestOptions::new(lua)?).await;
+      this.record(
+        target,
+        matches!(&result, Ok(resp) if !is_failure(resp.status())),
+      );
+      result.map(LuaResponse::from_hyper)
+    });
+  }
+}
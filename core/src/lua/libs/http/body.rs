@@ -1,13 +1,13 @@
 use super::LuaResponse;
-use crate::lua::error::rt_error;
-use crate::lua::fs::LuaFile;
+use crate::lua::error::{rt_error, rt_error_fmt};
+use crate::lua::fs::{GenericFile, LuaFile};
 use crate::lua::stream::{is_stream, ByteStream};
 use crate::lua::LuaCacheExt;
 use crate::runtime::abel::{abel_spawn, create_fn_spawn, is_in_abel_context};
 use hyper::body::Bytes;
 use hyper::header::HeaderValue;
 use hyper::{Body, HeaderMap, StatusCode};
-use mlua::{AnyUserData, Lua, LuaSerdeExt, ToLua, UserData};
+use mlua::{AnyUserData, Function, Lua, LuaSerdeExt, ToLua, UserData};
 use std::cell::RefCell;
 use std::rc::Rc;
 
@@ -16,6 +16,8 @@ pub enum LuaBody {
   Json(serde_json::Value),
   Bytes(Vec<u8>),
   Stream(Body),
+  /// Seekable, so that byte ranges of it can be sent.
+  File(GenericFile),
 }
 
 impl LuaBody {
@@ -69,30 +71,49 @@ impl LuaBody {
   }
 }
 
-fn body_from_lua_stream(lua: &Lua, stream: mlua::Value) -> mlua::Result<LuaBody> {
-  struct LuaBodySender(hyper::body::Sender);
-
-  impl UserData for LuaBodySender {
-    fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
-      methods.add_meta_function("__close", |_lua, this: AnyUserData| {
-        let _ = this.take::<Self>();
-        Ok(())
-      });
-
-      #[allow(clippy::await_holding_refcell_ref)]
-      methods.add_async_function(
-        "send",
-        |_lua, (this, data): (AnyUserData, mlua::String)| async move {
-          let mut tx = this.borrow_mut::<Self>()?;
-          tx.0
-            .send_data(Bytes::copy_from_slice(data.as_bytes()))
-            .await
-            .map_err(rt_error)
-        },
-      );
-    }
+/// Sending half of a streaming body, exposed to Lua as a writer.
+struct LuaBodySender(hyper::body::Sender);
+
+impl UserData for LuaBodySender {
+  fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_meta_function("__close", |_lua, this: AnyUserData| {
+      let _ = this.take::<Self>();
+      Ok(())
+    });
+
+    #[allow(clippy::await_holding_refcell_ref)]
+    methods.add_async_function(
+      "write",
+      |lua, (this, data): (AnyUserData, mlua::Value)| async move {
+        let type_name = data.type_name();
+        let data = lua
+          .coerce_string(data)?
+          .ok_or_else(|| rt_error_fmt!("expected string to write, got {type_name}"))?;
+        let mut tx = this
+          .borrow_mut::<Self>()
+          .map_err(|_| rt_error("writer is closed"))?;
+        tx.0
+          .send_data(Bytes::copy_from_slice(data.as_bytes()))
+          .await
+          .map_err(rt_error)
+      },
+    );
+
+    methods.add_function("close", |_lua, this: AnyUserData| {
+      let _ = this.take::<Self>();
+      Ok(())
+    });
+
+    methods.add_function("abort", |_lua, this: AnyUserData| {
+      if let Ok(tx) = this.take::<Self>() {
+        tx.0.abort();
+      }
+      Ok(())
+    });
   }
+}
 
+fn body_from_lua_stream(lua: &Lua, stream: mlua::Value) -> mlua::Result<LuaBody> {
   if !is_in_abel_context(lua) {
     return Err(rt_error("cannot send stream outside Abel context"));
   }
@@ -107,13 +128,39 @@ fn body_from_lua_stream(lua: &Lua, stream: mlua::Value) -> mlua::Result<LuaBody>
           local bytes = st:read()
           if p then p:await() end
           if not bytes then break end
-          p = spawn(tx.send, tx, bytes)
+          p = spawn(tx.write, tx, bytes)
         end
       "#;
       lua.load(SRC).into_function()
     })?
     .bind((stream, LuaBodySender(tx), create_fn_spawn(lua)?))?;
-  let _ = abel_spawn(lua, f)?;
+  drop(abel_spawn(lua, f)?);
+
+  Ok(LuaBody::Stream(body))
+}
+
+/// Creates a streaming body whose content is produced by `f(writer)` running
+/// in the background.
+pub(super) fn body_from_lua_writer_fn(lua: &Lua, f: Function) -> mlua::Result<LuaBody> {
+  if !is_in_abel_context(lua) {
+    return Err(rt_error("cannot send stream outside Abel context"));
+  }
+
+  let (tx, body) = Body::channel();
+  let g = lua
+    .create_cached_value("abel:body_spawn_write", || {
+      const SRC: &str = r#"
+        local f, writer <close> = ...
+        local ok, err = pcall(f, writer)
+        if not ok then
+          writer:abort()
+          error(err, 0)
+        end
+      "#;
+      lua.load(SRC).into_function()
+    })?
+    .bind((f, LuaBodySender(tx)))?;
+  drop(abel_spawn(lua, g)?);
 
   Ok(LuaBody::Stream(body))
 }
@@ -131,6 +178,7 @@ impl From<LuaBody> for Body {
       LuaBody::Json(x) => x.to_string().into(),
       LuaBody::Bytes(x) => x.into(),
       LuaBody::Stream(x) => x,
+      LuaBody::File(x) => Body::wrap_stream(ByteStream::from_async_read(x).0),
     }
   }
 }
@@ -142,6 +190,7 @@ impl<'lua> ToLua<'lua> for LuaBody {
       Self::Json(x) => lua.to_value(&x),
       Self::Bytes(x) => Ok(mlua::Value::String(lua.create_string(&x)?)),
       Self::Stream(x) => lua.pack(ByteStream::from(x)),
+      Self::File(x) => lua.pack(ByteStream::from_async_read(x)),
     }
   }
 }
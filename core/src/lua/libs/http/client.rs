@@ -0,0 +1,290 @@
+//! Sending services' outbound requests, with timeouts, redirects and proxies.
+
+use crate::lua::error::{bad_field, rt_error, rt_error_fmt, TableCheckExt};
+use crate::lua::{LuaEither, LUA_HTTP_CLIENT};
+use crate::HttpClientConfig;
+use clru::CLruCache;
+use hyper::body::{Bytes, HttpBody};
+use hyper::client::HttpConnector;
+use hyper::header::{
+  AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE, COOKIE, HOST, LOCATION, PROXY_AUTHORIZATION,
+};
+use hyper::http::request::Parts;
+use hyper::{Body, Client, HeaderMap, Method, Request, Response, StatusCode, Uri};
+use hyper_proxy::{Intercept, Proxy, ProxyConnector};
+use hyper_tls::HttpsConnector;
+use mlua::{Lua, Table};
+use nonzero_ext::nonzero;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+use std::num::NonZeroUsize;
+use std::time::Duration;
+
+type ProxyClient = Client<ProxyConnector<HttpsConnector<HttpConnector>>>;
+
+/// Proxy clients kept before the least recently used one is dropped.
+///
+/// Services choose proxies per request, so the set of them is unbounded.
+const PROXY_CLIENTS_CAPACITY: NonZeroUsize = nonzero!(64usize);
+
+static PROXY_CLIENTS: Lazy<Mutex<CLruCache<Uri, ProxyClient>>> =
+  Lazy::new(|| Mutex::new(CLruCache::new(PROXY_CLIENTS_CAPACITY)));
+
+/// Connector proxy clients are cloned from, as setting up TLS is costly.
+static PROXY_CONNECTOR: Lazy<Result<ProxyConnector<HttpsConnector<HttpConnector>>, String>> =
+  Lazy::new(|| ProxyConnector::new(HttpsConnector::new()).map_err(|x| x.to_string()));
+
+/// Client sending requests through `proxy`, shared by requests using the
+/// same one so that connections to it are reused.
+fn proxy_client(proxy: &Uri) -> mlua::Result<ProxyClient> {
+  let mut clients = PROXY_CLIENTS.lock();
+  if let Some(client) = clients.get(proxy) {
+    return Ok(client.clone());
+  }
+  let mut connector = PROXY_CONNECTOR.clone().map_err(rt_error)?;
+  connector.add_proxy(Proxy::new(Intercept::All, proxy.clone()));
+  let client = Client::builder().build(connector);
+  clients.put(proxy.clone(), client.clone());
+  Ok(client)
+}
+
+/// Options of a single outbound request.
+pub(crate) struct RequestOptions {
+  timeout: Option<Duration>,
+  follow_redirects: u32,
+  proxy: Option<Uri>,
+}
+
+impl RequestOptions {
+  /// Server-wide defaults.
+  pub fn new(lua: &Lua) -> mlua::Result<Self> {
+    let config = lua.app_data_ref::<HttpClientConfig>();
+    let config = config.as_deref();
+    let proxy = (config.and_then(|x| x.proxy.as_deref()))
+      .map(|x| {
+        x.parse()
+          .map_err(|_| rt_error_fmt!("invalid default proxy URL: {x}"))
+      })
+      .transpose()?;
+    Ok(Self {
+      timeout: config.and_then(|x| x.timeout_ms).map(Duration::from_millis),
+      follow_redirects: 0,
+      proxy,
+    })
+  }
+
+  /// Reads `{ timeout, follow_redirects, proxy }`, falling back to server-wide
+  /// defaults. `proxy = false` disables the default proxy.
+  pub fn from_lua(lua: &Lua, options: Option<Table>) -> mlua::Result<Self> {
+    let mut result = Self::new(lua)?;
+    let options = match options {
+      Some(x) => x,
+      None => return Ok(result),
+    };
+    let timeout: Option<u64> = options.check_raw_get(lua, "timeout", "non-negative integer")?;
+    if let Some(timeout) = timeout {
+      result.timeout = Some(Duration::from_millis(timeout));
+    }
+    let follow_redirects: Option<u32> =
+      options.check_raw_get(lua, "follow_redirects", "non-negative integer")?;
+    result.follow_redirects = follow_redirects.unwrap_or(0);
+    let proxy: Option<LuaEither<mlua::String, bool>> =
+      options.check_raw_get(lua, "proxy", "string or false")?;
+    match proxy {
+      Some(LuaEither::Left(x)) => {
+        let uri = Uri::try_from(x.as_bytes()).map_err(|error| bad_field("proxy", error))?;
+        result.proxy = Some(uri);
+      }
+      Some(LuaEither::Right(false)) => result.proxy = None,
+      Some(LuaEither::Right(true)) => return Err(bad_field("proxy", "string or false expected")),
+      None => {}
+    }
+    Ok(result)
+  }
+}
+
+/// Sends `req`, following redirects and giving up after timeout as `options`
+/// says.
+///
+/// The timeout only covers waiting for the final response's headers, not
+/// reading its body.
+pub(crate) async fn send(
+  req: Request<Body>,
+  options: &RequestOptions,
+) -> mlua::Result<Response<Body>> {
+  let fut = send_following(req, options);
+  match options.timeout {
+    Some(timeout) => (tokio::time::timeout(timeout, fut).await)
+      .map_err(|_| rt_error_fmt!("request timed out after {}ms", timeout.as_millis()))?,
+    None => fut.await,
+  }
+}
+
+async fn send_once(req: Request<Body>, proxy: Option<&Uri>) -> mlua::Result<Response<Body>> {
+  let proxy = match proxy {
+    Some(x) => x,
+    None => return LUA_HTTP_CLIENT.request(req).await.map_err(rt_error),
+  };
+  let client = proxy_client(proxy)?;
+  client.request(req).await.map_err(rt_error)
+}
+
+/// Bodies already in memory can be sent again on 307 and 308 redirects;
+/// streaming ones can only be sent once.
+enum RedirectBody {
+  Buffered(Bytes),
+  Streaming(Option<Body>),
+}
+
+async fn send_following(
+  req: Request<Body>,
+  options: &RequestOptions,
+) -> mlua::Result<Response<Body>> {
+  if options.follow_redirects == 0 {
+    return send_once(req, options.proxy.as_ref()).await;
+  }
+
+  let (parts, body) = req.into_parts();
+  let Parts {
+    mut method,
+    mut uri,
+    mut headers,
+    version,
+    ..
+  } = parts;
+
+  let mut body = if body.size_hint().exact().is_some() {
+    let bytes = hyper::body::to_bytes(body).await.map_err(rt_error)?;
+    RedirectBody::Buffered(bytes)
+  } else {
+    RedirectBody::Streaming(Some(body))
+  };
+
+  let mut remaining = options.follow_redirects;
+  loop {
+    let this_body = match &mut body {
+      RedirectBody::Buffered(bytes) => Body::from(bytes.clone()),
+      RedirectBody::Streaming(x) => x.take().unwrap_or_else(Body::empty),
+    };
+    let mut req = Request::new(this_body);
+    *req.method_mut() = method.clone();
+    *req.uri_mut() = uri.clone();
+    *req.headers_mut() = headers.clone();
+    *req.version_mut() = version;
+    let resp = send_once(req, options.proxy.as_ref()).await?;
+
+    let status = resp.status();
+    let location = (resp.headers().get(LOCATION))
+      .and_then(|x| x.to_str().ok())
+      .and_then(|x| resolve_location(&uri, x));
+    let location = match location {
+      Some(x) if remaining > 0 => x,
+      _ => return Ok(resp),
+    };
+    // Like browsers do, some redirects are followed by a GET without body
+    let to_get = match status {
+      StatusCode::MOVED_PERMANENTLY | StatusCode::FOUND => method == Method::POST,
+      StatusCode::SEE_OTHER => method != Method::HEAD,
+      StatusCode::TEMPORARY_REDIRECT | StatusCode::PERMANENT_REDIRECT => false,
+      _ => return Ok(resp),
+    };
+    if to_get {
+      method = Method::GET;
+      body = RedirectBody::Buffered(Bytes::new());
+      headers.remove(CONTENT_TYPE);
+      headers.remove(CONTENT_LENGTH);
+    } else if let RedirectBody::Streaming(_) = body {
+      return Ok(resp);
+    }
+
+    remove_credentials(&mut headers, &uri, &location);
+    headers.remove(HOST);
+    uri = location;
+    remaining -= 1;
+  }
+}
+
+/// Removes credentials from `headers` when redirected from `from` to another
+/// origin, as they are only for the origin they were meant for.
+///
+/// `Proxy-Authorization` goes too, as HTTPS requests tunnelled through a proxy
+/// carry it to the origin itself.
+fn remove_credentials(headers: &mut HeaderMap, from: &Uri, to: &Uri) {
+  if to.scheme() != from.scheme() || to.authority() != from.authority() {
+    headers.remove(AUTHORIZATION);
+    headers.remove(PROXY_AUTHORIZATION);
+    headers.remove(COOKIE);
+  }
+}
+
+/// Resolves a redirect's `Location` against the URI it was sent from.
+fn resolve_location(base: &Uri, location: &str) -> Option<Uri> {
+  let scheme = base.scheme_str().unwrap_or("http");
+  let has_scheme = (location.split_once("://")).is_some_and(|(s, _)| {
+    !s.is_empty() && (s.chars()).all(|c| c.is_ascii_alphanumeric() || "+-.".contains(c))
+  });
+  let resolved = if has_scheme {
+    location.to_string()
+  } else if location.starts_with("//") {
+    format!("{scheme}:{location}")
+  } else {
+    let authority = base.authority()?;
+    if location.starts_with('/') {
+      format!("{scheme}://{authority}{location}")
+    } else {
+      let dir = &base.path()[..base.path().rfind('/').map_or(0, |x| x + 1)];
+      let dir = if dir.is_empty() { "/" } else { dir };
+      format!("{scheme}://{authority}{dir}{location}")
+    }
+  };
+  resolved.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("http://other.com/x" => "http://other.com/x")]
+  #[test_case("//other.com/x" => "https://other.com/x")]
+  #[test_case("/x?y=z" => "https://example.com/x?y=z")]
+  #[test_case("x" => "https://example.com/a/x")]
+  fn test_resolve_location(location: &str) -> String {
+    let base: Uri = "https://example.com/a/b?c=d".parse().unwrap();
+    resolve_location(&base, location).unwrap().to_string()
+  }
+
+  #[test]
+  fn test_remove_credentials() {
+    let credentials = || {
+      [AUTHORIZATION, PROXY_AUTHORIZATION, COOKIE]
+        .into_iter()
+        .map(|x| (x, "secret".parse().unwrap()))
+        .collect::<HeaderMap>()
+    };
+    let from: Uri = "https://example.com/a".parse().unwrap();
+
+    let mut headers = credentials();
+    remove_credentials(
+      &mut headers,
+      &from,
+      &"https://example.com/b".parse().unwrap(),
+    );
+    assert_eq!(headers.len(), 3);
+
+    for to in ["https://other.com/a", "http://example.com/a"] {
+      let mut headers = credentials();
+      remove_credentials(&mut headers, &from, &to.parse().unwrap());
+      assert!(headers.is_empty());
+    }
+  }
+
+  #[test]
+  fn test_proxy_clients_bounded() {
+    let capacity = PROXY_CLIENTS_CAPACITY.get();
+    for i in 0..capacity + 8 {
+      proxy_client(&format!("http://proxy-{i}:8080").parse().unwrap()).unwrap();
+    }
+    assert_eq!(PROXY_CLIENTS.lock().len(), capacity);
+  }
+}
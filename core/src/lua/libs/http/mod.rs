@@ -1,69 +1,139 @@
+mod balancer;
 mod body;
+mod client;
 mod header_map;
+mod multipart;
+mod range;
 mod request;
 mod response;
 mod uri;
 
+pub(crate) use body::LuaBody;
+pub(crate) use range::RangeRequest;
 pub use request::LuaRequest;
 pub use response::LuaResponse;
 pub(crate) use uri::LuaUri;
 
-use crate::lua::error::{arg_error, check_value, rt_error, rt_error_fmt, tag_error, tag_handler};
-use crate::lua::{LuaCacheExt, LuaEither, LUA_HTTP_CLIENT};
+use crate::lua::error::{arg_error, check_value, rt_error_fmt, tag_error, tag_handler};
+use crate::lua::{LuaCacheExt, LuaEither};
+use crate::source::Source;
+use crate::storage::LocalStorage;
+use crate::task::TaskContext;
+use balancer::create_fn_http_balancer;
 use bstr::ByteSlice;
+use client::RequestOptions;
 use hyper::header::{HeaderName, HeaderValue};
-use hyper::HeaderMap;
+use hyper::{Body, HeaderMap, Request, Response};
+use mlua::Value::Nil;
 use mlua::{AnyUserData, Function, Lua, MultiValue, Table};
-use response::create_fn_http_create_response;
+use multipart::create_fn_http_multipart;
+use response::create_http_response_table;
+use tracing::field::Empty;
+use tracing::{info_span, Instrument};
 use uri::create_fn_http_create_uri;
 
-pub fn create_preload_http(lua: &Lua) -> mlua::Result<Function> {
-  lua.create_cached_function("abel:preload_http", move |lua, ()| {
-    let http = lua.create_table()?;
-    http.raw_set("request", create_fn_http_request(lua)?)?;
-    http.raw_set("Response", create_fn_http_create_response(lua)?)?;
-    http.raw_set("Uri", create_fn_http_create_uri(lua)?)?;
-    Ok(http)
-  })
+const X_REQUEST_ID: &str = "x-request-id";
+
+pub fn create_preload_http(
+  source: Source,
+  storage: LocalStorage,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let http = lua.create_table()?;
+      http.raw_set("request", create_fn_http_request(lua)?)?;
+      http.raw_set(
+        "Response",
+        create_http_response_table(lua, source.clone(), storage.clone())?,
+      )?;
+      http.raw_set("Uri", create_fn_http_create_uri(lua)?)?;
+      http.raw_set("balancer", create_fn_http_balancer(lua)?)?;
+      http.raw_set("multipart", create_fn_http_multipart(lua)?)?;
+      Ok(http)
+    })
+  }
 }
 
-pub fn create_fn_http_request(lua: &Lua) -> mlua::Result<Function> {
-  fn check_request_first_arg(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<LuaRequest> {
-    use LuaEither::*;
-    type RequestMeta<'a> = LuaEither<LuaEither<mlua::String<'a>, Table<'a>>, AnyUserData<'a>>;
-    const EXPECTED: &str = "URI or request";
+/// Checks a request given as a URI string, a table, a request or a URI.
+fn check_request(lua: &Lua, value: Option<mlua::Value>, pos: usize) -> mlua::Result<LuaRequest> {
+  use LuaEither::*;
+  type RequestMeta<'a> = LuaEither<LuaEither<mlua::String<'a>, Table<'a>>, AnyUserData<'a>>;
+  const EXPECTED: &str = "URI or request";
 
-    let either =
-      check_value::<RequestMeta>(lua, value, EXPECTED).map_err(tag_handler(lua, 1, 1))?;
-    match either {
-      Left(Left(uri)) => Ok(LuaRequest {
-        uri: hyper::Uri::try_from(uri.as_bytes())
-          .map_err(|error| arg_error(lua, 1, &error.to_string(), 1))?,
-        ..Default::default()
-      }),
-      Left(Right(table)) => LuaRequest::from_table(lua, table),
-      Right(u) if u.is::<LuaRequest>() => LuaRequest::from_userdata(lua, u),
-      Right(u) if u.is::<LuaUri>() => Ok(LuaRequest {
-        uri: u.borrow::<LuaUri>()?.0.clone(),
-        ..Default::default()
-      }),
-      Right(_) => Err(tag_error(lua, 1, EXPECTED, "other userdata", 1)),
-    }
+  let either =
+    check_value::<RequestMeta>(lua, value, EXPECTED).map_err(tag_handler(lua, pos, 1))?;
+  match either {
+    Left(Left(uri)) => Ok(LuaRequest {
+      uri: hyper::Uri::try_from(uri.as_bytes())
+        .map_err(|error| arg_error(lua, pos, &error.to_string(), 1))?,
+      ..Default::default()
+    }),
+    Left(Right(table)) => LuaRequest::from_table(lua, table),
+    Right(u) if u.is::<LuaRequest>() => LuaRequest::from_userdata(lua, u),
+    Right(u) if u.is::<LuaUri>() => Ok(LuaRequest {
+      uri: u.borrow::<LuaUri>()?.0.clone(),
+      ..Default::default()
+    }),
+    Right(_) => Err(tag_error(lua, pos, EXPECTED, "other userdata", 1)),
   }
+}
 
+pub fn create_fn_http_request(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_async_function(
     "abel:http.request",
     move |lua, mut args: MultiValue| async move {
-      let req = check_request_first_arg(lua, args.pop_front())?;
-      LUA_HTTP_CLIENT
-        .request(req.into())
-        .await
-        .map(LuaResponse::from_hyper)
-        .map_err(rt_error)
+      let req = check_request(lua, args.pop_front(), 1)?;
+      let options: Option<Table> = check_value(lua, Some(args.pop_front().unwrap_or(Nil)), "table")
+        .map_err(tag_handler(lua, 2, 1))?;
+      let options = RequestOptions::from_lua(lua, options)?;
+      (send_request(lua, req, &options).await).map(LuaResponse::from_hyper)
     },
   )
 }
 
+/// Converts a request made by a service, passing on the ID of the request it
+/// is handling, so that services down the line can correlate their logs.
+fn outgoing_request(lua: &Lua, req: LuaRequest) -> Request<Body> {
+  let mut req: Request<Body> = req.into();
+  if let Some(id) = TaskContext::request_id(lua) {
+    let headers = req.headers_mut();
+    if let (false, Ok(value)) = (headers.contains_key(X_REQUEST_ID), id.parse()) {
+      headers.insert(X_REQUEST_ID, value);
+    }
+  }
+  req
+}
+
+/// Sends a request made by a service in a client span.
+async fn send_request(
+  lua: &Lua,
+  req: LuaRequest,
+  options: &RequestOptions,
+) -> mlua::Result<Response<Body>> {
+  let req = outgoing_request(lua, req);
+  let span = info_span!(
+    "http.request",
+    otel.kind = "client",
+    otel.status_code = Empty,
+    http.method = %req.method(),
+    http.url = %req.uri(),
+    http.status_code = Empty,
+  );
+  let result = client::send(req, options).instrument(span.clone()).await;
+  match &result {
+    Ok(resp) => {
+      span.record("http.status_code", resp.status().as_u16());
+      if resp.status().is_server_error() {
+        span.record("otel.status_code", "ERROR");
+      }
+    }
+    Err(_) => {
+      span.record("otel.status_code", "ERROR");
+    }
+  }
+  result
+}
+
 fn check_headers(lua: &Lua, headers_table: Table) -> mlua::Result<HeaderMap> {
   let mut headers = HeaderMap::new();
   for entry in headers_table.pairs::<mlua::Value, mlua::Value>() {
@@ -0,0 +1,126 @@
+//! Streaming `multipart/form-data` request bodies.
+
+use super::request::invalid_body;
+use super::LuaRequest;
+use crate::lua::error::{check_userdata_mut, check_value, rt_error, tag_error, tag_handler};
+use crate::lua::stream::{create_table_stream, ByteStream};
+use crate::lua::LuaCacheExt;
+use futures::StreamExt;
+use hyper::header::CONTENT_TYPE;
+use hyper::Body;
+use mlua::Value::Nil;
+use mlua::{AnyUserData, Function, Lua, MultiValue, UserData, UserDataFields, UserDataMethods};
+use multer::{Field, Multipart};
+use std::sync::{Arc, Mutex};
+use std::task::Poll;
+
+/// Field currently readable from its body stream. Emptied when the next field
+/// is read, since `multer` only allows one field at a time.
+type FieldSlot = Arc<Mutex<Option<Field<'static>>>>;
+
+/// `multipart:read() -> field?`, where `field` is a table of `name`,
+/// `filename`, `content_type` and `body`, a byte stream.
+///
+/// A field's body must be read before the next field; what is left unread is
+/// skipped.
+pub struct LuaMultipart {
+  multipart: Multipart<'static>,
+  current: Option<FieldSlot>,
+}
+
+impl LuaMultipart {
+  async fn next_field(&mut self) -> multer::Result<Option<Field<'static>>> {
+    if let Some(current) = self.current.take() {
+      let field = current.lock().unwrap().take();
+      if let Some(mut field) = field {
+        while field.chunk().await?.is_some() {}
+      }
+    }
+    self.multipart.next_field().await
+  }
+}
+
+fn field_body(slot: FieldSlot) -> ByteStream {
+  let stream = futures::stream::poll_fn(move |cx| match &mut *slot.lock().unwrap() {
+    Some(field) => (field.poll_next_unpin(cx)).map(|x| x.map(|x| x.map_err(rt_error))),
+    None => Poll::Ready(Some(Err(rt_error(
+      "attempt to read a skipped multipart field",
+    )))),
+  });
+  ByteStream(stream.boxed())
+}
+
+impl UserData for LuaMultipart {
+  fn add_fields<'lua, F: UserDataFields<'lua, Self>>(fields: &mut F) {
+    fields.add_meta_field_with("__index", create_table_stream);
+  }
+
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    methods.add_meta_function("__close", |_lua, this: AnyUserData| {
+      drop(this.take::<Self>());
+      Ok(())
+    });
+
+    methods.add_async_function("read", |lua, mut args: MultiValue| async move {
+      let mut this = check_userdata_mut::<Self>(args.pop_front(), "multipart")
+        .map_err(tag_handler(lua, 1, 1))?;
+      let field = (this.with_borrowed_mut(|x| x.next_field()).await)
+        .map_err(|error| invalid_body(lua, error))?;
+      let field = match field {
+        Some(x) => x,
+        None => return Ok(Nil),
+      };
+
+      let table = lua.create_table()?;
+      table.raw_set("name", field.name())?;
+      table.raw_set("filename", field.file_name())?;
+      table.raw_set(
+        "content_type",
+        field.content_type().map(ToString::to_string),
+      )?;
+      let slot = Arc::new(Mutex::new(Some(field)));
+      this.with_borrowed_mut(|x| x.current = Some(slot.clone()));
+      table.raw_set("body", field_body(slot))?;
+      Ok(mlua::Value::Table(table))
+    });
+  }
+}
+
+/// `http.multipart(req)`: parses the request's body as `multipart/form-data`
+/// while it is being received.
+pub fn create_fn_http_multipart(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.multipart", |lua, mut args: MultiValue| {
+    let this: AnyUserData =
+      check_value(lua, args.pop_front(), "request").map_err(tag_handler(lua, 1, 0))?;
+    if !this.is::<LuaRequest>() {
+      return Err(tag_error(lua, 1, "request", "other userdata", 0));
+    }
+    let (content_type, body) = {
+      let mut this_ = this.borrow_mut::<LuaRequest>()?;
+      let content_type = (this_.headers.borrow().get(CONTENT_TYPE))
+        .and_then(|x| x.to_str().ok())
+        .map(String::from);
+      (content_type, this_.body.take())
+    };
+    let boundary = multer::parse_boundary(content_type.unwrap_or_default())
+      .map_err(|error| invalid_body(lua, error))?;
+
+    let body = match body {
+      Some(body) => Body::from(body),
+      None => match this.get_named_user_value("body")? {
+        Nil => Body::empty(),
+        mlua::Value::String(s) => Body::from(s.as_bytes().to_vec()),
+        mlua::Value::UserData(u) if u.is::<ByteStream>() => {
+          let stream = (u.take::<ByteStream>())
+            .map_err(|_| rt_error("request body has already been read as a stream"))?;
+          Body::wrap_stream(stream.0)
+        }
+        _ => return Err(rt_error("request body is neither a string nor a stream")),
+      },
+    };
+    Ok(LuaMultipart {
+      multipart: Multipart::new(body, boundary),
+      current: None,
+    })
+  })
+}
@@ -0,0 +1,163 @@
+//! Byte-range requests (RFC 7233) for responses backed by files.
+
+use super::{LuaBody, LuaResponse};
+use crate::lua::error::rt_error;
+use crate::lua::stream::ByteStream;
+use hyper::header::{
+  HeaderValue, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, ETAG, IF_RANGE, LAST_MODIFIED, RANGE,
+};
+use hyper::{Body, HeaderMap, Method, Request, StatusCode};
+use std::io::SeekFrom;
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+
+/// Parts of a request deciding which range of a file is sent.
+pub(crate) struct RangeRequest {
+  range: Option<String>,
+  if_range: Option<String>,
+}
+
+impl RangeRequest {
+  pub fn from_request(req: &Request<Body>) -> Self {
+    let get = |name| {
+      (req
+        .headers()
+        .get(name)
+        .and_then(|x: &HeaderValue| x.to_str().ok()))
+      .map(String::from)
+    };
+    Self {
+      range: get(RANGE).filter(|_| req.method() == Method::GET),
+      if_range: get(IF_RANGE),
+    }
+  }
+
+  /// Advertises range support on successful file responses, and narrows
+  /// them to the requested range, if any.
+  ///
+  /// Ranges that cannot be parsed, and multiple ranges, are ignored and the
+  /// whole file is sent, as RFC 7233 allows.
+  pub async fn apply(self, mut resp: LuaResponse) -> mlua::Result<LuaResponse> {
+    if resp.status != StatusCode::OK {
+      return Ok(resp);
+    }
+    let mut file = match resp.body.take() {
+      Some(LuaBody::File(file)) => file,
+      body => {
+        resp.body = body;
+        return Ok(resp);
+      }
+    };
+    (resp.headers.borrow_mut()).insert(ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+
+    let range = match self.range {
+      Some(range) if if_range_matches(self.if_range.as_deref(), &resp.headers.borrow()) => range,
+      _ => {
+        resp.body = Some(LuaBody::File(file));
+        return Ok(resp);
+      }
+    };
+    let len = file.len().await.map_err(rt_error)?;
+    let (start, end) = match parse_range(&range, len) {
+      None => {
+        resp.body = Some(LuaBody::File(file));
+        return Ok(resp);
+      }
+      Some(None) => {
+        resp.status = StatusCode::RANGE_NOT_SATISFIABLE;
+        let mut headers = resp.headers.borrow_mut();
+        headers.remove(CONTENT_LENGTH);
+        headers.insert(CONTENT_RANGE, content_range(format!("*/{len}")));
+        drop(headers);
+        resp.body = Some(LuaBody::Empty);
+        return Ok(resp);
+      }
+      Some(Some(x)) => x,
+    };
+
+    file.seek(SeekFrom::Start(start)).await.map_err(rt_error)?;
+    let part = file.take(end - start + 1);
+    resp.status = StatusCode::PARTIAL_CONTENT;
+    let mut headers = resp.headers.borrow_mut();
+    headers.insert(CONTENT_LENGTH, (end - start + 1).into());
+    headers.insert(CONTENT_RANGE, content_range(format!("{start}-{end}/{len}")));
+    drop(headers);
+    resp.body = Some(LuaBody::Stream(Body::wrap_stream(
+      ByteStream::from_async_read(part).0,
+    )));
+    Ok(resp)
+  }
+}
+
+fn content_range(range: String) -> HeaderValue {
+  HeaderValue::try_from(format!("bytes {range}")).unwrap()
+}
+
+/// Whether the range should be sent: `If-Range` is absent, or matches the
+/// response's strong `ETag` or its `Last-Modified` exactly.
+fn if_range_matches(if_range: Option<&str>, headers: &HeaderMap) -> bool {
+  let if_range = match if_range {
+    Some(x) => x.trim(),
+    None => return true,
+  };
+  let get = |name| {
+    headers
+      .get(name)
+      .and_then(|x: &HeaderValue| x.to_str().ok())
+  };
+  if if_range.starts_with('"') {
+    get(ETAG).is_some_and(|etag| etag == if_range)
+  } else if if_range.starts_with("W/") {
+    false
+  } else {
+    get(LAST_MODIFIED).is_some_and(|x| x == if_range)
+  }
+}
+
+/// Parses a single byte range into inclusive bounds.
+///
+/// Returns `None` if the header should be ignored, and `Some(None)` if the
+/// range cannot be satisfied.
+fn parse_range(range: &str, len: u64) -> Option<Option<(u64, u64)>> {
+  let spec = range.trim().strip_prefix("bytes=")?.trim();
+  if spec.contains(',') {
+    return None;
+  }
+  let (start, end) = spec.split_once('-')?;
+  let (start, end) = (start.trim(), end.trim());
+  let result = if start.is_empty() {
+    let suffix: u64 = end.parse().ok()?;
+    (suffix > 0 && len > 0).then(|| (len.saturating_sub(suffix), len - 1))
+  } else {
+    let start: u64 = start.parse().ok()?;
+    let end = if end.is_empty() {
+      u64::MAX
+    } else {
+      end.parse().ok()?
+    };
+    if end < start {
+      return None;
+    }
+    (start < len).then(|| (start, end.min(len - 1)))
+  };
+  Some(result)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use test_case::test_case;
+
+  #[test_case("bytes=0-99" => Some(Some((0, 99))))]
+  #[test_case("bytes=100-" => Some(Some((100, 999))))]
+  #[test_case("bytes=-100" => Some(Some((900, 999))))]
+  #[test_case("bytes=-2000" => Some(Some((0, 999))))]
+  #[test_case("bytes=900-2000" => Some(Some((900, 999))))]
+  #[test_case("bytes=1000-" => Some(None))]
+  #[test_case("bytes=-0" => Some(None))]
+  #[test_case("bytes=5-1" => None)]
+  #[test_case("bytes=0-1,5-6" => None)]
+  #[test_case("items=0-1" => None)]
+  fn test_parse_range(range: &str) -> Option<Option<(u64, u64)>> {
+    parse_range(range, 1000)
+  }
+}
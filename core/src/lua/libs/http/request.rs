@@ -1,15 +1,27 @@
 use super::body::LuaBody;
 use super::header_map::LuaHeaderMap;
-use super::uri::LuaUri;
-use crate::lua::error::{bad_field, rt_error_fmt, TableCheckExt};
+use super::uri::{parse_query, LuaUri, QueryMap};
+use crate::lua::error::{
+  bad_field, check_value, http_error, rt_error, rt_error_fmt, tag_error, tag_handler, TableCheckExt,
+};
 use crate::lua::http::check_headers;
 use crate::path::Params;
 use crate::task::close_value;
+use crate::RequestId;
+use hyper::body::{Bytes, HttpBody};
+use hyper::header::CONTENT_TYPE;
 use hyper::http::request::Parts;
-use hyper::{Body, HeaderMap, Method, Request, Uri};
-use mlua::{AnyUserData, Lua, Table, UserData};
+use hyper::{Body, HeaderMap, Method, Request, StatusCode, Uri};
+use mlua::Value::Nil;
+use mlua::{AnyUserData, Lua, LuaSerdeExt, MultiValue, Table, UserData};
+use multer::Multipart;
+use serde_json::json;
 use std::cell::RefCell;
+use std::collections::HashMap;
+use std::convert::Infallible;
+use std::fmt::Display;
 use std::rc::Rc;
+use std::sync::Arc;
 
 pub struct LuaRequest {
   pub(crate) method: Method,
@@ -19,16 +31,23 @@ pub struct LuaRequest {
   pub(crate) body: Option<LuaBody>,
   /// Only used in Abel core
   pub(crate) params: Option<Params>,
+  /// Path within the service that routing matches, which middlewares may
+  /// rewrite. Only used in Abel core
+  pub(crate) path: Option<String>,
+  /// ID of an incoming request.
+  pub(crate) id: Option<Arc<str>>,
 }
 
 impl LuaRequest {
   #[rustfmt::skip]
-  pub fn new(req: Request<Body>, params: Params) -> Self {
-    let (Parts { method, uri, headers, .. }, body) = req.into_parts();
+  pub fn new(req: Request<Body>, path: String, params: Params) -> Self {
+    let (Parts { method, uri, headers, extensions, .. }, body) = req.into_parts();
     let headers = Rc::new(RefCell::new(headers));
     let body = Some(body.into());
     let params = Some(params);
-    Self { method, uri, headers, body, params }
+    let path = Some(path);
+    let id = extensions.get::<RequestId>().map(|x| x.0.clone());
+    Self { method, uri, headers, body, params, path, id }
   }
 
   pub fn from_table<'lua>(lua: &'lua Lua, table: Table<'lua>) -> mlua::Result<LuaRequest> {
@@ -86,6 +105,8 @@ impl Default for LuaRequest {
       headers: Default::default(),
       body: Some(LuaBody::Empty),
       params: None,
+      path: None,
+      id: None,
     }
   }
 }
@@ -112,8 +133,29 @@ impl UserData for LuaRequest {
         })
     });
 
+    fields.add_field_function_get("query", |lua, this| {
+      this
+        .get_named_user_value::<_, Table>("query")
+        .or_else(|_err| {
+          let this_ref = this.borrow::<Self>()?;
+          let query = this_ref.uri.query().unwrap_or_default();
+          let query = parse_query(lua, query.as_bytes())?;
+          this.set_named_user_value("query", query.clone())?;
+          Ok(query)
+        })
+    });
+
     fields.add_field_method_get("method", |lua, this| lua.pack(this.method.as_str()));
     fields.add_field_method_get("uri", |_lua, this| Ok(LuaUri(this.uri.clone())));
+    fields.add_field_method_get("id", |lua, this| lua.pack(this.id.as_deref()));
+
+    fields.add_field_method_get("path", |lua, this| {
+      lua.pack(this.path.as_deref().unwrap_or_else(|| this.uri.path()))
+    });
+    fields.add_field_method_set("path", |_lua, this, path: String| {
+      this.path = Some(path);
+      Ok(())
+    });
 
     fields.add_field_function_get("body", |lua, this| {
       let mut this_ = this.borrow_mut::<Self>()?;
@@ -133,6 +175,56 @@ impl UserData for LuaRequest {
   }
 
   fn add_methods<'lua, M: mlua::UserDataMethods<'lua, Self>>(methods: &mut M) {
+    // `req:decoded_body(options)`: reads the whole body and decodes it
+    // according to Content-Type. The raw bytes stay available in `req.body`.
+    methods.add_async_function("decoded_body", |lua, mut args: MultiValue| async move {
+      let this: AnyUserData =
+        check_value(lua, args.pop_front(), "request").map_err(tag_handler(lua, 1, 1))?;
+      if !this.is::<Self>() {
+        return Err(tag_error(lua, 1, "request", "other userdata", 1));
+      }
+      let options: Option<Table> = check_value(lua, Some(args.pop_front().unwrap_or(Nil)), "table")
+        .map_err(tag_handler(lua, 2, 1))?;
+      let max_size = options
+        .map(|x| x.check_raw_get::<Option<usize>>(lua, "max_size", "integer"))
+        .transpose()?
+        .flatten()
+        .unwrap_or(DEFAULT_MAX_DECODED_SIZE);
+
+      let decoded: mlua::Value = this.get_named_user_value("decoded_body")?;
+      if decoded != Nil {
+        return Ok(decoded);
+      }
+      let (content_type, body) = {
+        let mut this_ = this.borrow_mut::<Self>()?;
+        let content_type = (this_.headers.borrow().get(CONTENT_TYPE))
+          .and_then(|x| x.to_str().ok())
+          .map(String::from);
+        (content_type, this_.body.take())
+      };
+      let bytes = match body {
+        Some(LuaBody::Empty) => Bytes::new(),
+        Some(LuaBody::Json(x)) => x.to_string().into(),
+        Some(LuaBody::Bytes(x)) => x.into(),
+        Some(body @ (LuaBody::Stream(_) | LuaBody::File(_))) => {
+          read_body(lua, body.into(), max_size).await?
+        }
+        None => match this.get_named_user_value("body")? {
+          Nil => Bytes::new(),
+          mlua::Value::String(s) => Bytes::copy_from_slice(s.as_bytes()),
+          _ => return Err(rt_error("request body has already been read as a stream")),
+        },
+      };
+      this.set_named_user_value("body", lua.create_string(&bytes)?)?;
+      if bytes.len() > max_size {
+        return Err(body_too_large(lua, max_size));
+      }
+
+      let decoded = decode_body(lua, content_type.as_deref(), bytes).await?;
+      this.set_named_user_value("decoded_body", decoded.clone())?;
+      Ok(decoded)
+    });
+
     methods.add_meta_function("__close", |_lua, this: AnyUserData| {
       let _ = this.get_named_user_value("body").and_then(close_value);
       let _ = this.take::<Self>();
@@ -152,3 +244,113 @@ impl From<LuaRequest> for Request<Body> {
     builder.body(x.body.unwrap().into()).unwrap()
   }
 }
+
+/// Bodies larger than this are rejected by `decoded_body`, unless its
+/// `max_size` option says otherwise.
+const DEFAULT_MAX_DECODED_SIZE: usize = 1024 * 1024;
+
+fn body_too_large(lua: &Lua, max_size: usize) -> mlua::Error {
+  let msg = format!("request body is larger than {max_size} bytes");
+  http_error(
+    lua,
+    StatusCode::PAYLOAD_TOO_LARGE,
+    "request body too large",
+    json!({ "msg": msg }),
+  )
+}
+
+pub(super) fn invalid_body(lua: &Lua, msg: impl Display) -> mlua::Error {
+  let detail = json!({ "msg": msg.to_string() });
+  http_error(lua, StatusCode::BAD_REQUEST, "invalid request body", detail)
+}
+
+async fn read_body(lua: &Lua, mut body: Body, max_size: usize) -> mlua::Result<Bytes> {
+  if body.size_hint().lower() > max_size as u64 {
+    return Err(body_too_large(lua, max_size));
+  }
+  let mut buf = Vec::new();
+  while let Some(chunk) = body.data().await {
+    let chunk = chunk.map_err(|error| {
+      let detail = json!({ "msg": error.to_string() });
+      http_error(
+        lua,
+        StatusCode::BAD_REQUEST,
+        "failed to read request body",
+        detail,
+      )
+    })?;
+    if buf.len() + chunk.len() > max_size {
+      return Err(body_too_large(lua, max_size));
+    }
+    buf.extend_from_slice(&chunk);
+  }
+  Ok(buf.into())
+}
+
+/// JSON and form bodies become tables, and so do multipart ones, whose fields
+/// are strings, or tables of `filename`, `content_type` and `data` for files.
+/// Fields that appear more than once are collected into sequences. Other
+/// bodies are returned as strings.
+async fn decode_body<'lua>(
+  lua: &'lua Lua,
+  content_type: Option<&str>,
+  bytes: Bytes,
+) -> mlua::Result<mlua::Value<'lua>> {
+  if bytes.is_empty() {
+    return Ok(Nil);
+  }
+  let media = (content_type.and_then(|x| x.split(';').next()))
+    .map(|x| x.trim().to_ascii_lowercase())
+    .unwrap_or_default();
+
+  if media == "application/json" || media.ends_with("+json") {
+    let value: serde_json::Value = serde_json::from_slice(&bytes)
+      .map_err(|error| invalid_body(lua, format!("invalid JSON: {error}")))?;
+    lua.to_value(&value)
+  } else if media == "application/x-www-form-urlencoded" {
+    let form = serde_qs::from_bytes::<QueryMap>(&bytes)
+      .map_err(|error| invalid_body(lua, format!("invalid form: {error}")))?;
+    lua.pack(form)
+  } else if media == "multipart/form-data" {
+    let boundary = multer::parse_boundary(content_type.unwrap_or_default())
+      .map_err(|error| invalid_body(lua, error))?;
+    let stream = futures::stream::once(async move { Ok::<_, Infallible>(bytes) });
+    let mut multipart = Multipart::new(stream, boundary);
+
+    let mut fields = HashMap::<String, Vec<mlua::Value>>::new();
+    while let Some(field) =
+      (multipart.next_field().await).map_err(|error| invalid_body(lua, error))?
+    {
+      let name = field.name().unwrap_or_default().to_owned();
+      let filename = field.file_name().map(String::from);
+      let field_type = field.content_type().map(ToString::to_string);
+      let data = field
+        .bytes()
+        .await
+        .map_err(|error| invalid_body(lua, error))?;
+      let data = lua.create_string(&data)?;
+      let value = if let Some(filename) = filename {
+        let file = lua.create_table()?;
+        file.raw_set("filename", filename)?;
+        file.raw_set("content_type", field_type)?;
+        file.raw_set("data", data)?;
+        mlua::Value::Table(file)
+      } else {
+        mlua::Value::String(data)
+      };
+      fields.entry(name).or_default().push(value);
+    }
+
+    let table = lua.create_table()?;
+    for (name, mut values) in fields {
+      if values.len() == 1 {
+        table.raw_set(name, values.pop())?;
+      } else {
+        table.raw_set(name, lua.create_sequence_from(values)?)?;
+      }
+    }
+    Ok(mlua::Value::Table(table))
+  } else {
+    lua.pack(lua.create_string(&bytes)?)
+  }
+}
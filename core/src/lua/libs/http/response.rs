@@ -1,12 +1,22 @@
-use super::body::LuaBody;
+use super::body::{body_from_lua_writer_fn, LuaBody};
 use super::check_headers;
 use super::header_map::LuaHeaderMap;
-use crate::lua::error::{bad_field, check_value, rt_error_fmt, tag_handler, TableCheckExt};
+use crate::lua::error::{
+  arg_error, bad_field, check_string, check_value, http_error, rt_error, rt_error_fmt, tag_handler,
+  TableCheckExt,
+};
+use crate::lua::fs::open_read;
 use crate::lua::LuaCacheExt;
+use crate::source::Source;
+use crate::storage::LocalStorage;
+use hyper::header::{HeaderValue, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
 use hyper::http::{HeaderMap, StatusCode};
 use hyper::{Body, Response};
+use mlua::Value::Nil;
 use mlua::{FromLua, Function, Lua, MultiValue, Table, UserData, UserDataFields};
+use serde_json::json;
 use std::cell::RefCell;
+use std::io;
 use std::rc::Rc;
 
 #[derive(Default)]
@@ -25,6 +35,17 @@ impl LuaResponse {
       body: Some(body.into()),
     }
   }
+
+  /// Whether the body is known to be empty.
+  pub(crate) fn is_body_empty(&self) -> bool {
+    use hyper::body::HttpBody;
+    match &self.body {
+      None | Some(LuaBody::Empty) => true,
+      Some(LuaBody::Bytes(x)) => x.is_empty(),
+      Some(LuaBody::Stream(x)) => x.is_end_stream(),
+      Some(LuaBody::Json(_) | LuaBody::File(_)) => false,
+    }
+  }
 }
 
 impl UserData for LuaResponse {
@@ -93,26 +114,175 @@ impl From<LuaResponse> for Response<Body> {
   }
 }
 
-pub fn create_fn_http_create_response(lua: &Lua) -> mlua::Result<Function> {
-  lua.create_cached_function("abel:http.Response", |lua, mut args: MultiValue| {
-    let params: Table =
-      check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
-    let body = LuaBody::from_lua_with_error_msg(lua, params.raw_get::<_, mlua::Value>("body")?)?
-      .map_err(|error| bad_field("body", error))?;
-    let mut response = body.into_default_response();
-
-    // TODO: better error message for status code
-    let status: Option<u16> = params.check_raw_get(lua, "status", "16-bit integer")?;
-    if let Some(x) = status {
-      response.status =
-        StatusCode::from_u16(x).map_err(|_| rt_error_fmt!("invalid status code: {x}"))?;
-    }
+pub fn create_http_response_table(
+  lua: &Lua,
+  source: Source,
+  storage: LocalStorage,
+) -> mlua::Result<Table> {
+  let call =
+    lua.create_cached_function("abel:http.Response.__call", |lua, mut args: MultiValue| {
+      // Skip `Response` table itself
+      args.pop_front();
+      create_response(lua, args)
+    })?;
+  let metatable = lua.create_table_from([
+    ("__call", mlua::Value::Function(call)),
+    ("__metatable", mlua::Value::Boolean(false)),
+  ])?;
+  let response = lua.create_table_from([
+    ("stream", create_fn_http_response_stream(lua)?),
+    ("redirect", create_fn_http_response_redirect(lua)?),
+    ("json", create_fn_http_response_json(lua)?),
+    ("file", create_fn_http_response_file(lua, source, storage)?),
+  ])?;
+  response.set_metatable(Some(metatable));
+  Ok(response)
+}
+
+fn create_response(lua: &Lua, mut args: MultiValue) -> mlua::Result<LuaResponse> {
+  let params: Table =
+    check_value(lua, args.pop_front(), "table").map_err(tag_handler(lua, 1, 0))?;
+  let body = LuaBody::from_lua_with_error_msg(lua, params.raw_get::<_, mlua::Value>("body")?)?
+    .map_err(|error| bad_field("body", error))?;
+  let mut response = body.into_default_response();
+  apply_params(lua, &mut response, params)?;
+  Ok(response)
+}
+
+fn check_status(
+  lua: &Lua,
+  value: Option<mlua::Value>,
+  pos: usize,
+  level: usize,
+) -> mlua::Result<Option<StatusCode>> {
+  let status: Option<u16> = check_value(lua, Some(value.unwrap_or(Nil)), "integer")
+    .map_err(tag_handler(lua, pos, level))?;
+  (status.map(StatusCode::from_u16).transpose())
+    .map_err(|_| arg_error(lua, pos, "invalid status code", level))
+}
+
+fn apply_params(lua: &Lua, response: &mut LuaResponse, params: Table) -> mlua::Result<()> {
+  // TODO: better error message for status code
+  let status: Option<u16> = params.check_raw_get(lua, "status", "16-bit integer")?;
+  if let Some(x) = status {
+    response.status =
+      StatusCode::from_u16(x).map_err(|_| rt_error_fmt!("invalid status code: {x}"))?;
+  }
+
+  let headers_table: Option<Table> = params.check_raw_get(lua, "headers", "table")?;
+  if let Some(t) = headers_table {
+    response.headers.borrow_mut().extend(check_headers(lua, t)?)
+  }
+  Ok(())
+}
 
-    let headers_table: Option<Table> = params.check_raw_get(lua, "headers", "table")?;
-    if let Some(t) = headers_table {
-      response.headers.borrow_mut().extend(check_headers(lua, t)?)
+/// `Response.stream(f, params?)`
+///
+/// Calls `f(writer)` in the background, sending every chunk passed to
+/// `writer:write` to the client as soon as it is written.
+fn create_fn_http_response_stream(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.Response.stream", |lua, mut args: MultiValue| {
+    let f: Function =
+      check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 1, 1))?;
+    let params: Option<Table> = check_value(lua, Some(args.pop_front().unwrap_or(Nil)), "table")
+      .map_err(tag_handler(lua, 2, 1))?;
+    let mut response = body_from_lua_writer_fn(lua, f)?.into_default_response();
+    if let Some(params) = params {
+      apply_params(lua, &mut response, params)?;
     }
+    Ok(response)
+  })
+}
+
+/// `Response.redirect(url, status?)`
+///
+/// Redirects the client to `url`, with status 302 by default.
+fn create_fn_http_response_redirect(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function(
+    "abel:http.Response.redirect",
+    |lua, mut args: MultiValue| {
+      let url = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 0))?;
+      let location =
+        HeaderValue::from_bytes(url.as_bytes()).map_err(|_| arg_error(lua, 1, "invalid URL", 0))?;
+      let status = check_status(lua, args.pop_front(), 2, 0)?.unwrap_or(StatusCode::FOUND);
+      if !status.is_redirection() {
+        return Err(arg_error(lua, 2, "expected a 3xx status code", 0));
+      }
+      let response = LuaResponse {
+        status,
+        headers: Default::default(),
+        body: Some(LuaBody::Empty),
+      };
+      response.headers.borrow_mut().insert(LOCATION, location);
+      Ok(response)
+    },
+  )
+}
 
+/// `Response.json(value, status?)`
+///
+/// Sends `value` as JSON, even if it is a string or a number.
+fn create_fn_http_response_json(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_function("abel:http.Response.json", |lua, mut args: MultiValue| {
+    let value = args.pop_front().unwrap_or(Nil);
+    let value =
+      serde_json::to_value(&value).map_err(|error| arg_error(lua, 1, &error.to_string(), 0))?;
+    let mut response = LuaBody::Json(value).into_default_response();
+    if let Some(status) = check_status(lua, args.pop_front(), 2, 0)? {
+      response.status = status;
+    }
     Ok(response)
   })
 }
+
+/// `Response.file(path, params?)`
+///
+/// Streams a file from `source:` or local storage, with `content-type`
+/// guessed from its extension. Responds with 404 if the file does not exist.
+///
+/// Range requests for it are handled after the handler returns.
+fn create_fn_http_response_file(
+  lua: &Lua,
+  source: Source,
+  storage: LocalStorage,
+) -> mlua::Result<Function> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let source = source.clone();
+    let storage = storage.clone();
+    async move {
+      let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let params: Option<Table> = check_value(lua, Some(args.pop_front().unwrap_or(Nil)), "table")
+        .map_err(tag_handler(lua, 2, 1))?;
+
+      let path_str = path.to_string_lossy();
+      let mut file = match open_read(&source, &storage, &path).await? {
+        Ok(file) => file,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {
+          let detail = json!({ "path": path_str });
+          return Err(http_error(
+            lua,
+            StatusCode::NOT_FOUND,
+            "file not found",
+            detail,
+          ));
+        }
+        Err(error) => return Err(rt_error(error)),
+      };
+      let len = file.len().await.map_err(rt_error)?;
+      let content_type = mime_guess::from_path(&*path_str).first_or_octet_stream();
+
+      let mut response = LuaBody::File(file).into_default_response();
+      {
+        let mut headers = response.headers.borrow_mut();
+        if let Ok(value) = HeaderValue::from_str(content_type.as_ref()) {
+          headers.insert(CONTENT_TYPE, value);
+        }
+        headers.insert(CONTENT_LENGTH, len.into());
+      }
+      if let Some(params) = params {
+        apply_params(lua, &mut response, params)?;
+      }
+      Ok(response)
+    }
+  })
+}
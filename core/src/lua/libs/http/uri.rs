@@ -13,6 +13,134 @@ use std::collections::HashMap;
 #[derive(Debug)]
 pub struct LuaUri(pub(crate) Uri);
 
+/// Query strings and form bodies parsed with `serde_qs`.
+pub(super) type QueryMap<'a> = HashMap<Cow<'a, str>, QueryField<'a>>;
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub(super) enum QueryField<'a> {
+  Single(Cow<'a, str>),
+  Map(QueryMap<'a>),
+  Sequence(Vec<QueryField<'a>>),
+}
+
+impl<'a, 'lua> ToLua<'lua> for QueryField<'a> {
+  fn to_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
+    match self {
+      Self::Single(s) => lua.pack(s),
+      Self::Map(x) => lua.pack(x),
+      Self::Sequence(x) => lua.pack(x),
+    }
+  }
+}
+
+/// Parses a query string into a table, keeping values as raw bytes after
+/// percent-decoding.
+///
+/// Keys follow `serde_qs`: `a[b]=1` nests tables, and `a[]=1`, `a[0]=1`
+/// append to sequences. Unlike `serde_qs`, repeated keys collect their values
+/// into a sequence instead of failing.
+pub(super) fn parse_query<'lua>(lua: &'lua Lua, query: &[u8]) -> mlua::Result<Table<'lua>> {
+  let table = lua.create_table()?;
+  for pair in query.split(|&b| b == b'&').filter(|x| !x.is_empty()) {
+    let (key, value) = match pair.iter().position(|&b| b == b'=') {
+      Some(pos) => (&pair[..pos], &pair[pos + 1..]),
+      None => (pair, &b""[..]),
+    };
+    let key = percent_decode(key);
+    let value = lua.create_string(&percent_decode(value))?;
+    let (root, segments) = split_query_key(&key);
+    let root = mlua::Value::String(lua.create_string(root)?);
+    insert_query_value(lua, &table, root, &segments, value)?;
+  }
+  Ok(table)
+}
+
+fn percent_decode(s: &[u8]) -> Vec<u8> {
+  let hex = |b: u8| (b as char).to_digit(16).map(|x| x as u8);
+  let mut result = Vec::with_capacity(s.len());
+  let mut i = 0;
+  while i < s.len() {
+    match s[i] {
+      b'+' => result.push(b' '),
+      b'%' if i + 2 < s.len() => match (hex(s[i + 1]), hex(s[i + 2])) {
+        (Some(h), Some(l)) => {
+          result.push(h << 4 | l);
+          i += 2;
+        }
+        _ => result.push(b'%'),
+      },
+      b => result.push(b),
+    }
+    i += 1;
+  }
+  result
+}
+
+/// Splits `a[b][]` into `a` and `["b", ""]`. Keys with unbalanced brackets
+/// are used as a whole.
+fn split_query_key(key: &[u8]) -> (&[u8], Vec<&[u8]>) {
+  let start = match key.iter().position(|&b| b == b'[') {
+    Some(pos) if pos > 0 => pos,
+    _ => return (key, Vec::new()),
+  };
+  let mut segments = Vec::new();
+  let mut rest = &key[start..];
+  while let Some(x) = rest.strip_prefix(b"[") {
+    match x.iter().position(|&b| b == b']') {
+      Some(end) => {
+        segments.push(&x[..end]);
+        rest = &x[end + 1..];
+      }
+      None => return (key, Vec::new()),
+    }
+  }
+  if !rest.is_empty() {
+    return (key, Vec::new());
+  }
+  (&key[..start], segments)
+}
+
+fn insert_query_value<'lua>(
+  lua: &'lua Lua,
+  table: &Table<'lua>,
+  key: mlua::Value<'lua>,
+  segments: &[&[u8]],
+  value: mlua::String<'lua>,
+) -> mlua::Result<()> {
+  let existing: mlua::Value = table.raw_get(key.clone())?;
+  match segments.split_first() {
+    None => match existing {
+      mlua::Value::Nil => table.raw_set(key, value),
+      mlua::Value::String(s) => table.raw_set(key, lua.create_sequence_from([s, value])?),
+      mlua::Value::Table(t) => t.raw_set(t.raw_len() + 1, value),
+      _ => Ok(()),
+    },
+    Some((first, rest)) => {
+      let sub = match existing {
+        mlua::Value::Table(t) => t,
+        mlua::Value::Nil => {
+          let t = lua.create_table()?;
+          table.raw_set(key, t.clone())?;
+          t
+        }
+        // Conflicts with a value already there, e.g. `a=1&a[b]=2`
+        _ => return Ok(()),
+      };
+      // `[]` appends, and `[n]` is a zero-based index
+      let first = if first.is_empty() {
+        mlua::Value::Integer(sub.raw_len() + 1)
+      } else if let Some(i) = (std::str::from_utf8(first).ok()).and_then(|x| x.parse::<u32>().ok())
+      {
+        mlua::Value::Integer(i as i64 + 1)
+      } else {
+        mlua::Value::String(lua.create_string(first)?)
+      };
+      insert_query_value(lua, &sub, first, rest, value)
+    }
+  }
+}
+
 impl LuaUri {
   fn from_lua_parts(lua: &Lua, parts: Table) -> mlua::Result<Self> {
     let mut p = Parts::default();
@@ -95,32 +223,9 @@ impl UserData for LuaUri {
     methods.add_meta_method("__tostring", |_lua, this, ()| Ok(this.0.to_string()));
 
     methods.add_function("query", |lua, mut args: MultiValue| {
-      type QueryMap<'a> = HashMap<Cow<'a, str>, QueryField<'a>>;
-
-      #[derive(Deserialize)]
-      #[serde(untagged)]
-      enum QueryField<'a> {
-        Single(Cow<'a, str>),
-        Map(QueryMap<'a>),
-        Sequence(Vec<QueryField<'a>>),
-      }
-
-      impl<'a, 'lua> ToLua<'lua> for QueryField<'a> {
-        fn to_lua(self, lua: &'lua Lua) -> mlua::Result<mlua::Value<'lua>> {
-          match self {
-            Self::Single(s) => lua.pack(s),
-            Self::Map(x) => lua.pack(x),
-            Self::Sequence(x) => lua.pack(x),
-          }
-        }
-      }
-
       let this = check_userdata::<Self>(args.pop_front(), "URI").map_err(tag_handler(lua, 1, 0))?;
-      (this.borrow_borrowed().0.query())
-        .map(serde_qs::from_str::<QueryMap>)
-        .transpose()
-        .map(Option::unwrap_or_default)
-        .map_err(rt_error)
+      let query = this.borrow_borrowed().0.query().unwrap_or_default();
+      parse_query(lua, query.as_bytes())
     });
   }
 }
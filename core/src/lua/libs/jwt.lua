@@ -0,0 +1,160 @@
+local crypto = require "crypto"
+local json = require "json"
+
+local jwt = {}
+
+local hmac_digests = { HS256 = "sha256", HS384 = "sha384", HS512 = "sha512" }
+local rsa_digests = { RS256 = "sha256", RS384 = "sha384", RS512 = "sha512" }
+
+local function b64url(data)
+  return crypto.encode(data, "base64url")
+end
+
+local function sign_input(algorithm, key, input)
+  local hmac_digest = hmac_digests[algorithm]
+  if hmac_digest then
+    return crypto.hmac(hmac_digest, key, input, "raw")
+  end
+  local rsa_digest = rsa_digests[algorithm]
+  if rsa_digest then
+    return crypto.rsa_sign(rsa_digest, key, input)
+  end
+  error("unsupported algorithm '" .. tostring(algorithm) .. "'", 3)
+end
+
+local function verify_input(algorithm, key, input, signature)
+  local hmac_digest = hmac_digests[algorithm]
+  if hmac_digest then
+    return crypto.constant_time_eq(crypto.hmac(hmac_digest, key, input, "raw"), signature)
+  end
+  return crypto.rsa_verify(rsa_digests[algorithm], key, input, signature)
+end
+
+local function decode_part(part)
+  local raw = crypto.decode(part, "base64url")
+  if not raw then return nil end
+  local ok, value = pcall(json.parse, raw)
+  if ok and type(value) == "table" then return value end
+end
+
+local function split(token)
+  if type(token) ~= "string" then
+    error("bad argument #1 (string expected, got " .. type(token) .. ")", 3)
+  end
+  local header, claims, signature = token:match "^([^.]*)%.([^.]*)%.([^.]*)$"
+  if not header then return nil end
+  return header, claims, signature
+end
+
+--- Signs `claims` into a compact JWT.
+---
+--- `key` is the secret for `HS*` algorithms, or a PEM private key for `RS*`
+--- ones. `options.algorithm` defaults to `HS256`; `options.header` adds extra
+--- header fields such as `kid`.
+---
+--- @param claims table
+--- @param key string
+--- @param options { algorithm: string?, header: table? }?
+--- @return string
+function jwt.sign(claims, key, options)
+  options = options or {}
+  local algorithm = options.algorithm or "HS256"
+  local header = { typ = "JWT" }
+  for k, v in pairs(options.header or {}) do
+    header[k] = v
+  end
+  header.alg = algorithm
+
+  local input = b64url(json.stringify(header)) .. "." .. b64url(json.stringify(claims))
+  return input .. "." .. b64url(sign_input(algorithm, key, input))
+end
+
+--- Decodes `token` WITHOUT verifying it, e.g. to look at `kid` before
+--- choosing a key.
+---
+--- @param token string
+--- @return table? claims
+--- @return table|string header or error message
+function jwt.decode(token)
+  local header, claims = split(token)
+  header = header and decode_part(header)
+  claims = claims and decode_part(claims)
+  if not header or not claims then
+    return nil, "malformed token"
+  end
+  return claims, header
+end
+
+--- Verifies `token`'s signature and claims, returning the claims, or `nil`
+--- and the reason it is rejected.
+---
+--- Only algorithms in `options.algorithms` are accepted, defaulting to
+--- `{ "HS256" }`. Never mix `HS*` and `RS*` ones for the same key, or a
+--- public key could be used as an HMAC secret.
+---
+--- `exp` and `nbf` are checked with `options.leeway` seconds of tolerance
+--- (defaults to 0). `iss` and `aud` are checked if `options.issuer` and
+--- `options.audience` are given.
+---
+--- @param token string
+--- @param key string
+--- @param options { algorithms: string[]?, leeway: number?, issuer: string?, audience: string? }?
+--- @return table? claims
+--- @return string? error
+function jwt.verify(token, key, options)
+  options = options or {}
+  local header_part, claims_part, signature_part = split(token)
+  if not header_part then return nil, "malformed token" end
+  local header = decode_part(header_part)
+  local claims = decode_part(claims_part)
+  local signature = crypto.decode(signature_part, "base64url")
+  if not header or not claims or not signature then
+    return nil, "malformed token"
+  end
+
+  local algorithm = header.alg
+  local allowed = false
+  for _, v in ipairs(options.algorithms or { "HS256" }) do
+    if v == algorithm then
+      allowed = true
+      break
+    end
+  end
+  if not allowed or not (hmac_digests[algorithm] or rsa_digests[algorithm]) then
+    return nil, "algorithm '" .. tostring(algorithm) .. "' not allowed"
+  end
+  if not verify_input(algorithm, key, header_part .. "." .. claims_part, signature) then
+    return nil, "invalid signature"
+  end
+
+  local now = os.time()
+  local leeway = options.leeway or 0
+  if claims.exp ~= nil and (type(claims.exp) ~= "number" or now > claims.exp + leeway) then
+    return nil, "token expired"
+  end
+  if claims.nbf ~= nil and (type(claims.nbf) ~= "number" or now + leeway < claims.nbf) then
+    return nil, "token not yet valid"
+  end
+  if options.issuer and claims.iss ~= options.issuer then
+    return nil, "issuer mismatch"
+  end
+  if options.audience then
+    local aud = claims.aud
+    local matched = aud == options.audience
+    if type(aud) == "table" then
+      for _, v in ipairs(aud) do
+        if v == options.audience then
+          matched = true
+          break
+        end
+      end
+    end
+    if not matched then
+      return nil, "audience mismatch"
+    end
+  end
+
+  return claims
+end
+
+return jwt
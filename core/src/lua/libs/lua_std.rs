@@ -40,7 +40,9 @@ pub fn side_effect_global_whitelist(
   ])?;
 
   // Custom functions
-  apply_whitelist(globals, local_env, ["debug_fmt", "HttpError", "bind"])
+  apply_whitelist(globals, local_env, [
+    "debug_fmt", "HttpError", "bind", "assert_arg", "fail",
+  ])
 }
 
 create_whitelist_preloads! {
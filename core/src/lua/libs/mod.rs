@@ -1,7 +1,9 @@
+pub mod cache;
 pub mod crypto;
 pub mod fs;
 pub mod http;
 pub mod json;
 pub mod lua_std;
 pub mod rand;
+pub mod sqlite;
 pub mod stream;
@@ -0,0 +1,171 @@
+local crypto = require "crypto"
+local json = require "json"
+
+local pagination = {}
+
+local function b64url(data)
+  return crypto.encode(data, "base64url")
+end
+
+local function sign(secret, payload)
+  return crypto.hmac("sha256", secret, payload, "raw")
+end
+
+local function check_secret(secret, level)
+  if type(secret) ~= "string" or #secret == 0 then
+    error("cursors require a non-empty `secret` option", level + 1)
+  end
+end
+
+--- Encodes `value` into an opaque cursor signed with `secret`, so clients
+--- cannot forge or alter it.
+---
+--- @param value any JSON-serializable value, e.g. the last item's key
+--- @param secret string
+--- @return string
+function pagination.encode_cursor(value, secret)
+  check_secret(secret, 2)
+  local payload = b64url(json.stringify(value))
+  return payload .. "." .. b64url(sign(secret, payload))
+end
+
+--- Decodes a cursor made by `encode_cursor`, returning `nil` if it is
+--- malformed or its signature does not match.
+---
+--- @param cursor string
+--- @param secret string
+--- @return any?
+function pagination.decode_cursor(cursor, secret)
+  check_secret(secret, 2)
+  if type(cursor) ~= "string" then return nil end
+  local payload, signature = cursor:match "^([^.]+)%.([^.]+)$"
+  signature = signature and crypto.decode(signature, "base64url")
+  if not signature or not crypto.constant_time_eq(sign(secret, payload), signature) then
+    return nil
+  end
+  local raw = crypto.decode(payload, "base64url")
+  local ok, value = pcall(json.parse, raw)
+  if ok then return value end
+end
+
+local function parse_integer(query, name, min, max)
+  local value = query[name]
+  if value == nil then return nil end
+  local n = math.tointeger(tonumber(value))
+  if not n or n < min or (max and n > max) then
+    local range = max and ("from " .. min .. " to " .. max) or ("no less than " .. min)
+    fail(400, "invalid pagination", "'" .. name .. "' must be an integer " .. range)
+  end
+  return n
+end
+
+--- Reads `limit`, `offset` and `cursor` from the request's query.
+---
+--- `limit` defaults to `options.default` (20 if not given, capped to
+--- `options.max`, which defaults to 100). Invalid values fail with 400 Bad
+--- Request. If `cursor` is given, it is decoded with `options.secret` into
+--- `page.cursor`, and `offset` is ignored.
+---
+--- The returned page is passed to `pagination.links`.
+---
+--- @param req table request, or anything with `uri` and `query`
+--- @param options { max: integer?, default: integer?, secret: string? }?
+--- @return { limit: integer, offset: integer, cursor: any? }
+function pagination.parse(req, options)
+  options = options or {}
+  local max = options.max or 100
+  local query = req.query or {}
+
+  local page = {
+    limit = parse_integer(query, "limit", 1, max) or math.min(options.default or 20, max),
+    offset = 0,
+    uri = req.uri,
+    secret = options.secret,
+  }
+  local cursor = query.cursor
+  if cursor ~= nil then
+    check_secret(options.secret, 2)
+    page.cursor = pagination.decode_cursor(cursor, options.secret)
+    if page.cursor == nil then
+      fail(400, "invalid pagination", "invalid cursor")
+    end
+  else
+    page.offset = parse_integer(query, "offset", 0) or 0
+  end
+  return page
+end
+
+-- Request's path and query, with pagination parameters replaced by `params`
+local function page_link(page, params)
+  local parts = {}
+  for pair in string.gmatch(page.uri and page.uri.query_string or "", "[^&]+") do
+    local key = pair:match "^([^=]*)"
+    if key ~= "limit" and key ~= "offset" and key ~= "cursor" then
+      parts[#parts + 1] = pair
+    end
+  end
+  parts[#parts + 1] = "limit=" .. page.limit
+  for _, key in ipairs { "offset", "cursor" } do
+    if params[key] then
+      parts[#parts + 1] = key .. "=" .. params[key]
+    end
+  end
+  local path = page.uri and page.uri.path or ""
+  return "<" .. path .. "?" .. table.concat(parts, "&") .. ">"
+end
+
+--- Adds a `Link` header with `first`, `prev`, `next` and `last` pages, and
+--- `X-Total-Count`, to the response parameters `resp`, which is returned to
+--- be passed to `http.Response`.
+---
+--- With cursors, `total` may be `nil`, and `next_cursor` is the value encoded
+--- into the `next` link; no `next` link is added without it.
+---
+--- @param resp table response parameters
+--- @param total integer?
+--- @param page table returned by `pagination.parse`
+--- @param next_cursor any?
+--- @return table
+function pagination.links(resp, total, page, next_cursor)
+  if type(resp) ~= "table" then
+    error("bad argument #1 to 'links' (table expected, got " .. type(resp) .. ")", 2)
+  end
+  if type(page) ~= "table" or not page.limit then
+    error("bad argument #3 to 'links' (page expected, got " .. type(page) .. ")", 2)
+  end
+
+  local links = {}
+  local function add(rel, params)
+    links[#links + 1] = page_link(page, params) .. '; rel="' .. rel .. '"'
+  end
+  if next_cursor ~= nil then
+    add("next", { cursor = pagination.encode_cursor(next_cursor, page.secret) })
+  elseif page.cursor == nil then
+    local limit, offset = page.limit, page.offset
+    add("first", { offset = 0 })
+    if offset > 0 then
+      add("prev", { offset = math.max(offset - limit, 0) })
+    end
+    if not total or offset + limit < total then
+      add("next", { offset = offset + limit })
+    end
+    if total then
+      add("last", { offset = math.max(total - 1, 0) // limit * limit })
+    end
+  end
+
+  local headers = {}
+  for k, v in pairs(resp.headers or {}) do
+    headers[k] = v
+  end
+  if #links > 0 then
+    headers.link = table.concat(links, ", ")
+  end
+  if total then
+    headers["x-total-count"] = tostring(total)
+  end
+  resp.headers = headers
+  return resp
+end
+
+return pagination
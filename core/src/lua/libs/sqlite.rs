@@ -0,0 +1,384 @@
+use super::fs::{parse_path, Scheme};
+use crate::lua::error::{arg_error, check_string, check_userdata, rt_error, tag_handler};
+use crate::storage::LocalStorage;
+use crate::task::{DeadlineError, TaskContext};
+use mlua::Value::Nil;
+use mlua::{ExternalError, Function, Lua, MultiValue, Table, UserData, UserDataMethods};
+use parking_lot::Mutex;
+use rusqlite::hooks::{AuthAction, AuthContext, Authorization};
+use rusqlite::limits::Limit;
+use rusqlite::types::Value as SqlValue;
+use rusqlite::{Connection, ErrorCode, InterruptHandle, Statement};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tokio::task::spawn_blocking;
+
+pub fn create_preload_sqlite(storage: LocalStorage) -> impl FnOnce(&Lua) -> mlua::Result<Function> {
+  |lua| {
+    lua.create_function(move |lua, ()| {
+      let sqlite = lua.create_table()?;
+      sqlite.raw_set("open", create_fn_sqlite_open(lua, storage.clone())?)?;
+      Ok(sqlite)
+    })
+  }
+}
+
+fn create_fn_sqlite_open(lua: &Lua, storage: LocalStorage) -> mlua::Result<Function<'_>> {
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let storage = storage.clone();
+    async move {
+      let path = check_string(lua, args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let path = match parse_path(&path)? {
+        // Databases are written in place, which the remote backend would not
+        // see
+        (Scheme::Local, _) if storage.is_remote() => {
+          return Err(rt_error(
+            "cannot open database in remotely backed local storage",
+          ))
+        }
+        (Scheme::Local, path) => storage.disk_path(path),
+        (Scheme::Source, _) => return Err(rt_error("cannot open database in service source")),
+      };
+      let conn = spawn_blocking(move || open_confined(path))
+        .await
+        .map_err(rt_error)?
+        .map_err(rt_error)?;
+      Ok(LuaDatabase(SharedConnection::new(conn)))
+    }
+  })
+}
+
+/// Opens a database that cannot reach other files on the host: attaching
+/// databases, which `VACUUM INTO` also does, and loading extensions are
+/// denied. Since plain `VACUUM` attaches a temporary database too, it is not
+/// available either; `PRAGMA auto_vacuum` still is.
+fn open_confined(path: PathBuf) -> rusqlite::Result<Connection> {
+  let conn = Connection::open(path)?;
+  conn.set_limit(Limit::SQLITE_LIMIT_ATTACHED, 0);
+  conn.authorizer(Some(|ctx: AuthContext<'_>| match ctx.action {
+    AuthAction::Attach { .. } | AuthAction::Detach { .. } => Authorization::Deny,
+    AuthAction::Function { function_name }
+      if function_name.eq_ignore_ascii_case("load_extension") =>
+    {
+      Authorization::Deny
+    }
+    _ => Authorization::Allow,
+  }));
+  Ok(conn)
+}
+
+/// SQLite virtual machine instructions between checks of whether a query
+/// should stop.
+const PROGRESS_INTERVAL: i32 = 1000;
+
+#[derive(Clone)]
+struct SharedConnection {
+  conn: Arc<Mutex<Option<Connection>>>,
+  interrupt: Arc<InterruptHandle>,
+}
+
+impl SharedConnection {
+  fn new(conn: Connection) -> Self {
+    let interrupt = Arc::new(conn.get_interrupt_handle());
+    Self {
+      conn: Arc::new(Mutex::new(Some(conn))),
+      interrupt,
+    }
+  }
+}
+
+/// Runs `f` with the connection on the blocking thread pool.
+///
+/// Blocking calls cannot be cancelled like the task awaiting them, so SQLite
+/// checks every so often whether the innermost `abel.timeout` deadline has
+/// passed, raising the same error as Lua code would. The query is also
+/// interrupted if the call is dropped before it finishes.
+async fn with_conn<T: Send + 'static>(
+  lua: &Lua,
+  conn: SharedConnection,
+  f: impl FnOnce(&Connection) -> rusqlite::Result<T> + Send + 'static,
+) -> mlua::Result<T> {
+  let deadline =
+    TaskContext::get_current(lua).and_then(|x| x.deadlines.borrow().iter().min().copied());
+  let guard = InterruptGuard {
+    interrupt: conn.interrupt.clone(),
+    cancelled: Arc::new(AtomicBool::new(false)),
+    running: Arc::new(AtomicBool::new(false)),
+  };
+  let (cancelled, running) = (guard.cancelled.clone(), guard.running.clone());
+  let result = spawn_blocking(move || {
+    let conn = conn.conn.lock();
+    let conn = conn
+      .as_ref()
+      .ok_or_else(|| rt_error("attempt to use a closed database"))?;
+    if cancelled.load(Ordering::Relaxed) {
+      return Err(rt_error("cancelled"));
+    }
+    running.store(true, Ordering::Relaxed);
+    conn.progress_handler(
+      PROGRESS_INTERVAL,
+      Some(move || {
+        cancelled.load(Ordering::Relaxed) || deadline.is_some_and(|x| Instant::now() >= x)
+      }),
+    );
+    let result = f(conn);
+    conn.progress_handler(0, None::<fn() -> bool>);
+    running.store(false, Ordering::Relaxed);
+    match result {
+      Err(rusqlite::Error::SqliteFailure(error, _))
+        if error.code == ErrorCode::OperationInterrupted
+          && deadline.is_some_and(|x| Instant::now() >= x) =>
+      {
+        Err(DeadlineError(()).to_lua_err())
+      }
+      result => result.map_err(rt_error),
+    }
+  })
+  .await;
+  drop(guard);
+  result.map_err(rt_error)?
+}
+
+/// Stops the query of a `with_conn` call dropped before it finishes.
+struct InterruptGuard {
+  interrupt: Arc<InterruptHandle>,
+  cancelled: Arc<AtomicBool>,
+  running: Arc<AtomicBool>,
+}
+
+impl Drop for InterruptGuard {
+  fn drop(&mut self) {
+    self.cancelled.store(true, Ordering::Relaxed);
+    // Other calls may be using the connection otherwise
+    if self.running.load(Ordering::Relaxed) {
+      self.interrupt.interrupt();
+    }
+  }
+}
+
+/// Converts the rest of the arguments into statement parameters.
+///
+/// A single table is treated as either a list of positional parameters or a
+/// map of named ones (`{ id = 1 }` binds to `:id`, `@id` or `$id`); otherwise
+/// each argument is bound positionally.
+fn params_from_lua(lua: &Lua, args: MultiValue, start: usize) -> mlua::Result<Params> {
+  let mut args = args.into_vec();
+  if let [mlua::Value::Table(table)] = &*args {
+    let table = table.clone();
+    if table.raw_len() > 0 {
+      let values = (table.sequence_values::<mlua::Value>())
+        .enumerate()
+        .map(|(i, x)| value_from_lua(lua, x?, start + i))
+        .collect::<mlua::Result<_>>()?;
+      return Ok(Params::Positional(values));
+    }
+    let values = (table.pairs::<mlua::String, mlua::Value>())
+      .map(|x| {
+        let (k, v) = x?;
+        let k = k.to_str()?.to_string();
+        Ok((k, value_from_lua(lua, v, start)?))
+      })
+      .collect::<mlua::Result<_>>()?;
+    return Ok(Params::Named(values));
+  }
+  let values = (args.drain(..))
+    .enumerate()
+    .map(|(i, x)| value_from_lua(lua, x, start + i))
+    .collect::<mlua::Result<_>>()?;
+  Ok(Params::Positional(values))
+}
+
+fn value_from_lua(lua: &Lua, value: mlua::Value, pos: usize) -> mlua::Result<SqlValue> {
+  let result = match value {
+    Nil => SqlValue::Null,
+    mlua::Value::Boolean(b) => SqlValue::Integer(b as _),
+    mlua::Value::Integer(i) => SqlValue::Integer(i),
+    mlua::Value::Number(n) => SqlValue::Real(n),
+    mlua::Value::String(s) => match s.to_str() {
+      Ok(s) => SqlValue::Text(s.into()),
+      Err(_) => SqlValue::Blob(s.as_bytes().into()),
+    },
+    _ => {
+      let msg = format!("cannot bind {} value", value.type_name());
+      return Err(arg_error(lua, pos, &msg, 1));
+    }
+  };
+  Ok(result)
+}
+
+fn value_to_lua(lua: &Lua, value: SqlValue) -> mlua::Result<mlua::Value<'_>> {
+  let result = match value {
+    SqlValue::Null => Nil,
+    SqlValue::Integer(i) => mlua::Value::Integer(i),
+    SqlValue::Real(n) => mlua::Value::Number(n),
+    SqlValue::Text(s) => mlua::Value::String(lua.create_string(&s)?),
+    SqlValue::Blob(b) => mlua::Value::String(lua.create_string(&b)?),
+  };
+  Ok(result)
+}
+
+enum Params {
+  Positional(Vec<SqlValue>),
+  Named(Vec<(String, SqlValue)>),
+}
+
+impl Params {
+  fn bind(&self, stmt: &mut Statement) -> rusqlite::Result<()> {
+    match self {
+      Params::Positional(values) => {
+        for (i, value) in values.iter().enumerate() {
+          stmt.raw_bind_parameter(i + 1, value)?;
+        }
+      }
+      Params::Named(values) => {
+        for (name, value) in values {
+          let index = [":", "@", "$"]
+            .iter()
+            .find_map(|prefix| {
+              let name = format!("{prefix}{name}");
+              stmt.parameter_index(&name).transpose()
+            })
+            .transpose()?;
+          match index {
+            Some(index) => stmt.raw_bind_parameter(index, value)?,
+            None => return Err(rusqlite::Error::InvalidParameterName(name.clone())),
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+type Rows = (Vec<String>, Vec<Vec<SqlValue>>);
+
+fn execute(conn: &Connection, sql: &str, params: Params) -> rusqlite::Result<usize> {
+  let mut stmt = conn.prepare_cached(sql)?;
+  params.bind(&mut stmt)?;
+  stmt.raw_execute()
+}
+
+fn query(conn: &Connection, sql: &str, params: Params) -> rusqlite::Result<Rows> {
+  let mut stmt = conn.prepare_cached(sql)?;
+  params.bind(&mut stmt)?;
+  let columns = (stmt.column_names().into_iter())
+    .map(String::from)
+    .collect::<Vec<_>>();
+  let len = columns.len();
+  let mut rows = stmt.raw_query();
+  let mut result = Vec::new();
+  while let Some(row) = rows.next()? {
+    let row = (0..len)
+      .map(|i| row.get::<_, SqlValue>(i))
+      .collect::<rusqlite::Result<_>>()?;
+    result.push(row);
+  }
+  Ok((columns, result))
+}
+
+fn rows_to_lua(lua: &Lua, (columns, rows): Rows) -> mlua::Result<Table<'_>> {
+  let result = lua.create_table_with_capacity(rows.len() as _, 0)?;
+  for (i, row) in rows.into_iter().enumerate() {
+    let row_table = lua.create_table_with_capacity(0, columns.len() as _)?;
+    for (column, value) in columns.iter().zip(row) {
+      row_table.raw_set(&**column, value_to_lua(lua, value)?)?;
+    }
+    result.raw_set(i + 1, row_table)?;
+  }
+  Ok(result)
+}
+
+struct LuaDatabase(SharedConnection);
+
+impl UserData for LuaDatabase {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<SharedConnection> {
+      let this =
+        check_userdata::<LuaDatabase>(value, "database").map_err(tag_handler(lua, 1, 1))?;
+      Ok(this.with_borrowed(|x| x.0.clone()))
+    }
+
+    fn check_sql(lua: &Lua, value: Option<mlua::Value>) -> mlua::Result<String> {
+      let sql = check_string(lua, value).map_err(tag_handler(lua, 2, 1))?;
+      Ok(sql.to_str()?.into())
+    }
+
+    methods.add_async_function("exec", |lua, mut args: MultiValue| async move {
+      let conn = check_self(lua, args.pop_front())?;
+      let sql = check_sql(lua, args.pop_front())?;
+      let params = params_from_lua(lua, args, 3)?;
+      with_conn(lua, conn, move |conn| execute(conn, &sql, params)).await
+    });
+
+    methods.add_async_function("query", |lua, mut args: MultiValue| async move {
+      let conn = check_self(lua, args.pop_front())?;
+      let sql = check_sql(lua, args.pop_front())?;
+      let params = params_from_lua(lua, args, 3)?;
+      let rows = with_conn(lua, conn, move |conn| query(conn, &sql, params)).await?;
+      rows_to_lua(lua, rows)
+    });
+
+    methods.add_async_function("prepare", |lua, mut args: MultiValue| async move {
+      let conn = check_self(lua, args.pop_front())?;
+      let sql: Arc<str> = check_sql(lua, args.pop_front())?.into();
+      let sql2 = sql.clone();
+      with_conn(lua, conn.clone(), move |conn| {
+        conn.prepare_cached(&sql2)?;
+        Ok(())
+      })
+      .await?;
+      Ok(LuaStatement { conn, sql })
+    });
+
+    fn close(lua: &Lua, mut args: MultiValue) -> mlua::Result<()> {
+      let conn = check_self(lua, args.pop_front())?;
+      if let Some(conn) = conn.conn.lock().take() {
+        conn.close().map_err(|(_, error)| rt_error(error))?;
+      }
+      Ok(())
+    }
+
+    methods.add_function("close", close);
+    methods.add_meta_function("__close", close);
+  }
+}
+
+/// A statement bound to a database. The compiled statement itself lives in
+/// the connection's statement cache.
+struct LuaStatement {
+  conn: SharedConnection,
+  sql: Arc<str>,
+}
+
+impl UserData for LuaStatement {
+  fn add_methods<'lua, M: UserDataMethods<'lua, Self>>(methods: &mut M) {
+    fn check_self(
+      lua: &Lua,
+      value: Option<mlua::Value>,
+    ) -> mlua::Result<(SharedConnection, Arc<str>)> {
+      let this =
+        check_userdata::<LuaStatement>(value, "statement").map_err(tag_handler(lua, 1, 1))?;
+      Ok(this.with_borrowed(|x| (x.conn.clone(), x.sql.clone())))
+    }
+
+    methods.add_async_function("exec", |lua, mut args: MultiValue| async move {
+      let (conn, sql) = check_self(lua, args.pop_front())?;
+      let params = params_from_lua(lua, args, 2)?;
+      with_conn(lua, conn, move |conn| execute(conn, &sql, params)).await
+    });
+
+    methods.add_async_function("query", |lua, mut args: MultiValue| async move {
+      let (conn, sql) = check_self(lua, args.pop_front())?;
+      let params = params_from_lua(lua, args, 2)?;
+      let rows = with_conn(lua, conn, move |conn| query(conn, &sql, params)).await?;
+      rows_to_lua(lua, rows)
+    });
+
+    methods.add_meta_function("__tostring", |lua, mut args: MultiValue| {
+      let (_, sql) = check_self(lua, args.pop_front())?;
+      Ok(format!("statement: {sql}"))
+    });
+  }
+}
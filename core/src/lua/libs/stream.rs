@@ -1,5 +1,7 @@
 use super::json::create_fn_json_parse;
-use crate::lua::error::{check_userdata_mut, rt_error, tag_handler};
+use crate::lua::error::{
+  arg_error, check_integer, check_userdata_mut, check_value, rt_error, tag_handler,
+};
 use crate::lua::LuaCacheExt;
 use futures::stream::BoxStream;
 use futures::{StreamExt, TryStreamExt};
@@ -43,6 +45,9 @@ pub(crate) fn create_table_stream(lua: &Lua) -> mlua::Result<mlua::Table> {
   })
 }
 
+/// Default upper bound of chunk size for `byte_stream:stream()`.
+const DEFAULT_MAX_CHUNK: usize = 64 * 1024;
+
 pub struct ByteStream(pub(crate) BoxStream<'static, mlua::Result<Bytes>>);
 
 impl ByteStream {
@@ -51,6 +56,26 @@ impl ByteStream {
   }
 }
 
+/// Splits every chunk of `stream` so that none is larger than `max_chunk`.
+///
+/// `Bytes` are reference counted, so this does not copy any data.
+fn rechunk(
+  stream: BoxStream<'static, mlua::Result<Bytes>>,
+  max_chunk: usize,
+) -> BoxStream<'static, mlua::Result<Bytes>> {
+  stream
+    .map_ok(move |mut bytes| {
+      let mut chunks = Vec::with_capacity(bytes.len() / max_chunk + 1);
+      while bytes.len() > max_chunk {
+        chunks.push(Ok(bytes.split_to(max_chunk)));
+      }
+      chunks.push(Ok(bytes));
+      futures::stream::iter(chunks)
+    })
+    .try_flatten()
+    .boxed()
+}
+
 impl From<Body> for ByteStream {
   fn from(body: Body) -> Self {
     Self(body.map_err(rt_error).boxed())
@@ -78,5 +103,23 @@ impl UserData for ByteStream {
       };
       Ok(value)
     });
+
+    // Consumes this stream and returns one that yields chunks of at most
+    // `max_chunk` bytes, so large bodies can be piped chunk by chunk.
+    methods.add_function("stream", |lua, mut args: MultiValue| {
+      let this = check_value::<AnyUserData>(lua, args.pop_front(), "byte stream")
+        .map_err(tag_handler(lua, 1, 1))?;
+      let max_chunk = match args.pop_front() {
+        None | Some(Nil) => DEFAULT_MAX_CHUNK,
+        x => match check_integer(x).map_err(tag_handler(lua, 2, 1))? {
+          i if i > 0 => i as _,
+          _ => return Err(arg_error(lua, 2, "chunk size must be positive", 1)),
+        },
+      };
+      let inner = (this.take::<Self>())
+        .map_err(|_| rt_error("attempt to stream a consumed byte stream"))?
+        .0;
+      Ok(Self(rechunk(inner, max_chunk)))
+    });
   }
 }
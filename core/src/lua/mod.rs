@@ -1,3 +1,4 @@
+pub mod bytecode;
 pub mod error;
 pub mod global_env;
 pub mod isolate;
@@ -8,8 +9,9 @@ mod libs;
 #[cfg(test)]
 mod tests;
 
-pub use libs::{fs, http, json, lua_std, rand, stream};
+pub use libs::{cache, fs, http, json, lua_std, rand, stream};
 
+use crate::task::TimeoutError;
 use crate::{Error, ErrorKind};
 use error::{resolve_callback_error, CustomError};
 use futures::Future;
@@ -158,12 +160,67 @@ pub fn sanitize_error(error: mlua::Error) -> Error {
         if let Some(error) = extract_custom_error(error) {
           return error;
         }
+        if error.is::<TimeoutError>() {
+          return ErrorKind::CpuLimitExceeded.into();
+        }
+      }
+      if let mlua::Error::MemoryError(_) = cause {
+        return ErrorKind::MemoryLimitExceeded.into();
+      }
+      if is_stack_overflow(cause) {
+        return ErrorKind::StackOverflow.into();
       }
       format!("{cause}\n{traceback}").to_lua_err().into()
     }
+    mlua::Error::ExternalError(error) if error.is::<TimeoutError>() => {
+      ErrorKind::CpuLimitExceeded.into()
+    }
     mlua::Error::ExternalError(error) => {
       extract_custom_error(&error).unwrap_or_else(|| mlua::Error::ExternalError(error).into())
     }
+    mlua::Error::MemoryError(_) => ErrorKind::MemoryLimitExceeded.into(),
+    _ if is_stack_overflow(&error) => ErrorKind::StackOverflow.into(),
     _ => error.into(),
   }
 }
+
+/// Whether `error` is Lua's own stack overflow error, either of the Lua or
+/// the C stack.
+///
+/// Lua raises these like any other runtime error, with a message that
+/// `error("stack overflow")` can produce too. Those are told apart by where
+/// they are raised: a real overflow is raised by the function that overflowed,
+/// never by `error` or `assert`.
+fn is_stack_overflow(error: &mlua::Error) -> bool {
+  match error {
+    mlua::Error::StackError => true,
+    mlua::Error::RuntimeError(msg) => {
+      let mut lines = msg.lines();
+      let overflowed = lines.next().is_some_and(|line| {
+        // Lua prefixes the position of the overflowing Lua function
+        let msg = match line.rsplit_once(": ") {
+          Some((pos, msg)) if is_position(pos) => msg,
+          _ => line,
+        };
+        msg == "stack overflow" || msg == "C stack overflow"
+      });
+      let raised_by = (lines.skip_while(|x| *x != "stack traceback:"))
+        .nth(1)
+        .map(str::trim);
+      overflowed
+        && !matches!(
+          raised_by,
+          Some("[C]: in function 'error'" | "[C]: in function 'assert'")
+        )
+    }
+    _ => false,
+  }
+}
+
+/// Whether `pos` looks like `<chunk>:<line>`.
+fn is_position(pos: &str) -> bool {
+  matches!(
+    pos.rsplit_once(':'),
+    Some((_, line)) if !line.is_empty() && line.bytes().all(|x| x.is_ascii_digit())
+  )
+}
@@ -1,75 +1,182 @@
 use super::http::LuaUri;
 use super::{LuaCacheExt, LUA_HTTP_CLIENT};
-use crate::rt_error_fmt;
+use crate::source::Source;
+use crate::{rt_error_fmt, RemoteCacheConfig, RemoteCredential};
 use anyhow::{anyhow, bail, Context};
 use bstr::ByteSlice;
-use data_encoding::BASE64URL_NOPAD;
+use data_encoding::{BASE64URL_NOPAD, HEXLOWER};
 use futures::future::join;
 use hyper::body::Bytes;
+use hyper::header::{HeaderValue, AUTHORIZATION};
 use hyper::http::uri::{Parts, Scheme};
-use hyper::{Body, Response, Uri};
-use log::debug;
+use hyper::{Body, Request, Response, Uri};
+use log::{debug, warn};
 use mlua::{ExternalResult, Function, Lua, Table, UserData};
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::borrow::Cow;
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::fs::{read, write, File};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::fs::{read, read_dir, remove_file, write, File};
 use tokio::io::AsyncReadExt;
+use tokio::sync::OnceCell;
+
+/// Module hashes recorded by `abel resolve`, keyed like `path @uri`.
+type Lockfile = HashMap<String, String>;
+
+/// File in a source that [`Lockfile`] is read from.
+///
+/// It shares its name with the lock the server holds at the root of its data
+/// folder, which no source lives at.
+pub const LOCKFILE: &str = "abel.lock";
+
+/// Directory `abel vendor` downloads remote modules into, laid out as
+/// `<authority>/<path>` of the URIs they were downloaded from.
+pub const VENDOR_DIR: &str = "vendor";
 
 #[derive(Debug, Clone, Default)]
 pub struct RemoteInterface {
   cache_path: Option<Arc<Path>>,
+  allow_unlocked: bool,
+  credentials: Arc<HashMap<String, RemoteCredential>>,
+  cache_limits: RemoteCacheConfig,
+  /// Source requiring modules, and its lockfile once read.
+  source: Option<(Source, Arc<OnceCell<Option<Lockfile>>>)>,
 }
 
 impl RemoteInterface {
   pub fn new(cache_path: Option<PathBuf>) -> Self {
     Self {
       cache_path: cache_path.map(From::from),
+      ..Default::default()
+    }
+  }
+
+  /// Only warn about modules missing from, or not matching, sources'
+  /// `abel.lock`, instead of refusing to load them.
+  pub fn allow_unlocked(mut self, allow_unlocked: bool) -> Self {
+    self.allow_unlocked = allow_unlocked;
+    self
+  }
+
+  /// Sends `credentials` when fetching modules from their hosts.
+  pub fn credentials(mut self, credentials: HashMap<String, RemoteCredential>) -> Self {
+    self.credentials = Arc::new(credentials);
+    self
+  }
+
+  /// Limits enforced by [`evict_cache`](Self::evict_cache).
+  pub fn cache_limits(mut self, cache_limits: RemoteCacheConfig) -> Self {
+    self.cache_limits = cache_limits;
+    self
+  }
+
+  /// `Authorization` header for fetching from `uri`'s host. Credentials are
+  /// only sent over HTTPS.
+  fn authorization(&self, uri: &Uri) -> Option<HeaderValue> {
+    let authority = uri.authority()?;
+    let credential = (self.credentials.get(authority.as_str()))
+      .or_else(|| self.credentials.get(authority.host()))?;
+    if uri.scheme() != Some(&Scheme::HTTPS) {
+      warn!("not sending credentials for {authority} to non-HTTPS URI '{uri}'");
+      return None;
+    }
+    credential.header_value()
+  }
+
+  /// Verifies modules against `source`'s `abel.lock`.
+  pub(crate) fn with_source(&self, source: Source) -> Self {
+    Self {
+      source: Some((source, Default::default())),
+      ..self.clone()
+    }
+  }
+
+  /// Checks `bytes` of module `key` against the source's lockfile.
+  ///
+  /// Sources without a lockfile have every module unlocked. On a failure,
+  /// the other modules in the lockfile are checked as well, so that all
+  /// stale entries are reported at once.
+  async fn verify(&self, key: &str, bytes: &[u8]) -> anyhow::Result<()> {
+    let (source, lockfile) = match &self.source {
+      Some(x) => x,
+      None => return Ok(()),
+    };
+    let lockfile = (lockfile.get_or_try_init(|| read_lockfile(source)).await)
+      .with_context(|| format!("failed to read {LOCKFILE}"))?;
+    let error = match lockfile {
+      Some(lockfile) => match check_hash(lockfile, key, bytes) {
+        Ok(()) => return Ok(()),
+        Err(error) => error,
+      },
+      None => format!("module '{key}' is not locked, as there is no {LOCKFILE}"),
+    };
+    if self.allow_unlocked {
+      warn!("{error}");
+      return Ok(());
+    }
+
+    let mut errors = vec![error];
+    if let Some(lockfile) = lockfile {
+      errors.extend(self.check_lockfile(lockfile, key).await);
+    }
+    bail!(
+      "{}\n\t(run `abel resolve` to update {LOCKFILE}, or allow with `--allow-unlocked`)",
+      errors.join("\n")
+    )
+  }
+
+  /// Fetches every module in `lockfile` other than `skip`, returning why
+  /// those failing verification did.
+  async fn check_lockfile(&self, lockfile: &Lockfile, skip: &str) -> Vec<String> {
+    let mut keys = (lockfile.keys()).filter(|x| *x != skip).collect::<Vec<_>>();
+    keys.sort();
+    let mut errors = Vec::new();
+    for key in keys {
+      let (path, uri) = match key.split_once('@') {
+        Some((path, uri)) => (path.trim_end(), uri),
+        None => {
+          errors.push(format!("invalid {LOCKFILE} entry '{key}'"));
+          continue;
+        }
+      };
+      let result = async {
+        let uri = Uri::try_from(uri)?;
+        anyhow::Ok(self.fetch(path, uri).await?.0)
+      };
+      match result.await {
+        Ok(bytes) => errors.extend(check_hash(lockfile, key, &bytes).err()),
+        Err(error) => errors.push(format!("failed to fetch module '{key}' ({error})")),
+      }
+    }
+    errors
+  }
+
+  /// Fetches a module from the source's vendored tree, the cache or the
+  /// network, in that order.
+  async fn fetch(&self, path: &str, uri: Uri) -> anyhow::Result<(Bytes, Uri)> {
+    match self.get_vendored(path, &uri).await? {
+      Some(x) => Ok(x),
+      None => self.get_cached(path, uri).await,
     }
   }
 
   async fn get(&self, path: &str, uri: Uri) -> anyhow::Result<(Bytes, Uri)> {
-    let Parts {
-      scheme,
-      authority,
-      path_and_query,
-      ..
-    } = uri.clone().into_parts();
-    let scheme = scheme.unwrap_or(Scheme::HTTP);
-    let authority = authority.ok_or_else(|| anyhow!("invalid uri '{uri}' (authority required)"))?;
-    let path_and_query =
-      path_and_query.ok_or_else(|| anyhow!("invalid uri '{uri}' (path required)"))?;
-    let uri_path = path_and_query.path();
-    let query = path_and_query
-      .query()
-      .map(|x| format!("?{x}").into())
-      .unwrap_or(Cow::Borrowed(""));
-
-    let segments = uri_path
-      .split('/')
-      .chain(path.split('.'))
-      .filter(|x| !x.is_empty());
-    let base_path: String = itertools::intersperse(segments, "/").collect();
-
-    let init_uri = Uri::builder()
-      .scheme(scheme.clone())
-      .authority(authority.clone())
-      .path_and_query(format!("/{base_path}/init.lua{query}"))
-      .build()?;
-    let file_uri = Uri::builder()
-      .scheme(scheme)
-      .authority(authority)
-      .path_and_query(format!("/{base_path}.lua{query}"))
-      .build()?;
+    let (init_uri, file_uri) = module_uris(path, &uri)?;
     if path.is_empty() {
       debug!("Loading '@{uri}'");
     } else {
       debug!("Loading '{path} @{uri}'");
     }
-    let resps = join(request_ok(init_uri.clone()), request_ok(file_uri.clone())).await;
+    let auth = self.authorization(&init_uri);
+    let resps = join(
+      request_ok(init_uri.clone(), auth.clone()),
+      request_ok(file_uri.clone(), auth),
+    )
+    .await;
 
     match resps {
       (Ok((uri, mut resp)), Err(_)) | (Err(_), Ok((uri, mut resp))) => {
@@ -86,6 +193,32 @@ impl RemoteInterface {
     }
   }
 
+  /// Loads the module from the source's vendored tree, if it is there.
+  async fn get_vendored(&self, path: &str, uri: &Uri) -> anyhow::Result<Option<(Bytes, Uri)>> {
+    let source = match &self.source {
+      Some((source, _)) => source,
+      None => return Ok(None),
+    };
+    let (init_uri, file_uri) = module_uris(path, uri)?;
+    let mut found = None;
+    for uri in [init_uri, file_uri] {
+      let vendor_path = vendor_path(&uri).context("invalid uri")?;
+      if source.exists(&vendor_path).await? {
+        if let Some((other, _)) = found {
+          bail!("vendored file '{other}' and '{vendor_path}' conflicts");
+        }
+        found = Some((vendor_path, uri));
+      }
+    }
+    match found {
+      Some((vendor_path, uri)) => {
+        debug!("Loading vendored '{uri}' from '{vendor_path}'");
+        Ok(Some((source.get_bytes(&vendor_path).await?.into(), uri)))
+      }
+      None => Ok(None),
+    }
+  }
+
   async fn get_cached(&self, path: &str, uri: Uri) -> anyhow::Result<(Bytes, Uri)> {
     match self.cache_path.as_deref() {
       Some(cache_path) => {
@@ -110,6 +243,8 @@ impl RemoteInterface {
           }
           let mut buf = Vec::with_capacity(file.metadata().await?.len() as _);
           file.read_to_end(&mut buf).await?;
+          // Modification time marks when a module was last used, for eviction
+          let _ = file.into_std().await.set_modified(SystemTime::now());
           let metadata = read(cache_file_path.with_extension("metadata")).await?;
           let CacheMetadata { uri } = serde_json::from_slice(&metadata)?;
           Ok((buf.into(), uri.try_into()?))
@@ -127,8 +262,189 @@ impl RemoteInterface {
   }
 }
 
-async fn request_ok(uri: Uri) -> anyhow::Result<(Uri, Response<Body>)> {
-  let resp = LUA_HTTP_CLIENT.get(uri.clone()).await?;
+/// Cached remote modules, or ones removed from the cache.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct RemoteCacheStats {
+  pub modules: usize,
+  /// Total size in bytes.
+  pub size: u64,
+}
+
+impl RemoteCacheStats {
+  fn add(&mut self, entry: &CacheEntry) {
+    self.modules += 1;
+    self.size += entry.size;
+  }
+}
+
+/// A cached module and its metadata file.
+struct CacheEntry {
+  path: PathBuf,
+  size: u64,
+  last_used: SystemTime,
+}
+
+impl CacheEntry {
+  async fn remove(&self) -> std::io::Result<()> {
+    match remove_file(self.path.with_extension("metadata")).await {
+      Err(error) if error.kind() != std::io::ErrorKind::NotFound => return Err(error),
+      _ => {}
+    }
+    remove_file(&self.path).await
+  }
+}
+
+impl RemoteInterface {
+  async fn cache_entries(&self) -> std::io::Result<Vec<CacheEntry>> {
+    let cache_path = match self.cache_path.as_deref() {
+      Some(x) => x,
+      None => return Ok(Vec::new()),
+    };
+    let mut result = Vec::new();
+    let mut entries = read_dir(cache_path).await?;
+    while let Some(entry) = entries.next_entry().await? {
+      let path = entry.path();
+      if path.extension().is_some() {
+        continue;
+      }
+      let metadata = entry.metadata().await?;
+      let metadata_size =
+        (tokio::fs::metadata(path.with_extension("metadata")).await).map_or(0, |x| x.len());
+      result.push(CacheEntry {
+        size: metadata.len() + metadata_size,
+        last_used: metadata.modified()?,
+        path,
+      });
+    }
+    Ok(result)
+  }
+
+  pub async fn cache_stats(&self) -> std::io::Result<RemoteCacheStats> {
+    let mut stats = RemoteCacheStats::default();
+    for entry in self.cache_entries().await? {
+      stats.add(&entry);
+    }
+    Ok(stats)
+  }
+
+  /// Removes modules unused for longer than `max_age`, then least recently
+  /// used ones until the cache fits in `max_size_mb`.
+  pub async fn evict_cache(&self) -> std::io::Result<RemoteCacheStats> {
+    let RemoteCacheConfig {
+      max_size_mb,
+      max_age,
+    } = self.cache_limits;
+    let mut removed = RemoteCacheStats::default();
+    if max_size_mb.is_none() && max_age.is_none() {
+      return Ok(removed);
+    }
+    let mut entries = self.cache_entries().await?;
+    entries.sort_by_key(|x| x.last_used);
+    let mut size: u64 = entries.iter().map(|x| x.size).sum();
+    let max_size = max_size_mb.map_or(u64::MAX, |x| x.saturating_mul(1024 * 1024));
+    let oldest = (max_age.map(Duration::from_secs))
+      .and_then(|x| SystemTime::now().checked_sub(x))
+      .unwrap_or(UNIX_EPOCH);
+    for entry in entries {
+      if size <= max_size && entry.last_used >= oldest {
+        break;
+      }
+      entry.remove().await?;
+      size -= entry.size;
+      removed.add(&entry);
+    }
+    Ok(removed)
+  }
+
+  pub async fn purge_cache(&self) -> std::io::Result<RemoteCacheStats> {
+    let mut removed = RemoteCacheStats::default();
+    for entry in self.cache_entries().await? {
+      entry.remove().await?;
+      removed.add(&entry);
+    }
+    Ok(removed)
+  }
+}
+
+/// URIs a module is looked for at: `<path>/init.lua` and `<path>.lua` under
+/// the base URI.
+fn module_uris(path: &str, uri: &Uri) -> anyhow::Result<(Uri, Uri)> {
+  let Parts {
+    scheme,
+    authority,
+    path_and_query,
+    ..
+  } = uri.clone().into_parts();
+  let scheme = scheme.unwrap_or(Scheme::HTTP);
+  let authority = authority.ok_or_else(|| anyhow!("invalid uri '{uri}' (authority required)"))?;
+  let path_and_query =
+    path_and_query.ok_or_else(|| anyhow!("invalid uri '{uri}' (path required)"))?;
+  let uri_path = path_and_query.path();
+  let query = path_and_query
+    .query()
+    .map(|x| format!("?{x}").into())
+    .unwrap_or(Cow::Borrowed(""));
+
+  let segments = uri_path
+    .split('/')
+    .chain(path.split('.'))
+    .filter(|x| !x.is_empty());
+  let base_path: String = itertools::intersperse(segments, "/").collect();
+
+  let init_uri = Uri::builder()
+    .scheme(scheme.clone())
+    .authority(authority.clone())
+    .path_and_query(format!("/{base_path}/init.lua{query}"))
+    .build()?;
+  let file_uri = Uri::builder()
+    .scheme(scheme)
+    .authority(authority)
+    .path_and_query(format!("/{base_path}.lua{query}"))
+    .build()?;
+  Ok((init_uri, file_uri))
+}
+
+/// Where a module downloaded from `uri` is vendored, relative to the source.
+///
+/// Returns `None` for URIs that cannot be mapped to a path safely.
+pub fn vendor_path(uri: &Uri) -> Option<String> {
+  let authority = uri.authority()?.as_str();
+  let path = uri.path();
+  let safe = |x: &str| !x.is_empty() && x != "." && x != ".." && !x.contains('\\');
+  if !safe(authority) || !path.split('/').skip(1).all(safe) {
+    return None;
+  }
+  Some(format!("{VENDOR_DIR}/{authority}{path}"))
+}
+
+/// Compares the hash of `bytes` with that of module `key` in `lockfile`.
+fn check_hash(lockfile: &Lockfile, key: &str, bytes: &[u8]) -> Result<(), String> {
+  let actual = HEXLOWER.encode(&Sha256::digest(bytes));
+  match lockfile.get(key) {
+    Some(expected) if *expected == actual => Ok(()),
+    Some(expected) => Err(format!(
+      "module '{key}' does not match {LOCKFILE}\n\
+      \texpected sha256 {expected}\n\
+      \tgot sha256 {actual}"
+    )),
+    None => Err(format!("module '{key}' is not in {LOCKFILE}")),
+  }
+}
+
+async fn read_lockfile(source: &Source) -> anyhow::Result<Option<Lockfile>> {
+  if !source.exists(LOCKFILE).await? {
+    return Ok(None);
+  }
+  let bytes = source.get_bytes(LOCKFILE).await?;
+  Ok(Some(serde_json::from_slice(&bytes)?))
+}
+
+async fn request_ok(uri: Uri, auth: Option<HeaderValue>) -> anyhow::Result<(Uri, Response<Body>)> {
+  let mut req = Request::get(uri.clone()).body(Body::empty())?;
+  if let Some(auth) = auth {
+    req.headers_mut().insert(AUTHORIZATION, auth);
+  }
+  let resp = LUA_HTTP_CLIENT.request(req).await?;
   if resp.status() != 200 {
     bail!("server responded with status code {}", resp.status())
   }
@@ -154,20 +470,21 @@ impl UserData for RemoteInterface {
         let path = path.to_str().map_err(|error| {
           rt_error_fmt!("invalid path '{}' ({error})", path.as_bytes().as_bstr())
         })?;
+        let key = if path.is_empty() {
+          format!("@{}", uri.as_bytes().as_bstr())
+        } else {
+          format!("{path} @{}", uri.as_bytes().as_bstr())
+        };
         let uri = Uri::try_from(uri.as_bytes())
           .map_err(|error| rt_error_fmt!("invalid uri '{}' ({error})", uri.as_bytes().as_bstr()))?;
-        this
-          .get_cached(path, uri)
-          .await
-          .to_lua_err()
-          .and_then(|(x, uri)| {
-            let loader = lua
-              .load(&*x)
-              .set_environment(env)?
-              .set_name(format!("@{uri}"))?
-              .into_function()?;
-            Ok((loader, LuaUri(uri)))
-          })
+        let (x, uri) = this.fetch(path, uri).await.to_lua_err()?;
+        this.verify(&key, &x).await.to_lua_err()?;
+        let loader = lua
+          .load(&*x)
+          .set_environment(env)?
+          .set_name(format!("@{uri}"))?
+          .into_function()?;
+        Ok((loader, LuaUri(uri)))
       },
     );
 
@@ -202,3 +519,64 @@ pub fn load_create_require(lua: &Lua) -> mlua::Result<Function> {
       .into_function()
   })
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::source::DirSource;
+
+  #[test]
+  fn test_authorization_https_only() {
+    let remote = RemoteInterface::new(None).credentials(HashMap::from([(
+      "modules.internal".into(),
+      RemoteCredential::Bearer("token".into()),
+    )]));
+    let auth = |uri: &str| remote.authorization(&uri.parse().unwrap());
+    assert_eq!(auth("https://modules.internal/x").unwrap(), "Bearer token");
+    assert!(auth("http://modules.internal/x").is_none());
+    assert!(auth("https://other.host/x").is_none());
+  }
+
+  fn hash(bytes: &[u8]) -> String {
+    HEXLOWER.encode(&Sha256::digest(bytes))
+  }
+
+  #[tokio::test]
+  async fn test_verify_without_lockfile() {
+    let dir = tempfile::TempDir::new().unwrap();
+    let source = Source::new(DirSource::new(dir.path()));
+    let remote = RemoteInterface::new(None).with_source(source);
+    let error = remote.verify("@http://x/", b"").await.unwrap_err();
+    assert!(error.to_string().contains("not locked"));
+    let remote = remote.allow_unlocked(true);
+    remote.verify("@http://x/", b"").await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn test_verify_reports_all_mismatches() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::create_dir_all(dir.path().join("vendor/x")).unwrap();
+    std::fs::write(dir.path().join("vendor/x/a.lua"), "a").unwrap();
+    std::fs::write(dir.path().join("vendor/x/b.lua"), "changed").unwrap();
+    std::fs::write(dir.path().join("vendor/x/c.lua"), "c").unwrap();
+    let lockfile = serde_json::json!({
+      "a @http://x/": hash(b"a"),
+      "b @http://x/": hash(b"b"),
+      "c @http://x/": hash(b"c"),
+    });
+    std::fs::write(dir.path().join("abel.lock"), lockfile.to_string()).unwrap();
+    let source = Source::new(DirSource::new(dir.path()));
+    let remote = RemoteInterface::new(None).with_source(source);
+
+    remote.verify("a @http://x/", b"a").await.unwrap();
+    let error = remote.verify("a @http://x/", b"changed").await.unwrap_err();
+    let error = error.to_string();
+    assert!(error.contains("module 'a @http://x/' does not match"));
+    assert!(error.contains("module 'b @http://x/' does not match"));
+    assert!(!error.contains("module 'c @http://x/'"));
+    let error = remote.verify("d @http://x/", b"d").await.unwrap_err();
+    assert!(error
+      .to_string()
+      .contains("module 'd @http://x/' is not in"));
+  }
+}
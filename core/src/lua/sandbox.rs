@@ -1,9 +1,11 @@
+use super::bytecode::BytecodeCache;
 use super::fs::create_preload_fs;
 use super::global_env::modify_global_env;
 use super::http::create_preload_http;
 use super::isolate::{Isolate, IsolateBuilder};
 use super::json::create_preload_json;
 use super::libs::crypto::create_preload_crypto;
+use super::libs::sqlite::create_preload_sqlite;
 use super::lua_std::{
   create_preload_coroutine, create_preload_math, create_preload_os, create_preload_string,
   create_preload_table, create_preload_utf8, side_effect_global_whitelist,
@@ -13,21 +15,57 @@ use super::require::RemoteInterface;
 use super::sanitize_error;
 use super::stream::create_preload_stream;
 use crate::source::Source;
+use crate::storage::LocalStorage;
 use crate::Result;
-use mlua::{FromLuaMulti, Lua, Table, ToLuaMulti};
-use std::path::{Path, PathBuf};
+use mlua::{FromLuaMulti, Function, Lua, Table, ToLuaMulti};
+use std::fmt::{self, Debug, Formatter};
 use std::sync::Arc;
 
+/// Creates the preloader of a Lua module the embedder provides. It is called
+/// for every isolate built, so expensive values should be shared through e.g.
+/// `Lua::set_app_data` or captures.
+pub type ModuleRegistrar = Arc<dyn Fn(&Lua) -> mlua::Result<Function> + Send + Sync>;
+
+/// Lua modules provided by the embedder, which services `require` by name.
+#[derive(Clone, Default)]
+pub struct HostModules(Arc<[(String, ModuleRegistrar)]>);
+
+impl HostModules {
+  pub fn new(modules: Vec<(String, ModuleRegistrar)>) -> Self {
+    Self(modules.into())
+  }
+}
+
+impl Debug for HostModules {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.debug_list()
+      .entries(self.0.iter().map(|(name, _)| name))
+      .finish()
+  }
+}
+
 pub struct Sandbox {
   lua: Lua,
   remote: RemoteInterface,
+  modules: HostModules,
 }
 
 impl Sandbox {
-  pub fn new(remote: RemoteInterface) -> mlua::Result<Self> {
+  /// `bytecode` is used by every isolate's `Source::load`, and can be shared
+  /// with other sandboxes.
+  pub fn new(
+    remote: RemoteInterface,
+    bytecode: Arc<BytecodeCache>,
+    modules: HostModules,
+  ) -> mlua::Result<Self> {
     let lua = Lua::new();
     modify_global_env(&lua)?;
-    Ok(Self { lua, remote })
+    lua.set_app_data(bytecode);
+    Ok(Self {
+      lua,
+      remote,
+      modules,
+    })
   }
 
   pub fn lua(&self) -> &Lua {
@@ -41,10 +79,9 @@ impl Sandbox {
   pub fn isolate_builder_with_stdlib(
     &self,
     source: Source,
-    lsp: impl Into<PathBuf>,
+    storage: LocalStorage,
   ) -> mlua::Result<IsolateBuilder> {
-    let lsp: Arc<Path> = lsp.into().into();
-    self
+    let mut builder = self
       .isolate_builder(source.clone())?
       .add_side_effect(side_effect_global_whitelist)?
       // Lua std, modified
@@ -55,15 +92,22 @@ impl Sandbox {
       .add_lib("os", create_preload_os)?
       .add_lib("utf8", create_preload_utf8)?
       // Abel std (?)
-      .add_lib("fs", create_preload_fs(source, lsp))?
-      .add_lib("http", create_preload_http)?
+      .add_lib("fs", create_preload_fs(source.clone(), storage.clone()))?
+      .add_lib("http", create_preload_http(source, storage.clone()))?
       .add_lib("json", create_preload_json)?
       .add_lib("rand", create_preload_rand)?
       .add_lib("crypto", create_preload_crypto)?
       .add_lib("stream", create_preload_stream)?
+      .add_lib("sqlite", create_preload_sqlite(storage.clone()))?
       .add_lua_lib("testing", include_str!("libs/testing.lua"))?
-      // ...and load some of then into local env
-      .load_libs(["math", "string", "table", "coroutine", "os", "utf8"])
+      .add_lua_lib("jwt", include_str!("libs/jwt.lua"))?
+      .add_lua_lib("pagination", include_str!("libs/pagination.lua"))?;
+    // Embedder's modules, which may shadow the ones above
+    for (name, registrar) in self.modules.0.iter() {
+      builder = builder.add_lib(name, &**registrar)?;
+    }
+    // ...and load some of then into local env
+    builder.load_libs(["math", "string", "table", "coroutine", "os", "utf8"])
   }
 
   pub async fn run_isolate<'lua, A: ToLuaMulti<'lua>, R: FromLuaMulti<'lua>>(
@@ -0,0 +1,235 @@
+mod prometheus;
+
+use crate::error::Fault;
+use dashmap::DashMap;
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+/// Upper bounds (in seconds) of request latency histogram buckets.
+pub const LATENCY_BUCKETS: &[f64] = &[
+  0.001, 0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1., 2.5, 5., 10.,
+];
+
+/// Counters collected by Abel while serving requests.
+///
+/// Everything here is lock-free except for the first request of a service,
+/// which inserts its entry.
+#[derive(Debug, Default)]
+pub(crate) struct Metrics {
+  services: DashMap<Box<str>, Arc<ServiceMetrics>>,
+  isolate_cache_hits: AtomicU64,
+  isolate_cache_misses: AtomicU64,
+  isolate_evictions: AtomicU64,
+}
+
+#[derive(Debug, Default)]
+struct ServiceMetrics {
+  requests: AtomicU64,
+  errors: AtomicU64,
+  latency: Histogram,
+  cpu_time_micros: AtomicU64,
+  egress_bytes: AtomicU64,
+  mirrored: AtomicU64,
+  mirror_errors: AtomicU64,
+  mirror_compared: AtomicU64,
+  mirror_mismatches: AtomicU64,
+  out_of_memory: AtomicU64,
+  stack_overflows: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Histogram {
+  buckets: Box<[AtomicU64]>,
+  sum_micros: AtomicU64,
+}
+
+impl Default for Histogram {
+  fn default() -> Self {
+    Self {
+      buckets: LATENCY_BUCKETS.iter().map(|_| AtomicU64::new(0)).collect(),
+      sum_micros: AtomicU64::new(0),
+    }
+  }
+}
+
+impl Metrics {
+  fn service(&self, name: &str) -> Arc<ServiceMetrics> {
+    if let Some(x) = self.services.get(name) {
+      return x.clone();
+    }
+    self.services.entry(name.into()).or_default().clone()
+  }
+
+  pub fn record_request(
+    &self,
+    service_name: &str,
+    elapsed: Duration,
+    cpu_time: Duration,
+    error: bool,
+  ) {
+    let service = self.service(service_name);
+    service.requests.fetch_add(1, Ordering::Relaxed);
+    if error {
+      service.errors.fetch_add(1, Ordering::Relaxed);
+    }
+    let secs = elapsed.as_secs_f64();
+    if let Some(i) = LATENCY_BUCKETS.iter().position(|&x| secs <= x) {
+      service.latency.buckets[i].fetch_add(1, Ordering::Relaxed);
+    }
+    (service.latency.sum_micros).fetch_add(elapsed.as_micros() as _, Ordering::Relaxed);
+    (service.cpu_time_micros).fetch_add(cpu_time.as_micros() as _, Ordering::Relaxed);
+  }
+
+  pub fn record_egress(&self, service_name: &str, bytes: u64) {
+    let service = self.service(service_name);
+    service.egress_bytes.fetch_add(bytes, Ordering::Relaxed);
+  }
+
+  pub fn record_mirror(&self, service_name: &str, error: bool) {
+    let service = self.service(service_name);
+    service.mirrored.fetch_add(1, Ordering::Relaxed);
+    if error {
+      service.mirror_errors.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  pub fn record_mirror_comparison(&self, service_name: &str, mismatch: bool) {
+    let service = self.service(service_name);
+    service.mirror_compared.fetch_add(1, Ordering::Relaxed);
+    if mismatch {
+      service.mirror_mismatches.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  pub fn record_fault(&self, service_name: &str, fault: Fault) {
+    let service = self.service(service_name);
+    let counter = match fault {
+      Fault::OutOfMemory => &service.out_of_memory,
+      Fault::StackOverflow => &service.stack_overflows,
+    };
+    counter.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn record_isolate_cache(&self, hit: bool) {
+    if hit {
+      self.isolate_cache_hits.fetch_add(1, Ordering::Relaxed);
+    } else {
+      self.isolate_cache_misses.fetch_add(1, Ordering::Relaxed);
+    }
+  }
+
+  pub fn record_isolate_eviction(&self) {
+    self.isolate_evictions.fetch_add(1, Ordering::Relaxed);
+  }
+
+  pub fn isolate_cache_hits(&self) -> u64 {
+    self.isolate_cache_hits.load(Ordering::Relaxed)
+  }
+
+  pub fn isolate_cache_misses(&self) -> u64 {
+    self.isolate_cache_misses.load(Ordering::Relaxed)
+  }
+
+  pub fn isolate_evictions(&self) -> u64 {
+    self.isolate_evictions.load(Ordering::Relaxed)
+  }
+
+  pub fn remove_service(&self, name: &str) {
+    self.services.remove(name);
+  }
+
+  pub fn snapshot(&self, active_services: usize) -> MetricsSnapshot {
+    let mut services = (self.services.iter())
+      .map(|entry| {
+        let x = entry.value();
+        // Buckets are stored non-cumulatively, and summed up here.
+        let mut count = 0;
+        let latency_buckets = (x.latency.buckets.iter())
+          .map(|b| {
+            count += b.load(Ordering::Relaxed);
+            count
+          })
+          .collect();
+        let requests = x.requests.load(Ordering::Relaxed);
+        ServiceMetricsSnapshot {
+          name: entry.key().to_string(),
+          requests,
+          errors: x.errors.load(Ordering::Relaxed),
+          latency_buckets,
+          latency_sum: x.latency.sum_micros.load(Ordering::Relaxed) as f64 / 1e6,
+          cpu_time: x.cpu_time_micros.load(Ordering::Relaxed) as f64 / 1e6,
+          egress_bytes: x.egress_bytes.load(Ordering::Relaxed),
+          mirrored: x.mirrored.load(Ordering::Relaxed),
+          mirror_errors: x.mirror_errors.load(Ordering::Relaxed),
+          mirror_compared: x.mirror_compared.load(Ordering::Relaxed),
+          mirror_mismatches: x.mirror_mismatches.load(Ordering::Relaxed),
+          out_of_memory: x.out_of_memory.load(Ordering::Relaxed),
+          stack_overflows: x.stack_overflows.load(Ordering::Relaxed),
+        }
+      })
+      .collect::<Vec<_>>();
+    services.sort_by(|a, b| a.name.cmp(&b.name));
+
+    MetricsSnapshot {
+      services,
+      active_services,
+      isolate_cache_hits: self.isolate_cache_hits(),
+      isolate_cache_misses: self.isolate_cache_misses(),
+      isolate_evictions: self.isolate_evictions(),
+    }
+  }
+}
+
+/// A point-in-time copy of Abel's metrics.
+#[derive(Debug, Clone, Serialize)]
+pub struct MetricsSnapshot {
+  pub services: Vec<ServiceMetricsSnapshot>,
+  pub active_services: usize,
+  pub isolate_cache_hits: u64,
+  pub isolate_cache_misses: u64,
+  pub isolate_evictions: u64,
+}
+
+/// Statistics of workers' isolate caches.
+#[derive(Debug, Clone, Serialize)]
+pub struct RuntimeStats {
+  pub workers: usize,
+  /// Isolates each worker keeps loaded, apart from pinned services'.
+  pub isolate_cache_size: usize,
+  pub isolate_cache_hits: u64,
+  pub isolate_cache_misses: u64,
+  /// Isolates dropped to make room for others.
+  pub isolate_evictions: u64,
+  /// Running services whose isolates are never evicted.
+  pub pinned_services: usize,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ServiceMetricsSnapshot {
+  pub name: String,
+  pub requests: u64,
+  pub errors: u64,
+  /// Cumulative counts for each of [`LATENCY_BUCKETS`]. Requests slower than
+  /// the last bucket are only counted in `requests`.
+  pub latency_buckets: Vec<u64>,
+  /// Total request latency in seconds.
+  pub latency_sum: f64,
+  /// Total CPU time in seconds spent in Lua handling requests.
+  pub cpu_time: f64,
+  /// Response body bytes sent.
+  pub egress_bytes: u64,
+  /// Requests copied to the service's mirror target.
+  pub mirrored: u64,
+  /// Mirrored requests that failed or got a server error.
+  pub mirror_errors: u64,
+  /// Mirrored responses compared against the primary ones.
+  pub mirror_compared: u64,
+  /// Compared mirrored responses that differed from the primary ones.
+  pub mirror_mismatches: u64,
+  /// Requests that ran out of memory.
+  pub out_of_memory: u64,
+  /// Requests that overflowed the Lua or C stack.
+  pub stack_overflows: u64,
+}
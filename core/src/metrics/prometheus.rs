@@ -0,0 +1,118 @@
+use super::{MetricsSnapshot, LATENCY_BUCKETS};
+use crate::error::Fault;
+use std::fmt::{Result, Write};
+
+impl MetricsSnapshot {
+  /// Renders the snapshot in Prometheus text exposition format.
+  pub fn to_prometheus(&self) -> String {
+    let mut s = String::new();
+    self.write_prometheus(&mut s).unwrap();
+    s
+  }
+
+  fn write_prometheus(&self, s: &mut String) -> Result {
+    let services = (self.services.iter())
+      .map(|x| (format!("service=\"{}\"", escape(&x.name)), x))
+      .collect::<Vec<_>>();
+
+    header(s, "abel_requests_total", "counter", "Requests handled.")?;
+    for (label, x) in &services {
+      writeln!(s, "abel_requests_total{{{label}}} {}", x.requests)?;
+    }
+
+    header(s, "abel_errors_total", "counter", "Requests that failed.")?;
+    for (label, x) in &services {
+      writeln!(s, "abel_errors_total{{{label}}} {}", x.errors)?;
+    }
+
+    let name = "abel_request_duration_seconds";
+    header(s, name, "histogram", "Request latency.")?;
+    for (label, x) in &services {
+      for (le, count) in LATENCY_BUCKETS.iter().zip(&x.latency_buckets) {
+        writeln!(s, "{name}_bucket{{{label},le=\"{le}\"}} {count}")?;
+      }
+      writeln!(s, "{name}_bucket{{{label},le=\"+Inf\"}} {}", x.requests)?;
+      writeln!(s, "{name}_sum{{{label}}} {}", x.latency_sum)?;
+      writeln!(s, "{name}_count{{{label}}} {}", x.requests)?;
+    }
+
+    let name = "abel_cpu_seconds_total";
+    header(s, name, "counter", "CPU time spent handling requests.")?;
+    for (label, x) in &services {
+      writeln!(s, "{name}{{{label}}} {}", x.cpu_time)?;
+    }
+
+    let name = "abel_egress_bytes_total";
+    header(s, name, "counter", "Response body bytes sent.")?;
+    for (label, x) in &services {
+      writeln!(s, "{name}{{{label}}} {}", x.egress_bytes)?;
+    }
+
+    header(s, "abel_mirrored_total", "counter", "Requests mirrored.")?;
+    for (label, x) in &services {
+      writeln!(s, "abel_mirrored_total{{{label}}} {}", x.mirrored)?;
+    }
+
+    let name = "abel_mirror_errors_total";
+    header(s, name, "counter", "Mirrored requests that failed.")?;
+    for (label, x) in &services {
+      writeln!(s, "{name}{{{label}}} {}", x.mirror_errors)?;
+    }
+
+    let name = "abel_mirror_compared_total";
+    header(s, name, "counter", "Mirrored responses compared.")?;
+    for (label, x) in &services {
+      writeln!(s, "{name}{{{label}}} {}", x.mirror_compared)?;
+    }
+
+    let name = "abel_mirror_mismatches_total";
+    header(s, name, "counter", "Mirrored responses that differed.")?;
+    for (label, x) in &services {
+      writeln!(s, "{name}{{{label}}} {}", x.mirror_mismatches)?;
+    }
+
+    let name = "abel_service_faults_total";
+    header(
+      s,
+      name,
+      "counter",
+      "Requests that exhausted memory or stack.",
+    )?;
+    for (label, x) in &services {
+      for (fault, count) in [
+        (Fault::OutOfMemory, x.out_of_memory),
+        (Fault::StackOverflow, x.stack_overflows),
+      ] {
+        writeln!(s, "{name}{{{label},fault=\"{}\"}} {count}", fault.as_str())?;
+      }
+    }
+
+    let name = "abel_isolate_cache_hits_total";
+    header(s, name, "counter", "Requests served by a loaded isolate.")?;
+    writeln!(s, "{name} {}", self.isolate_cache_hits)?;
+
+    let name = "abel_isolate_cache_misses_total";
+    header(s, name, "counter", "Requests that loaded a new isolate.")?;
+    writeln!(s, "{name} {}", self.isolate_cache_misses)?;
+
+    let name = "abel_isolate_evictions_total";
+    header(s, name, "counter", "Isolates evicted to load others.")?;
+    writeln!(s, "{name} {}", self.isolate_evictions)?;
+
+    let name = "abel_active_services";
+    header(s, name, "gauge", "Services currently running.")?;
+    writeln!(s, "{name} {}", self.active_services)
+  }
+}
+
+fn header(s: &mut String, name: &str, kind: &str, help: &str) -> Result {
+  writeln!(s, "# HELP {name} {help}")?;
+  writeln!(s, "# TYPE {name} {kind}")
+}
+
+fn escape(label: &str) -> String {
+  label
+    .replace('\\', "\\\\")
+    .replace('"', "\\\"")
+    .replace('\n', "\\n")
+}
@@ -0,0 +1,700 @@
+//! Request validation against a service's `openapi.json`.
+//!
+//! Only the parts of OpenAPI 3 that describe requests are used: path, query
+//! and header parameters, and request bodies. Schemas support a common subset
+//! of JSON Schema; unknown keywords such as `format` are ignored.
+
+use crate::ErrorKind::{InvalidOpenApi, RequestValidation};
+use crate::Result;
+use hyper::{HeaderMap, Method};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+
+/// How many `$ref`s may be followed in a row before giving up.
+const MAX_REF_HOPS: usize = 32;
+
+/// A request parameter or body that does not match the document.
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+  /// JSON pointer to the offending value, e.g. `/query/limit` or
+  /// `/body/items/0/name`.
+  pub pointer: String,
+  pub msg: String,
+}
+
+#[derive(Debug)]
+pub struct OpenApi {
+  doc: Value,
+  operations: Vec<Operation>,
+  patterns: HashMap<String, Regex>,
+}
+
+#[derive(Debug)]
+struct Operation {
+  method: Method,
+  path: Regex,
+  param_names: Vec<String>,
+  parameters: Vec<Parameter>,
+  body: Option<RequestBody>,
+}
+
+#[derive(Debug)]
+struct Parameter {
+  name: String,
+  location: Location,
+  required: bool,
+  schema: Option<Value>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Location {
+  Path,
+  Query,
+  Header,
+}
+
+impl Location {
+  fn as_str(self) -> &'static str {
+    match self {
+      Self::Path => "path",
+      Self::Query => "query",
+      Self::Header => "header",
+    }
+  }
+}
+
+#[derive(Debug)]
+struct RequestBody {
+  required: bool,
+  content: Vec<(String, Option<Value>)>,
+}
+
+fn invalid(msg: impl Into<Box<str>>) -> crate::Error {
+  InvalidOpenApi { msg: msg.into() }.into()
+}
+
+impl OpenApi {
+  pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+    let doc: Value = serde_json::from_slice(bytes).map_err(|e| invalid(e.to_string()))?;
+    let paths = (doc.get("paths").and_then(Value::as_object))
+      .ok_or_else(|| invalid("missing 'paths' object"))?;
+
+    let mut operations = Vec::new();
+    for (template, item) in paths {
+      let item =
+        resolve(&doc, item).ok_or_else(|| invalid(format!("invalid path '{template}'")))?;
+      let (path, param_names) = path_regex(template)?;
+      let common = item.get("parameters");
+      for (method, op) in item.as_object().into_iter().flatten() {
+        let method = match method.to_uppercase().parse::<Method>() {
+          Ok(m) if is_operation(&m) => m,
+          _ => continue,
+        };
+        let mut parameters = parse_parameters(&doc, common, template)?;
+        for param in parse_parameters(&doc, op.get("parameters"), template)? {
+          // Operation-level parameters override path-level ones.
+          parameters.retain(|x| x.name != param.name || x.location != param.location);
+          parameters.push(param);
+        }
+        let body = match op.get("requestBody") {
+          Some(body) => Some(parse_body(&doc, body, template)?),
+          None => None,
+        };
+        operations.push(Operation {
+          method,
+          path: path.clone(),
+          param_names: param_names.clone(),
+          parameters,
+          body,
+        });
+      }
+    }
+
+    let mut patterns = HashMap::new();
+    collect_patterns(&doc, &mut patterns)?;
+    Ok(Self {
+      doc,
+      operations,
+      patterns,
+    })
+  }
+
+  /// Checks a request against the operation it matches. Requests matching
+  /// no operation in the document are let through.
+  pub fn validate(
+    &self,
+    method: &Method,
+    path: &str,
+    query: Option<&str>,
+    headers: &HeaderMap,
+    body: &[u8],
+  ) -> Result<()> {
+    let (op, captures) = match (self.operations.iter())
+      .filter(|x| x.method == method)
+      .find_map(|x| x.path.captures(path).map(|c| (x, c)))
+    {
+      Some(x) => x,
+      None => return Ok(()),
+    };
+    let path_params = (op.param_names.iter())
+      .zip(captures.iter().skip(1))
+      .filter_map(|(n, m)| Some((n.as_str(), percent_decode(m?.as_str()))))
+      .collect::<HashMap<_, _>>();
+    let query = form_urlencoded::parse(query.unwrap_or("").as_bytes()).collect::<Vec<_>>();
+
+    let mut errors = Vec::new();
+    for param in &op.parameters {
+      let pointer = format!("/{}/{}", param.location.as_str(), escape(&param.name));
+      let values = match param.location {
+        Location::Path => (path_params.get(&*param.name).cloned().into_iter()).collect(),
+        Location::Query => (query.iter())
+          .filter(|(k, _)| *k == param.name)
+          .map(|(_, v)| v.to_string())
+          .collect(),
+        Location::Header => (headers.get_all(&*param.name).iter())
+          .map(|x| String::from_utf8_lossy(x.as_bytes()).into_owned())
+          .collect::<Vec<_>>(),
+      };
+      if values.is_empty() {
+        if param.required {
+          errors.push(violation(pointer, "is required"));
+        }
+        continue;
+      }
+      let schema = match &param.schema {
+        Some(schema) => schema,
+        None => continue,
+      };
+      match self.coerce(schema, values) {
+        Ok(value) => self.check(schema, &value, &pointer, &mut errors),
+        Err(msg) => errors.push(violation(pointer, msg)),
+      }
+    }
+    if let Some(spec) = &op.body {
+      self.check_body(spec, headers, body, &mut errors);
+    }
+
+    if errors.is_empty() {
+      Ok(())
+    } else {
+      Err(RequestValidation { errors }.into())
+    }
+  }
+
+  fn check_body(
+    &self,
+    spec: &RequestBody,
+    headers: &HeaderMap,
+    body: &[u8],
+    errors: &mut Vec<Violation>,
+  ) {
+    if body.is_empty() {
+      if spec.required {
+        errors.push(violation("/body".into(), "is required"));
+      }
+      return;
+    }
+    let content_type = (headers.get(hyper::header::CONTENT_TYPE))
+      .and_then(|x| x.to_str().ok())
+      .and_then(|x| x.split(';').next())
+      .map(|x| x.trim().to_ascii_lowercase())
+      .unwrap_or_default();
+    let (media, schema) = match find_media(&spec.content, &content_type) {
+      Some(x) => x,
+      None => {
+        let expected = spec.content.iter().map(|(k, _)| &**k).collect::<Vec<_>>();
+        let msg = format!(
+          "unsupported content type '{content_type}'; expected one of {}",
+          expected.join(", ")
+        );
+        errors.push(violation("/header/content-type".into(), msg));
+        return;
+      }
+    };
+    let schema = match schema {
+      Some(schema) => schema,
+      None => return,
+    };
+    if is_json(media) || (media.ends_with("/*") && is_json(&content_type)) {
+      match serde_json::from_slice::<Value>(body) {
+        Ok(value) => self.check(schema, &value, "/body", errors),
+        Err(error) => errors.push(violation("/body".into(), format!("invalid JSON: {error}"))),
+      }
+    } else if content_type == "application/x-www-form-urlencoded" {
+      let mut fields = HashMap::<_, Vec<_>>::new();
+      for (k, v) in form_urlencoded::parse(body) {
+        fields
+          .entry(k.into_owned())
+          .or_default()
+          .push(v.into_owned());
+      }
+      let object = self.resolve(schema);
+      let mut value = Map::new();
+      for (k, v) in fields {
+        let pointer = format!("/body/{}", escape(&k));
+        let field_schema = object
+          .and_then(|x| x.get("properties"))
+          .and_then(|x| x.get(&k));
+        let field = match field_schema {
+          Some(s) => self.coerce(s, v),
+          None => Ok(Value::String(v.into_iter().next().unwrap_or_default())),
+        };
+        match field {
+          Ok(field) => drop(value.insert(k, field)),
+          Err(msg) => errors.push(violation(pointer, msg)),
+        }
+      }
+      self.check(schema, &Value::Object(value), "/body", errors);
+    }
+  }
+
+  fn resolve<'a>(&'a self, schema: &'a Value) -> Option<&'a Value> {
+    resolve(&self.doc, schema)
+  }
+
+  /// Converts parameter strings to the type `schema` expects.
+  fn coerce(&self, schema: &Value, mut values: Vec<String>) -> Result<Value, String> {
+    let schema = self.resolve(schema).ok_or("unresolvable schema")?;
+    if schema_type(schema) == Some("array") {
+      if values.len() == 1 {
+        values = values[0].split(',').map(Into::into).collect();
+      }
+      let items = schema.get("items");
+      return (values.into_iter())
+        .map(|x| match items {
+          Some(items) => self.coerce(items, vec![x]),
+          None => Ok(Value::String(x)),
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .map(Value::Array);
+    }
+    let value = values.swap_remove(0);
+    let result = match schema_type(schema) {
+      Some("integer") => (value.parse::<i64>().ok()).map(Value::from),
+      Some("number") => (value.parse::<f64>().ok()).map(Value::from),
+      Some("boolean") => match &*value {
+        "true" => Some(Value::Bool(true)),
+        "false" => Some(Value::Bool(false)),
+        _ => None,
+      },
+      _ => return Ok(Value::String(value)),
+    };
+    result.ok_or_else(|| format!("expected {}, found '{value}'", schema_type(schema).unwrap()))
+  }
+
+  fn check(&self, schema: &Value, value: &Value, pointer: &str, errors: &mut Vec<Violation>) {
+    let schema = match self.resolve(schema) {
+      Some(Value::Object(schema)) => schema,
+      Some(Value::Bool(false)) => return errors.push(violation(pointer.into(), "is not allowed")),
+      _ => return,
+    };
+    let error =
+      |errors: &mut Vec<Violation>, msg: String| errors.push(violation(pointer.into(), msg));
+
+    if value.is_null() && schema.get("nullable") == Some(&Value::Bool(true)) {
+      return;
+    }
+    if let Some(types) = schema.get("type") {
+      let types = match types {
+        Value::String(t) => vec![&**t],
+        Value::Array(ts) => ts.iter().filter_map(Value::as_str).collect(),
+        _ => Vec::new(),
+      };
+      if !types.is_empty() && !types.iter().any(|t| has_type(value, t)) {
+        return error(
+          errors,
+          format!(
+            "expected {}, found {}",
+            types.join(" or "),
+            type_name(value)
+          ),
+        );
+      }
+    }
+    if let Some(Value::Array(variants)) = schema.get("enum") {
+      if !variants.contains(value) {
+        error(
+          errors,
+          format!("must be one of {}", Value::Array(variants.clone())),
+        );
+      }
+    }
+    if let Some(expected) = schema.get("const") {
+      if expected != value {
+        error(errors, format!("must be {expected}"));
+      }
+    }
+
+    if let Some(Value::Array(all)) = schema.get("allOf") {
+      for s in all {
+        self.check(s, value, pointer, errors);
+      }
+    }
+    if let Some(Value::Array(any)) = schema.get("anyOf") {
+      if !any.iter().any(|s| self.matches(s, value)) {
+        error(errors, "does not match any of the allowed schemas".into());
+      }
+    }
+    if let Some(Value::Array(one)) = schema.get("oneOf") {
+      let count = one.iter().filter(|s| self.matches(s, value)).count();
+      if count != 1 {
+        error(
+          errors,
+          format!("matches {count} schemas in 'oneOf' instead of exactly one"),
+        );
+      }
+    }
+
+    let number = |key| schema.get(key).and_then(Value::as_f64);
+    match value {
+      Value::String(s) => {
+        let len = s.chars().count() as f64;
+        if let Some(min) = number("minLength").filter(|x| len < *x) {
+          error(errors, format!("must be at least {min} characters long"));
+        }
+        if let Some(max) = number("maxLength").filter(|x| len > *x) {
+          error(errors, format!("must be at most {max} characters long"));
+        }
+        if let Some(pattern) = schema.get("pattern").and_then(Value::as_str) {
+          if !self.patterns[pattern].is_match(s) {
+            error(errors, format!("must match pattern '{pattern}'"));
+          }
+        }
+      }
+      Value::Number(n) => {
+        let n = n.as_f64().unwrap_or_default();
+        let exclusive = |key| schema.get(key) == Some(&Value::Bool(true));
+        if let Some(min) = number("minimum") {
+          if n < min || (exclusive("exclusiveMinimum") && n == min) {
+            error(errors, format!("must be at least {min}"));
+          }
+        }
+        if let Some(max) = number("maximum") {
+          if n > max || (exclusive("exclusiveMaximum") && n == max) {
+            error(errors, format!("must be at most {max}"));
+          }
+        }
+        if let Some(min) = number("exclusiveMinimum").filter(|x| n <= *x) {
+          error(errors, format!("must be greater than {min}"));
+        }
+        if let Some(max) = number("exclusiveMaximum").filter(|x| n >= *x) {
+          error(errors, format!("must be less than {max}"));
+        }
+        if let Some(m) = number("multipleOf").filter(|x| *x > 0. && (n / x).fract() != 0.) {
+          error(errors, format!("must be a multiple of {m}"));
+        }
+      }
+      Value::Array(items) => {
+        let len = items.len() as f64;
+        if let Some(min) = number("minItems").filter(|x| len < *x) {
+          error(errors, format!("must have at least {min} items"));
+        }
+        if let Some(max) = number("maxItems").filter(|x| len > *x) {
+          error(errors, format!("must have at most {max} items"));
+        }
+        if schema.get("uniqueItems") == Some(&Value::Bool(true))
+          && (1..items.len()).any(|i| items[..i].contains(&items[i]))
+        {
+          error(errors, "must not contain duplicate items".into());
+        }
+        if let Some(item_schema) = schema.get("items") {
+          for (i, item) in items.iter().enumerate() {
+            self.check(item_schema, item, &format!("{pointer}/{i}"), errors);
+          }
+        }
+      }
+      Value::Object(object) => {
+        let properties = schema.get("properties").and_then(Value::as_object);
+        if let Some(Value::Array(required)) = schema.get("required") {
+          for name in required.iter().filter_map(Value::as_str) {
+            if !object.contains_key(name) {
+              errors.push(violation(
+                format!("{pointer}/{}", escape(name)),
+                "is required",
+              ));
+            }
+          }
+        }
+        for (k, v) in object {
+          let child = format!("{pointer}/{}", escape(k));
+          match (
+            properties.and_then(|x| x.get(k)),
+            schema.get("additionalProperties"),
+          ) {
+            (Some(s), _) => self.check(s, v, &child, errors),
+            (None, Some(Value::Bool(false))) => errors.push(violation(child, "is not allowed")),
+            (None, Some(s)) => self.check(s, v, &child, errors),
+            (None, None) => {}
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+
+  fn matches(&self, schema: &Value, value: &Value) -> bool {
+    let mut errors = Vec::new();
+    self.check(schema, value, "", &mut errors);
+    errors.is_empty()
+  }
+}
+
+fn violation(pointer: String, msg: impl Into<String>) -> Violation {
+  Violation {
+    pointer,
+    msg: msg.into(),
+  }
+}
+
+fn is_operation(method: &Method) -> bool {
+  [
+    Method::GET,
+    Method::PUT,
+    Method::POST,
+    Method::DELETE,
+    Method::OPTIONS,
+    Method::HEAD,
+    Method::PATCH,
+    Method::TRACE,
+  ]
+  .contains(method)
+}
+
+/// Follows `$ref`s, which may only point inside the document.
+fn resolve<'a>(doc: &'a Value, mut value: &'a Value) -> Option<&'a Value> {
+  for _ in 0..MAX_REF_HOPS {
+    match value.get("$ref").and_then(Value::as_str) {
+      Some(r) => value = doc.pointer(r.strip_prefix('#')?)?,
+      None => return Some(value),
+    }
+  }
+  None
+}
+
+/// Turns `/users/{id}` into a regex capturing `id`.
+fn path_regex(template: &str) -> Result<(Regex, Vec<String>)> {
+  let mut regex = "^".to_owned();
+  let mut names = Vec::new();
+  let mut rest = template;
+  while let Some(start) = rest.find('{') {
+    let end = (rest[start..].find('}'))
+      .ok_or_else(|| invalid(format!("unclosed '{{' in path '{template}'")))?;
+    regex += &regex::escape(&rest[..start]);
+    regex += "([^/]+)";
+    names.push(rest[start + 1..start + end].to_owned());
+    rest = &rest[start + end + 1..];
+  }
+  regex += &regex::escape(rest);
+  regex += "$";
+  Ok((Regex::new(&regex)?, names))
+}
+
+fn parse_parameters(doc: &Value, params: Option<&Value>, template: &str) -> Result<Vec<Parameter>> {
+  let params = match params {
+    Some(Value::Array(params)) => params,
+    Some(_) => {
+      return Err(invalid(format!(
+        "'parameters' of '{template}' is not an array"
+      )))
+    }
+    None => return Ok(Vec::new()),
+  };
+  let mut result = Vec::new();
+  for param in params {
+    let param = resolve(doc, param)
+      .ok_or_else(|| invalid(format!("unresolvable parameter in '{template}'")))?;
+    let name = (param.get("name").and_then(Value::as_str))
+      .ok_or_else(|| invalid(format!("parameter without name in '{template}'")))?;
+    let location = match param.get("in").and_then(Value::as_str) {
+      Some("path") => Location::Path,
+      Some("query") => Location::Query,
+      Some("header") => Location::Header,
+      Some("cookie") => continue,
+      _ => {
+        return Err(invalid(format!(
+          "parameter '{name}' in '{template}' has invalid 'in'"
+        )))
+      }
+    };
+    result.push(Parameter {
+      name: name.into(),
+      location,
+      required: location == Location::Path || param.get("required") == Some(&Value::Bool(true)),
+      schema: param.get("schema").cloned(),
+    });
+  }
+  Ok(result)
+}
+
+fn parse_body(doc: &Value, body: &Value, template: &str) -> Result<RequestBody> {
+  let body = resolve(doc, body)
+    .ok_or_else(|| invalid(format!("unresolvable request body in '{template}'")))?;
+  let content = (body.get("content").and_then(Value::as_object))
+    .ok_or_else(|| invalid(format!("request body in '{template}' has no 'content'")))?
+    .iter()
+    .map(|(k, v)| (k.to_ascii_lowercase(), v.get("schema").cloned()))
+    .collect();
+  Ok(RequestBody {
+    required: body.get("required") == Some(&Value::Bool(true)),
+    content,
+  })
+}
+
+fn collect_patterns(value: &Value, patterns: &mut HashMap<String, Regex>) -> Result<()> {
+  match value {
+    Value::Object(object) => {
+      if let Some(Value::String(p)) = object.get("pattern") {
+        if !patterns.contains_key(p) {
+          let regex = Regex::new(p).map_err(|e| invalid(format!("invalid pattern '{p}': {e}")))?;
+          patterns.insert(p.clone(), regex);
+        }
+      }
+      object
+        .values()
+        .try_for_each(|x| collect_patterns(x, patterns))
+    }
+    Value::Array(array) => array.iter().try_for_each(|x| collect_patterns(x, patterns)),
+    _ => Ok(()),
+  }
+}
+
+/// Picks the most specific entry for a content type, e.g. `application/json`
+/// over `application/*` over `*/*`.
+fn find_media<'a>(
+  content: &'a [(String, Option<Value>)],
+  content_type: &str,
+) -> Option<(&'a str, Option<&'a Value>)> {
+  let wildcard = content_type.split('/').next().map(|x| format!("{x}/*"));
+  let found = [Some(content_type), wildcard.as_deref(), Some("*/*")]
+    .into_iter()
+    .flatten()
+    .find_map(|t| content.iter().find(|(k, _)| k == t));
+  found.map(|(k, v)| (&**k, v.as_ref()))
+}
+
+fn is_json(media: &str) -> bool {
+  media == "application/json" || media.ends_with("+json")
+}
+
+fn schema_type(schema: &Value) -> Option<&str> {
+  match schema.get("type")? {
+    Value::String(t) => Some(t),
+    Value::Array(ts) => ts.iter().filter_map(Value::as_str).find(|x| *x != "null"),
+    _ => None,
+  }
+}
+
+fn has_type(value: &Value, t: &str) -> bool {
+  match t {
+    "integer" => value.as_f64().is_some_and(|x| x.fract() == 0.),
+    "number" => value.is_number(),
+    _ => type_name(value) == t,
+  }
+}
+
+fn type_name(value: &Value) -> &'static str {
+  match value {
+    Value::Null => "null",
+    Value::Bool(_) => "boolean",
+    Value::Number(_) => "number",
+    Value::String(_) => "string",
+    Value::Array(_) => "array",
+    Value::Object(_) => "object",
+  }
+}
+
+fn escape(s: &str) -> String {
+  s.replace('~', "~0").replace('/', "~1")
+}
+
+fn percent_decode(s: &str) -> String {
+  form_urlencoded::parse(format!("x={}", s.replace('+', "%2B")).as_bytes())
+    .next()
+    .map(|(_, v)| v.into_owned())
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use serde_json::json;
+
+  fn errors(api: &OpenApi, method: Method, path: &str, query: &str, body: Value) -> Vec<String> {
+    let mut headers = HeaderMap::new();
+    headers.insert("content-type", "application/json".parse().unwrap());
+    let body = if body.is_null() {
+      Vec::new()
+    } else {
+      body.to_string().into_bytes()
+    };
+    match api.validate(&method, path, Some(query), &headers, &body) {
+      Ok(()) => Vec::new(),
+      Err(error) => match error.into_parts().0 {
+        RequestValidation { errors } => errors.into_iter().map(|x| x.pointer).collect(),
+        kind => panic!("unexpected error: {kind}"),
+      },
+    }
+  }
+
+  #[test]
+  fn test_validate() {
+    let doc = json!({
+      "openapi": "3.0.3",
+      "paths": {
+        "/pets/{id}": {
+          "parameters": [{ "name": "id", "in": "path", "schema": { "type": "integer" } }],
+          "get": {
+            "parameters": [{ "name": "limit", "in": "query", "schema": { "type": "integer", "maximum": 10 } }]
+          },
+          "put": {
+            "requestBody": {
+              "required": true,
+              "content": { "application/json": { "schema": { "$ref": "#/components/schemas/Pet" } } }
+            }
+          }
+        }
+      },
+      "components": {
+        "schemas": {
+          "Pet": {
+            "type": "object",
+            "required": ["name"],
+            "additionalProperties": false,
+            "properties": {
+              "name": { "type": "string", "pattern": "^[a-z]+$" },
+              "tags": { "type": "array", "items": { "type": "string" } }
+            }
+          }
+        }
+      }
+    });
+    let api = OpenApi::from_slice(doc.to_string().as_bytes()).unwrap();
+
+    assert!(errors(&api, Method::GET, "/pets/1", "limit=5", Value::Null).is_empty());
+    assert!(errors(&api, Method::GET, "/unknown", "", Value::Null).is_empty());
+    assert_eq!(
+      errors(&api, Method::GET, "/pets/x", "limit=11", Value::Null),
+      ["/path/id", "/query/limit"]
+    );
+    assert!(errors(&api, Method::PUT, "/pets/1", "", json!({ "name": "tom" })).is_empty());
+    assert_eq!(errors(&api, Method::PUT, "/pets/1", "", Value::Null), [
+      "/body"
+    ]);
+    assert_eq!(
+      errors(
+        &api,
+        Method::PUT,
+        "/pets/1",
+        "",
+        json!({ "tags": [1], "x": 1 })
+      ),
+      ["/body/name", "/body/tags/0", "/body/x"]
+    );
+    assert_eq!(
+      errors(&api, Method::PUT, "/pets/1", "", json!({ "name": "Tom" })),
+      ["/body/name"]
+    );
+  }
+}
@@ -2,7 +2,9 @@ use crate::Result;
 use once_cell::sync::Lazy;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
 use std::collections::HashMap;
+use strum::EnumString;
 
 pub type Params = HashMap<Box<str>, Box<str>>;
 
@@ -68,6 +70,100 @@ impl PathMatcher {
   }
 }
 
+/// How a service's request paths are normalized before they are matched, set
+/// by `abel.path_policy`. The default keeps paths as they are.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct PathPolicy {
+  pub trailing_slash: TrailingSlash,
+  /// Collapse consecutive slashes into one.
+  pub merge_slashes: bool,
+  pub decode: PercentDecode,
+}
+
+/// What to do when a path only matches with its trailing slash added or
+/// removed.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum TrailingSlash {
+  /// Treat it as not found.
+  #[default]
+  Strict,
+  /// Redirect to the matching path with 308 Permanent Redirect.
+  Redirect,
+  /// Handle it as if the matching path was requested.
+  Rewrite,
+}
+
+/// Which percent-encoded characters are decoded.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, EnumString)]
+#[strum(serialize_all = "snake_case")]
+pub enum PercentDecode {
+  #[default]
+  None,
+  /// Only unreserved characters, as RFC 3986 recommends.
+  Unreserved,
+  /// Everything except `%2F`, so that segments stay the same. Paths that
+  /// would not be valid UTF-8 are left encoded.
+  All,
+}
+
+impl PathPolicy {
+  pub fn normalize<'a>(&self, path: &'a str) -> Cow<'a, str> {
+    let mut path = Cow::Borrowed(path);
+    if self.merge_slashes && path.contains("//") {
+      static SLASHES: Lazy<Regex> = Lazy::new(|| Regex::new("//+").unwrap());
+      path = Cow::Owned(SLASHES.replace_all(&path, "/").into_owned());
+    }
+    if self.decode != PercentDecode::None && path.contains('%') {
+      if let Some(decoded) = percent_decode(&path, self.decode) {
+        path = Cow::Owned(decoded);
+      }
+    }
+    path
+  }
+}
+
+fn percent_decode(path: &str, mode: PercentDecode) -> Option<String> {
+  let hex = |b: u8| (b as char).to_digit(16).map(|x| x as u8);
+  let bytes = path.as_bytes();
+  let mut result = Vec::with_capacity(bytes.len());
+  let mut i = 0;
+  while i < bytes.len() {
+    let decoded = (bytes[i] == b'%' && i + 2 < bytes.len())
+      .then(|| Some(hex(bytes[i + 1])? << 4 | hex(bytes[i + 2])?))
+      .flatten()
+      .filter(|&b| match mode {
+        PercentDecode::None => false,
+        PercentDecode::Unreserved => b.is_ascii_alphanumeric() || b"-._~".contains(&b),
+        PercentDecode::All => b != b'/',
+      });
+    match decoded {
+      Some(b) => {
+        result.push(b);
+        i += 3;
+      }
+      None => {
+        result.push(bytes[i]);
+        i += 1;
+      }
+    }
+  }
+  String::from_utf8(result).ok()
+}
+
+/// Adds a trailing slash to `path`, or removes it. The root path has no
+/// alternative.
+pub fn toggle_trailing_slash(path: &str) -> Option<String> {
+  match path.strip_suffix('/') {
+    Some(stripped) => {
+      let stripped = stripped.trim_end_matches('/');
+      (!stripped.is_empty()).then(|| stripped.to_string())
+    }
+    None if path.is_empty() => None,
+    None => Some(format!("{path}/")),
+  }
+}
+
 /// The returned path is always relative, which is intentional and convenient
 /// for concatenating to other paths in usual cases.
 pub fn normalize_path_str(path: &str) -> String {
@@ -105,6 +201,27 @@ mod tests {
     PathMatcher::new(matcher).unwrap().gen_params(path)
   }
 
+  #[test_case("/a//b/%7Euser%20x/%2F", false, PercentDecode::None => "/a//b/%7Euser%20x/%2F"; "unchanged")]
+  #[test_case("/a//b///c", true, PercentDecode::None => "/a/b/c"; "merge slashes")]
+  #[test_case("/%7Euser%20x/%2F", false, PercentDecode::Unreserved => "/~user%20x/%2F"; "decode unreserved")]
+  #[test_case("/%7Euser%20x/%2F%e4%bd%a0", false, PercentDecode::All => "/~user x/%2F你"; "decode all")]
+  #[test_case("/%ff%2", false, PercentDecode::All => "/%ff%2"; "invalid utf-8")]
+  fn test_path_policy(path: &str, merge_slashes: bool, decode: PercentDecode) -> String {
+    let policy = PathPolicy {
+      merge_slashes,
+      decode,
+      ..Default::default()
+    };
+    policy.normalize(path).into_owned()
+  }
+
+  #[test_case("/a" => Some("/a/".into()); "add")]
+  #[test_case("/a//" => Some("/a".into()); "remove")]
+  #[test_case("/" => None; "root")]
+  fn test_toggle_trailing_slash(path: &str) -> Option<String> {
+    toggle_trailing_slash(path)
+  }
+
   #[test_case("" => ""; "empty string")]
   #[test_case("etc/rpc" => "etc/rpc"; "force absolute")]
   #[test_case("../../././///etc/rpc" => "etc/rpc"; "special path components")]
@@ -0,0 +1,185 @@
+//! Token buckets of services' rate limits, one per service and client.
+//!
+//! Clients are told apart by whatever the service's config keys on, which
+//! may be a header they control, so buckets are kept in an LRU cache of
+//! bounded size. An evicted bucket starts over full, which costs the limit
+//! some precision under a flood of distinct keys but never memory.
+
+use crate::config::{RateLimitConfig, RateLimitKey};
+use crate::service::ServiceName;
+use crate::ClientAddr;
+use clru::CLruCache;
+use hyper::{Body, Request};
+use nonzero_ext::nonzero;
+use parking_lot::Mutex;
+use std::num::NonZeroUsize;
+use std::time::{Duration, Instant};
+
+/// Buckets kept before the least recently used one is evicted.
+const DEFAULT_CAPACITY: NonZeroUsize = nonzero!(65536usize);
+
+/// Buckets are swept of full ones every this many requests.
+const SWEEP_INTERVAL: usize = 4096;
+
+#[derive(Debug)]
+struct Bucket {
+  tokens: f64,
+  updated: Instant,
+  /// Time an empty bucket takes to fill up.
+  refill: Duration,
+}
+
+type Key = (ServiceName, Box<str>);
+
+#[derive(Debug)]
+pub(crate) struct RateLimiter(Mutex<State>);
+
+#[derive(Debug)]
+struct State {
+  buckets: CLruCache<Key, Bucket>,
+  requests: usize,
+}
+
+impl RateLimiter {
+  pub fn new(capacity: NonZeroUsize) -> Self {
+    Self(Mutex::new(State {
+      buckets: CLruCache::new(capacity),
+      requests: 0,
+    }))
+  }
+
+  /// Takes a token from the bucket of `req`'s client, or returns how long
+  /// until one is available.
+  pub fn acquire(
+    &self,
+    service: &ServiceName,
+    config: &RateLimitConfig,
+    req: &Request<Body>,
+  ) -> Result<(), Duration> {
+    let mut state = self.0.lock();
+    state.requests = state.requests.wrapping_add(1);
+    if state.requests.is_multiple_of(SWEEP_INTERVAL) {
+      state.sweep();
+    }
+
+    let now = Instant::now();
+    let burst = config.burst() as f64;
+    let key = (service.clone(), client_key(&config.key, req).into());
+    let new = |_: &Key, ()| Bucket {
+      tokens: burst,
+      updated: now,
+      refill: secs(burst / config.rate),
+    };
+    let bucket = (state.buckets).put_or_modify(key, new, |_, _, ()| {}, ());
+    let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+    bucket.tokens = (bucket.tokens + elapsed * config.rate).min(burst);
+    bucket.updated = now;
+    if bucket.tokens >= 1. {
+      bucket.tokens -= 1.;
+      Ok(())
+    } else {
+      Err(secs((1. - bucket.tokens) / config.rate))
+    }
+  }
+}
+
+impl Default for RateLimiter {
+  fn default() -> Self {
+    Self::new(DEFAULT_CAPACITY)
+  }
+}
+
+impl State {
+  /// Drops buckets that would be full by now.
+  fn sweep(&mut self) {
+    let now = Instant::now();
+    (self.buckets).retain(|_, x| now.duration_since(x.updated) < x.refill);
+  }
+}
+
+/// Converts `x` seconds to a [`Duration`], saturating instead of panicking
+/// should a tiny rate make it overflow.
+fn secs(x: f64) -> Duration {
+  Duration::try_from_secs_f64(x).unwrap_or(Duration::MAX)
+}
+
+/// Identifies the client of `req`. Clients without the key share one bucket.
+fn client_key<'a>(key: &RateLimitKey, req: &'a Request<Body>) -> std::borrow::Cow<'a, str> {
+  match key {
+    RateLimitKey::Ip => (req.extensions().get::<ClientAddr>())
+      .map(|x| x.0.to_string().into())
+      .unwrap_or_default(),
+    RateLimitKey::Header(name) => (req.headers().get(name.as_str()))
+      .and_then(|x| x.to_str().ok())
+      .unwrap_or_default()
+      .into(),
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_burst_then_reject() {
+    let limiter = RateLimiter::default();
+    let config = RateLimitConfig {
+      rate: 1.,
+      burst: Some(2),
+      key: RateLimitKey::Header("x-api-key".into()),
+    };
+    let service = ServiceName::from("test");
+    let req = |key: &str| {
+      Request::builder()
+        .header("x-api-key", key)
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    assert!(limiter.acquire(&service, &config, &req("a")).is_ok());
+    assert!(limiter.acquire(&service, &config, &req("a")).is_ok());
+    let wait = limiter.acquire(&service, &config, &req("a")).unwrap_err();
+    assert!(wait > Duration::ZERO && wait <= Duration::from_secs(1));
+    assert!(limiter.acquire(&service, &config, &req("b")).is_ok());
+  }
+
+  #[test]
+  fn test_evict_least_recent() {
+    let limiter = RateLimiter::new(nonzero!(2usize));
+    let config = RateLimitConfig {
+      rate: 1e-3,
+      burst: Some(1),
+      key: RateLimitKey::Header("x-api-key".into()),
+    };
+    let service = ServiceName::from("test");
+    let req = |key: &str| {
+      Request::builder()
+        .header("x-api-key", key)
+        .body(Body::empty())
+        .unwrap()
+    };
+
+    assert!(limiter.acquire(&service, &config, &req("a")).is_ok());
+    assert!(limiter.acquire(&service, &config, &req("a")).is_err());
+    assert!(limiter.acquire(&service, &config, &req("b")).is_ok());
+    assert!(limiter.acquire(&service, &config, &req("c")).is_ok());
+    assert_eq!(limiter.0.lock().buckets.len(), 2);
+    // `a` was evicted and starts over
+    assert!(limiter.acquire(&service, &config, &req("a")).is_ok());
+  }
+
+  #[test]
+  fn test_tiny_rate() {
+    let limiter = RateLimiter::default();
+    let config = RateLimitConfig {
+      rate: 1e-300,
+      burst: Some(1),
+      key: RateLimitKey::Ip,
+    };
+    let service = ServiceName::from("test");
+    let req = Request::new(Body::empty());
+    assert!(limiter.acquire(&service, &config, &req).is_ok());
+    let wait = limiter.acquire(&service, &config, &req).unwrap_err();
+    assert_eq!(wait, Duration::MAX);
+  }
+}
@@ -1,39 +1,116 @@
+use super::wait::MAX_WAIT;
+use super::Runtime;
 use crate::lua::error::{
-  arg_error, check_integer, check_userdata_mut, check_value, rt_error, tag_error, tag_handler,
+  arg_error, check_integer, check_userdata_mut, check_value, resolve_callback_error, rt_error,
+  rt_error_fmt, tag_error, tag_handler,
 };
-use crate::lua::LuaCacheExt;
-use crate::task::{LocalTask, TaskContext};
-use futures::future::BoxFuture;
+use crate::lua::{sanitize_error, LuaCacheExt};
+use crate::task::{DeadlineError, DetachedTasks, LocalTask, TaskContext};
+use crate::RegionalConfig;
+use futures::future::{Abortable, BoxFuture};
 use futures::{Future, FutureExt};
+use log::{debug, warn};
 use mlua::Value::Nil;
 use mlua::{Function, Lua, MultiValue, RegistryKey, Table, UserData};
-use std::time::Duration;
+use std::cell::RefCell;
+use std::rc::{Rc, Weak};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::sync::oneshot::error::RecvError;
+use uuid::Uuid;
 
 pub fn side_effect_abel(lua: &Lua, local_env: Table, internal: Table) -> mlua::Result<()> {
   use mlua::Value::Function as Func;
   let abel = lua.create_table_from([
-    ("listen", Func(create_fn_listen(lua, internal)?)),
+    ("listen", Func(create_fn_listen(lua, internal.clone())?)),
+    ("use", Func(create_fn_use(lua, internal.clone())?)),
+    ("fallback", Func(create_fn_fallback(lua, internal.clone())?)),
+    (
+      "error_page",
+      Func(create_fn_error_page(lua, internal.clone())?),
+    ),
+    ("path_policy", Func(create_fn_path_policy(lua, internal)?)),
     ("spawn", Func(create_fn_spawn(lua)?)),
     ("await_all", Func(create_fn_await_all(lua)?)),
     ("sleep", Func(create_fn_sleep(lua)?)),
+    ("timeout", Func(create_fn_timeout(lua)?)),
     ("current_worker", lua.pack(std::thread::current().name())?),
   ])?;
   local_env.raw_set("abel", abel.clone())?;
   Ok(())
 }
 
+/// Sets `abel.env` to service's variables and secrets.
+pub fn side_effect_env<'a>(
+  env: impl IntoIterator<Item = (&'a str, &'a str)>,
+) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> {
+  |lua, local_env, _| {
+    let abel: Table = local_env.raw_get("abel")?;
+    abel.raw_set("env", lua.create_table_from(env)?)
+  }
+}
+
+/// Sets `abel.service` to the service's name and regional defaults.
+pub fn side_effect_service<'a>(
+  name: &'a str,
+  regional: &'a RegionalConfig,
+) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> + 'a {
+  move |lua, local_env, _| {
+    let abel: Table = local_env.raw_get("abel")?;
+    let service = lua.create_table()?;
+    service.raw_set("name", name)?;
+    service.raw_set("timezone", regional.timezone.as_deref())?;
+    service.raw_set("locale", regional.locale.as_deref())?;
+    abel.raw_set("service", service)
+  }
+}
+
+/// Sets `abel.spawn_detached`, whose tasks belong to version `uuid` of
+/// service `name`.
+pub fn side_effect_spawn_detached(
+  name: &str,
+  uuid: Uuid,
+) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> + '_ {
+  move |lua, local_env, internal| {
+    let abel: Table = local_env.raw_get("abel")?;
+    abel.raw_set(
+      "spawn_detached",
+      create_fn_spawn_detached(lua, name, uuid, internal)?,
+    )
+  }
+}
+
+/// Sets `abel.wait_for` and `abel.notify`, whose keys belong to service
+/// `name`.
+pub fn side_effect_wait(name: &str) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> + '_ {
+  |lua, local_env, _| {
+    let abel: Table = local_env.raw_get("abel")?;
+    abel.raw_set("wait_for", create_fn_wait_for(lua, name)?)?;
+    abel.raw_set("notify", create_fn_notify(lua, name)?)
+  }
+}
+
 pub fn is_in_abel_context(lua: &Lua) -> bool {
   lua.app_data_mut::<Vec<LocalTask>>().is_some()
 }
 
 fn create_fn_listen<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
   const SRC: &str = r#"
-    local internal, path, handler = ...
+    local internal, path, handler, options = ...
     assert(
       not internal.sealed,
       "cannot call `listen` from places other than the top level of `main.lua`"
     )
+    local timeout
+    if options ~= nil then
+      if type(options) ~= "table" then
+        error "options must be a table"
+      end
+      timeout = options.timeout
+      if timeout ~= nil and (type(timeout) ~= "number" or not (timeout > 0) or timeout == math.huge) then
+        error "timeout must be a positive, finite number"
+      end
+    end
     local type_handler = type(handler)
     if type_handler ~= "function" then
       if type_handler == "table" then
@@ -41,12 +118,24 @@ fn create_fn_listen<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Funct
         if type(mt) == "table" and type(mt.__call) == "function" then
           goto ok
         end
+        -- Table of methods to handlers, e.g. `{ GET = f, POST = g }`
+        for method, f in pairs(handler) do
+          if type(method) ~= "string" or not method:match "^[A-Z]+$" then
+            error("invalid method in handler table: " .. tostring(method))
+          end
+          if type(f) ~= "function" then
+            error("handler of method " .. method .. " must be a function")
+          end
+        end
+        if next(handler) ~= nil then
+          goto ok
+        end
       end
-      error "handler must either be a function or a callable table"
+      error "handler must either be a function, a callable table or a table of methods"
     end
 
     ::ok::
-    table.insert(internal.paths, { path, handler })
+    table.insert(internal.paths, { path, handler, timeout = timeout })
   "#;
   let f = lua.create_cached_value("abel:abel.listen::meta", || {
     lua.load(SRC).set_name("@[abel.listen]")?.into_function()
@@ -54,6 +143,166 @@ fn create_fn_listen<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Funct
   f.bind(internal)
 }
 
+/// Adds a middleware, called as `middleware(req, next)` before the handler.
+///
+/// Requests are routed after all middlewares have run, by the `path` of the
+/// request passed to the last `next`. A middleware may thus send a request
+/// to another handler by setting `req.path`, or passing `next` a request
+/// with another `path`.
+fn create_fn_use<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, middleware = ...
+    assert(
+      not internal.sealed,
+      "cannot call `use` from places other than the top level of `main.lua`"
+    )
+    if type(middleware) ~= "function" then
+      error "middleware must be a function"
+    end
+    table.insert(internal.middlewares, middleware)
+  "#;
+  let f = lua.create_cached_value("abel:abel.use::meta", || {
+    lua.load(SRC).set_name("@[abel.use]")?.into_function()
+  })?;
+  f.bind(internal)
+}
+
+/// Sets the handler of paths that no `listen` matches.
+fn create_fn_fallback<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, handler = ...
+    assert(
+      not internal.sealed,
+      "cannot call `fallback` from places other than the top level of `main.lua`"
+    )
+    local type_handler = type(handler)
+    if type_handler ~= "function" then
+      if type_handler == "table" then
+        local mt = getmetatable(handler)
+        if type(mt) == "table" and type(mt.__call) == "function" then
+          goto ok
+        end
+      end
+      error "handler must either be a function or a callable table"
+    end
+
+    ::ok::
+    internal.fallback = handler
+  "#;
+  let f = lua.create_cached_value("abel:abel.fallback::meta", || {
+    lua.load(SRC).set_name("@[abel.fallback]")?.into_function()
+  })?;
+  f.bind(internal)
+}
+
+/// Sets the page of responses with a status code and no body, either a
+/// string used as HTML, or a handler called with the status and the request.
+fn create_fn_error_page<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, status, page = ...
+    assert(
+      not internal.sealed,
+      "cannot call `error_page` from places other than the top level of `main.lua`"
+    )
+    if math.type(status) ~= "integer" or status < 400 or status > 599 then
+      error "status must be an integer from 400 to 599"
+    end
+    local type_page = type(page)
+    if type_page ~= "string" and type_page ~= "function" then
+      if type_page == "table" then
+        local mt = getmetatable(page)
+        if type(mt) == "table" and type(mt.__call) == "function" then
+          goto ok
+        end
+      end
+      error "page must be a string, a function or a callable table"
+    end
+
+    ::ok::
+    internal.error_pages[status] = page
+  "#;
+  let f = lua.create_cached_value("abel:abel.error_page::meta", || {
+    lua
+      .load(SRC)
+      .set_name("@[abel.error_page]")?
+      .into_function()
+  })?;
+  f.bind(internal)
+}
+
+/// Sets how request paths are normalized before matching: `trailing_slash`
+/// (`"strict"`, `"redirect"` or `"rewrite"`), `merge_slashes` and `decode`
+/// (`"none"`, `"unreserved"` or `"all"`).
+fn create_fn_path_policy<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  const SRC: &str = r#"
+    local internal, policy = ...
+    assert(
+      not internal.sealed,
+      "cannot call `path_policy` from places other than the top level of `main.lua`"
+    )
+    if type(policy) ~= "table" then
+      error "policy must be a table"
+    end
+    local function check_option(name, options)
+      local value = policy[name]
+      if value == nil then
+        return
+      end
+      for _, option in ipairs(options) do
+        if value == option then
+          return value
+        end
+      end
+      error(name .. " must be one of '" .. table.concat(options, "', '") .. "'")
+    end
+    local merge_slashes = policy.merge_slashes
+    if merge_slashes ~= nil and type(merge_slashes) ~= "boolean" then
+      error "merge_slashes must be a boolean"
+    end
+    internal.path_policy = {
+      trailing_slash = check_option("trailing_slash", { "strict", "redirect", "rewrite" }),
+      merge_slashes = merge_slashes,
+      decode = check_option("decode", { "none", "unreserved", "all" }),
+    }
+  "#;
+  let f = lua.create_cached_value("abel:abel.path_policy::meta", || {
+    lua
+      .load(SRC)
+      .set_name("@[abel.path_policy]")?
+      .into_function()
+  })?;
+  f.bind(internal)
+}
+
+/// Runs middlewares in order, each calling `next(req)` to pass the request
+/// on, and finally the handler `route(req)` finds. Without a handler, the
+/// request falls through to a 404 error.
+///
+/// See [`create_fn_use`].
+pub(crate) fn create_fn_dispatch(lua: &Lua) -> mlua::Result<Function<'_>> {
+  const SRC: &str = r#"
+    local middlewares, route, not_found, req = ...
+    local function run(i, req)
+      local middleware = middlewares[i]
+      if middleware then
+        return middleware(req, function(next_req)
+          return run(i + 1, next_req or req)
+        end)
+      end
+      local handler = route(req)
+      if handler then
+        return handler(req)
+      end
+      not_found.detail.path = req.path
+      error(not_found)
+    end
+    return run(1, req)
+  "#;
+  lua.create_cached_value("abel:abel.use::dispatch", || {
+    lua.load(SRC).set_name("@[abel.use]")?.into_function()
+  })
+}
+
 pub struct LuaPromise {
   inner: BoxFuture<'static, Result<Box<mlua::Result<RegistryKey>>, RecvError>>,
 }
@@ -107,6 +356,65 @@ pub(crate) fn create_fn_spawn(lua: &Lua) -> mlua::Result<Function> {
   })
 }
 
+fn create_fn_spawn_detached<'a>(
+  lua: &'a Lua,
+  name: &str,
+  uuid: Uuid,
+  internal: Table<'a>,
+) -> mlua::Result<Function<'a>> {
+  let name: Arc<str> = name.into();
+  let f = lua.create_function(move |lua, mut args: MultiValue| {
+    let internal: Table = check_value(lua, args.pop_front(), "table").unwrap();
+    if !internal.raw_get::<_, bool>("sealed")? {
+      return Err(rt_error(
+        "cannot call `spawn_detached` from the top level of `main.lua`",
+      ));
+    }
+    let f: Function =
+      check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 1, 1))?;
+    let f = if args.is_empty() { f } else { f.bind(args)? };
+    spawn_detached(lua, name.clone(), uuid, f)
+  })?;
+  f.bind(internal)
+}
+
+/// Spawns `f` as a task that outlives the current one.
+///
+/// The task has its own context, so it neither shares the spawner's CPU time
+/// nor closes its resources, though the same limits apply. It is aborted when
+/// version `uuid` of `service` stops or is replaced.
+fn spawn_detached(lua: &Lua, service: Arc<str>, uuid: Uuid, f: Function) -> mlua::Result<()> {
+  let detached = (lua.app_data_ref::<Arc<DetachedTasks>>())
+    .map(|x| x.clone())
+    .ok_or_else(|| rt_error("`spawn_detached` can only be used in services"))?;
+  let mut ctx = TaskContext::new_with_close_table(lua)?;
+  ctx.limits = TaskContext::get_current(lua)
+    .map(|x| x.limits)
+    .unwrap_or_default();
+
+  let key = lua.create_registry_value(f)?;
+  let (id, registration) = detached.register(uuid);
+  let (task, _) = LocalTask::new(ctx, move |rt| async move {
+    let lua = rt.lua();
+    let task = async {
+      let f: Function = lua.registry_value(&key)?;
+      f.call_async::<_, ()>(()).await
+    };
+    match Abortable::new(task, registration).await {
+      Ok(Ok(())) => {}
+      Ok(Err(error)) => warn!(
+        "detached task of service '{service}' failed: {}",
+        sanitize_error(error)
+      ),
+      Err(_) => debug!("detached task of service '{service}' aborted"),
+    }
+    detached.unregister(uuid, id);
+    lua.remove_registry_value(key)
+  });
+  lua.app_data_mut::<Vec<LocalTask>>().unwrap().push(task);
+  Ok(())
+}
+
 fn create_fn_await_all(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_async_function("abel:abel.await_all", |lua, args: MultiValue| async move {
     let args = args
@@ -144,6 +452,53 @@ fn create_fn_await_all(lua: &Lua) -> mlua::Result<Function> {
   })
 }
 
+fn get_runtime(lua: &Lua, fn_name: &str) -> mlua::Result<Rc<Runtime>> {
+  (lua.app_data_ref::<Weak<Runtime>>())
+    .and_then(|x| x.upgrade())
+    .ok_or_else(|| rt_error_fmt!("`{fn_name}` can only be used in services"))
+}
+
+/// Waits until `key` is notified or `timeout` milliseconds pass, returning
+/// whether it was notified.
+///
+/// The timeout is capped at [`MAX_WAIT`] and at the innermost `abel.timeout`
+/// deadline, since a wait cancelled by the latter would otherwise hold on to
+/// its entry until the coroutine is collected.
+fn create_fn_wait_for<'a>(lua: &'a Lua, name: &str) -> mlua::Result<Function<'a>> {
+  let name: Arc<str> = name.into();
+  lua.create_async_function(move |lua, mut args: MultiValue| {
+    let name = name.clone();
+    async move {
+      let key: mlua::String =
+        check_value(lua, args.pop_front(), "string").map_err(tag_handler(lua, 1, 1))?;
+      let ms = check_integer(args.pop_front()).map_err(tag_handler(lua, 2, 1))?;
+      let ms = u64::try_from(ms).map_err(|_| arg_error(lua, 2, "timeout cannot be negative", 1))?;
+      let mut timeout = Duration::from_millis(ms).min(MAX_WAIT);
+      let deadline =
+        (TaskContext::get_current(lua)).and_then(|x| x.deadlines.borrow().iter().min().copied());
+      if let Some(deadline) = deadline {
+        timeout = timeout.min(deadline.saturating_duration_since(Instant::now()));
+      }
+      let rt = get_runtime(lua, "wait_for")?;
+      let waiters = &rt.state.waiters;
+      let notified = waiters.wait(&name, key.to_str()?, timeout);
+      Ok(notified.await)
+    }
+  })
+}
+
+/// Wakes every request waiting on `key`.
+fn create_fn_notify<'a>(lua: &'a Lua, name: &str) -> mlua::Result<Function<'a>> {
+  let name: Arc<str> = name.into();
+  lua.create_function(move |lua, mut args: MultiValue| {
+    let key: mlua::String =
+      check_value(lua, args.pop_front(), "string").map_err(tag_handler(lua, 1, 1))?;
+    let rt = get_runtime(lua, "notify")?;
+    rt.state.waiters.notify(&name, key.to_str()?);
+    Ok(())
+  })
+}
+
 fn create_fn_sleep(lua: &Lua) -> mlua::Result<Function> {
   lua.create_cached_async_function("abel:abel.sleep", |lua, mut args: MultiValue| async move {
     let ms = check_integer(args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
@@ -153,3 +508,68 @@ fn create_fn_sleep(lua: &Lua) -> mlua::Result<Function> {
     Ok(())
   })
 }
+
+/// Calls `f` with the rest of the arguments, raising an error if it does not
+/// return in `ms` milliseconds. `f` is cancelled when the time is up.
+///
+/// `f` is cancelled where it awaits, or in code that does not yield, where
+/// the CPU time limit is checked. The latter happens every million or so
+/// instructions, so `f` may run a little past `ms` before it stops. Errors
+/// on timeout cannot be caught by `pcall` inside `f`.
+fn create_fn_timeout(lua: &Lua) -> mlua::Result<Function> {
+  lua.create_cached_async_function(
+    "abel:abel.timeout",
+    |lua, mut args: MultiValue| async move {
+      let ms = check_integer(args.pop_front()).map_err(tag_handler(lua, 1, 1))?;
+      let ms = u64::try_from(ms).map_err(|_| arg_error(lua, 1, "timeout cannot be negative", 1))?;
+      let f: Function =
+        check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 2, 1))?;
+      let timeout = Duration::from_millis(ms);
+      // Too far in the future to be represented, which is as good as none
+      let deadline = Instant::now().checked_add(timeout);
+      let guard = (deadline.zip(TaskContext::get_current(lua)))
+        .map(|(deadline, x)| DeadlineGuard::new(&x, deadline));
+      let call = f.call_async::<_, MultiValue>(args);
+      let result = tokio::time::timeout(timeout, call).await;
+      drop(guard);
+      let timed_out = || rt_error_fmt!("timed out after {ms}ms");
+      match result {
+        // Deadlines of enclosing calls are left for them to report
+        Ok(Err(error))
+          if deadline.is_some_and(|x| Instant::now() >= x) && is_deadline_error(&error) =>
+        {
+          Err(timed_out())
+        }
+        Ok(result) => result,
+        Err(_) => Err(timed_out()),
+      }
+    },
+  )
+}
+
+/// Keeps a deadline in the task's context, until dropped along with the
+/// `abel.timeout` call it belongs to, even if an enclosing one cancels it.
+struct DeadlineGuard(Rc<RefCell<Vec<Instant>>>, Instant);
+
+impl DeadlineGuard {
+  fn new(ctx: &TaskContext, deadline: Instant) -> Self {
+    ctx.deadlines.borrow_mut().push(deadline);
+    Self(ctx.deadlines.clone(), deadline)
+  }
+}
+
+impl Drop for DeadlineGuard {
+  fn drop(&mut self) {
+    let mut deadlines = self.0.borrow_mut();
+    if let Some(i) = deadlines.iter().rposition(|x| *x == self.1) {
+      deadlines.remove(i);
+    }
+  }
+}
+
+fn is_deadline_error(error: &mlua::Error) -> bool {
+  matches!(
+    resolve_callback_error(error),
+    mlua::Error::ExternalError(x) if x.is::<DeadlineError>()
+  )
+}
@@ -1,15 +1,37 @@
-use log::{info, warn};
+use crate::lua::error::{arg_error, check_value, tag_handler};
+use crate::task::TaskContext;
+use log::{info, log, warn, Level};
 use mlua::{Function, Lua, MultiValue, Table};
+use parking_lot::Mutex;
+use serde_json::{Map, Value};
+use std::fmt::Write;
+use std::sync::Arc;
+use uuid::Uuid;
+
+/// A line logged by a service with `print`, `warn` or the `log` module.
+#[derive(Debug, Clone)]
+pub struct CapturedLog {
+  pub level: Level,
+  pub message: String,
+  /// Fields passed to the `log` module; empty for `print` and `warn`.
+  pub fields: Map<String, Value>,
+  /// ID of the request being handled when the line was logged.
+  pub request_id: Option<Arc<str>>,
+}
+
+/// Lines logged while handling a request, collected by
+/// [`Abel::run_service_with_logs`](crate::Abel::run_service_with_logs).
+pub type LogCapture = Arc<Mutex<Vec<CapturedLog>>>;
 
 pub fn side_effect_log(name: &str) -> impl FnOnce(&Lua, Table, Table) -> mlua::Result<()> + '_ {
   |lua, env, _| {
     env.raw_set(
       "print",
-      create_fn_log(lua, name, |t, s| info!(target: t, "{s}"))?,
+      create_fn_log(lua, name, Level::Info, |t, s| info!(target: t, "{s}"))?,
     )?;
     env.raw_set(
       "warn",
-      create_fn_log(lua, name, |t, s| warn!(target: t, "{s}"))?,
+      create_fn_log(lua, name, Level::Warn, |t, s| warn!(target: t, "{s}"))?,
     )
   }
 }
@@ -17,12 +39,13 @@ pub fn side_effect_log(name: &str) -> impl FnOnce(&Lua, Table, Table) -> mlua::R
 fn create_fn_log<'a>(
   lua: &'a Lua,
   service_name: &str,
+  level: Level,
   f: impl Fn(&str, &str) + 'static,
 ) -> mlua::Result<Function<'a>> {
   let tostring: Function = lua.globals().raw_get("tostring")?;
   let target = format!("service '{service_name}'");
 
-  let f = lua.create_function(move |_lua, (tostring, mut args): (Function, MultiValue)| {
+  let f = lua.create_function(move |lua, (tostring, mut args): (Function, MultiValue)| {
     let first: mlua::String = tostring.call(args.pop_front())?;
     let first = String::from_utf8_lossy(first.as_bytes()).into_owned();
     let s = args
@@ -35,7 +58,121 @@ fn create_fn_log<'a>(
         Ok(init)
       })?;
     f(&target, &s);
+    capture(lua, level, s, Map::new());
     Ok(())
   })?;
   f.bind(tostring)
 }
+
+fn capture(lua: &Lua, level: Level, message: String, fields: Map<String, Value>) {
+  let capture = TaskContext::get_current(lua).and_then(|x| x.log_capture.borrow().clone());
+  if let Some(capture) = capture {
+    (capture.lock()).push(CapturedLog {
+      level,
+      message,
+      fields,
+      request_id: TaskContext::request_id(lua),
+    });
+  }
+}
+
+/// Creates the `log` module, e.g. `log.info("user created", { id = 42 })`.
+///
+/// Records are written to the host logger with their fields, the service's
+/// UUID and the request's ID appended as `key=value` pairs.
+pub fn create_preload_log(
+  service_name: &str,
+  uuid: Option<Uuid>,
+) -> impl FnOnce(&Lua) -> mlua::Result<Function> + '_ {
+  move |lua| {
+    let target: Arc<str> = format!("service '{service_name}'").into();
+    lua.create_function(move |lua, ()| {
+      let log_table = lua.create_table()?;
+      for (name, level) in [
+        ("debug", Level::Debug),
+        ("info", Level::Info),
+        ("warn", Level::Warn),
+        ("error", Level::Error),
+      ] {
+        let target = target.clone();
+        let f = lua.create_function(move |lua, mut args: MultiValue| {
+          let tostring: Function = lua.globals().raw_get("tostring")?;
+          let message: mlua::String = tostring.call(args.pop_front())?;
+          let message = String::from_utf8_lossy(message.as_bytes()).into_owned();
+          let fields = match args.pop_front() {
+            None | Some(mlua::Value::Nil) => Map::new(),
+            x => {
+              let table: Table = check_value(lua, x, "table").map_err(tag_handler(lua, 2, 0))?;
+              convert_fields(lua, table, &tostring)?
+            }
+          };
+
+          let mut line = message.clone();
+          for (k, v) in &fields {
+            write!(line, " {k}={}", logfmt_value(v)).unwrap();
+          }
+          if let Some(uuid) = uuid {
+            write!(line, " uuid={uuid}").unwrap();
+          }
+          if let Some(id) = TaskContext::request_id(lua) {
+            write!(line, " request_id={}", logfmt_value(&Value::from(&*id))).unwrap();
+          }
+          log!(target: &target, level, "{line}");
+          capture(lua, level, message, fields);
+          Ok(())
+        })?;
+        log_table.raw_set(name, f)?;
+      }
+      Ok(log_table)
+    })
+  }
+}
+
+fn convert_fields(
+  lua: &Lua,
+  table: Table,
+  tostring: &Function,
+) -> mlua::Result<Map<String, Value>> {
+  let mut fields = Vec::new();
+  for kv in table.pairs::<mlua::Value, mlua::Value>() {
+    let (k, v) = kv?;
+    let k = match k {
+      mlua::Value::String(k) => k.to_str()?.to_owned(),
+      _ => return Err(arg_error(lua, 2, "field names must be strings", 0)),
+    };
+    let v = match v {
+      mlua::Value::Nil => continue,
+      mlua::Value::Boolean(b) => Value::Bool(b),
+      mlua::Value::Integer(i) => Value::from(i),
+      mlua::Value::Number(n) => Value::from(n),
+      mlua::Value::String(s) => Value::String(s.to_string_lossy().into()),
+      // Tables that cannot be turned into JSON, e.g. ones containing
+      // functions, are logged like other values.
+      v => match (matches!(v, mlua::Value::Table(_)))
+        .then(|| serde_json::to_value(&v).ok())
+        .flatten()
+      {
+        Some(json) => json,
+        None => {
+          let s: mlua::String = tostring.call(v)?;
+          Value::String(s.to_string_lossy().into())
+        }
+      },
+    };
+    fields.push((k, v));
+  }
+  fields.sort_by(|(a, _), (b, _)| a.cmp(b));
+  Ok(fields.into_iter().collect())
+}
+
+/// Strings are written bare unless they need quoting.
+fn logfmt_value(v: &Value) -> String {
+  match v {
+    Value::String(s)
+      if !s.is_empty() && !s.contains(|c: char| c.is_whitespace() || "\"=".contains(c)) =>
+    {
+      s.clone()
+    }
+    v => v.to_string(),
+  }
+}
@@ -1,50 +1,99 @@
 pub(super) mod abel;
 
 mod logging;
+mod rpc;
+pub(crate) mod wait;
 
-use crate::lua::error::rt_error_fmt;
-use crate::lua::http::{LuaRequest, LuaResponse};
+pub use logging::{CapturedLog, LogCapture};
+
+use crate::lua::cache::create_preload_cache;
+use crate::lua::error::{rt_error, rt_error_fmt};
+use crate::lua::http::{LuaBody, LuaRequest, LuaResponse, RangeRequest};
 use crate::lua::isolate::Isolate;
 use crate::lua::sandbox::Sandbox;
 use crate::lua::{sanitize_error, LuaTableExt};
-use crate::path::PathMatcher;
-use crate::service::{get_local_storage_path, RunningService};
+use crate::path::{toggle_trailing_slash, Params, PathMatcher, PathPolicy, TrailingSlash};
+use crate::service::{get_local_storage_path, RunningService, ServiceImpl};
 use crate::source::Source;
-use crate::task::TaskContext;
+use crate::storage::{LocalStorage, ServiceStorageConfig};
+use crate::task::{DetachedTasks, TaskContext};
 use crate::ErrorKind::*;
-use crate::{AbelState, Result};
-use abel::side_effect_abel;
+use crate::{AbelState, Error, Fault, RegionalConfig, Result};
+use abel::{
+  create_fn_dispatch, side_effect_abel, side_effect_env, side_effect_service,
+  side_effect_spawn_detached, side_effect_wait,
+};
 use clru::CLruCache;
-use hyper::{Body, Request};
-use log::{debug, info};
-use logging::side_effect_log;
-use mlua::{self, FromLuaMulti, Function, Table, TableExt, ToLuaMulti};
-use nonzero_ext::nonzero;
+use hyper::header::{HeaderValue, ALLOW, CONTENT_LENGTH, CONTENT_TYPE, LOCATION};
+use hyper::{Body, HeaderMap, Method, Request, StatusCode, Uri};
+use log::{debug, info, warn};
+use logging::{create_preload_log, side_effect_log};
+use mlua::{self, FromLuaMulti, Function, Lua, LuaSerdeExt, Table, TableExt, ToLuaMulti};
 use once_cell::sync::Lazy;
 use regex::Regex;
-use std::cell::{Ref, RefCell};
+use rpc::side_effect_rpc;
+use std::cell::{Cell, Ref, RefCell};
+use std::collections::HashMap;
 use std::ops::{Deref, DerefMut};
+use std::rc::Rc;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tracing::{info_span, Instrument};
+use uuid::Uuid;
+
+/// Faults after which an isolate is dropped and loaded again.
+const RECYCLE_AFTER_FAULTS: u32 = 3;
+/// Time in which [`RECYCLE_AFTER_FAULTS`] faults have to happen for the
+/// isolate to be recycled. Older faults are forgotten.
+const FAULT_WINDOW: Duration = Duration::from_secs(60);
 
 pub struct Runtime {
   sandbox: Sandbox,
   loaded: RefCell<CLruCache<Box<str>, LoadedService>>,
-  state: Arc<AbelState>,
+  /// Isolates of pinned services, which are kept out of `loaded` so that they
+  /// are never evicted.
+  pinned: RefCell<HashMap<Box<str>, LoadedService>>,
+  pub(crate) state: Arc<AbelState>,
 }
 
 #[derive(Debug)]
 struct LoadedService {
   service: RunningService,
   isolate: Isolate,
+  /// Requests that ran out of memory or stack in this isolate.
+  faults: Faults,
+}
+
+/// Faults of an isolate in the current [`FAULT_WINDOW`].
+#[derive(Debug, Default)]
+struct Faults(Cell<Option<(Instant, u32)>>);
+
+impl Faults {
+  /// Records a fault at `now`, returning the number of faults in the window
+  /// so far. A window starts at the first fault after the last one ended.
+  fn record(&self, now: Instant) -> u32 {
+    let (start, count) = match self.0.get() {
+      Some((start, count)) if now.duration_since(start) < FAULT_WINDOW => (start, count + 1),
+      _ => (now, 1),
+    };
+    self.0.set(Some((start, count)));
+    count
+  }
 }
 
 impl Runtime {
   pub fn new(state: Arc<AbelState>) -> mlua::Result<Self> {
-    let loaded = RefCell::new(CLruCache::new(nonzero!(16usize)));
-    let sandbox = Sandbox::new(state.remote.clone())?;
+    let loaded = RefCell::new(CLruCache::new(state.isolate_cache_size));
+    let sandbox = Sandbox::new(
+      state.remote.clone(),
+      state.bytecode.clone(),
+      state.modules.clone(),
+    )?;
+    (sandbox.lua()).set_app_data(state.http_client.clone());
     Ok(Self {
       sandbox,
       loaded,
+      pinned: Default::default(),
       state,
     })
   }
@@ -67,16 +116,61 @@ impl Runtime {
     service: RunningService,
     path: &str,
     req: Request<Body>,
+  ) -> Result<LuaResponse> {
+    let result = self.handle_request_inner(service.clone(), path, req).await;
+    match result {
+      Err(error) => match error.kind().fault() {
+        Some(fault) => Err(self.record_fault(&service, error, fault)),
+        None => Err(error),
+      },
+      Ok(resp) => Ok(resp),
+    }
+  }
+
+  /// Counts a request running out of memory or stack against the service and
+  /// its isolate, dropping the isolate if it keeps happening, in case it is
+  /// left in a bad state.
+  ///
+  /// Running out of memory is still reported as such, since it is the
+  /// service's limit, not the server, that failed the request.
+  fn record_fault(&self, service: &RunningService, error: Error, fault: Fault) -> Error {
+    let service = match service.try_upgrade() {
+      Ok(x) => x,
+      Err(error) => return error,
+    };
+    let name = service.name.clone();
+    self.state.metrics.record_fault(&name, fault);
+
+    let key = cache_key(&service);
+    let faults = (self.peek_loaded(&key)).map(|x| x.faults.record(Instant::now()));
+    if matches!(faults, Some(x) if x >= RECYCLE_AFTER_FAULTS) {
+      if let Some(loaded) = self.take_loaded(&key) {
+        warn!(
+          "recycling isolate of service '{name}' after {RECYCLE_AFTER_FAULTS} faults in {}s",
+          FAULT_WINDOW.as_secs()
+        );
+        if let Err(error) = self.remove_isolate(loaded.isolate) {
+          warn!("failed to unload isolate of service '{name}': {error}");
+        }
+      }
+    }
+    match fault {
+      Fault::OutOfMemory => error,
+      Fault::StackOverflow => ServiceFault {
+        service: name,
+        fault,
+      }
+      .into(),
+    }
+  }
+
+  async fn handle_request_inner(
+    &self,
+    service: RunningService,
+    path: &str,
+    req: Request<Body>,
   ) -> Result<LuaResponse> {
     let guard = service.try_upgrade()?;
-    let (params, matcher) = guard
-      .paths
-      .iter()
-      .find_map(|m| m.gen_params(path).map(|p| (p, m)))
-      .ok_or_else(|| ServicePathNotFound {
-        service: guard.name.clone(),
-        path: path.into(),
-      })?;
 
     // `loaded` is a mapped, immutable, checked-at-runtime borrow from
     // `self.loaded`. Dropping it early here prevents `self.loaded` being borrowed
@@ -86,35 +180,162 @@ impl Runtime {
       self.get_internal(&loaded.isolate)?
     };
 
-    for f in internal
-      .raw_get_path::<Table>("<internal>", &["paths"])?
-      .sequence_values::<Table>()
-    {
-      let f = f?;
-      let path = f.raw_get::<u8, String>(1)?;
-      if path == matcher.as_str() {
-        let handler = f.raw_get::<u8, mlua::Value>(2)?;
-
-        // Request object in handler should be ephemeral, otherwise graceful shutdown
-        // would be blocked.
-        let req = self.lua().create_userdata(LuaRequest::new(req, params))?;
-        TaskContext::register(self.lua(), req.clone())?;
-
-        let resp = self.call_extract_error(handler, req).await?;
-        return Ok(resp);
+    let policy = path_policy(&internal)?;
+    let middlewares: Table = internal.raw_get("middlewares")?;
+    let fallback: mlua::Value = internal.raw_get("fallback")?;
+    let error_pages: Table = internal.raw_get("error_pages")?;
+    let range = RangeRequest::from_request(&req);
+    let is_head = req.method() == Method::HEAD;
+
+    // Request object in handler should be ephemeral, otherwise graceful shutdown
+    // would be blocked.
+    let (resp, req) = if middlewares.raw_len() == 0 {
+      let method = req.method().clone();
+      let (path, params, handler) = match route(&guard.paths, &internal, policy, path)? {
+        Some(x) if x.toggled && policy.trailing_slash == TrailingSlash::Redirect => {
+          return redirect_trailing_slash(req.uri())
+        }
+        Some(Route {
+          path,
+          params,
+          handler,
+          timeout,
+          ..
+        }) => {
+          set_timeout(self.lua(), timeout);
+          let handler = select_method(self.lua(), handler, &method)?;
+          (path, params, Some(handler))
+        }
+        None if fallback != mlua::Value::Nil => (
+          policy.normalize(path).into(),
+          Default::default(),
+          Some(fallback),
+        ),
+        None if error_pages.contains_key(404)? => {
+          (policy.normalize(path).into(), Default::default(), None)
+        }
+        None => {
+          return Err(
+            ServicePathNotFound {
+              service: guard.name.clone(),
+              path: policy.normalize(path).into(),
+            }
+            .into(),
+          )
+        }
+      };
+      let req = (self.lua()).create_userdata(LuaRequest::new(req, path, params))?;
+      TaskContext::register(self.lua(), req.clone())?;
+      let resp = match handler {
+        Some(handler) => self.call_extract_error(handler, req.clone()).await?,
+        None => LuaResponse {
+          status: StatusCode::NOT_FOUND,
+          ..Default::default()
+        },
+      };
+      (resp, req)
+    } else {
+      let path = policy.normalize(path).into_owned();
+      let uri = req.uri().clone();
+      let req = LuaRequest::new(req, path.clone(), Default::default());
+      let req = self.lua().create_userdata(req)?;
+      TaskContext::register(self.lua(), req.clone())?;
+      let route = create_fn_route(self.lua(), service.clone(), policy, uri, path)?;
+      let route = route.bind((internal, fallback))?;
+      let resp = (self.dispatch(&guard.name, middlewares, route, req.clone())).await?;
+      (resp, req)
+    };
+    let resp = self.apply_error_page(error_pages, resp, req).await?;
+    let mut resp = range.apply(resp).await?;
+    if is_head {
+      drop_body(&mut resp).await?;
+    }
+    Ok(resp)
+  }
+
+  /// Runs the middlewares, and then the handler `route` finds for the
+  /// request they pass on.
+  async fn dispatch<'a>(
+    &'a self,
+    name: &str,
+    middlewares: Table<'a>,
+    route: Function<'a>,
+    req: mlua::AnyUserData<'a>,
+  ) -> Result<LuaResponse> {
+    let not_found = self.lua().create_table_from([
+      ("status", self.lua().pack(404)?),
+      ("error", self.lua().pack("path not found")?),
+      (
+        "detail",
+        self
+          .lua()
+          .to_value(&serde_json::json!({ "service": name }))?,
+      ),
+    ])?;
+    let dispatch = mlua::Value::Function(create_fn_dispatch(self.lua())?);
+    let args = (middlewares, route, not_found, req);
+    self.call_extract_error(dispatch, args).await
+  }
+
+  /// Replaces a response with no body by the service's `abel.error_page` for
+  /// its status, if any. The status and headers of the original response are
+  /// kept.
+  async fn apply_error_page<'a>(
+    &'a self,
+    error_pages: Table<'a>,
+    resp: LuaResponse,
+    req: mlua::AnyUserData<'a>,
+  ) -> Result<LuaResponse> {
+    if !resp.is_body_empty() {
+      return Ok(resp);
+    }
+    let page = match error_pages.raw_get(resp.status.as_u16())? {
+      mlua::Value::Nil => return Ok(resp),
+      mlua::Value::String(template) => {
+        let headers = HeaderMap::from_iter([(
+          CONTENT_TYPE,
+          HeaderValue::from_static("text/html; charset=utf-8"),
+        )]);
+        LuaResponse {
+          headers: Rc::new(RefCell::new(headers)),
+          body: Some(LuaBody::Bytes(template.as_bytes().into())),
+          ..Default::default()
+        }
       }
+      handler => (self.call_extract_error(handler, (resp.status.as_u16(), req))).await?,
+    };
+
+    let mut headers = resp.headers.borrow_mut();
+    let page_headers = page.headers.borrow();
+    for name in page_headers.keys() {
+      headers.remove(name);
     }
-    unreachable!("path matched but no handler found")
+    for (name, value) in page_headers.iter() {
+      headers.append(name, value.clone());
+    }
+    headers.remove(CONTENT_LENGTH);
+    drop((headers, page_headers));
+    Ok(LuaResponse {
+      body: page.body,
+      ..resp
+    })
   }
 
-  /// Extracts information from the code, but does not create the service yet
-  pub(crate) async fn prepare_service(
+  /// Extracts information from the code, but does not create the service yet.
+  ///
+  /// Returns the service's paths, whether it defines `abel.health`, and the
+  /// isolate.
+  pub(crate) async fn prepare_service<'a>(
     &self,
     name: &str,
+    uuid: Uuid,
     source: Source,
-  ) -> Result<(Vec<PathMatcher>, Isolate)> {
+    env: impl IntoIterator<Item = (&'a str, &'a str)>,
+    regional: &RegionalConfig,
+    storage: Option<&ServiceStorageConfig>,
+  ) -> Result<(Vec<PathMatcher>, bool, Isolate)> {
     check_name(name)?;
-    let (isolate, internal) = self.run_source(name, source).await?;
+    let (isolate, internal) = (self.run_source(name, uuid, source, env, regional, storage)).await?;
 
     let mut paths = Vec::new();
     for f in internal
@@ -126,24 +347,26 @@ impl Runtime {
       paths.push(path);
     }
 
-    Ok((paths, isolate))
+    let health_fn: Option<Function> =
+      (self.get_local_env(&isolate)?).raw_get_path("<local_env>", &["abel", "health"])?;
+
+    Ok((paths, health_fn.is_some(), isolate))
   }
 
   pub(crate) async fn create_service(
     &self,
-    name: &str,
     service: RunningService,
     isolate: Isolate,
     hot_update: bool,
   ) -> Result<()> {
-    if service.is_dropped() {
-      return Err(ServiceDropped.into());
-    }
+    let guard = service.try_upgrade()?;
     let loaded = LoadedService {
       service: service.clone(),
       isolate,
+      faults: Default::default(),
     };
-    self.loaded.borrow_mut().put(name.into(), loaded);
+    self.insert_loaded(cache_key(&guard), loaded, guard.pinned)?;
+    drop(guard);
     if !hot_update {
       self.run_start(service).await?;
     }
@@ -159,33 +382,128 @@ impl Runtime {
         .raw_get_path("<local_env>", &["abel", "start"])?
     };
     if let Some(f) = start_fn {
-      f.call_async(()).await.map_err(sanitize_error)?;
+      f.call_async::<_, ()>(()).await.map_err(sanitize_error)?;
     }
     Ok(())
   }
 
+  /// Runs the service's `abel.stop` if any, and then aborts detached tasks
+  /// of this version on every worker.
   pub(crate) async fn run_stop(&self, service: RunningService) -> Result<()> {
-    let stop_fn: Option<Function> = {
+    let (name, uuid) = {
+      let service = service.try_upgrade()?;
+      (service.name.clone(), service.uuid())
+    };
+    let result = async {
+      let stop_fn: Option<Function> = {
+        let loaded = self.load_service(service).await?;
+        self
+          .get_local_env(&loaded.isolate)?
+          .raw_get_path("<local_env>", &["abel", "stop"])?
+      };
+      if let Some(f) = stop_fn {
+        f.call_async::<_, ()>(()).await.map_err(sanitize_error)?;
+      }
+      // Call modules' `stop`
+      Ok(())
+    };
+    let result = result.await;
+    if let Some(detached) = self.lua().app_data_ref::<Arc<DetachedTasks>>() {
+      let count = detached.abort(uuid);
+      if count > 0 {
+        debug!("aborted {count} detached task(s) of service '{name}'");
+      }
+    }
+    result
+  }
+
+  /// Runs the service's `abel.health`, returning whether it is healthy and
+  /// the detail it gives. Services without one are always healthy.
+  pub(crate) async fn run_health(
+    &self,
+    service: RunningService,
+  ) -> Result<(bool, Option<serde_json::Value>)> {
+    let health_fn: Option<Function> = {
       let loaded = self.load_service(service).await?;
       self
         .get_local_env(&loaded.isolate)?
-        .raw_get_path("<local_env>", &["abel", "stop"])?
+        .raw_get_path("<local_env>", &["abel", "health"])?
+    };
+    let f = match health_fn {
+      Some(f) => f,
+      None => return Ok((true, None)),
     };
-    if let Some(f) = stop_fn {
+    let (healthy, detail): (mlua::Value, mlua::Value) =
       f.call_async(()).await.map_err(sanitize_error)?;
+    let healthy = !matches!(healthy, mlua::Value::Nil | mlua::Value::Boolean(false));
+    let detail = match detail {
+      mlua::Value::Nil => None,
+      detail => Some(serde_json::to_value(&detail).map_err(mlua::Error::external)?),
+    };
+    Ok((healthy, detail))
+  }
+
+  /// Loads the service's isolate on this worker, if it is not already.
+  pub(crate) async fn prewarm(&self, service: RunningService) -> Result<()> {
+    self.load_service(service).await.map(drop)
+  }
+
+  /// Runs the service's `abel.warmup` if any. Errors are only logged, since
+  /// the service works without it.
+  pub(crate) async fn run_warmup(&self, service: RunningService) {
+    let name = match service.try_upgrade() {
+      Ok(x) => x.name.clone(),
+      Err(_) => return,
+    };
+    let result = async {
+      let warmup_fn: Option<Function> = {
+        let loaded = self.load_service(service).await?;
+        self
+          .get_local_env(&loaded.isolate)?
+          .raw_get_path("<local_env>", &["abel", "warmup"])?
+      };
+      if let Some(f) = warmup_fn {
+        f.call_async::<_, ()>(()).await.map_err(sanitize_error)?;
+      }
+      Ok::<_, crate::Error>(())
+    };
+    if let Err(error) = result.await {
+      warn!("failed to warm up service '{name}': {error}");
     }
-    // Call modules' `stop`
-    Ok(())
   }
 
-  async fn run_source<'a>(&'a self, name: &str, source: Source) -> Result<(Isolate, Table<'a>)> {
-    let local_storage_path = get_local_storage_path(&self.state, name);
-    let isolate = self
-      .isolate_builder_with_stdlib(source.clone(), local_storage_path)?
-      .add_side_effect(side_effect_abel)?
-      .add_side_effect(side_effect_log(name))?
-      .build()?;
-    self.run_isolate(&isolate, "main.lua", ()).await?;
+  async fn run_source<'a, 'b>(
+    &'a self,
+    name: &str,
+    uuid: Uuid,
+    source: Source,
+    env: impl IntoIterator<Item = (&'b str, &'b str)>,
+    regional: &RegionalConfig,
+    storage: Option<&ServiceStorageConfig>,
+  ) -> Result<(Isolate, Table<'a>)> {
+    let mut local_storage = LocalStorage::new(get_local_storage_path(&self.state, name));
+    if let Some(config) = &self.state.storage {
+      local_storage = local_storage.with_remote(config.backend(name, storage));
+    }
+    let cache = self.state.caches.entry(name.into()).or_default().clone();
+    let span = info_span!("load_isolate", service = name);
+    let isolate = span.in_scope(|| {
+      self
+        .isolate_builder_with_stdlib(source.clone(), local_storage)?
+        .add_lib("cache", create_preload_cache(cache))?
+        .add_lib("log", create_preload_log(name, Some(uuid)))?
+        .add_side_effect(side_effect_abel)?
+        .add_side_effect(side_effect_rpc)?
+        .add_side_effect(side_effect_env(env))?
+        .add_side_effect(side_effect_service(name, regional))?
+        .add_side_effect(side_effect_spawn_detached(name, uuid))?
+        .add_side_effect(side_effect_wait(name))?
+        .add_side_effect(side_effect_log(name))?
+        .build()
+    })?;
+    (self.run_isolate::<_, ()>(&isolate, "main.lua", ()))
+      .instrument(span)
+      .await?;
 
     let internal = self.get_internal(&isolate)?;
     internal.raw_set("sealed", true)?;
@@ -193,56 +511,157 @@ impl Runtime {
     Ok((isolate, internal))
   }
 
+  /// Runs a standalone chunk in a fresh isolate, without registering it as a
+  /// service.
+  ///
+  /// The chunk receives `args` and its first return value is converted into
+  /// JSON. Its local storage is a temporary directory of its own, removed
+  /// once it returns.
+  pub(crate) async fn eval(
+    &self,
+    name: &str,
+    source: Source,
+    args: serde_json::Value,
+  ) -> Result<serde_json::Value> {
+    let local_storage_dir = tempfile::Builder::new().prefix("abel-eval-").tempdir()?;
+    let local_storage = LocalStorage::new(local_storage_dir.path());
+    let isolate = self
+      .isolate_builder_with_stdlib(source, local_storage)?
+      .add_lib("log", create_preload_log(name, None))?
+      .add_side_effect(side_effect_abel)?
+      .add_side_effect(side_effect_rpc)?
+      .add_side_effect(side_effect_env([]))?
+      .add_side_effect(side_effect_log(name))?
+      .build()?;
+    let result = async {
+      let args = self.lua().to_value(&args)?;
+      let value: mlua::Value = self.run_isolate(&isolate, "main.lua", args).await?;
+      Ok(serde_json::to_value(&value).map_err(mlua::Error::external)?)
+    }
+    .await;
+    self.remove_isolate(isolate)?;
+    result
+  }
+
   async fn load_service(&self, service: RunningService) -> Result<Ref<'_, LoadedService>> {
     let service_guard = service.try_upgrade()?;
     let name = &*service_guard.name;
-    {
-      let mut self_loaded = self.loaded.borrow_mut();
-      if let Some(loaded) = self_loaded.pop(name) {
-        if !loaded.service.is_dropped() && loaded.service.ptr_eq(&service) {
-          debug!(
-            "service '{name}' cache hit on '{}'",
-            std::thread::current().name().unwrap_or("<unnamed>")
-          );
-          self_loaded.put(name.into(), loaded);
-          drop(self_loaded);
-          self.loaded.borrow_mut().get(name);
-          return Ok(Ref::map(self.loaded.borrow(), |x| x.peek(name).unwrap()));
-        } else {
-          self.remove_isolate(loaded.isolate)?;
-        }
+    let key = &*cache_key(&service_guard);
+    // Taken out and put back, so that it moves between `loaded` and `pinned`
+    // if the service is (un)pinned.
+    if let Some(loaded) = self.take_loaded(key) {
+      if !loaded.service.is_dropped() && loaded.service.ptr_eq(&service) {
+        debug!(
+          "service '{name}' cache hit on '{}'",
+          std::thread::current().name().unwrap_or("<unnamed>")
+        );
+        self.state.metrics.record_isolate_cache(true);
+        self.insert_loaded(key.into(), loaded, service_guard.pinned)?;
+        return Ok(self.peek_loaded(key).unwrap());
+      } else {
+        self.remove_isolate(loaded.isolate)?;
       }
+    }
+
+    {
       debug!(
-        "service {name} cache miss on '{}'",
+        "service '{name}' cache miss on '{}'",
         std::thread::current().name().unwrap_or("<unnamed>")
       );
+      self.state.metrics.record_isolate_cache(false);
     }
     let source = service_guard.source();
-    let (isolate, _) = self.run_source(name, source.clone()).await?;
+    let env = service_guard.lua_env();
+    let uuid = service_guard.uuid;
+    let regional = service_guard.regional();
+    let storage = service_guard.storage();
+    let (isolate, _) =
+      (self.run_source(name, uuid, source.clone(), env, regional, storage)).await?;
 
     let loaded = LoadedService {
       service: service.clone(),
       isolate,
+      faults: Default::default(),
+    };
+    self.insert_loaded(key.into(), loaded, service_guard.pinned)?;
+    Ok(self.peek_loaded(key).unwrap())
+  }
+
+  fn peek_loaded(&self, key: &str) -> Option<Ref<'_, LoadedService>> {
+    if let Ok(x) = Ref::filter_map(self.pinned.borrow(), |x| x.get(key)) {
+      return Some(x);
+    }
+    Ref::filter_map(self.loaded.borrow(), |x| x.peek(key)).ok()
+  }
+
+  fn take_loaded(&self, key: &str) -> Option<LoadedService> {
+    (self.pinned.borrow_mut().remove(key)).or_else(|| self.loaded.borrow_mut().pop(key))
+  }
+
+  /// Caches a loaded isolate, evicting the least recently used one if the
+  /// cache is full and the service is not pinned.
+  fn insert_loaded(&self, key: Box<str>, loaded: LoadedService, pinned: bool) -> Result<()> {
+    let replaced = if pinned {
+      self.pinned.borrow_mut().insert(key, loaded)
+    } else {
+      let mut self_loaded = self.loaded.borrow_mut();
+      let evicted = if self_loaded.is_full() && self_loaded.peek(&key).is_none() {
+        self_loaded.pop_back()
+      } else {
+        None
+      };
+      let replaced = self_loaded.put(key, loaded);
+      drop(self_loaded);
+      if let Some((key, evicted)) = evicted {
+        debug!(
+          "evicted '{key}' on '{}'",
+          std::thread::current().name().unwrap_or("<unnamed>")
+        );
+        self.state.metrics.record_isolate_eviction();
+        self.remove_isolate(evicted.isolate)?;
+      }
+      replaced
     };
-    let mut self_loaded = self.loaded.borrow_mut();
-    self_loaded.put(name.into(), loaded);
-    drop(self_loaded);
-    self.loaded.borrow_mut().get(name);
-    Ok(Ref::map(self.loaded.borrow(), |x| x.peek(name).unwrap()))
+    if let Some(replaced) = replaced {
+      self.remove_isolate(replaced.isolate)?;
+    }
+    Ok(())
   }
 
   pub fn cleanup(&self) {
     let mut count = 0;
-    self.loaded.borrow_mut().retain(|_, v| {
+    let mut retain = |v: &LoadedService| {
       let r = !v.service.is_dropped();
       if !r {
         count += 1;
       }
       r
-    });
+    };
+    self.loaded.borrow_mut().retain(|_, v| retain(v));
+    self.pinned.borrow_mut().retain(|_, v| retain(v));
     if count > 0 {
       info!("successfully cleaned {count} dropped services");
     }
+
+    let idle = (self.loaded.borrow().iter())
+      .filter_map(|(key, v)| {
+        let service = v.service.try_upgrade().ok()?;
+        let unload_after = service.idle_config(self.state.idle).unload_after;
+        matches!(unload_after, Some(x) if service.idle_secs() >= x)
+          .then(|| (key.clone(), service.name.clone()))
+      })
+      .collect::<Vec<_>>();
+    for (key, name) in idle {
+      if let Some(loaded) = self.loaded.borrow_mut().pop(&key) {
+        if let Err(error) = self.remove_isolate(loaded.isolate) {
+          warn!("failed to unload idle service '{name}': {error}");
+        }
+      }
+      debug!(
+        "unloaded idle service '{name}' on '{}'",
+        std::thread::current().name().unwrap_or("<unnamed>")
+      );
+    }
   }
 }
 
@@ -260,6 +679,245 @@ impl DerefMut for Runtime {
   }
 }
 
+/// Versions of a service are cached separately, so that a canary and the
+/// stable version do not keep evicting each other.
+fn cache_key(service: &ServiceImpl) -> Box<str> {
+  format!("{}@{}", service.name, service.uuid()).into()
+}
+
+/// Finds the handler of `path`, along with its `timeout` in seconds, if any.
+fn path_policy(internal: &Table) -> Result<PathPolicy> {
+  let policy: Option<Table> = internal.raw_get("path_policy")?;
+  let policy = match policy {
+    Some(x) => x,
+    None => return Ok(Default::default()),
+  };
+  // Values are already checked in `abel.path_policy`
+  let trailing_slash: Option<String> = policy.raw_get("trailing_slash")?;
+  let decode: Option<String> = policy.raw_get("decode")?;
+  Ok(PathPolicy {
+    trailing_slash: (trailing_slash.and_then(|x| x.parse().ok())).unwrap_or_default(),
+    merge_slashes: policy
+      .raw_get::<_, Option<bool>>("merge_slashes")?
+      .unwrap_or(false),
+    decode: (decode.and_then(|x| x.parse().ok())).unwrap_or_default(),
+  })
+}
+
+/// Redirects to `uri`'s path with its trailing slash added or removed.
+fn redirect_trailing_slash(uri: &Uri) -> Result<LuaResponse> {
+  let path = toggle_trailing_slash(uri.path()).unwrap_or_else(|| "/".into());
+  let location = match uri.query() {
+    Some(query) => format!("{path}?{query}"),
+    None => path,
+  };
+  let resp = LuaResponse {
+    status: StatusCode::PERMANENT_REDIRECT,
+    body: Some(LuaBody::Empty),
+    ..Default::default()
+  };
+  let location = HeaderValue::try_from(location).map_err(rt_error)?;
+  resp.headers.borrow_mut().insert(LOCATION, location);
+  Ok(resp)
+}
+
+/// A `listen` path matching a request.
+struct Route<'a> {
+  /// The normalized path, with its trailing slash toggled if `toggled`.
+  path: String,
+  params: Params,
+  handler: mlua::Value<'a>,
+  timeout: Option<f64>,
+  /// Only matched with its trailing slash added or removed.
+  toggled: bool,
+}
+
+/// Finds the `listen` path matching `path` after normalizing it, trying it
+/// with its trailing slash toggled unless `policy` is strict about it.
+fn route<'a>(
+  paths: &[PathMatcher],
+  internal: &Table<'a>,
+  policy: PathPolicy,
+  path: &str,
+) -> mlua::Result<Option<Route<'a>>> {
+  let find_match = |path: &str| (paths.iter()).find_map(|m| m.gen_params(path).map(|p| (p, m)));
+  let path = policy.normalize(path).into_owned();
+  let (path, (params, matcher), toggled) = match find_match(&path) {
+    Some(matched) => (path, matched, false),
+    None if policy.trailing_slash == TrailingSlash::Strict => return Ok(None),
+    None => match toggle_trailing_slash(&path) {
+      Some(alt) => match find_match(&alt) {
+        Some(matched) => (alt, matched, true),
+        None => return Ok(None),
+      },
+      None => return Ok(None),
+    },
+  };
+  let (handler, timeout) = find_handler(internal, matcher.as_str())?;
+  Ok(Some(Route {
+    path,
+    params,
+    handler,
+    timeout,
+    toggled,
+  }))
+}
+
+fn set_timeout(lua: &Lua, timeout: Option<f64>) {
+  if let Some(timeout) = timeout {
+    let timeout = Duration::try_from_secs_f64(timeout).unwrap_or(Duration::MAX);
+    TaskContext::set_timeout(lua, timeout);
+  }
+}
+
+/// Creates `route(internal, fallback, req)`, called after the middlewares to
+/// find the handler of the request they pass on, by its possibly rewritten
+/// `path`. `fallback` is returned if nothing matches.
+///
+/// Paths only matching with their trailing slash toggled are redirected as
+/// `policy` says, unless the middlewares changed `path` from `original`, as
+/// the client cannot be redirected to where it did not ask for.
+fn create_fn_route(
+  lua: &Lua,
+  service: RunningService,
+  policy: PathPolicy,
+  uri: Uri,
+  original: String,
+) -> mlua::Result<Function<'_>> {
+  lua.create_function(
+    move |lua, (internal, fallback, req): (Table, mlua::Value, mlua::Value)| {
+      let (path, method) = match &req {
+        mlua::Value::Table(t) => (t.get::<_, String>("path")?, t.get::<_, String>("method")?),
+        mlua::Value::UserData(u) if u.is::<LuaRequest>() => {
+          let u = u.borrow::<LuaRequest>()?;
+          let path = (u.path.clone()).unwrap_or_else(|| u.uri.path().into());
+          (path, u.method.to_string())
+        }
+        _ => return Err(rt_error("request expected")),
+      };
+      let method = Method::from_bytes(method.as_bytes()).map_err(rt_error)?;
+      let guard = service.try_upgrade()?;
+      let route = match route(&guard.paths, &internal, policy, &path)? {
+        Some(x) => x,
+        None => return Ok(fallback),
+      };
+      if route.toggled && policy.trailing_slash == TrailingSlash::Redirect && path == original {
+        let resp = redirect_trailing_slash(&uri)?;
+        let resp = Cell::new(Some(resp));
+        let f = lua.create_function(move |_lua, ()| Ok(resp.take()))?;
+        return Ok(mlua::Value::Function(f));
+      }
+
+      set_timeout(lua, route.timeout);
+      let handler = select_method(lua, route.handler, &method)?;
+      let params = (route.params.into_iter()).map(|(k, v)| (k.into_string(), v.into_string()));
+      let params = lua.create_table_from(params)?;
+      match &req {
+        mlua::Value::Table(t) => {
+          t.raw_set("params", params)?;
+          t.raw_set("path", route.path)?;
+        }
+        mlua::Value::UserData(u) => {
+          u.set_named_user_value("params", params)?;
+          u.borrow_mut::<LuaRequest>()?.path = Some(route.path);
+        }
+        _ => unreachable!(),
+      }
+      Ok(handler)
+    },
+  )
+}
+
+fn find_handler<'a>(internal: &Table<'a>, path: &str) -> Result<(mlua::Value<'a>, Option<f64>)> {
+  for f in internal
+    .raw_get_path::<Table>("<internal>", &["paths"])?
+    .sequence_values::<Table>()
+  {
+    let f = f?;
+    if f.raw_get::<u8, String>(1)? == path {
+      return Ok((f.raw_get(2u8)?, f.raw_get("timeout")?));
+    }
+  }
+  unreachable!("path matched but no handler found")
+}
+
+/// Picks the handler of `method` if `handler` is a table of methods.
+///
+/// HEAD falls back to GET's handler. Methods not in the table are answered
+/// with their `Allow` header, with 204 for OPTIONS and 405 for others.
+fn select_method<'a>(
+  lua: &'a Lua,
+  handler: mlua::Value<'a>,
+  method: &Method,
+) -> mlua::Result<mlua::Value<'a>> {
+  let table = match &handler {
+    mlua::Value::Table(t) if !is_callable(t)? => t.clone(),
+    _ => return Ok(handler),
+  };
+  let f: mlua::Value = table.raw_get(method.as_str())?;
+  if f != mlua::Value::Nil {
+    return Ok(f);
+  }
+  if method == Method::HEAD {
+    let f: mlua::Value = table.raw_get("GET")?;
+    if f != mlua::Value::Nil {
+      return Ok(f);
+    }
+  }
+
+  let mut allowed = (table.pairs::<String, mlua::Value>())
+    .map(|x| x.map(|(k, _)| k))
+    .collect::<mlua::Result<Vec<_>>>()?;
+  if allowed.iter().any(|x| x == "GET") {
+    allowed.push("HEAD".into());
+  }
+  allowed.push("OPTIONS".into());
+  allowed.sort();
+  allowed.dedup();
+  let allow = HeaderValue::try_from(allowed.join(", ")).map_err(rt_error)?;
+  let got = method.to_string();
+  let f = lua.create_function(move |_lua, ()| {
+    let resp = if got == "OPTIONS" {
+      LuaBody::Empty.into_default_response()
+    } else {
+      let body = serde_json::json!({
+        "error": "method not allowed",
+        "detail": { "expected": allowed, "got": got },
+      });
+      let mut resp = LuaBody::Json(body).into_default_response();
+      resp.status = StatusCode::METHOD_NOT_ALLOWED;
+      resp
+    };
+    resp.headers.borrow_mut().insert(ALLOW, allow.clone());
+    Ok(resp)
+  })?;
+  Ok(mlua::Value::Function(f))
+}
+
+fn is_callable(table: &Table) -> mlua::Result<bool> {
+  match table.get_metatable() {
+    Some(mt) => Ok(mt.raw_get::<_, mlua::Value>("__call")? != mlua::Value::Nil),
+    None => Ok(false),
+  }
+}
+
+/// Drops the body of a response to HEAD, keeping its length if known.
+async fn drop_body(resp: &mut LuaResponse) -> mlua::Result<()> {
+  use hyper::body::HttpBody;
+  let len = match resp.body.take() {
+    Some(LuaBody::Bytes(x)) => Some(x.len() as u64),
+    Some(LuaBody::Json(x)) => Some(x.to_string().len() as u64),
+    Some(LuaBody::File(mut x)) => Some(x.len().await.map_err(rt_error)?),
+    Some(LuaBody::Stream(x)) => x.size_hint().exact(),
+    Some(LuaBody::Empty) | None => None,
+  };
+  if let Some(len) = len {
+    (resp.headers.borrow_mut().entry(CONTENT_LENGTH)).or_insert_with(|| len.into());
+  }
+  resp.body = Some(LuaBody::Empty);
+  Ok(())
+}
+
 pub fn check_name(name: &str) -> Result<()> {
   static NAME_CHECK_REGEX: Lazy<Regex> = Lazy::new(|| Regex::new("^[a-z0-9-]{1,64}$").unwrap());
 
@@ -269,3 +927,20 @@ pub fn check_name(name: &str) -> Result<()> {
     Err(InvalidServiceName { name: name.into() }.into())
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn test_faults_decay() {
+    let faults = Faults::default();
+    let start = Instant::now();
+    assert_eq!(faults.record(start), 1);
+    assert_eq!(faults.record(start + Duration::from_secs(30)), 2);
+    // A new window starts after the first one ends
+    assert_eq!(faults.record(start + FAULT_WINDOW), 1);
+    assert_eq!(faults.record(start + FAULT_WINDOW * 3 / 2), 2);
+    assert_eq!(faults.record(start + FAULT_WINDOW * 3 / 2), 3);
+  }
+}
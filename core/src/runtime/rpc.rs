@@ -0,0 +1,382 @@
+//! Calls between services.
+//!
+//! Arguments and return values cross isolates as MessagePack, so services
+//! never share Lua values with each other.
+//!
+//! A call runs as a task of its own, as if it were a request to the callee:
+//! under the callee's limits and concurrency, counted in its metrics and
+//! starting it first if it was stopped for being idle.
+
+use super::Runtime;
+use crate::lua::error::{check_value, rt_error, rt_error_fmt, tag_handler};
+use crate::lua::LuaCacheExt;
+use crate::service::{resolve_name, RunningService};
+use crate::task::TaskContext;
+use crate::ErrorKind::{RpcMethodNotFound, ServiceNotFound, ServiceOverloaded, ServiceStopped};
+use crate::{task_limits, AbelInner, Result};
+use mlua::Value::Nil;
+use mlua::{AnyUserData, Function, Lua, LuaSerdeExt, MultiValue, Table, UserData};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+pub fn side_effect_rpc(lua: &Lua, local_env: Table, internal: Table) -> mlua::Result<()> {
+  use mlua::Value::Function as Func;
+  internal.raw_set("rpc", lua.create_table()?)?;
+  let rpc = lua.create_table_from([
+    ("export", Func(create_fn_export(lua, internal)?)),
+    ("call", Func(create_fn_call(lua)?)),
+  ])?;
+  let abel: Table = local_env.raw_get("abel")?;
+  abel.raw_set("rpc", rpc)
+}
+
+fn create_fn_export<'a>(lua: &'a Lua, internal: Table<'a>) -> mlua::Result<Function<'a>> {
+  let f = lua.create_cached_function("abel:abel.rpc.export", |lua, mut args: MultiValue| {
+    let internal: Table = check_value(lua, args.pop_front(), "table").unwrap();
+    if internal.raw_get("sealed")? {
+      return Err(rt_error(
+        "cannot call `rpc.export` from places other than the top level of `main.lua`",
+      ));
+    }
+    let method: mlua::String =
+      check_value(lua, args.pop_front(), "string").map_err(tag_handler(lua, 1, 1))?;
+    let handler: Function =
+      check_value(lua, args.pop_front(), "function").map_err(tag_handler(lua, 2, 1))?;
+    let schema = match args.pop_front().unwrap_or(Nil) {
+      Nil => MethodSchema::default(),
+      schema => {
+        let schema: MethodSchema =
+          (lua.from_value(schema)).map_err(|error| rt_error_fmt!("invalid schema: {error}"))?;
+        (schema.args.iter().chain(&schema.returns))
+          .try_for_each(Schema::check)
+          .map_err(|error| rt_error_fmt!("invalid schema: {error}"))?;
+        schema
+      }
+    };
+    let entry = lua.create_table_from([("handler", mlua::Value::Function(handler))])?;
+    entry.raw_set("schema", schema)?;
+    internal.raw_get::<_, Table>("rpc")?.raw_set(method, entry)
+  })?;
+  f.bind(internal)
+}
+
+fn create_fn_call(lua: &Lua) -> mlua::Result<Function<'_>> {
+  lua.create_cached_async_function(
+    "abel:abel.rpc.call",
+    |lua, mut args: MultiValue| async move {
+      let service: mlua::String =
+        check_value(lua, args.pop_front(), "string").map_err(tag_handler(lua, 1, 1))?;
+      let method: mlua::String =
+        check_value(lua, args.pop_front(), "string").map_err(tag_handler(lua, 2, 1))?;
+      let args = rmp_serde::to_vec(&args.pop_front().unwrap_or(Nil)).map_err(rt_error)?;
+
+      let abel = (lua.app_data_ref::<std::rc::Weak<Runtime>>())
+        .and_then(|x| x.upgrade())
+        .and_then(|rt| rt.state.abel.get().and_then(std::sync::Weak::upgrade))
+        .ok_or_else(|| rt_error("`rpc.call` can only be used in services"))?;
+      let request_id = TaskContext::request_id(lua);
+      let result = (abel.call_rpc(service.to_str()?, method.to_str()?, args, request_id)).await?;
+
+      let result: rmpv::Value = rmp_serde::from_slice(&result).map_err(rt_error)?;
+      lua.to_value(&result)
+    },
+  )
+}
+
+impl AbelInner {
+  /// Calls `method` exported by `service` with MessagePack-encoded `args`,
+  /// returning the encoded result.
+  pub(crate) async fn call_rpc(
+    &self,
+    service: &str,
+    method: &str,
+    args: Vec<u8>,
+    request_id: Option<Arc<str>>,
+  ) -> Result<Vec<u8>> {
+    let name = resolve_name(&self.state.services, service).ok_or(ServiceNotFound {
+      name: service.into(),
+    })?;
+    let service = (self.activate_service(&name).await).map_err(|error| {
+      match (error.kind(), self.service_pool.get(&name)) {
+        (ServiceNotFound { .. }, Some(_)) => ServiceStopped { name: name.clone() }.into(),
+        _ => error,
+      }
+    })?;
+    let (limits, concurrency) = {
+      let guard = service.try_upgrade()?;
+      guard.touch();
+      (task_limits(&guard), guard.concurrency.clone())
+    };
+    let _permit = match concurrency {
+      Some(x) => Some((x.acquire().await).ok_or_else(|| ServiceOverloaded { name: name.clone() })?),
+      None => None,
+    };
+
+    let start = Instant::now();
+    let cpu_time = Arc::<Mutex<Duration>>::default();
+    let method = method.to_owned();
+    let result = (self.runtime_pool)
+      .scope_with_cpu_time(limits, cpu_time.clone(), move |rt| async move {
+        if let Some(id) = request_id {
+          TaskContext::set_request_id(rt.lua(), id);
+        }
+        rt.run_rpc(service, &method, &args).await
+      })
+      .await;
+    let cpu_time = *cpu_time.lock();
+    (self.state.metrics).record_request(&name, start.elapsed(), cpu_time, result.is_err());
+    result
+  }
+}
+
+impl Runtime {
+  /// Runs `method` exported by `service` in the current task.
+  async fn run_rpc(&self, service: RunningService, method: &str, args: &[u8]) -> Result<Vec<u8>> {
+    let name = service.try_upgrade()?.name.clone();
+    let entry: Option<Table> = {
+      let loaded = self.load_service(service).await?;
+      let rpc: Table = self.get_internal(&loaded.isolate)?.raw_get("rpc")?;
+      rpc.raw_get(method)?
+    };
+    let entry = entry.ok_or_else(|| RpcMethodNotFound {
+      service: name.clone(),
+      method: method.into(),
+    })?;
+    let handler: Function = entry.raw_get("handler")?;
+    let schema: AnyUserData = entry.raw_get("schema")?;
+
+    let args: rmpv::Value = rmp_serde::from_slice(args).map_err(rt_error)?;
+    if let Some(args_schema) = &schema.borrow::<MethodSchema>()?.args {
+      (args_schema.validate(&args))
+        .map_err(|error| rt_error_fmt!("invalid arguments to '{name}.{method}': {error}"))?;
+    }
+    let args = self.lua().to_value(&args)?;
+    let result: mlua::Value = self
+      .call_extract_error(mlua::Value::Function(handler), args)
+      .await?;
+
+    let result = rmpv::ext::to_value(&result).map_err(rt_error)?;
+    if let Some(returns_schema) = &schema.borrow::<MethodSchema>()?.returns {
+      (returns_schema.validate(&result))
+        .map_err(|error| rt_error_fmt!("invalid return value of '{name}.{method}': {error}"))?;
+    }
+    Ok(rmp_serde::to_vec(&result).map_err(rt_error)?)
+  }
+}
+
+/// Optional schemas of an exported method's arguments and return value.
+#[derive(Debug, Default, Deserialize)]
+struct MethodSchema {
+  args: Option<Schema>,
+  returns: Option<Schema>,
+}
+
+impl UserData for MethodSchema {}
+
+/// Shape of a value, written in Lua as:
+///
+/// - a type name, `"any"`, `"nil"`, `"boolean"`, `"number"`, `"integer"`,
+///   `"string"` or `"table"`, with a trailing `?` if it can also be nil;
+/// - `{ schema }` for arrays of `schema`;
+/// - `{ field = schema, ... }` for tables with such fields. Other fields are
+///   allowed.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum Schema {
+  Type(String),
+  Array((Box<Schema>,)),
+  Table(HashMap<String, Schema>),
+}
+
+const TYPES: &[&str] = &[
+  "any", "nil", "boolean", "number", "integer", "string", "table",
+];
+
+impl Schema {
+  fn check(&self) -> Result<(), String> {
+    match self {
+      Self::Type(ty) if TYPES.contains(&ty.strip_suffix('?').unwrap_or(ty)) => Ok(()),
+      Self::Type(ty) => Err(format!("unknown type '{ty}'")),
+      Self::Array((item,)) => item.check(),
+      Self::Table(fields) => fields.values().try_for_each(Self::check),
+    }
+  }
+
+  fn validate(&self, value: &rmpv::Value) -> Result<(), String> {
+    self.validate_at(value, &mut String::new())
+  }
+
+  fn validate_at(&self, value: &rmpv::Value, path: &mut String) -> Result<(), String> {
+    use rmpv::Value as V;
+    let mismatch = |expected: &str, path: &str| {
+      let at = if path.is_empty() {
+        String::new()
+      } else {
+        format!(" at '{path}'")
+      };
+      Err(format!("{expected} expected{at}, got {}", type_name(value)))
+    };
+
+    match self {
+      Self::Type(ty) => {
+        let (ty, optional) = match ty.strip_suffix('?') {
+          Some(ty) => (ty, true),
+          None => (ty.as_str(), false),
+        };
+        let ok = match (ty, value) {
+          (_, V::Nil) => optional || ty == "any" || ty == "nil",
+          ("any", _) => true,
+          ("boolean", V::Boolean(_)) => true,
+          ("number", V::Integer(_) | V::F32(_) | V::F64(_)) => true,
+          ("integer", V::Integer(_)) => true,
+          ("integer", V::F64(x)) => x.fract() == 0.,
+          ("string", V::String(_) | V::Binary(_)) => true,
+          ("table", V::Array(_) | V::Map(_)) => true,
+          _ => false,
+        };
+        if !ok {
+          return mismatch(ty, path);
+        }
+      }
+      Self::Array((item,)) => {
+        let items = match value {
+          V::Array(items) => &items[..],
+          // Empty tables are sent as maps
+          V::Map(x) if x.is_empty() => &[],
+          _ => return mismatch("array", path),
+        };
+        let len = path.len();
+        for (i, x) in items.iter().enumerate() {
+          write!(path, "[{}]", i + 1).unwrap();
+          item.validate_at(x, path)?;
+          path.truncate(len);
+        }
+      }
+      Self::Table(fields) => {
+        let entries = match value {
+          V::Map(entries) => &entries[..],
+          V::Array(x) if x.is_empty() => &[],
+          _ => return mismatch("table", path),
+        };
+        let len = path.len();
+        for (field, schema) in fields {
+          let x = (entries.iter())
+            .find(|(k, _)| k.as_str() == Some(field))
+            .map_or(&V::Nil, |(_, v)| v);
+          if !path.is_empty() {
+            path.push('.');
+          }
+          path.push_str(field);
+          schema.validate_at(x, path)?;
+          path.truncate(len);
+        }
+      }
+    }
+    Ok(())
+  }
+}
+
+fn type_name(value: &rmpv::Value) -> &'static str {
+  use rmpv::Value as V;
+  match value {
+    V::Nil => "nil",
+    V::Boolean(_) => "boolean",
+    V::Integer(_) => "integer",
+    V::F32(_) | V::F64(_) => "number",
+    V::String(_) | V::Binary(_) => "string",
+    V::Array(_) | V::Map(_) => "table",
+    V::Ext(..) => "extension",
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::source::{SingleSource, Source};
+  use serde_json::json;
+  use tempfile::TempDir;
+  use test_case::test_case;
+
+  const CALLEE: &str = r#"
+    abel.rpc.export("add", function(args) return args.x + args.y end)
+    abel.rpc.export("spin", function() while true do end end)
+    abel.listen("/", function() end)
+  "#;
+
+  async fn call(abel: &crate::Abel, method: &str) -> crate::Result<serde_json::Value> {
+    let code = format!(r#"return abel.rpc.call("callee", "{method}", {{ x = 1, y = 2 }})"#);
+    abel
+      .eval(None, Source::new(SingleSource::new(code)), ())
+      .await
+  }
+
+  #[tokio::test]
+  async fn test_call_idle_stopped() {
+    let dir = TempDir::new().unwrap();
+    let abel = crate::tests::abel(&dir);
+    let source = Source::new(SingleSource::new(CALLEE));
+    (abel.cold_update_or_create_service("callee", None, source, Default::default()))
+      .await
+      .unwrap();
+    let uuid = abel.stop_service("callee").await.unwrap().uuid();
+    abel.inner.idle_stopped.lock().insert("callee".into(), uuid);
+
+    assert_eq!(call(&abel, "add").await.unwrap(), json!(3));
+    assert!(abel.get_running_service("callee").is_ok());
+    let metrics = abel.metrics();
+    let callee = metrics
+      .services
+      .iter()
+      .find(|x| x.name == "callee")
+      .unwrap();
+    assert_eq!(callee.requests, 1);
+  }
+
+  #[tokio::test]
+  async fn test_call_stopped() {
+    let dir = TempDir::new().unwrap();
+    let abel = crate::tests::abel(&dir);
+    let source = Source::new(SingleSource::new(CALLEE));
+    (abel.cold_update_or_create_service("callee", None, source, Default::default()))
+      .await
+      .unwrap();
+    abel.stop_service("callee").await.unwrap();
+    assert!(call(&abel, "add").await.is_err());
+  }
+
+  #[tokio::test]
+  async fn test_call_callee_limits() {
+    let dir = TempDir::new().unwrap();
+    let abel = crate::tests::abel(&dir);
+    let config = serde_json::from_value(json!({ "limits": { "cpu_ms_per_request": 50 } })).unwrap();
+    let source = Source::new(SingleSource::new(CALLEE));
+    (abel.cold_update_or_create_service("callee", None, source, config))
+      .await
+      .unwrap();
+
+    // The caller is allowed a second, but the callee only 50 ms.
+    let start = Instant::now();
+    assert!(call(&abel, "spin").await.is_err());
+    assert!(start.elapsed() < Duration::from_millis(500));
+  }
+
+  #[test_case(json!("string"), json!("abc") => Ok(()); "string")]
+  #[test_case(json!("integer?"), json!(null) => Ok(()); "optional")]
+  #[test_case(json!("integer"), json!(1.5) => Err("integer expected, got number".into()); "not integer")]
+  #[test_case(json!(["number"]), json!([1, 2.5]) => Ok(()); "array")]
+  #[test_case(
+    json!({ "user": { "name": "string", "tags": ["string"] } }),
+    json!({ "user": { "name": "a", "tags": ["b", 3] } })
+    => Err("string expected at 'user.tags[2]', got integer".into());
+    "nested"
+  )]
+  #[test_case(json!({ "id": "integer" }), json!({}) => Err("integer expected at 'id', got nil".into()); "missing field")]
+  fn test_schema(schema: serde_json::Value, value: serde_json::Value) -> Result<(), String> {
+    let schema: Schema = serde_json::from_value(schema).unwrap();
+    schema.validate(&rmpv::ext::to_value(value).unwrap())
+  }
+}
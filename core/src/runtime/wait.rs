@@ -0,0 +1,61 @@
+//! Waking requests that wait on a key, for long polling.
+//!
+//! Keys are scoped to services. `abel.notify(key)` wakes every request that
+//! is waiting on `key` at that moment, across all workers; ones that start
+//! waiting afterwards wait for the next notification.
+
+use crate::service::ServiceName;
+use dashmap::DashMap;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+
+/// The longest a single `abel.wait_for` call may wait.
+pub(crate) const MAX_WAIT: Duration = Duration::from_secs(300);
+
+type Key = (ServiceName, Box<str>);
+
+#[derive(Debug, Default)]
+pub(crate) struct Waiters(DashMap<Key, Arc<Notify>>);
+
+impl Waiters {
+  /// Waits until `key` of `service` is notified, or `timeout` passes.
+  /// Returns whether it was notified.
+  pub async fn wait(&self, service: &str, key: &str, timeout: Duration) -> bool {
+    let key: Key = (service.into(), key.into());
+    let notify = self.0.entry(key.clone()).or_default().clone();
+    let waiting = Waiting {
+      waiters: self,
+      key,
+      notify,
+    };
+    let notified = waiting.notify.notified();
+    tokio::time::timeout(timeout, notified).await.is_ok()
+  }
+
+  #[cfg(test)]
+  pub fn is_empty(&self) -> bool {
+    self.0.is_empty()
+  }
+
+  pub fn notify(&self, service: &str, key: &str) {
+    if let Some(notify) = self.0.get(&(service.into(), key.into())) {
+      notify.notify_waiters();
+    }
+  }
+}
+
+/// Removes the entry once its last waiter is gone, even if the waiting
+/// request is aborted.
+struct Waiting<'a> {
+  waiters: &'a Waiters,
+  key: Key,
+  notify: Arc<Notify>,
+}
+
+impl Drop for Waiting<'_> {
+  fn drop(&mut self) {
+    // The map holds one reference and we hold another
+    (self.waiters.0).remove_if(&self.key, |_, x| Arc::strong_count(x) <= 2);
+  }
+}
@@ -1,13 +1,19 @@
 use super::{
-  get_local_storage_path, RunningService, Service, ServiceImpl, ServiceInfo, ServiceName,
-  ServicePool, ServiceState, StoppedService,
+  get_local_storage_path, normalize_name, prewarm, unix_secs, Canary, CanaryRule, Concurrency,
+  RunningService, Service, ServiceImpl, ServiceInfo, ServiceName, ServicePool, ServiceState,
+  StoppedService,
 };
 use crate::lua::isolate::Isolate;
+use crate::openapi::OpenApi;
 use crate::runtime::Runtime;
 use crate::source::Source;
 use crate::task::Pool;
-use crate::ErrorKind::{self, ServiceNotFound, ServiceStopped};
+use crate::ErrorKind::{
+  self, InvalidOpenApi, ServiceNotFound, ServiceStopped, StorageNotConfigured,
+};
 use crate::{Config, Error, Result};
+use std::collections::HashMap;
+use std::sync::atomic::AtomicU64;
 use std::sync::Arc;
 use uuid::Uuid;
 
@@ -39,17 +45,84 @@ async fn prepare_service(
   let Config {
     pkg_name,
     description,
+    report_dsn,
+    aliases,
+    limits,
+    mirror,
+    backup,
+    idle,
+    compress,
+    filters,
+    idempotency,
+    rate_limit,
+    pinned,
+    prewarm,
+    validate_openapi,
+    regional,
+    storage,
+    env,
+    secrets,
   } = config;
-  let (paths, isolate) = rt.prepare_service(&name, source.clone()).await?;
+  let secrets = (secrets.into_iter())
+    .map(|secret| Ok((secret.clone(), rt.state.secrets.get(&name, &secret)?)))
+    .collect::<Result<HashMap<_, _>>>()?;
+  if storage.is_some() && rt.state.storage.is_none() {
+    return Err(StorageNotConfigured.into());
+  }
+  let openapi = if validate_openapi {
+    let doc = match source.get_bytes("openapi.json").await {
+      Ok(doc) => doc,
+      Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+        let msg = "not found in source, but `validate_openapi` is set";
+        return Err(InvalidOpenApi { msg: msg.into() }.into());
+      }
+      Err(error) => return Err(error.into()),
+    };
+    Some(Arc::new(OpenApi::from_slice(&doc)?))
+  } else {
+    None
+  };
+  let lua_env = (env.iter().chain(secrets.iter())).map(|(k, v)| (k.as_str(), v.as_str()));
+  let uuid = uuid.unwrap_or_else(Uuid::new_v4);
+  let (paths, has_health, isolate) = rt
+    .prepare_service(
+      &name,
+      uuid,
+      source.clone(),
+      lua_env,
+      &regional,
+      storage.as_ref(),
+    )
+    .await?;
   let service_impl = ServiceImpl {
     info: ServiceInfo {
       name,
       pkg_name,
       description,
       paths,
-      uuid: uuid.unwrap_or_else(Uuid::new_v4),
+      uuid,
+      aliases: aliases.iter().map(|x| normalize_name(x).into()).collect(),
+      report_dsn,
+      limits,
+      mirror,
+      backup,
+      idle,
+      compress,
+      filters,
+      idempotency,
+      rate_limit,
+      pinned,
+      prewarm,
+      openapi,
+      regional,
+      storage,
+      env,
+      secrets,
     },
     source,
+    concurrency: Concurrency::from_limits(&limits),
+    last_active: Arc::new(AtomicU64::new(unix_secs())),
+    health: has_health.then(Default::default),
   };
   Ok((service_impl, isolate))
 }
@@ -63,6 +136,7 @@ impl ServicePool {
     source: Source,
     config: Config,
   ) -> Result<(StoppedService<'_>, Option<ServiceImpl>, ErrorPayload)> {
+    self.check_aliases(&name, &config.aliases)?;
     let services = self.services.clone();
     let name2 = name.clone();
     let (service_impl, error_payload) = rt_pool
@@ -83,6 +157,7 @@ impl ServicePool {
       })
       .await?;
 
+    self.remove_canary(rt_pool, &name);
     let replaced = (self.services)
       .remove(&*name)
       .map(|(_name, service)| service.into_impl());
@@ -102,6 +177,7 @@ impl ServicePool {
     source: Source,
     config: Config,
   ) -> Result<(Service<'_>, Option<ServiceImpl>, ErrorPayload)> {
+    self.check_aliases(&name, &config.aliases)?;
     let services = self.services.clone();
     let state = self.state.clone();
     let name2 = name.clone();
@@ -124,7 +200,7 @@ impl ServicePool {
 
         let service_impl = Arc::new(service_impl);
         let result = rt
-          .create_service(&service_impl.name, service_impl.downgrade(), isolate, false)
+          .create_service(service_impl.downgrade(), isolate, false)
           .await;
         let state = ServiceState::Running(service_impl);
         let state = match result {
@@ -143,7 +219,9 @@ impl ServicePool {
 
     match service_state {
       ServiceState::Running(service_impl) => {
+        prewarm(rt_pool, &service_impl).await;
         let service = service_impl.downgrade();
+        self.remove_canary(rt_pool, &name);
         let replaced = (self.services)
           .remove(&*name)
           .map(|(_name, service)| service.into_impl());
@@ -154,6 +232,7 @@ impl ServicePool {
         Ok((Service::Running(service), replaced, error_payload))
       }
       ServiceState::Stopped(_) => {
+        self.remove_canary(rt_pool, &name);
         let replaced = (self.services)
           .remove(&*name)
           .map(|(_name, service)| service.into_impl());
@@ -176,24 +255,12 @@ impl ServicePool {
     source: Source,
     config: Config,
   ) -> Result<(RunningService, ServiceImpl)> {
-    match self.get(&*name) {
-      Some(x) if x.is_stopped() => return Err(ErrorKind::ServiceStopped { name }.into()),
-      None => return Err(ErrorKind::ServiceNotFound { name }.into()),
-      _ => {}
-    }
-
-    let name2 = name.clone();
-    let service_impl = rt_pool
-      .scope(move |rt| async move {
-        let (service_impl, isolate) = prepare_service(&rt, name2, uuid, source, config).await?;
-        let service_impl = Arc::new(service_impl);
-        rt.create_service(&service_impl.name, service_impl.downgrade(), isolate, true)
-          .await?;
-        Ok::<_, crate::Error>(service_impl)
-      })
+    let service_impl = self
+      .prepare_hot_update(rt_pool, name.clone(), uuid, source, config, false)
       .await?;
 
     let service = service_impl.downgrade();
+    self.remove_canary(rt_pool, &name);
     let replaced = (self.services)
       .remove(&*name)
       .map(|(_name, service)| service.into_impl())
@@ -202,7 +269,84 @@ impl ServicePool {
       .services
       .insert(name, ServiceState::Running(service_impl))
       .is_none());
+    rt_pool.abort_detached(replaced.uuid());
+
+    Ok((service, replaced))
+  }
+
+  /// Like [`hot_update`](Self::hot_update), but keeps the current version
+  /// running, and sends requests matching `rule` to the new one until it is
+  /// promoted or rolled back. Replaces the previous canary, if any.
+  ///
+  /// While it is a canary, it takes its slots from the stable version's
+  /// `max_concurrent_requests`, so the service as a whole never handles more
+  /// requests at once than configured.
+  pub async fn canary_update(
+    &self,
+    rt_pool: &Pool,
+    name: ServiceName,
+    uuid: Option<Uuid>,
+    source: Source,
+    config: Config,
+    rule: CanaryRule,
+  ) -> Result<(RunningService, Option<ServiceImpl>)> {
+    let service_impl = self
+      .prepare_hot_update(rt_pool, name.clone(), uuid, source, config, true)
+      .await?;
+    if self.get_running(&name).is_none() {
+      return Err(ErrorKind::ServiceStopped { name }.into());
+    }
 
+    let service = service_impl.downgrade();
+    let canary = Canary {
+      service: service_impl,
+      rule,
+    };
+    let replaced = (self.canaries.insert(name, canary)).map(|x| {
+      rt_pool.abort_detached(x.service.uuid());
+      Arc::try_unwrap(x.service).unwrap_or_else(|arc| arc.as_ref().clone())
+    });
     Ok((service, replaced))
   }
+
+  /// Prepares a new version of a running service and loads it on one of the
+  /// workers, without replacing the current one.
+  ///
+  /// If `share_concurrency` is set, the new version uses the current one's
+  /// concurrency limit, if it has any, instead of its own.
+  async fn prepare_hot_update(
+    &self,
+    rt_pool: &Pool,
+    name: ServiceName,
+    uuid: Option<Uuid>,
+    source: Source,
+    config: Config,
+    share_concurrency: bool,
+  ) -> Result<Arc<ServiceImpl>> {
+    match self.get(&name) {
+      Some(x) if x.is_stopped() => return Err(ErrorKind::ServiceStopped { name }.into()),
+      None => return Err(ErrorKind::ServiceNotFound { name }.into()),
+      _ => {}
+    }
+    self.check_aliases(&name, &config.aliases)?;
+    let shared = (self.get_running(&name))
+      .filter(|_| share_concurrency)
+      .and_then(|x| x.try_upgrade().ok()?.concurrency.clone());
+
+    let service_impl = rt_pool
+      .scope(move |rt| async move {
+        let (mut service_impl, isolate) = prepare_service(&rt, name, uuid, source, config).await?;
+        if shared.is_some() {
+          service_impl.concurrency = shared;
+        }
+        let service_impl = Arc::new(service_impl);
+        rt.create_service(service_impl.downgrade(), isolate, true)
+          .await?;
+        rt.run_warmup(service_impl.downgrade()).await;
+        Ok::<_, crate::Error>(service_impl)
+      })
+      .await?;
+    prewarm(rt_pool, &service_impl).await;
+    Ok(service_impl)
+  }
 }
@@ -1,22 +1,43 @@
 use super::ServiceName;
+use crate::openapi::OpenApi;
 use crate::path::PathMatcher;
 use crate::source::Source;
+use crate::storage::ServiceStorageConfig;
 use crate::ErrorKind::ServiceDropped;
-use crate::Result;
+use crate::{
+  BackupConfig, IdempotencyConfig, IdleConfig, Limits, MirrorConfig, RateLimitConfig,
+  RegionalConfig, RequestFilters, Result,
+};
 use dashmap::mapref::multiple::RefMulti;
 use dashmap::mapref::one::Ref;
-use serde::{Deserialize, Serialize};
+use hyper::HeaderMap;
+use parking_lot::Mutex;
+use rand::{thread_rng, Rng};
+use serde::{Deserialize, Serialize, Serializer};
+use std::collections::HashMap;
 use std::marker::PhantomData;
 use std::ops::Deref;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Weak};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
 use uuid::Uuid;
 
-pub(super) enum ServiceState {
+#[derive(Debug)]
+#[allow(clippy::large_enum_variant)]
+pub(crate) enum ServiceState {
   Running(Arc<ServiceImpl>),
   Stopped(ServiceImpl),
 }
 
 impl ServiceState {
+  pub fn info(&self) -> &ServiceInfo {
+    match self {
+      Self::Running(x) => x.info(),
+      Self::Stopped(x) => x.info(),
+    }
+  }
+
   pub fn into_impl(self) -> ServiceImpl {
     match self {
       Self::Running(x) => Arc::try_unwrap(x).unwrap_or_else(|arc| arc.as_ref().clone()),
@@ -29,6 +50,128 @@ impl ServiceState {
 pub struct ServiceImpl {
   pub(crate) info: ServiceInfo,
   pub(crate) source: Source,
+  pub(crate) concurrency: Option<Arc<Concurrency>>,
+  /// UNIX timestamp in seconds of the last request.
+  pub(crate) last_active: Arc<AtomicU64>,
+  /// Result of the latest health check, if the service defines
+  /// `abel.health`.
+  pub(crate) health: Option<Arc<Mutex<Option<Health>>>>,
+}
+
+/// Result of running a service's `abel.health`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Health {
+  pub healthy: bool,
+  /// The second value `abel.health` returned, or why the check failed.
+  pub detail: Option<serde_json::Value>,
+  /// UNIX timestamp in seconds.
+  pub checked_at: u64,
+}
+
+/// Which requests a canary version of a service receives. The rest go to
+/// the stable version.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct CanaryRule {
+  /// Percentage of requests picked at random, from 0 to 100.
+  #[serde(default)]
+  pub percent: f64,
+  /// Requests with this header always go to the canary.
+  pub header: Option<String>,
+  /// Only match `header` if it has this value.
+  pub header_value: Option<String>,
+}
+
+impl CanaryRule {
+  fn matches(&self, headers: &HeaderMap) -> bool {
+    let header_matched = match &self.header {
+      Some(name) => match (headers.get(name), &self.header_value) {
+        (Some(x), Some(value)) => x == value,
+        (Some(_), None) => true,
+        (None, _) => false,
+      },
+      None => false,
+    };
+    header_matched || (self.percent > 0. && thread_rng().gen_range(0.0..100.0) < self.percent)
+  }
+}
+
+/// A new version of a running service, receiving part of its traffic until
+/// promoted or rolled back.
+#[derive(Debug)]
+pub(crate) struct Canary {
+  pub service: Arc<ServiceImpl>,
+  pub rule: CanaryRule,
+}
+
+impl Canary {
+  pub fn pick(&self, headers: &HeaderMap) -> Option<RunningService> {
+    self.rule.matches(headers).then(|| self.service.downgrade())
+  }
+
+  pub fn status(&self) -> CanaryStatus {
+    CanaryStatus {
+      uuid: self.service.uuid(),
+      rule: self.rule.clone(),
+    }
+  }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CanaryStatus {
+  pub uuid: Uuid,
+  #[serde(flatten)]
+  pub rule: CanaryRule,
+}
+
+/// Caps the number of requests a service handles at the same time, letting a
+/// bounded number of excess ones wait for a slot.
+#[derive(Debug)]
+pub(crate) struct Concurrency {
+  running: Arc<Semaphore>,
+  queue: Option<Semaphore>,
+  queue_timeout: Option<Duration>,
+  /// The limits this was created from.
+  limits: (usize, Option<usize>, Option<u64>),
+}
+
+impl Concurrency {
+  /// Returns `None` if `limits` does not cap concurrent requests.
+  pub fn from_limits(limits: &Limits) -> Option<Arc<Self>> {
+    let max_running = limits.max_concurrent_requests?;
+    Some(Arc::new(Self {
+      running: Arc::new(Semaphore::new(max_running)),
+      queue: (limits.max_queued_requests)
+        .filter(|&x| x > 0)
+        .map(Semaphore::new),
+      queue_timeout: limits.queue_timeout_ms.map(Duration::from_millis),
+      limits: (
+        max_running,
+        limits.max_queued_requests,
+        limits.queue_timeout_ms,
+      ),
+    }))
+  }
+
+  /// Whether `this` enforces the concurrency part of `limits`.
+  pub fn enforces(this: Option<&Self>, limits: &Limits) -> bool {
+    let expected = (limits.max_concurrent_requests)
+      .map(|x| (x, limits.max_queued_requests, limits.queue_timeout_ms));
+    this.map(|x| x.limits) == expected
+  }
+
+  /// Waits for a slot. Returns `None` if all slots are taken and the queue
+  /// is full, or no slot is freed before the queue timeout.
+  pub async fn acquire(&self) -> Option<OwnedSemaphorePermit> {
+    if let Ok(permit) = self.running.clone().try_acquire_owned() {
+      return Some(permit);
+    }
+    let _queued = self.queue.as_ref()?.try_acquire().ok()?;
+    let permit = self.running.clone().acquire_owned();
+    match self.queue_timeout {
+      Some(timeout) => tokio::time::timeout(timeout, permit).await.ok()?.ok(),
+      None => permit.await.ok(),
+    }
+  }
 }
 
 impl ServiceImpl {
@@ -45,6 +188,32 @@ impl ServiceImpl {
   pub fn source(&self) -> &Source {
     &self.source
   }
+
+  /// Result of the latest health check, if there is one.
+  pub fn health(&self) -> Option<Health> {
+    self.health.as_ref().and_then(|x| x.lock().clone())
+  }
+
+  pub(crate) fn touch(&self) {
+    self.last_active.store(unix_secs(), Ordering::Relaxed);
+  }
+
+  /// Seconds since the last request, or since the service was created or
+  /// started.
+  pub fn idle_secs(&self) -> u64 {
+    unix_secs().saturating_sub(self.last_active.load(Ordering::Relaxed))
+  }
+
+  /// The service's idle settings, with unset ones taken from `default`.
+  pub fn idle_config(&self, default: IdleConfig) -> IdleConfig {
+    self.idle.unwrap_or_default().or(default)
+  }
+}
+
+pub(crate) fn unix_secs() -> u64 {
+  (SystemTime::now().duration_since(UNIX_EPOCH))
+    .map(|x| x.as_secs())
+    .unwrap_or_default()
 }
 
 impl Deref for ServiceImpl {
@@ -62,6 +231,56 @@ pub struct ServiceInfo {
   pub(crate) description: Option<String>,
   pub(crate) paths: Vec<PathMatcher>,
   pub(crate) uuid: Uuid,
+  #[serde(default, skip_serializing_if = "Vec::is_empty")]
+  pub(crate) aliases: Vec<ServiceName>,
+  #[serde(default)]
+  pub(crate) limits: Limits,
+  #[serde(skip)]
+  pub(crate) report_dsn: Option<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) mirror: Option<MirrorConfig>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) backup: Option<BackupConfig>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) idle: Option<IdleConfig>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) compress: Option<bool>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) filters: Option<RequestFilters>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) idempotency: Option<IdempotencyConfig>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) rate_limit: Option<RateLimitConfig>,
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub(crate) pinned: bool,
+  #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+  pub(crate) prewarm: bool,
+  /// Parsed `openapi.json`, if `validate_openapi` is set.
+  #[serde(skip)]
+  pub(crate) openapi: Option<Arc<OpenApi>>,
+  #[serde(flatten)]
+  pub(crate) regional: RegionalConfig,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  pub(crate) storage: Option<ServiceStorageConfig>,
+  #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+  pub(crate) env: HashMap<String, String>,
+  /// Resolved secrets. Only their names are serialized.
+  #[serde(
+    default,
+    skip_deserializing,
+    skip_serializing_if = "HashMap::is_empty",
+    serialize_with = "serialize_secret_names"
+  )]
+  pub(crate) secrets: HashMap<String, String>,
+}
+
+fn serialize_secret_names<S: Serializer>(
+  secrets: &HashMap<String, String>,
+  serializer: S,
+) -> Result<S::Ok, S::Error> {
+  let mut names = secrets.keys().collect::<Vec<_>>();
+  names.sort();
+  serializer.collect_seq(names)
 }
 
 #[rustfmt::skip]
@@ -71,6 +290,31 @@ impl ServiceInfo {
   pub fn description(&self) -> Option<&str> { self.description.as_deref() }
   pub fn paths(&self) -> &[PathMatcher] { &self.paths }
   pub fn uuid(&self) -> Uuid { self.uuid }
+  pub fn aliases(&self) -> &[ServiceName] { &self.aliases }
+  pub fn limits(&self) -> &Limits { &self.limits }
+  pub fn report_dsn(&self) -> Option<&str> { self.report_dsn.as_deref() }
+  pub fn mirror(&self) -> Option<&MirrorConfig> { self.mirror.as_ref() }
+  pub fn backup(&self) -> Option<&BackupConfig> { self.backup.as_ref() }
+  pub fn idle(&self) -> Option<&IdleConfig> { self.idle.as_ref() }
+  pub fn compress(&self) -> Option<bool> { self.compress }
+  pub fn filters(&self) -> Option<&RequestFilters> { self.filters.as_ref() }
+  pub fn idempotency(&self) -> Option<IdempotencyConfig> { self.idempotency }
+  pub fn rate_limit(&self) -> Option<&RateLimitConfig> { self.rate_limit.as_ref() }
+  pub fn pinned(&self) -> bool { self.pinned }
+  pub fn prewarm(&self) -> bool { self.prewarm }
+  pub fn openapi(&self) -> Option<&Arc<OpenApi>> { self.openapi.as_ref() }
+  pub fn regional(&self) -> &RegionalConfig { &self.regional }
+  pub fn storage(&self) -> Option<&ServiceStorageConfig> { self.storage.as_ref() }
+  pub fn env(&self) -> &HashMap<String, String> { &self.env }
+}
+
+impl ServiceInfo {
+  /// Variables and secrets visible in `abel.env`.
+  pub(crate) fn lua_env(&self) -> impl Iterator<Item = (&str, &str)> {
+    (self.env.iter())
+      .chain(self.secrets.iter())
+      .map(|(k, v)| (k.as_str(), v.as_str()))
+  }
 }
 
 pub enum Service<'a> {
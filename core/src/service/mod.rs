@@ -4,29 +4,52 @@ mod impls;
 pub use create::ErrorPayload;
 pub use impls::*;
 
-use crate::runtime::Runtime;
+use crate::runtime::{check_name, Runtime};
 use crate::task::Pool;
 use crate::ErrorKind::*;
 use crate::{AbelState, Result};
 use dashmap::DashMap;
+use hyper::HeaderMap;
 use log::warn;
-use replace_with::{replace_with_or_abort, replace_with_or_abort_and_return};
+use replace_with::replace_with_or_abort;
 use smallstr::SmallString;
+use std::borrow::Cow;
 use std::path::PathBuf;
 use std::sync::Arc;
 
 pub type ServiceName = SmallString<[u8; 16]>;
-type Services = DashMap<ServiceName, ServiceState>;
+pub(crate) type Services = DashMap<ServiceName, ServiceState>;
+
+/// Loads the service's isolate on every worker if it is configured to
+/// `prewarm`. Failures are only logged, since workers that miss it still load
+/// it on their first request.
+async fn prewarm(rt_pool: &Pool, service_impl: &Arc<ServiceImpl>) {
+  if !service_impl.prewarm {
+    return;
+  }
+  let name = service_impl.name.clone();
+  let service = service_impl.downgrade();
+  let results = (rt_pool)
+    .broadcast(move |rt| async move { rt.prewarm(service).await })
+    .await;
+  for error in results.into_iter().filter_map(Result::err) {
+    warn!("failed to prewarm service '{name}': {error}");
+  }
+}
 
 pub struct ServicePool {
   services: Arc<Services>,
+  /// Canaries of running services. They are dropped whenever the stable
+  /// version is stopped, replaced or removed.
+  canaries: DashMap<ServiceName, Canary>,
   state: Arc<AbelState>,
 }
 
 impl ServicePool {
   pub fn new(state: Arc<AbelState>) -> Self {
     Self {
-      services: Default::default(),
+      services: state.services.clone(),
+      canaries: DashMap::new(),
       state,
     }
   }
@@ -47,6 +70,45 @@ impl ServicePool {
     }
   }
 
+  /// Resolves a normalized name or alias to the name of the service it
+  /// refers to.
+  pub fn resolve_name(&self, name: &str) -> Option<ServiceName> {
+    resolve_name(&self.services, name)
+  }
+
+  /// Checks that neither `name` nor any of `aliases` collides with names or
+  /// aliases of other services.
+  fn check_aliases(&self, name: &str, aliases: &[String]) -> Result<()> {
+    for alias in aliases {
+      check_name(&normalize_name(alias))?;
+    }
+    for service in self.services.iter() {
+      if service.key() == name {
+        continue;
+      }
+      let other_aliases = &service.value().info().aliases;
+      let conflict = aliases
+        .iter()
+        .map(|x| normalize_name(x))
+        .find(|alias| service.key() == alias || other_aliases.iter().any(|a| a == alias));
+      let conflict = conflict.or_else(|| {
+        (other_aliases.iter())
+          .any(|a| a == name)
+          .then(|| name.into())
+      });
+      if let Some(alias) = conflict {
+        return Err(
+          AliasConflict {
+            alias: alias.into(),
+            service: service.key().clone(),
+          }
+          .into(),
+        );
+      }
+    }
+    Ok(())
+  }
+
   pub fn list(&self) -> impl Iterator<Item = Service<'_>> {
     self.services.iter().map(|x| match x.value() {
       ServiceState::Running(x) => Service::Running(x.downgrade()),
@@ -55,23 +117,31 @@ impl ServicePool {
   }
 
   pub async fn stop(&self, rt_pool: &Pool, name: &str) -> Result<StoppedService<'_>> {
-    if let Some(mut service) = self.services.get_mut(name) {
-      let state = service.value_mut();
-      if let ServiceState::Running(service2) = state {
-        let x = service2.downgrade();
-        let result = rt_pool
-          .scope(|rt| async move {
-            rt.run_stop(x).await?;
-            Ok::<_, crate::Error>(())
-          })
-          .await;
+    // The entry is not locked while `abel.stop` runs, so that other tasks on
+    // the same thread accessing services do not deadlock with it.
+    let running = match self.services.get(name).as_deref() {
+      Some(ServiceState::Running(x)) => x.downgrade(),
+      Some(ServiceState::Stopped(_)) => return Err(ServiceStopped { name: name.into() }.into()),
+      None => return Err(ServiceNotFound { name: name.into() }.into()),
+    };
+    let x = running.clone();
+    let result = rt_pool
+      .scope(|rt| async move {
+        rt.run_stop(x).await?;
+        Ok::<_, crate::Error>(())
+      })
+      .await;
+
+    let mut service = (self.services.get_mut(name)).ok_or(ServiceNotFound { name: name.into() })?;
+    let state = service.value_mut();
+    match state {
+      ServiceState::Running(x) if x.downgrade().ptr_eq(&running) => {
+        self.remove_canary(rt_pool, name);
         replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
         result.map(|_| StoppedService::from_ref(service.downgrade()))
-      } else {
-        Err(ServiceStopped { name: name.into() }.into())
       }
-    } else {
-      Err(ServiceNotFound { name: name.into() }.into())
+      // Replaced or stopped by someone else in the meantime
+      _ => Err(ServiceDropped.into()),
     }
   }
 
@@ -92,6 +162,12 @@ impl ServicePool {
   }
 
   pub async fn stop_all(&self, rt_pool: &Pool) {
+    let names = (self.canaries.iter())
+      .map(|x| x.key().clone())
+      .collect::<Vec<_>>();
+    for name in names {
+      self.remove_canary(rt_pool, &name);
+    }
     for mut service in self.services.iter_mut() {
       let state = service.value_mut();
       if let ServiceState::Running(service2) = state {
@@ -113,40 +189,40 @@ impl ServicePool {
     }
   }
 
+  /// Starts the service, and runs its `abel.warmup` before marking it as
+  /// running.
   pub async fn start(&self, rt_pool: &Pool, name: &str) -> Result<RunningService> {
-    if let Some(mut service) = self.services.get_mut(name) {
-      if let state @ ServiceState::Stopped(_) = service.value_mut() {
-        let running = replace_with_or_abort_and_return(state, |x| {
-          if let ServiceState::Stopped(s) = x {
-            let s = Arc::new(s);
-            (s.downgrade(), ServiceState::Running(s))
-          } else {
-            unreachable!()
-          }
-        });
-        let running2 = running.clone();
-        let result = rt_pool
-          .scope(move |rt| async move {
-            rt.run_start(running2).await?;
-            Ok::<_, crate::Error>(())
-          })
-          .await;
-        match result {
-          Ok(_) => Ok(running),
-          Err(error) => {
-            replace_with_or_abort(state, |x| ServiceState::Stopped(x.into_impl()));
-            Err(error)
-          }
-        }
-      } else {
-        Err(ServiceRunning { name: name.into() }.into())
+    // As with `stop`, the entry is not locked while Lua code runs.
+    let service_impl = match self.services.get(name).as_deref() {
+      Some(ServiceState::Stopped(x)) => Arc::new(x.clone()),
+      Some(ServiceState::Running(_)) => return Err(ServiceRunning { name: name.into() }.into()),
+      None => return Err(ServiceNotFound { name: name.into() }.into()),
+    };
+    let running = service_impl.downgrade();
+    let running2 = running.clone();
+    rt_pool
+      .scope(move |rt| async move {
+        rt.run_start(running2.clone()).await?;
+        rt.run_warmup(running2).await;
+        Ok::<_, crate::Error>(())
+      })
+      .await?;
+    prewarm(rt_pool, &service_impl).await;
+
+    let mut service = (self.services.get_mut(name)).ok_or(ServiceNotFound { name: name.into() })?;
+    let state = service.value_mut();
+    match state {
+      ServiceState::Stopped(x) if x.uuid() == service_impl.uuid() => {
+        *state = ServiceState::Running(service_impl);
+        Ok(running)
       }
-    } else {
-      Err(ServiceNotFound { name: name.into() }.into())
+      // Replaced or started by someone else in the meantime
+      _ => Err(ServiceDropped.into()),
     }
   }
 
   pub async fn remove(&self, state: &AbelState, name: &str) -> Result<ServiceImpl> {
+    self.canaries.remove(name);
     if let Some((name2, old_service)) = self.services.remove(name) {
       if let ServiceState::Stopped(x) = old_service {
         let local_storage_path = get_local_storage_path(state, name);
@@ -162,6 +238,78 @@ impl ServicePool {
   }
 }
 
+impl ServicePool {
+  /// Picks the canary of service `name` for a request, if there is one and
+  /// its rule matches.
+  pub fn pick_canary(&self, name: &str, headers: &HeaderMap) -> Option<RunningService> {
+    self.canaries.get(name)?.pick(headers)
+  }
+
+  pub fn canary(&self, name: &str) -> Option<CanaryStatus> {
+    self.canaries.get(name).map(|x| x.status())
+  }
+
+  /// Removes the canary of service `name`, aborting its detached tasks.
+  fn remove_canary(&self, rt_pool: &Pool, name: &str) -> Option<Canary> {
+    let (_, canary) = self.canaries.remove(name)?;
+    rt_pool.abort_detached(canary.service.uuid());
+    Some(canary)
+  }
+
+  /// Replaces the stable version with the canary, returning the new running
+  /// service and the replaced one, whose detached tasks are aborted.
+  pub fn promote_canary(
+    &self,
+    rt_pool: &Pool,
+    name: &str,
+  ) -> Result<(RunningService, ServiceImpl)> {
+    let mut service = (self.services.get_mut(name)).ok_or(ServiceNotFound { name: name.into() })?;
+    let (_, canary) = (self.canaries.remove(name)).ok_or(NoCanary { name: name.into() })?;
+    // The canary shared the stable version's concurrency limit; give it its
+    // own if it configures a different one. Workers then load it again, as
+    // their isolates belong to the canary's old instance.
+    let limits = canary.service.info.limits;
+    let promoted = if Concurrency::enforces(canary.service.concurrency.as_deref(), &limits) {
+      canary.service
+    } else {
+      let mut service = Arc::try_unwrap(canary.service).unwrap_or_else(|arc| arc.as_ref().clone());
+      service.concurrency = Concurrency::from_limits(&limits);
+      Arc::new(service)
+    };
+    let running = promoted.downgrade();
+    let replaced = std::mem::replace(service.value_mut(), ServiceState::Running(promoted));
+    let replaced = replaced.into_impl();
+    rt_pool.abort_detached(replaced.uuid());
+    Ok((running, replaced))
+  }
+
+  /// Drops the canary, returning it.
+  pub fn rollback_canary(&self, rt_pool: &Pool, name: &str) -> Result<ServiceImpl> {
+    let canary = (self.remove_canary(rt_pool, name)).ok_or(NoCanary { name: name.into() })?;
+    Ok(Arc::try_unwrap(canary.service).unwrap_or_else(|arc| arc.as_ref().clone()))
+  }
+}
+
+/// Normalizes a user-supplied service name or alias by trimming whitespace
+/// and lowercasing it.
+pub fn normalize_name(name: &str) -> Cow<'_, str> {
+  let name = name.trim();
+  if name.bytes().any(|x| x.is_ascii_uppercase()) {
+    Cow::Owned(name.to_ascii_lowercase())
+  } else {
+    Cow::Borrowed(name)
+  }
+}
+
+pub(crate) fn resolve_name(services: &Services, name: &str) -> Option<ServiceName> {
+  if services.contains_key(name) {
+    return Some(name.into());
+  }
+  (services.iter())
+    .find(|x| x.value().info().aliases.iter().any(|a| a == name))
+    .map(|x| x.key().clone())
+}
+
 pub(crate) fn get_local_storage_path(state: &AbelState, name: &str) -> PathBuf {
   state.local_storage_path.join(name)
 }
@@ -1,20 +1,40 @@
+use crate::lua::bytecode::BytecodeCache;
+use crate::path::normalize_path_str;
 use crate::ErrorKind::EntryNotFound;
 use crate::Result;
 use async_trait::async_trait;
-use mlua::{ExternalResult, Function, Lua, Table, UserData};
+use futures::future::BoxFuture;
+use mlua::{ChunkMode, ExternalResult, Function, Lua, Table, UserData};
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::fmt::Debug;
-use std::io::SeekFrom;
+use std::io::{Cursor, SeekFrom};
 use std::ops::Deref;
+use std::path::{Path, PathBuf};
 use std::pin::Pin;
 use std::sync::Arc;
 use tokio::io::ErrorKind::NotFound;
 use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeek, AsyncSeekExt};
+use zip::ZipArchive;
 
+/// Read-only file tree a service is loaded from.
+///
+/// Paths are `/`-separated and relative to the tree's root, but may come
+/// straight from Lua; implementations should pass them through
+/// [`normalize_path_str`] before use. Methods may be called concurrently from
+/// different workers, and files returned by `get` are read independently of
+/// each other, so implementations that fetch over the network should not
+/// hold locks across awaits.
 #[async_trait]
 pub trait SourceVfs {
   type File: AsyncRead + AsyncSeek;
+  /// Opens a file for reading, failing with [`NotFound`] if it does not
+  /// exist.
   async fn get(&self, path: &str) -> io::Result<Self::File>;
+  /// Whether a file or directory exists. Missing entries are `Ok(false)`,
+  /// not errors.
   async fn exists(&self, path: &str) -> io::Result<bool>;
+  /// Fails with [`NotFound`] if the entry does not exist.
   async fn metadata(&self, path: &str) -> io::Result<Metadata>;
 }
 
@@ -68,7 +88,7 @@ impl Source {
     Self(Arc::new(SourceInner(vfs)) as _)
   }
 
-  async fn get_bytes(&self, path: &str) -> io::Result<Vec<u8>> {
+  pub(crate) async fn get_bytes(&self, path: &str) -> io::Result<Vec<u8>> {
     let mut file = self.get(path).await?;
     let len = file.seek(SeekFrom::End(0)).await?;
     file.rewind().await?;
@@ -90,11 +110,20 @@ impl Source {
       }
       Err(error) => return Err(error.into()),
     };
-    let result = lua
-      .load(&code)
+    let cache = lua.app_data_ref::<Arc<BytecodeCache>>().map(|x| x.clone());
+    let key = cache.as_ref().map(|_| BytecodeCache::key(path, &code));
+    let cached = (cache.as_ref().zip(key.as_ref())).and_then(|(cache, key)| cache.get(key));
+    let chunk = match &cached {
+      Some(bytecode) => lua.load(&**bytecode).set_mode(ChunkMode::Binary),
+      None => lua.load(&code),
+    };
+    let result = chunk
       .set_name(&format!("@{path}"))? // This prevents `[string 'chunk_name']`
       .set_environment(env)?
       .into_function()?;
+    if let (None, Some(cache), Some(key)) = (cached, cache, key) {
+      cache.insert(key, result.dump(false));
+    }
     Ok(result)
   }
 }
@@ -113,6 +142,43 @@ impl Debug for Source {
   }
 }
 
+/// Opens a source from its URI, e.g. `s3://bucket/service`.
+pub type SourceOpener = Arc<dyn Fn(&str) -> BoxFuture<'static, io::Result<Source>> + Send + Sync>;
+
+/// Source backends by URI scheme, so that services can be loaded from
+/// somewhere other than uploaded files.
+#[derive(Clone, Default)]
+pub struct SourceRegistry(HashMap<String, SourceOpener>);
+
+impl SourceRegistry {
+  /// Opens sources whose URI starts with `{scheme}://` with `opener`,
+  /// replacing the previous opener of `scheme`.
+  pub fn register(&mut self, scheme: impl Into<String>, opener: SourceOpener) -> &mut Self {
+    self.0.insert(scheme.into(), opener);
+    self
+  }
+
+  pub async fn open(&self, uri: &str) -> io::Result<Source> {
+    let scheme = uri.split_once("://").map(|x| x.0).ok_or_else(|| {
+      io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("invalid source URI '{uri}'"),
+      )
+    })?;
+    let opener = self.0.get(scheme).ok_or_else(|| {
+      let msg = format!("no source backend registered for '{scheme}://'");
+      io::Error::new(io::ErrorKind::Unsupported, msg)
+    })?;
+    opener(uri).await
+  }
+}
+
+impl Debug for SourceRegistry {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    f.debug_set().entries(self.0.keys()).finish()
+  }
+}
+
 #[derive(Debug, Clone)]
 pub struct SourceUserData(pub Source);
 
@@ -133,3 +199,362 @@ impl UserData for SourceUserData {
     )
   }
 }
+
+/// A source consisting of a single `main.lua`.
+pub struct SingleSource(Arc<[u8]>);
+
+impl SingleSource {
+  pub fn new(src: impl AsRef<[u8]>) -> Self {
+    Self(Arc::from(src.as_ref()))
+  }
+}
+
+#[async_trait]
+impl SourceVfs for SingleSource {
+  type File = Cursor<Arc<[u8]>>;
+
+  async fn get(&self, path: &str) -> io::Result<Self::File> {
+    match &*normalize_path_str(path) {
+      "main.lua" => Ok(Cursor::new(self.0.clone())),
+      "" => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+      _ => Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No such file or directory",
+      )),
+    }
+  }
+
+  async fn exists(&self, path: &str) -> io::Result<bool> {
+    match &*normalize_path_str(path) {
+      "main.lua" | "" => Ok(true),
+      _ => Ok(false),
+    }
+  }
+
+  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
+    match &*normalize_path_str(path) {
+      "main.lua" => Ok(Metadata::File {
+        size: self.0.len() as _,
+      }),
+      "" => Ok(Metadata::Dir),
+      _ => Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "No such file or directory",
+      )),
+    }
+  }
+}
+
+/// A source that reads files directly from a local directory.
+pub struct DirSource(PathBuf);
+
+impl DirSource {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self(path.into())
+  }
+}
+
+#[async_trait]
+impl SourceVfs for DirSource {
+  type File = tokio::fs::File;
+
+  async fn get(&self, path: &str) -> io::Result<Self::File> {
+    tokio::fs::File::open(self.0.join(normalize_path_str(path))).await
+  }
+
+  async fn exists(&self, path: &str) -> io::Result<bool> {
+    Ok(
+      tokio::fs::metadata(self.0.join(normalize_path_str(path)))
+        .await
+        .is_ok(),
+    )
+  }
+
+  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
+    let metadata = tokio::fs::metadata(self.0.join(normalize_path_str(path))).await?;
+    if metadata.is_file() {
+      Ok(Metadata::File {
+        size: metadata.len(),
+      })
+    } else {
+      Ok(Metadata::Dir)
+    }
+  }
+}
+
+/// A source holding a copy of a directory's files, taken at one point in
+/// time, so that later changes to the directory do not affect it.
+pub struct SnapshotSource(HashMap<String, Option<Arc<[u8]>>>);
+
+impl SnapshotSource {
+  /// Copies every file under `path` into memory, apart from `.git`.
+  pub async fn new(path: impl Into<PathBuf>) -> io::Result<Self> {
+    let path = path.into();
+    tokio::task::spawn_blocking(move || {
+      let mut entries = HashMap::from([(String::new(), None)]);
+      Self::read_dir(&path, "", &mut entries)?;
+      Ok(Self(entries))
+    })
+    .await?
+  }
+
+  fn read_dir(
+    base: &Path,
+    prefix: &str,
+    entries: &mut HashMap<String, Option<Arc<[u8]>>>,
+  ) -> io::Result<()> {
+    for entry in std::fs::read_dir(base.join(prefix))? {
+      let entry = entry?;
+      let name = entry.file_name();
+      let name = match name.to_str() {
+        Some(".git") => continue,
+        Some(x) => x,
+        None => return Err(io::Error::new(io::ErrorKind::InvalidData, "non-UTF-8 path")),
+      };
+      let path = if prefix.is_empty() {
+        name.to_string()
+      } else {
+        format!("{prefix}/{name}")
+      };
+      // Follows symlinks, as `DirSource` does
+      if std::fs::metadata(entry.path())?.is_dir() {
+        Self::read_dir(base, &path, entries)?;
+        entries.insert(path, None);
+      } else {
+        let bytes = std::fs::read(entry.path())?;
+        entries.insert(path, Some(bytes.into()));
+      }
+    }
+    Ok(())
+  }
+
+  fn entry(&self, path: &str) -> io::Result<&Option<Arc<[u8]>>> {
+    (self.0.get(&normalize_path_str(path)))
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file or directory"))
+  }
+}
+
+#[async_trait]
+impl SourceVfs for SnapshotSource {
+  type File = Cursor<Arc<[u8]>>;
+
+  async fn get(&self, path: &str) -> io::Result<Self::File> {
+    match self.entry(path)? {
+      Some(bytes) => Ok(Cursor::new(bytes.clone())),
+      None => Err(io::Error::from_raw_os_error(libc::EISDIR)),
+    }
+  }
+
+  async fn exists(&self, path: &str) -> io::Result<bool> {
+    Ok(self.0.contains_key(&normalize_path_str(path)))
+  }
+
+  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
+    match self.entry(path)? {
+      Some(bytes) => Ok(Metadata::File {
+        size: bytes.len() as _,
+      }),
+      None => Ok(Metadata::Dir),
+    }
+  }
+}
+
+/// Largest file read from a zip archive, by both the size it declares and
+/// the bytes it actually decompresses to.
+const MAX_ZIP_ENTRY_SIZE: u64 = 64 * 1024 * 1024;
+
+/// A source that reads files from a zip archive.
+///
+/// Files are decompressed into memory as a whole when opened.
+pub struct ZipSource {
+  archive: Arc<Mutex<ZipArchive<std::fs::File>>>,
+  entries: HashMap<String, ZipEntry>,
+}
+
+enum ZipEntry {
+  Dir,
+  File { index: usize, size: u64 },
+}
+
+impl ZipSource {
+  pub async fn open(path: impl Into<PathBuf>) -> io::Result<Self> {
+    let path = path.into();
+    tokio::task::spawn_blocking(move || Self::open_blocking(std::fs::File::open(path)?)).await?
+  }
+
+  fn open_blocking(file: std::fs::File) -> io::Result<Self> {
+    let mut archive = ZipArchive::new(file)?;
+    // Directories are not necessarily stored in zip archives, so parents of
+    // every entry are added as well.
+    let mut entries = HashMap::from([(String::new(), ZipEntry::Dir)]);
+    for index in 0..archive.len() {
+      let file = archive.by_index_raw(index)?;
+      let name = normalize_path_str(file.name());
+      let mut parent = name.as_str();
+      while let Some((x, _)) = parent.rsplit_once('/') {
+        entries.entry(x.into()).or_insert(ZipEntry::Dir);
+        parent = x;
+      }
+      let entry = if file.is_dir() {
+        ZipEntry::Dir
+      } else {
+        ZipEntry::File {
+          index,
+          size: file.size(),
+        }
+      };
+      entries.insert(name, entry);
+    }
+    Ok(Self {
+      archive: Arc::new(Mutex::new(archive)),
+      entries,
+    })
+  }
+
+  /// Paths and sizes of files in the archive, in no particular order.
+  pub fn files(&self) -> impl Iterator<Item = (&str, u64)> {
+    (self.entries.iter()).filter_map(|(path, entry)| match entry {
+      ZipEntry::Dir => None,
+      ZipEntry::File { size, .. } => Some((path.as_str(), *size)),
+    })
+  }
+
+  fn entry(&self, path: &str) -> io::Result<&ZipEntry> {
+    (self.entries.get(&normalize_path_str(path)))
+      .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "No such file or directory"))
+  }
+}
+
+fn zip_entry_too_large(size: u64) -> io::Error {
+  let msg = format!("zip entry of {size} bytes is larger than {MAX_ZIP_ENTRY_SIZE}");
+  io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[async_trait]
+impl SourceVfs for ZipSource {
+  type File = Cursor<Vec<u8>>;
+
+  async fn get(&self, path: &str) -> io::Result<Self::File> {
+    let (index, size) = match self.entry(path)? {
+      ZipEntry::Dir => return Err(io::Error::from_raw_os_error(libc::EISDIR)),
+      &ZipEntry::File { index, size } => (index, size),
+    };
+    if size > MAX_ZIP_ENTRY_SIZE {
+      return Err(zip_entry_too_large(size));
+    }
+    let archive = self.archive.clone();
+    tokio::task::spawn_blocking(move || {
+      let mut archive = archive.lock();
+      let file = archive.by_index(index)?;
+      let mut buf = Vec::with_capacity(size as _);
+      // One byte more than declared catches entries lying about their size
+      std::io::Read::read_to_end(&mut std::io::Read::take(file, size + 1), &mut buf)?;
+      if buf.len() as u64 > size {
+        return Err(io::Error::new(
+          io::ErrorKind::InvalidData,
+          "zip entry is larger than its declared size",
+        ));
+      }
+      Ok(Cursor::new(buf))
+    })
+    .await?
+  }
+
+  async fn exists(&self, path: &str) -> io::Result<bool> {
+    Ok(self.entries.contains_key(&normalize_path_str(path)))
+  }
+
+  async fn metadata(&self, path: &str) -> io::Result<Metadata> {
+    match self.entry(path)? {
+      ZipEntry::Dir => Ok(Metadata::Dir),
+      &ZipEntry::File { size, .. } => Ok(Metadata::File { size }),
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{Seek, Write};
+  use zip::write::FileOptions;
+  use zip::ZipWriter;
+
+  /// Zip archive of `a.txt` containing `content`, with the uncompressed size
+  /// in its headers replaced by `declared_size`.
+  fn zip_with_declared_size(content: &[u8], declared_size: u32) -> std::fs::File {
+    let mut file = tempfile::tempfile().unwrap();
+    let mut writer = ZipWriter::new(&mut file);
+    writer.start_file("a.txt", FileOptions::default()).unwrap();
+    writer.write_all(content).unwrap();
+    writer.finish().unwrap();
+    drop(writer);
+
+    let mut bytes = Vec::new();
+    file.rewind().unwrap();
+    std::io::Read::read_to_end(&mut file, &mut bytes).unwrap();
+    // Uncompressed size is at offset 22 of local file headers and 24 of
+    // central directory headers.
+    for (signature, offset) in [(0x04034b50u32, 22), (0x02014b50, 24)] {
+      let start = (bytes.windows(4))
+        .position(|x| x == signature.to_le_bytes())
+        .unwrap();
+      bytes[start + offset..start + offset + 4].copy_from_slice(&declared_size.to_le_bytes());
+    }
+    file.set_len(0).unwrap();
+    file.rewind().unwrap();
+    file.write_all(&bytes).unwrap();
+    file.rewind().unwrap();
+    file
+  }
+
+  async fn read(source: &ZipSource, path: &str) -> io::Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    source.get(path).await?.read_to_end(&mut buf).await?;
+    Ok(buf)
+  }
+
+  #[tokio::test]
+  async fn test_zip_entry() {
+    let source = ZipSource::open_blocking(zip_with_declared_size(b"hello", 5)).unwrap();
+    assert_eq!(read(&source, "a.txt").await.unwrap(), b"hello");
+  }
+
+  #[tokio::test]
+  async fn test_zip_entry_declared_too_large() {
+    let source = ZipSource::open_blocking(zip_with_declared_size(b"hello", u32::MAX - 1)).unwrap();
+    let error = read(&source, "a.txt").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[tokio::test]
+  async fn test_zip_entry_larger_than_declared() {
+    let content = vec![b'a'; 4096];
+    let source = ZipSource::open_blocking(zip_with_declared_size(&content, 16)).unwrap();
+    let error = read(&source, "a.txt").await.unwrap_err();
+    assert_eq!(error.kind(), io::ErrorKind::InvalidData);
+  }
+
+  #[tokio::test]
+  async fn test_snapshot_source() {
+    let dir = tempfile::TempDir::new().unwrap();
+    std::fs::create_dir_all(dir.path().join("lib/.git")).unwrap();
+    std::fs::write(dir.path().join("main.lua"), "old").unwrap();
+    std::fs::write(dir.path().join("lib/a.lua"), "a").unwrap();
+    let source = SnapshotSource::new(dir.path()).await.unwrap();
+    std::fs::write(dir.path().join("main.lua"), "new").unwrap();
+
+    let read = |path| {
+      let source = &source;
+      async move {
+        let mut buf = Vec::new();
+        source.get(path).await?.read_to_end(&mut buf).await?;
+        io::Result::Ok(buf)
+      }
+    };
+    assert_eq!(read("main.lua").await.unwrap(), b"old");
+    assert_eq!(read("./lib/a.lua").await.unwrap(), b"a");
+    assert_eq!(source.metadata("lib").await.unwrap(), Metadata::Dir);
+    assert!(!source.exists("lib/.git").await.unwrap());
+    assert!(read("lib").await.is_err());
+  }
+}
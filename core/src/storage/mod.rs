@@ -0,0 +1,194 @@
+//! Where services' local storage (`local:` paths) is kept.
+//!
+//! Files always live on disk, under the service's local storage path. With a
+//! remote backend, that folder is a read-through cache of it: files missing
+//! on disk are fetched when they are opened or looked up, files written are
+//! uploaded when they are closed, and removals and renames are applied to
+//! both.
+//!
+//! The backend is set server-wide, with the server's credentials. Services
+//! can only choose a sub-prefix of their own keys in `abel.json`. SQLite
+//! databases are files on disk the backend does not see, so they cannot be
+//! opened in remotely backed local storage.
+
+mod s3;
+
+pub use s3::S3Config;
+
+use crate::path::normalize_path_str;
+use async_trait::async_trait;
+use hyper::body::Bytes;
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug, Formatter};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use tokio::{fs, io};
+use uuid::Uuid;
+
+/// Object storage a service's local storage is backed by.
+///
+/// Keys are normalized paths relative to the service's local storage, e.g.
+/// `data/users.json`.
+#[async_trait]
+pub trait StorageBackend: Send + Sync {
+  /// Reads an object, returning `None` if it does not exist.
+  async fn get(&self, key: &str) -> io::Result<Option<Bytes>>;
+  async fn put(&self, key: &str, body: Bytes) -> io::Result<()>;
+  /// Removes an object. Removing a missing one is not an error.
+  async fn delete(&self, key: &str) -> io::Result<()>;
+}
+
+/// Remote backend of services' local storage, set server-wide.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StorageConfig {
+  S3(S3Config),
+}
+
+impl StorageConfig {
+  /// Backend of `service`'s local storage.
+  pub(crate) fn backend(
+    &self,
+    service: &str,
+    service_config: Option<&ServiceStorageConfig>,
+  ) -> Arc<dyn StorageBackend> {
+    let mut prefix = format!("{service}/");
+    if let Some(sub_prefix) = service_config.map(|x| normalize_path_str(&x.prefix)) {
+      if !sub_prefix.is_empty() {
+        prefix += &sub_prefix;
+        prefix.push('/');
+      }
+    }
+    match self {
+      Self::S3(config) => Arc::new(s3::S3Storage::new(config.clone(), prefix)),
+    }
+  }
+}
+
+/// `storage` in a service's `abel.json`, which can only narrow down where its
+/// keys are kept in the server-wide backend.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ServiceStorageConfig {
+  /// Kept under the service's own prefix, i.e. keys are
+  /// `<server prefix><service>/<prefix>/<path>`.
+  #[serde(default)]
+  pub prefix: String,
+}
+
+/// A service's local storage, optionally backed by a remote backend.
+#[derive(Clone)]
+pub struct LocalStorage {
+  path: Arc<Path>,
+  remote: Option<Arc<dyn StorageBackend>>,
+}
+
+impl LocalStorage {
+  pub fn new(path: impl Into<PathBuf>) -> Self {
+    Self {
+      path: path.into().into(),
+      remote: None,
+    }
+  }
+
+  pub fn with_remote(self, remote: Arc<dyn StorageBackend>) -> Self {
+    Self {
+      remote: Some(remote),
+      ..self
+    }
+  }
+
+  pub fn path(&self) -> &Path {
+    &self.path
+  }
+
+  pub fn is_remote(&self) -> bool {
+    self.remote.is_some()
+  }
+
+  /// Path on disk of `path` in local storage.
+  pub(crate) fn disk_path(&self, path: &str) -> PathBuf {
+    self.path.join(normalize_path_str(path))
+  }
+
+  /// Downloads `path` from the remote backend if it is missing on disk.
+  pub(crate) async fn fetch(&self, path: &str) -> io::Result<()> {
+    let (remote, key) = match self.remote_key(path) {
+      Some(x) => x,
+      None => return Ok(()),
+    };
+    let disk_path = self.path.join(&key);
+    if fs::metadata(&disk_path).await.is_ok() {
+      return Ok(());
+    }
+    if let Some(body) = remote.get(&key).await? {
+      if let Some(parent) = disk_path.parent() {
+        fs::create_dir_all(parent).await?;
+      }
+      // Written aside first so that concurrent readers never see half of it
+      let temp_path = disk_path.with_file_name(format!(".{}.fetch", Uuid::new_v4()));
+      fs::write(&temp_path, body).await?;
+      fs::rename(temp_path, disk_path).await?;
+    }
+    Ok(())
+  }
+
+  /// Creates `path`'s parent on disk before it is written. Directories are
+  /// not kept remotely, so they may be missing even if files in them exist.
+  pub(crate) async fn create_parent(&self, path: &str) -> io::Result<()> {
+    if let Some((_, key)) = self.remote_key(path) {
+      if let Some(parent) = self.path.join(key).parent() {
+        fs::create_dir_all(parent).await?;
+      }
+    }
+    Ok(())
+  }
+
+  /// Uploads `path` on disk to the remote backend.
+  pub(crate) async fn upload(&self, path: &str) -> io::Result<()> {
+    if let Some((remote, key)) = self.remote_key(path) {
+      let body = fs::read(self.path.join(&key)).await?;
+      remote.put(&key, body.into()).await?;
+    }
+    Ok(())
+  }
+
+  /// Removes `path` from the remote backend. Files in a removed directory
+  /// are only removed if they are on disk.
+  pub(crate) async fn remove_remote(&self, path: &str) -> io::Result<()> {
+    let (remote, key) = match self.remote_key(path) {
+      Some(x) => x,
+      None => return Ok(()),
+    };
+    let disk_path = self.path.join(&key);
+    if !disk_path.is_dir() {
+      return remote.delete(&key).await;
+    }
+    let mut dirs = vec![disk_path];
+    while let Some(dir) = dirs.pop() {
+      let mut entries = fs::read_dir(dir).await?;
+      while let Some(entry) = entries.next_entry().await? {
+        if entry.file_type().await?.is_dir() {
+          dirs.push(entry.path());
+        } else if let Ok(key) = entry.path().strip_prefix(&self.path) {
+          remote.delete(&key.to_string_lossy()).await?;
+        }
+      }
+    }
+    Ok(())
+  }
+
+  fn remote_key(&self, path: &str) -> Option<(&dyn StorageBackend, String)> {
+    let remote = self.remote.as_deref()?;
+    let key = normalize_path_str(path);
+    (!key.is_empty()).then_some((remote, key))
+  }
+}
+
+impl Debug for LocalStorage {
+  fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+    f.debug_struct("LocalStorage")
+      .field("path", &self.path)
+      .field("remote", &self.remote.is_some())
+      .finish()
+  }
+}
@@ -1,14 +1,50 @@
+use crate::runtime::LogCapture;
 use mlua::{Function, Lua, RegistryKey, Table, ToLua};
 use parking_lot::Mutex;
-use std::cell::Ref;
+use std::cell::{Cell, Ref, RefCell};
 use std::rc::Rc;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
+
+/// Resource limits applied to a task and every task spawned from it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TaskLimits {
+  pub cpu_time: Duration,
+  /// How much the worker's Lua state's memory usage may grow while the task
+  /// runs, in bytes. The state is shared by all services on the worker, so
+  /// this is not accounted per task or per service.
+  pub memory: Option<usize>,
+}
+
+impl Default for TaskLimits {
+  fn default() -> Self {
+    Self {
+      cpu_time: Duration::from_secs(1),
+      memory: None,
+    }
+  }
+}
 
 #[derive(Debug, Clone, Default)]
 pub struct TaskContext {
   pub close_table: Option<Rc<RegistryKey>>,
   pub cpu_time: Arc<Mutex<Duration>>,
+  pub limits: TaskLimits,
+  /// Lua memory usage when the task was first polled.
+  pub memory_base: Rc<Cell<Option<usize>>>,
+  /// CPU time limit lowering `limits.cpu_time`, set by the `timeout` of the
+  /// path a request matches. It never raises it. Shared with tasks spawned
+  /// from this one.
+  pub timeout: Rc<Cell<Option<Duration>>>,
+  /// Where the service's `print` and `warn` lines also go, besides the
+  /// logger. Shared with tasks spawned from this one.
+  pub log_capture: Rc<RefCell<Option<LogCapture>>>,
+  /// ID of the request being handled, included in logs and passed on in
+  /// outgoing requests. Shared with tasks spawned from this one.
+  pub request_id: Rc<RefCell<Option<Arc<str>>>>,
+  /// Deadlines of the `abel.timeout` calls the task is in, checked while Lua
+  /// code runs, so that calls not yielding are stopped too.
+  pub deadlines: Rc<RefCell<Vec<Instant>>>,
 }
 
 impl TaskContext {
@@ -28,6 +64,32 @@ impl TaskContext {
     lua.app_data_ref::<Self>()
   }
 
+  /// Lowers the CPU time limit of the current task, if any, to `timeout`.
+  pub fn set_timeout(lua: &Lua, timeout: Duration) {
+    if let Some(ctx) = Self::get_current(lua) {
+      ctx.timeout.set(Some(timeout));
+    }
+  }
+
+  /// Has the current task, if any, also send service logs to `capture`.
+  pub fn capture_logs(lua: &Lua, capture: LogCapture) {
+    if let Some(ctx) = Self::get_current(lua) {
+      *ctx.log_capture.borrow_mut() = Some(capture);
+    }
+  }
+
+  /// Sets the ID of the request the current task, if any, handles.
+  pub fn set_request_id(lua: &Lua, id: Arc<str>) {
+    if let Some(ctx) = Self::get_current(lua) {
+      *ctx.request_id.borrow_mut() = Some(id);
+    }
+  }
+
+  /// ID of the request the current task handles, if any.
+  pub fn request_id(lua: &Lua) -> Option<Arc<str>> {
+    Self::get_current(lua).and_then(|x| x.request_id.borrow().clone())
+  }
+
   pub fn remove_current(lua: &Lua) -> Option<Self> {
     lua.remove_app_data::<Self>()
   }
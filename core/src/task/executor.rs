@@ -1,5 +1,5 @@
 use super::task_future::TaskFuture;
-use super::{LocalTask, Task};
+use super::{DetachedTasks, LocalTask, Task};
 use crate::runtime::Runtime;
 use futures::future::select;
 use futures::future::Either::*;
@@ -43,7 +43,11 @@ pub struct Executor {
 }
 
 impl Executor {
-  pub fn new(f: impl FnOnce() -> mlua::Result<Runtime> + Send + 'static, name: String) -> Self {
+  pub fn new(
+    f: impl FnOnce() -> mlua::Result<Runtime> + Send + 'static,
+    name: String,
+    detached: Arc<DetachedTasks>,
+  ) -> Self {
     let panicked = Arc::new(AtomicBool::new(false));
     let panic_notifier = PanicNotifier(panicked.clone());
     let (task_tx, mut task_rx) = mpsc::channel::<Task>(16);
@@ -62,8 +66,10 @@ impl Executor {
           let waker = waker(Arc::new(MyWaker(waker_tx)));
 
           rt.lua().set_app_data(Vec::<LocalTask>::new());
+          rt.lua().set_app_data(Rc::downgrade(&rt));
+          rt.lua().set_app_data(detached);
 
-          let dur = Duration::from_secs(600);
+          let dur = Duration::from_secs(60);
           let mut clean_interval = tokio::time::interval_at(Instant::now() + dur, dur);
 
           loop {
@@ -3,10 +3,10 @@ mod executor;
 mod pool;
 mod task_future;
 
-pub use context::{close_value, TaskContext};
+pub use context::{close_value, TaskContext, TaskLimits};
 pub use executor::Executor;
-pub use pool::Pool;
-pub use task_future::TimeoutError;
+pub use pool::{DetachedTasks, Pool};
+pub use task_future::{DeadlineError, TimeoutError};
 
 use crate::runtime::Runtime;
 use futures::future::LocalBoxFuture;
@@ -28,6 +28,7 @@ pub struct SharedTask(Arc<Mutex<Option<OwnedTask>>>);
 impl SharedTask {
   pub fn new<'a, F, Fut>(
     init_cpu_time: Arc<Mutex<Duration>>,
+    limits: TaskLimits,
     task_fn: F,
   ) -> (
     Self,
@@ -38,7 +39,7 @@ impl SharedTask {
     Fut: Future + 'a,
     Fut::Output: Send + 'static,
   {
-    let (task, rx) = OwnedTask::new(init_cpu_time, task_fn);
+    let (task, rx) = OwnedTask::new(init_cpu_time, limits, task_fn);
     let task = Self(Arc::new(Mutex::new(Some(task))));
     (task, rx)
   }
@@ -62,11 +63,13 @@ pub struct OwnedTask {
   task_fn: TaskFn,
   tx: oneshot::Sender<AnyBox>,
   init_cpu_time: Arc<Mutex<Duration>>,
+  limits: TaskLimits,
 }
 
 impl OwnedTask {
   pub fn new<'a, F, Fut>(
     cpu_time: Arc<Mutex<Duration>>,
+    limits: TaskLimits,
     task_fn: F,
   ) -> (
     Self,
@@ -83,6 +86,7 @@ impl OwnedTask {
       task_fn,
       tx,
       init_cpu_time: cpu_time,
+      limits,
     };
     let rx = rx.map_ok(|x| x.downcast::<Fut::Output>().unwrap());
     (task, rx)
@@ -93,9 +97,11 @@ impl OwnedTask {
       task_fn,
       tx,
       init_cpu_time,
+      limits,
     } = self;
     let mut context = TaskContext::new_with_close_table(lua)?;
     context.cpu_time = init_cpu_time;
+    context.limits = limits;
     let task = LocalTask {
       task_fn,
       tx,
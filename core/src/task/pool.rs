@@ -1,15 +1,22 @@
 use crate::runtime::Runtime;
-use crate::task::{Executor, SharedTask};
+use crate::task::{Executor, OwnedTask, SharedTask, TaskLimits};
 use crate::Result;
+use futures::future::{AbortHandle, AbortRegistration};
 use futures::Future;
 use log::error;
+use parking_lot::Mutex;
+use std::collections::HashMap;
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
-use tokio::sync::RwLock;
+use std::time::Duration;
+use tokio::sync::{RwLock, RwLockReadGuard};
+use uuid::Uuid;
 
 pub struct Pool {
   executors: Vec<RwLock<Executor>>,
   f: Arc<dyn Fn() -> mlua::Result<Runtime> + Send + Sync>,
+  detached: Arc<DetachedTasks>,
 }
 
 impl Pool {
@@ -18,17 +25,28 @@ impl Pool {
     f: impl Fn() -> mlua::Result<Runtime> + Send + Sync + 'static,
   ) -> Result<Self> {
     let f = Arc::new(f);
+    let detached = Arc::new(DetachedTasks::default());
     let executors = (0..size)
       .map(|i| {
         let f = f.clone();
         Ok(RwLock::new(Executor::new(
           move || f(),
           format!("abel-worker-{i}"),
+          detached.clone(),
         )))
       })
       .collect::<Result<_>>()?;
 
-    Ok(Self { executors, f })
+    Ok(Self {
+      executors,
+      f,
+      detached,
+    })
+  }
+
+  /// Number of workers.
+  pub fn size(&self) -> usize {
+    self.executors.len()
   }
 
   pub async fn scope<'a, F, Fut, R>(&self, task_fn: F) -> R
@@ -37,25 +55,127 @@ impl Pool {
     Fut: Future<Output = R> + 'a,
     R: Send + 'static,
   {
-    let (task, rx) = SharedTask::new(Default::default(), task_fn);
-
-    for (i, e) in self.executors.iter().enumerate() {
-      let rl = e.read().await;
-      let result = if rl.is_panicked() {
-        drop(rl);
-        let mut wl = e.write().await;
-        // let state = self.state.clone();
-        let f = self.f.clone();
-        *wl = Executor::new(move || f(), format!("abel-worker-{i}"));
-        wl.send(task.clone()).await
-      } else {
-        rl.send(task.clone()).await
-      };
-      if result.is_err() {
+    self.scope_with_limits(Default::default(), task_fn).await
+  }
+
+  pub async fn scope_with_limits<'a, F, Fut, R>(&self, limits: TaskLimits, task_fn: F) -> R
+  where
+    F: FnOnce(Rc<Runtime>) -> Fut + Send + 'static,
+    Fut: Future<Output = R> + 'a,
+    R: Send + 'static,
+  {
+    (self.scope_with_cpu_time(limits, Default::default(), task_fn)).await
+  }
+
+  /// Same as `scope_with_limits`, but CPU time used by the task and those it
+  /// spawns is accumulated in `cpu_time`.
+  pub async fn scope_with_cpu_time<'a, F, Fut, R>(
+    &self,
+    limits: TaskLimits,
+    cpu_time: Arc<Mutex<Duration>>,
+    task_fn: F,
+  ) -> R
+  where
+    F: FnOnce(Rc<Runtime>) -> Fut + Send + 'static,
+    Fut: Future<Output = R> + 'a,
+    R: Send + 'static,
+  {
+    let (task, rx) = SharedTask::new(cpu_time, limits, task_fn);
+    for i in 0..self.executors.len() {
+      if self.executor(i).await.send(task.clone()).await.is_err() {
         error!("task send failed");
       }
     }
-
     *rx.await.unwrap()
   }
+
+  /// Runs a task on every worker, instead of on whichever is free first.
+  ///
+  /// Returns the results in the order of workers. Workers the task failed to
+  /// reach are left out.
+  pub async fn broadcast<F, Fut, R>(&self, task_fn: F) -> Vec<R>
+  where
+    F: FnOnce(Rc<Runtime>) -> Fut + Clone + Send + 'static,
+    Fut: Future<Output = R>,
+    R: Send + 'static,
+  {
+    let mut receivers = Vec::with_capacity(self.executors.len());
+    for i in 0..self.executors.len() {
+      let (task, rx) = OwnedTask::new(Default::default(), Default::default(), task_fn.clone());
+      if self.executor(i).await.send(task).await.is_err() {
+        error!("task send failed");
+        continue;
+      }
+      receivers.push(rx);
+    }
+    let results = futures::future::join_all(receivers).await;
+    (results.into_iter())
+      .filter_map(|x| x.ok().map(|x| *x))
+      .collect()
+  }
+
+  /// Aborts detached tasks spawned by the service version `uuid` on every
+  /// worker, returning how many there were.
+  pub(crate) fn abort_detached(&self, uuid: Uuid) -> usize {
+    self.detached.abort(uuid)
+  }
+
+  /// Executor `i`, restarted first if it has panicked.
+  async fn executor(&self, i: usize) -> RwLockReadGuard<'_, Executor> {
+    let e = &self.executors[i];
+    let rl = e.read().await;
+    if !rl.is_panicked() {
+      return rl;
+    }
+    drop(rl);
+    let mut wl = e.write().await;
+    if wl.is_panicked() {
+      let f = self.f.clone();
+      *wl = Executor::new(
+        move || f(),
+        format!("abel-worker-{i}"),
+        self.detached.clone(),
+      );
+    }
+    wl.downgrade()
+  }
+}
+
+/// Tasks spawned with `abel.spawn_detached` on all executors, grouped by the
+/// UUID of the service version that spawned them, so they can be aborted
+/// when that version stops or is replaced.
+///
+/// Keying by name would let a replaced version's tasks outlive it, and
+/// stopping one version would abort those of another with the same name.
+#[derive(Default)]
+pub struct DetachedTasks {
+  next_id: AtomicU64,
+  tasks: Mutex<HashMap<Uuid, HashMap<u64, AbortHandle>>>,
+}
+
+impl DetachedTasks {
+  pub(crate) fn register(&self, service: Uuid) -> (u64, AbortRegistration) {
+    let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+    let (handle, registration) = AbortHandle::new_pair();
+    let mut tasks = self.tasks.lock();
+    tasks.entry(service).or_default().insert(id, handle);
+    (id, registration)
+  }
+
+  pub(crate) fn unregister(&self, service: Uuid, id: u64) {
+    let mut tasks = self.tasks.lock();
+    if let Some(x) = tasks.get_mut(&service) {
+      x.remove(&id);
+      if x.is_empty() {
+        tasks.remove(&service);
+      }
+    }
+  }
+
+  /// Aborts every detached task of `service`, returning how many there were.
+  pub(crate) fn abort(&self, service: Uuid) -> usize {
+    let handles = self.tasks.lock().remove(&service).unwrap_or_default();
+    handles.values().for_each(AbortHandle::abort);
+    handles.len()
+  }
 }
@@ -5,11 +5,11 @@ use futures::Future;
 use log::error;
 use mlua::{self, ExternalError, HookTriggers};
 use pin_project::pin_project;
-use std::cell::RefCell;
+use std::cell::Cell;
 use std::pin::Pin;
 use std::rc::Rc;
 use std::task::{Context, Poll};
-use std::time::{Duration, Instant};
+use std::time::Instant;
 use thiserror::Error;
 use tokio::sync::oneshot;
 
@@ -53,20 +53,38 @@ impl Future for TaskFuture {
 
     this.context.set_current(lua);
 
+    let memory_limit = this.context.limits.memory;
+    if let Some(limit) = memory_limit {
+      let base = (this.context.memory_base.get()).unwrap_or_else(|| lua.used_memory());
+      this.context.memory_base.set(Some(base));
+      // The limit is on the whole worker's Lua state, so other tasks' usage
+      // since `base` was taken counts too. Memory limit is unavailable if Lua
+      // does not use mlua's allocator
+      let _ = lua.set_memory_limit(base.saturating_add(limit));
+    }
+
     let hook_triggers = HookTriggers::every_nth_instruction(1048576);
+    let t1 = Rc::new(Cell::new(Instant::now()));
     lua.set_hook(hook_triggers, {
-      let t1 = RefCell::new(Instant::now());
+      let t1 = t1.clone();
+      let cpu_limit = this.context.limits.cpu_time;
+      let timeout = this.context.timeout.clone();
       let cpu_time = this.context.cpu_time.clone();
+      let deadlines = this.context.deadlines.clone();
       move |_lua, _| {
         let mut cpu_time = cpu_time.lock();
         let t2 = Instant::now();
-        let dur = t2.duration_since(*t1.borrow());
+        let dur = t2.duration_since(t1.get());
         *cpu_time += dur;
 
-        if *cpu_time >= Duration::from_secs(1) {
+        // Paths may only shorten the service's limit, not lift it
+        let limit = timeout.get().map_or(cpu_limit, |x| x.min(cpu_limit));
+        if *cpu_time >= limit {
           Err(TimeoutError(()).to_lua_err())
+        } else if deadlines.borrow().iter().any(|x| t2 >= *x) {
+          Err(DeadlineError(()).to_lua_err())
         } else {
-          *t1.borrow_mut() = t2;
+          t1.set(t2);
           Ok(())
         }
       }
@@ -74,6 +92,11 @@ impl Future for TaskFuture {
 
     let poll = this.task.poll(cx);
     lua.remove_hook();
+    // Count time since the last hook call as well
+    *this.context.cpu_time.lock() += t1.get().elapsed();
+    if memory_limit.is_some() {
+      let _ = lua.set_memory_limit(0);
+    }
     let x = TaskContext::remove_current(lua);
     assert_eq!(x.as_ref(), Some(&*this.context));
     drop(x);
@@ -94,3 +117,9 @@ impl Future for TaskFuture {
 #[derive(Debug, Error)]
 #[error("timeout")]
 pub struct TimeoutError(pub(crate) ());
+
+/// Raised in Lua code running past the deadline of an `abel.timeout` call,
+/// until that call turns it into its own error.
+#[derive(Debug, Error)]
+#[error("deadline exceeded")]
+pub struct DeadlineError(pub(crate) ());
@@ -0,0 +1,259 @@
+//! Exposes [`Abel`] as a [`tower_service::Service`], so applications running
+//! their own hyper or axum server can mount Abel services without running a
+//! second server.
+//!
+//! [`ManagementLayer`] adds the management API in front of it, for those who
+//! want to upload, start and stop services over HTTP as well:
+//!
+//! ```no_run
+//! use abel_core::Abel;
+//! use abel_tower::{AbelService, ManagementLayer};
+//! use hyper::{Body, Request, Response};
+//! use std::convert::Infallible;
+//! use std::sync::Arc;
+//! use tower_layer::Layer;
+//! use tower_service::Service;
+//!
+//! fn app(abel: Arc<Abel>) -> impl Service<Request<Body>, Response = Response<Body>, Error = Infallible> {
+//!   ManagementLayer::new(abel.clone())
+//!     .with_auth_token("secret")
+//!     .layer(AbelService::new(abel))
+//! }
+//! ```
+//!
+//! Features of the `abel` binary's API tied to its data folder, such as
+//! versions, backups and tokens, are not included.
+
+mod management;
+
+pub use management::{Management, ManagementLayer};
+
+use abel_core::{Abel, Error};
+use futures::future::BoxFuture;
+use hyper::{Body, Request, Response, StatusCode};
+use log::error;
+use serde_json::json;
+use std::convert::Infallible;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_service::Service;
+
+/// Routes `/<service>/<path>` to the service's handler.
+#[derive(Clone)]
+pub struct AbelService {
+  abel: Arc<Abel>,
+  prefix: Option<Arc<str>>,
+  expose_internal_errors: bool,
+}
+
+impl AbelService {
+  pub fn new(abel: Arc<Abel>) -> Self {
+    Self {
+      abel,
+      prefix: None,
+      expose_internal_errors: false,
+    }
+  }
+
+  /// Strips `prefix` from request paths before routing, for routers that do
+  /// not do so themselves.
+  ///
+  /// Requests outside of `prefix` are answered with 404.
+  pub fn with_prefix(mut self, prefix: &str) -> Self {
+    self.prefix = Some(prefix.trim_end_matches('/').into());
+    self
+  }
+
+  /// Whether details of internal errors are sent to clients. Defaults to
+  /// `false`.
+  pub fn expose_internal_errors(mut self, expose: bool) -> Self {
+    self.expose_internal_errors = expose;
+    self
+  }
+
+  async fn call_inner(self, req: Request<Body>) -> Response<Body> {
+    let path = req.uri().path();
+    let path = match &self.prefix {
+      Some(prefix) => match path.strip_prefix(&**prefix) {
+        Some(x) if x.is_empty() || x.starts_with('/') => x,
+        _ => return not_found(path),
+      },
+      None => path,
+    };
+    let (service_name, sub_path) = match path.trim_start_matches('/').split_once('/') {
+      Some((name, rest)) => (name, format!("/{rest}")),
+      None => (path.trim_start_matches('/'), "/".into()),
+    };
+    if service_name.is_empty() {
+      return not_found(path);
+    }
+
+    let service_name = self.abel.resolve_service_name(service_name);
+    let result = match self.abel.get_running_service(&service_name) {
+      Ok(service) => self.abel.run_service(service, sub_path, req).await,
+      Err(error) => Err(error),
+    };
+    result.unwrap_or_else(|error| error_response(error, self.expose_internal_errors))
+  }
+}
+
+impl Service<Request<Body>> for AbelService {
+  type Response = Response<Body>;
+  type Error = Infallible;
+  type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    Poll::Ready(Ok(()))
+  }
+
+  fn call(&mut self, req: Request<Body>) -> Self::Future {
+    let this = self.clone();
+    Box::pin(async move { Ok(this.call_inner(req).await) })
+  }
+}
+
+fn error_response(error: Error, expose_internal_errors: bool) -> Response<Body> {
+  let kind = error.kind();
+  let body = if kind.internal() && !expose_internal_errors {
+    error!("{error}");
+    json!({ "error": "internal error" })
+  } else {
+    json!({ "error": kind.error(), "detail": kind.detail() })
+  };
+  json_response(kind.status(), body)
+}
+
+fn not_found(path: &str) -> Response<Body> {
+  json_response(
+    StatusCode::NOT_FOUND,
+    json!({ "error": "path not found", "detail": { "path": path } }),
+  )
+}
+
+fn json_response(status: StatusCode, body: serde_json::Value) -> Response<Body> {
+  Response::builder()
+    .status(status)
+    .header("content-type", "application/json")
+    .body(body.to_string().into())
+    .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use abel_core::AbelOptions;
+  use hyper::Method;
+  use tempfile::TempDir;
+  use tower_layer::Layer;
+
+  fn abel(dir: &TempDir) -> Arc<Abel> {
+    let local_storage_path = dir.path().join("storage");
+    std::fs::create_dir(&local_storage_path).unwrap();
+    let abel = Abel::new(AbelOptions {
+      runtime_pool_size: 1,
+      local_storage_path,
+      remote_cache_path: None,
+      idle: Default::default(),
+      secrets: Default::default(),
+      secret_grants: Default::default(),
+      isolate_cache_size: None,
+      http_client: Default::default(),
+      allow_unlocked: false,
+      remote_credentials: Default::default(),
+      remote_cache: Default::default(),
+      modules: Vec::new(),
+      sources: Default::default(),
+      storage: None,
+    });
+    Arc::new(abel.unwrap())
+  }
+
+  async fn send<S>(service: &mut S, method: Method, path: &str, body: &str) -> (StatusCode, String)
+  where
+    S: Service<Request<Body>, Response = Response<Body>>,
+    S::Error: std::fmt::Debug,
+  {
+    let req = Request::builder()
+      .method(method)
+      .uri(path)
+      .header("authorization", "Abel secret")
+      .body(Body::from(body.to_string()))
+      .unwrap();
+    let resp = service.call(req).await.unwrap();
+    let status = resp.status();
+    let body = hyper::body::to_bytes(resp.into_body()).await.unwrap();
+    (status, String::from_utf8(body.to_vec()).unwrap())
+  }
+
+  #[tokio::test]
+  async fn test_routing() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let code = r#"abel.listen("/:x", function(req) return req.params.x end)"#;
+    let source = abel_core::Source::new(abel_core::source::SingleSource::new(code));
+    (abel.cold_update_or_create_service("a", None, source, Default::default()))
+      .await
+      .unwrap();
+
+    let mut service = AbelService::new(abel.clone()).with_prefix("/abel/");
+    assert_eq!(
+      send(&mut service, Method::GET, "/abel/a/hello", "").await,
+      (StatusCode::OK, "hello".into())
+    );
+    let (status, _) = send(&mut service, Method::GET, "/abelx/a/hello", "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(&mut service, Method::GET, "/abel/b/hello", "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn test_management_layer() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let mut app = (ManagementLayer::new(abel.clone()))
+      .with_auth_token("secret")
+      .layer(AbelService::new(abel));
+    let code = |x| format!(r#"abel.listen("/", function() return "{x}" end)"#);
+
+    let (status, body) = send(&mut app, Method::PUT, "/services/a", &code("v1")).await;
+    assert_eq!(status, StatusCode::OK, "{body}");
+    assert_eq!(send(&mut app, Method::GET, "/a/", "").await.1, "v1");
+
+    // Running services are hot-updated
+    send(&mut app, Method::PUT, "/services/a", &code("v2")).await;
+    assert_eq!(send(&mut app, Method::GET, "/a/", "").await.1, "v2");
+
+    let (_, body) = send(&mut app, Method::GET, "/services", "").await;
+    let list: serde_json::Value = serde_json::from_str(&body).unwrap();
+    assert_eq!(list[0]["name"], "a");
+    assert_eq!(list[0]["status"], "running");
+
+    // Only stopped services may be removed
+    let (status, _) = send(&mut app, Method::DELETE, "/services/a", "").await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    let (status, _) = send(&mut app, Method::PATCH, "/services/a?op=stop", "").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&mut app, Method::GET, "/a/", "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+    let (status, _) = send(&mut app, Method::PATCH, "/services/a?op=pause", "").await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (status, _) = send(&mut app, Method::DELETE, "/services/a", "").await;
+    assert_eq!(status, StatusCode::OK);
+    let (status, _) = send(&mut app, Method::GET, "/services/a", "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+  }
+
+  #[tokio::test]
+  async fn test_management_auth() {
+    let dir = TempDir::new().unwrap();
+    let abel = abel(&dir);
+    let mut app = (ManagementLayer::new(abel.clone()))
+      .with_auth_token("other")
+      .layer(AbelService::new(abel));
+    let (status, _) = send(&mut app, Method::GET, "/services", "").await;
+    assert_eq!(status, StatusCode::UNAUTHORIZED);
+    // Service routes are not the layer's to guard
+    let (status, _) = send(&mut app, Method::GET, "/a/", "").await;
+    assert_eq!(status, StatusCode::NOT_FOUND);
+  }
+}
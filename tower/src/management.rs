@@ -0,0 +1,211 @@
+//! The parts of the management API that make sense for embedders, as a
+//! layer in front of [`AbelService`](crate::AbelService) or any other
+//! service.
+
+use crate::{error_response, json_response};
+use abel_core::service::{normalize_name, Service as ServiceEntry, ServiceInfo};
+use abel_core::source::{SingleSource, Source};
+use abel_core::{Abel, Config};
+use futures::future::BoxFuture;
+use hyper::{Body, Method, Request, Response, StatusCode};
+use serde_json::{json, Value};
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// Serves the management API under `/services`, passing other requests on.
+///
+/// - `GET /services` lists services.
+/// - `GET /services/<name>` shows one.
+/// - `PUT /services/<name>` creates or updates a single-file service, with its
+///   `main.lua` as the body. Running services are hot-updated.
+/// - `PATCH /services/<name>?op=start` or `?op=stop` starts or stops one.
+/// - `DELETE /services/<name>` removes a stopped one.
+///
+/// Without [`with_auth_token`](Self::with_auth_token), anyone reaching the
+/// layer may manage services, so the application should authenticate these
+/// requests itself.
+#[derive(Clone)]
+pub struct ManagementLayer {
+  abel: Arc<Abel>,
+  auth_token: Option<Arc<str>>,
+}
+
+impl ManagementLayer {
+  pub fn new(abel: Arc<Abel>) -> Self {
+    Self {
+      abel,
+      auth_token: None,
+    }
+  }
+
+  /// Requires management requests to present `token` as `Authorization:
+  /// Abel <token>`, like the `abel` binary's API does.
+  pub fn with_auth_token(mut self, token: impl Into<String>) -> Self {
+    self.auth_token = Some(token.into().into());
+    self
+  }
+}
+
+impl<S> Layer<S> for ManagementLayer {
+  type Service = Management<S>;
+
+  fn layer(&self, inner: S) -> Management<S> {
+    Management {
+      layer: self.clone(),
+      inner,
+    }
+  }
+}
+
+/// Service made by [`ManagementLayer`].
+#[derive(Clone)]
+pub struct Management<S> {
+  layer: ManagementLayer,
+  inner: S,
+}
+
+impl<S> Service<Request<Body>> for Management<S>
+where
+  S: Service<Request<Body>, Response = Response<Body>>,
+  S::Future: Send + 'static,
+{
+  type Response = Response<Body>;
+  type Error = S::Error;
+  type Future = BoxFuture<'static, Result<Self::Response, Self::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<Body>) -> Self::Future {
+    let path = req.uri().path();
+    if path != "/services" && !path.starts_with("/services/") {
+      return Box::pin(self.inner.call(req));
+    }
+    let layer = self.layer.clone();
+    Box::pin(async move { Ok(layer.handle(req).await) })
+  }
+}
+
+impl ManagementLayer {
+  async fn handle(self, req: Request<Body>) -> Response<Body> {
+    if !self.is_authorized(&req) {
+      return json_response(
+        StatusCode::UNAUTHORIZED,
+        json!({ "error": "unauthorized", "detail": null }),
+      );
+    }
+    let path = req.uri().path().to_string();
+    let segments = (path.split('/'))
+      .filter(|x| !x.is_empty())
+      .skip(1)
+      .collect::<Vec<_>>();
+    let abel = &self.abel;
+    let result = match (req.method(), &segments[..]) {
+      (&Method::GET, []) => Ok(json_response(
+        StatusCode::OK,
+        abel.list_services().map(|x| service_json(&x)).collect(),
+      )),
+      (&Method::GET, [name]) => (abel.get_service(&abel.resolve_service_name(name)))
+        .map(|x| json_response(StatusCode::OK, service_json(&x))),
+      (&Method::PUT, [name]) => {
+        let name = normalize_name(name).into_owned();
+        self.upload(name, req).await
+      }
+      (&Method::PATCH, [name]) => {
+        let name = abel.resolve_service_name(name);
+        let op = (req.uri().query().unwrap_or("").split('&')).find_map(|x| x.strip_prefix("op="));
+        match op {
+          Some("start") => match abel.start_service(&name).await {
+            Ok(_) => abel.get_service(&name),
+            Err(error) => Err(error),
+          }
+          .map(|x| json_response(StatusCode::OK, service_json(&x))),
+          Some("stop") => (abel.stop_service(&name).await)
+            .map(|x| json_response(StatusCode::OK, status_json("stopped", x.info()))),
+          _ => return bad_request("`op` must be `start` or `stop`"),
+        }
+      }
+      (&Method::DELETE, [name]) => (abel.remove_service(&abel.resolve_service_name(name)).await)
+        .map(|x| json_response(StatusCode::OK, status_json("removed", x.info()))),
+      (_, [] | [_]) => {
+        return json_response(
+          StatusCode::METHOD_NOT_ALLOWED,
+          json!({ "error": "method not allowed", "detail": { "method": req.method().as_str() } }),
+        )
+      }
+      _ => {
+        return json_response(
+          StatusCode::NOT_FOUND,
+          json!({ "error": "path not found", "detail": { "path": path } }),
+        )
+      }
+    };
+    result.unwrap_or_else(|error| error_response(error, false))
+  }
+
+  fn is_authorized(&self, req: &Request<Body>) -> bool {
+    let token = match &self.auth_token {
+      Some(x) => x,
+      None => return true,
+    };
+    let presented = (req.headers().get("authorization"))
+      .and_then(|x| x.to_str().ok())
+      .and_then(|x| x.strip_prefix("Abel "));
+    presented.is_some_and(|x| constant_time_eq(x.as_bytes(), token.as_bytes()))
+  }
+
+  async fn upload(&self, name: String, req: Request<Body>) -> abel_core::Result<Response<Body>> {
+    let code = match hyper::body::to_bytes(req.into_body()).await {
+      Ok(x) => x,
+      Err(error) => return Ok(bad_request(&error.to_string())),
+    };
+    let source = Source::new(SingleSource::new(code));
+    let running = (self.abel.get_service(&name)).is_ok_and(|x| x.is_running());
+    if running {
+      let (service, _) = (self.abel)
+        .hot_update_service(name, None, source, Config::default())
+        .await?;
+      let guard = service.try_upgrade()?;
+      Ok(json_response(
+        StatusCode::OK,
+        status_json("running", guard.info()),
+      ))
+    } else {
+      let (service, _, _) = (self.abel)
+        .cold_update_or_create_service(name, None, source, Config::default())
+        .await?;
+      Ok(json_response(StatusCode::OK, service_json(&service)))
+    }
+  }
+}
+
+fn service_json(service: &ServiceEntry) -> Value {
+  let status = if service.is_running() {
+    "running"
+  } else {
+    "stopped"
+  };
+  status_json(status, service.upgrade().info())
+}
+
+fn status_json(status: &str, info: &ServiceInfo) -> Value {
+  let mut value = serde_json::to_value(info).unwrap_or_default();
+  if let Value::Object(x) = &mut value {
+    x.insert("status".into(), status.into());
+  }
+  value
+}
+
+fn bad_request(detail: &str) -> Response<Body> {
+  json_response(
+    StatusCode::BAD_REQUEST,
+    json!({ "error": "bad request", "detail": detail }),
+  )
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+  a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}